@@ -0,0 +1,103 @@
+//! Frame encoding and decoding throughput, run with `cargo bench --bench frame`.
+//! Compares a fresh `serialize` per frame with `encode_into` a reused buffer, and
+//! `FrameReader` with a buffer drained after every frame
+
+use bytes::BytesMut;
+use sowback::{Frame, FrameReader, Message};
+use std::hint::black_box;
+use std::time::Instant;
+
+const FRAMES: usize = 200_000;
+
+fn report(name: &str, payload: usize, start: Instant) {
+    let elapsed = start.elapsed();
+    println!(
+        "{:<12} {:>6} B payload: {:>8.0} frames/s",
+        name,
+        payload,
+        FRAMES as f64 / elapsed.as_secs_f64()
+    );
+}
+
+/// Decodes every frame of `burst` the way `FrameReader` used to, moving the rest of
+/// the buffer forward after each one
+fn drain_decode(burst: &[u8]) -> usize {
+    let mut buffer = burst.to_vec();
+    let mut frames = 0;
+    while buffer.len() >= 4 {
+        let (frame, used) = Frame::deserialize(&buffer).unwrap();
+        black_box(frame);
+        buffer.drain(..used);
+        frames += 1;
+    }
+    frames
+}
+
+fn reader_decode(burst: &[u8]) -> usize {
+    let mut reader = FrameReader::new();
+    reader.feed_data(burst);
+    let mut frames = 0;
+    while let Some(frame) = reader.try_read_frame().unwrap() {
+        black_box(frame);
+        frames += 1;
+    }
+    frames
+}
+
+fn decode(burst_frames: usize) {
+    let mut burst = BytesMut::new();
+    for _ in 0..burst_frames {
+        Frame::new(Message::new_heartbeat())
+            .encode_into(&mut burst)
+            .unwrap();
+    }
+    let rounds = FRAMES / burst_frames;
+
+    for (name, decode) in [
+        ("drain", drain_decode as fn(&[u8]) -> usize),
+        ("FrameReader", reader_decode),
+    ] {
+        let start = Instant::now();
+        for _ in 0..rounds {
+            assert_eq!(decode(&burst), burst_frames);
+        }
+        let elapsed = start.elapsed();
+        println!(
+            "{:<12} {:>6} frame burst: {:>8.0} frames/s",
+            name,
+            burst_frames,
+            (rounds * burst_frames) as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
+
+fn main() {
+    for burst_frames in [10, 1_000, 20_000] {
+        decode(burst_frames);
+    }
+
+    for payload in [64, 1024, 16 * 1024] {
+        let message = Message::new_data(
+            "d0c5ff4e-4a1b-4c8e-9f3e-1b2c3d4e5f60",
+            0,
+            vec![0xab; payload],
+        );
+
+        let start = Instant::now();
+        for _ in 0..FRAMES {
+            black_box(Frame::new(message.clone()).serialize().unwrap());
+        }
+        report("serialize", payload, start);
+
+        let mut buffer = BytesMut::new();
+        let start = Instant::now();
+        for _ in 0..FRAMES {
+            buffer.clear();
+            Frame::new(message.clone())
+                .encode_into(&mut buffer)
+                .unwrap();
+            black_box(&buffer);
+        }
+        report("encode_into", payload, start);
+    }
+}
@@ -1,12 +1,29 @@
 use anyhow::Result;
-use clap::{Parser, Subcommand};
-use tracing::info;
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
 
-use crate::client::Client;
-use crate::config::{ClientConfig, Config, ServerConfig, ServiceConfig};
-use crate::log_info;
-use crate::logging::init_logger;
-use crate::server::Server;
+use serde::Serialize;
+use sowback::config::{
+    client_template, config_candidates, discover_config, generate_token, resolve_token,
+    server_template, AuthMode, ClientConfig, Config, ConfigIssue, HumanBytes, HumanDuration,
+    RelayConfig, ServerConfig, ServiceConfig, VisitorConfig, TOKEN_ENV,
+};
+use sowback::logging::console::{init_color, ColorChoice};
+use sowback::logging::{format_bytes, init_logger, short_id, LogLevel, LogSettings, Verbosity};
+use sowback::{
+    build_runtime, daemonize, stop_daemon, AdminClient, BenchOptions, BenchReport,
+    CancellationToken, Client, GaveUp, Percentiles, PidFile, PingError, PingSummary, Server,
+    Throughput,
+};
+use sowback::{log_debug, log_info, warn};
+#[cfg(unix)]
+use sowback::{ControlClient, ControlCommand, ServiceSpec};
+use std::path::Path;
+use std::time::Duration;
+use tokio::runtime::{Runtime, RuntimeFlavor};
+
+mod prompt;
+#[cfg(any(windows, test))]
+mod service;
 
 // --- Clap ---
 
@@ -21,9 +38,29 @@ struct Cli {
     #[arg(long, global = true)]
     log: Option<String>,
 
-    /// Verbose logging
+    /// Detailed console logging, -vv adding debug output of the data path
+    #[arg(short, long, global = true, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Print only warnings and errors on the console, the log file keeps everything
     #[arg(short, long, global = true)]
-    verbose: bool,
+    quiet: bool,
+
+    /// When to color the console output
+    #[arg(long, global = true, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Maximum log level, overriding the config's log_level and RUST_LOG
+    #[arg(long, global = true, value_enum)]
+    log_level: Option<LogLevel>,
+
+    /// Ignore unknown config keys instead of failing, like `strict = false`
+    #[arg(long, global = true)]
+    no_strict_config: bool,
+
+    /// Fail when no token is given instead of asking for it on the terminal
+    #[arg(long, global = true)]
+    no_prompt: bool,
 
     #[command(subcommand)]
     command: Commands,
@@ -32,90 +69,1134 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Start the server (listen mode)
-    Listen {
-        /// Server name for human identification (not unique)
-        #[arg(long)]
-        name: Option<String>,
-
+    Listen(ListenArgs),
+    /// Connect to server (client mode)
+    Connect(ConnectArgs),
+    /// Reach another client's secret service on a local port (visitor mode)
+    Visitor {
         /// Configuration file path
         #[arg(short, long)]
         config: Option<String>,
 
-        /// Listen address (default: 0.0.0.0:7000)
-        address: Option<String>,
+        /// Name of the secret service
+        service: String,
 
-        /// Bind host for services (default: 0.0.0.0)
+        /// Secret the service was registered with
         #[arg(long)]
-        bind: Option<String>,
+        secret: String,
 
-        /// Authentication token (required)
+        /// Local address to accept connections on, e.g. 127.0.0.1:2222
+        #[arg(long)]
+        bind: String,
+
+        /// Server the service is registered on, the config's first server when absent
+        #[arg(long)]
+        server: Option<String>,
+
+        /// Authentication token, visible to other local users, prefer --token-file
+        /// or the SOWBACK_TOKEN environment variable
         #[arg(long)]
         token: Option<String>,
+
+        /// File holding the authentication token, not readable by other users
+        #[arg(long)]
+        token_file: Option<String>,
     },
-    /// Connect to server (client mode)
-    Connect {
-        /// Client name for human identification (not unique)
+    /// Check that a server is reachable and accepts the token, and measure its latency
+    Ping {
+        /// Configuration file path, for TLS settings
+        #[arg(short, long)]
+        config: Option<String>,
+
+        /// Server address
         #[arg(long)]
-        name: Option<String>,
+        server: String,
 
-        /// Configuration file path
+        /// Authentication token
+        #[arg(long)]
+        token: Option<String>,
+
+        /// File holding the authentication token
+        #[arg(long)]
+        token_file: Option<String>,
+
+        /// Number of heartbeats to send, one per second
+        #[arg(short = 'n', long, default_value_t = 4)]
+        count: usize,
+    },
+    /// Measure the throughput and latency of a tunnel through a server
+    Bench {
+        /// Configuration file path, for TLS and transport settings
         #[arg(short, long)]
         config: Option<String>,
 
-        /// Server addresses (can specify multiple)
-        servers: Vec<String>,
+        /// Server address
+        #[arg(long)]
+        server: String,
 
-        /// Authentication token (required)
+        /// Authentication token
         #[arg(long)]
         token: Option<String>,
 
-        /// Service configurations: local_ip:local_port:remote_port
-        #[arg(short, long, action = clap::ArgAction::Append)]
-        service: Vec<String>,
+        /// File holding the authentication token
+        #[arg(long)]
+        token_file: Option<String>,
+
+        /// How long the latency, upload and download measurements each run
+        #[arg(short, long, default_value = "5s")]
+        duration: HumanDuration,
+
+        /// Connections transferring data at once
+        #[arg(short = 'P', long, default_value_t = 4)]
+        #[arg(value_parser = clap::value_parser!(u16).range(1..))]
+        streams: u16,
+
+        /// Size of each write during the transfers
+        #[arg(long, default_value = "16KiB")]
+        payload_size: HumanBytes,
+
+        /// Print the results as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Validate a configuration file
+    Check {
+        /// Configuration file path, discovered like --config when absent
+        config: Option<String>,
+    },
+    /// Generate a commented starter configuration with a random token
+    Init {
+        /// Which side the configuration is for
+        #[arg(long, value_enum)]
+        mode: InitMode,
+
+        /// File to write the configuration to
+        #[arg(short, long, required_unless_present = "stdout")]
+        output: Option<String>,
+
+        /// Overwrite the output file if it exists
+        #[arg(long)]
+        force: bool,
+
+        /// Print the configuration instead of writing a file
+        #[arg(long, conflicts_with = "output")]
+        stdout: bool,
+    },
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Act on a running server through its admin API, see [server.admin]
+    Admin {
+        #[command(flatten)]
+        target: AdminArgs,
+
+        #[command(subcommand)]
+        command: AdminCommand,
+    },
+    /// Act on a running client through its control socket, see `control_socket`
+    #[cfg(unix)]
+    Ctl {
+        #[command(flatten)]
+        target: CtlArgs,
+
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
+    /// Install, remove or run sowback as a Windows service
+    #[cfg(windows)]
+    Service {
+        #[command(subcommand)]
+        command: service::ServiceCommand,
     },
 }
 
-/// Execute entry
-pub async fn execute() -> Result<()> {
-    let cli = Cli::parse();
+#[derive(Args)]
+struct AdminArgs {
+    /// Configuration file path, for the server's admin.listen_addr
+    #[arg(short, long, global = true)]
+    config: Option<String>,
 
-    // Initialize logging system very early
-    init_logger(cli.log.clone(), cli.verbose);
+    /// Address of the admin API, the config's admin.listen_addr when absent
+    #[arg(long, global = true)]
+    addr: Option<String>,
 
-    match cli.command {
-        // server listen
-        Commands::Listen {
-            name,
-            config,
-            address,
-            bind,
-            token,
-        } => {
-            let mut server_config = if let Some(config_path) = config {
-                Config::from_file(&config_path)?.server.unwrap_or_default()
+    /// Admin token, visible to other local users, prefer --token-file
+    /// or the SOWBACK_ADMIN_TOKEN environment variable
+    #[arg(long, global = true)]
+    token: Option<String>,
+
+    /// File holding the admin token, not readable by other users
+    #[arg(long, global = true)]
+    token_file: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum AdminCommand {
+    /// List connected clients and their proxies
+    Clients,
+    /// List the bound service ports and the clients holding them
+    Ports,
+    /// Disconnect a client, freeing its ports
+    Kick {
+        /// Client ID, or a unique prefix of it like the short IDs in logs
+        client_id: String,
+    },
+    /// Close one proxy connection
+    Close {
+        /// Connection ID, or a unique prefix of it like the short IDs in logs
+        connection_id: String,
+    },
+    /// Refuse connections from an address on the control port and every service port
+    Ban {
+        /// IP address or CIDR block, e.g. 203.0.113.7 or 10.0.0.0/8
+        target: String,
+
+        /// How long the ban lasts, e.g. 30m or 1d
+        #[arg(short, long, default_value = "1h")]
+        duration: String,
+    },
+}
+
+#[cfg(unix)]
+#[derive(Args)]
+struct CtlArgs {
+    /// Configuration file path, for the client's control_socket
+    #[arg(short, long, global = true)]
+    config: Option<String>,
+
+    /// Path of the control socket, the config's control_socket when absent
+    #[arg(long, global = true)]
+    socket: Option<String>,
+}
+
+#[cfg(unix)]
+#[derive(Subcommand)]
+enum CtlCommand {
+    /// Show the servers and services of the client and their state
+    Status,
+    /// Load the configuration again and apply its services, like SIGHUP
+    Reload,
+    /// Register one more service with every server
+    AddService {
+        /// The service, like --service of `connect` or a JSON table of
+        /// `[[client.services]]` keys such as '{"name": "web", "local_port": 8080}'
+        service: String,
+
+        /// Server to add it to, every server when absent
+        #[arg(long)]
+        server: Option<String>,
+    },
+    /// Unregister a service from every server
+    RemoveService {
+        /// Name of the service
+        name: String,
+
+        /// Server to remove it from, every server when absent
+        #[arg(long)]
+        server: Option<String>,
+    },
+}
+
+#[derive(Args)]
+struct ListenArgs {
+    /// Server name for human identification (not unique)
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Configuration file path, else ./sowback.toml, $XDG_CONFIG_HOME/sowback/config.toml
+    /// or /etc/sowback/config.toml, the first that exists
+    #[arg(short, long)]
+    config: Option<String>,
+
+    /// Listen address (default: 0.0.0.0:7000)
+    address: Option<String>,
+
+    /// Bind host for services (default: 0.0.0.0)
+    #[arg(long)]
+    bind: Option<String>,
+
+    /// Authentication token, visible to other local users, prefer --token-file
+    /// or the SOWBACK_TOKEN environment variable
+    #[arg(long)]
+    token: Option<String>,
+
+    /// File holding the authentication token, not readable by other users
+    #[arg(long)]
+    token_file: Option<String>,
+
+    /// Runtime worker threads, 1 for a single thread, 0 for one per core
+    #[arg(long)]
+    workers: Option<usize>,
+
+    #[command(flatten)]
+    daemon: DaemonArgs,
+
+    /// Unix socket SIGUSR2 hands the listeners over through, to a new process of the
+    /// binary started with the same arguments, for upgrades without downtime
+    #[arg(long)]
+    upgrade_socket: Option<String>,
+
+    /// Show a live view of clients, proxies and traffic instead of the console output,
+    /// q quits, k kicks the selected client
+    #[cfg(feature = "dashboard")]
+    #[arg(long)]
+    dashboard: bool,
+}
+
+#[derive(Args, Clone)]
+struct ConnectArgs {
+    /// Client name for human identification (not unique)
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Configuration file path, else ./sowback.toml, $XDG_CONFIG_HOME/sowback/config.toml
+    /// or /etc/sowback/config.toml, the first that exists
+    #[arg(short, long)]
+    config: Option<String>,
+
+    /// Server addresses (can specify multiple)
+    servers: Vec<String>,
+
+    /// Authentication token, visible to other local users, prefer --token-file
+    /// or the SOWBACK_TOKEN environment variable
+    #[arg(long)]
+    token: Option<String>,
+
+    /// File holding the authentication token, not readable by other users
+    #[arg(long)]
+    token_file: Option<String>,
+
+    /// Service configurations: [local_ip:]local_port[:remote_port][@bind_host][/max_connections],
+    /// local_ip defaults to 127.0.0.1 and remote_port to local_port, ports may be ranges of
+    /// equal length like 8000-8010:9000-9010
+    #[arg(short, long, action = clap::ArgAction::Append)]
+    service: Vec<String>,
+    /// Reach the relay service of another client on a local port:
+    /// client:service@[local_ip:]local_port, local_ip defaults to 127.0.0.1
+    #[arg(long, action = clap::ArgAction::Append)]
+    relay: Vec<String>,
+    /// Register services with a ttl again once it runs out, instead of leaving them expired
+    #[arg(long)]
+    renew: bool,
+
+    /// Connect to each server once and exit when the connection ends instead of
+    /// reconnecting: 0 after a clean shutdown, 2 on a rejected token, 3 on network failures
+    #[arg(long)]
+    once: bool,
+
+    /// Runtime worker threads, 1 for a single thread, 0 for one per core
+    #[arg(long)]
+    workers: Option<usize>,
+
+    #[command(flatten)]
+    daemon: DaemonArgs,
+}
+
+#[derive(Args, Clone)]
+struct DaemonArgs {
+    /// Fork into the background, detached from the terminal, console output appended to
+    /// the log file
+    #[arg(long)]
+    daemon: bool,
+
+    /// File the daemon writes its pid to, refusing to start while the pid in it runs
+    #[arg(long)]
+    pid_file: Option<String>,
+
+    /// Stop the daemon of --pid-file with SIGTERM and wait for it to exit
+    #[arg(long, requires = "pid_file", conflicts_with = "daemon")]
+    stop: bool,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print the configuration a command would run with, the config file, environment
+    /// and command line merged, secrets redacted
+    Dump {
+        #[command(subcommand)]
+        mode: DumpMode,
+    },
+}
+
+#[derive(Subcommand)]
+enum DumpMode {
+    /// The configuration of `sowback listen` with these arguments
+    Listen(ListenArgs),
+    /// The configuration of `sowback connect` with these arguments
+    Connect(ConnectArgs),
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum InitMode {
+    Server,
+    Client,
+}
+
+/// Logs warnings and fails on errors, so bad configs stop before anything starts
+fn enforce_valid(issues: Vec<ConfigIssue>) -> Result<()> {
+    let (errors, warnings): (Vec<_>, Vec<_>) = issues.into_iter().partition(ConfigIssue::is_error);
+    for issue in &warnings {
+        warn!("Config {}: {}", issue.path, issue.message);
+    }
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    let details: Vec<String> = errors
+        .iter()
+        .map(|issue| format!("  {}: {}", issue.path, issue.message))
+        .collect();
+    Err(anyhow::anyhow!(
+        "Invalid configuration:\n{}",
+        details.join("\n")
+    ))
+}
+
+/// The issues keeping the server from starting: its configuration's, and its control
+/// port taken, unless an upgrade hands the listeners over
+fn server_issues(server_config: &ServerConfig, upgrading: bool) -> Vec<ConfigIssue> {
+    let mut issues = server_config.validate();
+    if !upgrading {
+        issues.extend(server_config.check_listen());
+    }
+    issues
+}
+
+/// Why a client command cannot go on without a token
+fn client_token_required() -> String {
+    format!(
+        "Token is required. Please provide --token-file, {} or --token, or a tls client certificate",
+        TOKEN_ENV
+    )
+}
+
+/// Whether the server has neither a token nor certificate authentication to admit clients
+fn server_token_missing(server_config: &ServerConfig) -> bool {
+    server_config.token_entries().is_empty() && server_config.auth_mode() != AuthMode::Cert
+}
+
+/// Whether the client has neither a token nor a certificate to authenticate with
+fn client_token_missing(client_config: &ClientConfig) -> bool {
+    client_config.token.is_empty()
+        && client_config.tls.cert.is_none()
+        && client_config
+            .connections
+            .iter()
+            .all(|entry| entry.token.is_none())
+}
+
+/// Asks for the missing token when stdin is a terminal, unless `no_prompt`,
+/// else fails with `missing`
+fn ask_token(no_prompt: bool, missing: String) -> Result<String> {
+    let interactive = !no_prompt && atty::is(atty::Stream::Stdin);
+    prompt::missing_token(interactive, missing)
+}
+
+/// The token from the environment, if set
+fn token_env() -> Option<String> {
+    std::env::var(TOKEN_ENV).ok()
+}
+
+/// Environment variable holding the token of `sowback admin`
+const ADMIN_TOKEN_ENV: &str = "SOWBACK_ADMIN_TOKEN";
+
+/// The admin API `sowback admin` talks to: `--addr`, else the `admin.listen_addr` of the
+/// server config, with the token from the arguments, the environment or the terminal
+fn admin_client(args: AdminArgs, strict: bool, no_prompt: bool) -> Result<AdminClient> {
+    let addr = match args.addr {
+        Some(addr) => addr,
+        None => server_file(args.config, strict)?
+            .0
+            .admin
+            .map(|admin| admin.listen_addr)
+            .ok_or_else(|| {
+                anyhow::anyhow!("No admin API address, pass --addr or set server.admin")
+            })?,
+    };
+    let token = match resolve_token(
+        args.token,
+        args.token_file.as_deref(),
+        std::env::var(ADMIN_TOKEN_ENV).ok(),
+    )? {
+        Some(token) => token,
+        None => ask_token(
+            no_prompt,
+            format!(
+                "Admin token is required. Please provide --token-file, {} or --token",
+                ADMIN_TOKEN_ENV
+            ),
+        )?,
+    };
+    Ok(AdminClient::new(addr, token))
+}
+
+/// The control socket `sowback ctl` talks to: `--socket`, else the `control_socket` of
+/// the client config
+#[cfg(unix)]
+fn control_client(args: CtlArgs, strict: bool) -> Result<ControlClient> {
+    let path = match args.socket {
+        Some(path) => path,
+        None => client_file(args.config, strict)?
+            .0
+            .control_socket
+            .ok_or_else(|| {
+                anyhow::anyhow!("No control socket, pass --socket or set client.control_socket")
+            })?,
+    };
+    Ok(ControlClient::new(path))
+}
+
+/// The control command of `sowback ctl`
+#[cfg(unix)]
+fn control_command(command: CtlCommand) -> Result<ControlCommand> {
+    Ok(match command {
+        CtlCommand::Status => ControlCommand::Status,
+        CtlCommand::Reload => ControlCommand::Reload,
+        CtlCommand::AddService { service, server } => {
+            let service = if service.trim_start().starts_with('{') {
+                ServiceSpec::Table(Box::new(
+                    serde_json::from_str(&service)
+                        .map_err(|e| anyhow::anyhow!("Invalid service table: {}", e))?,
+                ))
             } else {
-                ServerConfig::default()
+                ServiceSpec::Mapping(service)
             };
+            ControlCommand::AddService { service, server }
+        }
+        CtlCommand::RemoveService { name, server } => {
+            ControlCommand::RemoveService { name, server }
+        }
+    })
+}
 
-            // Override with command line arguments
-            if let Some(addr) = address {
-                server_config.listen_addr = addr;
+/// Writes a generated config to `path`, readable only by its owner since it holds the
+/// token. An existing file is only replaced with `force`
+/// One line per client of an admin `clients` listing, followed by its proxies
+fn clients_table(listing: &serde_json::Value) -> String {
+    let text = |value: &serde_json::Value, field: &str| {
+        value
+            .get(field)
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or_default()
+            .to_string()
+    };
+    let list = |value: &serde_json::Value, field: &str| {
+        value
+            .get(field)
+            .and_then(serde_json::Value::as_array)
+            .cloned()
+            .unwrap_or_default()
+    };
+    let clients = list(listing, "clients");
+    if clients.is_empty() {
+        return "No clients connected".to_string();
+    }
+    let mut lines = Vec::new();
+    for client in &clients {
+        let name = text(client, "name");
+        let mut line = format!(
+            "{}  {}  {}",
+            short_id(&text(client, "id")),
+            if name.is_empty() { "-" } else { &name },
+            text(client, "addr")
+        );
+        let meta = &client["meta"];
+        if meta.is_object() {
+            line.push_str(&format!(
+                "  v{} {}/{}",
+                text(meta, "version"),
+                text(meta, "os"),
+                text(meta, "arch")
+            ));
+            let hostname = text(meta, "hostname");
+            if !hostname.is_empty() {
+                line.push_str(&format!(" on {}", hostname));
             }
-            if let Some(bind_host) = bind {
-                server_config.bind_host = bind_host;
+        }
+        lines.push(line);
+        for proxy in list(client, "proxies") {
+            let mut line = format!(
+                "  {}  {} -> :{}",
+                text(&proxy, "name"),
+                text(&proxy, "local"),
+                proxy["remote_port"]
+            );
+            if let Some(error) = proxy["local_error"].as_str() {
+                line.push_str(&format!("  (local service unreachable: {})", error));
             }
-            if let Some(auth_token) = token {
-                server_config.token = auth_token;
-            } else if server_config.token.is_empty() {
-                return Err(anyhow::anyhow!("Token is required. Please provide --token"));
+            lines.push(line);
+        }
+    }
+    lines.join("\n")
+}
+
+fn ports_table(listing: &serde_json::Value) -> String {
+    let text = |value: &serde_json::Value, field: &str| {
+        value
+            .get(field)
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or_default()
+            .to_string()
+    };
+    let ports = listing["ports"].as_array().cloned().unwrap_or_default();
+    if ports.is_empty() {
+        return "No ports bound".to_string();
+    }
+    let mut lines = Vec::new();
+    for port in &ports {
+        let name = text(port, "client_name");
+        let proxies: Vec<&str> = port["proxies"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(serde_json::Value::as_str)
+            .map(short_id)
+            .collect();
+        let mut line = format!(
+            ":{}  {}  client {}{}  proxy {}  up {}",
+            port["port"],
+            text(port, "bind_host"),
+            short_id(&text(port, "client_id")),
+            if name.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", name)
+            },
+            if proxies.is_empty() {
+                "-".to_string()
+            } else {
+                proxies.join(",")
+            },
+            format_uptime(port["uptime_secs"].as_u64().unwrap_or_default())
+        );
+        if let Some(group) = port["group"].as_str() {
+            line.push_str(&format!("  group '{}'", group));
+        }
+        if port["orphaned"].as_bool() == Some(true) {
+            line.push_str("  (reserved, client away)");
+        }
+        lines.push(line);
+    }
+    lines.join("\n")
+}
+
+/// `secs` in its two largest units, e.g. `3d 4h` or `12m 5s`
+fn format_uptime(secs: u64) -> String {
+    let units = [(86_400, "d"), (3_600, "h"), (60, "m"), (1, "s")];
+    let Some(first) = units.iter().position(|(size, _)| secs >= *size) else {
+        return "0s".to_string();
+    };
+    units[first..]
+        .iter()
+        .take(2)
+        .scan(secs, |rest, (size, unit)| {
+            let count = *rest / size;
+            *rest %= size;
+            Some((count, unit))
+        })
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, unit)| format!("{}{}", count, unit))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn write_config(path: &str, content: &str, force: bool) -> Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::AlreadyExists => {
+            anyhow::anyhow!("{} already exists, pass --force to overwrite it", path)
+        }
+        _ => anyhow::anyhow!("Failed to write {}: {}", path, e),
+    })?;
+    file.write_all(content.as_bytes())?;
+    Ok(())
+}
+
+/// Exit code of `sowback ping` for a failure, so scripts can tell them apart
+fn ping_exit_code(error: &PingError) -> i32 {
+    match error {
+        PingError::Unreachable(_) => 2,
+        PingError::AuthRejected(_) => 3,
+        PingError::ProtocolMismatch(_) => 4,
+        PingError::Other(_) => 1,
+    }
+}
+
+/// Exits with the code of the reason the client gave up on every server, removing the pid
+/// file first; other errors are returned
+fn exit_on_give_up(run: Result<()>, pid_file: Option<PidFile>) -> Result<()> {
+    let Err(e) = run else {
+        return Ok(());
+    };
+    let Some(gave_up) = e.downcast_ref::<GaveUp>() else {
+        return Err(e);
+    };
+    eprintln!("Error: {}", gave_up);
+    drop(pid_file);
+    std::process::exit(gave_up.exit_code());
+}
+
+/// Authenticates to `server`, sends `count` heartbeats and prints their round trips
+async fn ping(client_config: ClientConfig, server: &str, count: usize) -> Result<()> {
+    let token = client_config.token.clone();
+    let client = Client::new(client_config)?;
+    let mut session = match client.ping(server, &token).await {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(ping_exit_code(&e));
+        }
+    };
+    println!("Authenticated to {} in {:.1?}", server, session.auth_time);
+
+    let mut rtts = Vec::new();
+    for seq in 1..=count {
+        if seq > 1 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        match session.probe(Duration::from_secs(5)).await? {
+            Some(rtt) => {
+                println!("Heartbeat {} from {}: rtt {:.1?}", seq, server, rtt);
+                rtts.push(rtt);
             }
-            if let Some(name_str) = name {
-                server_config.name = Some(name_str);
+            None => println!("Heartbeat {} from {}: no response", seq, server),
+        }
+    }
+
+    println!("--- {} ping statistics ---", server);
+    println!(
+        "{} sent, {} answered, auth {:.1?}",
+        count,
+        rtts.len(),
+        session.auth_time
+    );
+    match PingSummary::of(&rtts) {
+        Some(summary) => println!(
+            "rtt min/avg/max = {:.1?}/{:.1?}/{:.1?}",
+            summary.min, summary.avg, summary.max
+        ),
+        None if count > 0 => return Err(anyhow::anyhow!("No heartbeat was answered")),
+        None => {}
+    }
+    Ok(())
+}
+
+/// Runs `Client::bench` through `server` until done or interrupted with Ctrl-C, then
+/// prints its results
+async fn bench(
+    client_config: ClientConfig,
+    server: &str,
+    options: BenchOptions,
+    json: bool,
+) -> Result<()> {
+    let stop = CancellationToken::new();
+    tokio::spawn({
+        let stop = stop.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                stop.cancel();
             }
+        }
+    });
+    let report = Client::bench(client_config, server, &options, stop).await?;
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&bench_json(&report, &options))?
+        );
+    } else {
+        println!("{}", bench_table(&report, &options));
+    }
+    Ok(())
+}
 
-            if let Some(log_file) = &cli.log {
-                server_config.log_file = Some(log_file.clone());
+/// A rate of bytes per second, with its bits per second as network links are rated
+fn format_rate(throughput: &Throughput) -> String {
+    let rate = throughput.bytes_per_second();
+    format!(
+        "{}/s ({:.1} Mbit/s), {} in {:.1?}",
+        format_bytes(rate as u64),
+        rate * 8.0 / 1e6,
+        format_bytes(throughput.bytes),
+        throughput.elapsed
+    )
+}
+
+/// The results of `sowback bench` as a table
+fn bench_table(report: &BenchReport, options: &BenchOptions) -> String {
+    let row = |label: &str, percentiles: &Percentiles| {
+        format!(
+            "{:<9}{:>8} {:>9.1?} {:>9.1?} {:>9.1?} {:>9.1?} {:>9.1?}",
+            label,
+            percentiles.count,
+            percentiles.min,
+            percentiles.p50,
+            percentiles.p90,
+            percentiles.p99,
+            percentiles.max
+        )
+    };
+    [
+        format!(
+            "Benchmark through {}: {} streams of {} payloads, {:.1?} each",
+            report.public_addr,
+            options.streams,
+            format_bytes(options.payload_size as u64),
+            options.duration
+        ),
+        format!(
+            "{:<9}{:>8} {:>9} {:>9} {:>9} {:>9} {:>9}",
+            "", "samples", "min", "p50", "p90", "p99", "max"
+        ),
+        row("setup", &report.setup),
+        row("latency", &report.latency),
+        format!("{:<9}{}", "upload", format_rate(&report.upload)),
+        format!("{:<9}{}", "download", format_rate(&report.download)),
+    ]
+    .join("\n")
+}
+
+/// The results of `sowback bench` for `--json`, durations in milliseconds
+fn bench_json(report: &BenchReport, options: &BenchOptions) -> serde_json::Value {
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    let percentiles = |percentiles: &Percentiles| {
+        serde_json::json!({
+            "count": percentiles.count,
+            "min": ms(percentiles.min),
+            "p50": ms(percentiles.p50),
+            "p90": ms(percentiles.p90),
+            "p99": ms(percentiles.p99),
+            "max": ms(percentiles.max),
+        })
+    };
+    let throughput = |throughput: &Throughput| {
+        serde_json::json!({
+            "bytes": throughput.bytes,
+            "elapsed_ms": ms(throughput.elapsed),
+            "bits_per_second": throughput.bytes_per_second() * 8.0,
+        })
+    };
+    serde_json::json!({
+        "public_addr": report.public_addr,
+        "duration_ms": ms(options.duration),
+        "streams": options.streams,
+        "payload_size": options.payload_size,
+        "setup_ms": percentiles(&report.setup),
+        "latency_ms": percentiles(&report.latency),
+        "upload": throughput(&report.upload),
+        "download": throughput(&report.download),
+    })
+}
+
+/// Loads the config file given with `--config`, else the first one discovered,
+/// along with its path. None when there is neither
+fn load_config(config: Option<String>, strict: bool) -> Result<Option<(String, Config)>> {
+    let Some(path) = config.or_else(discover_config) else {
+        return Ok(None);
+    };
+    let config = Config::load(&path, strict)?;
+    Ok(Some((path, config)))
+}
+
+/// Where a configuration came from besides the defaults and the command line
+#[derive(Debug)]
+struct Sources {
+    /// The config file loaded, if any
+    file: Option<String>,
+    /// The `SOWBACK_<SECTION>_<KEY>` variables applied on top of it
+    env: Vec<String>,
+}
+
+impl Sources {
+    /// Logs where the configuration came from, once logging is set up
+    fn log(&self) {
+        match &self.file {
+            Some(path) => log_info!("Using configuration file {}", path),
+            None => log_debug!("No configuration file, using defaults and the command line"),
+        }
+        if !self.env.is_empty() {
+            log_info!("Configuration overridden by {}", self.env.join(", "));
+        }
+    }
+}
+
+/// The environment variable `name`, if set
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+/// The `[server]` table of the config file, the defaults without one,
+/// with `SOWBACK_SERVER_*` variables applied
+fn server_file(config: Option<String>, strict: bool) -> Result<(ServerConfig, Sources)> {
+    let (mut server_config, file) = match load_config(config, strict)? {
+        Some((path, config)) => (config.server.unwrap_or_default(), Some(path)),
+        None => (ServerConfig::default(), None),
+    };
+    let env = server_config.apply_env(env_var)?;
+    Ok((server_config, Sources { file, env }))
+}
+
+/// The `[client]` table of the config file, the defaults without one,
+/// with `SOWBACK_CLIENT_*` variables applied
+fn client_file(config: Option<String>, strict: bool) -> Result<(ClientConfig, Sources)> {
+    let (mut client_config, file) = match load_config(config, strict)? {
+        Some((path, config)) => (config.client.unwrap_or_default(), Some(path)),
+        None => (ClientConfig::default(), None),
+    };
+    let env = client_config.apply_env(env_var)?;
+    Ok((client_config, Sources { file, env }))
+}
+
+/// The configuration `sowback listen` runs with: the config file, the environment,
+/// then `--log`, the token and the arguments on top. Returns its sources too
+fn server_config(
+    args: ListenArgs,
+    log: Option<&str>,
+    strict: bool,
+) -> Result<(ServerConfig, Sources)> {
+    let (mut server_config, sources) = server_file(args.config, strict)?;
+    if let Some(log_file) = log {
+        server_config.log_file = Some(log_file.to_string());
+    }
+    if let Some(addr) = args.address {
+        server_config.listen_addr = addr;
+    }
+    if let Some(bind_host) = args.bind {
+        server_config.bind_host = bind_host;
+    }
+    if let Some(auth_token) = resolve_token(args.token, args.token_file.as_deref(), token_env())? {
+        server_config.token = auth_token;
+    }
+    if let Some(name) = args.name {
+        server_config.name = Some(name);
+    }
+    if let Some(workers) = args.workers {
+        server_config.workers = workers;
+    }
+    Ok((server_config, sources))
+}
+
+/// The configuration `sowback connect` runs with: the config file, the environment,
+/// then `--log`, the token and the arguments on top. Returns its sources too
+fn client_config(
+    args: ConnectArgs,
+    log: Option<&str>,
+    strict: bool,
+) -> Result<(ClientConfig, Sources)> {
+    let (mut client_config, sources) = client_file(args.config, strict)?;
+    if let Some(log_file) = log {
+        client_config.log_file = Some(log_file.to_string());
+    }
+    if !args.servers.is_empty() {
+        client_config.servers = args.servers;
+    }
+    if let Some(auth_token) = resolve_token(args.token, args.token_file.as_deref(), token_env())? {
+        client_config.token = auth_token;
+    }
+    if !args.service.is_empty() {
+        client_config.services = args
+            .service
+            .iter()
+            .map(|svc_str| ServiceConfig::parse_cli_ranges(svc_str))
+            .collect::<Result<Vec<Vec<ServiceConfig>>>>()?
+            .concat();
+        client_config.normalize_services()?;
+    }
+    for relay in &args.relay {
+        client_config.relays.push(RelayConfig::parse_cli(relay)?);
+    }
+    if let Some(name) = args.name {
+        client_config.name = Some(name);
+    }
+    if args.renew {
+        client_config.renew = true;
+    }
+    if let Some(workers) = args.workers {
+        client_config.workers = workers;
+    }
+    Ok((client_config, sources))
+}
+
+/// The runtime `sowback listen`, `connect` or `visitor` runs on with `workers` worker
+/// threads, logging which it is
+fn runtime(workers: usize) -> Result<Runtime> {
+    let runtime = build_runtime(workers)?;
+    match runtime.handle().runtime_flavor() {
+        RuntimeFlavor::CurrentThread => log_info!("Running on a single-threaded runtime"),
+        _ => log_info!(
+            "Running on a multi-threaded runtime with {} workers",
+            runtime.metrics().num_workers()
+        ),
+    }
+    Ok(runtime)
+}
+
+impl DaemonArgs {
+    /// With `--daemon`, forks into the background once the config proves valid, `output`
+    /// receiving the console output. Returns the pid file, removed when dropped
+    fn detach(&self, issues: Vec<ConfigIssue>, output: Option<&str>) -> Result<Option<PidFile>> {
+        if !self.daemon {
+            return Ok(None);
+        }
+        // nothing is logged before the fork, so only errors show here; the daemon logs
+        // the warnings once it runs
+        enforce_valid(issues)?;
+        daemonize(
+            self.pid_file.as_deref().map(Path::new),
+            output.map(Path::new),
+        )
+        .map(Some)
+    }
+
+    /// `--stop`: ends the daemon the pid file names
+    fn stop(&self) -> Result<()> {
+        let pid_file = self.pid_file.as_deref().unwrap_or_default();
+        let pid = stop_daemon(Path::new(pid_file))?;
+        println!("Stopped sowback (pid {})", pid);
+        Ok(())
+    }
+}
+
+/// A shutdown token cancelled on SIGTERM, so `--stop`, systemd and the like stop the
+/// server or client gracefully
+fn shutdown_on_sigterm() -> CancellationToken {
+    let shutdown = CancellationToken::new();
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    terminate.recv().await;
+                    log_info!("SIGTERM received, shutting down");
+                    shutdown.cancel();
+                });
+            }
+            Err(e) => {
+                warn!("Cannot listen for SIGTERM: {}", e);
             }
+        }
+    }
+    shutdown
+}
+
+/// Whether `--dashboard` can take over the terminal, warning why not otherwise
+#[cfg(feature = "dashboard")]
+fn dashboard_available(verbosity: Verbosity) -> bool {
+    if !atty::is(atty::Stream::Stdout) {
+        warn!("--dashboard needs a terminal, running without it");
+        return false;
+    }
+    if verbosity.detailed() {
+        warn!("--dashboard replaces the brief console output, which --verbose turns off; running without it");
+        return false;
+    }
+    true
+}
+
+/// `config` as TOML under a `[section]` header, headed by comments naming its sources
+/// from the lowest precedence to the highest
+fn dump_config(section: &str, config: &impl Serialize, sources: &Sources) -> Result<String> {
+    let mut table = toml::Table::new();
+    table.insert(section.to_string(), toml::Value::try_from(config)?);
+    let mut layers = vec!["defaults".to_string()];
+    layers.extend(sources.file.clone());
+    match sources.env.as_slice() {
+        [] => {}
+        env => layers.push(env.join(", ")),
+    }
+    layers.push("command line".to_string());
+    Ok(format!(
+        "# Effective configuration, each source overriding the ones before it:\n# {}\n{}",
+        layers.join(" < "),
+        toml::to_string(&table)?
+    ))
+}
+
+/// Execute entry
+pub fn execute() -> Result<()> {
+    let cli = Cli::parse();
+    let strict = !cli.no_strict_config;
+    let verbosity = Verbosity::from_flags(cli.quiet, cli.verbose);
+    init_color(cli.color);
+    // the config may set the log file, levels and syslog too, so logging starts once it is
+    // loaded, `--log` overriding its log_file. Events before `init_logger` would be dropped,
+    // so nothing is logged until then: loading only collects what it has to report, like
+    // `Sources`, and the errors it fails with reach the terminal through main
+    let log_settings = |config_level, config_filter, syslog| LogSettings {
+        cli_level: cli.log_level,
+        config_level,
+        config_filter,
+        syslog,
+    };
+
+    match cli.command {
+        // server listen
+        Commands::Listen(args) => {
+            if args.daemon.stop {
+                return args.daemon.stop();
+            }
+            let daemon = args.daemon.clone();
+            #[cfg(feature = "dashboard")]
+            let dashboard = args.dashboard;
+            let upgrade_socket = args.upgrade_socket.clone();
+            if upgrade_socket.is_some() && cfg!(not(unix)) {
+                return Err(anyhow::anyhow!(
+                    "--upgrade-socket is only supported on Unix"
+                ));
+            }
+            // started by an upgrade: the old process detached already and holds the pid
+            // file until this one accepts
+            let upgrading = upgrade_socket.is_some() && Server::is_upgrade();
+            let (mut server_config, sources) = server_config(args, cli.log.as_deref(), strict)?;
+            // asked before detaching from the terminal
+            if server_token_missing(&server_config) {
+                server_config.token = ask_token(
+                    cli.no_prompt,
+                    format!(
+                        "Token is required. Please provide --token-file, {} or --token",
+                        TOKEN_ENV
+                    ),
+                )?;
+            }
+            let _pid_file = if upgrading {
+                daemon
+                    .pid_file
+                    .as_deref()
+                    .map(|path| PidFile::adopt(Path::new(path)))
+            } else {
+                daemon.detach(
+                    server_issues(&server_config, false),
+                    server_config.log_file.as_deref(),
+                )?
+            };
+            init_logger(
+                server_config.log_file.clone(),
+                verbosity,
+                &log_settings(
+                    server_config.log_level,
+                    server_config.log_filter.clone(),
+                    server_config.syslog(),
+                ),
+            );
+            sources.log();
 
             log_info!(
                 "Server '{}' listening on {}. Services will bind on {}.",
@@ -124,53 +1205,565 @@ pub async fn execute() -> Result<()> {
                 server_config.bind_host
             );
 
-            let server = Server::new(server_config);
-            server.run().await?;
+            log_debug!("Server configuration: {:?}", server_config.redacted());
+            enforce_valid(server_issues(&server_config, upgrading))?;
+            let pid_file = daemon.pid_file.clone();
+            runtime(server_config.workers)?.block_on(async {
+                let mut server = Server::new(server_config)?;
+                if let Some(path) = upgrade_socket {
+                    server = server.upgrade_socket(path);
+                    if let Some(pid_file) = pid_file {
+                        server = server.pid_file(pid_file);
+                    }
+                }
+                #[cfg(feature = "dashboard")]
+                if dashboard && dashboard_available(verbosity) {
+                    return server.run_with_dashboard(shutdown_on_sigterm()).await;
+                }
+                server.run(shutdown_on_sigterm()).await
+            })?;
         }
         // client connect
-        Commands::Connect {
-            name,
+        Commands::Connect(args) => {
+            if args.daemon.stop {
+                return args.daemon.stop();
+            }
+            let daemon = args.daemon.clone();
+            let once = args.once;
+            let mut reload_args = args.clone();
+            let (mut client_config, sources) = client_config(args, cli.log.as_deref(), strict)?;
+            // asked before detaching from the terminal
+            if client_token_missing(&client_config) {
+                client_config.token = ask_token(cli.no_prompt, client_token_required())?;
+            }
+            let pid_file =
+                daemon.detach(client_config.validate(), client_config.log_file.as_deref())?;
+            init_logger(
+                client_config.log_file.clone(),
+                verbosity,
+                &log_settings(
+                    client_config.log_level,
+                    client_config.log_filter.clone(),
+                    client_config.syslog(),
+                ),
+            );
+            sources.log();
+
+            let client_name = client_config.name.as_deref().unwrap_or("client");
+            let servers: Vec<String> = client_config
+                .connection_entries()
+                .into_iter()
+                .map(|entry| entry.server)
+                .collect();
+            log_info!(
+                "Client '{}' connecting to servers: {:?}",
+                client_name,
+                servers
+            );
+
+            log_debug!("Client configuration: {:?}", client_config.redacted());
+            enforce_valid(client_config.validate())?;
+            // the file found at start is read again on reload, with the same arguments on top
+            let reloader = sources.file.clone().map(|file| {
+                reload_args.config = Some(file);
+                let log = cli.log.clone();
+                move || {
+                    let (config, _) =
+                        self::client_config(reload_args.clone(), log.as_deref(), strict)?;
+                    enforce_valid(config.validate())?;
+                    Ok(config)
+                }
+            });
+            let run = runtime(client_config.workers)?.block_on(async {
+                let mut client = Client::new(client_config)?;
+                if let Some(reloader) = reloader {
+                    client = client.with_reloader(reloader);
+                }
+                if once {
+                    client = client.once();
+                }
+                client.run(shutdown_on_sigterm()).await
+            });
+            exit_on_give_up(run, pid_file)?;
+        }
+        // client visitor
+        Commands::Visitor {
             config,
-            servers,
-            token,
             service,
+            secret,
+            bind,
+            server,
+            token,
+            token_file,
         } => {
-            let mut client_config = if let Some(config_path) = config {
-                Config::from_file(&config_path)?.client.unwrap_or_default()
-            } else {
-                ClientConfig::default()
-            };
+            let (mut client_config, sources) = client_file(config, strict)?;
+            if let Some(log_file) = &cli.log {
+                client_config.log_file = Some(log_file.clone());
+            }
+            init_logger(
+                client_config.log_file.clone(),
+                verbosity,
+                &log_settings(
+                    client_config.log_level,
+                    client_config.log_filter.clone(),
+                    client_config.syslog(),
+                ),
+            );
+            sources.log();
 
-            // Override with command line arguments
-            if !servers.is_empty() {
-                client_config.servers = servers;
+            // a server given here is the only one connected to
+            if let Some(server) = &server {
+                if client_config.connections.is_empty() {
+                    client_config.servers = vec![server.clone()];
+                }
             }
-            if let Some(auth_token) = token {
+            if let Some(auth_token) = resolve_token(token, token_file.as_deref(), token_env())? {
                 client_config.token = auth_token;
-            } else if client_config.token.is_empty() {
-                return Err(anyhow::anyhow!("Token is required. Please provide --token"));
+            } else if client_config.token.is_empty() && client_config.tls.cert.is_none() {
+                client_config.token = ask_token(cli.no_prompt, client_token_required())?;
             }
-            if !service.is_empty() {
-                client_config.services = service
-                    .iter()
-                    .map(|svc_str| ServiceConfig::parse_cli(svc_str))
-                    .collect::<Result<Vec<ServiceConfig>>>()?;
+            client_config.visitors.push(VisitorConfig {
+                service,
+                secret,
+                bind_addr: bind,
+                server,
+            });
+
+            log_debug!("Client configuration: {:?}", client_config.redacted());
+            enforce_valid(client_config.validate())?;
+            runtime(client_config.workers)?.block_on(async {
+                let client = Client::new(client_config)?;
+                client.run(shutdown_on_sigterm()).await
+            })?;
+        }
+        // connectivity check
+        Commands::Ping {
+            config,
+            server,
+            token,
+            token_file,
+            count,
+        } => {
+            let (mut client_config, sources) = client_file(config, strict)?;
+            init_logger(
+                cli.log.clone(),
+                verbosity,
+                &log_settings(
+                    client_config.log_level,
+                    client_config.log_filter.clone(),
+                    None,
+                ),
+            );
+            sources.log();
+            if let Some(auth_token) = resolve_token(token, token_file.as_deref(), token_env())? {
+                client_config.token = auth_token;
+            } else if client_config.token.is_empty() && client_config.tls.cert.is_none() {
+                client_config.token = ask_token(cli.no_prompt, client_token_required())?;
             }
-            if let Some(client_name) = name {
-                client_config.name = Some(client_name);
+            build_runtime(1)?.block_on(ping(client_config, &server, count))?;
+        }
+        // tunnel benchmark
+        Commands::Bench {
+            config,
+            server,
+            token,
+            token_file,
+            duration,
+            streams,
+            payload_size,
+            json,
+        } => {
+            let (mut client_config, sources) = client_file(config, strict)?;
+            // the client's own output would interleave with the results, -v shows it
+            let verbosity = match verbosity {
+                Verbosity::Normal => Verbosity::Quiet,
+                verbosity => verbosity,
+            };
+            init_logger(
+                cli.log.clone(),
+                verbosity,
+                &log_settings(
+                    client_config.log_level,
+                    client_config.log_filter.clone(),
+                    None,
+                ),
+            );
+            sources.log();
+            if let Some(auth_token) = resolve_token(token, token_file.as_deref(), token_env())? {
+                client_config.token = auth_token;
+            } else if client_config.token.is_empty() && client_config.tls.cert.is_none() {
+                client_config.token = ask_token(cli.no_prompt, client_token_required())?;
             }
+            let options = BenchOptions {
+                duration: duration.0,
+                streams: streams.into(),
+                payload_size: payload_size.as_usize(),
+            };
+            let run = runtime(client_config.workers)?.block_on(bench(
+                client_config,
+                &server,
+                options,
+                json,
+            ));
+            exit_on_give_up(run, None)?;
+        }
+        // validate config
+        Commands::Check { config } => {
+            init_logger(cli.log.clone(), verbosity, &log_settings(None, None, None));
+            let Some((config_path, config)) = load_config(config, strict)? else {
+                return Err(anyhow::anyhow!(
+                    "No configuration file given, and none of {} exists",
+                    config_candidates().join(", ")
+                ));
+            };
+            log_info!("Using configuration file {}", config_path);
 
-            let client_name = client_config.name.as_deref().unwrap_or("client");
-            log_info!(
-                "Client '{}' connecting to servers: {:?}",
-                client_name,
-                client_config.servers
-            );
+            let issues = config.validate();
+            for issue in &issues {
+                println!("{}", issue);
+            }
 
-            let client = Client::new(client_config);
-            client.run().await?;
+            let errors = issues.iter().filter(|issue| issue.is_error()).count();
+            let warnings = issues.len() - errors;
+            if errors > 0 {
+                return Err(anyhow::anyhow!(
+                    "{} error(s), {} warning(s)",
+                    errors,
+                    warnings
+                ));
+            }
+            println!("Configuration OK ({} warning(s))", warnings);
+        }
+        // starter config
+        Commands::Init {
+            mode,
+            output,
+            force,
+            ..
+        } => {
+            let token = generate_token();
+            let content = match mode {
+                InitMode::Server => server_template(&token)?,
+                InitMode::Client => client_template(&token)?,
+            };
+            match output {
+                Some(path) => {
+                    write_config(&path, &content, force)?;
+                    println!("Wrote {}", path);
+                }
+                None => print!("{}", content),
+            }
+        }
+        // effective configuration
+        Commands::Config {
+            command: ConfigCommand::Dump { mode },
+        } => {
+            let dump = match mode {
+                DumpMode::Listen(args) => {
+                    let (config, sources) = server_config(args, cli.log.as_deref(), strict)?;
+                    dump_config("server", &config.redacted(), &sources)?
+                }
+                DumpMode::Connect(args) => {
+                    let (config, sources) = client_config(args, cli.log.as_deref(), strict)?;
+                    dump_config("client", &config.redacted(), &sources)?
+                }
+            };
+            print!("{}", dump);
+        }
+        // admin API actions
+        Commands::Admin { target, command } => {
+            init_logger(cli.log.clone(), verbosity, &log_settings(None, None, None));
+            let admin = admin_client(target, strict, cli.no_prompt)?;
+            let message = build_runtime(1)?.block_on(async {
+                anyhow::Ok(match command {
+                    AdminCommand::Clients => clients_table(&admin.clients().await?),
+                    AdminCommand::Ports => ports_table(&admin.ports().await?),
+                    AdminCommand::Kick { client_id } => admin.kick(&client_id).await?,
+                    AdminCommand::Close { connection_id } => admin.close(&connection_id).await?,
+                    AdminCommand::Ban { target, duration } => admin.ban(&target, &duration).await?,
+                })
+            })?;
+            println!("{}", message);
+        }
+        // client control socket actions
+        #[cfg(unix)]
+        Commands::Ctl { target, command } => {
+            init_logger(cli.log.clone(), verbosity, &log_settings(None, None, None));
+            let control = control_client(target, strict)?;
+            let status = matches!(command, CtlCommand::Status);
+            let command = control_command(command)?;
+            let answer = build_runtime(1)?.block_on(control.send(&command))?;
+            match answer.get("message").and_then(serde_json::Value::as_str) {
+                Some(message) if !status => println!("{}", message),
+                _ => println!("{}", serde_json::to_string_pretty(&answer)?),
+            }
+        }
+        // windows service
+        #[cfg(windows)]
+        Commands::Service { command } => {
+            // a running service has no console, it logs to its file once the config is read
+            if !matches!(command, service::ServiceCommand::Run(_)) {
+                init_logger(None, verbosity, &log_settings(None, None, None));
+            }
+            service::execute(command, cli.log.as_deref(), strict, cli.log_level)?;
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_file(content: &str) -> tempfile::TempPath {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), content).unwrap();
+        file.into_temp_path()
+    }
+
+    /// Parses `sowback config dump` followed by `args`
+    fn dump_mode(args: &[&str]) -> DumpMode {
+        let args = ["sowback", "config", "dump"].iter().chain(args);
+        match Cli::parse_from(args).command {
+            Commands::Config {
+                command: ConfigCommand::Dump { mode },
+            } => mode,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_verbosity_flags() {
+        let verbosity = |args: &[&str]| {
+            let args = ["sowback"].iter().chain(args).chain(&["check"]);
+            Cli::try_parse_from(args).map(|cli| Verbosity::from_flags(cli.quiet, cli.verbose))
+        };
+        assert_eq!(verbosity(&[]).unwrap(), Verbosity::Normal);
+        assert_eq!(verbosity(&["-q"]).unwrap(), Verbosity::Quiet);
+        assert_eq!(verbosity(&["--verbose"]).unwrap(), Verbosity::Verbose);
+        assert_eq!(verbosity(&["-vv"]).unwrap(), Verbosity::Debug);
+        assert_eq!(verbosity(&["-v", "-v", "-v"]).unwrap(), Verbosity::Debug);
+
+        let conflict = verbosity(&["-q", "-v"]).unwrap_err();
+        assert_eq!(conflict.kind(), clap::error::ErrorKind::ArgumentConflict);
+        assert!(verbosity(&["--quiet", "-vv"]).is_err());
+    }
+
+    #[test]
+    fn test_bench_output() {
+        let percentiles = |ms: u64| Percentiles {
+            count: 10,
+            min: Duration::from_millis(ms),
+            p50: Duration::from_millis(ms),
+            p90: Duration::from_millis(ms),
+            p99: Duration::from_millis(ms),
+            max: Duration::from_millis(ms),
+        };
+        let throughput = Throughput {
+            bytes: 10 << 20,
+            elapsed: Duration::from_secs(2),
+        };
+        let report = BenchReport {
+            public_addr: "1.2.3.4:40123".to_string(),
+            setup: percentiles(20),
+            latency: percentiles(2),
+            upload: throughput,
+            download: throughput,
+        };
+        let options = BenchOptions {
+            duration: Duration::from_secs(2),
+            streams: 4,
+            payload_size: 16 * 1024,
+        };
+        let table = bench_table(&report, &options);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(
+            lines[0],
+            "Benchmark through 1.2.3.4:40123: 4 streams of 16.0 KiB payloads, 2.0s each"
+        );
+        assert!(
+            lines[2].starts_with("setup          10    20.0ms"),
+            "{}",
+            lines[2]
+        );
+        assert_eq!(
+            lines[4],
+            "upload   5.0 MiB/s (41.9 Mbit/s), 10.0 MiB in 2.0s"
+        );
+
+        let json = bench_json(&report, &options);
+        assert_eq!(json["setup_ms"]["p99"], 20.0);
+        assert_eq!(json["download"]["bits_per_second"], 41_943_040.0);
+        assert_eq!(json["streams"], 4);
+
+        let parsed = Cli::try_parse_from([
+            "sowback",
+            "bench",
+            "--server",
+            "1.2.3.4:7000",
+            "-d",
+            "10s",
+            "-P",
+            "8",
+        ])
+        .unwrap();
+        let Commands::Bench {
+            duration,
+            streams,
+            payload_size,
+            ..
+        } = parsed.command
+        else {
+            panic!("not a bench command");
+        };
+        assert_eq!(duration.0, Duration::from_secs(10));
+        assert_eq!(streams, 8);
+        assert_eq!(payload_size.as_usize(), 16 * 1024);
+        assert!(Cli::try_parse_from(["sowback", "bench", "--server", "x:1", "-P", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_ports_table() {
+        let listing = serde_json::json!({ "ports": [{
+            "port": 8080,
+            "bind_host": "0.0.0.0",
+            "client_id": "7046c8b3-b9ef-4fe9-abcf-68e5b1b79eb7",
+            "client_name": "laptop",
+            "group": null,
+            "proxies": ["0b7c1d6e-2f3a-4c5d-8e9f-a0b1c2d3e4f5"],
+            "orphaned": false,
+            "uptime_secs": 3_725,
+        }]});
+        assert_eq!(
+            ports_table(&listing),
+            ":8080  0.0.0.0  client 7046c8b3 (laptop)  proxy 0b7c1d6e  up 1h 2m"
+        );
+        assert_eq!(
+            ports_table(&serde_json::json!({ "ports": [] })),
+            "No ports bound"
+        );
+
+        assert_eq!(format_uptime(0), "0s");
+        assert_eq!(format_uptime(59), "59s");
+        assert_eq!(format_uptime(86_400 + 5), "1d");
+        assert_eq!(format_uptime(90), "1m 30s");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_ctl_add_service_takes_mappings_or_tables() {
+        let command = |service: &str| {
+            let cli =
+                Cli::try_parse_from(["sowback", "ctl", "add-service", service, "--server", "a:1"])
+                    .unwrap();
+            let Commands::Ctl { command, .. } = cli.command else {
+                panic!("expected ctl");
+            };
+            control_command(command)
+        };
+        assert!(matches!(
+            command("22:2222").unwrap(),
+            ControlCommand::AddService {
+                service: ServiceSpec::Mapping(_),
+                server: Some(_),
+            }
+        ));
+        assert!(matches!(
+            command(r#"{"name": "ssh", "local_port": 22}"#).unwrap(),
+            ControlCommand::AddService {
+                service: ServiceSpec::Table(_),
+                ..
+            }
+        ));
+        let error = command(r#"{"name": "ssh""#).unwrap_err();
+        assert!(error.to_string().contains("Invalid service table"));
+    }
+
+    #[test]
+    fn test_dump_merges_overrides_and_redacts() {
+        let file = config_file(
+            r#"
+            [client]
+            servers = ["1.2.3.4:7000"]
+            token = "file-secret"
+            reconnect_interval = 5
+            heartbeat_interval = 30
+
+            [[client.services]]
+            name = "ssh"
+            local_port = 22
+            secret = "service-secret"
+            "#,
+        );
+        let DumpMode::Connect(args) = dump_mode(&[
+            "connect",
+            "--config",
+            file.to_str().unwrap(),
+            "--token",
+            "cli-secret",
+            "--name",
+            "laptop",
+            "5.6.7.8:7000",
+        ]) else {
+            unreachable!()
+        };
+        let (config, sources) = client_config(args, Some("/tmp/sowback.log"), true).unwrap();
+        assert_eq!(sources.file.as_deref(), file.to_str());
+        assert_eq!(config.token, "cli-secret");
+        assert_eq!(config.servers, ["5.6.7.8:7000"]);
+
+        let dump = dump_config("client", &config.redacted(), &sources).unwrap();
+        assert!(
+            dump.contains(&format!("# defaults < {} < command line\n", file.display())),
+            "{}",
+            dump
+        );
+        assert!(!dump.contains("secret\""), "{}", dump);
+        assert!(dump.contains(r#"token = "<redacted>""#), "{}", dump);
+        assert!(dump.contains(r#"secret = "<redacted>""#), "{}", dump);
+        assert!(dump.contains(r#"name = "laptop""#), "{}", dump);
+        assert!(
+            dump.contains(r#"log_file = "/tmp/sowback.log""#),
+            "{}",
+            dump
+        );
+        assert!(dump.contains(r#"reconnect_interval = "5s""#), "{}", dump);
+
+        // the dump is a config file itself
+        let reread = config_file(&dump);
+        let reread = Config::from_file(reread.to_str().unwrap()).unwrap();
+        assert_eq!(reread.client.unwrap().services[0].local_port, 22);
+    }
+
+    #[test]
+    fn test_dump_without_a_file() {
+        let DumpMode::Listen(args) =
+            dump_mode(&["listen", "--config", "/nonexistent/sowback.toml"])
+        else {
+            unreachable!()
+        };
+        let err = server_config(args, None, true).unwrap_err().to_string();
+        assert!(err.contains("/nonexistent/sowback.toml"), "{}", err);
+
+        let DumpMode::Listen(args) =
+            dump_mode(&["listen", "0.0.0.0:7100", "--token", "cli-secret"])
+        else {
+            unreachable!()
+        };
+        let (config, _) = server_config(args, None, true).unwrap();
+        assert_eq!(config.listen_addr, "0.0.0.0:7100");
+        let sources = Sources {
+            file: None,
+            env: vec!["SOWBACK_SERVER_TOKEN".to_string()],
+        };
+        let dump = dump_config("server", &config.redacted(), &sources).unwrap();
+        assert!(
+            dump.contains("# defaults < SOWBACK_SERVER_TOKEN < command line\n"),
+            "{}",
+            dump
+        );
+        assert!(dump.contains("[server]"), "{}", dump);
+        assert!(!dump.contains("cli-secret"), "{}", dump);
+    }
+}
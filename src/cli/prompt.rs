@@ -0,0 +1,160 @@
+use anyhow::{anyhow, Result};
+use std::io::{self, BufRead, Write};
+
+/// Entries read before giving up when the token entered is empty
+const ATTEMPTS: usize = 3;
+
+/// The token for a command that has none: read from the terminal when `interactive`,
+/// else `missing` as the error so scripts fail fast
+pub fn missing_token(interactive: bool, missing: String) -> Result<String> {
+    token_with(interactive, missing, || read_hidden("Token: "))
+}
+
+/// `missing_token` with the terminal check done and entries read with `read`
+fn token_with(
+    interactive: bool,
+    missing: String,
+    mut read: impl FnMut() -> Result<Option<String>>,
+) -> Result<String> {
+    if !interactive {
+        return Err(anyhow!(missing));
+    }
+    for _ in 0..ATTEMPTS {
+        let Some(entry) = read()? else {
+            return Err(anyhow!("No token entered"));
+        };
+        let token = entry.trim();
+        if !token.is_empty() {
+            return Ok(token.to_string());
+        }
+        eprintln!("The token must not be empty");
+    }
+    Err(anyhow!("No token entered after {} attempts", ATTEMPTS))
+}
+
+/// Reads a line from the terminal without echoing it, None at end of input
+fn read_hidden(prompt: &str) -> Result<Option<String>> {
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+    let _echo = EchoOff::new()?;
+    let mut line = String::new();
+    let read = io::stdin().lock().read_line(&mut line)?;
+    Ok((read > 0).then_some(line))
+}
+
+/// Terminal echo turned off on stdin until dropped
+struct EchoOff {
+    #[cfg(unix)]
+    original: libc::termios,
+    #[cfg(windows)]
+    original: windows_sys::Win32::System::Console::CONSOLE_MODE,
+}
+
+impl EchoOff {
+    #[cfg(unix)]
+    fn new() -> Result<Self> {
+        let mut original = std::mem::MaybeUninit::<libc::termios>::uninit();
+        // SAFETY: tcgetattr fills the termios it is given when it succeeds
+        let original = unsafe {
+            if libc::tcgetattr(libc::STDIN_FILENO, original.as_mut_ptr()) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            original.assume_init()
+        };
+        let mut hidden = original;
+        // the newline is still echoed, so output after the prompt starts on its own line
+        hidden.c_lflag &= !libc::ECHO;
+        hidden.c_lflag |= libc::ECHONL;
+        // SAFETY: hidden is a valid termios taken from tcgetattr
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &hidden) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Self { original })
+    }
+
+    #[cfg(windows)]
+    fn new() -> Result<Self> {
+        use std::os::windows::io::AsRawHandle;
+        use windows_sys::Win32::System::Console::{
+            GetConsoleMode, SetConsoleMode, ENABLE_ECHO_INPUT,
+        };
+
+        let console = io::stdin().as_raw_handle();
+        let mut original = 0;
+        // SAFETY: console is the process's stdin handle, GetConsoleMode writes the mode
+        // it is given when it succeeds
+        unsafe {
+            if GetConsoleMode(console, &mut original) == 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            if SetConsoleMode(console, original & !ENABLE_ECHO_INPUT) == 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+        Ok(Self { original })
+    }
+
+    /// Without a way to hide what is typed the token is not asked for at all
+    #[cfg(not(any(unix, windows)))]
+    fn new() -> Result<Self> {
+        Err(anyhow!(
+            "Cannot turn off echo on this terminal, pass the token with --token-file"
+        ))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        // SAFETY: original is the termios stdin had before
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        use std::os::windows::io::AsRawHandle;
+        // SAFETY: original is the mode stdin's console had before
+        unsafe {
+            windows_sys::Win32::System::Console::SetConsoleMode(
+                io::stdin().as_raw_handle(),
+                self.original,
+            );
+        }
+        // the newline typed was not echoed, output after the prompt starts on its own line
+        eprintln!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(entries: &[&str]) -> impl FnMut() -> Result<Option<String>> {
+        let mut entries: Vec<String> = entries.iter().rev().map(|e| e.to_string()).collect();
+        move || Ok(entries.pop())
+    }
+
+    #[test]
+    fn test_missing_token_fallback() {
+        let missing = || "Token is required".to_string();
+        // scripts fail fast without reading anything
+        let err = token_with(false, missing(), || unreachable!()).unwrap_err();
+        assert_eq!(err.to_string(), "Token is required");
+
+        let token = token_with(true, missing(), entries(&["  secret-token \n"])).unwrap();
+        assert_eq!(token, "secret-token");
+
+        // empty entries are asked again, up to three times
+        let token = token_with(true, missing(), entries(&["\n", " \n", "third\n"])).unwrap();
+        assert_eq!(token, "third");
+        let err = token_with(true, missing(), entries(&["\n", "\n", "\n", "late\n"])).unwrap_err();
+        assert_eq!(err.to_string(), "No token entered after 3 attempts");
+
+        let err = token_with(true, missing(), entries(&[])).unwrap_err();
+        assert_eq!(err.to_string(), "No token entered");
+    }
+}
@@ -0,0 +1,507 @@
+use anyhow::{anyhow, Result};
+use clap::{Args, Subcommand, ValueEnum};
+use sowback::config::Config;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Name the service is registered under without `--service-name`
+const DEFAULT_SERVICE_NAME: &str = "sowback";
+
+#[derive(Subcommand)]
+pub(super) enum ServiceCommand {
+    /// Register sowback with the service manager, started at boot with the given config
+    Install(ServiceArgs),
+    /// Stop the service and remove it from the service manager
+    Uninstall {
+        /// Name of the service in the service manager
+        #[arg(long, default_value = DEFAULT_SERVICE_NAME)]
+        service_name: String,
+    },
+    /// Run under the service manager, which starts sowback this way once it is installed
+    #[command(hide = true)]
+    Run(ServiceArgs),
+}
+
+#[derive(Args)]
+pub(super) struct ServiceArgs {
+    /// Configuration file path
+    #[arg(short, long)]
+    config: String,
+
+    /// Whether the service runs the server or the client, by the config's [server] or
+    /// [client] table when absent
+    #[arg(long, value_enum)]
+    mode: Option<ServiceMode>,
+
+    /// Name of the service in the service manager
+    #[arg(long, default_value = DEFAULT_SERVICE_NAME)]
+    service_name: String,
+}
+
+/// What a service runs, like `sowback listen` or `sowback connect`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(super) enum ServiceMode {
+    Listen,
+    Connect,
+}
+
+/// The mode `mode` asks for, else the one the config has a table for
+fn service_mode(config: &Config, mode: Option<ServiceMode>) -> Result<ServiceMode> {
+    if let Some(mode) = mode {
+        return Ok(mode);
+    }
+    match (&config.server, &config.client) {
+        (Some(_), None) => Ok(ServiceMode::Listen),
+        (None, Some(_)) => Ok(ServiceMode::Connect),
+        (Some(_), Some(_)) => Err(anyhow!(
+            "The config has both [server] and [client], pass --mode listen or --mode connect"
+        )),
+        (None, None) => Err(anyhow!("The config has neither [server] nor [client]")),
+    }
+}
+
+/// The arguments the service manager starts the binary with: `service run` and everything
+/// it needs, paths absolute since services start in the system directory
+fn launch_arguments(
+    config: &Path,
+    mode: ServiceMode,
+    service_name: &str,
+    log: Option<&Path>,
+    strict: bool,
+) -> Vec<OsString> {
+    let mode = match mode {
+        ServiceMode::Listen => "listen",
+        ServiceMode::Connect => "connect",
+    };
+    let mut arguments: Vec<OsString> = vec![
+        "service".into(),
+        "run".into(),
+        "--config".into(),
+        config.into(),
+        "--mode".into(),
+        mode.into(),
+        "--service-name".into(),
+        service_name.into(),
+    ];
+    if let Some(log) = log {
+        arguments.extend(["--log".into(), log.into()]);
+    }
+    if !strict {
+        arguments.push("--no-strict-config".into());
+    }
+    arguments
+}
+
+/// Where a service logs without `--log` or `log_file`: next to its config, as it has no
+/// console to print to
+fn default_log_file(config: &Path) -> PathBuf {
+    config.with_file_name("sowback.log")
+}
+
+#[cfg(windows)]
+pub(super) use windows::execute;
+
+#[cfg(windows)]
+mod windows {
+    use super::*;
+    use crate::cli::{
+        client_file, client_token_missing, client_token_required, enforce_valid, runtime,
+        server_file, server_issues, server_token_missing,
+    };
+    use sowback::logging::{init_file_logger, LogLevel, LogSettings};
+    use sowback::{log_error, log_info, CancellationToken, Client, Server};
+    use std::sync::OnceLock;
+    use std::time::Duration;
+    use windows_service::service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    /// What `service run` was started with, for the service's main function, which the
+    /// service manager calls without it
+    struct Launch {
+        args: ServiceArgs,
+        log: Option<String>,
+        strict: bool,
+        log_level: Option<LogLevel>,
+    }
+
+    static LAUNCH: OnceLock<Launch> = OnceLock::new();
+
+    /// Runs `sowback service` with the global `--log`, `--no-strict-config` and
+    /// `--log-level`
+    pub(in crate::cli) fn execute(
+        command: ServiceCommand,
+        log: Option<&str>,
+        strict: bool,
+        log_level: Option<LogLevel>,
+    ) -> Result<()> {
+        match command {
+            ServiceCommand::Install(args) => install(args, log, strict),
+            ServiceCommand::Uninstall { service_name } => uninstall(&service_name),
+            ServiceCommand::Run(args) => {
+                let service_name = args.service_name.clone();
+                let launch = Launch {
+                    args,
+                    log: log.map(str::to_string),
+                    strict,
+                    log_level,
+                };
+                if LAUNCH.set(launch).is_err() {
+                    return Err(anyhow!("The service is running already"));
+                }
+                // blocks until the service stopped
+                service_dispatcher::start(&service_name, ffi_service_main).map_err(|e| {
+                    anyhow!(
+                        "Cannot reach the service manager, `service run` is for it to start: {}",
+                        e
+                    )
+                })
+            }
+        }
+    }
+
+    fn install(args: ServiceArgs, log: Option<&str>, strict: bool) -> Result<()> {
+        let config = std::path::absolute(&args.config)?;
+        let loaded = Config::load(&config.to_string_lossy(), strict)?;
+        let mode = service_mode(&loaded, args.mode)?;
+        enforce_valid(loaded.validate())?;
+        let log = log.map(std::path::absolute).transpose()?;
+
+        let kind = match mode {
+            ServiceMode::Listen => "Server",
+            ServiceMode::Connect => "Client",
+        };
+        let info = ServiceInfo {
+            name: args.service_name.clone().into(),
+            display_name: format!("Sowback Tunnel {} ({})", kind, args.service_name).into(),
+            service_type: ServiceType::OWN_PROCESS,
+            // started at boot, without anyone logging in
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: std::env::current_exe()?,
+            launch_arguments: launch_arguments(
+                &config,
+                mode,
+                &args.service_name,
+                log.as_deref(),
+                strict,
+            ),
+            dependencies: vec![],
+            // LocalSystem
+            account_name: None,
+            account_password: None,
+        };
+        let manager = ServiceManager::local_computer(
+            None::<&str>,
+            ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+        )
+        .map_err(|e| {
+            anyhow!(
+                "Cannot open the service manager, run as Administrator: {}",
+                e
+            )
+        })?;
+        let service = manager
+            .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+            .map_err(|e| anyhow!("Cannot install service {}: {}", args.service_name, e))?;
+        service.set_description(format!(
+            "Multi-server reverse proxy, configured by {}",
+            config.display()
+        ))?;
+        println!(
+            "Installed service {}, it starts at boot or with `sc start {}`",
+            args.service_name, args.service_name
+        );
+        Ok(())
+    }
+
+    fn uninstall(service_name: &str) -> Result<()> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+            .map_err(|e| {
+            anyhow!(
+                "Cannot open the service manager, run as Administrator: {}",
+                e
+            )
+        })?;
+        let service = manager
+            .open_service(
+                service_name,
+                ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+            )
+            .map_err(|e| anyhow!("Cannot open service {}: {}", service_name, e))?;
+        if service.query_status()?.current_state != ServiceState::Stopped {
+            service.stop()?;
+        }
+        service.delete()?;
+        println!("Uninstalled service {}", service_name);
+        Ok(())
+    }
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    /// The service's main function, called by the service manager on a thread of its own
+    fn service_main(_arguments: Vec<OsString>) {
+        let Some(launch) = LAUNCH.get() else {
+            return;
+        };
+        let shutdown = CancellationToken::new();
+        let stop = shutdown.clone();
+        // stopping the service and shutting Windows down take the graceful shutdown path
+        let handler = move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                stop.cancel();
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        };
+        let Ok(status) = service_control_handler::register(&launch.args.service_name, handler)
+        else {
+            return;
+        };
+
+        let _ = status.set_service_status(service_status(
+            ServiceState::Running,
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            ServiceExitCode::NO_ERROR,
+        ));
+        let exit_code = match run(launch, shutdown) {
+            Ok(()) => ServiceExitCode::NO_ERROR,
+            Err(e) => {
+                log_error!("Service {} failed: {:#}", launch.args.service_name, e);
+                ServiceExitCode::ServiceSpecific(1)
+            }
+        };
+        let _ = status.set_service_status(service_status(
+            ServiceState::Stopped,
+            ServiceControlAccept::empty(),
+            exit_code,
+        ));
+    }
+
+    fn service_status(
+        state: ServiceState,
+        controls_accepted: ServiceControlAccept,
+        exit_code: ServiceExitCode,
+    ) -> ServiceStatus {
+        ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted,
+            exit_code,
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        }
+    }
+
+    /// Runs the server or client of `launch` until `shutdown`, logging to its file only
+    fn run(launch: &Launch, shutdown: CancellationToken) -> Result<()> {
+        let config = &launch.args.config;
+        let log_file = |configured: &mut Option<String>| {
+            if let Some(log) = &launch.log {
+                *configured = Some(log.clone());
+            }
+            configured
+                .get_or_insert_with(|| {
+                    default_log_file(Path::new(config))
+                        .to_string_lossy()
+                        .into_owned()
+                })
+                .clone()
+        };
+        let log_settings = |config_level, config_filter, syslog| LogSettings {
+            cli_level: launch.log_level,
+            config_level,
+            config_filter,
+            syslog,
+        };
+
+        let mode = match launch.args.mode {
+            Some(mode) => mode,
+            None => service_mode(&Config::load(config, launch.strict)?, None)?,
+        };
+        match mode {
+            ServiceMode::Listen => {
+                let (mut server_config, sources) =
+                    server_file(Some(config.clone()), launch.strict)?;
+                init_file_logger(
+                    log_file(&mut server_config.log_file),
+                    &log_settings(
+                        server_config.log_level,
+                        server_config.log_filter.clone(),
+                        server_config.syslog(),
+                    ),
+                );
+                sources.log();
+                if server_token_missing(&server_config) {
+                    return Err(anyhow!("Token is required. Please set it in {}", config));
+                }
+                enforce_valid(server_issues(&server_config, false))?;
+                log_info!(
+                    "Service {} listening on {}",
+                    launch.args.service_name,
+                    server_config.listen_addr
+                );
+                runtime(server_config.workers)?.block_on(async {
+                    let server = Server::new(server_config)?;
+                    server.run(shutdown).await
+                })
+            }
+            ServiceMode::Connect => {
+                let (mut client_config, sources) =
+                    client_file(Some(config.clone()), launch.strict)?;
+                init_file_logger(
+                    log_file(&mut client_config.log_file),
+                    &log_settings(
+                        client_config.log_level,
+                        client_config.log_filter.clone(),
+                        client_config.syslog(),
+                    ),
+                );
+                sources.log();
+                if client_token_missing(&client_config) {
+                    return Err(anyhow!(client_token_required()));
+                }
+                enforce_valid(client_config.validate())?;
+                let servers: Vec<String> = client_config
+                    .connection_entries()
+                    .into_iter()
+                    .map(|entry| entry.server)
+                    .collect();
+                log_info!(
+                    "Service {} connecting to servers: {:?}",
+                    launch.args.service_name,
+                    servers
+                );
+                runtime(client_config.workers)?.block_on(async {
+                    let client = Client::new(client_config)?;
+                    client.run(shutdown).await
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use sowback::config::{ClientConfig, ServerConfig};
+
+    /// The part of the command line the service manager starts sowback with
+    #[derive(Parser)]
+    struct Launched {
+        #[arg(long, global = true)]
+        log: Option<String>,
+
+        #[arg(long, global = true)]
+        no_strict_config: bool,
+
+        #[command(subcommand)]
+        command: LaunchedCommand,
+    }
+
+    #[derive(Subcommand)]
+    enum LaunchedCommand {
+        Service {
+            #[command(subcommand)]
+            command: ServiceCommand,
+        },
+    }
+
+    fn config(server: bool, client: bool) -> Config {
+        Config {
+            strict: true,
+            server: server.then(ServerConfig::default),
+            client: client.then(ClientConfig::default),
+            ignored_keys: vec![],
+        }
+    }
+
+    #[test]
+    fn test_service_mode_follows_the_config() {
+        let both = config(true, true);
+        assert_eq!(
+            service_mode(&config(true, false), None).unwrap(),
+            ServiceMode::Listen
+        );
+        assert_eq!(
+            service_mode(&config(false, true), None).unwrap(),
+            ServiceMode::Connect
+        );
+        assert!(service_mode(&both, None).is_err());
+        assert!(service_mode(&config(false, false), None).is_err());
+        // --mode settles it
+        assert_eq!(
+            service_mode(&both, Some(ServiceMode::Connect)).unwrap(),
+            ServiceMode::Connect
+        );
+    }
+
+    #[test]
+    fn test_install_parses_its_arguments() {
+        let cli = Launched::parse_from([
+            "sowback",
+            "service",
+            "install",
+            "--config",
+            r"C:\sowback\client.toml",
+        ]);
+        let LaunchedCommand::Service {
+            command: ServiceCommand::Install(args),
+        } = cli.command
+        else {
+            panic!("expected service install");
+        };
+        assert_eq!(args.config, r"C:\sowback\client.toml");
+        assert_eq!(args.mode, None);
+        assert_eq!(args.service_name, DEFAULT_SERVICE_NAME);
+
+        let cli = Launched::parse_from(["sowback", "service", "uninstall", "--service-name", "b"]);
+        assert!(matches!(
+            cli.command,
+            LaunchedCommand::Service {
+                command: ServiceCommand::Uninstall { service_name }
+            } if service_name == "b"
+        ));
+    }
+
+    #[test]
+    fn test_launch_arguments_parse_back_into_run() {
+        let config = Path::new("/etc/sowback/client.toml");
+        let log = Path::new("/var/log/sowback.log");
+        let arguments = launch_arguments(config, ServiceMode::Connect, "tunnels", Some(log), false);
+
+        let cli = Launched::parse_from(std::iter::once(OsString::from("sowback")).chain(arguments));
+        assert_eq!(cli.log.as_deref(), Some("/var/log/sowback.log"));
+        assert!(cli.no_strict_config);
+        let LaunchedCommand::Service {
+            command: ServiceCommand::Run(args),
+        } = cli.command
+        else {
+            panic!("expected service run");
+        };
+        assert_eq!(args.config, "/etc/sowback/client.toml");
+        assert_eq!(args.mode, Some(ServiceMode::Connect));
+        assert_eq!(args.service_name, "tunnels");
+
+        // nothing is passed on that install was not given
+        let arguments = launch_arguments(config, ServiceMode::Listen, "sowback", None, true);
+        assert!(!arguments.contains(&"--log".into()));
+        assert!(!arguments.contains(&"--no-strict-config".into()));
+        assert!(arguments.contains(&"listen".into()));
+    }
+
+    #[test]
+    fn test_services_log_next_to_their_config() {
+        assert_eq!(
+            default_log_file(Path::new("/etc/sowback/client.toml")),
+            Path::new("/etc/sowback/sowback.log")
+        );
+    }
+}
@@ -0,0 +1,476 @@
+use anyhow::{anyhow, Result};
+use futures_util::future::join_all;
+use std::future::Future;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout, timeout_at, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+use super::Client;
+use crate::config::{ClientConfig, HumanDuration, ServiceConfig};
+use crate::utils::net;
+use crate::{log_debug, log_info, warn};
+
+/// Byte the bench service answers the first byte of a connection with, once reached
+const READY: u8 = b'r';
+/// First byte of a connection to the bench service: echo what follows
+const MODE_ECHO: u8 = b'e';
+/// First byte of a connection to the bench service: read until the end, then answer with
+/// the number of bytes read as a big endian `u64`
+const MODE_SINK: u8 = b's';
+/// First byte of a connection to the bench service: write payloads of the big endian
+/// `u32` size that follows until the peer ends its side
+const MODE_SOURCE: u8 = b'g';
+
+/// Largest payload the bench service writes
+const MAX_SOURCE_PAYLOAD: usize = 16 * 1024 * 1024;
+/// Connections opened one after the other to time connection setup
+const SETUP_SAMPLES: usize = 10;
+/// Size of the messages whose round trips are timed
+const LATENCY_MESSAGE: usize = 64;
+/// How long the server may take to assign the bench service a port
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a connection through the tunnel or a round trip may take before the run fails
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the public port may stay open once the bench service was removed
+const UNREGISTER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Parameters of `Client::bench`
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// How long the latency, upload and download measurements each run
+    pub duration: Duration,
+    /// Connections transferring data at once during the upload and download
+    pub streams: usize,
+    /// Size of each write during the upload and download
+    pub payload_size: usize,
+}
+
+/// Distribution of timed samples
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Percentiles {
+    /// Number of samples
+    pub count: usize,
+    /// Fastest sample
+    pub min: Duration,
+    /// Median
+    pub p50: Duration,
+    /// 90th percentile
+    pub p90: Duration,
+    /// 99th percentile
+    pub p99: Duration,
+    /// Slowest sample
+    pub max: Duration,
+}
+
+impl Percentiles {
+    /// Summarizes `samples` by nearest rank, None without any
+    pub fn of(samples: &[Duration]) -> Option<Self> {
+        let mut sorted = samples.to_vec();
+        sorted.sort();
+        let rank = |percent: usize| sorted[(percent * sorted.len()).div_ceil(100).max(1) - 1];
+        Some(Self {
+            count: sorted.len(),
+            min: *sorted.first()?,
+            p50: rank(50),
+            p90: rank(90),
+            p99: rank(99),
+            max: *sorted.last()?,
+        })
+    }
+}
+
+/// Bytes moved in a time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Throughput {
+    /// Bytes that reached the other end
+    pub bytes: u64,
+    /// Time they took
+    pub elapsed: Duration,
+}
+
+impl Throughput {
+    /// Bytes per second, 0 when no time passed
+    pub fn bytes_per_second(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.bytes as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    /// Streams running side by side: their bytes over the time of the slowest
+    fn combined(streams: &[Self]) -> Self {
+        Self {
+            bytes: streams.iter().map(|stream| stream.bytes).sum(),
+            elapsed: streams
+                .iter()
+                .map(|stream| stream.elapsed)
+                .max()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// What `Client::bench` measured
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    /// Public address of the bench service on the server
+    pub public_addr: String,
+    /// Time from connecting to the public port to the first answer of the local service
+    pub setup: Percentiles,
+    /// Round trips of small messages on one connection
+    pub latency: Percentiles,
+    /// Data sent to the public port and received by the local service
+    pub upload: Throughput,
+    /// Data sent by the local service and received from the public port
+    pub download: Throughput,
+}
+
+impl Client {
+    /// Measures a tunnel through `server_addr` with the client and server as they run:
+    /// exposes a local bench service on a port the server assigns, drives traffic through
+    /// that public port and removes the service again. `stop` ends the run early
+    pub async fn bench(
+        mut config: ClientConfig,
+        server_addr: &str,
+        options: &BenchOptions,
+        stop: CancellationToken,
+    ) -> Result<BenchReport> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let local_port = listener.local_addr()?.port();
+        let service_task = tokio::spawn(serve_bench(listener));
+
+        let mut service = ServiceConfig::parse_cli(&format!("127.0.0.1:{}:0", local_port))?;
+        service.name = format!("bench-{}", local_port);
+        service.health_check = false;
+        // the bench service is the only thing the client does
+        config.servers = vec![server_addr.to_string()];
+        config.connections.clear();
+        config.services = vec![service.clone()];
+        config.visitors.clear();
+        config.relays.clear();
+        config.control_socket = None;
+        config.status_interval = HumanDuration::default();
+        let client = Client::new(config)?.once();
+
+        let shutdown = CancellationToken::new();
+        let mut running = tokio::spawn({
+            let client = client.clone();
+            let shutdown = shutdown.clone();
+            async move { client.run(shutdown).await }
+        });
+        let mut ended = false;
+        let outcome = tokio::select! {
+            outcome = client.bench_through(server_addr, &service.name, options) => outcome,
+            result = &mut running => {
+                ended = true;
+                Err(match result {
+                    Ok(Err(e)) => e,
+                    Ok(Ok(())) => anyhow!("The client stopped"),
+                    Err(e) => e.into(),
+                })
+            }
+            _ = stop.cancelled() => Err(anyhow!("Benchmark interrupted")),
+        };
+
+        if let Some(port) = client.assigned_port(server_addr, &service.name).await {
+            client
+                .remove_bench_service(server_addr, &service, port)
+                .await;
+            let public_addr = net::join_host_port(server_host(server_addr), port);
+            if !ended && !port_closed(&public_addr).await {
+                warn!(
+                    "{} still accepts connections after removing the bench service",
+                    public_addr
+                );
+            }
+        }
+        shutdown.cancel();
+        if !ended {
+            running.await??;
+        }
+        service_task.abort();
+        outcome
+    }
+
+    /// Asks `server_addr` to remove the bench service from `port`. The client goes on
+    /// serving it, connections racing the removal reach the bench service
+    async fn remove_bench_service(&self, server_addr: &str, service: &ServiceConfig, port: u16) {
+        let mut connections = self.connections.lock().await;
+        if let Some(conn) = connections.get_mut(server_addr) {
+            let request = conn.registrations.unregister(service, port);
+            let _ = conn.sender.send(request);
+        }
+    }
+
+    /// Waits for the server to assign the port of the bench service `name`, then measures
+    /// through it
+    async fn bench_through(
+        &self,
+        server_addr: &str,
+        name: &str,
+        options: &BenchOptions,
+    ) -> Result<BenchReport> {
+        let deadline = Instant::now() + REGISTER_TIMEOUT;
+        let port = loop {
+            if let Some(port) = self.assigned_port(server_addr, name).await {
+                break port;
+            }
+            if Instant::now() >= deadline {
+                return Err(anyhow!(
+                    "{} did not assign the bench service a port within {:?}",
+                    server_addr,
+                    REGISTER_TIMEOUT
+                ));
+            }
+            sleep(Duration::from_millis(50)).await;
+        };
+        let public_addr = net::join_host_port(server_host(server_addr), port);
+        log_info!("Benchmarking through {}", public_addr);
+
+        let setup = measure_setup(&public_addr).await?;
+        log_debug!("Connection setup: {:?}", setup);
+        let latency = measure_latency(&public_addr, options.duration).await?;
+        log_debug!("Latency: {:?}", latency);
+        let upload = in_parallel(options.streams, || {
+            upload(public_addr.clone(), options.duration, options.payload_size)
+        })
+        .await?;
+        let download = in_parallel(options.streams, || {
+            download(public_addr.clone(), options.duration, options.payload_size)
+        })
+        .await?;
+        Ok(BenchReport {
+            public_addr,
+            setup,
+            latency,
+            upload,
+            download,
+        })
+    }
+}
+
+/// Host part of `host:port`, brackets of an IPv6 literal included
+fn server_host(server_addr: &str) -> &str {
+    server_addr
+        .rsplit_once(':')
+        .map_or(server_addr, |(host, _)| host)
+}
+
+/// Whether connections to `addr` are refused within `UNREGISTER_TIMEOUT`
+async fn port_closed(addr: &str) -> bool {
+    let deadline = Instant::now() + UNREGISTER_TIMEOUT;
+    while Instant::now() < deadline {
+        if TcpStream::connect(addr).await.is_err() {
+            return true;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    false
+}
+
+/// Accepts the connections of the bench service until aborted
+async fn serve_bench(listener: TcpListener) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        tokio::spawn(async move {
+            if let Err(e) = bench_connection(stream).await {
+                log_debug!("Bench connection ended: {}", e);
+            }
+        });
+    }
+}
+
+/// Serves a connection to the bench service as its first byte says
+async fn bench_connection(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mode = stream.read_u8().await?;
+    stream.write_u8(READY).await?;
+    let (mut reader, mut writer) = stream.split();
+    match mode {
+        MODE_ECHO => {
+            io::copy(&mut reader, &mut writer).await?;
+        }
+        MODE_SINK => {
+            let received = io::copy(&mut reader, &mut io::sink()).await?;
+            writer.write_u64(received).await?;
+        }
+        MODE_SOURCE => {
+            let size = reader.read_u32().await? as usize;
+            let payload = vec![0; size.clamp(1, MAX_SOURCE_PAYLOAD)];
+            let mut end = [0; 1];
+            loop {
+                tokio::select! {
+                    read = reader.read(&mut end) => {
+                        read?;
+                        break;
+                    }
+                    written = writer.write_all(&payload) => written?,
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Connects to the bench service through `addr`, asking for `mode`, and waits until the
+/// service answered. The server closes connections sending much before that
+async fn open(addr: &str, mode: u8) -> Result<TcpStream> {
+    let connect = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        stream.write_u8(mode).await?;
+        match stream.read_u8().await? {
+            READY => io::Result::Ok(stream),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected answer of the bench service",
+            )),
+        }
+    };
+    timeout(ANSWER_TIMEOUT, connect)
+        .await
+        .map_err(|_| anyhow!("No answer through {} within {:?}", addr, ANSWER_TIMEOUT))?
+        .map_err(|e| anyhow!("Failed to reach the bench service through {}: {}", addr, e))
+}
+
+/// Sends `message` and reads its echo into `echo`, failing after `ANSWER_TIMEOUT`
+async fn round_trip(stream: &mut TcpStream, message: &[u8], echo: &mut [u8]) -> Result<()> {
+    let exchange = async {
+        stream.write_all(message).await?;
+        stream.read_exact(echo).await?;
+        io::Result::Ok(())
+    };
+    timeout(ANSWER_TIMEOUT, exchange)
+        .await
+        .map_err(|_| anyhow!("No echo within {:?}", ANSWER_TIMEOUT))?
+        .map_err(|e| anyhow!("Echo failed: {}", e))
+}
+
+/// Times `SETUP_SAMPLES` connections, each until the bench service answered
+async fn measure_setup(addr: &str) -> Result<Percentiles> {
+    let mut samples = Vec::with_capacity(SETUP_SAMPLES);
+    for _ in 0..SETUP_SAMPLES {
+        let start = Instant::now();
+        open(addr, MODE_ECHO).await?;
+        samples.push(start.elapsed());
+    }
+    Percentiles::of(&samples).ok_or_else(|| anyhow!("No connection was timed"))
+}
+
+/// Times round trips of `LATENCY_MESSAGE` bytes one after the other for `duration`
+async fn measure_latency(addr: &str, duration: Duration) -> Result<Percentiles> {
+    let mut stream = open(addr, MODE_ECHO).await?;
+    let message = [0; LATENCY_MESSAGE];
+    let mut echo = [0; LATENCY_MESSAGE];
+    let mut samples = Vec::new();
+    let start = Instant::now();
+    // at least one round trip, however short the duration
+    while samples.is_empty() || start.elapsed() < duration {
+        let sent = Instant::now();
+        round_trip(&mut stream, &message, &mut echo).await?;
+        samples.push(sent.elapsed());
+    }
+    Percentiles::of(&samples).ok_or_else(|| anyhow!("No round trip was timed"))
+}
+
+/// Runs `streams` transfers side by side on their own tasks
+async fn in_parallel<F, Fut>(streams: usize, transfer: F) -> Result<Throughput>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<Throughput>> + Send + 'static,
+{
+    let tasks = (0..streams.max(1)).map(|_| tokio::spawn(transfer()));
+    let mut throughputs = Vec::new();
+    for result in join_all(tasks).await {
+        throughputs.push(result??);
+    }
+    Ok(Throughput::combined(&throughputs))
+}
+
+/// Writes `payload_size` byte payloads through `addr` for `duration`, timed until the
+/// local service counted them all
+async fn upload(addr: String, duration: Duration, payload_size: usize) -> Result<Throughput> {
+    let mut stream = open(&addr, MODE_SINK).await?;
+    let payload = vec![0; payload_size.max(1)];
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        stream.write_all(&payload).await?;
+    }
+    stream.shutdown().await?;
+    let bytes = stream.read_u64().await?;
+    Ok(Throughput {
+        bytes,
+        elapsed: start.elapsed(),
+    })
+}
+
+/// Reads what the local service writes in `payload_size` byte payloads through `addr`
+/// for `duration`
+async fn download(addr: String, duration: Duration, payload_size: usize) -> Result<Throughput> {
+    let mut stream = open(&addr, MODE_SOURCE).await?;
+    stream
+        .write_u32(u32::try_from(payload_size).unwrap_or(u32::MAX))
+        .await?;
+    let mut buf = vec![0; 64 * 1024];
+    let mut bytes = 0;
+    let start = Instant::now();
+    let end = start + duration;
+    loop {
+        match timeout_at(end, stream.read(&mut buf)).await {
+            Err(_) => break,
+            Ok(Ok(0)) => return Err(anyhow!("The bench service closed the connection")),
+            Ok(Ok(read)) => bytes += read as u64,
+            Ok(Err(e)) => return Err(e.into()),
+        }
+    }
+    let elapsed = start.elapsed();
+    // what is still on its way is read but not counted, for the tunnel to close cleanly
+    stream.shutdown().await?;
+    timeout(ANSWER_TIMEOUT, io::copy(&mut stream, &mut io::sink()))
+        .await
+        .map_err(|_| anyhow!("The bench service did not stop within {:?}", ANSWER_TIMEOUT))??;
+    Ok(Throughput { bytes, elapsed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles_by_nearest_rank() {
+        assert_eq!(Percentiles::of(&[]), None);
+        let samples: Vec<Duration> = (1..=100).rev().map(Duration::from_millis).collect();
+        let percentiles = Percentiles::of(&samples).unwrap();
+        assert_eq!(percentiles.count, 100);
+        assert_eq!(percentiles.min, Duration::from_millis(1));
+        assert_eq!(percentiles.p50, Duration::from_millis(50));
+        assert_eq!(percentiles.p90, Duration::from_millis(90));
+        assert_eq!(percentiles.p99, Duration::from_millis(99));
+        assert_eq!(percentiles.max, Duration::from_millis(100));
+
+        let single = Percentiles::of(&[Duration::from_millis(7)]).unwrap();
+        assert_eq!(single.p99, Duration::from_millis(7));
+    }
+
+    #[test]
+    fn test_parallel_streams_combine() {
+        let streams = [
+            Throughput {
+                bytes: 1000,
+                elapsed: Duration::from_secs(1),
+            },
+            Throughput {
+                bytes: 3000,
+                elapsed: Duration::from_secs(2),
+            },
+        ];
+        let combined = Throughput::combined(&streams);
+        assert_eq!(combined.bytes, 4000);
+        assert_eq!(combined.bytes_per_second(), 2000.0);
+        assert_eq!(Throughput::default().bytes_per_second(), 0.0);
+    }
+}
@@ -0,0 +1,438 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use super::status::ServiceStatus;
+use super::Client;
+use crate::config::{normalize_service_list, ServiceConfig};
+use crate::utils::net;
+use crate::{error, info, log_debug, log_warn};
+
+/// Longest command the control socket reads, in bytes
+const MAX_COMMAND_LEN: usize = 64 * 1024;
+
+/// A command for a client's control socket, sent as one line of JSON such as
+/// `{"command": "remove-service", "name": "web"}` and answered with one line of JSON
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum ControlCommand {
+    /// The servers and services with their state, and the open connections through each
+    Status,
+    /// Load the configuration again and apply its services, like SIGHUP
+    Reload,
+    /// Register one more service with every server, or only with `server`
+    AddService {
+        /// The service to add
+        service: ServiceSpec,
+        /// Server to add it to, every server when absent
+        #[serde(default)]
+        server: Option<String>,
+    },
+    /// Unregister a service from every server, or only from `server`
+    RemoveService {
+        /// Name of the service
+        name: String,
+        /// Server to remove it from, every server when absent
+        #[serde(default)]
+        server: Option<String>,
+    },
+}
+
+/// A service to add: a `--service` mapping like `"127.0.0.1:22:2222"`, or a table with
+/// the keys of `[[client.services]]`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ServiceSpec {
+    /// `local_ip:local_port:remote_port` and the other forms of `--service`
+    Mapping(String),
+    /// A service as configured in the config file
+    Table(Box<ServiceConfig>),
+}
+
+impl ServiceSpec {
+    fn into_service(self) -> Result<ServiceConfig> {
+        match self {
+            ServiceSpec::Mapping(mapping) => ServiceConfig::parse_cli(&mapping),
+            ServiceSpec::Table(service) => Ok(*service),
+        }
+    }
+}
+
+impl Client {
+    /// Binds the control socket at `path`, for this user only. A socket left behind by a
+    /// client that is gone is replaced, one still answered is an error
+    pub(super) async fn bind_control(path: &str) -> Result<UnixListener> {
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(anyhow!(
+                    "Control socket {} exists and is not a socket",
+                    path
+                ));
+            }
+            if UnixStream::connect(path).await.is_ok() {
+                return Err(anyhow!(
+                    "Control socket {} is in use by another client",
+                    path
+                ));
+            }
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|e| anyhow!("Failed to bind control socket {}: {}", path, e))?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        Ok(listener)
+    }
+
+    /// Answers the connections to the control socket on `listener`
+    pub(super) async fn serve_control(&self, listener: UnixListener) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let client = self.clone();
+                    tokio::spawn(async move { client.handle_control_connection(stream).await });
+                }
+                Err(e) if net::is_fd_exhaustion(&e) => {
+                    log_warn!(
+                        "Out of file descriptors accepting control connections: {}",
+                        e
+                    );
+                    tokio::time::sleep(net::FD_EXHAUSTION_BACKOFF).await;
+                }
+                Err(e) => {
+                    error!("Failed to accept control connection: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Runs the commands of a control connection one line at a time, answering each with
+    /// what was asked for or `{"message": ...}` on success, `{"error": ...}` otherwise
+    async fn handle_control_connection(&self, stream: UnixStream) {
+        // the socket's mode keeps other users out, this also refuses those who connected
+        // before it was set
+        let own_uid = unsafe { libc::geteuid() };
+        match stream.peer_cred() {
+            Ok(peer) if peer.uid() == own_uid || peer.uid() == 0 => {}
+            Ok(peer) => {
+                log_warn!("Refusing control connection from uid {}", peer.uid());
+                return;
+            }
+            Err(e) => {
+                log_warn!("Refusing control connection of unknown peer: {}", e);
+                return;
+            }
+        }
+
+        let (read, mut write) = stream.into_split();
+        let mut reader = BufReader::new(read);
+        loop {
+            let mut line = String::new();
+            let read = (&mut reader)
+                .take(MAX_COMMAND_LEN as u64 + 1)
+                .read_line(&mut line)
+                .await;
+            match read {
+                Ok(0) => return,
+                Ok(_) => {}
+                Err(e) => {
+                    log_debug!("Closing control connection: {}", e);
+                    return;
+                }
+            }
+            let too_long = line.len() > MAX_COMMAND_LEN;
+            let answer = if too_long {
+                Err(anyhow!("Commands are limited to {} bytes", MAX_COMMAND_LEN))
+            } else if line.trim().is_empty() {
+                continue;
+            } else {
+                match serde_json::from_str::<ControlCommand>(&line) {
+                    Ok(command) => self.control_command(command).await,
+                    Err(e) => Err(anyhow!("Invalid command: {}", e)),
+                }
+            };
+            let mut answer = match answer {
+                Ok(answer) => answer.to_string(),
+                Err(e) => json!({ "error": format!("{:#}", e) }).to_string(),
+            };
+            answer.push('\n');
+            // the rest of an overlong command cannot be told from the next one
+            if write.write_all(answer.as_bytes()).await.is_err() || too_long {
+                return;
+            }
+        }
+    }
+
+    /// Runs `command`, returning what was asked for or what was done
+    async fn control_command(&self, command: ControlCommand) -> Result<Value> {
+        let message = match command {
+            ControlCommand::Status => return Ok(self.control_status().await),
+            ControlCommand::Reload => self.reload().await?,
+            ControlCommand::AddService { service, server } => {
+                self.add_service(service.into_service()?, server.as_deref())
+                    .await?
+            }
+            ControlCommand::RemoveService { name, server } => {
+                self.remove_service(&name, server.as_deref()).await?
+            }
+        };
+        Ok(json!({ "message": message }))
+    }
+
+    /// The status table as JSON, with the open connections through each server
+    async fn control_status(&self) -> Value {
+        let mut connections: HashMap<String, usize> = HashMap::new();
+        for connection in self.local_connections.lock().await.values() {
+            *connections
+                .entry(connection.server_addr.clone())
+                .or_default() += 1;
+        }
+        let mut status = self.state.to_json(&connections).await;
+        status["client_id"] = json!(self.client_id);
+        status
+    }
+
+    /// The servers a command is for: `server`, or every server when None
+    async fn target_servers(&self, server: Option<&str>) -> Result<Vec<String>> {
+        let services = self.services.lock().await;
+        match server {
+            Some(server) if services.contains_key(server) => Ok(vec![server.to_string()]),
+            Some(server) => Err(anyhow!("Not a server of this client: {}", server)),
+            None => {
+                let mut servers: Vec<String> = services.keys().cloned().collect();
+                servers.sort();
+                Ok(servers)
+            }
+        }
+    }
+
+    /// Adds `service` to the services of `server`, or of every server, and registers it
+    /// with those connected. Nothing is added unless it fits with the services of each
+    pub(super) async fn add_service(
+        &self,
+        service: ServiceConfig,
+        server: Option<&str>,
+    ) -> Result<String> {
+        let servers = self.target_servers(server).await?;
+        let service = {
+            let mut services = self.services.lock().await;
+            let mut updated = Vec::new();
+            for server in &servers {
+                let mut list = services.get(server).cloned().unwrap_or_default();
+                list.push(service.clone());
+                normalize_service_list(&mut list)
+                    .map_err(|e| anyhow!("Cannot add the service to {}: {}", server, e))?;
+                updated.push(list);
+            }
+            let mut added = service;
+            for (server, list) in servers.iter().zip(updated) {
+                // named, and its hosts lowercased
+                added = list.last().cloned().unwrap_or(added);
+                services.insert(server.clone(), list);
+            }
+            added
+        };
+
+        for server in &servers {
+            self.state.add_service(server, &service).await;
+            if !self.connections.lock().await.contains_key(server) {
+                // registered once the server is connected again
+                continue;
+            }
+            match self.ttl_left(server, &service).await {
+                Ok(ttl) => self.send_registration(server, &service, ttl).await,
+                Err(_) => {
+                    self.state
+                        .service_status(server, &service.name, ServiceStatus::Expired)
+                        .await;
+                }
+            }
+        }
+        let message = format!("Added service '{}' to {}", service.name, servers.join(", "));
+        info!("{}", message);
+        Ok(message)
+    }
+
+    /// Removes service `name` from the services of `server`, or of every server having it,
+    /// and unregisters it from those connected
+    pub(super) async fn remove_service(&self, name: &str, server: Option<&str>) -> Result<String> {
+        let mut removed_from = Vec::new();
+        for server in self.target_servers(server).await? {
+            let service = {
+                let mut services = self.services.lock().await;
+                let list = services.entry(server.clone()).or_default();
+                match list.iter().position(|service| service.name == name) {
+                    Some(index) => list.remove(index),
+                    None => continue,
+                }
+            };
+            self.unregister_service(&server, &service).await;
+            self.state.remove_service(&server, name).await;
+            removed_from.push(server);
+        }
+        if removed_from.is_empty() {
+            return Err(anyhow!("No service named '{}'", name));
+        }
+        let message = format!(
+            "Removed service '{}' from {}",
+            name,
+            removed_from.join(", ")
+        );
+        info!("{}", message);
+        Ok(message)
+    }
+
+    /// Asks `server_addr` to remove `service` if the session is up, and forgets what the
+    /// session knows of it
+    async fn unregister_service(&self, server_addr: &str, service: &ServiceConfig) {
+        let key = (server_addr.to_string(), service.name.clone());
+        let assigned = self.assigned_ports.lock().await.remove(&key);
+        // services on a port the server picked are removed from that port
+        let remote_port = match service.remote_port {
+            0 if service.shared_route().is_none() && !service.is_visited() => assigned,
+            port => Some(port),
+        };
+        let mut connections = self.connections.lock().await;
+        let Some(conn) = connections.get_mut(server_addr) else {
+            return;
+        };
+        conn.proxies.retain(|_, proxy| proxy.name != service.name);
+        conn.health.remove(&service.name);
+        conn.held_back.retain(|held| held.name != service.name);
+        let request = conn
+            .registrations
+            .unregister(service, remote_port.unwrap_or_default());
+        match remote_port {
+            Some(_) => {
+                let _ = conn.sender.send(request);
+            }
+            None => {
+                log_debug!(
+                    "Service '{}' was not given a port by {} yet, nothing to remove there",
+                    service.name,
+                    server_addr
+                );
+            }
+        }
+    }
+
+    /// Loads the configuration again and brings the services of each server in line with
+    /// it: new ones are added, those gone removed and changed ones registered again.
+    /// Servers and other settings only change with a restart
+    pub(super) async fn reload(&self) -> Result<String> {
+        let Some(reloader) = &self.reloader else {
+            return Err(anyhow!(
+                "Nothing to reload, the client was not started from a config file"
+            ));
+        };
+        let config = reloader()?;
+        let (mut added, mut removed, mut changed) = (0, 0, 0);
+        let mut restart = Vec::new();
+        let mut servers: Vec<String> = self.services.lock().await.keys().cloned().collect();
+        for entry in config.connection_entries() {
+            servers.retain(|server| *server != entry.server);
+            let Some(current) = self.services.lock().await.get(&entry.server).cloned() else {
+                restart.push(entry.server);
+                continue;
+            };
+            for service in &current {
+                let wanted = entry.services.iter().find(|new| new.name == service.name);
+                if wanted.is_some_and(|wanted| same_service(wanted, service)) {
+                    continue;
+                }
+                self.remove_service(&service.name, Some(&entry.server))
+                    .await?;
+                match wanted {
+                    Some(_) => changed += 1,
+                    None => removed += 1,
+                }
+            }
+            for service in &entry.services {
+                let kept = current
+                    .iter()
+                    .any(|old| old.name == service.name && same_service(old, service));
+                if !kept {
+                    self.add_service(service.clone(), Some(&entry.server))
+                        .await?;
+                    if current.iter().all(|old| old.name != service.name) {
+                        added += 1;
+                    }
+                }
+            }
+        }
+        // servers no longer configured are kept until a restart as well
+        restart.extend(servers);
+
+        let mut message = format!(
+            "Reloaded the configuration: {} services added, {} removed, {} changed",
+            added, removed, changed
+        );
+        if !restart.is_empty() {
+            message.push_str(&format!(
+                ", servers added or removed take a restart: {}",
+                restart.join(", ")
+            ));
+        }
+        info!("{}", message);
+        Ok(message)
+    }
+
+    /// Reloads the configuration on every SIGHUP
+    pub(super) async fn reload_on_hangup(&self) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+            return;
+        };
+        while hangup.recv().await.is_some() {
+            if let Err(e) = self.reload().await {
+                error!("Failed to reload the configuration: {:#}", e);
+            }
+        }
+    }
+}
+
+/// Whether two services are configured alike
+fn same_service(a: &ServiceConfig, b: &ServiceConfig) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+/// Client of a running client's control socket, see `control_socket`. Each call is one
+/// command, failing with the error the client answered
+pub struct ControlClient {
+    path: String,
+}
+
+impl ControlClient {
+    /// A client of the control socket at `path`
+    pub fn new(path: impl Into<String>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Sends `command`, returning the JSON of a successful answer
+    pub async fn send(&self, command: &ControlCommand) -> Result<Value> {
+        let stream = UnixStream::connect(&self.path).await.map_err(|e| {
+            anyhow!(
+                "Failed to connect to the control socket {}: {}",
+                self.path,
+                e
+            )
+        })?;
+        let (read, mut write) = stream.into_split();
+        let mut line = serde_json::to_string(command)?;
+        line.push('\n');
+        write.write_all(line.as_bytes()).await?;
+
+        let mut answer = String::new();
+        BufReader::new(read).read_line(&mut answer).await?;
+        let answer: Value = serde_json::from_str(&answer)
+            .map_err(|_| anyhow!("{} answered with an invalid response", self.path))?;
+        match answer.get("error").and_then(Value::as_str) {
+            Some(error) => Err(anyhow!(error.to_string())),
+            None => Ok(answer),
+        }
+    }
+}
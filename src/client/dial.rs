@@ -0,0 +1,126 @@
+use anyhow::{anyhow, Result};
+use std::net::SocketAddr;
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::{timeout, Duration};
+
+use crate::log_debug;
+
+/// How long one address may take to connect while others remain to be tried
+pub const ADDRESS_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Resolves `server_addr` afresh, so a changed DNS record is picked up on the next attempt
+pub async fn resolve(server_addr: &str) -> Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = lookup_host(server_addr)
+        .await
+        .map_err(|e| anyhow!("Failed to resolve {}: {}", server_addr, e))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(anyhow!("{} resolved to no addresses", server_addr));
+    }
+    Ok(addrs)
+}
+
+/// Moves addresses of the family that connected last time to the front,
+/// otherwise keeping the resolver's order
+pub fn order_addresses(mut addrs: Vec<SocketAddr>, last: Option<SocketAddr>) -> Vec<SocketAddr> {
+    if let Some(last) = last {
+        // stable, so each family keeps its order
+        addrs.sort_by_key(|addr| addr.is_ipv4() != last.is_ipv4());
+    }
+    addrs
+}
+
+/// Tries each address in turn, giving every one but the last `per_address` to connect.
+/// Returns the stream with the address it is connected to
+pub async fn connect_first(
+    addrs: &[SocketAddr],
+    per_address: Duration,
+) -> Result<(TcpStream, SocketAddr)> {
+    let mut last_error = None;
+    for (index, addr) in addrs.iter().enumerate() {
+        let attempt = TcpStream::connect(addr);
+        let result = if index + 1 < addrs.len() {
+            match timeout(per_address, attempt).await {
+                Ok(result) => result,
+                Err(_) => Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "connect timed out",
+                )),
+            }
+        } else {
+            attempt.await
+        };
+        match result {
+            Ok(stream) => return Ok((stream, *addr)),
+            Err(e) => {
+                log_debug!("Failed to connect to {}: {}", addr, e);
+                last_error = Some(format!("{}: {}", addr, e));
+            }
+        }
+    }
+    Err(anyhow!(
+        "{}",
+        last_error.unwrap_or_else(|| "no addresses to connect to".to_string())
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_last_family_is_preferred() {
+        let addrs = vec![
+            addr("[2001:db8::1]:7000"),
+            addr("192.0.2.1:7000"),
+            addr("[2001:db8::2]:7000"),
+            addr("192.0.2.2:7000"),
+        ];
+        assert_eq!(order_addresses(addrs.clone(), None), addrs);
+        assert_eq!(
+            order_addresses(addrs, Some(addr("192.0.2.9:7000"))),
+            vec![
+                addr("192.0.2.1:7000"),
+                addr("192.0.2.2:7000"),
+                addr("[2001:db8::1]:7000"),
+                addr("[2001:db8::2]:7000"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dead_addresses_are_skipped() {
+        // a port nothing listens on anymore refuses connections
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+        // TEST-NET address that never answers, cut short by the per-address timeout
+        let blackhole = addr("192.0.2.1:7000");
+
+        let (_, used) = connect_first(&[closed, blackhole, live], Duration::from_millis(200))
+            .await
+            .unwrap();
+        assert_eq!(used, live);
+
+        let err = connect_first(&[closed], Duration::from_millis(200))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with(&closed.to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_each_time() {
+        let addrs = resolve("localhost:7000").await.unwrap();
+        assert!(addrs.iter().all(|addr| addr.ip().is_loopback()));
+        assert!(resolve("no-such-host.invalid:7000").await.is_err());
+    }
+}
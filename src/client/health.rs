@@ -0,0 +1,112 @@
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+
+use crate::config::ServiceConfig;
+use crate::utils::net;
+
+/// How long a health check waits for the local service to accept
+pub const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Failed checks in a row before a service that was reachable is reported unreachable
+const FAILURES_TO_REPORT: u32 = 2;
+
+/// Connects to the local service of `service` and closes the connection right away,
+/// failing with why it could not be reached
+pub async fn check(service: &ServiceConfig) -> Result<(), String> {
+    let connect = async {
+        match &service.local_path {
+            #[cfg(unix)]
+            Some(path) => tokio::net::UnixStream::connect(path).await.map(drop),
+            #[cfg(not(unix))]
+            Some(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Unix domain sockets are only supported on Unix",
+            )),
+            None => {
+                let local_ip = net::unbracket(&service.local_ip);
+                TcpStream::connect((local_ip, service.local_port))
+                    .await
+                    .map(drop)
+            }
+        }
+    };
+    match timeout(HEALTH_CHECK_TIMEOUT, connect).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("no answer within {:?}", HEALTH_CHECK_TIMEOUT)),
+    }
+}
+
+/// The health of one local service as reported, a single failure after successes
+/// is not reported so a flapping service does not flood the logs
+#[derive(Debug, Default)]
+pub struct Health {
+    /// Failed checks in a row
+    failures: u32,
+    /// The outcome last reported, None before the first check
+    reported: Option<Result<(), String>>,
+}
+
+impl Health {
+    /// Records the outcome of a check, returning it when it is to be reported: the first
+    /// outcome, a recovery, or the failure making `FAILURES_TO_REPORT` in a row
+    pub fn record(&mut self, outcome: Result<(), String>) -> Option<Result<(), String>> {
+        match outcome {
+            Ok(()) => self.failures = 0,
+            Err(_) => self.failures += 1,
+        }
+        let report = match (&outcome, &self.reported) {
+            (_, None) => true,
+            (Ok(()), Some(reported)) => reported.is_err(),
+            // an unreachable service is reported once, not again with every new error
+            (Err(_), Some(reported)) => reported.is_ok() && self.failures >= FAILURES_TO_REPORT,
+        };
+        report.then(|| {
+            self.reported = Some(outcome.clone());
+            outcome
+        })
+    }
+
+    /// Why the service was last reported unreachable, None while it is reachable or unknown
+    pub fn error(&self) -> Option<&str> {
+        self.reported.as_ref()?.as_ref().err().map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_single_failures_are_not_reported() {
+        let mut health = Health::default();
+        let refused = || Err("connection refused".to_string());
+        assert_eq!(health.record(Ok(())), Some(Ok(())));
+        assert_eq!(health.record(Ok(())), None);
+
+        // a reachable service must fail twice in a row
+        assert_eq!(health.record(refused()), None);
+        assert_eq!(health.record(Ok(())), None);
+        assert_eq!(health.record(refused()), None);
+        assert_eq!(health.record(refused()), Some(refused()));
+        assert_eq!(health.error(), Some("connection refused"));
+        assert_eq!(health.record(Err("timed out".to_string())), None);
+        assert_eq!(health.record(Ok(())), Some(Ok(())));
+        assert_eq!(health.error(), None);
+
+        // the first check is reported as it is
+        let mut health = Health::default();
+        assert_eq!(health.record(refused()), Some(refused()));
+    }
+
+    #[tokio::test]
+    async fn test_check_connects_to_the_local_service() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let service = ServiceConfig::parse_cli(&format!("127.0.0.1:{}:0", port)).unwrap();
+        assert_eq!(check(&service).await, Ok(()));
+        drop(listener);
+        assert!(check(&service).await.is_err());
+    }
+}
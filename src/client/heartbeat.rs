@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+use tokio::time::{Duration, Instant};
+
+use crate::logging::format_bytes;
+use crate::utils::protocol::ClientStats;
+use crate::utils::Message;
+
+/// Heartbeats sent on a server connection that still wait for a response,
+/// shared by the heartbeat task sending them and the read task seeing the responses
+#[derive(Debug, Default)]
+pub struct Heartbeats {
+    /// When each unanswered heartbeat was sent, by its timestamp
+    pending: BTreeMap<u64, Instant>,
+    /// Round trip time of the last answered heartbeat
+    pub rtt: Option<Duration>,
+    /// What the server last counted of the client's connections, None from servers
+    /// that do not count them
+    pub stats: Option<ClientStats>,
+}
+
+impl Heartbeats {
+    /// Creates the next heartbeat and starts waiting for its response
+    pub fn new_heartbeat(&mut self) -> Message {
+        let heartbeat = Message::new_heartbeat();
+        if let Message::Heartbeat { timestamp } = heartbeat {
+            self.pending.insert(timestamp, Instant::now());
+        }
+        heartbeat
+    }
+
+    /// Records the response to the heartbeat sent with `timestamp` and returns its round trip.
+    /// Older heartbeats still pending count as answered, the connection is alive
+    pub fn answered(&mut self, timestamp: u64) -> Option<Duration> {
+        let sent = self.pending.remove(&timestamp)?;
+        self.pending.retain(|pending, _| *pending > timestamp);
+        let rtt = sent.elapsed();
+        self.rtt = Some(rtt);
+        Some(rtt)
+    }
+
+    /// Records the stats of a heartbeat response, returning a description of the change
+    /// when the open connections changed since the last response
+    pub fn record_stats(&mut self, stats: ClientStats) -> Option<String> {
+        let previous = self.stats.replace(stats).unwrap_or_default();
+        (stats.active_connections != previous.active_connections).then(|| {
+            format!(
+                "{} open connections, was {} ({} in all, {} forwarded)",
+                stats.active_connections,
+                previous.active_connections,
+                stats.total_connections,
+                format_bytes(stats.bytes_forwarded)
+            )
+        })
+    }
+
+    /// Heartbeats sent since the last response
+    pub fn missed(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_responses_clear_missed_heartbeats() {
+        let mut heartbeats = Heartbeats::default();
+        heartbeats.pending.insert(100, Instant::now());
+        heartbeats.pending.insert(101, Instant::now());
+        heartbeats.pending.insert(102, Instant::now());
+        assert_eq!(heartbeats.missed(), 3);
+
+        // unknown timestamps are ignored
+        assert!(heartbeats.answered(99).is_none());
+        assert!(heartbeats.rtt.is_none());
+
+        let rtt = heartbeats.answered(101).unwrap();
+        assert_eq!(heartbeats.rtt, Some(rtt));
+        assert_eq!(heartbeats.missed(), 1);
+    }
+
+    #[test]
+    fn test_changed_stats_are_described() {
+        let mut heartbeats = Heartbeats::default();
+        let stats = |active, total, bytes| ClientStats {
+            active_connections: active,
+            total_connections: total,
+            bytes_forwarded: bytes,
+        };
+
+        // idle from the start is nothing to tell
+        assert!(heartbeats.record_stats(stats(0, 0, 0)).is_none());
+        assert_eq!(
+            heartbeats.record_stats(stats(2, 2, 2048)).as_deref(),
+            Some("2 open connections, was 0 (2 in all, 2.0 KiB forwarded)")
+        );
+        // traffic alone is not
+        assert!(heartbeats.record_stats(stats(2, 2, 4096)).is_none());
+        assert_eq!(
+            heartbeats.record_stats(stats(1, 3, 8192)).as_deref(),
+            Some("1 open connections, was 2 (3 in all, 8.0 KiB forwarded)")
+        );
+        assert_eq!(heartbeats.stats, Some(stats(1, 3, 8192)));
+    }
+}
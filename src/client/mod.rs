@@ -1,18 +1,57 @@
 use anyhow::Result;
+use bytes::BytesMut;
+use futures_util::future::join_all;
 use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::sync::{mpsc, Mutex};
 use tokio::time::{interval, timeout, Duration};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 use uuid::Uuid;
 
-use crate::config::{ClientConfig, ServiceConfig};
-use crate::logging::{format_service_config, format_uuid};
-use crate::utils::crypto::{sha256_with_salt, MAGIC_SALT};
-use crate::utils::protocol::ProxyConfigOpCode;
-use crate::utils::{CryptoContext, Frame, FrameReader, Message};
-use crate::{console_info, debug, error, info, log_info, warn};
+use crate::config::{ClientConfig, HumanDuration, ServiceConfig, Transport};
+use crate::logging::{format_bytes, format_service_config, format_uuid, short_id};
+use crate::utils::compression::Compression;
+use crate::utils::protocol::{CloseCode, Framing, PROTOCOL_VERSION};
+use crate::utils::proxy::{
+    splice, HalfEnd, SpliceEnd, TransferCounter, WriteBacklog, WriteCommand,
+};
+#[cfg(feature = "quic")]
+use crate::utils::quic;
+use crate::utils::replay::REPLAY_CAPACITY;
+use crate::utils::{
+    net, sd_notify, tls, websocket, write_all_within, Acknowledger, Activity, BoxedStream,
+    CryptoContext, Frame, FrameReader, Message, ReplayBuffer, Route, SendWindow, SocketOptions,
+    TransferStats,
+};
+use crate::{console_info, debug, error, info, log_debug, log_info, warn};
+
+mod bench;
+#[cfg(unix)]
+mod control;
+mod dial;
+mod health;
+mod heartbeat;
+mod ping;
+mod registration;
+mod resume;
+mod status;
+mod visitor;
+
+pub use bench::{BenchOptions, BenchReport, Percentiles, Throughput};
+#[cfg(unix)]
+pub use control::{ControlClient, ControlCommand, ServiceSpec};
+use health::Health;
+use heartbeat::Heartbeats;
+pub use ping::{PingError, PingSession, PingSummary};
+use registration::{Deadlines, Expired, Registrations};
+use status::{ClientState, ServerStatus, ServiceStatus};
+use visitor::{VisitTarget, Visits};
 
 /// Main client structure that manages connections to multiple servers
 pub struct Client {
@@ -20,146 +59,832 @@ pub struct Client {
     client_id: String,
     connections: Arc<Mutex<HashMap<String, ServerConnection>>>,
     local_connections: Arc<Mutex<HashMap<String, LocalConnection>>>,
+    tls_connector: Option<TlsConnector>,
+    /// Remote ports picked by servers for `remote_port = 0` services, by (server, service name).
+    /// Kept across reconnects so the same port is requested again
+    assigned_ports: Arc<Mutex<HashMap<(String, String), u16>>>,
+    /// When services with a `ttl` run out on each server, kept across reconnects
+    deadlines: Arc<Mutex<Deadlines>>,
+    /// Address each server was last reached at, its family is tried first on reconnect
+    last_addrs: Arc<Mutex<HashMap<String, SocketAddr>>>,
+    /// State of each server and service, printed as the status table
+    state: ClientState,
+    /// Services to register with each server, by server address. Read on every connect and
+    /// changed while running through the control socket
+    services: Arc<Mutex<HashMap<String, Vec<ServiceConfig>>>>,
+    /// Loads the configuration again for `reload`, None when there is nothing to load
+    reloader: Option<Reloader>,
+    /// Give each server up once its first connection ends instead of reconnecting
+    once: bool,
 }
 
+/// Loads the client configuration again, see `Client::with_reloader`
+type Reloader = Arc<dyn Fn() -> Result<ClientConfig> + Send + Sync>;
+
 /// Represents a connection to a server with its communication channel
 struct ServerConnection {
     server_addr: String,
     sender: mpsc::UnboundedSender<Message>,
     crypto: Option<Arc<CryptoContext>>,
     connected: bool,
+    /// Compression codec negotiated with this server
+    compression: Compression,
+    /// Minimum payload size in bytes before compression is attempted
+    compression_threshold: usize,
+    /// Services awaiting a `ProxyConfigResponse`
+    registrations: Registrations,
+    /// Registered services by proxy ID
+    proxies: HashMap<String, ServiceConfig>,
+    /// Unanswered heartbeats and the last round trip time
+    heartbeats: Heartbeats,
+    /// How proxied connections travel to the server
+    data_channel: DataChannel,
+    /// Visitors waiting for a `VisitorConnectResponse`
+    visits: Visits,
+    /// Health of the local services with `health_check`, by service name
+    health: HashMap<String, Health>,
+    /// Services with `require_healthy` left unregistered until their local service answers
+    held_back: Vec<ServiceConfig>,
+}
+
+impl ServerConnection {
+    /// Where the session's connections send their data
+    fn route(&self) -> Route {
+        Route {
+            sender: self.sender.clone(),
+            compression: self.compression,
+        }
+    }
+}
+
+/// How the proxied connections of a session travel to the server
+#[derive(Clone)]
+enum DataChannel {
+    /// In `Data` messages on the control stream
+    Messages,
+    /// Each on a connection of its own to the server, opened with a `DataChannelHello`
+    Connections {
+        /// server the session is with
+        server_addr: String,
+        /// ID the server knows the session by
+        client_id: String,
+        /// the session's crypto context, keying the hello's proof
+        crypto: Arc<CryptoContext>,
+    },
+    /// Each on a stream of its own on the session's QUIC connection
+    #[cfg(feature = "quic")]
+    Quic(quinn::Connection),
+}
+
+impl DataChannel {
+    /// Carries a connection to a local service on a stream of its own if the session has
+    /// them, handing `local_stream` back when it goes in `Data` messages instead. Some
+    /// always do: the server writes HTTP tunnels to their peers on one shared writer,
+    /// and relays the messages of secret services to their visitors. So do those whose
+    /// data channel `client` could not open
+    #[cfg_attr(not(feature = "quic"), allow(unused_variables))]
+    async fn carry(
+        &self,
+        client: &Client,
+        local_stream: BoxedStream,
+        connection_id: &str,
+        in_messages: bool,
+        sender: mpsc::UnboundedSender<Message>,
+        idle_timeout: HumanDuration,
+    ) -> Option<BoxedStream> {
+        match self {
+            Self::Connections {
+                server_addr,
+                client_id,
+                crypto,
+            } if !in_messages => {
+                let opened = client
+                    .open_data_channel(server_addr, client_id, crypto, connection_id)
+                    .await;
+                match opened {
+                    Ok(channel) => {
+                        tokio::spawn(
+                            Client::splice_local_connection(
+                                local_stream,
+                                channel,
+                                connection_id.to_string(),
+                                idle_timeout,
+                            )
+                            .in_current_span(),
+                        );
+                        None
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Failed to open a data channel for connection {}, sending its data in messages: {}",
+                            connection_id, e
+                        );
+                        Some(local_stream)
+                    }
+                }
+            }
+            #[cfg(feature = "quic")]
+            Self::Quic(connection) if !in_messages => {
+                tokio::spawn(
+                    Client::carry_on_stream(
+                        connection.clone(),
+                        local_stream,
+                        connection_id.to_string(),
+                        sender,
+                        idle_timeout,
+                    )
+                    .in_current_span(),
+                );
+                None
+            }
+            _ => Some(local_stream),
+        }
+    }
+
+    /// Ends the streams of the session, if it has any
+    fn close(&self) {
+        #[cfg(feature = "quic")]
+        if let Self::Quic(connection) = self {
+            connection.close(0u32.into(), b"session ended");
+        }
+    }
+}
+
+/// How long opening a data channel may take before its connection goes in `Data` messages
+const DATA_CHANNEL_OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a stopping client waits for the `CloseConnection`s of its connections to be
+/// written before ending the session
+const CLOSE_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A control stream authenticated to a server, with what the handshake settled for its session
+struct Authenticated {
+    stream: BoxedStream,
+    /// Holds any bytes already received past the handshake
+    frame_reader: FrameReader,
+    crypto: Arc<CryptoContext>,
+    compression: Compression,
+    /// ID the server knows the session by, its own if it assigned one
+    client_id: String,
+    /// Whether the server lets the session open data channels
+    data_channels: bool,
 }
 
 struct LocalConnection {
-    sender: mpsc::UnboundedSender<Vec<u8>>,
+    /// Server the connection came through
+    server_addr: String,
+    sender: mpsc::UnboundedSender<WriteCommand>,
+    /// Credit for sending the connection's data to the server, held back while the server
+    /// paused the connection
+    window: Arc<SendWindow>,
+    /// What the server's end moved, when the server closed the connection
+    reported: Arc<OnceLock<TransferStats>>,
+    /// `seq` the server's next data message for the connection must carry
+    next_seq: u64,
+    /// Data from the server queued for the local service and not written yet
+    backlog: Arc<WriteBacklog>,
+    /// Data sent to the server and not acknowledged yet, for the next session with the
+    /// server to resume the connection with
+    replay: Arc<ReplayBuffer>,
+}
+
+impl LocalConnection {
+    /// A connection through `server_addr` whose data from the server goes to `sender`,
+    /// sending its own on `route`
+    fn new(
+        server_addr: &str,
+        connection_id: &str,
+        sender: mpsc::UnboundedSender<WriteCommand>,
+        route: Option<Route>,
+        compression_threshold: usize,
+    ) -> Self {
+        Self {
+            server_addr: server_addr.to_string(),
+            sender,
+            window: Arc::default(),
+            reported: Arc::default(),
+            next_seq: 0,
+            backlog: Arc::default(),
+            replay: Arc::new(ReplayBuffer::new(
+                connection_id,
+                REPLAY_CAPACITY,
+                compression_threshold,
+                route,
+            )),
+        }
+    }
+}
+
+/// A reason given by a server for refusing this client or ending its session,
+/// deciding whether reconnecting to that server can help
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ServerError {
+    /// The token or certificate was not accepted
+    Authentication(String),
+    /// Client and server speak different protocol versions
+    ProtocolVersion(String),
+    /// The server takes no more clients
+    ServerFull(String),
+    /// Anything else, e.g. a duplicate client ID or a reported protocol violation
+    Other(String),
+}
+
+impl ServerError {
+    /// Classifies a reason from a failed `AuthResponse` or a `Message::Error`
+    fn from_reason(reason: &str) -> Self {
+        let lower = reason.to_ascii_lowercase();
+        let reason = reason.to_string();
+        if lower.contains("upgrade required") {
+            Self::ProtocolVersion(reason)
+        } else if lower.contains("invalid token")
+            || lower.contains("token required")
+            || lower.contains("certificate required")
+        {
+            Self::Authentication(reason)
+        } else if lower.contains("server full") {
+            Self::ServerFull(reason)
+        } else {
+            Self::Other(reason)
+        }
+    }
+
+    /// Whether reconnecting with the same configuration is bound to fail again
+    fn is_fatal(&self) -> bool {
+        !matches!(self, Self::Other(_))
+    }
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Authentication(reason) => write!(f, "Authentication failed: {}", reason),
+            Self::ProtocolVersion(reason) | Self::ServerFull(reason) | Self::Other(reason) => {
+                write!(f, "{}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+/// Error `Client::run` ends with once every server was given up without a shutdown,
+/// ordered so the most telling reason among the servers is the greatest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GaveUp {
+    /// Servers could not be reached, or dropped the connection
+    Network,
+    /// A server refused the client for another reason, e.g. a protocol version mismatch
+    Refused,
+    /// A server rejected the token or certificate
+    Authentication,
+}
+
+impl GaveUp {
+    /// Classifies the error a server was given up with
+    fn of(error: &anyhow::Error) -> Self {
+        match error.downcast_ref::<ServerError>() {
+            Some(ServerError::Authentication(_)) => Self::Authentication,
+            Some(_) => Self::Refused,
+            None => Self::Network,
+        }
+    }
+
+    /// Exit code of `sowback connect` giving up for this reason, so scripts and service
+    /// managers can tell them apart
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Authentication => 2,
+            Self::Network => 3,
+            Self::Refused => 1,
+        }
+    }
+}
+
+impl std::fmt::Display for GaveUp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            Self::Network => "unreachable or disconnected",
+            Self::Refused => "refused",
+            Self::Authentication => "authentication failed",
+        };
+        write!(f, "Gave up on every server: {}", reason)
+    }
 }
 
+impl std::error::Error for GaveUp {}
+
 impl Client {
     /// Creates a new client instance with the given configuration
-    pub fn new(config: ClientConfig) -> Self {
-        Self {
+    pub fn new(config: ClientConfig) -> Result<Self> {
+        if config.transport == Transport::Quic && !cfg!(feature = "quic") {
+            return Err(anyhow::anyhow!(
+                "transport = \"quic\" needs a build with the quic feature"
+            ));
+        }
+        let tls_connector = if config.tls.enable {
+            Some(tls::build_connector(&config.tls)?)
+        } else {
+            None
+        };
+
+        let entries = config.connection_entries();
+        let state = ClientState::new(&entries);
+        let services = entries
+            .into_iter()
+            .map(|entry| (entry.server, entry.services))
+            .collect();
+        Ok(Self {
             config,
             client_id: Uuid::new_v4().to_string(),
             connections: Arc::new(Mutex::new(HashMap::new())),
             local_connections: Arc::new(Mutex::new(HashMap::new())),
-        }
+            tls_connector,
+            assigned_ports: Arc::new(Mutex::new(HashMap::new())),
+            deadlines: Arc::default(),
+            last_addrs: Arc::new(Mutex::new(HashMap::new())),
+            state,
+            services: Arc::new(Mutex::new(services)),
+            reloader: None,
+            once: false,
+        })
+    }
+
+    /// Lets the client load its configuration again with `load` on `reload` through the
+    /// control socket or on SIGHUP, e.g. from the file it was started with
+    pub fn with_reloader(
+        mut self,
+        load: impl Fn() -> Result<ClientConfig> + Send + Sync + 'static,
+    ) -> Self {
+        self.reloader = Some(Arc::new(load));
+        self
+    }
+
+    /// Makes the client connect to each server once and give it up when that connection
+    /// ends or fails, instead of reconnecting, for `--once`
+    pub fn once(mut self) -> Self {
+        self.once = true;
+        self
     }
 
     /// Starts the client and maintains connections to all configured servers
-    pub async fn run(&self) -> Result<()> {
+    /// until `shutdown` is cancelled, then closes them and every local connection
+    pub async fn run(&self, shutdown: CancellationToken) -> Result<()> {
         log_info!("Starting client with ID: {}", self.client_id);
         // console_info!(
         //     "sowback client started, ID: {}",
         //     format_uuid(&self.client_id, "client")
         // ); TODO:
 
-        // Parse service configurations
-        let service_configs = &self.config.services;
+        // Visitor and relay ports are bound up front, a taken one stops the client
+        let entries = self.config.connection_entries();
+        let targets = (self
+            .config
+            .visitors
+            .iter()
+            .cloned()
+            .map(VisitTarget::Secret))
+        .chain(self.config.relays.iter().cloned().map(VisitTarget::Relay));
+        let mut visitor_tasks = Vec::new();
+        for visitor in targets {
+            let listener = Self::bind_visitor(&visitor).await?;
+            let server_addr = match (visitor.server(), entries.first()) {
+                (Some(server), _) => server.clone(),
+                (None, Some(entry)) => entry.server.clone(),
+                (None, None) => return Err(anyhow::anyhow!("Visitors need a server")),
+            };
+            let client = self.clone();
+            let shutdown = shutdown.clone();
+            visitor_tasks.push(tokio::spawn(async move {
+                client
+                    .run_visitor(listener, visitor, server_addr, shutdown)
+                    .await
+            }));
+        }
+
+        let status_task = self.config.status_interval.non_zero().map(|every| {
+            let state = self.state.clone();
+            tokio::spawn(async move {
+                let mut interval = interval(every);
+                // the first tick is immediate, nothing is connected yet
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    state.print().await;
+                }
+            })
+        });
+
+        // the control socket and SIGHUP change services while running
+        #[cfg(unix)]
+        let control_tasks = {
+            let mut tasks = Vec::new();
+            if let Some(path) = &self.config.control_socket {
+                let listener = Self::bind_control(path).await?;
+                let client = self.clone();
+                tasks.push(tokio::spawn(
+                    async move { client.serve_control(listener).await },
+                ));
+            }
+            if self.reloader.is_some() {
+                let client = self.clone();
+                tasks.push(tokio::spawn(async move { client.reload_on_hangup().await }));
+            }
+            tasks
+        };
+
+        let watchdog_task = sd_notify::watchdog_period().map(|period| {
+            let client = self.clone();
+            tokio::spawn(async move {
+                // every local connection goes through this lock
+                sd_notify::run_watchdog(period, || async {
+                    timeout(period, client.local_connections.lock())
+                        .await
+                        .is_ok()
+                })
+                .await
+            })
+        });
 
         // Connect to all servers
         let mut tasks = Vec::new();
 
-        // create client for each server
-        for server_addr in &self.config.servers {
+        // create client for each server, each with its own token and services
+        for entry in entries {
             let client = self.clone();
-            let server_addr = server_addr.clone();
-            let service_configs = service_configs.clone();
+            let shutdown = shutdown.clone();
 
             let task = tokio::spawn(async move {
-                client.connect_to_server(server_addr, service_configs).await
+                let server_addr = entry.server.clone();
+                let connected = client
+                    .connect_to_server(entry.server, entry.token.unwrap_or_default(), shutdown)
+                    .await;
+                client.abandon_connections(&server_addr).await;
+                connected
             });
 
             tasks.push(task);
         }
 
-        // Wait for all tasks to complete
+        // Wait for all tasks to complete, a task only fails once its server is given up
+        let servers = tasks.len();
+        let mut given_up = Vec::new();
         for task in tasks {
             if let Err(e) = task.await? {
-                error!("Server connection error: {}", e);
+                given_up.push(GaveUp::of(&e));
             }
         }
 
+        for task in visitor_tasks {
+            task.abort();
+        }
+        if let Some(task) = status_task {
+            task.abort();
+        }
+        if let Some(task) = watchdog_task {
+            task.abort();
+        }
+        #[cfg(unix)]
+        {
+            for task in control_tasks {
+                task.abort();
+            }
+            if let Some(path) = &self.config.control_socket {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        sd_notify::notify("STOPPING=1");
+        // dropping their senders ends the local connections
+        self.local_connections.lock().await.clear();
+        log_info!("Client stopped");
+        if servers > 0 && given_up.len() == servers {
+            let reason = given_up.into_iter().max().unwrap_or(GaveUp::Network);
+            return Err(reason.into());
+        }
         Ok(())
     }
 
-    /// Maintains connection to a single server with automatic reconnection on failure
+    /// Remote port `server_addr` assigned to the service `name` with `remote_port = 0`,
+    /// once the server answered its registration
+    pub async fn assigned_port(&self, server_addr: &str, name: &str) -> Option<u16> {
+        let assigned_ports = self.assigned_ports.lock().await;
+        assigned_ports
+            .get(&(server_addr.to_string(), name.to_string()))
+            .copied()
+    }
+
+    /// Port to ask `server_addr` for when it picks the port of `service`: the one
+    /// it assigned on a previous connection, if any
+    async fn preferred_port(&self, server_addr: &str, service: &ServiceConfig) -> Option<u16> {
+        if service.remote_port != 0 || service.shared_route().is_some() {
+            return None;
+        }
+        self.assigned_port(server_addr, &service.name).await
+    }
+
+    /// Time `service` has left on `server_addr` if it has a `ttl`: Ok(None) without one,
+    /// Err once it ran out and `renew` is not set
+    async fn ttl_left(
+        &self,
+        server_addr: &str,
+        service: &ServiceConfig,
+    ) -> std::result::Result<Option<Duration>, HumanDuration> {
+        let Some(ttl) = service.ttl else {
+            return Ok(None);
+        };
+        let mut deadlines = self.deadlines.lock().await;
+        deadlines
+            .left(server_addr, &service.name, ttl.0, self.config.renew)
+            .map(Some)
+            .ok_or(ttl)
+    }
+
+    /// The services to register with `server_addr`
+    async fn services_of(&self, server_addr: &str) -> Vec<ServiceConfig> {
+        let services = self.services.lock().await;
+        services.get(server_addr).cloned().unwrap_or_default()
+    }
+
+    /// Records the status of a server, printing the status table when it changed
+    async fn report_server(&self, server_addr: &str, status: ServerStatus) {
+        if self.state.server_status(server_addr, status).await {
+            self.state.print().await;
+        }
+    }
+
+    /// Maintains connection to a single server with automatic reconnection on failure,
+    /// giving it up after `max_reconnect_attempts` failed attempts in a row
     async fn connect_to_server(
         &self,
         server_addr: String,
-        service_configs: Vec<ServiceConfig>,
+        token: String,
+        shutdown: CancellationToken,
     ) -> Result<()> {
+        let max_attempts = self.config.max_reconnect_attempts;
+        let mut failed_attempts = 0;
         loop {
             log_info!("Connecting to server: {}", server_addr);
 
+            // services added or removed while running are registered as they are now
+            let service_configs = self.services_of(&server_addr).await;
             match self
-                .try_connect_to_server(&server_addr, &service_configs)
+                .try_connect_to_server(&server_addr, &token, &service_configs, &shutdown)
                 .await
             {
+                _ if shutdown.is_cancelled() => return Ok(()),
+                Ok(_) if self.once => {
+                    error!(
+                        "Connection to {} closed, giving up with --once",
+                        server_addr
+                    );
+                    self.report_server(&server_addr, ServerStatus::GaveUp).await;
+                    return Err(anyhow::anyhow!("Connection to {} closed", server_addr));
+                }
                 Ok(_) => {
                     log_info!("Connection to {} closed", server_addr);
+                    // the server was reached, the next failure starts a new count
+                    failed_attempts = 0;
                 }
                 Err(e) => {
+                    if let Some(fatal) = e.downcast_ref::<ServerError>().filter(|e| e.is_fatal()) {
+                        error!(
+                            "Giving up on server {}, reconnecting cannot help: {}",
+                            server_addr, fatal
+                        );
+                        self.report_server(&server_addr, ServerStatus::GaveUp).await;
+                        return Err(e);
+                    }
+                    failed_attempts += 1;
+                    if self.once {
+                        error!(
+                            "Connection to {} failed, giving up with --once: {}",
+                            server_addr, e
+                        );
+                        self.report_server(&server_addr, ServerStatus::GaveUp).await;
+                        return Err(e);
+                    }
+                    if max_attempts > 0 && failed_attempts >= max_attempts {
+                        error!(
+                            "Giving up on server {} after {} failed attempts: {}",
+                            server_addr, failed_attempts, e
+                        );
+                        self.report_server(&server_addr, ServerStatus::GaveUp).await;
+                        return Err(e);
+                    }
                     error!("Connection to {} failed: {}", server_addr, e);
                 }
             }
+            self.report_server(&server_addr, ServerStatus::Disconnected)
+                .await;
 
             // Wait before reconnecting
             log_info!(
-                "Reconnecting to {} in {} seconds",
+                "Reconnecting to {} in {}",
                 server_addr,
                 self.config.reconnect_interval
             );
-            tokio::time::sleep(Duration::from_secs(self.config.reconnect_interval)).await;
+            tokio::select! {
+                _ = tokio::time::sleep(self.config.reconnect_interval.0) => {}
+                _ = shutdown.cancelled() => return Ok(()),
+            }
         }
     }
 
-    /// Attempts to establish a connection to a server and handle the session
-    async fn try_connect_to_server(
+    /// Resolves the server address again and connects to the first address that answers,
+    /// all within `connect_timeout`
+    async fn dial(&self, server_addr: &str) -> Result<TcpStream> {
+        let last = self.last_addrs.lock().await.get(server_addr).copied();
+        let connect = async {
+            let addrs = dial::order_addresses(dial::resolve(server_addr).await?, last);
+            dial::connect_first(&addrs, dial::ADDRESS_CONNECT_TIMEOUT).await
+        };
+        let connect_timeout = self.config.connect_timeout;
+        let (stream, addr) = match connect_timeout.non_zero() {
+            Some(limit) => timeout(limit, connect).await.map_err(|_| {
+                anyhow::anyhow!(
+                    "Timed out connecting to server {} within {}",
+                    server_addr,
+                    connect_timeout
+                )
+            })??,
+            None => connect.await?,
+        };
+        log_info!("Server {} reached at {}", server_addr, addr);
+        self.last_addrs
+            .lock()
+            .await
+            .insert(server_addr.to_string(), addr);
+        Ok(stream)
+    }
+
+    /// Connects to a server and authenticates, see `authenticate`. Also returns how the
+    /// session's proxied connections travel
+    async fn handshake(
         &self,
         server_addr: &str,
-        service_configs: &[ServiceConfig],
-    ) -> Result<()> {
-        let mut stream = TcpStream::connect(server_addr).await?;
+        token: &str,
+    ) -> Result<(
+        BoxedStream,
+        FrameReader,
+        Arc<CryptoContext>,
+        Compression,
+        DataChannel,
+    )> {
+        #[cfg(feature = "quic")]
+        if self.config.transport == Transport::Quic {
+            let (stream, connection) = self.dial_quic(server_addr).await?;
+            let session = self
+                .authenticate_stream(stream, None, server_addr, token)
+                .await?;
+            let data_channel = DataChannel::Quic(connection);
+            return Ok((
+                session.stream,
+                session.frame_reader,
+                session.crypto,
+                session.compression,
+                data_channel,
+            ));
+        }
+
+        let tcp_stream = self.dial(server_addr).await?;
+        let session = self.authenticate(tcp_stream, server_addr, token).await?;
+        let data_channel = match session.data_channels {
+            true => DataChannel::Connections {
+                server_addr: server_addr.to_string(),
+                client_id: session.client_id,
+                crypto: session.crypto.clone(),
+            },
+            false => DataChannel::Messages,
+        };
+        Ok((
+            session.stream,
+            session.frame_reader,
+            session.crypto,
+            session.compression,
+            data_channel,
+        ))
+    }
+
+    /// Connects to a server's QUIC endpoint at the address it resolves to first, returning
+    /// the connection with the control stream opened on it
+    #[cfg(feature = "quic")]
+    async fn dial_quic(&self, server_addr: &str) -> Result<(BoxedStream, quinn::Connection)> {
+        let last = self.last_addrs.lock().await.get(server_addr).copied();
+        let addrs = dial::order_addresses(dial::resolve(server_addr).await?, last);
+        let addr = addrs[0];
+        let name = tls::server_name(server_addr, &self.config.tls)?;
+
+        let endpoint = quic::client_endpoint(addr, &self.config.tls)?;
+        let connecting = endpoint.connect(addr, &name.to_str())?;
+        let connection = timeout(Duration::from_secs(30), connecting)
+            .await
+            .map_err(|_| anyhow::anyhow!("QUIC handshake with {} timed out", server_addr))?
+            .map_err(|e| anyhow::anyhow!("QUIC handshake with {} failed: {}", server_addr, e))?;
+        log_info!("Server {} reached over QUIC at {}", server_addr, addr);
+        self.last_addrs
+            .lock()
+            .await
+            .insert(server_addr.to_string(), addr);
+
+        let stream = quic::open_stream(&connection, "").await?;
+        Ok((stream, connection))
+    }
+
+    /// Runs the TLS and authentication handshake on a connection to `server_addr`
+    async fn authenticate(
+        &self,
+        tcp_stream: TcpStream,
+        server_addr: &str,
+        token: &str,
+    ) -> Result<Authenticated> {
+        self.config
+            .control_socket_options()
+            .apply(&tcp_stream, "control");
+        let (stream, tls_name) = self.wrap_stream(tcp_stream, server_addr).await?;
+        self.authenticate_stream(stream, tls_name, server_addr, token)
+            .await
+    }
+
+    /// Wraps a connection to `server_addr` in TLS and WebSocket as configured, returning the
+    /// TLS server name if it is TLS wrapped
+    async fn wrap_stream(
+        &self,
+        tcp_stream: TcpStream,
+        server_addr: &str,
+    ) -> Result<(BoxedStream, Option<ServerName<'static>>)> {
+        let (mut stream, tls_name): (BoxedStream, _) = match &self.tls_connector {
+            Some(connector) => {
+                let name = tls::server_name(server_addr, &self.config.tls)?;
+                let tls_stream = connector
+                    .connect(name.clone(), tcp_stream)
+                    .await
+                    .map_err(|e| tls::describe_handshake_error(e, server_addr, &name))?;
+                (Box::new(tls_stream), Some(name))
+            }
+            None => (Box::new(tcp_stream), None),
+        };
+        if self.config.transport == Transport::WebSocket {
+            stream = Box::new(websocket::connect(stream, server_addr).await?);
+        }
+        Ok((stream, tls_name))
+    }
+
+    /// Runs the authentication handshake on a control stream connected to `server_addr`,
+    /// `tls_name` set if the stream is TLS wrapped by this client
+    async fn authenticate_stream(
+        &self,
+        mut stream: BoxedStream,
+        tls_name: Option<ServerName<'static>>,
+        server_addr: &str,
+        token: &str,
+    ) -> Result<Authenticated> {
         log_info!("Connected to server: {}", server_addr);
 
+        // --- Receive authentication challenge ---
+
+        let mut frame_reader = FrameReader::new();
+        let (server_version, nonce) = self
+            .receive_challenge(&mut stream, &mut frame_reader, &tls_name, server_addr)
+            .await?;
+
         // --- Send authentication ---
 
         let auth_message = Message::new_auth(
-            &self.config.token,
+            token,
+            &nonce,
             &self.client_id,
             self.config.name.clone(),
+            Compression::advertised(self.config.compression),
+            self.config.data_channels && self.config.transport != Transport::Quic,
         );
         let auth_frame = Frame::new(auth_message);
         stream.write_all(&auth_frame.serialize()?).await?;
 
         // Read authentication response
-        let mut frame_reader = FrameReader::new();
-        let mut buffer = [0u8; 4096];
-
-        let n = timeout(Duration::from_secs(30), stream.read(&mut buffer)).await??;
-        if n == 0 {
-            return Err(anyhow::anyhow!("Connection closed during auth"));
-        }
-
-        frame_reader.feed_data(&buffer[..n]);
-
-        let frame = match frame_reader.try_read_frame()? {
-            Some(frame) => frame,
-            None => return Err(anyhow::anyhow!("Incomplete auth response")),
-        };
+        let frame = timeout(
+            Duration::from_secs(30),
+            frame_reader.read_frame(&mut stream),
+        )
+        .await??
+        .ok_or_else(|| anyhow::anyhow!("Connection closed during auth"))?;
 
-        let crypto = match frame.message {
+        let (crypto, compression, client_id, data_channels) = match frame.message {
             Message::AuthResponse {
                 success,
                 session_key,
                 name: server_name,
                 error,
+                compression,
+                client_id,
+                data_channels,
             } => {
                 if !success {
-                    return Err(anyhow::anyhow!(
-                        "Authentication failed: {}",
-                        error.unwrap_or_else(|| "Unknown error".to_string())
-                    ));
+                    let reason = error.unwrap_or_else(|| "Unknown error".to_string());
+                    return Err(match ServerError::from_reason(&reason) {
+                        ServerError::Other(reason) => {
+                            anyhow::anyhow!("Authentication failed: {}", reason)
+                        }
+                        fatal => fatal.into(),
+                    });
                 }
 
                 let session_key =
@@ -171,46 +896,266 @@ impl Client {
                     "Authentication successful for server: {}",
                     server_addr
                 );
-                crypto
+                log_debug!(
+                    "Negotiated compression {:?} with server {}",
+                    compression,
+                    server_addr
+                );
+                // the server knows this session by its own ID, not by ours
+                if let Some(client_id) = &client_id {
+                    log_info!(
+                        "Server {} assigned client ID {} for this session",
+                        server_addr,
+                        client_id
+                    );
+                }
+                if data_channels {
+                    log_debug!(
+                        "Server {} accepts data channels for this session",
+                        server_addr
+                    );
+                }
+                let client_id = client_id.unwrap_or_else(|| self.client_id.clone());
+                (crypto, compression, client_id, data_channels)
             }
             _ => return Err(anyhow::anyhow!("Expected auth response")),
         };
+        // the session's frames are marked as the version the server announced allows
+        frame_reader.set_framing(Framing::negotiate(server_version));
+
+        Ok(Authenticated {
+            stream,
+            frame_reader,
+            crypto,
+            compression,
+            client_id,
+            data_channels,
+        })
+    }
+
+    /// Opens a data channel carrying `connection_id` of the session `client_id` has with
+    /// `server_addr`: a connection of its own to the address the control connection reached,
+    /// wrapped like it and opened with a `DataChannelHello` answering the server's challenge
+    async fn open_data_channel(
+        &self,
+        server_addr: &str,
+        client_id: &str,
+        crypto: &CryptoContext,
+        connection_id: &str,
+    ) -> Result<BoxedStream> {
+        let addr = self
+            .last_addrs
+            .lock()
+            .await
+            .get(server_addr)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("Server {} was not reached yet", server_addr))?;
+        let open = async {
+            let tcp_stream = TcpStream::connect(addr).await?;
+            self.config
+                .socket_options()
+                .apply(&tcp_stream, "data channel");
+            let (mut stream, tls_name) = self.wrap_stream(tcp_stream, server_addr).await?;
+
+            let mut frame_reader = FrameReader::new();
+            let (_, nonce) = self
+                .receive_challenge(&mut stream, &mut frame_reader, &tls_name, server_addr)
+                .await?;
+            let hello = Message::DataChannelHello {
+                client_id: client_id.to_string(),
+                connection_id: connection_id.to_string(),
+                proof: crypto.data_channel_proof(&nonce, connection_id),
+            };
+            stream.write_all(&Frame::new(hello).serialize()?).await?;
+            Ok(stream)
+        };
+        timeout(DATA_CHANNEL_OPEN_TIMEOUT, open)
+            .await
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "Timed out within {}",
+                    HumanDuration(DATA_CHANNEL_OPEN_TIMEOUT)
+                )
+            })?
+    }
+
+    /// Reads the authentication challenge a server opens each connection with, returning
+    /// the protocol version the server announced and its nonce
+    async fn receive_challenge(
+        &self,
+        stream: &mut BoxedStream,
+        frame_reader: &mut FrameReader,
+        tls_name: &Option<ServerName<'static>>,
+        server_addr: &str,
+    ) -> Result<(u32, Vec<u8>)> {
+        // With TLS 1.3 a rejected client certificate only surfaces on this first read
+        let frame = timeout(
+            Duration::from_secs(30),
+            frame_reader.read_frame(stream),
+        )
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "Timed out waiting for the auth challenge from {} (the server may be too old, upgrade required)",
+                server_addr
+            )
+        })?
+        .map_err(|e| match (tls_name, e.downcast::<std::io::Error>()) {
+            (Some(name), Ok(e)) => tls::describe_handshake_error(e, server_addr, name),
+            (_, Ok(e)) => e.into(),
+            (_, Err(e)) => e,
+        })?;
+        let Some(frame) = frame else {
+            let hint = if self.tls_connector.is_none() {
+                " (if the server requires TLS, set tls.enable in the client config)"
+            } else {
+                ""
+            };
+            return Err(anyhow::anyhow!("Connection closed during auth{}", hint));
+        };
+
+        match frame.message {
+            Message::AuthChallenge { version, nonce } => {
+                if version != PROTOCOL_VERSION {
+                    return Err(ServerError::ProtocolVersion(format!(
+                        "Server {} speaks protocol version {} but this client speaks {}, upgrade required",
+                        server_addr,
+                        version,
+                        PROTOCOL_VERSION
+                    ))
+                    .into());
+                }
+                Ok((version, nonce))
+            }
+            _ => Err(anyhow::anyhow!("Expected auth challenge")),
+        }
+    }
+
+    /// Attempts to establish a connection to a server and handle the session
+    /// until it ends or `shutdown` is cancelled. Fails with a `ServerError` when
+    /// the server refused or ended it for good
+    async fn try_connect_to_server(
+        &self,
+        server_addr: &str,
+        token: &str,
+        service_configs: &[ServiceConfig],
+        shutdown: &CancellationToken,
+    ) -> Result<()> {
+        let (mut stream, mut frame_reader, crypto, compression, data_channel) = tokio::select! {
+            handshake = self.handshake(server_addr, token) => handshake?,
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+        // the service manager waits for the first server to accept the client
+        sd_notify::notify_ready();
 
         // --- Send service configurations ---
 
-        for service_config in service_configs {
-            let service_str = format!(
-                "{}:{}:{}",
-                service_config.local_ip, service_config.local_port, service_config.remote_port
-            );
+        // every local service is checked at once, before any is registered
+        let outcomes = join_all(service_configs.iter().map(|service| async move {
+            match service.health_check {
+                true => Some(health::check(service).await),
+                false => None,
+            }
+        }))
+        .await;
 
-            let service_message = Message::ProxyConfig {
-                op: ProxyConfigOpCode::Update,
-                local_ip: service_config.local_ip.clone(),
-                local_port: service_config.local_port,
-                remote_port: service_config.remote_port,
+        let mut registrations = Registrations::default();
+        let mut expired_services = Vec::new();
+        let mut healths = HashMap::new();
+        let mut held_back = Vec::new();
+        for (service_config, outcome) in service_configs.iter().zip(outcomes) {
+            // a reconnect does not give the service a new ttl
+            let ttl = match self.ttl_left(server_addr, service_config).await {
+                Ok(ttl) => ttl,
+                Err(ttl) => {
+                    warn!(
+                        "Service '{}' expired on {} after its ttl of {}, not registering it again without --renew",
+                        service_config.name, server_addr, ttl
+                    );
+                    expired_services.push(service_config.name.clone());
+                    continue;
+                }
             };
+            if let Some(outcome) = outcome {
+                let held = outcome.is_err() && service_config.require_healthy;
+                if let Err(e) = &outcome {
+                    warn!(
+                        "Local service of '{}' at {} is unreachable: {}, {}",
+                        service_config.name,
+                        service_config.local_addr(),
+                        e,
+                        match held {
+                            true => "registering it once it answers",
+                            false => "registering it anyway",
+                        }
+                    );
+                }
+                let health: &mut Health = healths.entry(service_config.name.clone()).or_default();
+                health.record(outcome);
+                if held {
+                    held_back.push(service_config.clone());
+                    continue;
+                }
+            }
+            let preferred_port = self.preferred_port(server_addr, service_config).await;
+
+            let service_message = registrations.register(service_config, preferred_port, ttl);
             let service_frame = Frame::new(service_message);
-            stream.write_all(&service_frame.serialize()?).await?;
+            stream
+                .write_all(&service_frame.serialize_framed(frame_reader.framing())?)
+                .await?;
 
-            log_info!(
-                "Sent service config '{}': {}:{} -> :{}",
-                service_str,
-                service_config.local_ip,
-                service_config.local_port,
-                service_config.remote_port
-            );
-            console_info!(
-                "Registered service '{}': {}",
-                service_str,
-                format_service_config(
-                    &service_config.local_ip,
-                    service_config.local_port,
-                    service_config.remote_port
-                )
-            );
+            match service_config.shared_route() {
+                _ if service_config.secret.is_some() => {
+                    log_info!(
+                        "Sent secret service config '{}': {}",
+                        service_config.name,
+                        service_config.local_addr()
+                    );
+                }
+                _ if service_config.relay_peers.is_some() => {
+                    log_info!(
+                        "Sent relay service config '{}': {}",
+                        service_config.name,
+                        service_config.local_addr()
+                    );
+                }
+                Some(route) => {
+                    log_info!(
+                        "Sent service config '{}': {} <- {}",
+                        service_config.name,
+                        service_config.local_addr(),
+                        route
+                    );
+                }
+                None => {
+                    log_info!(
+                        "Sent service config '{}': {} -> :{}",
+                        service_config.name,
+                        service_config.local_addr(),
+                        service_config.remote_port
+                    );
+                }
+            }
+            if service_config.remote_port != 0 && service_config.shared_route().is_none() {
+                console_info!(
+                    "Registered service '{}': {}",
+                    service_config.name,
+                    format_service_config(&service_config.local_addr(), service_config.remote_port)
+                );
+            }
         }
 
+        // the status table shows what the checks found
+        let local_errors: Vec<(String, Option<String>)> = healths
+            .iter()
+            .map(|(name, health)| (name.clone(), health.error().map(str::to_string)))
+            .collect();
+        let waiting: Vec<String> = held_back
+            .iter()
+            .map(|service| service.name.clone())
+            .collect();
+
         // -- Create connection channels --
 
         let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
@@ -225,231 +1170,804 @@ impl Client {
                     sender: tx,
                     crypto: Some(crypto.clone()),
                     connected: true,
+                    compression,
+                    compression_threshold: self.config.compression_threshold.as_usize(),
+                    registrations,
+                    proxies: HashMap::new(),
+                    heartbeats: Heartbeats::default(),
+                    data_channel: data_channel.clone(),
+                    visits: Visits::default(),
+                    health: healths,
+                    held_back,
                 },
             );
         }
+        self.resume_connections(server_addr).await;
+        // the table is printed once the server answered every registration
+        self.state
+            .server_status(server_addr, ServerStatus::Connected)
+            .await;
+        for name in &expired_services {
+            self.state
+                .service_status(server_addr, name, ServiceStatus::Expired)
+                .await;
+        }
+        for name in &waiting {
+            self.state
+                .service_status(server_addr, name, ServiceStatus::Waiting)
+                .await;
+        }
+        for (name, error) in local_errors {
+            self.state.local_error(server_addr, &name, error).await;
+        }
+        if !self.state.has_pending(server_addr).await {
+            self.state.print().await;
+        }
 
-        // Start heartbeat task
-        let heartbeat_tx = {
+        // Start heartbeat task, it ends when the server stops answering
+        let mut heartbeat_task = {
             let connections = self.connections.clone();
             let server_addr = server_addr.to_string();
             let heartbeat_interval = self.config.heartbeat_interval;
+            let max_missed = self.config.heartbeat_max_missed;
 
             tokio::spawn(async move {
-                let mut interval = interval(Duration::from_secs(heartbeat_interval));
+                let mut interval = interval(heartbeat_interval.0);
 
                 loop {
                     interval.tick().await;
 
-                    let connections_guard = connections.lock().await;
-                    if let Some(conn) = connections_guard.get(&server_addr) {
-                        if conn.connected {
-                            let heartbeat = Message::new_heartbeat();
-                            if let Err(e) = conn.sender.send(heartbeat) {
-                                error!("Failed to send heartbeat: {}", e);
-                                break;
-                            }
-                        } else {
-                            break;
-                        }
-                    } else {
+                    let mut connections_guard = connections.lock().await;
+                    let Some(conn) = connections_guard.get_mut(&server_addr) else {
+                        break;
+                    };
+                    if !conn.connected {
+                        break;
+                    }
+                    let missed = conn.heartbeats.missed();
+                    if max_missed > 0 && missed >= max_missed {
+                        warn!(
+                            "Server {} missed {} heartbeats, reconnecting",
+                            server_addr, missed
+                        );
+                        break;
+                    }
+                    let heartbeat = conn.heartbeats.new_heartbeat();
+                    if let Err(e) = conn.sender.send(heartbeat) {
+                        error!("Failed to send heartbeat: {}", e);
                         break;
                     }
                 }
             })
         };
 
-        // Convert service_configs to owned data
-        let service_configs_owned: Vec<ServiceConfig> = service_configs.to_vec();
-
-        // Handle incoming messages
-        let (mut stream_read, mut stream_write) = stream.into_split();
-
-        let read_task = {
-            let connections = self.connections.clone();
-            let local_connections = self.local_connections.clone();
+        // Check the local services again while connected, the first check was just done
+        let health_task = {
+            let client = self.clone();
             let server_addr = server_addr.to_string();
+            let services: Vec<ServiceConfig> = service_configs
+                .iter()
+                .filter(|service| service.health_check)
+                .cloned()
+                .collect();
 
             tokio::spawn(async move {
-                let mut frame_reader = FrameReader::new();
-                let mut buffer = [0u8; 4096];
+                let Some(period) = client.config.health_check_interval.non_zero() else {
+                    return;
+                };
+                if services.is_empty() {
+                    return;
+                }
+                let mut interval = interval(period);
+                interval.tick().await;
 
                 loop {
-                    match stream_read.read(&mut buffer).await {
-                        Ok(0) => break,
-                        Ok(n) => {
-                            frame_reader.feed_data(&buffer[..n]);
-
-                            while let Some(frame) = frame_reader.try_read_frame().unwrap_or(None) {
-                                Self::handle_server_message(
-                                    frame.message,
-                                    &connections,
-                                    &local_connections,
-                                    &service_configs_owned,
-                                    &server_addr,
-                                )
-                                .await;
+                    interval.tick().await;
+                    let outcomes = join_all(services.iter().map(health::check)).await;
+                    for (service, outcome) in services.iter().zip(outcomes) {
+                        client.record_health(&server_addr, service, outcome).await;
+                    }
+                }
+            })
+        };
+
+        // Resend registrations the server leaves unanswered, until they are answered or given up
+        let register_task = {
+            let connections = self.connections.clone();
+            let server_addr = server_addr.to_string();
+            let register_timeout = self.config.service_register_timeout;
+            let retries = self.config.service_register_retries;
+            let state = self.state.clone();
+
+            tokio::spawn(async move {
+                let Some(wait) = register_timeout.non_zero() else {
+                    return;
+                };
+                let mut interval = interval(wait.min(Duration::from_secs(1)));
+
+                loop {
+                    interval.tick().await;
+
+                    let mut connections_guard = connections.lock().await;
+                    let Some(conn) = connections_guard.get_mut(&server_addr) else {
+                        break;
+                    };
+                    if !conn.connected || conn.registrations.is_empty() {
+                        break;
+                    }
+                    for expired in conn.registrations.expired(wait, retries) {
+                        match expired {
+                            Expired::Retry {
+                                service,
+                                request,
+                                attempt,
+                            } => {
+                                warn!(
+                                    "Service '{}' not answered by {} within {}, retrying ({}/{})",
+                                    service,
+                                    server_addr,
+                                    register_timeout,
+                                    attempt - 1,
+                                    retries
+                                );
+                                let _ = conn.sender.send(*request);
+                            }
+                            Expired::GaveUp { service, attempts } => {
+                                error!(
+                                    "Service '{}' failed to register with {}: no response after {} attempts",
+                                    service, server_addr, attempts
+                                );
+                                let status = ServiceStatus::Rejected(format!(
+                                    "no response after {} attempts",
+                                    attempts
+                                ));
+                                if state.service_status(&server_addr, &service, status).await {
+                                    state.print_answered(&server_addr).await;
+                                }
+                            }
+                        }
+                    }
+                }
+            })
+        };
+
+        // Handle incoming messages
+        let (mut stream_read, mut stream_write) = tokio::io::split(stream);
+        let framing = frame_reader.framing();
+
+        let mut read_task = {
+            let client = self.clone();
+            let connections = self.connections.clone();
+            let server_addr = server_addr.to_string();
+
+            tokio::spawn(async move {
+                // a fatal error reported by the server ends the session for good
+                let fatal = 'read: loop {
+                    // frames may already be buffered from the handshake
+                    loop {
+                        let frame = match frame_reader.try_read_frame() {
+                            Ok(Some(frame)) => frame,
+                            Ok(None) => break,
+                            Err(e) => {
+                                // the stream cannot be trusted past a bad frame
+                                error!("Closing connection to server {}: {}", server_addr, e);
+                                break 'read None;
                             }
+                        };
+                        if let Err(e) = frame.message.check_payload_size() {
+                            error!("Closing connection to server {}: {}", server_addr, e);
+                            break 'read None;
+                        }
+                        let fatal = match &frame.message {
+                            Message::Error { message } => Some(ServerError::from_reason(message))
+                                .filter(|error| error.is_fatal()),
+                            _ => None,
+                        };
+                        client
+                            .handle_server_message(frame.message, &server_addr)
+                            .await;
+                        if fatal.is_some() {
+                            break 'read fatal;
                         }
+                    }
+
+                    let skipped = frame_reader.take_skipped();
+                    if skipped > 0 {
+                        warn!(
+                            "Skipped {} bytes of corrupt frames from server {}",
+                            skipped, server_addr
+                        );
+                    }
+
+                    match frame_reader.fill_from(&mut stream_read).await {
+                        Ok(0) => break None,
+                        Ok(_) => {}
                         Err(e) => {
                             error!("Error reading from server {}: {}", server_addr, e);
-                            break;
+                            break None;
                         }
                     }
-                }
+                };
 
                 // Mark connection as disconnected
                 let mut connections_guard = connections.lock().await;
                 if let Some(conn) = connections_guard.get_mut(&server_addr) {
                     conn.connected = false;
                 }
+                fatal
             })
         };
 
-        // Handle outgoing messages
-        let write_task = {
+        // Handle outgoing messages, a write stalled for too long takes the server as gone.
+        // Once `closing` is cancelled, the messages queued are written and the task ends
+        let closing = CancellationToken::new();
+        let mut write_task = {
+            let write_timeout = self.config.control_write_timeout();
+            let write_server_addr = server_addr.to_string();
+            let closing = closing.clone();
             tokio::spawn(async move {
-                while let Some(message) = rx.recv().await {
-                    let frame = Frame::new(message);
-                    match frame.serialize() {
-                        Ok(data) => {
-                            if let Err(e) = stream_write.write_all(&data).await {
-                                error!("Error writing to server: {}", e);
-                                break;
-                            }
-                        }
-                        Err(e) => {
-                            error!("Error serializing message: {}", e);
-                            break;
+                let mut buffer = BytesMut::new();
+                loop {
+                    let message = tokio::select! {
+                        message = rx.recv() => message,
+                        _ = closing.cancelled(), if !rx.is_closed() => {
+                            rx.close();
+                            continue;
                         }
+                    };
+                    let Some(message) = message else {
+                        break;
+                    };
+                    buffer.clear();
+                    if let Err(e) = Frame::new(message).encode_framed(&mut buffer, framing) {
+                        error!("Error serializing message: {}", e);
+                        break;
+                    }
+                    let written =
+                        write_all_within(&mut stream_write, &buffer, write_timeout.non_zero());
+                    if let Err(e) = written.await {
+                        error!("Error writing to server {}: {}", write_server_addr, e);
+                        break;
                     }
                 }
             })
         };
 
-        // Wait for tasks to complete
-        tokio::select! {
-            _ = read_task => {},
-            _ = write_task => {},
-            _ = heartbeat_tx => {},
+        // Wait for any task to complete, the others go with it
+        let (fatal, stopping) = tokio::select! {
+            fatal = &mut read_task => (fatal.ok().flatten(), false),
+            _ = &mut write_task => (None, false),
+            _ = &mut heartbeat_task => (None, false),
+            _ = shutdown.cancelled() => (None, true),
+        };
+        if stopping {
+            // the server learns why the connections end before the session does
+            self.close_local_connections(server_addr, CloseCode::ClientShutdown)
+                .await;
+            closing.cancel();
+            let _ = timeout(CLOSE_FLUSH_TIMEOUT, &mut write_task).await;
         }
+        read_task.abort();
+        write_task.abort();
+        heartbeat_task.abort();
+        health_task.abort();
+        register_task.abort();
+        data_channel.close();
 
-        // Clean up connection
-        {
-            let mut connections = self.connections.lock().await;
-            connections.remove(server_addr);
+        // Clean up connection, its proxied connections wait for the next session
+        self.detach_connections(server_addr).await;
+        let ended = self.connections.lock().await.remove(server_addr);
+        if let Some(conn) = ended {
+            debug!(
+                "Session with {} ended, session key held: {}",
+                conn.server_addr,
+                conn.crypto.is_some()
+            );
         }
 
-        Ok(())
+        match fatal {
+            Some(error) => Err(error.into()),
+            None => Ok(()),
+        }
     }
 
-    /// Processes messages received from a server
-    async fn handle_server_message(
-        message: Message,
-        connections: &Arc<Mutex<HashMap<String, ServerConnection>>>,
-        local_connections: &Arc<Mutex<HashMap<String, LocalConnection>>>,
-        service_configs: &[ServiceConfig],
+    /// Tells `server_addr` each local connection through it is closed for `code`, and drops
+    /// them. Without their senders their handlers end without closing them again
+    async fn close_local_connections(&self, server_addr: &str, code: CloseCode) {
+        let Some(sender) = self
+            .connections
+            .lock()
+            .await
+            .get(server_addr)
+            .map(|conn| conn.sender.clone())
+        else {
+            return;
+        };
+        self.local_connections
+            .lock()
+            .await
+            .retain(|connection_id, local_conn| {
+                if local_conn.server_addr != server_addr {
+                    return true;
+                }
+                let _ = sender.send(Message::new_close_connection(connection_id, code));
+                false
+            });
+    }
+
+    /// Drops the service of an expired proxy, registering it again for a new `ttl`
+    /// only when `renew` is set
+    async fn handle_proxy_expired(&self, proxy_id: &str, server_addr: &str) {
+        let service = {
+            let mut connections_guard = self.connections.lock().await;
+            connections_guard
+                .get_mut(server_addr)
+                .and_then(|conn| conn.proxies.remove(proxy_id))
+        };
+        let Some(service) = service else {
+            warn!(
+                "Server {} expired unknown proxy {}, ignoring it",
+                server_addr, proxy_id
+            );
+            return;
+        };
+        // servers only expire services registered with a ttl
+        let ttl = service.ttl.unwrap_or_default();
+
+        if !self.config.renew {
+            warn!(
+                "Service '{}' expired on {} after its ttl of {}, not registering it again without --renew",
+                service.name, server_addr, ttl
+            );
+            if self
+                .state
+                .service_status(server_addr, &service.name, ServiceStatus::Expired)
+                .await
+            {
+                self.state.print().await;
+            }
+            return;
+        }
+        // with renew set a ttl that ran out starts again
+        let ttl_left = self.ttl_left(server_addr, &service).await.ok().flatten();
+        warn!(
+            "Service '{}' expired on {} after its ttl of {}, registering it again",
+            service.name, server_addr, ttl
+        );
+        self.send_registration(server_addr, &service, ttl_left)
+            .await;
+    }
+
+    /// Registers `service` with `server_addr` on the current session, outside the
+    /// registrations sent when the session starts
+    async fn send_registration(
+        &self,
+        server_addr: &str,
+        service: &ServiceConfig,
+        ttl: Option<Duration>,
+    ) {
+        let preferred_port = self.preferred_port(server_addr, service).await;
+        if let Some(conn) = self.connections.lock().await.get_mut(server_addr) {
+            let request = conn.registrations.register(service, preferred_port, ttl);
+            let _ = conn.sender.send(request);
+        }
+        self.state
+            .service_status(server_addr, &service.name, ServiceStatus::Pending)
+            .await;
+    }
+
+    /// Records the outcome of a periodic health check of the local service of `service`.
+    /// A change is logged, shown in the status table and told to the server if the service
+    /// is registered, and a service held back for it is registered once it answers
+    async fn record_health(
+        &self,
         server_addr: &str,
+        service: &ServiceConfig,
+        outcome: std::result::Result<(), String>,
     ) {
+        let held = {
+            let mut connections_guard = self.connections.lock().await;
+            let Some(conn) = connections_guard.get_mut(server_addr) else {
+                return;
+            };
+            let Some(reported) = conn
+                .health
+                .entry(service.name.clone())
+                .or_default()
+                .record(outcome)
+            else {
+                return;
+            };
+            match &reported {
+                Ok(()) => {
+                    info!(
+                        "Local service of '{}' at {} answers",
+                        service.name,
+                        service.local_addr()
+                    );
+                }
+                Err(e) => {
+                    warn!(
+                        "Local service of '{}' at {} is unreachable: {}",
+                        service.name,
+                        service.local_addr(),
+                        e
+                    );
+                }
+            }
+            let proxy_id = conn
+                .proxies
+                .iter()
+                .find(|(_, registered)| registered.name == service.name)
+                .map(|(proxy_id, _)| proxy_id.clone());
+            if let Some(proxy_id) = proxy_id {
+                let _ = conn.sender.send(Message::ServiceStatus {
+                    proxy_id,
+                    error: reported.clone().err(),
+                });
+            }
+            if self
+                .state
+                .local_error(server_addr, &service.name, reported.clone().err())
+                .await
+            {
+                self.state.print().await;
+            }
+            let held = conn
+                .held_back
+                .iter()
+                .position(|held| held.name == service.name && reported.is_ok());
+            held.map(|index| conn.held_back.remove(index))
+        };
+
+        let Some(service) = held else {
+            return;
+        };
+        match self.ttl_left(server_addr, &service).await {
+            Ok(ttl) => self.send_registration(server_addr, &service, ttl).await,
+            Err(ttl) => {
+                warn!(
+                    "Service '{}' expired on {} after its ttl of {} while waiting for its local service",
+                    service.name, server_addr, ttl
+                );
+                self.state
+                    .service_status(server_addr, &service.name, ServiceStatus::Expired)
+                    .await;
+            }
+        }
+    }
+
+    /// Processes messages received from a server
+    async fn handle_server_message(&self, message: Message, server_addr: &str) {
+        let connections = &self.connections;
+        let local_connections = &self.local_connections;
+        let assigned_ports = &self.assigned_ports;
+        let state = &self.state;
+        let idle_timeout = self.config.idle_timeout;
+        let socket_options = self.config.socket_options();
         match message {
             Message::ProxyConfigResponse {
+                request_id,
                 success,
                 proxy_id,
                 error,
+                assigned_port,
             } => {
+                let service = {
+                    let mut connections_guard = connections.lock().await;
+                    let Some(conn) = connections_guard.get_mut(server_addr) else {
+                        return;
+                    };
+                    let Some(service) = conn.registrations.answered(request_id) else {
+                        let anomaly = if conn.registrations.was_sent(request_id) {
+                            "Duplicate"
+                        } else {
+                            "Unmatched"
+                        };
+                        warn!(
+                            "{} proxy config response {} from {}, ignoring it",
+                            anomaly, request_id, server_addr
+                        );
+                        return;
+                    };
+                    if let (true, Some(id)) = (success, &proxy_id) {
+                        conn.proxies.insert(id.clone(), service.clone());
+                        // the server hears of a local service found unreachable before
+                        let error = conn.health.get(&service.name).and_then(Health::error);
+                        if let Some(error) = error {
+                            let _ = conn.sender.send(Message::ServiceStatus {
+                                proxy_id: id.clone(),
+                                error: Some(error.to_string()),
+                            });
+                        }
+                    }
+                    service
+                };
+
+                if let (true, Some(port)) = (success, assigned_port) {
+                    if let Some(route) = service.shared_route() {
+                        console_info!(
+                            "Service '{}' serving {}:{} on {}: {}",
+                            service.name,
+                            route,
+                            port,
+                            server_addr,
+                            service.local_addr()
+                        );
+                    } else if service.remote_port == 0 {
+                        let previous = assigned_ports
+                            .lock()
+                            .await
+                            .insert((server_addr.to_string(), service.name.clone()), port);
+                        // the port of the previous session was asked for, it was taken
+                        if let Some(previous) = previous.filter(|previous| *previous != port) {
+                            warn!(
+                                "Service '{}' lost remote port {} on {}, moved to port {}",
+                                service.name, previous, server_addr, port
+                            );
+                        }
+                        console_info!(
+                            "Service '{}' assigned remote port {} by {}: {}",
+                            service.name,
+                            port,
+                            server_addr,
+                            format_service_config(&service.local_addr(), port)
+                        );
+                    }
+                }
+
+                if success && service.secret.is_some() {
+                    console_info!(
+                        "Secret service '{}' open to visitors of {}: {}",
+                        service.name,
+                        server_addr,
+                        service.local_addr()
+                    );
+                }
+                if let (true, Some(peers)) = (success, &service.relay_peers) {
+                    console_info!(
+                        "Relay service '{}' open to {} through {}: {}",
+                        service.name,
+                        peers.join(", "),
+                        server_addr,
+                        service.local_addr()
+                    );
+                }
+
+                let status = match (success, &error) {
+                    (true, _) => ServiceStatus::Registered(assigned_port),
+                    (false, error) => ServiceStatus::Rejected(
+                        error.clone().unwrap_or_else(|| "Unknown error".to_string()),
+                    ),
+                };
+                if state
+                    .service_status(server_addr, &service.name, status)
+                    .await
+                {
+                    state.print_answered(server_addr).await;
+                }
+
+                let service_name = &service.name;
                 if success {
                     if let Some(id) = proxy_id {
-                        log_info!("Service configuration accepted by {}: {}", server_addr, id);
+                        log_info!(
+                            "Service '{}' accepted by {}: {}",
+                            service_name,
+                            server_addr,
+                            id
+                        );
                     } else {
-                        log_info!("Service configuration accepted by {}", server_addr);
+                        log_info!("Service '{}' accepted by {}", service_name, server_addr);
                     }
                 } else {
                     error!(
-                        "Service configuration rejected by {}: {}",
+                        "Service '{}' rejected by {}: {}",
+                        service_name,
                         server_addr,
                         error.clone().unwrap_or_else(|| "Unknown error".to_string())
                     );
                 }
             }
-            Message::HeartbeatResponse { timestamp } => {
-                debug!("Heartbeat response from {}: {}", server_addr, timestamp);
+            Message::HeartbeatResponse { timestamp, stats } => {
+                let (rtt, change) = match connections.lock().await.get_mut(server_addr) {
+                    Some(conn) => (
+                        conn.heartbeats.answered(timestamp),
+                        stats.and_then(|stats| conn.heartbeats.record_stats(stats)),
+                    ),
+                    None => (None, None),
+                };
+                if let Some(stats) = stats {
+                    self.state.server_stats(server_addr, stats).await;
+                }
+                if let Some(change) = change {
+                    log_info!("Server {}: {}", server_addr, change);
+                }
+                match rtt {
+                    Some(rtt) => {
+                        debug!("Heartbeat response from {}: rtt {:.1?}", server_addr, rtt);
+                    }
+                    None => {
+                        debug!("Heartbeat response from {}: {}", server_addr, timestamp);
+                    }
+                }
+            }
+            Message::SessionClosed { reason } => {
+                warn!("Server {} closed the session: {}", server_addr, reason);
+            }
+            Message::ProxyExpired { proxy_id } => {
+                self.handle_proxy_expired(&proxy_id, server_addr).await;
+            }
+            Message::Error { message } => {
+                error!("Server {} reported an error: {}", server_addr, message);
             }
             Message::NewConnection {
                 proxy_id,
                 connection_id,
+                source_addr,
+                dest_addr,
             } => {
-                log_info!(
-                    "New connection request from {}: proxy={}, conn={}",
-                    server_addr,
-                    proxy_id,
-                    connection_id
-                );
-                console_info!(
-                    "New connection: proxy={}, conn={}",
-                    format_uuid(&proxy_id, "proxy"),
-                    format_uuid(&connection_id, "conn")
+                // every event of the connection carries the IDs the server logs it with
+                let span = tracing::info_span!(
+                    "local_conn",
+                    conn = short_id(&connection_id),
+                    proxy = short_id(&proxy_id),
+                    server = %server_addr,
+                    peer = %source_addr,
+                    service = tracing::field::Empty,
                 );
+                async {
+                    // Find the service registered under this proxy
+                    let (service_config, route) = {
+                        let connections_guard = connections.lock().await;
+                        let conn = connections_guard.get(server_addr);
+                        (
+                            conn.and_then(|conn| conn.proxies.get(&proxy_id).cloned()),
+                            conn.map(|conn| {
+                                (
+                                    conn.data_channel.clone(),
+                                    conn.route(),
+                                    conn.compression_threshold,
+                                )
+                            }),
+                        )
+                    };
+                    // the session is gone, there is nobody left to answer
+                    let Some((data_channel, route, compression_threshold)) = route else {
+                        return;
+                    };
+                    let sender = route.sender.clone();
+                    let Some(service_config) = service_config else {
+                        error!(
+                            "Failed to connect to local service: Unknown proxy {}",
+                            proxy_id
+                        );
+                        let _ = sender.send(Message::ConnectionResponse {
+                            connection_id,
+                            success: false,
+                            error: Some(format!(
+                                "Failed to connect to local service: Unknown proxy {}",
+                                proxy_id
+                            )),
+                        });
+                        return;
+                    };
+                    tracing::Span::current().record("service", service_config.name.as_str());
+                    log_info!(
+                        "New connection from {} to '{}' via {}: proxy={}, conn={}",
+                        source_addr,
+                        service_config.name,
+                        server_addr,
+                        proxy_id,
+                        connection_id
+                    );
+                    console_info!(
+                        "New connection from {} to '{}': conn={}",
+                        source_addr,
+                        service_config.name,
+                        format_uuid(&connection_id, "conn")
+                    );
 
-                // Find the corresponding service config
-                if let Some(service_config) = service_configs.first() {
-                    // Establish local connection
-                    let local_addr =
-                        format!("{}:{}", service_config.local_ip, service_config.local_port);
-
-                    match TcpStream::connect(&local_addr).await {
-                        Ok(local_stream) => {
-                            log_info!("Connected to local service at {}", local_addr);
-
-                            // Send success response
-                            let connections_guard = connections.lock().await;
-                            if let Some(conn) = connections_guard.get(server_addr) {
-                                let response = Message::ConnectionResponse {
-                                    connection_id: connection_id.clone(),
-                                    success: true,
-                                    error: None,
-                                };
-                                let _ = conn.sender.send(response);
-                            }
+                    // Register before reaching the local service, data for it may be the very
+                    // next message and waits here until the local service is reached
+                    let (local_tx, local_rx) = mpsc::unbounded_channel::<WriteCommand>();
+                    local_connections.lock().await.insert(
+                        connection_id.clone(),
+                        LocalConnection::new(
+                            server_addr,
+                            &connection_id,
+                            local_tx,
+                            Some(route),
+                            compression_threshold,
+                        ),
+                    );
 
-                            // Start handling the local connection
-                            let connections_clone = connections.clone();
-                            let local_connections_clone = local_connections.clone();
-                            let server_addr_clone = server_addr.to_string();
-                            let connection_id_clone = connection_id.clone();
-
-                            tokio::spawn(async move {
-                                Self::handle_local_connection(
-                                    local_stream,
-                                    connections_clone,
-                                    local_connections_clone,
-                                    server_addr_clone,
-                                    connection_id_clone,
-                                )
-                                .await;
-                            });
-                        }
-                        Err(e) => {
-                            error!("Failed to connect to local service {}: {}", local_addr, e);
-
-                            // Send error response
-                            let connections_guard = connections.lock().await;
-                            if let Some(conn) = connections_guard.get(server_addr) {
-                                let response = Message::ConnectionResponse {
-                                    connection_id,
-                                    success: false,
-                                    error: Some(format!(
-                                        "Failed to connect to local service: {}",
-                                        e
-                                    )),
-                                };
-                                let _ = conn.sender.send(response);
+                    // reaching it may take retries, which must not hold up other messages
+                    let connections = connections.clone();
+                    let local_connections = local_connections.clone();
+                    let server_addr = server_addr.to_string();
+                    let client = self.clone();
+                    let local_connect_timeout = service_config
+                        .local_connect_timeout
+                        .unwrap_or(self.config.local_connect_timeout);
+                    tokio::spawn(
+                        async move {
+                            let result = Self::open_local_service(
+                                &service_config,
+                                &source_addr,
+                                &dest_addr,
+                                socket_options,
+                                local_connect_timeout,
+                            )
+                            .await;
+
+                            let in_messages =
+                                service_config.http_host.is_some() || service_config.is_visited();
+                            let result = match result {
+                                Ok(local_stream) => match data_channel
+                                    .carry(
+                                        &client,
+                                        local_stream,
+                                        &connection_id,
+                                        in_messages,
+                                        sender.clone(),
+                                        idle_timeout,
+                                    )
+                                    .await
+                                {
+                                    // the stream carrying it answers the server
+                                    None => {
+                                        local_connections.lock().await.remove(&connection_id);
+                                        return;
+                                    }
+                                    Some(local_stream) => Ok(local_stream),
+                                },
+                                Err(reason) => Err(reason),
+                            };
+
+                            match result {
+                                Ok(local_stream) => {
+                                    let _ = sender.send(Message::ConnectionResponse {
+                                        connection_id: connection_id.clone(),
+                                        success: true,
+                                        error: None,
+                                    });
+                                    Self::handle_local_connection(
+                                        local_stream,
+                                        local_rx,
+                                        connections,
+                                        local_connections,
+                                        server_addr,
+                                        connection_id,
+                                        idle_timeout,
+                                    )
+                                    .await;
+                                }
+                                Err(reason) => {
+                                    error!(
+                                        "Failed to connect to local service for {}: {}",
+                                        source_addr, reason
+                                    );
+                                    local_connections.lock().await.remove(&connection_id);
+                                    let _ = sender.send(Message::ConnectionResponse {
+                                        connection_id,
+                                        success: false,
+                                        error: Some(format!(
+                                            "Failed to connect to local service: {}",
+                                            reason
+                                        )),
+                                    });
+                                }
                             }
                         }
-                    }
+                        .in_current_span(),
+                    );
                 }
+                .instrument(span)
+                .await;
             }
             Message::Data {
                 connection_id,
+                seq,
                 data,
             } => {
                 debug!(
@@ -459,22 +1977,162 @@ impl Client {
                     data.len()
                 );
 
-                // Forward data to local connection
-                let local_connections_guard = local_connections.lock().await;
-                if let Some(local_conn) = local_connections_guard.get(&connection_id) {
-                    if let Err(e) = local_conn.sender.send(data) {
-                        error!("Failed to forward data to local connection: {}", e);
+                self.forward_to_local_connection(server_addr, &connection_id, seq, data)
+                    .await;
+            }
+            Message::CompressedData {
+                connection_id,
+                seq,
+                codec,
+                data,
+            } => {
+                let data = match codec.decompress(&data) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        error!(
+                            "Failed to decompress data from {} for conn={}: {}",
+                            server_addr, connection_id, e
+                        );
+                        return;
                     }
-                } else {
-                    warn!("Local connection {} not found", connection_id);
+                };
+                debug!(
+                    "Data from {}: conn={}, len={} ({:?} compressed)",
+                    server_addr,
+                    connection_id,
+                    data.len(),
+                    codec
+                );
+
+                self.forward_to_local_connection(server_addr, &connection_id, seq, data)
+                    .await;
+            }
+            Message::VisitorConnectResponse {
+                request_id,
+                success,
+                connection_id,
+                error,
+            } => {
+                let waiting = connections
+                    .lock()
+                    .await
+                    .get_mut(server_addr)
+                    .and_then(|conn| conn.visits.answered(request_id));
+                let Some(waiting) = waiting else {
+                    warn!(
+                        "Unmatched visitor response {} from {}, ignoring it",
+                        request_id, server_addr
+                    );
+                    return;
+                };
+                let outcome = match (success, connection_id) {
+                    (true, Some(connection_id)) => {
+                        // Register before returning, data for it may be the very next message
+                        let (local_tx, local_rx) = mpsc::unbounded_channel::<WriteCommand>();
+                        let (route, compression_threshold) = connections
+                            .lock()
+                            .await
+                            .get(server_addr)
+                            .map(|conn| (Some(conn.route()), conn.compression_threshold))
+                            .unwrap_or_default();
+                        local_connections.lock().await.insert(
+                            connection_id.clone(),
+                            LocalConnection::new(
+                                server_addr,
+                                &connection_id,
+                                local_tx,
+                                route,
+                                compression_threshold,
+                            ),
+                        );
+                        Ok((connection_id, local_rx))
+                    }
+                    _ => Err(error.unwrap_or_else(|| "unknown error".to_string())),
+                };
+                // the visitor stopped waiting, the connection has no one to serve
+                if let Err(Ok((connection_id, _))) = waiting.send(outcome) {
+                    local_connections.lock().await.remove(&connection_id);
+                    if let Some(conn) = connections.lock().await.get(server_addr) {
+                        let _ = conn.sender.send(Message::new_close_connection(
+                            &connection_id,
+                            CloseCode::PeerClosed,
+                        ));
+                    }
+                }
+            }
+            Message::WindowUpdate {
+                connection_id,
+                bytes,
+            } => {
+                if let Some(local_conn) = local_connections.lock().await.get(&connection_id) {
+                    local_conn.window.grant(bytes);
+                    local_conn.replay.acknowledge(bytes);
+                }
+            }
+            Message::ResumeConnection {
+                connection_id,
+                received,
+                acknowledged,
+            } => {
+                self.resume_local_connection(server_addr, &connection_id, received, acknowledged)
+                    .await;
+            }
+            Message::Pause { connection_id } => {
+                if let Some(local_conn) = local_connections.lock().await.get(&connection_id) {
+                    debug!("Server {} paused conn={}", server_addr, connection_id);
+                    local_conn.window.pause();
+                }
+            }
+            Message::Resume { connection_id } => {
+                if let Some(local_conn) = local_connections.lock().await.get(&connection_id) {
+                    debug!("Server {} resumed conn={}", server_addr, connection_id);
+                    local_conn.window.resume();
                 }
             }
-            Message::CloseConnection { connection_id } => {
-                log_info!("Close connection from {}: {}", server_addr, connection_id);
+            Message::ShutdownWrite { connection_id } => {
+                debug!(
+                    "Server {} finished sending on conn={}",
+                    server_addr, connection_id
+                );
+                if let Some(local_conn) = local_connections.lock().await.get(&connection_id) {
+                    let _ = local_conn.sender.send(WriteCommand::ShutdownWrite);
+                }
+            }
+            Message::CloseConnection {
+                connection_id,
+                stats,
+                reason,
+                code,
+            } => {
+                // the words of a reason tell more than its code, a peer that went away is
+                // not worth telling
+                match (reason, code) {
+                    (Some(reason), _) => {
+                        log_info!(
+                            "Server {} closed conn={}: {}",
+                            server_addr,
+                            connection_id,
+                            reason
+                        );
+                    }
+                    (None, Some(code)) if code != CloseCode::PeerClosed => {
+                        log_info!(
+                            "Server {} closed conn={}: {}",
+                            server_addr,
+                            connection_id,
+                            code
+                        );
+                    }
+                    (None, _) => {
+                        log_debug!("Close connection from {}: {}", server_addr, connection_id);
+                    }
+                }
 
-                // Remove local connection
-                let mut local_connections_guard = local_connections.lock().await;
-                local_connections_guard.remove(&connection_id);
+                // Remove local connection, its handler logs what the server reported
+                let removed = local_connections.lock().await.remove(&connection_id);
+                if let (Some(local_conn), Some(stats)) = (removed, stats) {
+                    let _ = local_conn.reported.set(stats);
+                }
             }
             _ => {
                 warn!(
@@ -485,100 +2143,475 @@ impl Client {
         }
     }
 
-    /// Handles a new connection from the local service and forwards data to the server
-    async fn handle_local_connection(
-        stream: TcpStream,
-        connections: Arc<Mutex<HashMap<String, ServerConnection>>>,
-        local_connections: Arc<Mutex<HashMap<String, LocalConnection>>>,
-        server_addr: String,
-        connection_id: String,
+    /// Forwards payload received from a server to the matching local connection. Data
+    /// skipping or repeating a `seq` closes the connection instead, it may belong to
+    /// another one
+    async fn forward_to_local_connection(
+        &self,
+        server_addr: &str,
+        connection_id: &str,
+        seq: u64,
+        data: Vec<u8>,
     ) {
-        let (mut stream_read, mut stream_write) = stream.into_split();
-
-        // Channel for receiving data from server
-        let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
-
-        // Store local connection info
-        {
-            let mut local_connections_guard = local_connections.lock().await;
-            local_connections_guard.insert(connection_id.clone(), LocalConnection { sender: tx });
+        let mut local_connections_guard = self.local_connections.lock().await;
+        let Some(local_conn) = local_connections_guard.get_mut(connection_id) else {
+            warn!("Local connection {} not found", connection_id);
+            return;
+        };
+        let expected = local_conn.next_seq;
+        if seq == expected {
+            local_conn.next_seq += 1;
+            local_conn.replay.record_received(data.len());
+            // the connection's handler closes it once the local service fell that far behind
+            let limit = self.config.max_buffered_per_connection.as_usize();
+            if !local_conn.backlog.queue(data.len(), limit) {
+                return;
+            }
+            if let Err(e) = local_conn.sender.send(WriteCommand::Data(data)) {
+                error!("Failed to forward data to local connection: {}", e);
+            }
+            if local_conn.backlog.wants_pause() {
+                let backlog = local_conn.backlog.clone();
+                drop(local_connections_guard);
+                let paused = self
+                    .connections
+                    .lock()
+                    .await
+                    .get(server_addr)
+                    .is_some_and(|conn| {
+                        backlog.pause_with(|| {
+                            let _ = conn.sender.send(Message::new_pause(connection_id));
+                        })
+                    });
+                if paused {
+                    log_debug!(
+                        "Paused conn={} on {}, the local service is behind",
+                        connection_id,
+                        server_addr
+                    );
+                }
+            }
+            return;
         }
 
-        let connection_id_clone = connection_id.clone();
-        let _connections_clone = connections.clone();
-        let _local_connections_clone = local_connections.clone();
+        local_connections_guard.remove(connection_id);
+        drop(local_connections_guard);
+        error!(
+            "Data from {} for conn={} out of sequence: expected seq {}, received {}; closing the connection",
+            server_addr, connection_id, expected, seq
+        );
+        if let Some(conn) = self.connections.lock().await.get(server_addr) {
+            let _ = conn.sender.send(Message::new_close_connection(
+                connection_id,
+                CloseCode::LocalError,
+            ));
+        }
+    }
 
-        // Task to read from local service and send to server
-        let read_task = tokio::spawn(async move {
-            let mut buffer = [0u8; 4096];
+    /// Connects to a local service and writes its PROXY protocol header, if configured.
+    /// Each try at connecting may take `connect_timeout`, 0 leaving it to the OS
+    async fn open_local_service(
+        service_config: &ServiceConfig,
+        source_addr: &str,
+        dest_addr: &str,
+        socket_options: SocketOptions,
+        connect_timeout: HumanDuration,
+    ) -> std::result::Result<BoxedStream, String> {
+        let mut stream =
+            Self::connect_with_retries(service_config, socket_options, connect_timeout).await?;
 
-            loop {
-                match stream_read.read(&mut buffer).await {
-                    Ok(0) => {
-                        // Connection closed
-                        debug!("Local connection {} closed", connection_id);
+        if let Some(version) = service_config.proxy_protocol {
+            let (source, dest) = match (source_addr.parse(), dest_addr.parse()) {
+                (Ok(source), Ok(dest)) => (source, dest),
+                _ => {
+                    return Err(format!(
+                        "cannot build PROXY header from {} -> {}",
+                        source_addr, dest_addr
+                    ))
+                }
+            };
+            stream
+                .write_all(&version.header(source, dest))
+                .await
+                .map_err(|e| format!("failed to send PROXY header: {}", e))?;
+        }
 
-                        // Notify server about connection close
-                        let connections_guard = connections.lock().await;
-                        if let Some(conn) = connections_guard.get(&server_addr) {
-                            let message = Message::CloseConnection {
-                                connection_id: connection_id.clone(),
-                            };
-                            let _ = conn.sender.send(message);
-                        }
-                        break;
-                    }
-                    Ok(n) => {
-                        // Forward data to server
-                        let data = buffer[..n].to_vec();
-                        debug!("Forwarding {} bytes from local service to server", n);
+        Ok(stream)
+    }
 
-                        let connections_guard = connections.lock().await;
-                        if let Some(conn) = connections_guard.get(&server_addr) {
-                            let message = Message::Data {
-                                connection_id: connection_id.clone(),
-                                data,
-                            };
-                            if let Err(e) = conn.sender.send(message) {
-                                error!("Failed to forward data to server: {}", e);
-                                break;
-                            }
-                        } else {
-                            warn!("Server connection not found for data forwarding");
-                            break;
-                        }
-                    }
-                    Err(e) => {
-                        error!("Error reading from local stream: {}", e);
-                        break;
-                    }
+    /// Connects to a service's local service, trying `connect_attempts` times
+    /// `connect_retry_delay` apart so connections survive it restarting
+    async fn connect_with_retries(
+        service_config: &ServiceConfig,
+        socket_options: SocketOptions,
+        connect_timeout: HumanDuration,
+    ) -> std::result::Result<BoxedStream, String> {
+        let attempts = service_config.connect_attempts.max(1);
+        let delay = service_config.connect_retry_delay;
+        let mut attempt = 1;
+        loop {
+            let connect = Self::connect_local_service(service_config, socket_options);
+            let result = match connect_timeout.non_zero() {
+                Some(limit) => timeout(limit, connect).await.unwrap_or_else(|_| {
+                    Err(format!(
+                        "local service {} did not accept within {}",
+                        service_config.local_addr(),
+                        connect_timeout
+                    ))
+                }),
+                None => connect.await,
+            };
+            match result {
+                Ok(stream) => return Ok(stream),
+                Err(reason) if attempt < attempts => {
+                    log_info!(
+                        "Local service unreachable ({}), trying again in {} ({}/{})",
+                        reason,
+                        delay,
+                        attempt,
+                        attempts
+                    );
+                    tokio::time::sleep(delay.0).await;
+                    attempt += 1;
                 }
-            }
-        });
-
-        // Task to receive data from server and write to local service
-        let write_task = tokio::spawn(async move {
-            while let Some(data) = rx.recv().await {
-                debug!("Writing {} bytes to local connection", data.len());
-                if let Err(e) = stream_write.write_all(&data).await {
-                    error!("Error writing to local stream: {}", e);
-                    break;
+                Err(reason) if attempts > 1 => {
+                    return Err(format!("{} after {} attempts", reason, attempts))
                 }
+                Err(reason) => return Err(reason),
             }
-        });
-
-        // Wait for either task to complete
-        tokio::select! {
-            _ = read_task => {},
-            _ = write_task => {},
         }
+    }
 
-        // Clean up local connection
-        {
-            let mut local_connections_guard = local_connections.lock().await;
-            local_connections_guard.remove(&connection_id_clone);
+    /// Connects to a service's local TCP address or Unix domain socket.
+    /// The error is meant to be reported back in `ConnectionResponse`
+    async fn connect_local_service(
+        service_config: &ServiceConfig,
+        socket_options: SocketOptions,
+    ) -> std::result::Result<BoxedStream, String> {
+        if let Some(path) = &service_config.local_path {
+            return Self::connect_unix_socket(path).await;
         }
 
+        let local_addr = service_config.local_addr();
+        let local_ip = net::unbracket(&service_config.local_ip);
+        match TcpStream::connect((local_ip, service_config.local_port)).await {
+            Ok(stream) => {
+                log_info!("Connected to local service at {}", local_addr);
+                socket_options.apply(&stream, "local service");
+                Ok(Box::new(stream))
+            }
+            Err(e) => Err(format!("{}: {}", local_addr, e)),
+        }
+    }
+
+    #[cfg(unix)]
+    async fn connect_unix_socket(path: &str) -> std::result::Result<BoxedStream, String> {
+        match tokio::net::UnixStream::connect(path).await {
+            Ok(stream) => {
+                log_info!("Connected to local service at unix:{}", path);
+                Ok(Box::new(stream))
+            }
+            Err(e) => Err(match e.kind() {
+                std::io::ErrorKind::NotFound => format!("socket {} does not exist", path),
+                std::io::ErrorKind::PermissionDenied => {
+                    format!("permission denied on socket {}", path)
+                }
+                std::io::ErrorKind::ConnectionRefused => {
+                    format!("nothing is listening on socket {}", path)
+                }
+                _ => format!("unix:{}: {}", path, e),
+            }),
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn connect_unix_socket(path: &str) -> std::result::Result<BoxedStream, String> {
+        Err(format!(
+            "socket {} cannot be used, Unix domain sockets are only supported on Unix",
+            path
+        ))
+    }
+
+    /// Forwards a connection to a local service on a stream of its own on the session's QUIC
+    /// connection, opened with the connection's ID so the server can match it
+    #[cfg(feature = "quic")]
+    async fn carry_on_stream(
+        connection: quinn::Connection,
+        local_stream: BoxedStream,
+        connection_id: String,
+        sender: mpsc::UnboundedSender<Message>,
+        idle_timeout: HumanDuration,
+    ) {
+        let channel = match quic::open_stream(&connection, &connection_id).await {
+            Ok(channel) => channel,
+            Err(e) => {
+                error!(
+                    "Failed to open a stream for connection {}: {}",
+                    connection_id, e
+                );
+                let _ = sender.send(Message::ConnectionResponse {
+                    connection_id,
+                    success: false,
+                    error: Some(format!("Failed to open a stream: {}", e)),
+                });
+                return;
+            }
+        };
+
+        Self::splice_local_connection(local_stream, channel, connection_id, idle_timeout).await;
+    }
+
+    /// Forwards a connection to a local service on `channel`, a stream carrying only it
+    async fn splice_local_connection(
+        local_stream: BoxedStream,
+        channel: BoxedStream,
+        connection_id: String,
+        idle_timeout: HumanDuration,
+    ) {
+        let transfer = TransferCounter::default();
+        let end = splice(
+            local_stream,
+            channel,
+            idle_timeout.non_zero(),
+            |n| transfer.record_in(n),
+            |n| transfer.record_out(n),
+        )
+        .await;
+        if end == SpliceEnd::Idle {
+            log_info!(
+                "Local connection {} idle for {}, closing",
+                connection_id,
+                idle_timeout
+            );
+        }
+        log_info!(
+            "Connection {} closed: local service {}",
+            connection_id,
+            transfer.stats()
+        );
+        debug!("Local connection {} handler finished", connection_id);
+    }
+
+    /// Forwards data between a local service and the server, `rx` carries the server's side
+    async fn handle_local_connection<S>(
+        stream: S,
+        mut rx: mpsc::UnboundedReceiver<WriteCommand>,
+        connections: Arc<Mutex<HashMap<String, ServerConnection>>>,
+        local_connections: Arc<Mutex<HashMap<String, LocalConnection>>>,
+        server_addr: String,
+        connection_id: String,
+        idle_timeout: HumanDuration,
+    ) where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut stream_read, mut stream_write) = tokio::io::split(stream);
+        let Some((window, reported, backlog, replay)) = local_connections
+            .lock()
+            .await
+            .get(&connection_id)
+            .map(|local_conn| {
+                (
+                    local_conn.window.clone(),
+                    local_conn.reported.clone(),
+                    local_conn.backlog.clone(),
+                    local_conn.replay.clone(),
+                )
+            })
+        else {
+            // closed before it was handled
+            return;
+        };
+        let write_backlog = backlog.clone();
+        let read_replay = replay.clone();
+        let write_replay = replay.clone();
+        let transfer = Arc::new(TransferCounter::default());
+        let read_transfer = transfer.clone();
+        let write_transfer = transfer.clone();
+
+        let connection_id_clone = connection_id.clone();
+        let connections_clone = connections.clone();
+        let server_addr_clone = server_addr.clone();
+        let write_connections = connections.clone();
+        let write_server_addr = server_addr.clone();
+        let write_connection_id = connection_id.clone();
+        let activity = Activity::new();
+        let read_activity = activity.clone();
+        let write_activity = activity.clone();
+
+        // Task to read from local service and send to server
+        let mut read_task = tokio::spawn(
+            async move {
+                let mut buffer = [0u8; 4096];
+
+                loop {
+                    // pause reading until the server made room for more
+                    window.ready().await;
+                    match stream_read.read(&mut buffer).await {
+                        Ok(0) => {
+                            // The local service finished sending, it may still read
+                            debug!("Local connection {} half-closed", connection_id);
+
+                            return if read_replay.finish() {
+                                HalfEnd::Shutdown
+                            } else {
+                                HalfEnd::Failed
+                            };
+                        }
+                        Ok(n) => {
+                            // Forward data to server
+                            read_transfer.record_in(n);
+                            window.spend(n);
+                            read_activity.touch();
+                            debug!("Forwarding {} bytes from local service to server", n);
+
+                            // while the session is lost, the data waits for the next one
+                            if !read_replay.send(&buffer[..n]) {
+                                debug!(
+                                    "Local connection {} not resumed by the server",
+                                    connection_id
+                                );
+                                break;
+                            }
+                        }
+                        Err(e) => {
+                            error!("Error reading from local stream: {}", e);
+                            break;
+                        }
+                    }
+                }
+                HalfEnd::Failed
+            }
+            .in_current_span(),
+        );
+
+        // Task to receive data from server and write to local service
+        let mut write_task = tokio::spawn(
+            async move {
+                let mut acknowledger = Acknowledger::default();
+                while let Some(command) = rx.recv().await {
+                    match command {
+                        WriteCommand::Data(data) => {
+                            debug!("Writing {} bytes to local connection", data.len());
+                            if let Err(e) = stream_write.write_all(&data).await {
+                                error!("Error writing to local stream: {}", e);
+                                return HalfEnd::Failed;
+                            }
+                            write_backlog.written(data.len());
+                            write_transfer.record_out(data.len());
+                            write_activity.touch();
+                            let acknowledged = acknowledger.written(data.len());
+                            if let Some(bytes) = acknowledged {
+                                write_replay.record_acknowledged(bytes);
+                            }
+                            let resume = write_backlog.wants_resume();
+                            if acknowledged.is_some() || resume {
+                                let connections_guard = write_connections.lock().await;
+                                if let Some(conn) = connections_guard.get(&write_server_addr) {
+                                    if let Some(bytes) = acknowledged {
+                                        let message =
+                                            Message::new_window_update(&write_connection_id, bytes);
+                                        let _ = conn.sender.send(message);
+                                    }
+                                    if resume {
+                                        write_backlog.resume_with(|| {
+                                            let message = Message::new_resume(&write_connection_id);
+                                            let _ = conn.sender.send(message);
+                                        });
+                                    }
+                                }
+                            }
+                        }
+                        WriteCommand::ShutdownWrite => {
+                            return match stream_write.shutdown().await {
+                                Ok(()) => HalfEnd::Shutdown,
+                                Err(_) => HalfEnd::Failed,
+                            };
+                        }
+                    }
+                }
+                HalfEnd::Closed
+            }
+            .in_current_span(),
+        );
+
+        // The connection lives until both directions are shut down, anything else ends it at once
+        let (mut reading, mut writing) = (true, true);
+        let mut end = HalfEnd::Shutdown;
+        let mut reason = None;
+        let mut code = CloseCode::LocalError;
+        while (reading || writing) && end == HalfEnd::Shutdown {
+            tokio::select! {
+                finished = &mut read_task, if reading => {
+                    reading = false;
+                    end = finished.unwrap_or(HalfEnd::Failed);
+                }
+                finished = &mut write_task, if writing => {
+                    writing = false;
+                    end = finished.unwrap_or(HalfEnd::Failed);
+                }
+                _ = activity.idle(idle_timeout.non_zero()) => {
+                    log_info!(
+                        "Local connection {} idle for {}, closing",
+                        connection_id_clone,
+                        idle_timeout
+                    );
+                    end = HalfEnd::Failed;
+                    code = CloseCode::IdleTimeout;
+                }
+                _ = backlog.overflowed() => {
+                    warn!(
+                        "Local connection {} closed: slow consumer, {} of the server's data unread by the local service",
+                        connection_id_clone,
+                        format_bytes(backlog.queued() as u64)
+                    );
+                    end = HalfEnd::Failed;
+                    reason = Some("slow consumer".to_string());
+                    code = CloseCode::PolicyLimit;
+                }
+                _ = replay.abandoned() => {
+                    end = HalfEnd::Failed;
+                }
+            }
+        }
+        // dropping both halves closes the socket
+        read_task.abort();
+        write_task.abort();
+        let stats = transfer.stats();
+        if end == HalfEnd::Failed {
+            let connections_guard = connections_clone.lock().await;
+            if let Some(conn) = connections_guard.get(&server_addr_clone) {
+                let _ = conn.sender.send(Message::new_close_connection_with_stats(
+                    &connection_id_clone,
+                    stats,
+                    code,
+                    reason,
+                ));
+            }
+        }
+
+        // Clean up local connection
+        {
+            let mut local_connections_guard = local_connections.lock().await;
+            local_connections_guard.remove(&connection_id_clone);
+        }
+
+        match reported.get() {
+            Some(server) => {
+                log_info!(
+                    "Connection {} closed: local service {}, server {}",
+                    connection_id_clone,
+                    stats,
+                    server
+                );
+            }
+            None => {
+                log_info!(
+                    "Connection {} closed: local service {}",
+                    connection_id_clone,
+                    stats
+                );
+            }
+        }
         debug!("Local connection {} handler finished", connection_id_clone);
     }
 }
@@ -590,6 +2623,1657 @@ impl Clone for Client {
             client_id: self.client_id.clone(),
             connections: self.connections.clone(),
             local_connections: self.local_connections.clone(),
+            tls_connector: self.tls_connector.clone(),
+            assigned_ports: self.assigned_ports.clone(),
+            deadlines: self.deadlines.clone(),
+            last_addrs: self.last_addrs.clone(),
+            state: self.state.clone(),
+            services: self.services.clone(),
+            reloader: self.reloader.clone(),
+            once: self.once,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HumanBytes;
+    use crate::utils::protocol::{ProxyConfigOpCode, MAX_DATA_PAYLOAD};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_forward_to_ipv6_loopback_service() {
+        let listener = TcpListener::bind("[::1]:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let service = ServiceConfig::parse_cli(&format!("[::1]:{}:8080", port)).unwrap();
+        let options = SocketOptions {
+            nodelay: true,
+            keepalive: None,
+            user_timeout: None,
+        };
+
+        let (stream, accepted) = tokio::join!(
+            Client::open_local_service(
+                &service,
+                "[2001:db8::1]:5000",
+                "[::1]:8080",
+                options,
+                HumanDuration::default()
+            ),
+            listener.accept()
+        );
+        let mut stream = stream.unwrap();
+        let (mut local, peer) = accepted.unwrap();
+        assert!(peer.is_ipv6());
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut received = [0u8; 4];
+        local.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_frames_are_skipped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        let client = Client::new(ClientConfig::default()).unwrap();
+
+        // a server that authenticates the client, then sends garbage before a fatal error
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let challenge = Message::AuthChallenge {
+                version: PROTOCOL_VERSION,
+                nonce: vec![0; 32],
+            };
+            stream
+                .write_all(&Frame::new(challenge).serialize().unwrap())
+                .await
+                .unwrap();
+            let mut reader = FrameReader::new();
+            reader.read_frame(&mut stream).await.unwrap().unwrap();
+            let response = Message::AuthResponse {
+                success: true,
+                session_key: Some(vec![0; 32]),
+                name: None,
+                error: None,
+                compression: Compression::None,
+                client_id: None,
+                data_channels: false,
+            };
+            let mut data = Frame::new(response).serialize().unwrap();
+            data.extend_from_slice(&[0, 0, 0, 3, 0xff, 0xff, 0xff]);
+            let error = Message::Error {
+                message: "Protocol version 1 is not supported, upgrade required".to_string(),
+            };
+            data.extend(Frame::new(error).serialize_framed(Framing::Marked).unwrap());
+            stream.write_all(&data).await.unwrap();
+            while let Ok(Some(_)) = reader.read_frame(&mut stream).await {}
+        });
+
+        // the error past the garbage is still read, and ends the session for good
+        let error = timeout(
+            Duration::from_secs(5),
+            client.try_connect_to_server(&server_addr, "token", &[], &CancellationToken::new()),
+        )
+        .await
+        .expect("frame past the garbage was not read")
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ServerError>(),
+            Some(ServerError::ProtocolVersion(_))
+        ));
+        assert!(client.connections.lock().await.is_empty());
+        server.await.unwrap();
+    }
+
+    /// Accepts a client on `listener` and authenticates it like a server would
+    async fn accept_fake_session(listener: &TcpListener) -> (TcpStream, FrameReader) {
+        accept_fake_session_with(listener, false).await
+    }
+
+    /// Like `accept_fake_session`, letting the session open data channels if `data_channels`
+    async fn accept_fake_session_with(
+        listener: &TcpListener,
+        data_channels: bool,
+    ) -> (TcpStream, FrameReader) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let challenge = Message::AuthChallenge {
+            version: PROTOCOL_VERSION,
+            nonce: vec![0; 32],
+        };
+        stream
+            .write_all(&Frame::new(challenge).serialize().unwrap())
+            .await
+            .unwrap();
+        let mut reader = FrameReader::new();
+        reader.read_frame(&mut stream).await.unwrap().unwrap();
+        let response = Message::AuthResponse {
+            success: true,
+            session_key: Some(vec![0; 32]),
+            name: None,
+            error: None,
+            compression: Compression::None,
+            client_id: None,
+            data_channels,
+        };
+        stream
+            .write_all(&Frame::new(response).serialize().unwrap())
+            .await
+            .unwrap();
+        (stream, reader)
+    }
+
+    #[tokio::test]
+    async fn test_proxy_config_responses_match_by_request_id() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        let client = Client::new(ClientConfig::default()).unwrap();
+        let services = [("web", 3000), ("db", 5432)].map(|(name, port)| ServiceConfig {
+            name: name.to_string(),
+            ..ServiceConfig::parse_cli(&format!("127.0.0.1:{}:0", port)).unwrap()
+        });
+
+        // a server that answers the registrations out of order, one of them twice
+        let server = tokio::spawn(async move {
+            let (mut stream, mut reader) = accept_fake_session(&listener).await;
+            let mut requests = HashMap::new();
+            while requests.len() < 2 {
+                let frame = reader.read_frame(&mut stream).await.unwrap().unwrap();
+                if let Message::ProxyConfig {
+                    request_id, name, ..
+                } = frame.message
+                {
+                    requests.insert(name, request_id);
+                }
+            }
+            let rejected = Message::ProxyConfigResponse {
+                request_id: requests["db"],
+                success: false,
+                proxy_id: None,
+                error: Some("Port 5432 already in use".to_string()),
+                assigned_port: None,
+            };
+            let accepted = Message::ProxyConfigResponse {
+                request_id: requests["web"],
+                success: true,
+                proxy_id: Some(Uuid::new_v4().to_string()),
+                error: None,
+                assigned_port: Some(9000),
+            };
+            for message in [rejected.clone(), accepted, rejected] {
+                stream
+                    .write_all(&Frame::new(message).serialize().unwrap())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        timeout(
+            Duration::from_secs(5),
+            client.try_connect_to_server(
+                &server_addr,
+                "token",
+                &services,
+                &CancellationToken::new(),
+            ),
+        )
+        .await
+        .expect("connection did not end")
+        .unwrap();
+        server.await.unwrap();
+        assert_eq!(client.assigned_port(&server_addr, "web").await, Some(9000));
+        assert_eq!(client.assigned_port(&server_addr, "db").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_connections_wait_for_a_restarting_local_service() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_port = local.local_addr().unwrap().port();
+        drop(local);
+        let client = Client::new(ClientConfig::default()).unwrap();
+        let services = [ServiceConfig {
+            name: "web".to_string(),
+            health_check: false,
+            connect_attempts: 5,
+            ..ServiceConfig::parse_cli(&format!("127.0.0.1:{}:0", local_port)).unwrap()
+        }];
+
+        // a server that asks for a connection, sending its first data right away
+        let server = tokio::spawn(async move {
+            let (mut stream, mut reader) = accept_fake_session(&listener).await;
+            let request_id = loop {
+                let frame = reader.read_frame(&mut stream).await.unwrap().unwrap();
+                if let Message::ProxyConfig { request_id, .. } = frame.message {
+                    break request_id;
+                }
+            };
+            let proxy_id = Uuid::new_v4().to_string();
+            let connection_id = Uuid::new_v4().to_string();
+            let messages = [
+                Message::ProxyConfigResponse {
+                    request_id,
+                    success: true,
+                    proxy_id: Some(proxy_id.clone()),
+                    error: None,
+                    assigned_port: Some(9000),
+                },
+                Message::NewConnection {
+                    proxy_id,
+                    connection_id: connection_id.clone(),
+                    source_addr: "203.0.113.7:5000".to_string(),
+                    dest_addr: "127.0.0.1:9000".to_string(),
+                },
+                Message::Data {
+                    connection_id: connection_id.clone(),
+                    seq: 0,
+                    data: b"ping".to_vec(),
+                },
+            ];
+            for message in messages {
+                stream
+                    .write_all(&Frame::new(message).serialize().unwrap())
+                    .await
+                    .unwrap();
+            }
+
+            // the local service comes back a second later, while heartbeats go on
+            let local_listener = async {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let local = TcpListener::bind(("127.0.0.1", local_port)).await.unwrap();
+                let (mut local, _) = local.accept().await.unwrap();
+                let mut received = [0u8; 4];
+                local.read_exact(&mut received).await.unwrap();
+                received
+            };
+            let response = async {
+                loop {
+                    let frame = reader.read_frame(&mut stream).await.unwrap().unwrap();
+                    match frame.message {
+                        Message::ConnectionResponse {
+                            connection_id: answered,
+                            success,
+                            error,
+                        } => {
+                            assert_eq!(answered, connection_id);
+                            break (success, error);
+                        }
+                        Message::Heartbeat { .. } => {}
+                        other => panic!("expected ConnectionResponse, got {:?}", other),
+                    }
+                }
+            };
+            tokio::join!(local_listener, response)
+        });
+
+        let cancel = CancellationToken::new();
+        let (received, response) = tokio::select! {
+            result = server => result.unwrap(),
+            _ = client.try_connect_to_server(&server_addr, "token", &services, &cancel) => {
+                panic!("connection ended")
+            }
+            _ = tokio::time::sleep(Duration::from_secs(5)) => panic!("no response"),
+        };
+        assert_eq!(response, (true, None));
+        assert_eq!(&received, b"ping");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_missing_socket_is_reported_in_connection_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing.sock");
+        let client = Client::new(ClientConfig::default()).unwrap();
+        let services = [ServiceConfig {
+            name: "app".to_string(),
+            health_check: false,
+            connect_attempts: 1,
+            ..ServiceConfig::parse_cli(&format!("unix:{}:0", path.display())).unwrap()
+        }];
+
+        let server = tokio::spawn(async move {
+            let (mut stream, mut reader) = accept_fake_session(&listener).await;
+            let request_id = loop {
+                let frame = reader.read_frame(&mut stream).await.unwrap().unwrap();
+                if let Message::ProxyConfig { request_id, .. } = frame.message {
+                    break request_id;
+                }
+            };
+            let proxy_id = Uuid::new_v4().to_string();
+            let connection_id = Uuid::new_v4().to_string();
+            let messages = [
+                Message::ProxyConfigResponse {
+                    request_id,
+                    success: true,
+                    proxy_id: Some(proxy_id.clone()),
+                    error: None,
+                    assigned_port: Some(9000),
+                },
+                Message::NewConnection {
+                    proxy_id,
+                    connection_id: connection_id.clone(),
+                    source_addr: "203.0.113.7:5000".to_string(),
+                    dest_addr: "127.0.0.1:9000".to_string(),
+                },
+            ];
+            for message in messages {
+                stream
+                    .write_all(&Frame::new(message).serialize().unwrap())
+                    .await
+                    .unwrap();
+            }
+            loop {
+                let frame = reader.read_frame(&mut stream).await.unwrap().unwrap();
+                match frame.message {
+                    Message::ConnectionResponse {
+                        connection_id: answered,
+                        success,
+                        error,
+                    } => {
+                        assert_eq!(answered, connection_id);
+                        break (success, error);
+                    }
+                    Message::Heartbeat { .. } => {}
+                    other => panic!("expected ConnectionResponse, got {:?}", other),
+                }
+            }
+        });
+
+        let cancel = CancellationToken::new();
+        let response = tokio::select! {
+            result = server => result.unwrap(),
+            _ = client.try_connect_to_server(&server_addr, "token", &services, &cancel) => {
+                panic!("connection ended")
+            }
+            _ = tokio::time::sleep(Duration::from_secs(5)) => panic!("no response"),
+        };
+        assert_eq!(
+            response,
+            (
+                false,
+                Some(format!(
+                    "Failed to connect to local service: socket {} does not exist",
+                    path.display()
+                ))
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_local_service_learns_the_peer_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_port = local.local_addr().unwrap().port();
+        let client = Client::new(ClientConfig::default()).unwrap();
+        let services = [ServiceConfig {
+            name: "web".to_string(),
+            health_check: false,
+            proxy_protocol: Some(crate::utils::proxy_protocol::ProxyProtocol::V1),
+            ..ServiceConfig::parse_cli(&format!("127.0.0.1:{}:0", local_port)).unwrap()
+        }];
+
+        // a server that passes on a connection from an external peer, keeping the session open
+        let _server = tokio::spawn(async move {
+            let (mut stream, mut reader) = accept_fake_session(&listener).await;
+            let request_id = loop {
+                let frame = reader.read_frame(&mut stream).await.unwrap().unwrap();
+                if let Message::ProxyConfig { request_id, .. } = frame.message {
+                    break request_id;
+                }
+            };
+            let proxy_id = Uuid::new_v4().to_string();
+            let messages = [
+                Message::ProxyConfigResponse {
+                    request_id,
+                    success: true,
+                    proxy_id: Some(proxy_id.clone()),
+                    error: None,
+                    assigned_port: Some(9000),
+                },
+                Message::NewConnection {
+                    proxy_id,
+                    connection_id: Uuid::new_v4().to_string(),
+                    source_addr: "203.0.113.7:51234".to_string(),
+                    dest_addr: "198.51.100.1:9000".to_string(),
+                },
+            ];
+            for message in messages {
+                stream
+                    .write_all(&Frame::new(message).serialize().unwrap())
+                    .await
+                    .unwrap();
+            }
+            stream
+        });
+
+        let cancel = CancellationToken::new();
+        let header = tokio::select! {
+            accepted = local.accept() => {
+                let (mut local, _) = accepted.unwrap();
+                let mut header = vec![0u8; 48];
+                let n = local.read(&mut header).await.unwrap();
+                String::from_utf8_lossy(&header[..n]).to_string()
+            }
+            _ = client.try_connect_to_server(&server_addr, "token", &services, &cancel) => {
+                panic!("connection ended")
+            }
+            _ = tokio::time::sleep(Duration::from_secs(5)) => panic!("no connection"),
+        };
+        assert_eq!(header, "PROXY TCP4 203.0.113.7 198.51.100.1 51234 9000\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_closing_a_connection_reports_what_the_local_service_moved() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_port = local.local_addr().unwrap().port();
+        let client = Client::new(ClientConfig {
+            idle_timeout: "300ms".parse().unwrap(),
+            ..ClientConfig::default()
+        })
+        .unwrap();
+        let services = [ServiceConfig {
+            name: "web".to_string(),
+            health_check: false,
+            ..ServiceConfig::parse_cli(&format!("127.0.0.1:{}:0", local_port)).unwrap()
+        }];
+
+        // a server passing on 4 bytes, then waiting for the connection to close
+        let server = tokio::spawn(async move {
+            let (mut stream, mut reader) = accept_fake_session(&listener).await;
+            let request_id = loop {
+                let frame = reader.read_frame(&mut stream).await.unwrap().unwrap();
+                if let Message::ProxyConfig { request_id, .. } = frame.message {
+                    break request_id;
+                }
+            };
+            let proxy_id = Uuid::new_v4().to_string();
+            let connection_id = Uuid::new_v4().to_string();
+            let messages = [
+                Message::ProxyConfigResponse {
+                    request_id,
+                    success: true,
+                    proxy_id: Some(proxy_id.clone()),
+                    error: None,
+                    assigned_port: Some(9000),
+                },
+                Message::NewConnection {
+                    proxy_id,
+                    connection_id: connection_id.clone(),
+                    source_addr: "203.0.113.7:5000".to_string(),
+                    dest_addr: "127.0.0.1:9000".to_string(),
+                },
+                Message::new_data(&connection_id, 0, b"ping".to_vec()),
+            ];
+            for message in messages {
+                stream
+                    .write_all(&Frame::new(message).serialize().unwrap())
+                    .await
+                    .unwrap();
+            }
+            loop {
+                let frame = reader.read_frame(&mut stream).await.unwrap().unwrap();
+                if let Message::CloseConnection {
+                    connection_id: closed,
+                    stats,
+                    ..
+                } = frame.message
+                {
+                    assert_eq!(closed, connection_id);
+                    break stats;
+                }
+            }
+        });
+
+        // the local service answers with 5 bytes, then falls silent until the client gives up
+        let local_service = async {
+            let (mut local, _) = local.accept().await.unwrap();
+            let mut received = [0u8; 4];
+            local.read_exact(&mut received).await.unwrap();
+            local.write_all(b"pong!").await.unwrap();
+            local
+        };
+        let cancel = CancellationToken::new();
+        let (stats, _local) = tokio::select! {
+            result = async { tokio::join!(server, local_service) } => {
+                (result.0.unwrap(), result.1)
+            }
+            _ = client.try_connect_to_server(&server_addr, "token", &services, &cancel) => {
+                panic!("connection ended")
+            }
+            _ = tokio::time::sleep(Duration::from_secs(5)) => panic!("no close"),
+        };
+        let stats = stats.unwrap();
+        assert_eq!((stats.bytes_in, stats.bytes_out), (5, 4));
+        assert!(stats.duration_ms >= 300);
+    }
+
+    /// Registers a session with `server_addr` whose messages go to the returned receiver
+    async fn fake_server_connection(
+        client: &Client,
+        server_addr: &str,
+    ) -> mpsc::UnboundedReceiver<Message> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        client.connections.lock().await.insert(
+            server_addr.to_string(),
+            ServerConnection {
+                server_addr: server_addr.to_string(),
+                sender: tx,
+                crypto: None,
+                connected: true,
+                compression: Compression::None,
+                compression_threshold: 0,
+                registrations: Registrations::default(),
+                proxies: HashMap::new(),
+                heartbeats: Heartbeats::default(),
+                data_channel: DataChannel::Messages,
+                visits: Visits::default(),
+                health: HashMap::new(),
+                held_back: Vec::new(),
+            },
+        );
+        rx
+    }
+
+    #[tokio::test]
+    async fn test_data_out_of_sequence_closes_the_connection() {
+        let client = Client::new(ClientConfig::default()).unwrap();
+        let server_addr = "127.0.0.1:7000";
+        let mut rx = fake_server_connection(&client, server_addr).await;
+
+        for (connection_id, wrong_seq) in [("gap", 2), ("repeat", 0)] {
+            let (local_tx, mut local_rx) = mpsc::unbounded_channel();
+            let route = client.connections.lock().await[server_addr].route();
+            client.local_connections.lock().await.insert(
+                connection_id.to_string(),
+                LocalConnection::new(server_addr, connection_id, local_tx, Some(route), 0),
+            );
+
+            // the first message is in sequence and reaches the local service
+            let first = Message::new_data(connection_id, 0, b"first".to_vec());
+            client.handle_server_message(first, server_addr).await;
+            assert!(matches!(
+                local_rx.recv().await,
+                Some(WriteCommand::Data(data)) if data == b"first"
+            ));
+
+            // a skipped or repeated seq closes the connection on both ends
+            let stray = Message::new_data(connection_id, wrong_seq, b"stray".to_vec());
+            client.handle_server_message(stray, server_addr).await;
+            assert!(local_rx.recv().await.is_none());
+            assert!(matches!(
+                rx.recv().await,
+                Some(Message::CloseConnection { connection_id: closed, .. }) if closed == connection_id
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_local_services_that_stop_reading_are_closed_as_slow_consumers() {
+        let limit = 256 * 1024;
+        let client = Client::new(ClientConfig {
+            max_buffered_per_connection: HumanBytes(limit as u64),
+            ..ClientConfig::default()
+        })
+        .unwrap();
+        let server_addr = "127.0.0.1:7000";
+        let mut rx = fake_server_connection(&client, server_addr).await;
+
+        // a local service that never reads
+        let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        let stream = socket.connect(local.local_addr().unwrap()).await.unwrap();
+        let _local_service = local.accept().await.unwrap();
+        let (local_tx, local_rx) = mpsc::unbounded_channel();
+        let backlog = Arc::new(WriteBacklog::default());
+        let route = client.connections.lock().await[server_addr].route();
+        client.local_connections.lock().await.insert(
+            "slow".to_string(),
+            LocalConnection {
+                backlog: backlog.clone(),
+                ..LocalConnection::new(server_addr, "slow", local_tx, Some(route), 0)
+            },
+        );
+        let handler = tokio::spawn(Client::handle_local_connection(
+            stream,
+            local_rx,
+            client.connections.clone(),
+            client.local_connections.clone(),
+            server_addr.to_string(),
+            "slow".to_string(),
+            HumanDuration::default(),
+        ));
+
+        // the server keeps sending, far more than the local socket takes
+        let mut most_queued = 0;
+        for seq in 0..200 {
+            let data = Message::new_data("slow", seq, vec![0; MAX_DATA_PAYLOAD]);
+            client.handle_server_message(data, server_addr).await;
+            most_queued = most_queued.max(backlog.queued());
+            tokio::task::yield_now().await;
+        }
+        assert!(most_queued <= limit + MAX_DATA_PAYLOAD, "{}", most_queued);
+
+        // the server is told why
+        timeout(Duration::from_secs(2), handler)
+            .await
+            .unwrap()
+            .unwrap();
+        let reason = loop {
+            if let Message::CloseConnection { reason, .. } = rx.try_recv().unwrap() {
+                break reason;
+            }
+        };
+        assert_eq!(reason.as_deref(), Some("slow consumer"));
+        assert!(client.local_connections.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_slow_local_services_pause_the_server() {
+        use crate::utils::proxy::{PAUSE_HIGH_WATERMARK, PAUSE_LOW_WATERMARK};
+
+        let client = Client::new(ClientConfig::default()).unwrap();
+        let server_addr = "127.0.0.1:7000";
+        let mut rx = fake_server_connection(&client, server_addr).await;
+
+        // a local service reading slowly for its first MiB, then as fast as it can
+        let (stream, mut local_service) = tokio::io::duplex(4096);
+        let sink = tokio::spawn(async move {
+            let mut buffer = [0u8; 4096];
+            let mut received = 0;
+            loop {
+                match local_service.read(&mut buffer).await.unwrap() {
+                    0 => return received,
+                    n => received += n,
+                }
+                if received < 1024 * 1024 {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            }
+        });
+        let (local_tx, local_rx) = mpsc::unbounded_channel();
+        let backlog = Arc::new(WriteBacklog::default());
+        let route = client.connections.lock().await[server_addr].route();
+        client.local_connections.lock().await.insert(
+            "slow".to_string(),
+            LocalConnection {
+                backlog: backlog.clone(),
+                ..LocalConnection::new(server_addr, "slow", local_tx, Some(route), 0)
+            },
+        );
+        tokio::spawn(Client::handle_local_connection(
+            stream,
+            local_rx,
+            client.connections.clone(),
+            client.local_connections.clone(),
+            server_addr.to_string(),
+            "slow".to_string(),
+            HumanDuration::default(),
+        ));
+
+        // a server sending 4 MiB, holding back while paused
+        let chunks = 64;
+        let (mut paused, mut pauses, mut most_queued) = (false, 0, 0);
+        let mut seq = 0;
+        timeout(Duration::from_secs(10), async {
+            while seq < chunks {
+                let message = match paused {
+                    true => rx.recv().await,
+                    false => rx.try_recv().ok(),
+                };
+                match message {
+                    Some(Message::Pause { .. }) => (paused, pauses) = (true, pauses + 1),
+                    Some(Message::Resume { .. }) => {
+                        assert!(backlog.queued() <= PAUSE_LOW_WATERMARK);
+                        paused = false;
+                    }
+                    Some(_) => {}
+                    None => {
+                        let data = Message::new_data("slow", seq, vec![0; MAX_DATA_PAYLOAD]);
+                        client.handle_server_message(data, server_addr).await;
+                        most_queued = most_queued.max(backlog.queued());
+                        seq += 1;
+                        tokio::task::yield_now().await;
+                    }
+                }
+            }
+        })
+        .await
+        .expect("paused connection was never resumed");
+        assert!(pauses > 0);
+        assert!(
+            most_queued <= PAUSE_HIGH_WATERMARK + MAX_DATA_PAYLOAD,
+            "{}",
+            most_queued
+        );
+
+        // everything reaches the local service once it speeds up
+        let shutdown = Message::new_shutdown_write("slow");
+        client.handle_server_message(shutdown, server_addr).await;
+        let received = timeout(Duration::from_secs(5), sink)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, chunks as usize * MAX_DATA_PAYLOAD);
+    }
+
+    #[tokio::test]
+    async fn test_data_channels_carry_connections_or_fall_back_to_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_port = local.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = local.accept().await {
+                tokio::spawn(async move {
+                    let (mut read, mut write) = stream.split();
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                });
+            }
+        });
+        let client = Client::new(ClientConfig {
+            data_channels: true,
+            ..ClientConfig::default()
+        })
+        .unwrap();
+        let client_id = client.client_id.clone();
+        let services = [ServiceConfig {
+            name: "web".to_string(),
+            health_check: false,
+            ..ServiceConfig::parse_cli(&format!("127.0.0.1:{}:0", local_port)).unwrap()
+        }];
+
+        let server = tokio::spawn(async move {
+            let (mut stream, mut reader) = accept_fake_session_with(&listener, true).await;
+            let request_id = loop {
+                let frame = reader.read_frame(&mut stream).await.unwrap().unwrap();
+                if let Message::ProxyConfig { request_id, .. } = frame.message {
+                    break request_id;
+                }
+            };
+            let proxy_id = Uuid::new_v4().to_string();
+            let registered = Message::ProxyConfigResponse {
+                request_id,
+                success: true,
+                proxy_id: Some(proxy_id.clone()),
+                error: None,
+                assigned_port: Some(9000),
+            };
+            stream
+                .write_all(&Frame::new(registered).serialize().unwrap())
+                .await
+                .unwrap();
+            let new_connection = |connection_id: &str| Message::NewConnection {
+                proxy_id: proxy_id.clone(),
+                connection_id: connection_id.to_string(),
+                source_addr: "203.0.113.7:5000".to_string(),
+                dest_addr: "127.0.0.1:9000".to_string(),
+            };
+
+            // the first connection comes on a data channel proven with the session key
+            stream
+                .write_all(&Frame::new(new_connection("carried")).serialize().unwrap())
+                .await
+                .unwrap();
+            let (mut channel, _) = listener.accept().await.unwrap();
+            let challenge = Message::AuthChallenge {
+                version: PROTOCOL_VERSION,
+                nonce: vec![7; 32],
+            };
+            channel
+                .write_all(&Frame::new(challenge).serialize().unwrap())
+                .await
+                .unwrap();
+            let mut channel_reader = FrameReader::new();
+            let hello = channel_reader.read_frame(&mut channel).await.unwrap();
+            let Some(Frame {
+                message:
+                    Message::DataChannelHello {
+                        client_id: hello_client_id,
+                        connection_id,
+                        proof,
+                    },
+                ..
+            }) = hello
+            else {
+                panic!("expected DataChannelHello, got {:?}", hello);
+            };
+            assert_eq!(hello_client_id, client_id);
+            assert_eq!(connection_id, "carried");
+            let crypto = CryptoContext::new(&[0; 32]).unwrap();
+            assert!(crypto.verify_data_channel_proof(&[7; 32], "carried", &proof));
+            channel.write_all(b"ping").await.unwrap();
+            let mut echoed = [0u8; 4];
+            channel.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(&echoed, b"ping");
+
+            // the second one's data channel is dropped at once, it goes in messages instead
+            stream
+                .write_all(&Frame::new(new_connection("fallback")).serialize().unwrap())
+                .await
+                .unwrap();
+            drop(listener.accept().await.unwrap());
+            loop {
+                let frame = reader.read_frame(&mut stream).await.unwrap().unwrap();
+                if let Message::ConnectionResponse {
+                    connection_id,
+                    success,
+                    ..
+                } = frame.message
+                {
+                    assert_eq!((connection_id.as_str(), success), ("fallback", true));
+                    break;
+                }
+            }
+            let data = Message::new_data("fallback", 0, b"pong".to_vec());
+            stream
+                .write_all(&Frame::new(data).serialize().unwrap())
+                .await
+                .unwrap();
+            loop {
+                let frame = reader.read_frame(&mut stream).await.unwrap().unwrap();
+                if let Message::Data {
+                    connection_id,
+                    data,
+                    ..
+                } = frame.message
+                {
+                    assert_eq!(
+                        (connection_id.as_str(), data.as_slice()),
+                        ("fallback", &b"pong"[..])
+                    );
+                    break;
+                }
+            }
+        });
+
+        let cancel = CancellationToken::new();
+        tokio::select! {
+            result = server => result.unwrap(),
+            _ = client.try_connect_to_server(&server_addr, "token", &services, &cancel) => {
+                panic!("connection ended")
+            }
+            _ = tokio::time::sleep(Duration::from_secs(5)) => panic!("connections not carried"),
+        }
+    }
+
+    /// A listener with a full backlog, connecting to it hangs like connecting to an
+    /// address that drops packets
+    async fn blackhole() -> (TcpListener, TcpStream) {
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(0).unwrap();
+        let queued = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        (listener, queued)
+    }
+
+    #[tokio::test]
+    async fn test_dials_give_up_after_their_timeout() {
+        let (listener, _queued) = blackhole().await;
+        let addr = listener.local_addr().unwrap();
+        let client = Client::new(ClientConfig {
+            connect_timeout: "300ms".parse().unwrap(),
+            ..ClientConfig::default()
+        })
+        .unwrap();
+        let err = timeout(
+            Duration::from_secs(2),
+            client.try_connect_to_server(
+                &addr.to_string(),
+                "token",
+                &[],
+                &CancellationToken::new(),
+            ),
+        )
+        .await
+        .expect("server dial not bounded")
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Timed out connecting to server {} within 300ms", addr)
+        );
+
+        let service = ServiceConfig {
+            connect_attempts: 1,
+            ..ServiceConfig::parse_cli(&format!("127.0.0.1:{}:0", addr.port())).unwrap()
+        };
+        let options = SocketOptions {
+            nodelay: true,
+            keepalive: None,
+            user_timeout: None,
+        };
+        let local = Client::open_local_service(
+            &service,
+            "203.0.113.7:5000",
+            "127.0.0.1:9000",
+            options,
+            "300ms".parse().unwrap(),
+        );
+        let err = timeout(Duration::from_secs(2), local)
+            .await
+            .expect("local dial not bounded")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err,
+            format!("local service {} did not accept within 300ms", addr)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_registrations_are_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        let client = Client::new(ClientConfig {
+            service_register_timeout: "200ms".parse().unwrap(),
+            service_register_retries: 2,
+            ..ClientConfig::default()
+        })
+        .unwrap();
+        let services = [("web", 3000), ("db", 5432)].map(|(name, port)| ServiceConfig {
+            name: name.to_string(),
+            ..ServiceConfig::parse_cli(&format!("127.0.0.1:{}:0", port)).unwrap()
+        });
+
+        // a server that answers "web" on its second attempt and never answers "db"
+        let server = tokio::spawn(async move {
+            let (mut stream, mut reader) = accept_fake_session(&listener).await;
+            let mut attempts: HashMap<String, Vec<u64>> = HashMap::new();
+            let deadline = tokio::time::sleep(Duration::from_secs(2));
+            tokio::pin!(deadline);
+            loop {
+                let frame = tokio::select! {
+                    frame = reader.read_frame(&mut stream) => frame.unwrap().unwrap(),
+                    _ = &mut deadline => break,
+                };
+                let Message::ProxyConfig {
+                    request_id, name, ..
+                } = frame.message
+                else {
+                    continue;
+                };
+                let sent = attempts.entry(name.clone()).or_default();
+                sent.push(request_id);
+                if name == "web" && sent.len() == 2 {
+                    let accepted = Message::ProxyConfigResponse {
+                        request_id,
+                        success: true,
+                        proxy_id: Some(Uuid::new_v4().to_string()),
+                        error: None,
+                        assigned_port: Some(9000),
+                    };
+                    stream
+                        .write_all(&Frame::new(accepted).serialize().unwrap())
+                        .await
+                        .unwrap();
+                }
+            }
+            attempts
+        });
+
+        timeout(
+            Duration::from_secs(5),
+            client.try_connect_to_server(
+                &server_addr,
+                "token",
+                &services,
+                &CancellationToken::new(),
+            ),
+        )
+        .await
+        .expect("connection did not end")
+        .unwrap();
+        let attempts = server.await.unwrap();
+        // retries repeat the request_id of the first attempt
+        assert_eq!(attempts["web"], vec![1, 1]);
+        assert_eq!(attempts["db"], vec![2, 2, 2]);
+        assert_eq!(client.assigned_port(&server_addr, "web").await, Some(9000));
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_asks_for_the_assigned_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        let client = Client::new(ClientConfig::default()).unwrap();
+        let services = [ServiceConfig {
+            name: "web".to_string(),
+            ..ServiceConfig::parse_cli("127.0.0.1:3000:0").unwrap()
+        }];
+        // the port of an earlier session
+        client
+            .assigned_ports
+            .lock()
+            .await
+            .insert((server_addr.clone(), "web".to_string()), 9000);
+
+        // a server that finds the port taken and picks another
+        let server = tokio::spawn(async move {
+            let (mut stream, mut reader) = accept_fake_session(&listener).await;
+            loop {
+                let frame = reader.read_frame(&mut stream).await.unwrap().unwrap();
+                let Message::ProxyConfig {
+                    request_id,
+                    preferred_port,
+                    ..
+                } = frame.message
+                else {
+                    continue;
+                };
+                let moved = Message::ProxyConfigResponse {
+                    request_id,
+                    success: true,
+                    proxy_id: Some(Uuid::new_v4().to_string()),
+                    error: None,
+                    assigned_port: Some(9100),
+                };
+                stream
+                    .write_all(&Frame::new(moved).serialize().unwrap())
+                    .await
+                    .unwrap();
+                // the client has the answer before the connection ends
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                return preferred_port;
+            }
+        });
+
+        timeout(
+            Duration::from_secs(5),
+            client.try_connect_to_server(
+                &server_addr,
+                "token",
+                &services,
+                &CancellationToken::new(),
+            ),
+        )
+        .await
+        .expect("connection did not end")
+        .unwrap();
+        assert_eq!(server.await.unwrap(), Some(9000));
+        assert_eq!(client.assigned_port(&server_addr, "web").await, Some(9100));
+    }
+
+    #[tokio::test]
+    async fn test_ttl_is_not_reset_on_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        let client = Client::new(ClientConfig::default()).unwrap();
+        let services = [ServiceConfig {
+            name: "web".to_string(),
+            ttl: Some("1s".parse().unwrap()),
+            ..ServiceConfig::parse_cli("127.0.0.1:3000:0").unwrap()
+        }];
+
+        // a server that ends each session after its registration, recording the ttl asked for
+        let server = tokio::spawn(async move {
+            let mut ttls = Vec::new();
+            for _ in 0..3 {
+                let (mut stream, mut reader) = accept_fake_session(&listener).await;
+                let frame = timeout(Duration::from_millis(300), reader.read_frame(&mut stream));
+                ttls.push(match frame.await {
+                    Ok(frame) => match frame.unwrap().unwrap().message {
+                        Message::ProxyConfig { ttl_ms, .. } => ttl_ms,
+                        // sent first thing on a session with nothing to register
+                        Message::Heartbeat { .. } => None,
+                        other => panic!("expected ProxyConfig, got {:?}", other),
+                    },
+                    Err(_) => None,
+                });
+            }
+            ttls
+        });
+
+        for pause in [400, 700, 0] {
+            timeout(
+                Duration::from_secs(5),
+                client.try_connect_to_server(
+                    &server_addr,
+                    "token",
+                    &services,
+                    &CancellationToken::new(),
+                ),
+            )
+            .await
+            .expect("connection did not end")
+            .unwrap();
+            tokio::time::sleep(Duration::from_millis(pause)).await;
+        }
+        let ttls = server.await.unwrap();
+        let first = ttls[0].expect("no ttl on the first registration");
+        assert!((900..=1000).contains(&first), "{}", first);
+        // the second session only gets what was left, the third none at all
+        let second = ttls[1].expect("no ttl on the second registration");
+        assert!(second <= first - 400, "{} after {}", second, first);
+        assert_eq!(ttls[2], None);
+    }
+
+    #[tokio::test]
+    async fn test_unhealthy_service_is_registered_once_it_answers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_port = local.local_addr().unwrap().port();
+        drop(local);
+        let client = Client::new(ClientConfig {
+            health_check_interval: "100ms".parse().unwrap(),
+            ..ClientConfig::default()
+        })
+        .unwrap();
+        let services = [ServiceConfig {
+            name: "web".to_string(),
+            require_healthy: true,
+            ..ServiceConfig::parse_cli(&format!("127.0.0.1:{}:0", local_port)).unwrap()
+        }];
+
+        // a server that starts the local service a while into the session
+        let server = tokio::spawn(async move {
+            let (mut stream, mut reader) = accept_fake_session(&listener).await;
+            let started = tokio::time::Instant::now();
+            let mut local = None;
+            loop {
+                let frame = timeout(Duration::from_millis(300), reader.read_frame(&mut stream));
+                match frame.await {
+                    Ok(frame) => match frame.unwrap().unwrap().message {
+                        Message::ProxyConfig { name, .. } => {
+                            assert!(local.is_some(), "registered before it answered");
+                            return (name, started.elapsed());
+                        }
+                        Message::Heartbeat { .. } => {}
+                        other => panic!("expected ProxyConfig, got {:?}", other),
+                    },
+                    Err(_) if local.is_none() => {
+                        local = Some(TcpListener::bind(("127.0.0.1", local_port)).await.unwrap());
+                    }
+                    Err(_) => panic!("not registered once it answered"),
+                }
+            }
+        });
+
+        timeout(
+            Duration::from_secs(5),
+            client.try_connect_to_server(
+                &server_addr,
+                "token",
+                &services,
+                &CancellationToken::new(),
+            ),
+        )
+        .await
+        .expect("connection did not end")
+        .unwrap();
+        let (name, elapsed) = server.await.unwrap();
+        assert_eq!(name, "web");
+        assert!(elapsed >= Duration::from_millis(300), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_server_errors_are_classified() {
+        let fatal = [
+            "Invalid token",
+            "Token required",
+            "Client certificate required",
+            "Client protocol version 4 is not supported (server speaks 5), upgrade required",
+            "Server full (100 clients)",
+        ];
+        for reason in fatal {
+            assert!(ServerError::from_reason(reason).is_fatal(), "{}", reason);
+        }
+        assert!(matches!(
+            ServerError::from_reason("Invalid token"),
+            ServerError::Authentication(_)
+        ));
+
+        let transient = [
+            "Client ID 1234 is already connected",
+            "Stale authentication nonce",
+            "Protocol violation: Undecodable frame",
+        ];
+        for reason in transient {
+            assert_eq!(
+                ServerError::from_reason(reason),
+                ServerError::Other(reason.to_string())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejected_token_stops_reconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        let client = Client::new(ClientConfig {
+            reconnect_interval: HumanDuration(Duration::from_secs(1)),
+            ..ClientConfig::default()
+        })
+        .unwrap();
+
+        // a server that rejects every attempt, counting them
+        let server = tokio::spawn(async move {
+            let mut attempts = 0;
+            while let Ok(Ok((mut stream, _))) =
+                timeout(Duration::from_secs(2), listener.accept()).await
+            {
+                attempts += 1;
+                let challenge = Message::AuthChallenge {
+                    version: PROTOCOL_VERSION,
+                    nonce: vec![0; 32],
+                };
+                stream
+                    .write_all(&Frame::new(challenge).serialize().unwrap())
+                    .await
+                    .unwrap();
+                let mut reader = FrameReader::new();
+                reader.read_frame(&mut stream).await.unwrap().unwrap();
+                let response = Message::AuthResponse {
+                    success: false,
+                    session_key: None,
+                    name: None,
+                    error: Some("Invalid token".to_string()),
+                    compression: Compression::None,
+                    client_id: None,
+                    data_channels: false,
+                };
+                stream
+                    .write_all(&Frame::new(response).serialize().unwrap())
+                    .await
+                    .unwrap();
+            }
+            attempts
+        });
+
+        let error = timeout(
+            Duration::from_secs(5),
+            client.connect_to_server(server_addr, "wrong".to_string(), CancellationToken::new()),
+        )
+        .await
+        .expect("client kept reconnecting")
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ServerError>(),
+            Some(ServerError::Authentication(_))
+        ));
+        assert_eq!(server.await.unwrap(), 1);
+    }
+
+    /// A client of `server_addr` alone, giving each server up after `max_reconnect_attempts`
+    fn client_of(server_addr: &str, max_reconnect_attempts: u32) -> Client {
+        Client::new(ClientConfig {
+            servers: vec![server_addr.to_string()],
+            token: "token".to_string(),
+            reconnect_interval: HumanDuration(Duration::from_millis(10)),
+            max_reconnect_attempts,
+            ..ClientConfig::default()
+        })
+        .unwrap()
+    }
+
+    /// An address nothing listens on
+    fn dead_address() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_attempts_run_out_against_a_dead_address() {
+        let client = client_of(&dead_address(), 3);
+        let error = timeout(Duration::from_secs(5), client.run(CancellationToken::new()))
+            .await
+            .expect("client kept reconnecting")
+            .unwrap_err();
+        assert_eq!(error.downcast_ref::<GaveUp>(), Some(&GaveUp::Network));
+
+        // a server that closes every connection right away, counting them
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = client_of(&listener.local_addr().unwrap().to_string(), 3);
+        let server = tokio::spawn(async move {
+            let mut attempts = 0;
+            while let Ok(Ok(_)) = timeout(Duration::from_secs(1), listener.accept()).await {
+                attempts += 1;
+            }
+            attempts
+        });
+        let error = timeout(Duration::from_secs(5), client.run(CancellationToken::new()))
+            .await
+            .expect("client kept reconnecting")
+            .unwrap_err();
+        assert_eq!(error.downcast_ref::<GaveUp>(), Some(&GaveUp::Network));
+        assert_eq!(server.await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_once_exits_with_the_reason_it_gave_up() {
+        // a rejected token
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = client_of(&listener.local_addr().unwrap().to_string(), 0).once();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let challenge = Message::AuthChallenge {
+                version: PROTOCOL_VERSION,
+                nonce: vec![0; 32],
+            };
+            stream
+                .write_all(&Frame::new(challenge).serialize().unwrap())
+                .await
+                .unwrap();
+            let mut reader = FrameReader::new();
+            reader.read_frame(&mut stream).await.unwrap().unwrap();
+            let response = Message::AuthResponse {
+                success: false,
+                session_key: None,
+                name: None,
+                error: Some("Invalid token".to_string()),
+                compression: Compression::None,
+                client_id: None,
+                data_channels: false,
+            };
+            stream
+                .write_all(&Frame::new(response).serialize().unwrap())
+                .await
+                .unwrap();
+        });
+        let error = timeout(Duration::from_secs(5), client.run(CancellationToken::new()))
+            .await
+            .unwrap()
+            .unwrap_err();
+        let gave_up = error.downcast_ref::<GaveUp>().copied();
+        assert_eq!(gave_up, Some(GaveUp::Authentication));
+        assert_eq!(gave_up.unwrap().exit_code(), 2);
+
+        // an unreachable server, not tried again despite unlimited attempts
+        let client = client_of(&dead_address(), 0).once();
+        let error = timeout(Duration::from_secs(5), client.run(CancellationToken::new()))
+            .await
+            .expect("client reconnected with --once")
+            .unwrap_err();
+        let gave_up = error.downcast_ref::<GaveUp>().copied();
+        assert_eq!(gave_up, Some(GaveUp::Network));
+        assert_eq!(gave_up.unwrap().exit_code(), 3);
+
+        // a session the server drops
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = client_of(&listener.local_addr().unwrap().to_string(), 0).once();
+        tokio::spawn(async move {
+            let (stream, _) = accept_fake_session(&listener).await;
+            drop(stream);
+            // a reconnect would wait here forever
+            let _ = listener.accept().await;
+            std::future::pending::<()>().await;
+        });
+        let error = timeout(Duration::from_secs(5), client.run(CancellationToken::new()))
+            .await
+            .expect("client reconnected with --once")
+            .unwrap_err();
+        assert_eq!(error.downcast_ref::<GaveUp>(), Some(&GaveUp::Network));
+
+        // a clean shutdown while connected
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        let client = client_of(&server_addr, 0).once();
+        tokio::spawn(async move {
+            // held open until the client leaves
+            let (mut stream, _) = accept_fake_session(&listener).await;
+            let mut rest = Vec::new();
+            let _ = stream.read_to_end(&mut rest).await;
+        });
+        let shutdown = CancellationToken::new();
+        let run = tokio::spawn({
+            let client = client.clone();
+            let shutdown = shutdown.clone();
+            async move { client.run(shutdown).await }
+        });
+        timeout(Duration::from_secs(5), async {
+            while !client.connections.lock().await.contains_key(&server_addr) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("client did not connect");
+        shutdown.cancel();
+        timeout(Duration::from_secs(5), run)
+            .await
+            .expect("client did not stop")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_missed_heartbeats_end_the_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        let client = Client::new(ClientConfig {
+            heartbeat_interval: HumanDuration(Duration::from_secs(1)),
+            heartbeat_max_missed: 2,
+            ..ClientConfig::default()
+        })
+        .unwrap();
+
+        // a server that authenticates the client, then goes silent without closing
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let challenge = Message::AuthChallenge {
+                version: PROTOCOL_VERSION,
+                nonce: vec![0; 32],
+            };
+            stream
+                .write_all(&Frame::new(challenge).serialize().unwrap())
+                .await
+                .unwrap();
+            let mut reader = FrameReader::new();
+            reader.read_frame(&mut stream).await.unwrap().unwrap();
+            let response = Message::AuthResponse {
+                success: true,
+                session_key: Some(vec![0; 32]),
+                name: None,
+                error: None,
+                compression: Compression::None,
+                client_id: None,
+                data_channels: false,
+            };
+            stream
+                .write_all(&Frame::new(response).serialize().unwrap())
+                .await
+                .unwrap();
+            let mut heartbeats = 0;
+            while let Ok(Some(frame)) = reader.read_frame(&mut stream).await {
+                if matches!(frame.message, Message::Heartbeat { .. }) {
+                    heartbeats += 1;
+                }
+            }
+            heartbeats
+        });
+
+        timeout(
+            Duration::from_secs(5),
+            client.try_connect_to_server(&server_addr, "token", &[], &CancellationToken::new()),
+        )
+        .await
+        .expect("dead connection was not detected")
+        .unwrap();
+        assert!(client.connections.lock().await.is_empty());
+        // the connection was closed after the unanswered heartbeats
+        assert_eq!(server.await.unwrap(), 2);
+    }
+
+    /// The status of a running client from its control socket, once it answers
+    #[cfg(unix)]
+    async fn control_status(control: &ControlClient) -> serde_json::Value {
+        loop {
+            if let Ok(status) = control.send(&ControlCommand::Status).await {
+                return status;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// The names of the services of the first server in a control `status`
+    #[cfg(unix)]
+    fn service_names(status: &serde_json::Value) -> Vec<&str> {
+        status["servers"][0]["services"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|service| service["name"].as_str().unwrap())
+            .collect()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_control_socket_adds_and_removes_services() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir
+            .path()
+            .join("client.sock")
+            .to_string_lossy()
+            .into_owned();
+        let client = Client::new(ClientConfig {
+            servers: vec![server_addr.clone()],
+            token: "token".to_string(),
+            services: vec![ServiceConfig {
+                name: "web".to_string(),
+                ..ServiceConfig::parse_cli("127.0.0.1:3000:9000").unwrap()
+            }],
+            control_socket: Some(socket.clone()),
+            ..ClientConfig::default()
+        })
+        .unwrap();
+
+        // a server accepting every registration, reporting what it was asked
+        let (requests_tx, mut requests) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (mut stream, mut reader) = accept_fake_session(&listener).await;
+            while let Ok(Some(frame)) = reader.read_frame(&mut stream).await {
+                let Message::ProxyConfig {
+                    request_id,
+                    op,
+                    name,
+                    remote_port,
+                    ..
+                } = frame.message
+                else {
+                    continue;
+                };
+                if op == ProxyConfigOpCode::Update {
+                    let accepted = Message::ProxyConfigResponse {
+                        request_id,
+                        success: true,
+                        proxy_id: Some(Uuid::new_v4().to_string()),
+                        error: None,
+                        assigned_port: Some(remote_port),
+                    };
+                    stream
+                        .write_all(&Frame::new(accepted).serialize().unwrap())
+                        .await
+                        .unwrap();
+                }
+                let _ = requests_tx.send((op, name, remote_port));
+            }
+        });
+        let shutdown = CancellationToken::new();
+        let running = tokio::spawn({
+            let client = client.clone();
+            let shutdown = shutdown.clone();
+            async move { client.run(shutdown).await }
+        });
+
+        assert_eq!(
+            timeout(Duration::from_secs(5), requests.recv())
+                .await
+                .unwrap()
+                .unwrap(),
+            (ProxyConfigOpCode::Update, "web".to_string(), 9000)
+        );
+        let control = ControlClient::new(socket.clone());
+        let status = loop {
+            let status = control_status(&control).await;
+            if status["ready"] == true {
+                break status;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        };
+        assert_eq!(status["client_id"], client.client_id.as_str());
+        assert_eq!(status["servers"][0]["server"], server_addr.as_str());
+        assert_eq!(service_names(&status), ["web"]);
+
+        let add = ControlCommand::AddService {
+            service: ServiceSpec::Mapping("127.0.0.1:3001:9001".to_string()),
+            server: None,
+        };
+        let answer = control.send(&add).await.unwrap();
+        assert!(answer["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Added service"));
+        let (op, added, port) = timeout(Duration::from_secs(5), requests.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((op, port), (ProxyConfigOpCode::Update, 9001));
+        // a service clashing with one configured is refused before anything is sent
+        let error = control.send(&add).await.unwrap_err();
+        assert!(error.to_string().contains("Cannot add"), "{}", error);
+        let unknown = ControlCommand::RemoveService {
+            name: "web".to_string(),
+            server: Some("127.0.0.1:1".to_string()),
+        };
+        assert!(control.send(&unknown).await.is_err());
+
+        let remove = ControlCommand::RemoveService {
+            name: "web".to_string(),
+            server: None,
+        };
+        control.send(&remove).await.unwrap();
+        assert_eq!(
+            timeout(Duration::from_secs(5), requests.recv())
+                .await
+                .unwrap()
+                .unwrap(),
+            (ProxyConfigOpCode::Delete, "web".to_string(), 9000)
+        );
+        assert_eq!(service_names(&control_status(&control).await), [added]);
+        // without a config file there is nothing to reload
+        assert!(control.send(&ControlCommand::Reload).await.is_err());
+
+        shutdown.cancel();
+        timeout(Duration::from_secs(5), running)
+            .await
+            .expect("client did not stop")
+            .unwrap()
+            .unwrap();
+        assert!(!std::path::Path::new(&socket).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_control_commands_are_json_lines() {
+        let command: ControlCommand = serde_json::from_str(
+            r#"{"command": "add-service", "service": {"name": "web", "local_port": 8080}}"#,
+        )
+        .unwrap();
+        match command {
+            ControlCommand::AddService {
+                service: ServiceSpec::Table(service),
+                server: None,
+            } => assert_eq!((service.name.as_str(), service.local_port), ("web", 8080)),
+            other => panic!("expected a service table, got {:?}", other),
+        }
+        let command: ControlCommand =
+            serde_json::from_str(r#"{"command": "add-service", "service": "8080:9090"}"#).unwrap();
+        assert!(matches!(
+            command,
+            ControlCommand::AddService {
+                service: ServiceSpec::Mapping(_),
+                ..
+            }
+        ));
+        assert!(serde_json::from_str::<ControlCommand>(r#"{"command": "restart"}"#).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use anyhow::Result;
+use std::fmt;
+use tokio::io::AsyncWriteExt;
+use tokio::time::{timeout, Duration, Instant};
+
+use super::{Authenticated, Client, ServerError};
+#[cfg(feature = "quic")]
+use crate::config::Transport;
+use crate::utils::{BoxedStream, Frame, FrameReader, Message};
+
+/// Why `Client::ping` could not open a session, telling apart where the check stopped
+#[derive(Debug)]
+pub enum PingError {
+    /// No address of the server accepted a TCP connection
+    Unreachable(anyhow::Error),
+    /// The server refused the token or certificate
+    AuthRejected(anyhow::Error),
+    /// Client and server speak different protocol versions
+    ProtocolMismatch(anyhow::Error),
+    /// Anything else, e.g. a TLS failure or a dropped connection
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for PingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unreachable(e) => write!(f, "TCP unreachable: {}", e),
+            Self::AuthRejected(e) => write!(f, "Auth rejected: {}", e),
+            Self::ProtocolMismatch(e) => write!(f, "Protocol mismatch: {}", e),
+            Self::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for PingError {}
+
+/// An authenticated session with a server that only exchanges heartbeats
+pub struct PingSession {
+    stream: BoxedStream,
+    frame_reader: FrameReader,
+    /// Time from the TCP connection being established to the `AuthResponse`
+    pub auth_time: Duration,
+}
+
+impl PingSession {
+    /// Sends a heartbeat and returns its round trip time, None if it is not answered
+    /// within `wait`. Heartbeats are told apart by their second, send at most one per second
+    pub async fn probe(&mut self, wait: Duration) -> Result<Option<Duration>> {
+        let heartbeat = Message::new_heartbeat();
+        let Message::Heartbeat { timestamp: sent } = heartbeat else {
+            unreachable!();
+        };
+        let start = Instant::now();
+        self.stream
+            .write_all(&Frame::new(heartbeat).serialize_framed(self.frame_reader.framing())?)
+            .await?;
+
+        let answer = async {
+            loop {
+                let Some(frame) = self.frame_reader.read_frame(&mut self.stream).await? else {
+                    return Err(anyhow::anyhow!("Connection closed by server"));
+                };
+                match frame.message {
+                    Message::HeartbeatResponse { timestamp, .. } if timestamp == sent => {
+                        return Ok(start.elapsed());
+                    }
+                    Message::Error { message } => {
+                        return Err(anyhow::anyhow!("Server reported an error: {}", message));
+                    }
+                    _ => {}
+                }
+            }
+        };
+        match timeout(wait, answer).await {
+            Ok(rtt) => rtt.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// Round trip statistics of a ping run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingSummary {
+    /// Fastest answered probe
+    pub min: Duration,
+    /// Mean of the answered probes
+    pub avg: Duration,
+    /// Slowest answered probe
+    pub max: Duration,
+}
+
+impl PingSummary {
+    /// Summarizes the round trips of answered probes, None if none was answered
+    pub fn of(rtts: &[Duration]) -> Option<Self> {
+        let min = *rtts.iter().min()?;
+        let max = *rtts.iter().max()?;
+        let avg = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+        Some(Self { min, avg, max })
+    }
+}
+
+impl Client {
+    /// Connects and authenticates to `server_addr` without registering any service,
+    /// for checking the token and measuring latency with `PingSession::probe`
+    pub async fn ping(&self, server_addr: &str, token: &str) -> Result<PingSession, PingError> {
+        #[cfg(feature = "quic")]
+        if self.config.transport == Transport::Quic {
+            let (stream, _connection) = self
+                .dial_quic(server_addr)
+                .await
+                .map_err(PingError::Unreachable)?;
+            let start = Instant::now();
+            let authenticated = self
+                .authenticate_stream(stream, None, server_addr, token)
+                .await;
+            return Self::ping_session(authenticated, start);
+        }
+
+        let tcp_stream = self
+            .dial(server_addr)
+            .await
+            .map_err(PingError::Unreachable)?;
+        let start = Instant::now();
+        let authenticated = self.authenticate(tcp_stream, server_addr, token).await;
+        Self::ping_session(authenticated, start)
+    }
+
+    /// The session of an authentication made at `start`, or why it failed
+    fn ping_session(
+        authenticated: Result<Authenticated>,
+        start: Instant,
+    ) -> Result<PingSession, PingError> {
+        let Authenticated {
+            stream,
+            frame_reader,
+            ..
+        } = authenticated.map_err(|e| match e.downcast_ref::<ServerError>() {
+            Some(ServerError::Authentication(_)) => PingError::AuthRejected(e),
+            Some(ServerError::ProtocolVersion(_)) => PingError::ProtocolMismatch(e),
+            _ => PingError::Other(e),
+        })?;
+        Ok(PingSession {
+            stream,
+            frame_reader,
+            auth_time: start.elapsed(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_of_answered_probes() {
+        assert_eq!(PingSummary::of(&[]), None);
+        let rtts = [2, 1, 6].map(Duration::from_millis);
+        assert_eq!(
+            PingSummary::of(&rtts),
+            Some(PingSummary {
+                min: Duration::from_millis(1),
+                avg: Duration::from_millis(3),
+                max: Duration::from_millis(6),
+            })
+        );
+    }
+}
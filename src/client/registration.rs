@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+use crate::config::ServiceConfig;
+use crate::utils::crypto::secret_hash;
+use crate::utils::protocol::ProxyConfigOpCode;
+use crate::utils::Message;
+
+/// Service registrations sent on a server connection that still wait for a `ProxyConfigResponse`,
+/// shared by the session sending them, the read task seeing the responses and the retry task
+#[derive(Debug, Default)]
+pub struct Registrations {
+    /// Unanswered registrations by their `request_id`
+    pending: HashMap<u64, Pending>,
+    /// `request_id` of the last request sent, IDs start at 1
+    last_request_id: u64,
+}
+
+#[derive(Debug)]
+struct Pending {
+    service: ServiceConfig,
+    /// The request as sent, resent unchanged on retries
+    request: Message,
+    sent_at: Instant,
+    /// Times the request was sent
+    attempts: u32,
+}
+
+/// What became of a registration left unanswered too long
+#[derive(Debug)]
+pub enum Expired {
+    /// Sent again, this is its `attempt`th send
+    Retry {
+        service: String,
+        request: Box<Message>,
+        attempt: u32,
+    },
+    /// Out of retries and no longer waited for
+    GaveUp { service: String, attempts: u32 },
+}
+
+impl Registrations {
+    /// Creates the `ProxyConfig` registering `service` and starts waiting for its response.
+    /// `ttl` is the time the service has left, see `Deadlines`
+    pub fn register(
+        &mut self,
+        service: &ServiceConfig,
+        preferred_port: Option<u16>,
+        ttl: Option<Duration>,
+    ) -> Message {
+        self.last_request_id += 1;
+        let request = proxy_config(
+            self.last_request_id,
+            ProxyConfigOpCode::Update,
+            service,
+            service.remote_port,
+            preferred_port,
+            ttl,
+        );
+        self.pending.insert(
+            self.last_request_id,
+            Pending {
+                service: service.clone(),
+                request: request.clone(),
+                sent_at: Instant::now(),
+                attempts: 1,
+            },
+        );
+        request
+    }
+
+    /// Creates the `ProxyConfig` removing `service`, which was registered on `remote_port`,
+    /// and stops waiting for its registration. Servers do not answer removals
+    pub fn unregister(&mut self, service: &ServiceConfig, remote_port: u16) -> Message {
+        self.pending
+            .retain(|_, pending| pending.service.name != service.name);
+        self.last_request_id += 1;
+        proxy_config(
+            self.last_request_id,
+            ProxyConfigOpCode::Delete,
+            service,
+            remote_port,
+            None,
+            None,
+        )
+    }
+
+    /// Takes the service the response to `request_id` answers,
+    /// None if that request is unknown, answered already or given up
+    pub fn answered(&mut self, request_id: u64) -> Option<ServiceConfig> {
+        self.pending
+            .remove(&request_id)
+            .map(|pending| pending.service)
+    }
+
+    /// Whether a request with `request_id` was sent on this connection
+    pub fn was_sent(&self, request_id: u64) -> bool {
+        (1..=self.last_request_id).contains(&request_id)
+    }
+
+    /// Whether every registration was answered or given up
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Registrations unanswered for `timeout`: those sent fewer than `retries` times
+    /// again are to be resent, the others are given up
+    pub fn expired(&mut self, timeout: Duration, retries: u32) -> Vec<Expired> {
+        self.expired_at(Instant::now(), timeout, retries)
+    }
+
+    fn expired_at(&mut self, now: Instant, timeout: Duration, retries: u32) -> Vec<Expired> {
+        let mut expired = Vec::new();
+        self.pending.retain(|_, pending| {
+            if now.saturating_duration_since(pending.sent_at) < timeout {
+                return true;
+            }
+            if pending.attempts > retries {
+                expired.push(Expired::GaveUp {
+                    service: pending.service.name.clone(),
+                    attempts: pending.attempts,
+                });
+                return false;
+            }
+            pending.attempts += 1;
+            pending.sent_at = now;
+            expired.push(Expired::Retry {
+                service: pending.service.name.clone(),
+                request: Box::new(pending.request.clone()),
+                attempt: pending.attempts,
+            });
+            true
+        });
+        expired
+    }
+}
+
+/// The `ProxyConfig` asking for `op` on `service` exposed on `remote_port`
+fn proxy_config(
+    request_id: u64,
+    op: ProxyConfigOpCode,
+    service: &ServiceConfig,
+    remote_port: u16,
+    preferred_port: Option<u16>,
+    ttl: Option<Duration>,
+) -> Message {
+    Message::ProxyConfig {
+        request_id,
+        op,
+        name: service.name.clone(),
+        // servers only log the local side, so socket services send their path
+        local_ip: match &service.local_path {
+            Some(_) => service.local_addr(),
+            None => service.local_ip.clone(),
+        },
+        local_port: service.local_port,
+        remote_port,
+        preferred_port,
+        bind_host: service.bind_host.clone(),
+        http_host: service.http_host.clone(),
+        sni: service.sni.clone(),
+        group: service.group.clone(),
+        max_connections: service.max_connections,
+        secret_hash: service
+            .secret
+            .as_ref()
+            .map(|secret| secret_hash(&service.name, secret)),
+        relay_peers: service.relay_peers.clone(),
+        ttl_ms: ttl.map(|ttl| u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX)),
+    }
+}
+
+/// When services with a `ttl` run out, by (server, service name). Kept across reconnects,
+/// so a service registered again only gets the time it has left
+#[derive(Debug, Default)]
+pub struct Deadlines {
+    deadlines: HashMap<(String, String), Instant>,
+}
+
+impl Deadlines {
+    /// Time left for service `name` on `server_addr`, counted from its first registration.
+    /// Once it ran out a new `ttl` starts when `renew`, else None
+    pub fn left(
+        &mut self,
+        server_addr: &str,
+        name: &str,
+        ttl: Duration,
+        renew: bool,
+    ) -> Option<Duration> {
+        self.left_at(Instant::now(), server_addr, name, ttl, renew)
+    }
+
+    fn left_at(
+        &mut self,
+        now: Instant,
+        server_addr: &str,
+        name: &str,
+        ttl: Duration,
+        renew: bool,
+    ) -> Option<Duration> {
+        let deadline = self
+            .deadlines
+            .entry((server_addr.to_string(), name.to_string()))
+            .or_insert(now + ttl);
+        // the server counts in milliseconds, less than one is nothing left
+        let left = deadline.saturating_duration_since(now);
+        if left >= Duration::from_millis(1) {
+            return Some(left);
+        }
+        renew.then(|| {
+            *deadline = now + ttl;
+            ttl
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unanswered_registrations_are_retried_then_given_up() {
+        let mut registrations = Registrations::default();
+        let service = ServiceConfig::parse_cli("127.0.0.1:80:8080").unwrap();
+        let Message::ProxyConfig { request_id, .. } = registrations.register(&service, None, None)
+        else {
+            panic!("expected a ProxyConfig");
+        };
+        assert_eq!(request_id, 1);
+        let timeout = Duration::from_secs(10);
+        let start = Instant::now();
+
+        assert!(registrations.expired_at(start, timeout, 1).is_empty());
+        match &registrations.expired_at(start + timeout, timeout, 1)[..] {
+            [Expired::Retry {
+                request,
+                attempt: 2,
+                ..
+            }] if matches!(**request, Message::ProxyConfig { request_id: 1, .. }) => {}
+            other => panic!("expected a retry, got {:?}", other),
+        }
+        // the retry restarted the clock
+        assert!(registrations
+            .expired_at(start + timeout + Duration::from_secs(5), timeout, 1)
+            .is_empty());
+        match &registrations.expired_at(start + timeout * 2, timeout, 1)[..] {
+            [Expired::GaveUp { attempts: 2, .. }] => {}
+            other => panic!("expected giving up, got {:?}", other),
+        }
+        assert!(registrations.is_empty());
+
+        // a late response is recognized but no longer matched
+        assert!(registrations.was_sent(1));
+        assert!(registrations.answered(1).is_none());
+        assert!(!registrations.was_sent(2));
+    }
+
+    #[test]
+    fn test_deadlines_survive_reconnects() {
+        let mut deadlines = Deadlines::default();
+        let ttl = Duration::from_secs(60);
+        let start = Instant::now();
+        let left = |deadlines: &mut Deadlines, after: u64, renew: bool| {
+            deadlines.left_at(
+                start + Duration::from_secs(after),
+                "server",
+                "web",
+                ttl,
+                renew,
+            )
+        };
+
+        assert_eq!(left(&mut deadlines, 0, false), Some(ttl));
+        // registering again later only gets what is left
+        assert_eq!(
+            left(&mut deadlines, 45, false),
+            Some(Duration::from_secs(15))
+        );
+        assert_eq!(left(&mut deadlines, 60, false), None);
+        assert_eq!(left(&mut deadlines, 90, false), None);
+        // renewing starts a new ttl from then on
+        assert_eq!(left(&mut deadlines, 90, true), Some(ttl));
+        assert_eq!(
+            left(&mut deadlines, 120, false),
+            Some(Duration::from_secs(30))
+        );
+
+        // each server counts on its own
+        assert_eq!(
+            deadlines.left_at(start + Duration::from_secs(120), "other", "web", ttl, false),
+            Some(ttl)
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use super::{Client, ServerConnection};
+use crate::utils::{CloseCode, Message};
+use crate::{log_debug, log_info, warn};
+
+impl Client {
+    /// Detaches the connections through `server_addr` from its lost session, their data is
+    /// buffered for the next session to resume them with
+    pub(super) async fn detach_connections(&self, server_addr: &str) {
+        for local_conn in self.local_connections.lock().await.values() {
+            if local_conn.server_addr == server_addr {
+                local_conn.replay.detach();
+            }
+        }
+    }
+
+    /// Asks the new session with `server_addr` to resume each connection detached from the
+    /// last one, the server answers with a `ResumeConnection` or a `CloseConnection`
+    pub(super) async fn resume_connections(&self, server_addr: &str) {
+        let Some(sender) = self
+            .connections
+            .lock()
+            .await
+            .get(server_addr)
+            .map(|conn| conn.sender.clone())
+        else {
+            return;
+        };
+        let detached: Vec<Message> = self
+            .local_connections
+            .lock()
+            .await
+            .values()
+            .filter(|local_conn| {
+                local_conn.server_addr == server_addr && !local_conn.replay.is_attached()
+            })
+            .map(|local_conn| local_conn.replay.resume_message())
+            .collect();
+        if detached.is_empty() {
+            return;
+        }
+        log_info!(
+            "Resuming {} connections through {}",
+            detached.len(),
+            server_addr
+        );
+        for message in detached {
+            let _ = sender.send(message);
+        }
+    }
+
+    /// Closes the connections detached from the last session with `server_addr`, the
+    /// server is given up
+    pub(super) async fn abandon_connections(&self, server_addr: &str) {
+        self.local_connections.lock().await.retain(|_, local_conn| {
+            let detached =
+                local_conn.server_addr == server_addr && !local_conn.replay.is_attached();
+            if detached {
+                local_conn.replay.abandon();
+            }
+            !detached
+        });
+    }
+
+    /// Handles the server's `ResumeConnection` answering the one sent for a detached
+    /// connection: sends again what the server did not receive and goes on
+    pub(super) async fn resume_local_connection(
+        &self,
+        server_addr: &str,
+        connection_id: &str,
+        received: u64,
+        acknowledged: u64,
+    ) {
+        let Some(route) = self
+            .connections
+            .lock()
+            .await
+            .get(server_addr)
+            .map(ServerConnection::route)
+        else {
+            return;
+        };
+        let mut local_connections = self.local_connections.lock().await;
+        let Some(local_conn) = local_connections.get(connection_id) else {
+            return;
+        };
+        let sender = route.sender.clone();
+        match local_conn.replay.reattach(route, received, acknowledged) {
+            Ok(lost) => {
+                // updates lost with the session are granted, a pause asked again
+                local_conn.window.grant(lost);
+                local_conn.window.resume();
+                if local_conn.backlog.is_paused() {
+                    let _ = sender.send(Message::new_pause(connection_id));
+                }
+                log_debug!("Resumed conn={} through {}", connection_id, server_addr);
+            }
+            Err(reason) => {
+                warn!(
+                    "Could not resume conn={} through {}: {}",
+                    connection_id, server_addr, reason
+                );
+                if let Some(local_conn) = local_connections.remove(connection_id) {
+                    local_conn.replay.abandon();
+                }
+                let _ = sender.send(Message::CloseConnection {
+                    connection_id: connection_id.to_string(),
+                    stats: None,
+                    reason: Some(format!("Could not resume the connection: {}", reason)),
+                    code: Some(CloseCode::LocalError),
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,473 @@
+use colored::{Color, Colorize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::config::{ConnectionConfig, ServiceConfig};
+use crate::logging::console::{console_print_non_verbose, supports_color};
+use crate::utils::protocol::ClientStats;
+use crate::warn;
+
+/// Where the client stands with a server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerStatus {
+    /// Dialing or authenticating
+    Connecting,
+    /// The session is up
+    Connected,
+    /// The session ended, a reconnect is pending
+    Disconnected,
+    /// The server refused the client for good and is not reconnected to
+    GaveUp,
+}
+
+impl ServerStatus {
+    fn label(self) -> &'static str {
+        match self {
+            ServerStatus::Connecting => "connecting",
+            ServerStatus::Connected => "connected",
+            ServerStatus::Disconnected => "disconnected",
+            ServerStatus::GaveUp => "gave up",
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            ServerStatus::Connecting => Color::Yellow,
+            ServerStatus::Connected => Color::Green,
+            ServerStatus::Disconnected | ServerStatus::GaveUp => Color::Red,
+        }
+    }
+}
+
+/// Where a service stands with a server in the current session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceStatus {
+    /// Not answered yet
+    Pending,
+    /// Accepted, on the remote port the server answered with, if any
+    Registered(Option<u16>),
+    /// Refused by the server or never answered, for the reason given
+    Rejected(String),
+    /// Removed by the server once its `ttl` ran out, and not renewed
+    Expired,
+    /// Held back with `require_healthy` until its local service answers
+    Waiting,
+}
+
+struct ServiceState {
+    name: String,
+    local: String,
+    /// What the server is asked to expose: a port, `auto`, a route or `secret`
+    requested: String,
+    status: ServiceStatus,
+    /// Why the local service did not answer its last reported health check
+    local_error: Option<String>,
+}
+
+impl ServiceState {
+    fn new(service: &ServiceConfig) -> Self {
+        let requested = match service.shared_route() {
+            _ if service.secret.is_some() => "secret".to_string(),
+            _ if service.relay_peers.is_some() => "relay".to_string(),
+            Some(route) => route,
+            None if service.remote_port == 0 => "auto".to_string(),
+            None => format!(":{}", service.remote_port),
+        };
+        Self {
+            name: service.name.clone(),
+            local: service.local_addr(),
+            requested,
+            status: ServiceStatus::Pending,
+            local_error: None,
+        }
+    }
+
+    /// The remote column, with the port a server assigned once it is known
+    fn remote(&self) -> String {
+        match &self.status {
+            ServiceStatus::Registered(Some(port)) if self.requested == "auto" => {
+                format!(":{} (auto)", port)
+            }
+            _ => self.requested.clone(),
+        }
+    }
+
+    fn status(&self) -> (String, Color) {
+        match &self.status {
+            ServiceStatus::Pending => ("pending".to_string(), Color::Yellow),
+            ServiceStatus::Registered(_) if self.local_error.is_some() => (
+                "registered, local service unreachable".to_string(),
+                Color::Yellow,
+            ),
+            ServiceStatus::Registered(_) => ("registered".to_string(), Color::Green),
+            ServiceStatus::Rejected(reason) => (format!("rejected: {}", reason), Color::Red),
+            ServiceStatus::Expired => ("expired".to_string(), Color::Red),
+            ServiceStatus::Waiting => ("waiting for local service".to_string(), Color::Yellow),
+        }
+    }
+}
+
+struct ServerState {
+    addr: String,
+    status: ServerStatus,
+    services: Vec<ServiceState>,
+    /// What the server last counted of the client's connections in the current session
+    stats: Option<ClientStats>,
+}
+
+/// What the client knows about each server and its services, shown as the status table.
+/// Clones share the state
+#[derive(Clone)]
+pub struct ClientState {
+    servers: Arc<Mutex<Vec<ServerState>>>,
+}
+
+impl ClientState {
+    /// Every server of `entries` connecting, with its services pending
+    pub fn new(entries: &[ConnectionConfig]) -> Self {
+        let servers = entries
+            .iter()
+            .map(|entry| ServerState {
+                addr: entry.server.clone(),
+                status: ServerStatus::Connecting,
+                services: entry.services.iter().map(ServiceState::new).collect(),
+                stats: None,
+            })
+            .collect();
+        Self {
+            servers: Arc::new(Mutex::new(servers)),
+        }
+    }
+
+    /// Records the status of a server, returning whether it changed.
+    /// Services are pending again on every new session
+    pub async fn server_status(&self, server_addr: &str, status: ServerStatus) -> bool {
+        let mut servers = self.servers.lock().await;
+        let Some(server) = servers.iter_mut().find(|server| server.addr == server_addr) else {
+            return false;
+        };
+        if status == ServerStatus::Connected {
+            server.stats = None;
+            for service in &mut server.services {
+                service.status = ServiceStatus::Pending;
+            }
+        }
+        std::mem::replace(&mut server.status, status) != status
+    }
+
+    /// Records what a server counted of the client's connections in a heartbeat response
+    pub async fn server_stats(&self, server_addr: &str, stats: ClientStats) {
+        let mut servers = self.servers.lock().await;
+        if let Some(server) = servers.iter_mut().find(|server| server.addr == server_addr) {
+            server.stats = Some(stats);
+        }
+    }
+
+    /// Records the answer of a server to the registration of service `name`.
+    /// Returns whether that answered the last pending service of the server
+    pub async fn service_status(
+        &self,
+        server_addr: &str,
+        name: &str,
+        status: ServiceStatus,
+    ) -> bool {
+        let mut servers = self.servers.lock().await;
+        let Some(server) = servers.iter_mut().find(|server| server.addr == server_addr) else {
+            return false;
+        };
+        let Some(service) = server
+            .services
+            .iter_mut()
+            .find(|service| service.name == name)
+        else {
+            return false;
+        };
+        let was_pending = service.status == ServiceStatus::Pending;
+        service.status = status;
+        was_pending
+            && server
+                .services
+                .iter()
+                .all(|service| service.status != ServiceStatus::Pending)
+    }
+
+    /// Records why the local service of `name` does not answer its health checks,
+    /// None once it does. Returns whether that changed
+    pub async fn local_error(&self, server_addr: &str, name: &str, error: Option<String>) -> bool {
+        let mut servers = self.servers.lock().await;
+        let service = servers
+            .iter_mut()
+            .filter(|server| server.addr == server_addr)
+            .flat_map(|server| server.services.iter_mut())
+            .find(|service| service.name == name);
+        match service {
+            Some(service) => {
+                std::mem::replace(&mut service.local_error, error) != service.local_error
+            }
+            None => false,
+        }
+    }
+
+    /// Adds `service` to the services of a server, pending until the server answers it
+    pub async fn add_service(&self, server_addr: &str, service: &ServiceConfig) {
+        let mut servers = self.servers.lock().await;
+        if let Some(server) = servers.iter_mut().find(|server| server.addr == server_addr) {
+            server.services.push(ServiceState::new(service));
+        }
+    }
+
+    /// Removes service `name` from the services of a server
+    pub async fn remove_service(&self, server_addr: &str, name: &str) {
+        let mut servers = self.servers.lock().await;
+        if let Some(server) = servers.iter_mut().find(|server| server.addr == server_addr) {
+            server.services.retain(|service| service.name != name);
+        }
+    }
+
+    /// Each server and its services as JSON, what the status table shows, with
+    /// `connections` giving the open connections by server. `ready` tells whether every
+    /// server is connected and every service registered
+    pub async fn to_json(&self, connections: &HashMap<String, usize>) -> Value {
+        let servers = self.servers.lock().await;
+        let ready = servers.iter().all(|server| {
+            server.status == ServerStatus::Connected
+                && server
+                    .services
+                    .iter()
+                    .all(|service| matches!(service.status, ServiceStatus::Registered(_)))
+        });
+        let servers: Vec<Value> = servers
+            .iter()
+            .map(|server| {
+                let services: Vec<Value> = server
+                    .services
+                    .iter()
+                    .map(|service| {
+                        json!({
+                            "name": service.name,
+                            "local": service.local,
+                            "remote": service.remote(),
+                            "registered": matches!(service.status, ServiceStatus::Registered(_)),
+                            "status": service.status().0,
+                        })
+                    })
+                    .collect();
+                json!({
+                    "server": server.addr,
+                    "state": server.status.label(),
+                    "connections": connections.get(&server.addr).copied().unwrap_or(0),
+                    "stats": server.stats,
+                    "services": services,
+                })
+            })
+            .collect();
+        json!({ "ready": ready, "servers": servers })
+    }
+
+    /// Whether a server has services still waiting for an answer
+    pub async fn has_pending(&self, server_addr: &str) -> bool {
+        self.servers.lock().await.iter().any(|server| {
+            server.addr == server_addr
+                && server
+                    .services
+                    .iter()
+                    .any(|service| service.status == ServiceStatus::Pending)
+        })
+    }
+
+    /// Prints the status table to the console, colored if the terminal supports it
+    pub async fn print(&self) {
+        console_print_non_verbose(&self.render(supports_color()).await);
+    }
+
+    /// Prints the status table once a server answered every registration, warning
+    /// when it rejected some of the services
+    pub async fn print_answered(&self, server_addr: &str) {
+        self.print().await;
+        if let Some((accepted, answered)) = self.accepted(server_addr).await {
+            if accepted < answered {
+                warn!(
+                    "Server {} accepted {} of {} services, {} rejected",
+                    server_addr,
+                    accepted,
+                    answered,
+                    answered - accepted
+                );
+            }
+        }
+    }
+
+    /// How many of the services a server answered in the current session it accepted,
+    /// as (accepted, answered)
+    async fn accepted(&self, server_addr: &str) -> Option<(usize, usize)> {
+        let servers = self.servers.lock().await;
+        let server = servers.iter().find(|server| server.addr == server_addr)?;
+        let count = |matches: fn(&ServiceStatus) -> bool| {
+            server
+                .services
+                .iter()
+                .filter(|service| matches(&service.status))
+                .count()
+        };
+        let accepted = count(|status| matches!(status, ServiceStatus::Registered(_)));
+        let rejected = count(|status| matches!(status, ServiceStatus::Rejected(_)));
+        Some((accepted, accepted + rejected))
+    }
+
+    /// The status table: a row per service, each server named on its first row
+    pub async fn render(&self, color: bool) -> String {
+        const HEADER: [&str; 6] = ["SERVER", "STATE", "SERVICE", "LOCAL", "REMOTE", "STATUS"];
+        let servers = self.servers.lock().await;
+
+        // cells as (text, color), padded before coloring so escapes do not skew widths
+        let mut rows: Vec<[(String, Option<Color>); 6]> = Vec::new();
+        for server in servers.iter() {
+            let first = rows.len();
+            let status = server.status;
+            let server_cells = [
+                (server.addr.clone(), Some(Color::Blue)),
+                (status.label().to_string(), Some(status.color())),
+            ];
+            if server.services.is_empty() {
+                let none = (String::new(), None);
+                rows.push([
+                    server_cells[0].clone(),
+                    server_cells[1].clone(),
+                    ("-".to_string(), None),
+                    none.clone(),
+                    none.clone(),
+                    none,
+                ]);
+                continue;
+            }
+            for service in &server.services {
+                let (state, state_color) = service.status();
+                let [addr, status] = if rows.len() == first {
+                    server_cells.clone()
+                } else {
+                    [(String::new(), None), (String::new(), None)]
+                };
+                rows.push([
+                    addr,
+                    status,
+                    (service.name.clone(), None),
+                    (service.local.clone(), Some(Color::Magenta)),
+                    (service.remote(), Some(Color::Green)),
+                    (state, Some(state_color)),
+                ]);
+            }
+        }
+
+        let mut widths = HEADER.map(str::len);
+        for row in &rows {
+            for (width, (text, _)) in widths.iter_mut().zip(row) {
+                *width = (*width).max(text.chars().count());
+            }
+        }
+
+        let mut table = String::new();
+        let header = HEADER.map(|title| (title.to_string(), None));
+        for (index, row) in std::iter::once(&header).chain(&rows).enumerate() {
+            let mut line = String::new();
+            for (column, ((text, cell_color), width)) in row.iter().zip(widths).enumerate() {
+                // the last column is not padded, lines carry no trailing blanks
+                let cell = if column + 1 == row.len() {
+                    text.clone()
+                } else {
+                    format!("{:<width$}  ", text, width = width)
+                };
+                match (color, cell_color) {
+                    (false, _) => line.push_str(&cell),
+                    (true, _) if index == 0 => line.push_str(&cell.bold().to_string()),
+                    (true, Some(cell_color)) => line.push_str(&cell.color(*cell_color).to_string()),
+                    (true, None) => line.push_str(&cell),
+                }
+            }
+            let _ = writeln!(table, "{}", line.trim_end());
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(name: &str, local_port: u16, remote_port: u16) -> ServiceConfig {
+        ServiceConfig {
+            name: name.to_string(),
+            local_port,
+            remote_port,
+            ..ServiceConfig::parse_cli("127.0.0.1:1:1").unwrap()
+        }
+    }
+
+    fn entry(server: &str, services: Vec<ServiceConfig>) -> ConnectionConfig {
+        ConnectionConfig {
+            server: server.to_string(),
+            token: None,
+            services,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_table_follows_the_sessions() {
+        let state = ClientState::new(&[
+            entry(
+                "1.2.3.4:7000",
+                vec![service("web", 80, 8080), service("api", 3000, 0)],
+            ),
+            entry("backup.example.com:7000", vec![]),
+        ]);
+        let server = "1.2.3.4:7000";
+        state.server_status(server, ServerStatus::Connected).await;
+        assert!(state.has_pending(server).await);
+        let web = ServiceStatus::Registered(Some(8080));
+        assert!(!state.service_status(server, "web", web).await);
+        let api = ServiceStatus::Registered(Some(20001));
+        assert!(state.service_status(server, "api", api).await);
+        assert!(!state.has_pending(server).await);
+
+        assert_eq!(
+            state.render(false).await,
+            "\
+SERVER                   STATE       SERVICE  LOCAL           REMOTE         STATUS
+1.2.3.4:7000             connected   web      127.0.0.1:80    :8080          registered
+                                     api      127.0.0.1:3000  :20001 (auto)  registered
+backup.example.com:7000  connecting  -
+"
+        );
+
+        let stats = ClientStats {
+            active_connections: 1,
+            total_connections: 5,
+            bytes_forwarded: 300,
+        };
+        state.server_stats(server, stats).await;
+        let status = state.to_json(&HashMap::new()).await;
+        assert_eq!(status["servers"][0]["stats"]["active_connections"], 1);
+        assert_eq!(status["servers"][0]["stats"]["bytes_forwarded"], 300);
+        assert!(status["servers"][1]["stats"].is_null());
+
+        // a new session registers everything again and counts anew
+        state
+            .server_status(server, ServerStatus::Disconnected)
+            .await;
+        state.server_status(server, ServerStatus::Connected).await;
+        let status = state.to_json(&HashMap::new()).await;
+        assert!(status["servers"][0]["stats"].is_null());
+        let refused = ServiceStatus::Rejected("Port 8080 is in use".to_string());
+        assert!(!state.service_status(server, "web", refused).await);
+        let table = state.render(false).await;
+        assert!(table.contains("rejected: Port 8080 is in use"), "{}", table);
+        assert!(
+            table.contains("api      127.0.0.1:3000  auto "),
+            "{}",
+            table
+        );
+        assert!(!table.contains('\x1b'));
+    }
+}
@@ -0,0 +1,286 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{timeout, Duration};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use super::Client;
+use crate::config::{RelayConfig, VisitorConfig};
+use crate::logging::short_id;
+use crate::utils::crypto::secret_hash;
+use crate::utils::proxy::WriteCommand;
+use crate::utils::Message;
+use crate::{console_info, debug, log_info, warn};
+
+/// How long a visitor waits for the server to connect it to the service
+const VISIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A visitor connection the server opened: its ID and the channel its data arrives on,
+/// registered with the local connections before the outcome is passed on
+pub type VisitOutcome =
+    std::result::Result<(String, mpsc::UnboundedReceiver<WriteCommand>), String>;
+
+/// A service of another client that the connections on a local port reach
+#[derive(Debug, Clone)]
+pub enum VisitTarget {
+    /// A secret service, reached by presenting its secret
+    Secret(VisitorConfig),
+    /// A relay service, reached by the clients it names in its `relay_peers`
+    Relay(RelayConfig),
+}
+
+impl VisitTarget {
+    /// The request asking the server for a connection to the service
+    fn request(&self, request_id: u64) -> Message {
+        match self {
+            VisitTarget::Secret(visitor) => Message::VisitorConnect {
+                request_id,
+                service_name: visitor.service.clone(),
+                secret_hash: secret_hash(&visitor.service, &visitor.secret),
+            },
+            VisitTarget::Relay(relay) => Message::RelayConnect {
+                request_id,
+                target_client: relay.client.clone(),
+                target_service: relay.service.clone(),
+            },
+        }
+    }
+
+    /// Name the service was registered with
+    fn service(&self) -> &str {
+        match self {
+            VisitTarget::Secret(visitor) => &visitor.service,
+            VisitTarget::Relay(relay) => &relay.service,
+        }
+    }
+
+    /// Local address the connections are accepted on
+    fn bind_addr(&self) -> &str {
+        match self {
+            VisitTarget::Secret(visitor) => &visitor.bind_addr,
+            VisitTarget::Relay(relay) => &relay.bind_addr,
+        }
+    }
+
+    /// Server the service is registered on, the first configured server when None
+    pub fn server(&self) -> Option<&String> {
+        match self {
+            VisitTarget::Secret(visitor) => visitor.server.as_ref(),
+            VisitTarget::Relay(relay) => relay.server.as_ref(),
+        }
+    }
+}
+
+impl fmt::Display for VisitTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VisitTarget::Secret(visitor) => write!(f, "'{}'", visitor.service),
+            VisitTarget::Relay(relay) => {
+                write!(f, "'{}' of client {}", relay.service, relay.client)
+            }
+        }
+    }
+}
+
+/// `VisitorConnect` and `RelayConnect` requests sent on a server connection that still wait for a response
+#[derive(Default)]
+pub struct Visits {
+    /// Visitors waiting, by `request_id`
+    pending: HashMap<u64, oneshot::Sender<VisitOutcome>>,
+    /// `request_id` of the last request sent, IDs start at 1
+    last_request_id: u64,
+}
+
+impl Visits {
+    /// Creates the request for a connection to `target` and the receiver its outcome is
+    /// told on
+    pub fn start(&mut self, target: &VisitTarget) -> (Message, oneshot::Receiver<VisitOutcome>) {
+        self.last_request_id += 1;
+        let (tx, rx) = oneshot::channel();
+        self.pending.insert(self.last_request_id, tx);
+        (target.request(self.last_request_id), rx)
+    }
+
+    /// Takes the visitor waiting for the response to `request_id`, None if there is none
+    pub fn answered(&mut self, request_id: u64) -> Option<oneshot::Sender<VisitOutcome>> {
+        self.pending.remove(&request_id)
+    }
+}
+
+impl Client {
+    /// Binds the local port of a visitor, failing when it is taken
+    pub(super) async fn bind_visitor(target: &VisitTarget) -> Result<TcpListener> {
+        let listener = TcpListener::bind(target.bind_addr()).await.map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to bind visitor of {} on {}: {}",
+                target,
+                target.bind_addr(),
+                e
+            )
+        })?;
+        console_info!("Visitor of {} listening on {}", target, target.bind_addr());
+        Ok(listener)
+    }
+
+    /// Connects every connection accepted on a visitor's port to its service through
+    /// `server_addr`, until `shutdown` is cancelled
+    pub(super) async fn run_visitor(
+        &self,
+        listener: TcpListener,
+        visitor: VisitTarget,
+        server_addr: String,
+        shutdown: CancellationToken,
+    ) {
+        loop {
+            let (stream, peer) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Visitor of {} failed to accept: {}", visitor, e);
+                        continue;
+                    }
+                },
+                _ = shutdown.cancelled() => return,
+            };
+            debug!("Visitor of {} accepted {}", visitor, peer);
+            let client = self.clone();
+            let visitor = visitor.clone();
+            let server_addr = server_addr.clone();
+            tokio::spawn(async move { client.visit(stream, &visitor, &server_addr).await });
+        }
+    }
+
+    /// Asks the server for a connection to the visitor's service and forwards `stream`
+    /// on it like a connection to a local service
+    async fn visit(&self, stream: TcpStream, visitor: &VisitTarget, server_addr: &str) {
+        self.config.socket_options().apply(&stream, "visitor");
+        let outcome = {
+            let mut connections = self.connections.lock().await;
+            let Some(conn) = connections
+                .get_mut(server_addr)
+                .filter(|conn| conn.connected)
+            else {
+                warn!(
+                    "Visitor of {} dropped a connection, server {} is not connected",
+                    visitor, server_addr
+                );
+                return;
+            };
+            let (request, outcome) = conn.visits.start(visitor);
+            if conn.sender.send(request).is_err() {
+                return;
+            }
+            outcome
+        };
+
+        let (connection_id, local_rx) = match timeout(VISIT_TIMEOUT, outcome).await {
+            Ok(Ok(Ok(opened))) => opened,
+            Ok(Ok(Err(reason))) => {
+                warn!(
+                    "Server {} refused visitor of {}: {}",
+                    server_addr, visitor, reason
+                );
+                return;
+            }
+            Ok(Err(_)) => {
+                warn!(
+                    "Visitor of {} lost server {} while connecting",
+                    visitor, server_addr
+                );
+                return;
+            }
+            Err(_) => {
+                warn!(
+                    "Server {} did not connect visitor of {} within {:?}",
+                    server_addr, visitor, VISIT_TIMEOUT
+                );
+                return;
+            }
+        };
+
+        let span = tracing::info_span!(
+            "visitor_conn",
+            conn = short_id(&connection_id),
+            service = %visitor.service(),
+            server = %server_addr,
+        );
+        log_info!(
+            "Visitor of {} connected through {}: conn={}",
+            visitor,
+            server_addr,
+            connection_id
+        );
+        Self::handle_local_connection(
+            stream,
+            local_rx,
+            self.connections.clone(),
+            self.local_connections.clone(),
+            server_addr.to_string(),
+            connection_id,
+            self.config.idle_timeout,
+        )
+        .instrument(span)
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_visits_are_answered_once() {
+        let visitor = VisitTarget::Secret(VisitorConfig {
+            service: "ssh".to_string(),
+            secret: "shared".to_string(),
+            bind_addr: "127.0.0.1:2222".to_string(),
+            server: None,
+        });
+        let mut visits = Visits::default();
+        let (first, _first_rx) = visits.start(&visitor);
+        let (second, mut second_rx) = visits.start(&visitor);
+        let (
+            Message::VisitorConnect {
+                request_id: 1,
+                secret_hash: first_hash,
+                ..
+            },
+            Message::VisitorConnect {
+                request_id: 2,
+                secret_hash: second_hash,
+                ..
+            },
+        ) = (first, second)
+        else {
+            panic!("expected two numbered VisitorConnect requests");
+        };
+        assert_eq!(first_hash, secret_hash("ssh", "shared"));
+        assert_eq!(first_hash, second_hash);
+
+        let waiting = visits.answered(2).unwrap();
+        assert!(visits.answered(2).is_none());
+        assert!(visits.answered(3).is_none());
+        waiting.send(Err("refused".to_string())).unwrap();
+        assert_eq!(second_rx.try_recv().unwrap().unwrap_err(), "refused");
+
+        // relays count on with the same requests
+        let relay = VisitTarget::Relay(RelayConfig::parse_cli("laptop-b:ssh@2223").unwrap());
+        assert_eq!(relay.to_string(), "'ssh' of client laptop-b");
+        let (request, _rx) = visits.start(&relay);
+        let Message::RelayConnect {
+            request_id: 3,
+            target_client,
+            target_service,
+        } = request
+        else {
+            panic!("expected RelayConnect 3");
+        };
+        assert_eq!(
+            (target_client.as_str(), target_service.as_str()),
+            ("laptop-b", "ssh")
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use anyhow::{anyhow, Result};
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// Units of a size by their suffix, matched case-insensitively
+const UNITS: [(&str, u64); 9] = [
+    ("kib", 1 << 10),
+    ("mib", 1 << 20),
+    ("gib", 1 << 30),
+    ("kb", 1000),
+    ("mb", 1000 * 1000),
+    ("gb", 1000 * 1000 * 1000),
+    ("k", 1 << 10),
+    ("m", 1 << 20),
+    ("g", 1 << 30),
+];
+
+/// Size in bytes written with a unit: `512B`, `64KB`, `1MiB`. KB, MB and GB are powers
+/// of 1000, KiB, MiB and GiB, or just K, M and G, powers of 1024. A bare number is bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct HumanBytes(pub u64);
+
+impl HumanBytes {
+    /// The size as a `usize`, saturating on targets where it does not fit
+    pub fn as_usize(&self) -> usize {
+        usize::try_from(self.0).unwrap_or(usize::MAX)
+    }
+}
+
+impl FromStr for HumanBytes {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (value, unit) = s.split_at(split);
+        let value: u64 = value
+            .parse()
+            .map_err(|_| anyhow!("Invalid size '{}', expected e.g. 512B, 64KB or 1MiB", s))?;
+        let unit = unit.trim().to_ascii_lowercase();
+        let multiplier = match unit.as_str() {
+            "" | "b" => 1,
+            unit => UNITS
+                .iter()
+                .find(|(name, _)| *name == unit)
+                .map(|(_, size)| *size)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Invalid size unit '{}' in '{}', expected B, KB, MB, GB, KiB, MiB or GiB",
+                        unit,
+                        s
+                    )
+                })?,
+        };
+        value
+            .checked_mul(multiplier)
+            .map(HumanBytes)
+            .ok_or_else(|| anyhow!("Size '{}' is too large", s))
+    }
+}
+
+impl fmt::Display for HumanBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // largest unit the size is a whole number of, binary units first
+        let unit = [
+            (1 << 30, "GiB"),
+            (1 << 20, "MiB"),
+            (1 << 10, "KiB"),
+            (1000 * 1000 * 1000, "GB"),
+            (1000 * 1000, "MB"),
+            (1000, "KB"),
+        ]
+        .into_iter()
+        .find(|(size, _)| self.0 != 0 && self.0.is_multiple_of(*size));
+        match unit {
+            Some((size, unit)) => write!(f, "{}{}", self.0 / size, unit),
+            None => write!(f, "{}B", self.0),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for HumanBytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BytesVisitor;
+
+        impl Visitor<'_> for BytesVisitor {
+            type Value = HumanBytes;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a number of bytes or a size like \"64KB\" or \"1MiB\"")
+            }
+
+            fn visit_u64<E: de::Error>(self, bytes: u64) -> Result<HumanBytes, E> {
+                Ok(HumanBytes(bytes))
+            }
+
+            fn visit_i64<E: de::Error>(self, bytes: i64) -> Result<HumanBytes, E> {
+                u64::try_from(bytes)
+                    .map(HumanBytes)
+                    .map_err(|_| E::custom(format!("Invalid size {}, it is negative", bytes)))
+            }
+
+            fn visit_str<E: de::Error>(self, text: &str) -> Result<HumanBytes, E> {
+                text.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(BytesVisitor)
+    }
+}
+
+impl Serialize for HumanBytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_string().serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_size_forms() {
+        let parse = |s: &str| s.parse::<HumanBytes>().map(|size| size.0);
+        assert_eq!(parse("512").unwrap(), 512);
+        assert_eq!(parse("512B").unwrap(), 512);
+        assert_eq!(parse("64KB").unwrap(), 64_000);
+        assert_eq!(parse("64 KiB").unwrap(), 65_536);
+        assert_eq!(parse("1MiB").unwrap(), 1 << 20);
+        assert_eq!(parse("1mb").unwrap(), 1_000_000);
+        assert_eq!(parse("2G").unwrap(), 2 << 30);
+
+        let err = parse("64XB").unwrap_err().to_string();
+        assert!(err.contains("unit 'xb' in '64XB'"), "{}", err);
+        for invalid in ["", "KB", "1.5MB", "-1KB", "99999999999GiB"] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+
+        assert_eq!(HumanBytes(0).to_string(), "0B");
+        assert_eq!(HumanBytes(1500).to_string(), "1500B");
+        assert_eq!(HumanBytes(64_000).to_string(), "64KB");
+        assert_eq!(HumanBytes(3 << 20).to_string(), "3MiB");
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Wrapper {
+        size: HumanBytes,
+    }
+
+    #[test]
+    fn test_numbers_stay_bytes() {
+        let parsed: Wrapper = toml::from_str("size = 1024").unwrap();
+        assert_eq!(parsed.size, HumanBytes(1024));
+        let written = toml::to_string(&parsed).unwrap();
+        assert_eq!(written.trim(), r#"size = "1KiB""#);
+        let reread: Wrapper = toml::from_str(&written).unwrap();
+        assert_eq!(reread.size, parsed.size);
+
+        let err = toml::from_str::<Wrapper>("size = -1")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("negative"), "{}", err);
+        let err = toml::from_str::<Wrapper>("size = 1.5")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("1MiB"), "{}", err);
+    }
+}
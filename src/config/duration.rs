@@ -0,0 +1,197 @@
+use anyhow::{anyhow, Result};
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Duration written with unit suffixes: `500ms`, `30s`, `10m`, `2h`, `1d`, or several
+/// of them like `2m30s`. A bare number is taken as seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HumanDuration(pub Duration);
+
+impl HumanDuration {
+    /// The duration, or None when it is zero (disabled)
+    pub fn non_zero(&self) -> Option<Duration> {
+        (!self.0.is_zero()).then_some(self.0)
+    }
+}
+
+impl FromStr for HumanDuration {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(anyhow!("Invalid duration '', expected e.g. 30s or 2m30s"));
+        }
+        if let Ok(secs) = s.parse::<u64>() {
+            return Ok(HumanDuration(Duration::from_secs(secs)));
+        }
+
+        let mut total = Duration::ZERO;
+        let mut rest = s;
+        while !rest.is_empty() {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let units = rest[digits..]
+                .find(|c: char| c.is_ascii_digit())
+                .map_or(rest.len(), |end| digits + end);
+            let (value, unit) = (&rest[..digits], rest[digits..units].trim());
+            let value: u64 = value
+                .parse()
+                .map_err(|_| anyhow!("Invalid duration '{}', expected e.g. 30s or 2m30s", s))?;
+            let part = match unit {
+                "ms" => Duration::from_millis(value),
+                "s" => Duration::from_secs(value),
+                "m" => Duration::from_secs(value.saturating_mul(60)),
+                "h" => Duration::from_secs(value.saturating_mul(3600)),
+                "d" => Duration::from_secs(value.saturating_mul(86400)),
+                unit => {
+                    return Err(anyhow!(
+                        "Invalid duration unit '{}' in '{}', expected ms, s, m, h or d",
+                        unit,
+                        s
+                    ))
+                }
+            };
+            total = total.saturating_add(part);
+            rest = &rest[units..];
+        }
+        Ok(HumanDuration(total))
+    }
+}
+
+impl fmt::Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let millis = self.0.as_millis();
+        if millis == 0 {
+            return write!(f, "0s");
+        }
+        // largest unit the duration is a whole number of
+        let (size, unit) = [
+            (86_400_000, "d"),
+            (3_600_000, "h"),
+            (60_000, "m"),
+            (1000, "s"),
+        ]
+        .into_iter()
+        .find(|(size, _)| millis.is_multiple_of(*size))
+        .unwrap_or((1, "ms"));
+        write!(f, "{}{}", millis / size, unit)
+    }
+}
+
+impl<'de> Deserialize<'de> for HumanDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct DurationVisitor;
+
+        impl Visitor<'_> for DurationVisitor {
+            type Value = HumanDuration;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(
+                    f,
+                    "a number of seconds or a duration like \"30s\" or \"2m30s\""
+                )
+            }
+
+            fn visit_u64<E: de::Error>(self, secs: u64) -> Result<HumanDuration, E> {
+                Ok(HumanDuration(Duration::from_secs(secs)))
+            }
+
+            fn visit_i64<E: de::Error>(self, secs: i64) -> Result<HumanDuration, E> {
+                u64::try_from(secs)
+                    .map(|secs| HumanDuration(Duration::from_secs(secs)))
+                    .map_err(|_| E::custom(format!("Invalid duration {}, it is negative", secs)))
+            }
+
+            fn visit_f64<E: de::Error>(self, secs: f64) -> Result<HumanDuration, E> {
+                Err(E::custom(format!(
+                    "Invalid duration {}, write fractions with a smaller unit like \"1500ms\"",
+                    secs
+                )))
+            }
+
+            fn visit_str<E: de::Error>(self, text: &str) -> Result<HumanDuration, E> {
+                text.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(DurationVisitor)
+    }
+}
+
+impl Serialize for HumanDuration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_string().serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duration_forms() {
+        let parse = |s: &str| s.parse::<HumanDuration>().unwrap().0;
+        assert_eq!(parse("10m"), Duration::from_secs(600));
+        assert_eq!(parse("250ms"), Duration::from_millis(250));
+        assert_eq!(parse("45"), Duration::from_secs(45));
+        assert_eq!(parse("2h"), Duration::from_secs(7200));
+        assert!(parse("0").is_zero());
+        assert_eq!(HumanDuration::default().to_string(), "0s");
+        assert!("10 minutes".parse::<HumanDuration>().is_err());
+        assert!("m".parse::<HumanDuration>().is_err());
+
+        assert_eq!(HumanDuration(Duration::from_secs(600)).to_string(), "10m");
+        assert_eq!(
+            HumanDuration(Duration::from_millis(1500)).to_string(),
+            "1500ms"
+        );
+    }
+
+    #[test]
+    fn test_compound_durations() {
+        let parse = |s: &str| s.parse::<HumanDuration>().map(|d| d.0);
+        assert_eq!(parse("2m30s").unwrap(), Duration::from_secs(150));
+        assert_eq!(parse("1h 30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse("1s500ms").unwrap(), Duration::from_millis(1500));
+        assert_eq!(HumanDuration(parse("2m30s").unwrap()).to_string(), "150s");
+
+        let err = parse("2m30x").unwrap_err().to_string();
+        assert!(err.contains("unit 'x' in '2m30x'"), "{}", err);
+        for invalid in ["", "s30", "1.5s", "-5s"] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Wrapper {
+        interval: HumanDuration,
+    }
+
+    #[test]
+    fn test_numbers_stay_seconds() {
+        let parsed: Wrapper = toml::from_str("interval = 30").unwrap();
+        assert_eq!(parsed.interval.0, Duration::from_secs(30));
+        let parsed: Wrapper = toml::from_str(r#"interval = "2m30s""#).unwrap();
+        assert_eq!(parsed.interval.0, Duration::from_secs(150));
+        // written back in the human form, which reads back the same
+        let written = toml::to_string(&parsed).unwrap();
+        assert_eq!(written.trim(), r#"interval = "150s""#);
+        let reread: Wrapper = toml::from_str(&written).unwrap();
+        assert_eq!(reread.interval, parsed.interval);
+
+        for (invalid, reason) in [
+            ("interval = -5", "negative"),
+            ("interval = 1.5", "1500ms"),
+            ("interval = true", "2m30s"),
+            (r#"interval = "5 minutes""#, "'5 minutes'"),
+        ] {
+            let err = toml::from_str::<Wrapper>(invalid).unwrap_err().to_string();
+            assert!(err.contains(reason), "{}: {}", invalid, err);
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use toml::Value;
+
+use super::template::{Key, CLIENT_KEYS, SERVER_KEYS};
+use super::{ClientConfig, ServerConfig, ServiceConfig};
+
+/// Separator of list items, `\,` being a literal comma
+const LIST_SEPARATOR: char = ',';
+
+/// Separator of `SOWBACK_CLIENT_SERVICES` items, which hold commas themselves
+const SERVICE_SEPARATOR: char = ';';
+
+impl ServerConfig {
+    /// Overrides keys of the `[server]` table with `SOWBACK_SERVER_<KEY>` variables
+    /// looked up with `var`, returning the names of those that were set
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<Vec<String>> {
+        apply("server", self, SERVER_KEYS, var)
+    }
+}
+
+impl ClientConfig {
+    /// Overrides keys of the `[client]` table with `SOWBACK_CLIENT_<KEY>` variables
+    /// looked up with `var`, returning the names of those that were set
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<Vec<String>> {
+        let applied = apply("client", self, CLIENT_KEYS, var)?;
+        if !applied.is_empty() {
+            self.normalize_services()?;
+        }
+        Ok(applied)
+    }
+}
+
+/// Name of the variable overriding `key` of `section`, e.g. `SOWBACK_CLIENT_SERVERS`
+fn env_var(section: &str, key: &str) -> String {
+    format!(
+        "SOWBACK_{}_{}",
+        section.to_ascii_uppercase(),
+        key.to_ascii_uppercase()
+    )
+}
+
+/// Sets every key of `keys` that has a variable, each converted to the type the key
+/// holds, and checks the config still deserializes after each one
+fn apply<T: Serialize + DeserializeOwned>(
+    section: &str,
+    config: &mut T,
+    keys: &[Key],
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<String>> {
+    let Value::Table(mut table) = Value::try_from(&*config)? else {
+        return Err(anyhow!("{} config is not a table", section));
+    };
+    let mut applied = Vec::new();
+    for key in keys {
+        let name = env_var(section, key.name);
+        let Some(raw) = var(&name) else {
+            continue;
+        };
+        let value = match (section, key.name) {
+            ("client", "services") => services_value(&raw),
+            _ => env_value(&raw, shape(section, key, table.get(key.name)).as_ref()),
+        }
+        .map_err(|e| anyhow!("Invalid {}: {}", name, e))?;
+        table.insert(key.name.to_string(), value);
+        *config = Value::Table(table.clone())
+            .try_into()
+            .map_err(|e: toml::de::Error| anyhow!("Invalid {}: {}", name, e.message()))?;
+        applied.push(name);
+    }
+    Ok(applied)
+}
+
+/// A value of the type `key` holds: its current value, or its documented example
+/// when it is unset or an empty list
+fn shape(section: &str, key: &Key, current: Option<&Value>) -> Option<Value> {
+    let example = || {
+        let example = key.example?;
+        // a multi-line example is a whole table, a single line just the value
+        let document = if example.contains('\n') {
+            toml::from_str::<toml::Table>(example).ok()?
+        } else {
+            toml::from_str::<toml::Table>(&format!("{} = {}", key.name, example))
+                .ok()
+                .map(|value| toml::Table::from_iter([(section.to_string(), value.into())]))?
+        };
+        document.get(section)?.get(key.name).cloned()
+    };
+    match current {
+        Some(Value::Array(items)) if items.is_empty() => example().or(current.cloned()),
+        Some(value) => Some(value.clone()),
+        None => example(),
+    }
+}
+
+/// Converts `raw` to a value like `shape`: strings as they are, lists split on commas,
+/// tables and unknown types read as TOML
+fn env_value(raw: &str, shape: Option<&Value>) -> Result<Value> {
+    let raw_toml = || -> Result<Value> {
+        let document: toml::Table = toml::from_str(&format!("value = {}", raw))
+            .map_err(|_| anyhow!("'{}' is not a TOML value", raw))?;
+        document
+            .get("value")
+            .cloned()
+            .ok_or_else(|| anyhow!("'{}' is not a TOML value", raw))
+    };
+    match shape {
+        Some(Value::String(_)) => Ok(Value::String(raw.to_string())),
+        Some(Value::Integer(_)) => raw
+            .trim()
+            .parse()
+            .map(Value::Integer)
+            .map_err(|_| anyhow!("'{}' is not an integer", raw)),
+        Some(Value::Float(_)) => raw
+            .trim()
+            .parse()
+            .map(Value::Float)
+            .map_err(|_| anyhow!("'{}' is not a number", raw)),
+        Some(Value::Boolean(_)) => match raw.trim().to_ascii_lowercase().as_str() {
+            "true" | "1" | "yes" | "on" => Ok(Value::Boolean(true)),
+            "false" | "0" | "no" | "off" => Ok(Value::Boolean(false)),
+            _ => Err(anyhow!("'{}' is not true or false", raw)),
+        },
+        // lists of tables have commas inside their items, they are written as TOML
+        Some(Value::Array(items)) if items.first().is_some_and(Value::is_table) => raw_toml(),
+        Some(Value::Array(items)) => {
+            let item_shape = items
+                .first()
+                .cloned()
+                .unwrap_or_else(|| Value::String(String::new()));
+            split_list(raw, LIST_SEPARATOR)?
+                .iter()
+                .map(|item| env_value(item, Some(&item_shape)))
+                .collect::<Result<_>>()
+                .map(Value::Array)
+        }
+        Some(_) => raw_toml(),
+        None => raw_toml().or_else(|_| Ok(Value::String(raw.to_string()))),
+    }
+}
+
+/// `SOWBACK_CLIENT_SERVICES`: service strings as given to `--service`, split on semicolons
+fn services_value(raw: &str) -> Result<Value> {
+    let mut services = Vec::new();
+    for service in split_list(raw, SERVICE_SEPARATOR)? {
+        for expanded in ServiceConfig::parse_cli_ranges(&service)? {
+            services.push(Value::try_from(expanded)?);
+        }
+    }
+    Ok(Value::Array(services))
+}
+
+/// Splits `raw` on `separator` into trimmed, non-empty items. A backslash makes the
+/// next character literal, so `\,` is a comma inside an item and `\\` a backslash
+fn split_list(raw: &str, separator: char) -> Result<Vec<String>> {
+    let mut items = Vec::new();
+    let mut item = String::new();
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped) => item.push(escaped),
+                None => return Err(anyhow!("'{}' ends in an unfinished escape", raw)),
+            },
+            c if c == separator => items.push(std::mem::take(&mut item)),
+            c => item.push(c),
+        }
+    }
+    items.push(item);
+    Ok(items
+        .into_iter()
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn vars(pairs: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_split_list() {
+        assert_eq!(
+            split_list("a:7000, b:7000", ',').unwrap(),
+            ["a:7000", "b:7000"]
+        );
+        assert_eq!(split_list("a\\,b,c\\\\", ',').unwrap(), ["a,b", "c\\"]);
+        assert_eq!(split_list(" , a,, ", ',').unwrap(), ["a"]);
+        assert!(split_list("", ',').unwrap().is_empty());
+        assert!(split_list("a\\", ',').is_err());
+    }
+
+    #[test]
+    fn test_server_env() {
+        let mut config = ServerConfig::default();
+        let applied = config
+            .apply_env(vars(&[
+                ("SOWBACK_SERVER_LISTEN_ADDR", "0.0.0.0:7100"),
+                ("SOWBACK_SERVER_TOKEN", "12345678"),
+                ("SOWBACK_SERVER_MAX_CLIENTS", "7"),
+                ("SOWBACK_SERVER_HTTP_PORT", "8080"),
+                ("SOWBACK_SERVER_NAME", "1234"),
+                ("SOWBACK_SERVER_ALLOWED_PORTS", "8000-8999, 10443"),
+                (
+                    "SOWBACK_SERVER_TLS",
+                    r#"{ cert = "/c.pem", key = "/k.pem" }"#,
+                ),
+                ("SOWBACK_CLIENT_TOKEN", "not-for-the-server"),
+            ]))
+            .unwrap();
+        assert_eq!(applied.len(), 7);
+        assert_eq!(config.listen_addr, "0.0.0.0:7100");
+        // digits stay a string where the key holds one
+        assert_eq!(config.token, "12345678");
+        assert_eq!(config.name.as_deref(), Some("1234"));
+        assert_eq!(config.max_clients, 7);
+        assert_eq!(config.http_port, Some(8080));
+        assert!(config.allowed_ports.unwrap().contains(10443));
+        assert_eq!(config.tls.unwrap().cert, "/c.pem");
+
+        let err = ServerConfig::default()
+            .apply_env(vars(&[("SOWBACK_SERVER_MAX_CLIENTS", "many")]))
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Invalid SOWBACK_SERVER_MAX_CLIENTS: 'many'"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_client_env() {
+        let mut config = ClientConfig::default();
+        let applied = config
+            .apply_env(vars(&[
+                (
+                    "SOWBACK_CLIENT_SERVERS",
+                    "1.2.3.4:7000,backup.example.com:7000",
+                ),
+                (
+                    "SOWBACK_CLIENT_SERVICES",
+                    "127.0.0.1:80:8080; 127.0.0.1:22:2222@10.0.0.5",
+                ),
+                ("SOWBACK_CLIENT_RECONNECT_INTERVAL", "2m30s"),
+                ("SOWBACK_CLIENT_TCP_NODELAY", "off"),
+            ]))
+            .unwrap();
+        assert_eq!(applied.len(), 4);
+        assert_eq!(config.servers, ["1.2.3.4:7000", "backup.example.com:7000"]);
+        assert_eq!(config.services.len(), 2);
+        assert_eq!(config.services[1].remote_port, 2222);
+        assert_eq!(config.services[1].bind_host.as_deref(), Some("10.0.0.5"));
+        assert_eq!(config.reconnect_interval.0, Duration::from_secs(150));
+        assert!(!config.tcp_nodelay);
+
+        let err = ClientConfig::default()
+            .apply_env(vars(&[("SOWBACK_CLIENT_RECONNECT_INTERVAL", "soon")]))
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Invalid SOWBACK_CLIENT_RECONNECT_INTERVAL:"),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,485 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::de::{DeArray, DeTable, DeValue, Deserializer};
+
+use super::ports::{pair_ranges, PortRange};
+use super::Config;
+
+/// Largest edit distance at which a known key is suggested for an unknown one
+const MAX_SUGGESTION_DISTANCE: usize = 3;
+
+impl Config {
+    /// Loads configuration from a TOML file, unknown keys being an error
+    /// unless the file sets `strict = false`
+    pub fn from_file(path: &str) -> Result<Self> {
+        Self::load(path, true)
+    }
+
+    /// Loads configuration from a TOML file. With `strict` false, as with
+    /// `strict = false` in the file, unknown keys are dropped into `ignored_keys`
+    pub fn load(path: &str, strict: bool) -> Result<Self> {
+        let content =
+            fs::read_to_string(path).map_err(|e| anyhow!("Failed to read {}: {}", path, e))?;
+        let mut config = parse(&content, strict)
+            .map_err(|err| anyhow!("Invalid config {}: {}", path, describe(&content, &err)))?;
+        if let Some(client) = &mut config.client {
+            client.normalize_services()?;
+        }
+        Ok(config)
+    }
+}
+
+/// Config files looked for when none is given, in order: `./sowback.toml`,
+/// `$XDG_CONFIG_HOME/sowback/config.toml`, `~/.config` standing in for an unset
+/// `XDG_CONFIG_HOME`, then `/etc/sowback/config.toml`
+pub fn config_candidates() -> Vec<String> {
+    candidates_in(
+        Path::new("."),
+        env::var_os("XDG_CONFIG_HOME").map(PathBuf::from),
+        env::var_os("HOME").map(PathBuf::from),
+    )
+    .iter()
+    .map(|path| path.to_string_lossy().into_owned())
+    .collect()
+}
+
+/// The first of `config_candidates` that exists
+pub fn discover_config() -> Option<String> {
+    config_candidates()
+        .into_iter()
+        .find(|path| Path::new(path).is_file())
+}
+
+/// `config_candidates` with the working directory and the XDG and home directories given
+fn candidates_in(
+    cwd: &Path,
+    xdg_config_home: Option<PathBuf>,
+    home: Option<PathBuf>,
+) -> Vec<PathBuf> {
+    let mut candidates = vec![cwd.join("sowback.toml")];
+    // the XDG spec ignores relative values
+    let config_home = xdg_config_home
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| home.map(|home| home.join(".config")));
+    if let Some(dir) = config_home {
+        candidates.push(dir.join("sowback").join("config.toml"));
+    }
+    candidates.push(PathBuf::from("/etc/sowback/config.toml"));
+    candidates
+}
+
+/// Deserializes `content`, dropping unknown keys one by one when not strict
+fn parse(content: &str, strict: bool) -> Result<Config, toml::de::Error> {
+    let mut document = DeTable::parse(content)?;
+    expand_port_ranges(document.get_mut()).map_err(serde::de::Error::custom)?;
+    let strict = strict
+        && document
+            .get_ref()
+            .get("strict")
+            .and_then(|value| value.get_ref().as_bool())
+            != Some(false);
+    let mut ignored_keys = Vec::new();
+    loop {
+        let err = match Config::deserialize(Deserializer::from(document.clone())) {
+            Ok(mut config) => {
+                config.ignored_keys = ignored_keys;
+                return Ok(config);
+            }
+            Err(err) => err,
+        };
+        let removed = match err.span() {
+            Some(span) if !strict && unknown_key(&err).is_some() => {
+                remove_key(document.get_mut(), span.start, "")
+            }
+            _ => None,
+        };
+        match removed {
+            Some(key) => ignored_keys.push(key),
+            None => return Err(err),
+        }
+    }
+}
+
+/// Expands each client service whose `local_port` or `remote_port` is a range like
+/// `"8000-8010"` into a service per port, before the services are deserialized.
+/// A `name` gets the local port appended, `web-8003`
+fn expand_port_ranges(document: &mut DeTable<'_>) -> Result<()> {
+    let Some(DeValue::Table(client)) = document.get_mut("client").map(|value| value.get_mut())
+    else {
+        return Ok(());
+    };
+    if let Some(services) = client.get_mut("services") {
+        expand_services(services.get_mut(), "client.services")?;
+    }
+    if let Some(DeValue::Array(connections)) =
+        client.get_mut("connections").map(|value| value.get_mut())
+    {
+        for (index, connection) in connections.iter_mut().enumerate() {
+            let DeValue::Table(connection) = connection.get_mut() else {
+                continue;
+            };
+            if let Some(services) = connection.get_mut("services") {
+                let path = format!("client.connections[{}].services", index);
+                expand_services(services.get_mut(), &path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Expands the ranged services of the list at `path`, see `expand_port_ranges`
+fn expand_services(services: &mut DeValue<'_>, path: &str) -> Result<()> {
+    let DeValue::Array(items) = services else {
+        return Ok(());
+    };
+    let mut expanded = DeArray::new();
+    for (index, item) in items.iter().enumerate() {
+        let DeValue::Table(service) = item.get_ref() else {
+            expanded.push(item.clone());
+            continue;
+        };
+        let port = |key: &str| service.get(key).map(|value| value.get_ref());
+        let is_range =
+            |key: &str| matches!(port(key), Some(DeValue::String(text)) if text.contains('-'));
+        // services without a range are left for deserializing to check
+        if !is_range("local_port") && !is_range("remote_port") {
+            expanded.push(item.clone());
+            continue;
+        }
+        let range = |key: &str| -> Result<PortRange> {
+            let text = match port(key) {
+                Some(DeValue::String(text)) => text.to_string(),
+                Some(DeValue::Integer(port)) => port.to_string(),
+                Some(_) => return Err(anyhow!("{} must be a port or a range of ports", key)),
+                None => "0".to_string(),
+            };
+            text.parse()
+        };
+        let pairs = range("local_port")
+            .and_then(|local| pair_ranges(local, range("remote_port")?))
+            .map_err(|e| anyhow!("{}[{}]: {}", path, index, e))?;
+        for (local_port, remote_port) in pairs {
+            let mut copy = service.clone();
+            for (key, port) in [("local_port", local_port), ("remote_port", remote_port)] {
+                if let Some(value) = copy.get_mut(key) {
+                    *value.get_mut() = DeValue::String(port.to_string().into());
+                }
+            }
+            if let Some(DeValue::String(name)) = copy.get_mut("name").map(|value| value.get_mut()) {
+                *name = format!("{}-{}", name, local_port).into();
+            }
+            expanded.push(toml::Spanned::new(item.span(), DeValue::Table(copy)));
+        }
+    }
+    *items = expanded;
+    Ok(())
+}
+
+/// Removes the key starting at byte `offset` from `table` or a table nested in it,
+/// returning its full path below `prefix`
+fn remove_key(table: &mut DeTable<'_>, offset: usize, prefix: &str) -> Option<String> {
+    let join = |key: &str| match prefix {
+        "" => key.to_string(),
+        prefix => format!("{}.{}", prefix, key),
+    };
+    if let Some(key) = table.keys().find(|key| key.span().start == offset).cloned() {
+        table.remove(&key);
+        return Some(join(key.get_ref()));
+    }
+    for (key, value) in table.iter_mut() {
+        let path = join(key.get_ref());
+        let found = match value.get_mut() {
+            DeValue::Table(nested) => remove_key(nested, offset, &path),
+            DeValue::Array(items) => {
+                items
+                    .iter_mut()
+                    .enumerate()
+                    .find_map(|(index, item)| match item.get_mut() {
+                        DeValue::Table(nested) => {
+                            remove_key(nested, offset, &format!("{}[{}]", path, index))
+                        }
+                        _ => None,
+                    })
+            }
+            _ => None,
+        };
+        if found.is_some() {
+            return found;
+        }
+    }
+    None
+}
+
+/// The unknown key and the keys serde expected instead, for an unknown field error
+fn unknown_key(err: &toml::de::Error) -> Option<(&str, Vec<&str>)> {
+    let message = err.message();
+    if !message.starts_with("unknown field `") {
+        return None;
+    }
+    // names are quoted in backticks: the unknown one, then every expected one
+    let mut names = message.split('`').skip(1).step_by(2);
+    Some((names.next()?, names.collect()))
+}
+
+/// A message for `err` naming the key it is about and the section it is in
+fn describe(content: &str, err: &toml::de::Error) -> String {
+    let Some(location) = err.span().and_then(|span| locate_key(content, span.start)) else {
+        return err.to_string();
+    };
+    let (section, key) = location;
+    if let Some((unknown, expected)) = unknown_key(err) {
+        let section = section.map_or("at the top level".to_string(), |header| {
+            format!("in [{}]", header)
+        });
+        let suggestion = closest(unknown, &expected)
+            .map(|known| format!("; did you mean `{}`?", known))
+            .unwrap_or_default();
+        return format!("unknown key `{}` {}{}", unknown, section, suggestion);
+    }
+    let key = match section {
+        Some(header) => format!("{}.{}", header.trim_matches(['[', ']']), key),
+        None => key.to_string(),
+    };
+    format!("invalid value for {}: {}", key, err.message())
+}
+
+/// The table header in force and the key assigned on the line holding byte
+/// `offset`, when that line is a `key = value` line
+fn locate_key(content: &str, offset: usize) -> Option<(Option<&str>, &str)> {
+    let line_start = content[..offset.min(content.len())]
+        .rfind('\n')
+        .map_or(0, |end| end + 1);
+    let line = content[line_start..].lines().next()?;
+    let (key, _) = line.split_once('=')?;
+    let key = key.trim().trim_matches('"');
+    if key.is_empty() || key.starts_with(['#', '[']) {
+        return None;
+    }
+    // the header as written without its brackets, `[[client.services]]` keeping the inner pair
+    let section = content[..line_start]
+        .lines()
+        .rev()
+        .map(str::trim)
+        .find(|line| line.starts_with('['))
+        .and_then(|header| {
+            let inner = header.strip_prefix('[')?;
+            let end = if inner.starts_with('[') {
+                inner.find("]]")? + 1
+            } else {
+                inner.find(']')?
+            };
+            Some(inner[..end].trim())
+        });
+    Some((section, key))
+}
+
+/// The known key closest to `unknown` by edit distance, if any is close enough
+fn closest<'a>(unknown: &str, known: &[&'a str]) -> Option<&'a str> {
+    known
+        .iter()
+        .map(|key| (edit_distance(unknown, key), *key))
+        .filter(|(distance, _)| *distance <= MAX_SUGGESTION_DISTANCE)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, key)| key)
+}
+
+/// Levenshtein distance between two strings, counted in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(content: &str, strict: bool) -> Result<Config> {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), content).unwrap();
+        Config::load(file.path().to_str().unwrap(), strict)
+    }
+
+    const CLIENT: &str = r#"
+        [client]
+        servers = ["1.2.3.4:7000"]
+        token = "test-token"
+        reconnect_interval = 5
+        heartbeat_interval = 30
+    "#;
+
+    const SERVER: &str = r#"
+        [server]
+        listen_addr = "0.0.0.0:7000"
+        bind_host = "0.0.0.0"
+        token = "test-token"
+        max_clients = 10
+    "#;
+
+    #[test]
+    fn test_unknown_keys_are_named_with_a_suggestion() {
+        let typo = CLIENT.replace("heartbeat_interval", "hearbeat_interval");
+        let err = load(&typo, true).unwrap_err().to_string();
+        assert!(
+            err.ends_with(
+                "unknown key `hearbeat_interval` in [client]; did you mean `heartbeat_interval`?"
+            ),
+            "{}",
+            err
+        );
+
+        let typo = format!("{}    bind_hots = \"127.0.0.1\"\n", SERVER);
+        let err = load(&typo, true).unwrap_err().to_string();
+        assert!(
+            err.ends_with("unknown key `bind_hots` in [server]; did you mean `bind_host`?"),
+            "{}",
+            err
+        );
+
+        // nothing close enough is suggested
+        let unknown = format!(
+            "{}\n[[client.services]]\nlocal_port = 80\nflavour = 1\n",
+            CLIENT
+        );
+        let err = load(&unknown, true).unwrap_err().to_string();
+        assert!(
+            err.ends_with("unknown key `flavour` in [[client.services]]"),
+            "{}",
+            err
+        );
+
+        let err = load(&format!("stric = false\n{}", SERVER), true)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.ends_with("unknown key `stric` at the top level; did you mean `strict`?"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_non_strict_configs_drop_unknown_keys() {
+        let content = format!(
+            "strict = false\n{}    future_option = [\n        1,\n    ]\n\n[[client.services]]\nlocal_port = 80\nremote_port = 8080\nflavour = \"x\"\n",
+            CLIENT
+        );
+        let config = load(&content, true).unwrap();
+        assert_eq!(
+            config.ignored_keys,
+            ["client.future_option", "client.services[0].flavour"]
+        );
+        assert!(config
+            .validate()
+            .iter()
+            .any(|issue| issue.path == "client.future_option" && !issue.is_error()));
+        assert_eq!(config.client.unwrap().services[0].remote_port, 8080);
+
+        // the flag relaxes a config that does not ask for it
+        let typo = format!("{}    http_prot = 80\n", SERVER);
+        assert!(load(&typo, true).is_err());
+        let config = load(&typo, false).unwrap();
+        assert_eq!(config.ignored_keys, ["server.http_prot"]);
+        assert!(config.server.is_some());
+
+        // other errors stay errors
+        let invalid = CLIENT.replace("= 30", "= \"30x\"");
+        let err = load(&invalid, false).unwrap_err().to_string();
+        assert!(
+            err.contains("invalid value for client.heartbeat_interval"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_port_ranges_expand_into_services() {
+        let content = format!(
+            "{}\n[[client.services]]\nname = \"media\"\nlocal_port = \"8000-8002\"\nremote_port = \"9000-9002\"\n\n[[client.services]]\nlocal_port = 80\nremote_port = 8080\n",
+            CLIENT
+        );
+        let config = load(&content, true).unwrap();
+        let ports: Vec<_> = config
+            .client
+            .unwrap()
+            .services
+            .iter()
+            .map(|service| {
+                (
+                    service.name.clone(),
+                    service.local_port,
+                    service.remote_port,
+                )
+            })
+            .collect();
+        assert_eq!(
+            ports,
+            [
+                ("media-8000".to_string(), 8000, 9000),
+                ("media-8001".to_string(), 8001, 9001),
+                ("media-8002".to_string(), 8002, 9002),
+                ("127.0.0.1:80:8080".to_string(), 80, 8080),
+            ]
+        );
+
+        let mismatched = content.replace("9000-9002", "9000-9001");
+        let err = load(&mismatched, true).unwrap_err().to_string();
+        assert!(err.contains("client.services[0]"), "{}", err);
+        assert!(err.contains("differ in length"), "{}", err);
+
+        // ranges only make sense where a service is
+        let server = format!("{}    http_port = \"80-81\"\n", SERVER);
+        assert!(load(&server, true).is_err());
+    }
+
+    #[test]
+    fn test_discovery_precedence() {
+        let dir = tempfile::tempdir().unwrap();
+        let cwd = dir.path().join("work");
+        let xdg = dir.path().join("xdg");
+        let home = dir.path().join("home");
+        let candidates = candidates_in(&cwd, Some(xdg.clone()), Some(home.clone()));
+        assert_eq!(
+            candidates,
+            [
+                cwd.join("sowback.toml"),
+                xdg.join("sowback/config.toml"),
+                PathBuf::from("/etc/sowback/config.toml"),
+            ]
+        );
+        // without XDG_CONFIG_HOME, or with a relative one, ~/.config stands in
+        for xdg in [None, Some(PathBuf::from("relative"))] {
+            let candidates = candidates_in(&cwd, xdg, Some(home.clone()));
+            assert_eq!(candidates[1], home.join(".config/sowback/config.toml"));
+        }
+        assert_eq!(candidates_in(&cwd, None, None).len(), 2);
+
+        let first = || candidates.iter().find(|path| path.is_file()).cloned();
+        fs::create_dir_all(xdg.join("sowback")).unwrap();
+        fs::write(&candidates[1], "").unwrap();
+        assert_eq!(first(), Some(candidates[1].clone()));
+        fs::create_dir_all(&cwd).unwrap();
+        fs::write(&candidates[0], "").unwrap();
+        assert_eq!(first(), Some(candidates[0].clone()));
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("hearbeat", "heartbeat"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(closest("tokn", &["token", "tokens", "name"]), Some("token"));
+        assert_eq!(closest("flavour", &["token", "name"]), None);
+    }
+}
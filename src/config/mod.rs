@@ -1,12 +1,48 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::fs;
+use std::collections::HashMap;
+
+use crate::logging::syslog::SyslogAddr;
+use crate::logging::{redact, LogLevel, LogTarget};
+use crate::utils::compression::{Compression, DEFAULT_COMPRESSION_THRESHOLD};
+use crate::utils::net::join_host_port;
+use crate::utils::proxy_protocol::ProxyProtocol;
+use crate::utils::SocketOptions;
+
+mod bytes;
+mod duration;
+mod env;
+mod load;
+mod ports;
+mod template;
+mod token;
+mod validate;
+mod version;
+
+pub use bytes::HumanBytes;
+pub use duration::HumanDuration;
+pub use load::{config_candidates, discover_config};
+pub use ports::{PortRange, PortSet};
+pub use template::{client_template, generate_token, server_template};
+pub use token::{read_token_file, resolve_token, TOKEN_ENV};
+pub use validate::ConfigIssue;
+pub use version::Version;
 
 /// Main configuration structure that can contain either server or client configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    /// Whether unknown keys are an error, `strict = false` ignores them
+    /// for configs written for newer versions
+    #[serde(default = "default_strict")]
+    pub strict: bool,
+    /// The `[server]` table, used by `sowback listen`
     pub server: Option<ServerConfig>,
+    /// The `[client]` table, used by `sowback connect`
     pub client: Option<ClientConfig>,
+    /// Unknown keys dropped while loading a non-strict config, as full paths
+    #[serde(skip)]
+    pub ignored_keys: Vec<String>,
 }
 
 /// Configuration for server mode operation
@@ -14,8 +50,9 @@ pub struct Config {
 /// sowback listen
 /// ```
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ServerConfig {
-    // Specify a server name for human to identify (not unique)
+    /// Specify a server name for human to identify (not unique)
     pub name: Option<String>,
     /// Port or address
     pub listen_addr: String,
@@ -23,10 +60,270 @@ pub struct ServerConfig {
     pub bind_host: String,
     /// For authentication and cryptography
     pub token: String,
+    /// Additional named tokens, each optionally restricted to a set of ports
+    #[serde(default)]
+    pub tokens: Vec<TokenConfig>,
     /// Maximum number of clients
     pub max_clients: usize,
+    /// Connections the server holds open at once across its control, proxy and shared
+    /// ports; more are closed right after being accepted. 0 is unlimited
+    #[serde(default)]
+    pub max_connections: usize,
+    /// Runtime worker threads, overridden by `--workers`: 1 runs everything on a single
+    /// thread, 0 starts one per core
+    #[serde(default)]
+    pub workers: usize,
+    /// Set SO_REUSEPORT on the control, proxy and shared port listeners, so a second server
+    /// process can bind the same ports during an upgrade. Linux and the BSDs only
+    #[serde(default)]
+    pub reuseport: bool,
     /// Log file path
     pub log_file: Option<String>,
+    /// Maximum log level, overridden by `--log-level`
+    pub log_level: Option<LogLevel>,
+    /// Per-target log levels, e.g. `"sowback::server=debug,sowback::utils=warn"`
+    pub log_filter: Option<String>,
+    /// Where logs go besides the console: the log file, or syslog too
+    #[serde(default)]
+    pub log_target: LogTarget,
+    /// Syslog daemon of `log_target = "syslog"`: `journald`, a socket path,
+    /// `udp://host:port` or `tcp://host:port`; journald under systemd, else `/dev/log`
+    pub syslog_addr: Option<String>,
+    /// JSON lines log of proxy connections, reopened on SIGHUP or SIGUSR1
+    pub access_log: Option<String>,
+    /// Preferred compression codec for `Data` payloads, used if the client supports it
+    #[serde(default)]
+    pub compression: Compression,
+    /// Minimum payload size before compression is attempted
+    #[serde(default = "default_compression_threshold")]
+    pub compression_threshold: HumanBytes,
+    /// Serve the control port over TLS
+    pub tls: Option<ServerTlsConfig>,
+    /// UDP address clients with `transport = "quic"` connect to, presenting the certificate
+    /// of `tls`. QUIC is disabled when absent, it needs a build with the `quic` feature
+    pub quic_listen_addr: Option<String>,
+    /// Address clients with `transport = "websocket"` connect to, served over TLS like the
+    /// control port when `tls` is set. WebSocket is disabled when absent
+    pub websocket_listen_addr: Option<String>,
+    /// Ports to pick from when a client asks for `remote_port = 0`, any free port when absent
+    pub port_range: Option<PortRange>,
+    /// Remote ports clients may claim, e.g. `["8000-8999", "10443"]`; any port when absent
+    pub allowed_ports: Option<PortSet>,
+    /// Hosts services may bind instead of `bind_host`
+    #[serde(default)]
+    pub allowed_bind_hosts: Vec<String>,
+    /// Port shared by `http` services, routed by the request's Host header.
+    /// HTTP routing is disabled when absent
+    pub http_port: Option<u16>,
+    /// Port shared by TLS services, routed by the ClientHello's SNI name without
+    /// terminating TLS. SNI routing is disabled when absent
+    pub sni_port: Option<u16>,
+    /// Minutes between per-proxy traffic summaries, 0 disables them
+    #[serde(default)]
+    pub stats_interval: u64,
+    /// Proxy connections with no traffic in either direction for this long are closed, 0 disables
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: HumanDuration,
+    /// Data from the client queued for a proxy connection's peer and not written yet, past
+    /// which the peer is taken as a slow consumer and the connection closed. 0 is unlimited
+    #[serde(default = "default_max_buffered_per_connection")]
+    pub max_buffered_per_connection: HumanBytes,
+    /// New proxy connections each peer IP may open per minute, 0 is unlimited.
+    /// Connections over the limit are closed before the client is notified
+    #[serde(default)]
+    pub max_connections_per_ip_per_minute: u32,
+    /// How long a proxy connection waits for the client to reach its local service,
+    /// 0 waits indefinitely
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: HumanDuration,
+    /// Disable Nagle's algorithm on control and proxy sockets
+    #[serde(default = "default_tcp_nodelay")]
+    pub tcp_nodelay: bool,
+    /// Idle time before TCP keepalive probes are sent, 0 disables keepalive
+    #[serde(default = "default_tcp_keepalive")]
+    pub tcp_keepalive: HumanDuration,
+    /// How long a write to a client's control connection may stall before the client is
+    /// taken as gone, also its TCP user timeout. 0 waits as long as the OS does
+    #[serde(default = "default_control_write_timeout")]
+    pub control_write_timeout: HumanDuration,
+    /// What to do when a client authenticates with a client ID that is already connected
+    #[serde(default)]
+    pub duplicate_client_policy: DuplicateClientPolicy,
+    /// Failed authentications from one IP within `auth_fail_window` before it is banned,
+    /// 0 never bans
+    #[serde(default = "default_auth_fail_limit")]
+    pub auth_fail_limit: usize,
+    /// Window authentication failures are counted in
+    #[serde(default = "default_auth_fail_window")]
+    pub auth_fail_window: HumanDuration,
+    /// How long a banned IP's connections are dropped before reading the auth message
+    #[serde(default = "default_auth_ban_duration")]
+    pub auth_ban_duration: HumanDuration,
+    /// How long the listeners of a disconnected client stay bound, handling connections as
+    /// `orphan_connection_policy` says, for it to reconnect and reclaim them. Its established
+    /// connections are kept as long for the next session to resume. 0 closes them at once
+    #[serde(default = "default_listener_grace_period")]
+    pub listener_grace_period: HumanDuration,
+    /// What happens to connections accepted while no session of the client serves its listener
+    #[serde(default)]
+    pub orphan_connection_policy: OrphanConnectionPolicy,
+    /// Connections each listener holds for its client to return, with the `queue` policy
+    #[serde(default = "default_orphan_queue_size")]
+    pub orphan_queue_size: usize,
+    /// How long a held connection waits for its client to return before it is refused
+    #[serde(default = "default_orphan_queue_timeout")]
+    pub orphan_queue_timeout: HumanDuration,
+    /// How long a server that handed its listeners to a new process on SIGUSR2 keeps
+    /// serving the connections it has before closing them, see `--upgrade-socket`
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: HumanDuration,
+    /// Give each session a client ID picked by the server instead of the one the client
+    /// sent, so clients cannot choose or collide with each other's IDs
+    #[serde(default)]
+    pub assign_client_ids: bool,
+    /// Name the client holding a port, by short ID and name, when another client's
+    /// registration is rejected for it
+    #[serde(default)]
+    pub reveal_port_owner: bool,
+    /// Let clients register relay services, reached by the clients named in their
+    /// `relay_peers` without a listener on the server
+    #[serde(default)]
+    pub allow_relay: bool,
+    /// Oldest sowback version clients may run, e.g. `"0.2.0"`; clients reporting an older
+    /// one, one that does not parse, or none are rejected. Any version is accepted when absent
+    pub min_client_version: Option<Version>,
+    /// Let clients asking for them carry each proxied connection on a connection of its
+    /// own to the control port, instead of in `Data` messages on the control connection
+    #[serde(default = "default_data_channels")]
+    pub data_channels: bool,
+    /// Where client and proxy events are POSTed, disabled when absent
+    pub webhooks: Option<WebhookConfig>,
+    /// HTTP API for operators to kick clients, close connections and ban peers,
+    /// disabled when absent
+    pub admin: Option<AdminConfig>,
+}
+
+/// Handling of a client connecting with the ID of a session the server still holds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateClientPolicy {
+    /// Refuse the new session
+    #[default]
+    Reject,
+    /// Close the old session and admit the new one, e.g. when the old one died silently
+    Replace,
+}
+
+/// Handling of connections accepted on a listener while its client is reconnecting
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OrphanConnectionPolicy {
+    /// Close them at once
+    #[default]
+    Refuse,
+    /// Hold up to `orphan_queue_size` of them for `orphan_queue_timeout`, passing them on
+    /// if the client returns and refusing them otherwise
+    Queue,
+}
+
+/// Where the server reports events, as JSON POSTed to an HTTP endpoint
+/// ```toml
+/// [server.webhooks]
+/// url = "https://hooks.example.com/sowback"
+/// events = ["client_disconnected", "listener_bind_failed"]
+/// secret = "shared-with-the-receiver"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// `http://` or `https://` URL the events are POSTed to
+    pub url: String,
+    /// Events to send, every event when absent
+    pub events: Option<Vec<WebhookEvent>>,
+    /// Key of the HMAC-SHA256 signature sent in the `X-Sowback-Signature` header
+    pub secret: Option<String>,
+}
+
+impl WebhookConfig {
+    /// Whether `event` is sent
+    pub fn sends(&self, event: WebhookEvent) -> bool {
+        self.events
+            .as_ref()
+            .is_none_or(|events| events.contains(&event))
+    }
+}
+
+/// The admin API, authenticated with a bearer token whose name identifies the operator
+/// ```toml
+/// [server.admin]
+/// listen_addr = "127.0.0.1:7001"
+/// tokens = [{ name = "alice", token = "admin-secret" }]
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Address the API is served on, keep it off public interfaces
+    pub listen_addr: String,
+    /// Tokens accepted by the API
+    #[serde(default)]
+    pub tokens: Vec<AdminToken>,
+}
+
+/// A named admin API token, the name is logged with every action taken with it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminToken {
+    /// Who the token belongs to
+    pub name: String,
+    /// Sent as `Authorization: Bearer <token>`
+    pub token: String,
+}
+
+/// A server event reported to webhooks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// A client authenticated and its session started
+    ClientAuthenticated,
+    /// A client session ended
+    ClientDisconnected,
+    /// A client registered a proxy
+    ProxyRegistered,
+    /// The listener of a proxy could not be bound
+    ListenerBindFailed,
+    /// An IP was banned for repeated authentication failures
+    AuthFailureBurst,
+}
+
+impl WebhookEvent {
+    /// Name of the event in payloads and in `events`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WebhookEvent::ClientAuthenticated => "client_authenticated",
+            WebhookEvent::ClientDisconnected => "client_disconnected",
+            WebhookEvent::ProxyRegistered => "proxy_registered",
+            WebhookEvent::ListenerBindFailed => "listener_bind_failed",
+            WebhookEvent::AuthFailureBurst => "auth_failure_burst",
+        }
+    }
+}
+
+/// A named authentication token
+/// ```toml
+/// [[server.tokens]]
+/// token = "secret"
+/// name = "alice"
+/// allowed_ports = "8000-8100"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenConfig {
+    /// The shared secret
+    pub token: String,
+    /// Name shown in logs for clients using this token
+    pub name: Option<String>,
+    /// Remote ports clients using this token may claim, unrestricted when absent
+    pub allowed_ports: Option<PortSet>,
 }
 
 /// Configuration for client mode operation
@@ -34,21 +331,292 @@ pub struct ServerConfig {
 /// sowback connect
 /// ```
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ClientConfig {
     /// Specify a client name for human to identify (not unique)
     pub name: Option<String>,
     /// List of server addresses to connect to
+    #[serde(default)]
     pub servers: Vec<String>,
     /// For authentication and cryptography
     pub token: String,
-    /// List of services to proxy to all servers
+    /// List of services to proxy to all servers.
+    /// A `remote_port` of 0 lets each server pick the port
+    #[serde(default)]
     pub services: Vec<ServiceConfig>,
-    /// Interval to reconnect to servers
-    pub reconnect_interval: u64,
-    /// Interval for sending heartbeat messages
-    pub heartbeat_interval: u64,
+    /// Per-server entries with their own services, superseding `servers` and `services`
+    #[serde(default)]
+    pub connections: Vec<ConnectionConfig>,
+    /// Local ports forwarding to the secret services of other clients
+    #[serde(default)]
+    pub visitors: Vec<VisitorConfig>,
+    /// Local ports forwarding to the relay services of other clients
+    #[serde(default)]
+    pub relays: Vec<RelayConfig>,
+    /// Wait between reconnect attempts, e.g. `"5s"`; a bare number is seconds
+    pub reconnect_interval: HumanDuration,
+    /// Failed connection attempts in a row after which a server is given up, 0 keeps
+    /// trying forever. Reaching the server starts a new count
+    #[serde(default)]
+    pub max_reconnect_attempts: u32,
+    /// Interval for sending heartbeat messages, e.g. `"30s"`; a bare number is seconds
+    pub heartbeat_interval: HumanDuration,
+    /// Unanswered heartbeats in a row after which the server is taken as gone and
+    /// reconnected to, 0 never gives up
+    #[serde(default = "default_heartbeat_max_missed")]
+    pub heartbeat_max_missed: usize,
+    /// Runtime worker threads, overridden by `--workers`: 1 runs everything on a single
+    /// thread, 0 starts one per core
+    #[serde(default)]
+    pub workers: usize,
     /// Log file path
     pub log_file: Option<String>,
+    /// Maximum log level, overridden by `--log-level`
+    pub log_level: Option<LogLevel>,
+    /// Per-target log levels, e.g. `"sowback::server=debug,sowback::utils=warn"`
+    pub log_filter: Option<String>,
+    /// Where logs go besides the console: the log file, or syslog too
+    #[serde(default)]
+    pub log_target: LogTarget,
+    /// Syslog daemon of `log_target = "syslog"`: `journald`, a socket path,
+    /// `udp://host:port` or `tcp://host:port`; journald under systemd, else `/dev/log`
+    pub syslog_addr: Option<String>,
+    /// Compression codec to request for `Data` payloads, the server makes the final choice
+    #[serde(default)]
+    pub compression: Compression,
+    /// Minimum payload size before compression is attempted
+    #[serde(default = "default_compression_threshold")]
+    pub compression_threshold: HumanBytes,
+    /// TLS settings for connecting to servers
+    #[serde(default)]
+    pub tls: ClientTlsConfig,
+    /// Local connections with no traffic in either direction for this long are closed, 0 disables
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: HumanDuration,
+    /// Data from the server queued for a local service and not written yet, past which the
+    /// service is taken as a slow consumer and the connection closed. 0 is unlimited
+    #[serde(default = "default_max_buffered_per_connection")]
+    pub max_buffered_per_connection: HumanBytes,
+    /// Disable Nagle's algorithm on server and local service sockets
+    #[serde(default = "default_tcp_nodelay")]
+    pub tcp_nodelay: bool,
+    /// Idle time before TCP keepalive probes are sent, 0 disables keepalive
+    #[serde(default = "default_tcp_keepalive")]
+    pub tcp_keepalive: HumanDuration,
+    /// How long a write to a server's control connection may stall before the server is
+    /// taken as gone, also its TCP user timeout. Twice `heartbeat_interval` when unset,
+    /// 0 waits as long as the OS does
+    pub control_write_timeout: Option<HumanDuration>,
+    /// How long to wait for the server to answer a service registration before sending it
+    /// again, 0 waits forever
+    #[serde(default = "default_service_register_timeout")]
+    pub service_register_timeout: HumanDuration,
+    /// Times an unanswered service registration is sent again before the service is given up
+    #[serde(default = "default_service_register_retries")]
+    pub service_register_retries: u32,
+    /// How the control connection reaches servers, inside TLS when `tls.enable` is set
+    #[serde(default)]
+    pub transport: Transport,
+    /// Carry each proxied connection on a connection of its own to the server, if the server
+    /// allows it, so a busy tunnel does not hold up the others. Proxied connections go in
+    /// `Data` messages when one cannot be opened. QUIC sessions always have their own streams
+    #[serde(default)]
+    pub data_channels: bool,
+    /// How often the table of servers and services is printed, besides whenever a server
+    /// connects or disconnects. 0 disables the periodic table
+    #[serde(default)]
+    pub status_interval: HumanDuration,
+    /// Unix socket the client answers `sowback ctl` commands on, only for its own user
+    pub control_socket: Option<String>,
+    /// Register services again for a new `ttl` once theirs runs out, instead of
+    /// leaving them expired
+    #[serde(default)]
+    pub renew: bool,
+    /// How often the local services with `health_check` are checked while connected,
+    /// 0 only checks them before they are registered
+    #[serde(default = "default_health_check_interval")]
+    pub health_check_interval: HumanDuration,
+    /// How long reaching a server may take before the attempt is given up, 0 leaves it
+    /// to the OS
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: HumanDuration,
+    /// How long a local service may take to accept a connection, 0 leaves it to the OS.
+    /// Services may override it
+    #[serde(default = "default_local_connect_timeout")]
+    pub local_connect_timeout: HumanDuration,
+}
+
+/// Carrier of the control connection to a server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// Frames straight over TCP
+    #[default]
+    Tcp,
+    /// Frames in binary WebSocket messages, for networks that only let HTTP out.
+    /// Servers accept it on their `websocket_listen_addr`
+    WebSocket,
+    /// Frames on a QUIC stream to the server's `quic_listen_addr`, each proxied connection
+    /// on a stream of its own. Always encrypted, `tls` verifies the server
+    Quic,
+}
+
+/// A server to connect to together with the services exposed on it
+/// ```toml
+/// [[client.connections]]
+/// server = "1.2.3.4:7000"
+/// token = "override"
+/// services = [{ name = "ssh", local_port = 22, remote_port = 2222 }]
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectionConfig {
+    /// Server address, `host:port`
+    pub server: String,
+    /// Token for this server, the client's `token` when absent
+    pub token: Option<String>,
+    /// Services to expose through this server
+    #[serde(default)]
+    pub services: Vec<ServiceConfig>,
+}
+
+/// A local port whose connections reach a secret service of another client
+/// ```toml
+/// [[client.visitors]]
+/// service = "ssh"
+/// secret = "shared-with-the-owner"
+/// bind_addr = "127.0.0.1:2222"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VisitorConfig {
+    /// Name the secret service was registered with
+    pub service: String,
+    /// Secret the service was registered with
+    pub secret: String,
+    /// Local address to accept connections on, `ip:port`
+    pub bind_addr: String,
+    /// Server the service is registered on, the first configured server when absent
+    pub server: Option<String>,
+}
+
+/// A local port whose connections reach a relay service of another client, which names
+/// this client in its `relay_peers`
+/// ```toml
+/// [[client.relays]]
+/// client = "laptop-b"
+/// service = "ssh"
+/// bind_addr = "127.0.0.1:2222"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RelayConfig {
+    /// ID or name of the client the service belongs to
+    pub client: String,
+    /// Name the relay service was registered with
+    pub service: String,
+    /// Local address to accept connections on, `ip:port`
+    pub bind_addr: String,
+    /// Server the service is registered on, the first configured server when absent
+    pub server: Option<String>,
+}
+
+impl RelayConfig {
+    /// Parses a relay string in the format "client:service@local_port", bound on
+    /// 127.0.0.1, or "client:service@local_ip:local_port"
+    pub fn parse_cli(relay_str: &str) -> Result<Self> {
+        let invalid = || {
+            anyhow::anyhow!(
+                "Invalid relay '{}', expected client:service@local_port",
+                relay_str
+            )
+        };
+        let (target, bind) = relay_str.rsplit_once('@').ok_or_else(invalid)?;
+        let (client, service) = target.split_once(':').ok_or_else(invalid)?;
+        if client.is_empty() || service.is_empty() {
+            return Err(invalid());
+        }
+        let bind_addr = match bind.parse::<u16>() {
+            Ok(port) => join_host_port(&default_local_ip(), port),
+            Err(_) if bind.parse::<std::net::SocketAddr>().is_ok() => bind.to_string(),
+            Err(_) => return Err(invalid()),
+        };
+        Ok(RelayConfig {
+            client: client.to_string(),
+            service: service.to_string(),
+            bind_addr,
+            server: None,
+        })
+    }
+}
+
+/// TLS settings for the server control port
+/// ```toml
+/// [server.tls]
+/// cert = "/etc/sowback/cert.pem"
+/// key = "/etc/sowback/key.pem"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerTlsConfig {
+    /// PEM certificate chain path
+    pub cert: String,
+    /// PEM private key path
+    pub key: String,
+    /// PEM CA bundle used to verify client certificates, enables mutual TLS
+    pub client_ca: Option<String>,
+    /// How clients authenticate: `token`, `cert` or `both`.
+    /// Defaults to `cert` when `client_ca` is set, `token` otherwise
+    pub auth_mode: Option<AuthMode>,
+}
+
+impl ServerTlsConfig {
+    /// Effective authentication mode for this TLS configuration
+    pub fn auth_mode(&self) -> AuthMode {
+        match self.auth_mode {
+            Some(mode) => mode,
+            None if self.client_ca.is_some() => AuthMode::Cert,
+            None => AuthMode::Token,
+        }
+    }
+}
+
+/// Credentials a client must present to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    /// Shared token only
+    Token,
+    /// Client certificate signed by `client_ca` only, the token may be empty
+    Cert,
+    /// Both a valid client certificate and the token
+    Both,
+}
+
+/// TLS settings for the client side of the control connection
+/// ```toml
+/// [client.tls]
+/// enable = true
+/// ca = "/etc/sowback/ca.pem"
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientTlsConfig {
+    /// Connect to servers over TLS
+    pub enable: bool,
+    /// PEM CA bundle to verify the server against, system roots when absent
+    pub ca: Option<String>,
+    /// Name to verify the server certificate against, the host of the server address when absent
+    pub server_name: Option<String>,
+    /// Accept any server certificate (testing only)
+    pub insecure_skip_verify: bool,
+    /// PEM client certificate chain for mutual TLS
+    pub cert: Option<String>,
+    /// PEM private key of the client certificate
+    pub key: Option<String>,
+    /// SHA-256 fingerprint of the server certificate, hex with optional colons. When set the
+    /// certificate is pinned instead of verified against a CA, e.g. a self-signed one
+    pub fingerprint: Option<String>,
 }
 
 // --- Default configuration ---
@@ -60,8 +628,51 @@ impl Default for ServerConfig {
             listen_addr: "0.0.0.0:7000".to_string(),
             bind_host: "0.0.0.0".to_string(),
             token: "".to_string(), // No default token - must be provided
+            tokens: vec![],
             max_clients: 100,
+            max_connections: 0,
+            workers: 0,
+            reuseport: false,
             log_file: None,
+            log_level: None,
+            log_filter: None,
+            log_target: LogTarget::File,
+            syslog_addr: None,
+            access_log: None,
+            compression: Compression::None,
+            compression_threshold: default_compression_threshold(),
+            tls: None,
+            quic_listen_addr: None,
+            websocket_listen_addr: None,
+            port_range: None,
+            allowed_ports: None,
+            allowed_bind_hosts: vec![],
+            http_port: None,
+            sni_port: None,
+            stats_interval: 0,
+            idle_timeout: default_idle_timeout(),
+            max_buffered_per_connection: default_max_buffered_per_connection(),
+            connect_timeout: default_connect_timeout(),
+            max_connections_per_ip_per_minute: 0,
+            tcp_nodelay: default_tcp_nodelay(),
+            tcp_keepalive: default_tcp_keepalive(),
+            control_write_timeout: default_control_write_timeout(),
+            duplicate_client_policy: DuplicateClientPolicy::Reject,
+            auth_fail_limit: default_auth_fail_limit(),
+            auth_fail_window: default_auth_fail_window(),
+            auth_ban_duration: default_auth_ban_duration(),
+            listener_grace_period: default_listener_grace_period(),
+            orphan_connection_policy: OrphanConnectionPolicy::Refuse,
+            orphan_queue_size: default_orphan_queue_size(),
+            orphan_queue_timeout: default_orphan_queue_timeout(),
+            drain_timeout: default_drain_timeout(),
+            assign_client_ids: false,
+            reveal_port_owner: false,
+            allow_relay: false,
+            min_client_version: None,
+            data_channels: default_data_channels(),
+            webhooks: None,
+            admin: None,
         }
     }
 }
@@ -73,51 +684,1167 @@ impl Default for ClientConfig {
             servers: vec![],       // must be provided at least one server
             token: "".to_string(), // No default token - must be provided
             services: vec![],
-            reconnect_interval: 5,
-            heartbeat_interval: 30,
+            connections: vec![],
+            visitors: vec![],
+            relays: vec![],
+            reconnect_interval: HumanDuration(std::time::Duration::from_secs(5)),
+            max_reconnect_attempts: 0,
+            heartbeat_interval: HumanDuration(std::time::Duration::from_secs(30)),
+            heartbeat_max_missed: default_heartbeat_max_missed(),
+            workers: 0,
             log_file: None,
+            log_level: None,
+            log_filter: None,
+            log_target: LogTarget::File,
+            syslog_addr: None,
+            compression: Compression::None,
+            compression_threshold: default_compression_threshold(),
+            tls: ClientTlsConfig::default(),
+            idle_timeout: default_idle_timeout(),
+            max_buffered_per_connection: default_max_buffered_per_connection(),
+            tcp_nodelay: default_tcp_nodelay(),
+            tcp_keepalive: default_tcp_keepalive(),
+            control_write_timeout: None,
+            service_register_timeout: default_service_register_timeout(),
+            service_register_retries: default_service_register_retries(),
+            transport: Transport::default(),
+            data_channels: false,
+            status_interval: HumanDuration::default(),
+            control_socket: None,
+            renew: false,
+            health_check_interval: default_health_check_interval(),
+            connect_timeout: default_connect_timeout(),
+            local_connect_timeout: default_local_connect_timeout(),
+        }
+    }
+}
+
+fn default_strict() -> bool {
+    true
+}
+
+fn default_data_channels() -> bool {
+    true
+}
+
+fn default_compression_threshold() -> HumanBytes {
+    HumanBytes(DEFAULT_COMPRESSION_THRESHOLD as u64)
+}
+
+fn default_heartbeat_max_missed() -> usize {
+    3
+}
+
+fn default_idle_timeout() -> HumanDuration {
+    HumanDuration(std::time::Duration::from_secs(600))
+}
+
+fn default_max_buffered_per_connection() -> HumanBytes {
+    HumanBytes(4 * 1024 * 1024)
+}
+
+fn default_connect_timeout() -> HumanDuration {
+    HumanDuration(std::time::Duration::from_secs(10))
+}
+
+fn default_local_connect_timeout() -> HumanDuration {
+    HumanDuration(std::time::Duration::from_secs(5))
+}
+
+fn default_tcp_nodelay() -> bool {
+    true
+}
+
+fn default_tcp_keepalive() -> HumanDuration {
+    HumanDuration(std::time::Duration::from_secs(60))
+}
+
+/// Twice the default `heartbeat_interval` of clients
+fn default_control_write_timeout() -> HumanDuration {
+    HumanDuration(std::time::Duration::from_secs(60))
+}
+
+fn default_service_register_timeout() -> HumanDuration {
+    HumanDuration(std::time::Duration::from_secs(10))
+}
+
+fn default_service_register_retries() -> u32 {
+    2
+}
+
+fn default_health_check_interval() -> HumanDuration {
+    HumanDuration(std::time::Duration::from_secs(30))
+}
+
+fn default_health_check() -> bool {
+    true
+}
+
+fn default_connect_attempts() -> u32 {
+    3
+}
+
+fn default_connect_retry_delay() -> HumanDuration {
+    HumanDuration(std::time::Duration::from_millis(500))
+}
+
+fn default_auth_fail_limit() -> usize {
+    5
+}
+
+fn default_auth_fail_window() -> HumanDuration {
+    HumanDuration(std::time::Duration::from_secs(600))
+}
+
+fn default_auth_ban_duration() -> HumanDuration {
+    HumanDuration(std::time::Duration::from_secs(900))
+}
+
+fn default_listener_grace_period() -> HumanDuration {
+    HumanDuration(std::time::Duration::from_secs(30))
+}
+
+fn default_orphan_queue_size() -> usize {
+    64
+}
+
+fn default_orphan_queue_timeout() -> HumanDuration {
+    HumanDuration(std::time::Duration::from_secs(10))
+}
+
+fn default_drain_timeout() -> HumanDuration {
+    HumanDuration(std::time::Duration::from_secs(60))
+}
+
+/// `syslog_addr` resolved when `target` is syslog, None for an invalid one, which
+/// validation reports
+fn syslog_target(target: LogTarget, syslog_addr: Option<&str>) -> Option<SyslogAddr> {
+    match target {
+        LogTarget::File => None,
+        LogTarget::Syslog => SyslogAddr::resolve(syslog_addr).ok(),
+    }
+}
+
+fn default_local_ip() -> String {
+    "127.0.0.1".to_string()
+}
+
+impl ServerConfig {
+    /// A copy with every token redacted, safe to log
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        config.token = redact(&config.token);
+        for entry in &mut config.tokens {
+            entry.token = redact(&entry.token);
+        }
+        if let Some(webhooks) = &mut config.webhooks {
+            webhooks.secret = webhooks.secret.as_deref().map(redact);
+        }
+        if let Some(admin) = &mut config.admin {
+            for entry in &mut admin.tokens {
+                entry.token = redact(&entry.token);
+            }
+        }
+        config
+    }
+
+    /// All accepted tokens, the legacy `token` field first as an unrestricted entry
+    pub fn token_entries(&self) -> Vec<TokenConfig> {
+        let legacy = (!self.token.is_empty()).then(|| TokenConfig {
+            token: self.token.clone(),
+            name: None,
+            allowed_ports: None,
+        });
+        legacy
+            .into_iter()
+            .chain(self.tokens.iter().cloned())
+            .collect()
+    }
+
+    /// Port of the control listener, which clients may never claim
+    pub fn listen_port(&self) -> Option<u16> {
+        self.listen_addr.rsplit(':').next()?.parse().ok()
+    }
+
+    /// Authentication mode required from clients
+    pub fn auth_mode(&self) -> AuthMode {
+        self.tls
+            .as_ref()
+            .map(ServerTlsConfig::auth_mode)
+            .unwrap_or(AuthMode::Token)
+    }
+
+    /// Socket options for proxy connections
+    pub fn socket_options(&self) -> SocketOptions {
+        SocketOptions {
+            nodelay: self.tcp_nodelay,
+            keepalive: self.tcp_keepalive.non_zero(),
+            user_timeout: None,
+        }
+    }
+
+    /// Socket options for control connections
+    pub fn control_socket_options(&self) -> SocketOptions {
+        SocketOptions {
+            user_timeout: self.control_write_timeout.non_zero(),
+            ..self.socket_options()
+        }
+    }
+
+    /// The syslog daemon logs go to, None unless `log_target = "syslog"`
+    pub fn syslog(&self) -> Option<SyslogAddr> {
+        syslog_target(self.log_target, self.syslog_addr.as_deref())
+    }
+}
+
+impl ClientConfig {
+    /// The syslog daemon logs go to, None unless `log_target = "syslog"`
+    pub fn syslog(&self) -> Option<SyslogAddr> {
+        syslog_target(self.log_target, self.syslog_addr.as_deref())
+    }
+
+    /// A copy with every token redacted, safe to log
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        config.token = redact(&config.token);
+        for entry in &mut config.connections {
+            entry.token = entry.token.as_deref().map(redact);
+            for service in &mut entry.services {
+                service.secret = service.secret.as_deref().map(redact);
+            }
+        }
+        for service in &mut config.services {
+            service.secret = service.secret.as_deref().map(redact);
+        }
+        for visitor in &mut config.visitors {
+            visitor.secret = redact(&visitor.secret);
+        }
+        config
+    }
+
+    /// Socket options for local service and data channel connections
+    pub fn socket_options(&self) -> SocketOptions {
+        SocketOptions {
+            nodelay: self.tcp_nodelay,
+            keepalive: self.tcp_keepalive.non_zero(),
+            user_timeout: None,
+        }
+    }
+
+    /// Socket options for control connections
+    pub fn control_socket_options(&self) -> SocketOptions {
+        SocketOptions {
+            user_timeout: self.control_write_timeout().non_zero(),
+            ..self.socket_options()
+        }
+    }
+
+    /// `control_write_timeout`, twice `heartbeat_interval` when unset
+    pub fn control_write_timeout(&self) -> HumanDuration {
+        self.control_write_timeout
+            .unwrap_or(HumanDuration(self.heartbeat_interval.0 * 2))
+    }
+
+    /// Servers to connect to, each with its resolved token and services.
+    /// `connections` when present, otherwise every `servers` entry with the shared `services`
+    pub fn connection_entries(&self) -> Vec<ConnectionConfig> {
+        if !self.connections.is_empty() {
+            return self
+                .connections
+                .iter()
+                .map(|entry| ConnectionConfig {
+                    token: entry.token.clone().or_else(|| Some(self.token.clone())),
+                    ..entry.clone()
+                })
+                .collect();
         }
+
+        self.servers
+            .iter()
+            .map(|server| ConnectionConfig {
+                server: server.clone(),
+                token: Some(self.token.clone()),
+                services: self.services.clone(),
+            })
+            .collect()
+    }
+
+    /// Names unnamed services after their mapping, then rejects duplicate
+    /// service names and remote ports, naming the offending entry
+    pub fn normalize_services(&mut self) -> Result<()> {
+        normalize_service_list(&mut self.services)?;
+        for entry in &mut self.connections {
+            normalize_service_list(&mut entry.services)
+                .map_err(|e| anyhow::anyhow!("In connection to {}: {}", entry.server, e))?;
+        }
+        Ok(())
     }
 }
 
-impl Config {
-    /// Loads configuration from a TOML file
-    pub fn from_file(path: &str) -> Result<Self> {
-        let content = fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&content)?;
-        Ok(config)
+/// Names unnamed services, lowercases their host names and rejects conflicting services
+pub(crate) fn normalize_service_list(services: &mut [ServiceConfig]) -> Result<()> {
+    let mut names: HashMap<String, usize> = HashMap::new();
+    let mut ports: HashMap<u16, String> = HashMap::new();
+    let mut routes: HashMap<String, String> = HashMap::new();
+
+    for (index, service) in services.iter_mut().enumerate() {
+        if service.local_path.is_some() && !cfg!(unix) {
+            return Err(anyhow::anyhow!(
+                "Service #{} forwards to a Unix domain socket, which is only supported on Unix",
+                index + 1
+            ));
+        }
+
+        if service.http_host.is_some() && service.sni.is_some() {
+            return Err(anyhow::anyhow!(
+                "Service #{} sets both http_host and sni, pick one",
+                index + 1
+            ));
+        }
+
+        // members share the listener of one port
+        if service.group.is_some()
+            && (service.remote_port == 0 || service.http_host.is_some() || service.sni.is_some())
+        {
+            return Err(anyhow::anyhow!(
+                "Service #{} sets a group, which needs a fixed remote_port",
+                index + 1
+            ));
+        }
+
+        // visitors ask for it by name, and it has no port of its own
+        if service.secret.is_some() {
+            if service.name.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Service #{} sets a secret, which needs a name for visitors to ask for",
+                    index + 1
+                ));
+            }
+            if service.remote_port != 0
+                || service.http_host.is_some()
+                || service.sni.is_some()
+                || service.group.is_some()
+            {
+                return Err(anyhow::anyhow!(
+                    "Service '{}' sets a secret, which rules out remote_port, http_host, sni and group",
+                    service.name
+                ));
+            }
+        }
+
+        // its peers ask for it by name, and it has no port of its own either
+        if let Some(peers) = &service.relay_peers {
+            if service.name.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Service #{} sets relay_peers, which needs a name for its peers to ask for",
+                    index + 1
+                ));
+            }
+            if peers.is_empty() || peers.iter().any(String::is_empty) {
+                return Err(anyhow::anyhow!(
+                    "Service '{}' sets relay_peers, which must name at least one client",
+                    service.name
+                ));
+            }
+            if service.remote_port != 0
+                || service.http_host.is_some()
+                || service.sni.is_some()
+                || service.group.is_some()
+                || service.secret.is_some()
+            {
+                return Err(anyhow::anyhow!(
+                    "Service '{}' sets relay_peers, which rules out remote_port, http_host, sni, group and secret",
+                    service.name
+                ));
+            }
+        }
+
+        if service.max_connections == Some(0) {
+            return Err(anyhow::anyhow!(
+                "Service #{} sets max_connections = 0, leave it out for no limit",
+                index + 1
+            ));
+        }
+
+        // host names are matched case-insensitively
+        for host in [&mut service.http_host, &mut service.sni]
+            .into_iter()
+            .flatten()
+        {
+            *host = host.to_ascii_lowercase();
+        }
+
+        if service.name.is_empty() {
+            service.name = match service.http_host.as_ref().or(service.sni.as_ref()) {
+                Some(host) => format!("{}@{}", service.local_addr(), host),
+                None => format!("{}:{}", service.local_addr(), service.remote_port),
+            };
+        }
+
+        if let Some(first) = names.insert(service.name.clone(), index) {
+            return Err(anyhow::anyhow!(
+                "Duplicate service name '{}' (services #{} and #{})",
+                service.name,
+                first + 1,
+                index + 1
+            ));
+        }
+
+        let route = match (&service.http_host, &service.sni) {
+            (Some(host), _) => Some(format!("HTTP host {}", host)),
+            (None, Some(name)) => Some(format!("SNI name {}", name)),
+            (None, None) => None,
+        };
+        if let Some(route) = route {
+            if let Some(other) = routes.insert(route.clone(), service.name.clone()) {
+                return Err(anyhow::anyhow!(
+                    "Service '{}' uses {} already taken by service '{}'",
+                    service.name,
+                    route,
+                    other
+                ));
+            }
+            continue;
+        }
+
+        // 0 asks the server to pick, so it may repeat
+        if service.remote_port != 0 {
+            if let Some(other) = ports.insert(service.remote_port, service.name.clone()) {
+                return Err(anyhow::anyhow!(
+                    "Service '{}' uses remote port {} already taken by service '{}'",
+                    service.name,
+                    service.remote_port,
+                    other
+                ));
+            }
+        }
     }
+
+    Ok(())
 }
 
 // ------------------------------------------------
 
 /// Configuration for a single service to be forwarded.
 /// - Related to cli option `--service`
+/// ```toml
+/// [[client.services]]
+/// name = "web"
+/// local_port = 3000
+/// remote_port = 8080
+/// ```
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ServiceConfig {
+    /// Name used in logs, `local_ip:local_port:remote_port` when absent
+    #[serde(default)]
     pub name: String,
+    /// Host of the local service
+    #[serde(default = "default_local_ip")]
     pub local_ip: String,
+    /// Port of the local service. A range like `"8000-8010"` expands the service
+    /// into one per port, paired with the ports of a `remote_port` range
+    #[serde(default, deserialize_with = "ports::deserialize_port")]
     pub local_port: u16,
+    /// Unix domain socket to forward to instead of `local_ip`/`local_port`
+    #[serde(default)]
+    pub local_path: Option<String>,
+    /// 0 lets the server pick the port, ignored for `http` services
+    #[serde(default, deserialize_with = "ports::deserialize_port")]
     pub remote_port: u16,
+    /// Makes this an `http` service, reached through the server's `http_port`
+    /// by requests for this host name instead of a dedicated remote port
+    pub http_host: Option<String>,
+    /// Makes this a TLS service, reached through the server's `sni_port`
+    /// by connections whose ClientHello names this host
+    pub sni: Option<String>,
+    /// Host to bind the remote port on, the server's `bind_host` when absent
+    pub bind_host: Option<String>,
+    /// Send a PROXY protocol header (`v1` or `v2`) to the local service
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// Share `remote_port` with the other clients registering it in this group,
+    /// the server balancing new connections between the members online
+    pub group: Option<String>,
+    /// Connections the server forwards at once, it closes more right after accepting them
+    pub max_connections: Option<u32>,
+    /// Makes this a secret service: the server binds no port for it, only visitors
+    /// knowing its name and this secret reach it
+    pub secret: Option<String>,
+    /// Makes this a relay service: the server binds no port for it, only the clients
+    /// named here, by ID or name, reach it through their `relays`
+    pub relay_peers: Option<Vec<String>>,
+    /// Have the server remove the service this long after it first registered,
+    /// reconnecting does not restart the count
+    pub ttl: Option<HumanDuration>,
+    /// Connect to the local service before registering it and while connected,
+    /// warning when nothing answers
+    #[serde(default = "default_health_check")]
+    pub health_check: bool,
+    /// Only register the service while its local service answers the health check
+    #[serde(default)]
+    pub require_healthy: bool,
+    /// Tries at reaching the local service for each connection before it is failed,
+    /// bridging the local service restarting
+    #[serde(default = "default_connect_attempts")]
+    pub connect_attempts: u32,
+    /// Wait between two tries at reaching the local service
+    #[serde(default = "default_connect_retry_delay")]
+    pub connect_retry_delay: HumanDuration,
+    /// How long the local service may take to accept a connection, instead of the
+    /// client's `local_connect_timeout`
+    pub local_connect_timeout: Option<HumanDuration>,
 }
 
 impl ServiceConfig {
-    /// Parses a service configuration string in the format "local_ip:local_port:remote_port"
+    /// Local target for display, `ip:port` or `unix:path`
+    pub fn local_addr(&self) -> String {
+        match &self.local_path {
+            Some(path) => format!("unix:{}", path),
+            None => join_host_port(&self.local_ip, self.local_port),
+        }
+    }
+
+    /// Whether other clients reach the service through the server instead of a remote
+    /// port, as a secret or a relay service
+    pub fn is_visited(&self) -> bool {
+        self.secret.is_some() || self.relay_peers.is_some()
+    }
+
+    /// `http://host` or `tls://name` for services routed on a shared server port
+    pub fn shared_route(&self) -> Option<String> {
+        match (&self.http_host, &self.sni) {
+            (Some(host), _) => Some(format!("http://{}", host)),
+            (None, Some(name)) => Some(format!("tls://{}", name)),
+            (None, None) => None,
+        }
+    }
+
+    /// Parses a service configuration string in the format "local_ip:local_port:remote_port",
+    /// "local_port:remote_port" or "port" on 127.0.0.1, or "unix:local_path:remote_port",
+    /// optionally followed by "@bind_host" and then "/max_connections"
     pub fn parse_cli(service_str: &str) -> Result<Self> {
-        // [local_ip]:[local_port]:[remote_port]
-        let parts: Vec<&str> = service_str.split(':').collect();
-        if parts.len() != 3 {
-            return Err(anyhow::anyhow!(
-                "Invalid service format. Expected: local_ip:local_port:remote_port"
-            ));
+        let mut services = Self::parse_cli_ranges(service_str)?;
+        match services.len() {
+            1 => Ok(services.remove(0)),
+            count => Err(anyhow::anyhow!(
+                "'{}' is a port range of {} services, expected a single service",
+                service_str,
+                count
+            )),
         }
+    }
 
-        let name = service_str.to_string();
-        Ok(ServiceConfig {
-            name,
-            local_ip: parts[0].to_string(),
-            local_port: parts[1].parse()?,
-            remote_port: parts[2].parse()?,
-        })
+    /// Parses a service configuration string like `parse_cli`, the ports of which may be
+    /// ranges: "127.0.0.1:8000-8010:9000-9010" expands into a service per port
+    pub fn parse_cli_ranges(service_str: &str) -> Result<Vec<Self>> {
+        // [mapping]@[bind_host]/[max_connections], a socket path may hold '/' too
+        let (rest, max_connections) = match service_str.rsplit_once('/') {
+            Some((rest, max)) if !max.is_empty() && max.bytes().all(|b| b.is_ascii_digit()) => {
+                (rest, Some(max.parse()?))
+            }
+            _ => (service_str, None),
+        };
+
+        // [mapping]@[bind_host]
+        let (mapping, bind_host) = match rest.split_once('@') {
+            Some((mapping, host)) if !host.is_empty() => (mapping, Some(host.to_string())),
+            Some(_) => return Err(anyhow::anyhow!("Empty bind host in '{}'", service_str)),
+            None => (rest, None),
+        };
+
+        // unix:[local_path]:[remote_port]@[bind_host]
+        if let Some(socket) = mapping.strip_prefix("unix:") {
+            if !cfg!(unix) {
+                return Err(anyhow::anyhow!(
+                    "Unix domain socket services are only supported on Unix: '{}'",
+                    service_str
+                ));
+            }
+            let (path, remote_port) = socket.rsplit_once(':').ok_or_else(|| {
+                anyhow::anyhow!("Invalid service format. Expected: unix:local_path:remote_port")
+            })?;
+            if path.is_empty() {
+                return Err(anyhow::anyhow!("Empty socket path in '{}'", service_str));
+            }
+            return Ok(vec![ServiceConfig {
+                name: service_str.to_string(),
+                local_ip: default_local_ip(),
+                local_port: 0,
+                local_path: Some(path.to_string()),
+                remote_port: remote_port.parse()?,
+                bind_host,
+                http_host: None,
+                sni: None,
+                proxy_protocol: None,
+                group: None,
+                max_connections,
+                secret: None,
+                relay_peers: None,
+                ttl: None,
+                health_check: default_health_check(),
+                require_healthy: false,
+                connect_attempts: default_connect_attempts(),
+                connect_retry_delay: default_connect_retry_delay(),
+                local_connect_timeout: None,
+            }]);
+        }
+        // [local_ip:]local_port[:remote_port], an IPv6 local address is written in
+        // brackets, [::1]:8080:80
+        let fields = service_fields(mapping)
+            .map_err(|e| anyhow::anyhow!("Invalid service '{}': {}", service_str, e))?;
+        let expected = || {
+            anyhow::anyhow!(
+                "Invalid service '{}': expected local_port:remote_port, got '{}'",
+                service_str,
+                mapping
+            )
+        };
+        let local_ip = default_local_ip();
+        let (local_ip, local_port, remote_port) = match fields[..] {
+            [port] if !port.starts_with('[') => (local_ip.as_str(), port, port),
+            [local_port, remote_port] if !local_port.starts_with('[') => {
+                (local_ip.as_str(), local_port, remote_port)
+            }
+            [ip, local_port, remote_port] => (ip, local_port, remote_port),
+            _ => return Err(expected()),
+        };
+        let local_ip = match local_ip.strip_prefix('[') {
+            Some(ip) => {
+                let ip = ip.strip_suffix(']').unwrap_or(ip);
+                if ip.parse::<std::net::Ipv6Addr>().is_err() {
+                    return Err(anyhow::anyhow!(
+                        "Invalid IPv6 address '{}' in '{}'",
+                        ip,
+                        service_str
+                    ));
+                }
+                ip
+            }
+            None if local_ip.is_empty() => {
+                return Err(anyhow::anyhow!("Empty local IP in '{}'", service_str))
+            }
+            None => local_ip,
+        };
+        // without a local IP a field that is not a port is more likely a misplaced address
+        // than a typo in a port
+        let port = |port: &str| -> Result<PortRange> {
+            port.parse().map_err(|e| match fields.len() {
+                3 => e,
+                _ => expected(),
+            })
+        };
+
+        let local_ports = port(local_port)?;
+        let remote_ports = port(remote_port)?;
+        let service = ServiceConfig {
+            name: service_str.to_string(),
+            local_ip: local_ip.to_string(),
+            local_port: local_ports.start,
+            local_path: None,
+            remote_port: remote_ports.start,
+            bind_host,
+            http_host: None,
+            sni: None,
+            proxy_protocol: None,
+            group: None,
+            max_connections,
+            secret: None,
+            relay_peers: None,
+            ttl: None,
+            health_check: default_health_check(),
+            require_healthy: false,
+            connect_attempts: default_connect_attempts(),
+            connect_retry_delay: default_connect_retry_delay(),
+            local_connect_timeout: None,
+        };
+        if local_ports.count() == 1 && remote_ports.count() == 1 {
+            return Ok(vec![service]);
+        }
+        // named like services without a name in config files
+        Ok(ports::pair_ranges(local_ports, remote_ports)?
+            .into_iter()
+            .map(|(local_port, remote_port)| ServiceConfig {
+                name: format!("{}:{}", join_host_port(local_ip, local_port), remote_port),
+                local_port,
+                remote_port,
+                ..service.clone()
+            })
+            .collect())
+    }
+}
+
+/// Splits the mapping of a service string at its colons, keeping an IPv6 address in
+/// brackets together as one field
+fn service_fields(mapping: &str) -> std::result::Result<Vec<&str>, String> {
+    let mut fields = Vec::new();
+    let mut rest = mapping;
+    loop {
+        let end = match rest.strip_prefix('[') {
+            Some(bracketed) => {
+                let end = bracketed
+                    .find(']')
+                    .ok_or_else(|| format!("unclosed '[' in '{}'", mapping))?
+                    + 2;
+                if !rest[end..].is_empty() && !rest[end..].starts_with(':') {
+                    return Err(format!("expected ':' after '{}'", &rest[..end]));
+                }
+                end
+            }
+            None => rest.find(':').unwrap_or(rest.len()),
+        };
+        fields.push(&rest[..end]);
+        match rest[end..].strip_prefix(':') {
+            Some(next) => rest = next,
+            None => return Ok(fields),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_structured_services() {
+        let config: Config = toml::from_str(
+            r#"
+            [client]
+            servers = ["127.0.0.1:7000"]
+            token = "secret"
+            reconnect_interval = 5
+            heartbeat_interval = 30
+
+            [[client.services]]
+            name = "web"
+            local_port = 3000
+            remote_port = 8080
+
+            [[client.services]]
+            local_ip = "10.0.0.2"
+            local_port = 22
+            remote_port = 2222
+            "#,
+        )
+        .unwrap();
+        let mut client = config.client.unwrap();
+        client.normalize_services().unwrap();
+
+        assert_eq!(client.services[0].name, "web");
+        assert_eq!(client.services[0].local_ip, "127.0.0.1");
+        assert_eq!(client.services[1].name, "10.0.0.2:22:2222");
+
+        client.services[1].name = "web".to_string();
+        let err = client.normalize_services().unwrap_err().to_string();
+        assert!(err.contains("Duplicate service name 'web'"), "{}", err);
+
+        client.services[1].name = "ssh".to_string();
+        client.services[1].remote_port = 8080;
+        let err = client.normalize_services().unwrap_err().to_string();
+        assert!(err.contains("'ssh'") && err.contains("'web'"), "{}", err);
+    }
+
+    #[test]
+    fn test_http_services() {
+        let mut client: ClientConfig = toml::from_str(
+            r#"
+            servers = ["127.0.0.1:7000"]
+            token = "secret"
+            reconnect_interval = 5
+            heartbeat_interval = 30
+
+            [[services]]
+            local_port = 3000
+            http_host = "App.Example.com"
+
+            [[services]]
+            local_port = 4000
+            http_host = "api.example.com"
+            "#,
+        )
+        .unwrap();
+        client.normalize_services().unwrap();
+
+        assert_eq!(client.services[0].name, "127.0.0.1:3000@app.example.com");
+        assert_eq!(client.services[1].remote_port, 0);
+
+        client.services[1].http_host = Some("APP.example.com".to_string());
+        let err = client.normalize_services().unwrap_err().to_string();
+        assert!(err.contains("HTTP host app.example.com"), "{}", err);
+
+        // SNI names are a separate namespace, but a service routes one way only
+        client.services[1].http_host = None;
+        client.services[1].sni = Some("app.example.com".to_string());
+        client.normalize_services().unwrap();
+        client.services[1].http_host = Some("api.example.com".to_string());
+        assert!(client.normalize_services().is_err());
+
+        // a group shares a remote port, a host route has none
+        client.services[1].sni = None;
+        client.services[1].group = Some("web".to_string());
+        let err = client.normalize_services().unwrap_err().to_string();
+        assert!(err.contains("fixed remote_port"), "{}", err);
+    }
+
+    #[test]
+    fn test_relay_services() {
+        let mut client: ClientConfig = toml::from_str(
+            r#"
+            servers = ["127.0.0.1:7000"]
+            token = "secret"
+            reconnect_interval = 5
+            heartbeat_interval = 30
+
+            [[services]]
+            name = "ssh"
+            local_port = 22
+            relay_peers = ["laptop-a"]
+
+            [[relays]]
+            client = "laptop-b"
+            service = "ssh"
+            bind_addr = "127.0.0.1:2222"
+            "#,
+        )
+        .unwrap();
+        client.normalize_services().unwrap();
+        assert!(client.services[0].is_visited());
+        assert_eq!(client.relays[0].client, "laptop-b");
+
+        client.services[0].relay_peers = Some(vec![]);
+        let err = client.normalize_services().unwrap_err().to_string();
+        assert!(err.contains("at least one client"), "{}", err);
+
+        // a relay service has no remote port, nor a secret on top
+        client.services[0].relay_peers = Some(vec!["laptop-a".to_string()]);
+        client.services[0].secret = Some("shared".to_string());
+        let err = client.normalize_services().unwrap_err().to_string();
+        assert!(err.contains("rules out"), "{}", err);
+    }
+
+    #[test]
+    fn test_per_server_connections() {
+        let config: Config = toml::from_str(
+            r#"
+            [client]
+            token = "shared-token"
+            reconnect_interval = 5
+            heartbeat_interval = 30
+
+            [[client.connections]]
+            server = "1.2.3.4:7000"
+            services = [{ name = "web", local_port = 80, remote_port = 8080 }]
+
+            [[client.connections]]
+            server = "5.6.7.8:7000"
+            token = "ssh-only-token"
+            services = [{ name = "ssh", local_port = 22, remote_port = 2222 }]
+            "#,
+        )
+        .unwrap();
+        let mut client = config.client.unwrap();
+        assert!(client.validate().is_empty());
+
+        let entries = client.connection_entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].token.as_deref(), Some("shared-token"));
+        assert_eq!(entries[0].services[0].name, "web");
+        assert_eq!(entries[1].token.as_deref(), Some("ssh-only-token"));
+        assert_eq!(entries[1].services[0].remote_port, 2222);
+
+        // mixing with the flat form is rejected
+        client.servers = vec!["9.9.9.9:7000".to_string()];
+        let issues = client.validate();
+        assert!(issues
+            .iter()
+            .any(|issue| issue.path == "client.connections"));
+    }
+
+    #[test]
+    fn test_human_values_and_key_errors() {
+        let write = |content: &str| {
+            let file = tempfile::NamedTempFile::new().unwrap();
+            std::fs::write(file.path(), content).unwrap();
+            file.into_temp_path()
+        };
+        let valid = write(
+            r#"
+            [client]
+            servers = ["1.2.3.4:7000"]
+            token = "test-token"
+            reconnect_interval = "2m30s"
+            heartbeat_interval = 30
+            compression_threshold = "4KiB"
+            "#,
+        );
+        let client = Config::from_file(valid.to_str().unwrap())
+            .unwrap()
+            .client
+            .unwrap();
+        assert_eq!(client.reconnect_interval.0.as_secs(), 150);
+        assert_eq!(client.heartbeat_interval.0.as_secs(), 30);
+        assert_eq!(client.control_write_timeout().0.as_secs(), 60);
+        assert_eq!(client.compression_threshold, HumanBytes(4096));
+        let written = toml::to_string(&client).unwrap();
+        assert!(
+            written.contains(r#"reconnect_interval = "150s""#),
+            "{}",
+            written
+        );
+        assert!(
+            written.contains(r#"compression_threshold = "4KiB""#),
+            "{}",
+            written
+        );
+
+        let invalid = write(
+            r#"
+            [client] # comment
+            servers = ["1.2.3.4:7000"]
+            token = "test-token"
+            reconnect_interval = "5x"
+            heartbeat_interval = 30
+            "#,
+        );
+        let path = invalid.to_str().unwrap();
+        let err = Config::from_file(path).unwrap_err().to_string();
+        assert!(
+            err.starts_with(&format!(
+                "Invalid config {}: invalid value for client.reconnect_interval: Invalid duration unit 'x' in '5x'",
+                path
+            )),
+            "{}",
+            err
+        );
+
+        let invalid =
+            write("[server]\nlisten_addr = \"0.0.0.0\"\ncompression_threshold = \"1.5MB\"\n");
+        let err = Config::from_file(invalid.to_str().unwrap())
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("server.compression_threshold") && err.contains("'1.5MB'"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_redacted_configs_hide_tokens() {
+        let mut server = ServerConfig {
+            token: "server-secret".to_string(),
+            ..ServerConfig::default()
+        };
+        server.tokens.push(TokenConfig {
+            token: "alice-secret".to_string(),
+            name: Some("alice".to_string()),
+            allowed_ports: None,
+        });
+        let client = ClientConfig {
+            token: "client-secret".to_string(),
+            connections: vec![ConnectionConfig {
+                server: "1.2.3.4:7000".to_string(),
+                token: Some("entry-secret".to_string()),
+                services: vec![],
+            }],
+            ..ClientConfig::default()
+        };
+
+        let echoed = format!("{:?} {:?}", server.redacted(), client.redacted());
+        assert!(!echoed.contains("secret"), "{}", echoed);
+        assert!(echoed.contains("alice"));
+        // the originals are untouched
+        assert_eq!(server.token, "server-secret");
+    }
+
+    #[test]
+    fn test_flat_servers_share_services() {
+        let client = ClientConfig {
+            servers: vec!["1.2.3.4:7000".to_string(), "5.6.7.8:7000".to_string()],
+            token: "shared-token".to_string(),
+            services: vec![ServiceConfig::parse_cli("127.0.0.1:80:8080").unwrap()],
+            ..ClientConfig::default()
+        };
+
+        let entries = client.connection_entries();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(
+            |entry| entry.services.len() == 1 && entry.token.as_deref() == Some("shared-token")
+        ));
+    }
+
+    #[test]
+    fn test_parse_cli_service() {
+        let service = ServiceConfig::parse_cli("127.0.0.1:8080:80").unwrap();
+        assert_eq!(service.local_port, 8080);
+        assert_eq!(service.remote_port, 80);
+        assert!(service.bind_host.is_none());
+
+        let service = ServiceConfig::parse_cli("127.0.0.1:8080:80@10.0.0.5").unwrap();
+        assert_eq!(service.remote_port, 80);
+        assert_eq!(service.bind_host.as_deref(), Some("10.0.0.5"));
+
+        assert!(ServiceConfig::parse_cli("127.0.0.1:8080:80@").is_err());
+
+        let service = ServiceConfig::parse_cli("127.0.0.1:8080:80@10.0.0.5/50").unwrap();
+        assert_eq!(service.bind_host.as_deref(), Some("10.0.0.5"));
+        assert_eq!(service.max_connections, Some(50));
+
+        #[cfg(unix)]
+        {
+            let service = ServiceConfig::parse_cli("unix:/run/app.sock:8080@10.0.0.5").unwrap();
+            assert_eq!(service.local_path.as_deref(), Some("/run/app.sock"));
+            assert_eq!(service.remote_port, 8080);
+            assert_eq!(service.local_addr(), "unix:/run/app.sock");
+            assert_eq!(service.bind_host.as_deref(), Some("10.0.0.5"));
+        }
+        #[cfg(not(unix))]
+        assert!(ServiceConfig::parse_cli("unix:/run/app.sock:8080").is_err());
+        assert!(ServiceConfig::parse_cli("127.0.0.1:8080").is_err());
+    }
+
+    #[test]
+    fn test_parse_cli_port_ranges() {
+        let services = ServiceConfig::parse_cli_ranges("127.0.0.1:8000-8002:9000-9002").unwrap();
+        let ports: Vec<_> = services
+            .iter()
+            .map(|service| {
+                (
+                    service.name.as_str(),
+                    service.local_port,
+                    service.remote_port,
+                )
+            })
+            .collect();
+        assert_eq!(
+            ports,
+            [
+                ("127.0.0.1:8000:9000", 8000, 9000),
+                ("127.0.0.1:8001:9001", 8001, 9001),
+                ("127.0.0.1:8002:9002", 8002, 9002),
+            ]
+        );
+
+        // a remote port of 0 lets the server pick a port for each
+        let services = ServiceConfig::parse_cli_ranges("127.0.0.1:8000-8001:0").unwrap();
+        assert!(services.iter().all(|service| service.remote_port == 0));
+        assert_eq!(services.len(), 2);
+
+        assert!(ServiceConfig::parse_cli_ranges("127.0.0.1:8000-8002:9000-9001").is_err());
+        assert!(ServiceConfig::parse_cli_ranges("127.0.0.1:8000-70000:0").is_err());
+        assert!(ServiceConfig::parse_cli_ranges("127.0.0.1:8002-8000:0").is_err());
+        let err = ServiceConfig::parse_cli("127.0.0.1:8000-8001:0").unwrap_err();
+        assert!(err.to_string().contains("port range"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_cli_short_forms() {
+        let service = ServiceConfig::parse_cli("3000:8080").unwrap();
+        assert_eq!(service.local_ip, "127.0.0.1");
+        assert_eq!(service.local_port, 3000);
+        assert_eq!(service.remote_port, 8080);
+
+        let service = ServiceConfig::parse_cli("3000").unwrap();
+        assert_eq!(service.local_addr(), "127.0.0.1:3000");
+        assert_eq!(service.remote_port, 3000);
+
+        let service = ServiceConfig::parse_cli("3000:0@10.0.0.5/20").unwrap();
+        assert_eq!(service.remote_port, 0);
+        assert_eq!(service.bind_host.as_deref(), Some("10.0.0.5"));
+        assert_eq!(service.max_connections, Some(20));
+
+        // the connection limit does not need a bind host
+        let service = ServiceConfig::parse_cli("127.0.0.1:8080:80/50").unwrap();
+        assert_eq!(service.remote_port, 80);
+        assert_eq!(service.max_connections, Some(50));
+
+        let services = ServiceConfig::parse_cli_ranges("8000-8001").unwrap();
+        let ports: Vec<_> = services
+            .iter()
+            .map(|service| (service.local_port, service.remote_port))
+            .collect();
+        assert_eq!(ports, [(8000, 8000), (8001, 8001)]);
+
+        for (malformed, message) in [
+            ("", "expected local_port:remote_port, got ''"),
+            (
+                "localhost",
+                "expected local_port:remote_port, got 'localhost'",
+            ),
+            (
+                "127.0.0.1:8080",
+                "expected local_port:remote_port, got '127.0.0.1:8080'",
+            ),
+            (
+                "3000:web",
+                "expected local_port:remote_port, got '3000:web'",
+            ),
+            (
+                "[::1]:8080",
+                "expected local_port:remote_port, got '[::1]:8080'",
+            ),
+            ("1:2:3:4", "expected local_port:remote_port, got '1:2:3:4'"),
+            ("127.0.0.1:http:80", "Invalid port 'http'"),
+            ("[::1:8080:80", "unclosed '['"),
+            ("[::1]8080:80", "expected ':' after '[::1]'"),
+            (":8080:80", "Empty local IP"),
+        ] {
+            let err = ServiceConfig::parse_cli(malformed).unwrap_err().to_string();
+            assert!(err.contains(message), "{}: {}", malformed, err);
+        }
+    }
+
+    #[test]
+    fn test_parse_cli_ipv6_and_hostnames() {
+        let service = ServiceConfig::parse_cli("[::1]:8080:80@[::]").unwrap();
+        assert_eq!(service.local_ip, "::1");
+        assert_eq!(service.local_port, 8080);
+        assert_eq!(service.remote_port, 80);
+        assert_eq!(service.local_addr(), "[::1]:8080");
+        assert_eq!(service.bind_host.as_deref(), Some("[::]"));
+
+        let service = ServiceConfig::parse_cli("db.internal:5432:15432").unwrap();
+        assert_eq!(service.local_ip, "db.internal");
+        assert_eq!(service.local_addr(), "db.internal:5432");
+
+        for malformed in [
+            "::1:8080:80",
+            "[::1:8080:80",
+            "[::1]8080:80",
+            "[db.internal]:8080:80",
+            "[::1]:8080",
+            ":8080:80",
+            "127.0.0.1:8080:80:90",
+        ] {
+            assert!(
+                ServiceConfig::parse_cli(malformed).is_err(),
+                "{} should be rejected",
+                malformed
+            );
+        }
+    }
+    #[test]
+    fn test_parse_cli_relay() {
+        let relay = RelayConfig::parse_cli("laptop-b:ssh@2222").unwrap();
+        assert_eq!(relay.client, "laptop-b");
+        assert_eq!(relay.service, "ssh");
+        assert_eq!(relay.bind_addr, "127.0.0.1:2222");
+        assert!(relay.server.is_none());
+
+        let relay = RelayConfig::parse_cli("laptop-b:web@0.0.0.0:8080").unwrap();
+        assert_eq!(relay.bind_addr, "0.0.0.0:8080");
+
+        for malformed in [
+            "laptop-b:ssh",
+            "ssh@2222",
+            ":ssh@2222",
+            "laptop-b:@2222",
+            "a:b@host",
+        ] {
+            assert!(
+                RelayConfig::parse_cli(malformed).is_err(),
+                "{} should be rejected",
+                malformed
+            );
+        }
     }
 }
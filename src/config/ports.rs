@@ -0,0 +1,291 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// Inclusive range of ports, written as `8000-8100` or a single `8080`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    /// First port of the range
+    pub start: u16,
+    /// Last port of the range
+    pub end: u16,
+}
+
+impl PortRange {
+    /// Whether `port` is in the range
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+
+    /// Iterates over every port in the range
+    pub fn ports(&self) -> impl Iterator<Item = u16> {
+        self.start..=self.end
+    }
+
+    /// Number of ports in the range
+    pub fn count(&self) -> usize {
+        usize::from(self.end - self.start) + 1
+    }
+}
+
+/// The (local, remote) port of each service a ranged service expands into: ranges of
+/// equal length paired port by port, or each local port with remote port 0 for the
+/// server to pick
+pub fn pair_ranges(local: PortRange, remote: PortRange) -> Result<Vec<(u16, u16)>> {
+    if remote.start == 0 && remote.end == 0 {
+        return Ok(local.ports().map(|port| (port, 0)).collect());
+    }
+    if local.count() != remote.count() {
+        return Err(anyhow!(
+            "Local ports {} and remote ports {} differ in length ({} and {})",
+            local,
+            remote,
+            local.count(),
+            remote.count()
+        ));
+    }
+    Ok(local.ports().zip(remote.ports()).collect())
+}
+
+/// Deserializes a port number, also given as a string where a range was expanded
+pub(super) fn deserialize_port<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<u16, D::Error> {
+    struct PortVisitor;
+
+    impl serde::de::Visitor<'_> for PortVisitor {
+        type Value = u16;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a port number")
+        }
+
+        fn visit_i64<E: serde::de::Error>(self, value: i64) -> Result<u16, E> {
+            u16::try_from(value).map_err(|_| {
+                E::invalid_value(serde::de::Unexpected::Signed(value), &"a port number")
+            })
+        }
+
+        fn visit_u64<E: serde::de::Error>(self, value: u64) -> Result<u16, E> {
+            u16::try_from(value).map_err(|_| {
+                E::invalid_value(serde::de::Unexpected::Unsigned(value), &"a port number")
+            })
+        }
+
+        fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<u16, E> {
+            let range: PortRange = value.parse().map_err(E::custom)?;
+            match range.count() {
+                1 => Ok(range.start),
+                _ => Err(E::custom(format!(
+                    "port range '{}' is only allowed in [[client.services]]",
+                    range
+                ))),
+            }
+        }
+    }
+
+    deserializer.deserialize_any(PortVisitor)
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_string().serialize(serializer)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let parse = |p: &str| {
+            p.trim()
+                .parse::<u16>()
+                .map_err(|_| anyhow!("Invalid port '{}' in '{}'", p.trim(), s))
+        };
+
+        let range = match s.split_once('-') {
+            Some((start, end)) => PortRange {
+                start: parse(start)?,
+                end: parse(end)?,
+            },
+            None => {
+                let port = parse(s)?;
+                PortRange {
+                    start: port,
+                    end: port,
+                }
+            }
+        };
+
+        if range.start > range.end {
+            return Err(anyhow!("Invalid port range '{}': start is after end", s));
+        }
+        Ok(range)
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+/// Set of ports made of single ports and ranges.
+/// In TOML it may be a port, a range string, or a list of both:
+/// `"8000-8100"`, `8080`, `["8000-8999", "10443", 22]`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PortSet {
+    ranges: Vec<PortRange>,
+}
+
+impl PortSet {
+    /// Whether `port` is in any of the ranges
+    pub fn contains(&self, port: u16) -> bool {
+        self.ranges.iter().any(|range| range.contains(port))
+    }
+
+    /// Iterates over every port in the set, in the order the ranges were given
+    pub fn ports(&self) -> impl Iterator<Item = u16> + '_ {
+        self.ranges.iter().flat_map(PortRange::ports)
+    }
+}
+
+impl FromStr for PortSet {
+    type Err = anyhow::Error;
+
+    /// Parses a comma separated list of ports and ranges
+    fn from_str(s: &str) -> Result<Self> {
+        let ranges = s
+            .split(',')
+            .filter(|part| !part.trim().is_empty())
+            .map(PortRange::from_str)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(PortSet { ranges })
+    }
+}
+
+impl fmt::Display for PortSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = self.ranges.iter().map(|r| r.to_string()).collect();
+        write!(f, "{}", parts.join(","))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortSpec {
+    Port(u16),
+    Text(String),
+}
+
+impl PortSpec {
+    fn into_ranges(self) -> Result<Vec<PortRange>> {
+        match self {
+            PortSpec::Port(port) => Ok(vec![PortRange {
+                start: port,
+                end: port,
+            }]),
+            PortSpec::Text(text) => Ok(text.parse::<PortSet>()?.ranges),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PortSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            One(PortSpec),
+            Many(Vec<PortSpec>),
+        }
+
+        let specs = match Repr::deserialize(deserializer)? {
+            Repr::One(spec) => vec![spec],
+            Repr::Many(specs) => specs,
+        };
+
+        let mut ranges = Vec::new();
+        for spec in specs {
+            ranges.extend(spec.into_ranges().map_err(serde::de::Error::custom)?);
+        }
+        Ok(PortSet { ranges })
+    }
+}
+
+impl Serialize for PortSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let parts: Vec<String> = self.ranges.iter().map(|r| r.to_string()).collect();
+        parts.serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Wrapper {
+        ports: PortSet,
+    }
+
+    fn parse_toml(value: &str) -> PortSet {
+        toml::from_str::<Wrapper>(&format!("ports = {}", value))
+            .unwrap()
+            .ports
+    }
+
+    #[test]
+    fn test_port_set_forms() {
+        let set = parse_toml(r#""8000-8100""#);
+        assert!(set.contains(8000) && set.contains(8100));
+        assert!(!set.contains(7999) && !set.contains(8101));
+
+        let set = parse_toml("8080");
+        assert!(set.contains(8080) && !set.contains(8081));
+
+        let set = parse_toml(r#"["8000-8999", "10443", 22]"#);
+        assert!(set.contains(8500) && set.contains(10443) && set.contains(22));
+        assert!(!set.contains(9000));
+        assert_eq!(set.to_string(), "8000-8999,10443,22");
+    }
+
+    #[test]
+    fn test_ranges_pair_port_by_port() {
+        let range = |s: &str| s.parse::<PortRange>().unwrap();
+        assert_eq!(
+            pair_ranges(range("8000-8002"), range("9000-9002")).unwrap(),
+            vec![(8000, 9000), (8001, 9001), (8002, 9002)]
+        );
+        assert_eq!(
+            pair_ranges(range("8000-8001"), range("0")).unwrap(),
+            vec![(8000, 0), (8001, 0)]
+        );
+        let err = pair_ranges(range("8000-8010"), range("9000-9005")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Local ports 8000-8010 and remote ports 9000-9005 differ in length (11 and 6)"
+        );
+        assert_eq!(range("65535").count(), 1);
+        assert_eq!(range("0-65535").count(), 65536);
+    }
+
+    #[test]
+    fn test_port_set_rejects_malformed() {
+        assert!(toml::from_str::<Wrapper>(r#"ports = "9000-8000""#).is_err());
+        assert!(toml::from_str::<Wrapper>(r#"ports = "80-http""#).is_err());
+        assert!(toml::from_str::<Wrapper>(r#"ports = ["70000"]"#).is_err());
+    }
+}
@@ -0,0 +1,499 @@
+use anyhow::{anyhow, Result};
+use rand::RngCore;
+use serde::Serialize;
+use std::fmt::Write;
+
+use super::{ClientConfig, ServerConfig};
+
+/// A documented key of a generated config
+pub(super) struct Key {
+    pub(super) name: &'static str,
+    comment: &'static str,
+    /// Commented-out example for keys unset by default, or a whole table for sections
+    pub(super) example: Option<&'static str>,
+}
+
+const fn key(name: &'static str, comment: &'static str) -> Key {
+    Key {
+        name,
+        comment,
+        example: None,
+    }
+}
+
+const fn optional(name: &'static str, comment: &'static str, example: &'static str) -> Key {
+    Key {
+        name,
+        comment,
+        example: Some(example),
+    }
+}
+
+/// Keys of the `[server]` table, in the order they are written
+pub(super) const SERVER_KEYS: &[Key] = &[
+    optional("name", "Name shown to clients and in logs", r#""main-server""#),
+    key("listen_addr", "Address of the control port clients connect to"),
+    key(
+        "bind_host",
+        "Host proxy listeners bind on, unless a service asks for one of allowed_bind_hosts",
+    ),
+    key("token", "Shared secret clients authenticate with, keep it private"),
+    key("max_clients", "Maximum number of connected clients"),
+    key(
+        "max_connections",
+        "Connections held open at once, more are closed when accepted; 0 is unlimited",
+    ),
+    key(
+        "workers",
+        "Runtime worker threads: 1 runs on a single thread, 0 starts one per core",
+    ),
+    key(
+        "reuseport",
+        "Let another server process bind the same ports, e.g. during upgrades (Linux, BSD)",
+    ),
+    optional("log_file", "Log file path", r#""/var/log/sowback.log""#),
+    optional(
+        "log_level",
+        "Maximum log level: error, warn, info, debug or trace",
+        r#""info""#,
+    ),
+    optional("log_filter", "Per-target log levels", r#""sowback::server=debug""#),
+    key(
+        "log_target",
+        "Where logs go besides the console: file, or syslog as well (Unix)",
+    ),
+    optional(
+        "syslog_addr",
+        "Syslog daemon: journald, a socket path, udp://host:port or tcp://host:port",
+        r#""udp://logs.example.com:514""#,
+    ),
+    optional(
+        "access_log",
+        "JSON lines log of proxy connections, reopened on SIGHUP or SIGUSR1",
+        r#""/var/log/sowback-access.log""#,
+    ),
+    key(
+        "compression",
+        "Preferred compression codec for tunneled data: none or zstd",
+    ),
+    key(
+        "compression_threshold",
+        "Minimum payload size before compression is attempted, e.g. \"1KiB\"",
+    ),
+    optional(
+        "port_range",
+        "Ports to pick from when a service asks for remote_port = 0",
+        r#""20000-20999""#,
+    ),
+    optional(
+        "allowed_ports",
+        "Remote ports clients may claim, any port when unset",
+        r#"["8000-8999", "10443"]"#,
+    ),
+    key("allowed_bind_hosts", "Hosts services may bind instead of bind_host"),
+    optional(
+        "http_port",
+        "Port shared by HTTP services, routed by the Host header",
+        "80",
+    ),
+    optional(
+        "sni_port",
+        "Port shared by TLS services, routed by SNI without terminating TLS",
+        "443",
+    ),
+    key(
+        "stats_interval",
+        "Minutes between per-proxy traffic summaries, 0 disables them",
+    ),
+    key(
+        "idle_timeout",
+        "Close proxy connections idle this long, 0 never does",
+    ),
+    key(
+        "max_buffered_per_connection",
+        "Close a proxy connection whose peer leaves this much data unread, 0 is unlimited",
+    ),
+    key(
+        "max_connections_per_ip_per_minute",
+        "New proxy connections each peer IP may open per minute, 0 is unlimited",
+    ),
+    key(
+        "connect_timeout",
+        "How long a proxy connection waits for the client to reach its local service",
+    ),
+    key("tcp_nodelay", "Disable Nagle's algorithm for lower latency"),
+    key(
+        "tcp_keepalive",
+        "Idle time before TCP keepalive probes, 0 disables them",
+    ),
+    key(
+        "control_write_timeout",
+        "Take a client as gone once a control write stalls this long, 0 leaves it to the OS",
+    ),
+    key(
+        "duplicate_client_policy",
+        "A client ID that is already connected: reject the new session, or replace the old one",
+    ),
+    key(
+        "auth_fail_limit",
+        "Failed authentications from one IP within auth_fail_window before it is banned, 0 never bans",
+    ),
+    key(
+        "auth_fail_window",
+        "Window authentication failures are counted in",
+    ),
+    key("auth_ban_duration", "How long a banned IP is refused"),
+    key(
+        "listener_grace_period",
+        "How long a disconnected client's ports and connections stay reserved for it to reconnect, 0 frees them at once",
+    ),
+    key(
+        "orphan_connection_policy",
+        "Connections to a reserved port while its client is away: refuse them, or queue them for it",
+    ),
+    key(
+        "orphan_queue_size",
+        "Connections each reserved port queues with the queue policy, more are refused",
+    ),
+    key(
+        "orphan_queue_timeout",
+        "How long a queued connection waits for its client before it is refused",
+    ),
+    key(
+        "drain_timeout",
+        "How long the old process keeps serving its connections after an upgrade on SIGUSR2",
+    ),
+    key(
+        "assign_client_ids",
+        "Give each session a server-picked client ID instead of the one the client sent",
+    ),
+    key(
+        "reveal_port_owner",
+        "Name the client holding a port when another client's registration is rejected for it",
+    ),
+    key(
+        "allow_relay",
+        "Let clients register relay services, reached by the clients in their relay_peers",
+    ),
+    optional(
+        "min_client_version",
+        "Reject clients running an older sowback version",
+        r#""0.2.0""#,
+    ),
+    key(
+        "data_channels",
+        "Let clients carry each proxied connection on a connection of its own to this port",
+    ),
+    optional(
+        "quic_listen_addr",
+        "UDP address QUIC clients connect to, with the certificate of [server.tls]",
+        r#""0.0.0.0:7000""#,
+    ),
+    optional(
+        "websocket_listen_addr",
+        "Address WebSocket clients connect to, over TLS with [server.tls]",
+        r#""0.0.0.0:7080""#,
+    ),
+    optional(
+        "webhooks",
+        "POST client and proxy events as JSON, optionally signed with secret",
+        r#"[server.webhooks]
+url = "https://hooks.example.com/sowback"
+events = ["client_authenticated", "client_disconnected", "proxy_registered", "listener_bind_failed", "auth_failure_burst"]
+secret = "shared-with-the-receiver""#,
+    ),
+    optional(
+        "admin",
+        "HTTP API to kick clients, close connections and ban peers, see sowback admin",
+        r#"[server.admin]
+listen_addr = "127.0.0.1:7001"
+tokens = [{ name = "alice", token = "admin-secret" }]"#,
+    ),
+    optional(
+        "tokens",
+        "Additional named tokens, each optionally restricted to some ports",
+        r#"[[server.tokens]]
+token = "another-secret"
+name = "alice"
+allowed_ports = "8000-8100""#,
+    ),
+    optional(
+        "tls",
+        "Serve the control port over TLS, set client_ca to require client certificates",
+        r#"[server.tls]
+cert = "/etc/sowback/cert.pem"
+key = "/etc/sowback/key.pem""#,
+    ),
+];
+
+/// Keys of the `[client]` table, in the order they are written
+pub(super) const CLIENT_KEYS: &[Key] = &[
+    optional("name", "Name shown in server logs", r#""web-client""#),
+    key("servers", "Servers to connect to, each gets every service"),
+    key("token", "Shared secret, must match a token of the servers"),
+    key("reconnect_interval", "Wait between reconnect attempts, e.g. \"5s\""),
+    key(
+        "max_reconnect_attempts",
+        "Give a server up after this many failed attempts in a row, 0 never does",
+    ),
+    key("heartbeat_interval", "Time between heartbeats, e.g. \"30s\""),
+    key(
+        "heartbeat_max_missed",
+        "Reconnect after this many unanswered heartbeats, 0 never does",
+    ),
+    key(
+        "workers",
+        "Runtime worker threads: 1 runs on a single thread, 0 starts one per core",
+    ),
+    optional(
+        "log_file",
+        "Log file path",
+        r#""/var/log/sowback-client.log""#,
+    ),
+    optional(
+        "log_level",
+        "Maximum log level: error, warn, info, debug or trace",
+        r#""info""#,
+    ),
+    optional(
+        "log_filter",
+        "Per-target log levels",
+        r#""sowback::client=debug""#,
+    ),
+    key(
+        "log_target",
+        "Where logs go besides the console: file, or syslog as well (Unix)",
+    ),
+    optional(
+        "syslog_addr",
+        "Syslog daemon: journald, a socket path, udp://host:port or tcp://host:port",
+        r#""udp://logs.example.com:514""#,
+    ),
+    key(
+        "compression",
+        "Compression codec to request: none or zstd, the server decides",
+    ),
+    key(
+        "compression_threshold",
+        "Minimum payload size before compression is attempted, e.g. \"1KiB\"",
+    ),
+    key(
+        "idle_timeout",
+        "Close local connections idle this long, 0 never does",
+    ),
+    key(
+        "max_buffered_per_connection",
+        "Close a local connection whose service leaves this much data unread, 0 is unlimited",
+    ),
+    key("tcp_nodelay", "Disable Nagle's algorithm for lower latency"),
+    key(
+        "tcp_keepalive",
+        "Idle time before TCP keepalive probes, 0 disables them",
+    ),
+    optional(
+        "control_write_timeout",
+        "Take the server as gone once a control write stalls this long, twice heartbeat_interval by default",
+        r#""60s""#,
+    ),
+    key(
+        "service_register_timeout",
+        "Resend service registrations the server leaves unanswered this long, 0 never does",
+    ),
+    key(
+        "service_register_retries",
+        "Resends before an unanswered service is given up",
+    ),
+    key(
+        "transport",
+        "Carrier of the server connection: tcp, websocket to pass HTTP-only networks, or quic",
+    ),
+    key(
+        "data_channels",
+        "Carry each proxied connection on a connection of its own if the server allows it",
+    ),
+    key(
+        "status_interval",
+        "Print the table of servers and services this often, 0 only when a server connects or disconnects",
+    ),
+    optional(
+        "control_socket",
+        "Unix socket answering `sowback ctl` status, reload and service changes, for this user only",
+        r#""/run/sowback/client.sock""#,
+    ),
+    key(
+        "renew",
+        "Register services with a ttl again once it runs out, instead of leaving them expired",
+    ),
+    key(
+        "health_check_interval",
+        "Check the local services of connected servers this often, 0 only before registering them",
+    ),
+    key(
+        "connect_timeout",
+        "Give up reaching a server after this long, 0 leaves it to the OS",
+    ),
+    key(
+        "local_connect_timeout",
+        "Fail connections whose local service does not accept within this long, 0 leaves it to the OS",
+    ),
+    optional(
+        "services",
+        "Services to expose, remote_port = 0 lets the server pick the port",
+        r#"[[client.services]]
+name = "web"
+local_ip = "127.0.0.1"
+local_port = 80
+remote_port = 8080"#,
+    ),
+    optional(
+        "connections",
+        "Per-server tokens and services, instead of servers and services",
+        r#"[[client.connections]]
+server = "backup.example.com:7000"
+token = "other-secret"
+services = [{ name = "ssh", local_port = 22, remote_port = 2222 }]"#,
+    ),
+    optional(
+        "visitors",
+        "Local ports reaching services other clients registered with a secret",
+        r#"[[client.visitors]]
+service = "ssh"
+secret = "shared-with-the-owner"
+bind_addr = "127.0.0.1:2222""#,
+    ),
+    optional(
+        "relays",
+        "Local ports reaching the relay services of other clients naming this one a peer",
+        r#"[[client.relays]]
+client = "laptop-b"
+service = "ssh"
+bind_addr = "127.0.0.1:2223""#,
+    ),
+    optional(
+        "tls",
+        "Connect to servers over TLS",
+        r#"[client.tls]
+enable = true
+ca = "/etc/sowback/ca.pem""#,
+    ),
+];
+
+/// A random token, well beyond the length `check` asks for
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// A commented starter `[server]` config holding `token`
+pub fn server_template(token: &str) -> Result<String> {
+    let config = ServerConfig {
+        token: token.to_string(),
+        ..ServerConfig::default()
+    };
+    render("server", &config, SERVER_KEYS, false)
+}
+
+/// A commented starter `[client]` config holding `token`
+pub fn client_template(token: &str) -> Result<String> {
+    let config = ClientConfig {
+        servers: vec!["server.example.com:7000".to_string()],
+        token: token.to_string(),
+        ..ClientConfig::default()
+    };
+    render("client", &config, CLIENT_KEYS, false)
+}
+
+/// Writes `config` as the `[section]` table, each key preceded by its comment.
+/// Keys unset in `config` are written as commented examples, or live ones with `examples`
+fn render(section: &str, config: &impl Serialize, keys: &[Key], examples: bool) -> Result<String> {
+    let toml::Value::Table(table) = toml::Value::try_from(config)? else {
+        return Err(anyhow!("{} config is not a table", section));
+    };
+    // a field without a key here would silently be left out
+    if let Some(name) = table
+        .keys()
+        .find(|name| !keys.iter().any(|k| k.name == *name))
+    {
+        return Err(anyhow!("{}.{} is not documented", section, name));
+    }
+    let commented = if examples { "" } else { "# " };
+
+    let mut out = format!("[{}]\n", section);
+    // a multi-line example is a whole table
+    let (sections, values): (Vec<&Key>, Vec<&Key>) = keys
+        .iter()
+        .partition(|key| key.example.is_some_and(|e| e.contains('\n')));
+    for key in values {
+        writeln!(out, "\n# {}", key.comment)?;
+        match table.get(key.name) {
+            Some(value) => writeln!(out, "{} = {}", key.name, value)?,
+            None => writeln!(
+                out,
+                "{}{} = {}",
+                commented,
+                key.name,
+                key.example.unwrap_or_default()
+            )?,
+        }
+    }
+    // tables go last, after them every key belongs to the table
+    for key in sections {
+        writeln!(out, "\n# {}", key.comment)?;
+        for line in key.example.unwrap_or_default().lines() {
+            writeln!(out, "{}{}", commented, line)?;
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn load(content: &str) -> Config {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), content).unwrap();
+        Config::from_file(file.path().to_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_templates_round_trip() {
+        let token = generate_token();
+        assert_eq!(token.len(), 64);
+
+        let server = load(&server_template(&token).unwrap()).server.unwrap();
+        assert_eq!(server.token, token);
+        assert!(server.validate().iter().all(|issue| !issue.is_error()));
+
+        let client = load(&client_template(&token).unwrap()).client.unwrap();
+        assert_eq!(client.token, token);
+        assert!(client.validate().iter().all(|issue| !issue.is_error()));
+    }
+
+    #[test]
+    fn test_every_example_is_a_real_key() {
+        // with every example live, each documented key must survive parsing
+        let server = ServerConfig {
+            token: generate_token(),
+            ..ServerConfig::default()
+        };
+        let content = render("server", &server, SERVER_KEYS, true).unwrap();
+        let parsed = toml::Value::try_from(load(&content).server.unwrap()).unwrap();
+        for key in SERVER_KEYS {
+            assert!(parsed.get(key.name).is_some(), "server.{}", key.name);
+        }
+
+        let client = ClientConfig {
+            token: generate_token(),
+            ..ClientConfig::default()
+        };
+        let content = render("client", &client, CLIENT_KEYS, true).unwrap();
+        let mut parsed: Config = toml::from_str(&content).unwrap();
+        let parsed = toml::Value::try_from(parsed.client.take().unwrap()).unwrap();
+        for key in CLIENT_KEYS {
+            assert!(parsed.get(key.name).is_some(), "client.{}", key.name);
+        }
+    }
+}
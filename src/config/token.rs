@@ -0,0 +1,101 @@
+use anyhow::{anyhow, Result};
+use std::fs;
+
+/// Environment variable the token is read from when neither `--token` nor `--token-file` is given
+pub const TOKEN_ENV: &str = "SOWBACK_TOKEN";
+
+/// Reads a token from `path`, without its trailing newline. On Unix the file must not
+/// be readable by other users, like an SSH key
+pub fn read_token_file(path: &str) -> Result<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path)
+            .map_err(|e| anyhow!("Failed to read token file {}: {}", path, e))?
+            .permissions()
+            .mode();
+        if mode & 0o004 != 0 {
+            return Err(anyhow!(
+                "Token file {} is readable by every user, restrict it with chmod 600",
+                path
+            ));
+        }
+    }
+
+    let content = fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read token file {}: {}", path, e))?;
+    let token = content.trim_end_matches(['\r', '\n']);
+    if token.is_empty() {
+        return Err(anyhow!("Token file {} is empty", path));
+    }
+    Ok(token.to_string())
+}
+
+/// Picks the token given outside the config file: the `--token` flag first, then
+/// `--token-file`, then the `SOWBACK_TOKEN` variable. None leaves the config's token
+pub fn resolve_token(
+    flag: Option<String>,
+    file: Option<&str>,
+    env: Option<String>,
+) -> Result<Option<String>> {
+    if flag.is_some() {
+        return Ok(flag);
+    }
+    if let Some(path) = file {
+        return read_token_file(path).map(Some);
+    }
+    Ok(env.filter(|token| !token.is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token_file(content: &str, mode: u32) -> tempfile::TempPath {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), content).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(file.path(), fs::Permissions::from_mode(mode)).unwrap();
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+        file.into_temp_path()
+    }
+
+    #[test]
+    fn test_token_precedence() {
+        let path = token_file("from-file\n", 0o600);
+        let file = Some(path.to_str().unwrap());
+        let env = || Some("from-env".to_string());
+
+        let flag = resolve_token(Some("from-flag".to_string()), file, env()).unwrap();
+        assert_eq!(flag.as_deref(), Some("from-flag"));
+        assert_eq!(
+            resolve_token(None, file, env()).unwrap().as_deref(),
+            Some("from-file")
+        );
+        assert_eq!(
+            resolve_token(None, None, env()).unwrap().as_deref(),
+            Some("from-env")
+        );
+        // nothing given, the config file's token stays
+        assert_eq!(
+            resolve_token(None, None, Some(String::new())).unwrap(),
+            None
+        );
+        assert_eq!(resolve_token(None, None, None).unwrap(), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_world_readable_token_file_is_refused() {
+        let path = token_file("secret\n", 0o644);
+        let error = read_token_file(path.to_str().unwrap()).unwrap_err();
+        assert!(error.to_string().contains("readable by every user"));
+
+        let empty = token_file("\n", 0o600);
+        assert!(read_token_file(empty.to_str().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,946 @@
+use colored::Colorize;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+
+use super::{
+    AuthMode, ClientConfig, Config, HumanDuration, OrphanConnectionPolicy, ServerConfig,
+    ServiceConfig, Transport,
+};
+use crate::logging::syslog::SyslogAddr;
+use crate::logging::LogTarget;
+use crate::utils::webhook::WebhookUrl;
+use crate::utils::{net, tls};
+
+/// Tokens shorter than this are reported as trivially guessable
+const MIN_TOKEN_LEN: usize = 8;
+
+/// Intervals longer than this are reported as suspicious
+const MAX_SANE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How serious a configuration problem is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The configuration cannot be used
+    Error,
+    /// The configuration works but is probably not what was intended
+    Warning,
+}
+
+/// A single configuration problem, located by its TOML path
+#[derive(Debug, Clone)]
+pub struct ConfigIssue {
+    /// Whether the configuration can be used despite the issue
+    pub severity: Severity,
+    /// e.g. `client.services[1].local_port`
+    pub path: String,
+    /// What is wrong
+    pub message: String,
+}
+
+impl ConfigIssue {
+    fn error(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+
+    fn warning(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+
+    /// Whether the issue makes the configuration unusable
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error".red(),
+            Severity::Warning => "warning".yellow(),
+        };
+        write!(f, "{}: {}: {}", severity, self.path.cyan(), self.message)
+    }
+}
+
+impl Config {
+    /// Validates every section present in the configuration
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues: Vec<ConfigIssue> = self
+            .ignored_keys
+            .iter()
+            .map(|key| {
+                ConfigIssue::warning(key, "unknown key, ignored as the config is not strict")
+            })
+            .collect();
+        if let Some(server) = &self.server {
+            issues.extend(server.validate());
+        }
+        if let Some(client) = &self.client {
+            issues.extend(client.validate());
+        }
+        if self.server.is_none() && self.client.is_none() {
+            issues.push(ConfigIssue::error(
+                "(root)",
+                "neither a [server] nor a [client] section is present",
+            ));
+        }
+        issues
+    }
+}
+
+impl ServerConfig {
+    /// Semantic validation beyond what deserialization checks
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+
+        if self.auth_mode() != AuthMode::Cert && self.token_entries().is_empty() {
+            issues.push(ConfigIssue::error("server.token", "a token is required"));
+        }
+        if !self.token.is_empty() {
+            check_token_strength("server.token", &self.token, &mut issues);
+        }
+        for (index, entry) in self.tokens.iter().enumerate() {
+            let path = format!("server.tokens[{}].token", index);
+            if entry.token.is_empty() {
+                issues.push(ConfigIssue::error(path, "token must not be empty"));
+            } else {
+                check_token_strength(&path, &entry.token, &mut issues);
+            }
+        }
+
+        check_listen_addr("server.listen_addr", &self.listen_addr, &mut issues);
+        check_bind_host("server.bind_host", &self.bind_host, &mut issues);
+        for (index, host) in self.allowed_bind_hosts.iter().enumerate() {
+            check_bind_host(
+                &format!("server.allowed_bind_hosts[{}]", index),
+                host,
+                &mut issues,
+            );
+        }
+        for (field, port) in [("http_port", self.http_port), ("sni_port", self.sni_port)] {
+            let Some(port) = port else {
+                continue;
+            };
+            if port == 0 || self.listen_port() == Some(port) {
+                issues.push(ConfigIssue::error(
+                    format!("server.{}", field),
+                    format!(
+                        "{} cannot be used, it must differ from the control port",
+                        port
+                    ),
+                ));
+            }
+        }
+        if self.http_port.is_some() && self.http_port == self.sni_port {
+            issues.push(ConfigIssue::error(
+                "server.sni_port",
+                "must differ from server.http_port",
+            ));
+        }
+        if self.reuseport && !net::REUSE_PORT_SUPPORTED {
+            issues.push(ConfigIssue::error(
+                "server.reuseport",
+                "SO_REUSEPORT is only available on Linux and the BSDs",
+            ));
+        }
+        if self.max_clients == 0 {
+            issues.push(ConfigIssue::error(
+                "server.max_clients",
+                "must allow at least one client",
+            ));
+        }
+        if self.orphan_connection_policy == OrphanConnectionPolicy::Queue {
+            if self.orphan_queue_size == 0 {
+                issues.push(ConfigIssue::error(
+                    "server.orphan_queue_size",
+                    "must hold at least one connection, or use the refuse policy",
+                ));
+            }
+            if self.orphan_queue_timeout.0.is_zero() {
+                issues.push(ConfigIssue::error(
+                    "server.orphan_queue_timeout",
+                    "must be longer than 0, or use the refuse policy",
+                ));
+            }
+        }
+        if let Some(addr) = &self.quic_listen_addr {
+            if let Err(e) = addr.parse::<SocketAddr>() {
+                issues.push(ConfigIssue::error(
+                    "server.quic_listen_addr",
+                    format!("'{}' is not a socket address: {}", addr, e),
+                ));
+            }
+            if self.tls.is_none() {
+                issues.push(ConfigIssue::error(
+                    "server.quic_listen_addr",
+                    "QUIC needs a certificate, set server.tls",
+                ));
+            }
+            if !cfg!(feature = "quic") {
+                issues.push(ConfigIssue::error(
+                    "server.quic_listen_addr",
+                    "this build has no QUIC support, rebuild with --features quic",
+                ));
+            }
+        }
+
+        if let Some(webhooks) = &self.webhooks {
+            if let Err(e) = WebhookUrl::parse(&webhooks.url) {
+                issues.push(ConfigIssue::error("server.webhooks.url", e.to_string()));
+            }
+            if webhooks.events.as_ref().is_some_and(Vec::is_empty) {
+                issues.push(ConfigIssue::warning(
+                    "server.webhooks.events",
+                    "no event is sent, leave events out to send every event",
+                ));
+            }
+            if webhooks.secret.as_deref() == Some("") {
+                issues.push(ConfigIssue::error(
+                    "server.webhooks.secret",
+                    "must not be empty",
+                ));
+            }
+        }
+
+        if let Some(admin) = &self.admin {
+            check_listen_addr("server.admin.listen_addr", &admin.listen_addr, &mut issues);
+            if admin.tokens.is_empty() {
+                issues.push(ConfigIssue::error(
+                    "server.admin.tokens",
+                    "at least one token is required, the API is never served unauthenticated",
+                ));
+            }
+            let mut names = HashSet::new();
+            for (index, entry) in admin.tokens.iter().enumerate() {
+                let path = format!("server.admin.tokens[{}]", index);
+                if entry.name.is_empty() {
+                    issues.push(ConfigIssue::error(
+                        format!("{}.name", path),
+                        "must not be empty, it is logged with every action",
+                    ));
+                } else if !names.insert(entry.name.as_str()) {
+                    issues.push(ConfigIssue::error(
+                        format!("{}.name", path),
+                        format!("'{}' is used by another token", entry.name),
+                    ));
+                }
+                if entry.token.len() < MIN_TOKEN_LEN {
+                    issues.push(ConfigIssue::error(
+                        format!("{}.token", path),
+                        format!("must be at least {} characters", MIN_TOKEN_LEN),
+                    ));
+                }
+            }
+        }
+
+        check_log_file("server.log_file", self.log_file.as_deref(), &mut issues);
+        check_log_file("server.access_log", self.access_log.as_deref(), &mut issues);
+        check_log_filter("server.log_filter", self.log_filter.as_deref(), &mut issues);
+        check_syslog(
+            "server",
+            self.log_target,
+            self.syslog_addr.as_deref(),
+            &mut issues,
+        );
+        issues
+    }
+}
+
+impl ServerConfig {
+    /// Tries binding `listen_addr` before the server starts, for a port taken by another
+    /// process to be reported rather than failing the start. Skipped with `reuseport`,
+    /// where other processes share the port on purpose
+    pub fn check_listen(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+        if self.reuseport {
+            return issues;
+        }
+        // unresolvable addresses are reported by `validate`
+        let Some(addr) = resolve(&self.listen_addr) else {
+            return issues;
+        };
+        if let Err(e) = TcpListener::bind(addr) {
+            let message = match e.kind() {
+                io::ErrorKind::AddrInUse => {
+                    format!("port {} already in use by another process", addr.port())
+                }
+                io::ErrorKind::PermissionDenied => {
+                    format!("no permission to bind port {}", addr.port())
+                }
+                io::ErrorKind::AddrNotAvailable => {
+                    format!("{} is not an address of this host", addr.ip())
+                }
+                _ => format!("cannot bind {}: {}", addr, e),
+            };
+            issues.push(ConfigIssue::error("server.listen_addr", message));
+        }
+        issues
+    }
+}
+
+impl ClientConfig {
+    /// Semantic validation beyond what deserialization checks.
+    /// Duplicate service names and remote ports are already rejected by `normalize_services`.
+    pub fn validate(&self) -> Vec<ConfigIssue> {
+        let mut issues = Vec::new();
+
+        if self.connections.is_empty() {
+            if self.servers.is_empty() {
+                issues.push(ConfigIssue::error(
+                    "client.servers",
+                    "at least one server is required",
+                ));
+            }
+            for (index, server) in self.servers.iter().enumerate() {
+                check_server_addr(&format!("client.servers[{}]", index), server, &mut issues);
+            }
+            self.check_token("client.token", &self.token, &mut issues);
+            check_services("client.services", &self.services, &mut issues);
+        } else {
+            if !self.servers.is_empty() || !self.services.is_empty() {
+                issues.push(ConfigIssue::error(
+                    "client.connections",
+                    "cannot be combined with client.servers or client.services",
+                ));
+            }
+
+            let mut seen = HashSet::new();
+            for (index, entry) in self.connections.iter().enumerate() {
+                let path = format!("client.connections[{}]", index);
+                check_server_addr(&format!("{}.server", path), &entry.server, &mut issues);
+                if !seen.insert(&entry.server) {
+                    issues.push(ConfigIssue::error(
+                        format!("{}.server", path),
+                        format!("'{}' is listed more than once", entry.server),
+                    ));
+                }
+                if let Some(token) = &entry.token {
+                    self.check_token(&format!("{}.token", path), token, &mut issues);
+                }
+                check_services(&format!("{}.services", path), &entry.services, &mut issues);
+            }
+            // the shared token only matters for entries without their own
+            if self.connections.iter().any(|entry| entry.token.is_none()) {
+                self.check_token("client.token", &self.token, &mut issues);
+            }
+        }
+
+        self.check_visitors(&mut issues);
+
+        if self.transport == Transport::Quic && !cfg!(feature = "quic") {
+            issues.push(ConfigIssue::error(
+                "client.transport",
+                "this build has no QUIC support, rebuild with --features quic",
+            ));
+        }
+        if let Some(fingerprint) = &self.tls.fingerprint {
+            if tls::parse_fingerprint(fingerprint).is_none() {
+                issues.push(ConfigIssue::error(
+                    "client.tls.fingerprint",
+                    "expected the 64 hex digits of a SHA-256 fingerprint",
+                ));
+            }
+        }
+
+        check_interval(
+            "client.reconnect_interval",
+            self.reconnect_interval,
+            &mut issues,
+        );
+        check_interval(
+            "client.heartbeat_interval",
+            self.heartbeat_interval,
+            &mut issues,
+        );
+
+        check_log_file("client.log_file", self.log_file.as_deref(), &mut issues);
+        check_log_filter("client.log_filter", self.log_filter.as_deref(), &mut issues);
+        check_syslog(
+            "client",
+            self.log_target,
+            self.syslog_addr.as_deref(),
+            &mut issues,
+        );
+        match self.control_socket.as_deref() {
+            Some(_) if !cfg!(unix) => issues.push(ConfigIssue::error(
+                "client.control_socket",
+                "control sockets are only supported on Unix",
+            )),
+            Some("") => issues.push(ConfigIssue::error(
+                "client.control_socket",
+                "must be a socket path",
+            )),
+            _ => {}
+        }
+        issues
+    }
+
+    fn check_visitors(&self, issues: &mut Vec<ConfigIssue>) {
+        let servers: Vec<String> = self
+            .connection_entries()
+            .into_iter()
+            .map(|entry| entry.server)
+            .collect();
+        let mut bound = HashSet::new();
+        for (index, visitor) in self.visitors.iter().enumerate() {
+            let path = format!("client.visitors[{}]", index);
+            if visitor.service.is_empty() {
+                issues.push(ConfigIssue::error(
+                    format!("{}.service", path),
+                    "must name a secret service",
+                ));
+            }
+            if visitor.secret.is_empty() {
+                issues.push(ConfigIssue::error(
+                    format!("{}.secret", path),
+                    "must not be empty",
+                ));
+            }
+            if visitor.bind_addr.parse::<SocketAddr>().is_err() {
+                issues.push(ConfigIssue::error(
+                    format!("{}.bind_addr", path),
+                    format!("'{}' is not an ip:port address", visitor.bind_addr),
+                ));
+            } else if !bound.insert(&visitor.bind_addr) {
+                issues.push(ConfigIssue::error(
+                    format!("{}.bind_addr", path),
+                    format!("'{}' is used by another visitor", visitor.bind_addr),
+                ));
+            }
+            if let Some(server) = &visitor.server {
+                if !servers.contains(server) {
+                    issues.push(ConfigIssue::error(
+                        format!("{}.server", path),
+                        format!("'{}' is not one of the configured servers", server),
+                    ));
+                }
+            }
+        }
+        // relays listen like visitors, on ports of their own
+        for (index, relay) in self.relays.iter().enumerate() {
+            let path = format!("client.relays[{}]", index);
+            if relay.client.is_empty() {
+                issues.push(ConfigIssue::error(
+                    format!("{}.client", path),
+                    "must name the client of the relay service",
+                ));
+            }
+            if relay.service.is_empty() {
+                issues.push(ConfigIssue::error(
+                    format!("{}.service", path),
+                    "must name a relay service",
+                ));
+            }
+            if relay.bind_addr.parse::<SocketAddr>().is_err() {
+                issues.push(ConfigIssue::error(
+                    format!("{}.bind_addr", path),
+                    format!("'{}' is not an ip:port address", relay.bind_addr),
+                ));
+            } else if !bound.insert(&relay.bind_addr) {
+                issues.push(ConfigIssue::error(
+                    format!("{}.bind_addr", path),
+                    format!("'{}' is used by another visitor or relay", relay.bind_addr),
+                ));
+            }
+            if let Some(server) = &relay.server {
+                if !servers.contains(server) {
+                    issues.push(ConfigIssue::error(
+                        format!("{}.server", path),
+                        format!("'{}' is not one of the configured servers", server),
+                    ));
+                }
+            }
+        }
+    }
+
+    fn check_token(&self, path: &str, token: &str, issues: &mut Vec<ConfigIssue>) {
+        if token.is_empty() {
+            if self.tls.cert.is_none() {
+                issues.push(ConfigIssue::error(
+                    path,
+                    "a token is required unless a TLS client certificate is configured",
+                ));
+            }
+        } else {
+            check_token_strength(path, token, issues);
+        }
+    }
+}
+
+fn check_server_addr(path: &str, server: &str, issues: &mut Vec<ConfigIssue>) {
+    let valid = server
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| is_valid_host(host) && port.parse::<u16>().is_ok());
+    if !valid {
+        issues.push(ConfigIssue::error(
+            path,
+            format!("'{}' is not a host:port address", server),
+        ));
+    }
+}
+
+fn check_services(path: &str, services: &[ServiceConfig], issues: &mut Vec<ConfigIssue>) {
+    for (index, service) in services.iter().enumerate() {
+        let path = format!("{}[{}]", path, index);
+        let routes = [("http_host", &service.http_host), ("sni", &service.sni)];
+        for (field, host) in routes {
+            let Some(host) = host else {
+                continue;
+            };
+            if !is_valid_host(host) {
+                issues.push(ConfigIssue::error(
+                    format!("{}.{}", path, field),
+                    format!("'{}' is not a valid host name", host),
+                ));
+            }
+            if service.remote_port != 0 {
+                issues.push(ConfigIssue::warning(
+                    format!("{}.remote_port", path),
+                    format!("ignored for services routed by {}", field),
+                ));
+            }
+        }
+        if service.secret.as_deref() == Some("") {
+            issues.push(ConfigIssue::error(
+                format!("{}.secret", path),
+                "must not be empty",
+            ));
+        }
+        if service.ttl.is_some_and(|ttl| ttl.0.is_zero()) {
+            issues.push(ConfigIssue::error(format!("{}.ttl", path), "must not be 0"));
+        }
+        if service.connect_attempts == 0 {
+            issues.push(ConfigIssue::error(
+                format!("{}.connect_attempts", path),
+                "must be at least 1",
+            ));
+        }
+        let retrying = service.connect_retry_delay.0 * service.connect_attempts.saturating_sub(1);
+        if retrying >= Duration::from_secs(10) {
+            issues.push(ConfigIssue::warning(
+                format!("{}.connect_retry_delay", path),
+                "retries outlast the 10s servers wait for a connection by default",
+            ));
+        }
+        if service.require_healthy && !service.health_check {
+            issues.push(ConfigIssue::warning(
+                format!("{}.require_healthy", path),
+                "ignored with health_check = false",
+            ));
+        }
+        if service.local_path.is_some() {
+            continue;
+        }
+        if service.local_port == 0 {
+            issues.push(ConfigIssue::error(
+                format!("{}.local_port", path),
+                "must not be 0",
+            ));
+        }
+        if !is_valid_host(&service.local_ip) {
+            issues.push(ConfigIssue::error(
+                format!("{}.local_ip", path),
+                format!(
+                    "'{}' is not a valid IP address or host name",
+                    service.local_ip
+                ),
+            ));
+        }
+    }
+}
+
+/// Checks that a listen address is an `ip:port` or a `host:port` resolving to one
+fn check_listen_addr(path: &str, addr: &str, issues: &mut Vec<ConfigIssue>) {
+    if addr.parse::<SocketAddr>().is_ok() {
+        return;
+    }
+    let message = match addr.to_socket_addrs().map(|mut resolved| resolved.next()) {
+        Ok(Some(_)) => return,
+        Ok(None) => format!("'{}' resolves to no address", addr),
+        Err(e) => format!("'{}' is not a socket address: {}", addr, e),
+    };
+    issues.push(ConfigIssue::error(path, message));
+}
+
+/// Checks that a host to bind on is an IP address or a host name resolving to one
+fn check_bind_host(path: &str, host: &str, issues: &mut Vec<ConfigIssue>) {
+    if !is_valid_host(host) {
+        issues.push(ConfigIssue::error(
+            path,
+            format!("'{}' is not a valid host", host),
+        ));
+    } else if host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .is_err()
+        && resolve(&net::join_host_port(host, 0)).is_none()
+    {
+        issues.push(ConfigIssue::error(
+            path,
+            format!("'{}' does not resolve to an address", host),
+        ));
+    }
+}
+
+/// The first address `addr` resolves to, None if it resolves to none
+fn resolve(addr: &str) -> Option<SocketAddr> {
+    addr.to_socket_addrs().ok()?.next()
+}
+
+fn check_token_strength(path: &str, token: &str, issues: &mut Vec<ConfigIssue>) {
+    if token.trim().len() < MIN_TOKEN_LEN {
+        issues.push(ConfigIssue::warning(
+            path,
+            format!(
+                "token is trivially guessable, use at least {} characters",
+                MIN_TOKEN_LEN
+            ),
+        ));
+    }
+}
+
+fn check_interval(path: &str, interval: HumanDuration, issues: &mut Vec<ConfigIssue>) {
+    if interval.0.is_zero() {
+        issues.push(ConfigIssue::error(path, "must be longer than 0s"));
+    } else if interval.0 > MAX_SANE_INTERVAL {
+        issues.push(ConfigIssue::warning(
+            path,
+            format!("{} is unusually long", interval),
+        ));
+    }
+}
+
+/// Checks that the log file can be appended to, without creating it
+fn check_log_file(path: &str, log_file: Option<&str>, issues: &mut Vec<ConfigIssue>) {
+    let Some(log_file) = log_file else {
+        return;
+    };
+
+    let file = Path::new(log_file);
+    let writable = if file.exists() {
+        OpenOptions::new().append(true).open(file).is_ok()
+    } else {
+        let parent = match file.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        parent
+            .metadata()
+            .is_ok_and(|meta| meta.is_dir() && !meta.permissions().readonly())
+    };
+
+    if !writable {
+        issues.push(ConfigIssue::error(
+            path,
+            format!("'{}' is not writable", log_file),
+        ));
+    }
+}
+
+/// Checks that the per-target directives parse, e.g. `sowback::server=debug`
+fn check_log_filter(path: &str, filter: Option<&str>, issues: &mut Vec<ConfigIssue>) {
+    let Some(filter) = filter else {
+        return;
+    };
+    if let Err(e) = tracing_subscriber::EnvFilter::try_new(filter) {
+        issues.push(ConfigIssue::error(
+            path,
+            format!("invalid directives: {}", e),
+        ));
+    }
+}
+
+/// Checks that syslog is available and `syslog_addr` parses, and that it is not set
+/// in vain
+fn check_syslog(
+    section: &str,
+    target: LogTarget,
+    syslog_addr: Option<&str>,
+    issues: &mut Vec<ConfigIssue>,
+) {
+    if target == LogTarget::File {
+        if syslog_addr.is_some() {
+            issues.push(ConfigIssue::warning(
+                format!("{}.syslog_addr", section),
+                "has no effect without log_target = \"syslog\"",
+            ));
+        }
+        return;
+    }
+    if !cfg!(unix) {
+        issues.push(ConfigIssue::error(
+            format!("{}.log_target", section),
+            "syslog is only supported on Unix",
+        ));
+        return;
+    }
+    if let Err(e) = SyslogAddr::resolve(syslog_addr) {
+        issues.push(ConfigIssue::error(format!("{}.syslog_addr", section), e));
+    }
+}
+
+/// Accepts IP addresses and syntactically valid host names
+fn is_valid_host(host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.parse::<IpAddr>().is_ok() {
+        return true;
+    }
+
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WebhookConfig;
+
+    fn paths(issues: &[ConfigIssue]) -> Vec<&str> {
+        issues.iter().map(|issue| issue.path.as_str()).collect()
+    }
+
+    #[test]
+    fn test_server_reports_every_problem() {
+        let config = ServerConfig {
+            listen_addr: "not-an-address".to_string(),
+            token: "abc".to_string(),
+            max_clients: 0,
+            orphan_connection_policy: OrphanConnectionPolicy::Queue,
+            orphan_queue_size: 0,
+            log_file: Some("/nonexistent-dir/sowback.log".to_string()),
+            log_filter: Some("sowback::server=loud".to_string()),
+            webhooks: Some(WebhookConfig {
+                url: "hooks.example.com/sowback".to_string(),
+                events: None,
+                secret: Some(String::new()),
+            }),
+            ..ServerConfig::default()
+        };
+
+        let issues = config.validate();
+        assert_eq!(
+            paths(&issues),
+            vec![
+                "server.token",
+                "server.listen_addr",
+                "server.max_clients",
+                "server.orphan_queue_size",
+                "server.webhooks.url",
+                "server.webhooks.secret",
+                "server.log_file",
+                "server.log_filter"
+            ]
+        );
+        // a short token works, it is only a warning
+        assert!(!issues[0].is_error());
+        assert!(issues[1..].iter().all(ConfigIssue::is_error));
+    }
+
+    #[test]
+    fn test_client_reports_every_problem() {
+        let mut config: ClientConfig = toml::from_str(
+            r#"
+            servers = ["127.0.0.1:7000", "missing-port"]
+            token = ""
+            reconnect_interval = 0
+            heartbeat_interval = 30
+
+            [[services]]
+            local_ip = "not a host"
+            local_port = 0
+            remote_port = 8080
+            ttl = "0s"
+            connect_attempts = 0
+            "#,
+        )
+        .unwrap();
+        config.normalize_services().unwrap();
+
+        let issues = config.validate();
+        assert_eq!(
+            paths(&issues),
+            vec![
+                "client.servers[1]",
+                "client.token",
+                "client.services[0].ttl",
+                "client.services[0].connect_attempts",
+                "client.services[0].local_port",
+                "client.services[0].local_ip",
+                "client.reconnect_interval",
+            ]
+        );
+        assert!(issues.iter().all(ConfigIssue::is_error));
+    }
+
+    #[test]
+    fn test_visitor_problems() {
+        let config: ClientConfig = toml::from_str(
+            r#"
+            servers = ["127.0.0.1:7000"]
+            token = "a-long-enough-token"
+            reconnect_interval = 5
+            heartbeat_interval = 30
+
+            [[visitors]]
+            service = "ssh"
+            secret = "shared"
+            bind_addr = "127.0.0.1:2222"
+
+            [[visitors]]
+            service = "web"
+            secret = ""
+            bind_addr = "127.0.0.1:2222"
+            server = "other:7000"
+
+            [[relays]]
+            client = "laptop-b"
+            service = ""
+            bind_addr = "127.0.0.1:2222"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            paths(&config.validate()),
+            vec![
+                "client.visitors[1].secret",
+                "client.visitors[1].bind_addr",
+                "client.visitors[1].server",
+                "client.relays[0].service",
+                "client.relays[0].bind_addr",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_quic_needs_a_certificate() {
+        let config = ServerConfig {
+            token: "a-long-enough-token".to_string(),
+            quic_listen_addr: Some("0.0.0.0:7000".to_string()),
+            ..ServerConfig::default()
+        };
+        let issues = config.validate();
+        assert!(issues
+            .iter()
+            .any(|issue| issue.message == "QUIC needs a certificate, set server.tls"));
+        assert!(paths(&issues)
+            .iter()
+            .all(|path| *path == "server.quic_listen_addr"));
+    }
+
+    #[test]
+    fn test_valid_config_has_no_issues() {
+        let config = ServerConfig {
+            token: "a-long-enough-secret".to_string(),
+            ..ServerConfig::default()
+        };
+        assert!(config.validate().is_empty());
+    }
+
+    #[test]
+    fn test_listen_addrs_and_bind_hosts_resolve() {
+        let config = ServerConfig {
+            token: "a-long-enough-secret".to_string(),
+            listen_addr: "localhost:7000".to_string(),
+            bind_host: "localhost".to_string(),
+            ..ServerConfig::default()
+        };
+        assert!(config.validate().is_empty());
+
+        let config = ServerConfig {
+            listen_addr: "no-such-host.invalid:7000".to_string(),
+            bind_host: "no-such-host.invalid".to_string(),
+            allowed_bind_hosts: vec!["10.0.0.5".to_string(), "bad host".to_string()],
+            ..config
+        };
+        let issues = config.validate();
+        assert_eq!(
+            paths(&issues),
+            vec![
+                "server.listen_addr",
+                "server.bind_host",
+                "server.allowed_bind_hosts[1]"
+            ]
+        );
+        assert_eq!(
+            issues[1].message,
+            "'no-such-host.invalid' does not resolve to an address"
+        );
+    }
+
+    #[test]
+    fn test_check_listen_reports_a_taken_port() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap();
+        let mut config = ServerConfig {
+            listen_addr: addr.to_string(),
+            ..ServerConfig::default()
+        };
+        let issues = config.check_listen();
+        assert_eq!(paths(&issues), vec!["server.listen_addr"]);
+        assert_eq!(
+            issues[0].message,
+            format!("port {} already in use by another process", addr.port())
+        );
+
+        // sharing the port is what reuseport is for
+        config.reuseport = true;
+        assert!(config.check_listen().is_empty());
+
+        drop(taken);
+        config.reuseport = false;
+        assert!(config.check_listen().is_empty());
+    }
+
+    #[test]
+    fn test_client_servers_and_intervals() {
+        let mut config: ClientConfig = toml::from_str(
+            r#"
+            token = "a-long-enough-token"
+            reconnect_interval = 5
+            heartbeat_interval = "2h"
+            "#,
+        )
+        .unwrap();
+        let issues = config.validate();
+        assert_eq!(
+            paths(&issues),
+            vec!["client.servers", "client.heartbeat_interval"]
+        );
+        // an hour-long heartbeat works, it is only suspicious
+        assert!(issues[0].is_error() && !issues[1].is_error());
+
+        config.servers = vec![
+            "relay.example.com:7000".to_string(),
+            "[::1]:7000".to_string(),
+            "relay.example.com:70000".to_string(),
+            "relay.example.com".to_string(),
+        ];
+        config.heartbeat_interval = HumanDuration(Duration::from_secs(30));
+        assert_eq!(
+            paths(&config.validate()),
+            vec!["client.servers[2]", "client.servers[3]"]
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// A sowback release, written `0.2.1` or `v0.2.1`. Missing parts are 0 and a pre-release
+/// or build suffix like `-rc.1` is ignored, so versions compare by their numbers only
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    /// Major version
+    pub major: u64,
+    /// Minor version
+    pub minor: u64,
+    /// Patch version
+    pub patch: u64,
+}
+
+impl Version {
+    /// The version of this build
+    pub fn current() -> Self {
+        env!("CARGO_PKG_VERSION")
+            .parse()
+            .expect("CARGO_PKG_VERSION is a version")
+    }
+}
+
+impl FromStr for Version {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let trimmed = s.trim();
+        let numbers = trimmed.strip_prefix('v').unwrap_or(trimmed);
+        let numbers = numbers.split(['-', '+']).next().unwrap_or_default();
+        let mut parts = [0u64; 3];
+        for (index, part) in numbers.split('.').enumerate() {
+            let slot = parts
+                .get_mut(index)
+                .ok_or_else(|| anyhow!("Invalid version '{}': more than 3 parts", s))?;
+            *slot = part
+                .parse()
+                .map_err(|_| anyhow!("Invalid version '{}': '{}' is not a number", s, part))?;
+        }
+        let [major, minor, patch] = parts;
+        Ok(Version {
+            major,
+            minor,
+            patch,
+        })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl<'de> Deserialize<'de> for Version {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for Version {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_string().serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_forms() {
+        let parse = |s: &str| s.parse::<Version>().unwrap().to_string();
+        assert_eq!(parse("0.2.1"), "0.2.1");
+        assert_eq!(parse("v1.4"), "1.4.0");
+        assert_eq!(parse("2"), "2.0.0");
+        assert_eq!(parse("0.3.0-rc.1"), "0.3.0");
+        assert_eq!(parse("1.0.0+build.5"), "1.0.0");
+        for invalid in ["", "v", "1..2", "1.2.3.4", "one.two", "1.-2"] {
+            assert!(invalid.parse::<Version>().is_err(), "{}", invalid);
+        }
+
+        // compared part by part, not as text
+        let version = |s: &str| s.parse::<Version>().unwrap();
+        assert!(version("0.10.0") > version("0.9.9"));
+        assert!(version("1.0.0") > version("0.99.0"));
+        assert_eq!(version("v0.2"), version("0.2.0-beta"));
+        assert_eq!(Version::current().to_string(), env!("CARGO_PKG_VERSION"));
+    }
+}
@@ -0,0 +1,56 @@
+//! sowback is a multi-server reverse proxy. A [`Server`] accepts clients on a control port
+//! and exposes their local services on remote ports, a [`Client`] connects to any number of
+//! servers and forwards the connections they receive to its local services.
+//!
+//! Both run until the [`CancellationToken`] passed to `run` is cancelled:
+//!
+//! ```no_run
+//! use sowback::{CancellationToken, Client, ClientConfig};
+//!
+//! # async fn example(config: ClientConfig) -> anyhow::Result<()> {
+//! let client = Client::new(config)?;
+//! let shutdown = CancellationToken::new();
+//! let task = tokio::spawn({
+//!     let shutdown = shutdown.clone();
+//!     async move { client.run(shutdown).await }
+//! });
+//! // ...
+//! shutdown.cancel();
+//! task.await??;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Nothing is logged until [`logging::init_logger`] installs the logger, so embedding
+//! applications keep control of their own tracing subscriber and console.
+
+#![warn(missing_docs)]
+
+/// Server and client configuration, as read from TOML files
+pub mod config;
+/// Console and file logging, and the macros writing to them
+pub mod logging;
+
+mod client;
+mod server;
+mod utils;
+
+pub use client::{
+    BenchOptions, BenchReport, Client, GaveUp, Percentiles, PingError, PingSession, PingSummary,
+    Throughput,
+};
+#[cfg(unix)]
+pub use client::{ControlClient, ControlCommand, ServiceSpec};
+pub use config::{ClientConfig, Config, ServerConfig, ServiceConfig};
+pub use server::{AdminClient, Server};
+pub use tokio_util::sync::CancellationToken;
+pub use utils::compression::Compression;
+pub use utils::crypto::CryptoContext;
+pub use utils::daemon::{daemonize, stop_daemon, PidFile};
+pub use utils::protocol::{
+    CloseCode, Frame, Framing, Message, ProxyConfigOpCode, FRAME_MAGIC, MAX_FRAME_LEN,
+    PROTOCOL_VERSION,
+};
+pub use utils::proxy::{forward_data, ForwardStats};
+pub use utils::runtime::{build_runtime, worker_threads};
+pub use utils::FrameReader;
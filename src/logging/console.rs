@@ -1,32 +1,138 @@
 use chrono::Local;
 use colored::{ColoredString, Colorize};
+use std::collections::VecDeque;
 use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
 
 use crate::logging::logger::LoggerConfig;
 
+/// Lines a `Scrollback` keeps, older ones are dropped
+const SCROLLBACK_LINES: usize = 1000;
+
+/// Where brief console output goes instead of the terminal, while captured
+static CAPTURE: Mutex<Option<Arc<Scrollback>>> = Mutex::new(None);
+
+/// Brief console output kept in memory while something else draws on the terminal,
+/// e.g. the dashboard
+#[derive(Debug, Default)]
+pub struct Scrollback {
+    lines: Mutex<VecDeque<(ConsoleLevel, String)>>,
+}
+
+impl Scrollback {
+    fn push(&self, level: ConsoleLevel, line: String) {
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == SCROLLBACK_LINES {
+            lines.pop_front();
+        }
+        lines.push_back((level, line));
+    }
+
+    /// The last `count` lines with their levels, oldest first
+    pub fn last(&self, count: usize) -> Vec<(ConsoleLevel, String)> {
+        let lines = self.lines.lock().unwrap();
+        lines
+            .iter()
+            .skip(lines.len().saturating_sub(count))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Routes brief console output into the returned scrollback instead of stdout and stderr,
+/// uncolored, until `release_console`
+pub fn capture_console() -> Arc<Scrollback> {
+    let scrollback = Arc::new(Scrollback::default());
+    colored::control::set_override(false);
+    *CAPTURE.lock().unwrap() = Some(scrollback.clone());
+    scrollback
+}
+
+/// Prints brief console output on the terminal again
+pub fn release_console() {
+    *CAPTURE.lock().unwrap() = None;
+    match *COLOR.lock().unwrap() {
+        Some(enabled) => colored::control::set_override(enabled),
+        None => colored::control::unset_override(),
+    }
+}
+
+/// The scrollback capturing console output, None while it is printed
+fn captured() -> Option<Arc<Scrollback>> {
+    CAPTURE.lock().unwrap().clone()
+}
+
 /// Format current time as H:M:S string
 pub fn format_local_time() -> String {
     Local::now().format("%H:%M:%S").to_string()
 }
 
-/// Check if the terminal supports color output
+/// Whether output is colored, decided by `init_color`, None before it was called
+static COLOR: Mutex<Option<bool>> = Mutex::new(None);
+
+/// Whether to color output, from `--color`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// Color when printing to a terminal, unless `NO_COLOR` is set or `FORCE_COLOR` asks to
+    #[default]
+    Auto,
+    /// Always color, even when piped
+    Always,
+    /// Never color
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether this choice colors output here and now
+    pub fn resolve(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => detect_color(
+                |name| std::env::var(name).ok(),
+                atty::is(atty::Stream::Stdout) || atty::is(atty::Stream::Stderr),
+            ),
+        }
+    }
+}
+
+/// Decides once whether output is colored, for the console and every formatted message
+/// alike, and has `colored` follow the decision rather than its own detection
+pub fn init_color(choice: ColorChoice) {
+    let enabled = choice.resolve();
+    *COLOR.lock().unwrap() = Some(enabled);
+    colored::control::set_override(enabled);
+}
+
+/// Whether to color output: as `init_color` decided, never while the console is captured,
+/// and by detection before `init_color`
 pub fn supports_color() -> bool {
-    // Check various environment variables and terminal capabilities
-    if std::env::var("NO_COLOR").is_ok() {
+    if captured().is_some() {
+        return false;
+    }
+    let decided = *COLOR.lock().unwrap();
+    decided.unwrap_or_else(|| ColorChoice::Auto.resolve())
+}
+
+/// Whether to color by the environment looked up with `var`, `terminal` telling whether
+/// stdout or stderr is a terminal
+fn detect_color(var: impl Fn(&str) -> Option<String>, terminal: bool) -> bool {
+    // set to anything but an empty string, https://no-color.org
+    let set = |name| var(name).is_some_and(|value: String| !value.is_empty());
+    if set("NO_COLOR") {
         return false;
     }
 
-    if std::env::var("FORCE_COLOR").is_ok() {
+    if set("FORCE_COLOR") {
         return true;
     }
 
-    // Check if we're in a TTY
-    if !atty::is(atty::Stream::Stdout) && !atty::is(atty::Stream::Stderr) {
+    if !terminal {
         return false;
     }
 
     // Check TERM environment variable
-    if let Ok(term) = std::env::var("TERM") {
+    if let Some(term) = var("TERM") {
         if term == "dumb" {
             return false;
         }
@@ -40,6 +146,7 @@ pub fn supports_color() -> bool {
 
 /// Console log levels ordered by severity (most severe first)
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[allow(missing_docs)]
 pub enum ConsoleLevel {
     Error,
     Warn,
@@ -81,8 +188,8 @@ impl ConsoleLevel {
     }
 }
 
-/// Format and print a console message
-pub fn console_log(level: ConsoleLevel, message: &str) {
+/// A console line: the time, the level and the message, colored if output is
+fn format_line(level: ConsoleLevel, message: &str) -> String {
     let time_str = if supports_color() {
         format_local_time().dimmed().to_string()
     } else {
@@ -92,7 +199,16 @@ pub fn console_log(level: ConsoleLevel, message: &str) {
     // Use appropriate level string based on color support
     let level_str = level.as_display_str();
 
-    let formatted = format!("{} {} {}", time_str, level_str, message);
+    format!("{} {} {}", time_str, level_str, message)
+}
+
+/// Format and print a console message
+pub fn console_log(level: ConsoleLevel, message: &str) {
+    if let Some(scrollback) = captured() {
+        scrollback.push(level, format!("{} {}", format_local_time(), message));
+        return;
+    }
+    let formatted = format_line(level, message);
 
     match level {
         ConsoleLevel::Error | ConsoleLevel::Warn | ConsoleLevel::Trace => {
@@ -106,8 +222,100 @@ pub fn console_log(level: ConsoleLevel, message: &str) {
     }
 }
 
+/// Prints a brief console message, unless verbose tracing output replaces the brief output
+/// or `-q` and the log level leave out its level.
+/// Nothing is printed before `init_logger`, so embedding the library stays quiet
 pub fn console_log_non_verbose(level: ConsoleLevel, message: &str) {
-    if !LoggerConfig::get_global_clone().verbose {
+    let Some(config) = LoggerConfig::global() else {
+        return;
+    };
+    if config.prints_brief(level) {
         console_log(level, message);
     }
 }
+
+/// Prints a block of lines as they are, e.g. a table, where `console_log_non_verbose`
+/// would print a message
+pub fn console_print_non_verbose(text: &str) {
+    let Some(config) = LoggerConfig::global() else {
+        return;
+    };
+    if config.prints_brief(ConsoleLevel::Info) {
+        if let Some(scrollback) = captured() {
+            for line in text.lines() {
+                scrollback.push(ConsoleLevel::Info, line.to_string());
+            }
+            return;
+        }
+        let _ = write!(io::stdout(), "{}", text);
+        let _ = io::stdout().flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::{format_client_info, format_service_config, format_uuid};
+
+    const ESCAPE: char = '\x1b';
+
+    #[test]
+    fn test_auto_color_follows_the_environment() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        assert!(detect_color(env(&[("TERM", "xterm-256color")]), true));
+        assert!(!detect_color(env(&[("TERM", "xterm-256color")]), false));
+        assert!(!detect_color(env(&[("TERM", "dumb")]), true));
+        assert!(!detect_color(env(&[("NO_COLOR", "1")]), true));
+        assert!(detect_color(env(&[("FORCE_COLOR", "1")]), false));
+        // NO_COLOR wins, and empty values do not count
+        assert!(!detect_color(
+            env(&[("NO_COLOR", "1"), ("FORCE_COLOR", "1")]),
+            true
+        ));
+        assert!(detect_color(
+            env(&[("NO_COLOR", ""), ("TERM", "xterm")]),
+            true
+        ));
+    }
+
+    #[test]
+    fn test_color_choice_reaches_every_output() {
+        let outputs = || {
+            [
+                format_line(ConsoleLevel::Warn, "message"),
+                format_uuid("7046c8b3-b9ef-4fe9-abcf-68e5b1b79eb7", "conn"),
+                format_client_info(Some("laptop"), "203.0.113.7:51234"),
+                format_service_config("127.0.0.1:22", 2222),
+                "issue".red().to_string(),
+            ]
+        };
+
+        init_color(ColorChoice::Always);
+        for output in outputs() {
+            assert!(output.contains(ESCAPE), "{:?} is not colored", output);
+        }
+
+        init_color(ColorChoice::Never);
+        for output in outputs() {
+            assert!(!output.contains(ESCAPE), "{:?} is colored", output);
+        }
+        assert_eq!(
+            format_service_config("127.0.0.1:22", 2222),
+            "127.0.0.1:22 -> :2222"
+        );
+
+        // captured output is never colored, released it is as decided again
+        init_color(ColorChoice::Always);
+        let _scrollback = capture_console();
+        assert!(!format_uuid("7046c8b3", "proxy").contains(ESCAPE));
+        release_console();
+        assert!(format_uuid("7046c8b3", "proxy").contains(ESCAPE));
+        init_color(ColorChoice::Never);
+    }
+}
@@ -1,30 +1,136 @@
 use colored::*;
 
+use crate::logging::console::supports_color;
+
+/// `text` in `color` if output is colored, as it is otherwise
+fn paint(text: &str, color: Color) -> String {
+    if supports_color() {
+        text.color(color).to_string()
+    } else {
+        text.to_string()
+    }
+}
+
+/// The first 8 characters of a UUID, how IDs appear in logs
+pub fn short_id(uuid: &str) -> &str {
+    uuid.get(..8).unwrap_or(uuid)
+}
+
 /// Formats a UUID for display with color coding based on its purpose
 pub fn format_uuid(uuid: &str, purpose: &str) -> String {
-    let short_uuid = &uuid[..8];
+    let short_uuid = short_id(uuid);
     match purpose {
-        "conn" => short_uuid.yellow().to_string(),
-        "proxy" => short_uuid.green().to_string(),
-        "client" | "server" => short_uuid.blue().to_string(),
-        _ => short_uuid.normal().to_string(),
+        "conn" => paint(short_uuid, Color::Yellow),
+        "proxy" => paint(short_uuid, Color::Green),
+        "client" | "server" => paint(short_uuid, Color::Blue),
+        _ => short_uuid.to_string(),
+    }
+}
+
+/// Stands in for a secret wherever a config is echoed, only telling whether it is set
+pub fn redact(secret: &str) -> String {
+    if secret.is_empty() {
+        String::new()
+    } else {
+        "<redacted>".to_string()
+    }
+}
+
+/// Stands in for secret or payload bytes in `Debug` output, only telling how many there are
+pub struct RedactedBytes<'a>(pub &'a [u8]);
+
+impl std::fmt::Debug for RedactedBytes<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<{} bytes>", self.0.len())
     }
 }
 
+/// Longest peer-supplied name kept for logs, in characters
+const MAX_NAME_LEN: usize = 64;
+
+/// Makes a name sent by a peer safe to log: control characters, which could forge
+/// log lines or terminal escapes, become `?` and overlong names are cut short
+pub fn sanitize_name(name: &str) -> String {
+    let mut sanitized: String = name
+        .trim()
+        .chars()
+        .map(|c| if c.is_control() { '?' } else { c })
+        .take(MAX_NAME_LEN)
+        .collect();
+    if name.trim().chars().count() > MAX_NAME_LEN {
+        sanitized.push('…');
+    }
+    sanitized
+}
+
 /// Formats client identification information with optional name and IP address
 pub fn format_client_info(name: Option<&str>, addr: &str) -> String {
     match name {
-        Some(n) if !n.is_empty() => format!("{} ({})", n.cyan(), addr),
+        Some(n) if !n.is_empty() => format!("{} ({})", paint(n, Color::Cyan), addr),
         _ => addr.to_string(),
     }
 }
 
 /// Formats service configuration for display with color coding
-pub fn format_service_config(local_ip: &str, local_port: u16, remote_port: u16) -> String {
+pub fn format_service_config(local_addr: &str, remote_port: u16) -> String {
     format!(
-        "{}:{} -> :{}",
-        local_ip.magenta(),
-        local_port.to_string().magenta(),
-        remote_port.to_string().green()
+        "{} -> :{}",
+        paint(local_addr, Color::Magenta),
+        paint(&remote_port.to_string(), Color::Green)
     )
 }
+
+/// Formats a byte count with binary units, e.g. `1.2 GiB`
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if value >= 100.0 {
+        format!("{:.0} {}", value, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(300 * 1024 * 1024), "300 MiB");
+        assert_eq!(format_bytes(1288490189), "1.2 GiB");
+    }
+
+    #[test]
+    fn test_sanitize_name() {
+        assert_eq!(sanitize_name(" laptop "), "laptop");
+        assert_eq!(
+            sanitize_name("evil\n[INFO] forged\x1b[31m"),
+            "evil?[INFO] forged?[31m"
+        );
+        let long = sanitize_name(&"x".repeat(1000));
+        assert_eq!(long.chars().count(), MAX_NAME_LEN + 1);
+        assert!(long.ends_with('…'));
+    }
+
+    #[test]
+    fn test_short_id() {
+        assert_eq!(short_id("7046c8b3-b9ef-4fe9-abcf-68e5b1b79eb7"), "7046c8b3");
+        // IDs that are not UUIDs are kept whole rather than panicking
+        assert_eq!(short_id("conn"), "conn");
+        assert_eq!(short_id(""), "");
+        // cut inside a multi-byte character
+        assert_eq!(short_id("aéééé"), "aéééé");
+    }
+}
@@ -1,43 +1,222 @@
+use serde::{Deserialize, Serialize};
 use std::sync::{Mutex, OnceLock};
 
+use crate::logging::console::ConsoleLevel;
+use crate::logging::syslog::{SyslogAddr, SyslogLayer};
+use crate::warn;
+
 /// Global logging configuration
 static LOGGER_CONFIG: OnceLock<Mutex<LoggerConfig>> = OnceLock::new();
 
 /// Configuration for the logging system
 #[derive(Debug, Clone)]
 pub struct LoggerConfig {
+    /// File receiving JSON lines of detailed logs
     pub log_file: Option<String>,
-    pub verbose: bool,
+    /// How much the console prints, from `-q` and `-v`
+    pub verbosity: Verbosity,
+    /// Most detailed level printed by the brief console output, everything when None
+    pub console_level: Option<ConsoleLevel>,
+    /// Print the brief console output at all, off where there is no console
+    pub console: bool,
+    /// Directives for the tracing `EnvFilter`, e.g. `info,sowback::server=debug`
+    pub filter: String,
+    /// Syslog daemon receiving the detailed logs too
+    pub syslog: Option<SyslogAddr>,
+}
+
+/// Maximum level to log, from `--log-level` or `log_level`
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+#[allow(missing_docs)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    fn as_str(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+
+    fn console_level(self) -> ConsoleLevel {
+        match self {
+            LogLevel::Error => ConsoleLevel::Error,
+            LogLevel::Warn => ConsoleLevel::Warn,
+            LogLevel::Info => ConsoleLevel::Info,
+            LogLevel::Debug => ConsoleLevel::Debug,
+            LogLevel::Trace => ConsoleLevel::Trace,
+        }
+    }
+}
+
+/// How much the console prints, from `-q`, `-v` and `-vv`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Verbosity {
+    /// Brief output of warnings and errors only (`-q`)
+    Quiet,
+    /// Brief output of what happens (the default)
+    #[default]
+    Normal,
+    /// Detailed tracing output instead of the brief one (`-v`)
+    Verbose,
+    /// Detailed tracing output down to debug, the data path included (`-vv`)
+    Debug,
+}
+
+impl Verbosity {
+    /// The verbosity of `-q` and of `-v` given `verbose` times
+    pub fn from_flags(quiet: bool, verbose: u8) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, _) => Verbosity::Debug,
+        }
+    }
+
+    /// Whether detailed tracing output replaces the brief console output
+    pub fn detailed(self) -> bool {
+        matches!(self, Verbosity::Verbose | Verbosity::Debug)
+    }
+}
+
+/// Where logs go besides the console, from `log_target`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    /// The JSON lines `log_file`, if one is set
+    #[default]
+    File,
+    /// The syslog daemon at `syslog_addr`, and `log_file` if one is set (Unix)
+    Syslog,
+}
+
+/// Where the log level and per-target directives come from, and the syslog daemon
+/// receiving the logs
+#[derive(Debug, Clone, Default)]
+pub struct LogSettings {
+    /// `--log-level`
+    pub cli_level: Option<LogLevel>,
+    /// `log_level` in the config file
+    pub config_level: Option<LogLevel>,
+    /// `log_filter` in the config file, e.g. `sowback::server=debug,sowback::utils=warn`
+    pub config_filter: Option<String>,
+    /// `syslog_addr` resolved, with `log_target = "syslog"`
+    pub syslog: Option<SyslogAddr>,
+}
+
+impl LogSettings {
+    /// The level given explicitly, the command line winning over the config
+    fn level(&self) -> Option<LogLevel> {
+        self.cli_level.or(self.config_level)
+    }
+
+    /// These settings with `-vv` as a command line level of debug, over the config's
+    /// log_level but not `--log-level`
+    fn with_verbosity(mut self, verbosity: Verbosity) -> Self {
+        if verbosity == Verbosity::Debug {
+            self.cli_level = self.cli_level.or(Some(LogLevel::Debug));
+        }
+        self
+    }
+
+    /// `EnvFilter` directives: the explicit level, else `rust_log`, else info,
+    /// refined by the config's per-target directives
+    pub fn filter_directives(&self, rust_log: Option<&str>) -> String {
+        let base = match (self.level(), rust_log) {
+            (Some(level), _) => level.as_str(),
+            (None, Some(rust_log)) if !rust_log.trim().is_empty() => rust_log.trim(),
+            (None, _) => "info",
+        };
+        match self.config_filter.as_deref().map(str::trim) {
+            Some(filter) if !filter.is_empty() => format!("{},{}", base, filter),
+            _ => base.to_string(),
+        }
+    }
 }
 
 impl LoggerConfig {
-    pub fn get_global_clone() -> LoggerConfig {
-        LOGGER_CONFIG.get().unwrap().lock().unwrap().clone()
+    /// The configuration installed by `init_logger`, None before it was called
+    pub fn global() -> Option<LoggerConfig> {
+        LOGGER_CONFIG
+            .get()
+            .map(|config| config.lock().unwrap().clone())
+    }
+
+    /// Whether the brief console output prints a message of `level`
+    pub fn prints_brief(&self, level: ConsoleLevel) -> bool {
+        self.console
+            && !self.verbosity.detailed()
+            && self.console_level.is_none_or(|max| level <= max)
     }
 }
 
+/// The brief console's most detailed level: the explicit level, lowered to warnings by
+/// `-q`. RUST_LOG is for tracing output, the brief console only follows an explicit level
+fn brief_console_level(verbosity: Verbosity, settings: &LogSettings) -> Option<ConsoleLevel> {
+    let level = match verbosity {
+        Verbosity::Quiet => Some(
+            settings
+                .level()
+                .map_or(LogLevel::Warn, |level| level.min(LogLevel::Warn)),
+        ),
+        _ => settings.level(),
+    };
+    level.map(LogLevel::console_level)
+}
+
 /// Initialize the logging system
-pub fn init_logger(log_file: Option<String>, verbose: bool) {
+pub fn init_logger(log_file: Option<String>, verbosity: Verbosity, settings: &LogSettings) {
+    let rust_log = std::env::var("RUST_LOG").ok();
+    let settings = settings.clone().with_verbosity(verbosity);
     let config = LoggerConfig {
         log_file: log_file.clone(),
-        verbose,
+        verbosity,
+        console_level: brief_console_level(verbosity, &settings),
+        console: true,
+        filter: settings.filter_directives(rust_log.as_deref()),
+        syslog: settings.syslog.clone(),
     };
     LOGGER_CONFIG.set(Mutex::new(config.clone())).unwrap();
     // Initialize tracing subscriber with the provided configuration
     init_tracing(&config);
 }
 
+/// Initialize the logging system to write `log_file` only, for processes without a
+/// console such as a Windows service
+pub fn init_file_logger(log_file: String, settings: &LogSettings) {
+    let config = LoggerConfig {
+        log_file: Some(log_file),
+        verbosity: Verbosity::Normal,
+        console_level: None,
+        console: false,
+        filter: settings.filter_directives(std::env::var("RUST_LOG").ok().as_deref()),
+        syslog: settings.syslog.clone(),
+    };
+    LOGGER_CONFIG.set(Mutex::new(config.clone())).unwrap();
+    init_tracing(&config);
+}
+
 /// Initialize tracing subscriber with different modes
 pub fn init_tracing(config: &LoggerConfig) {
-    use tracing_subscriber::{
-        fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer,
-    };
+    use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
-    let env_filter_base = std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string());
-    let env_filter = EnvFilter::new(&env_filter_base);
+    let env_filter = EnvFilter::new(&config.filter);
 
-    // console detail layer (if verbose enabled)
-    let console_detail_layer = if config.verbose {
+    // console detail layer (with -v or -vv)
+    let console_detail_layer = if config.verbosity.detailed() {
         let layer = fmt::Layer::new()
             .with_target(true)
             .with_level(true)
@@ -71,9 +250,130 @@ pub fn init_tracing(config: &LoggerConfig) {
         None
     };
 
+    // syslog layer (if log_target = "syslog"), beside the file so one can be tailed locally
+    let mut syslog_error = None;
+    let syslog_layer = config
+        .syslog
+        .as_ref()
+        .and_then(|addr| match SyslogLayer::new(addr) {
+            Ok(layer) => Some(layer),
+            Err(e) => {
+                syslog_error = Some(format!("Cannot send logs to syslog at {}: {}", addr, e));
+                None
+            }
+        });
+
     tracing_subscriber::registry()
         .with(env_filter)
         .with(console_detail_layer)
         .with(file_json_layer)
+        .with(syslog_layer)
         .init();
+
+    if let Some(error) = syslog_error {
+        warn!("{}", error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_precedence() {
+        let mut settings = LogSettings::default();
+        assert_eq!(settings.filter_directives(None), "info");
+        assert_eq!(settings.filter_directives(Some("warn")), "warn");
+
+        settings.config_level = Some(LogLevel::Debug);
+        assert_eq!(settings.filter_directives(Some("warn")), "debug");
+        settings.cli_level = Some(LogLevel::Error);
+        assert_eq!(settings.filter_directives(Some("warn")), "error");
+
+        settings.config_filter = Some("sowback::server=debug,sowback::utils=warn".to_string());
+        assert_eq!(
+            settings.filter_directives(None),
+            "error,sowback::server=debug,sowback::utils=warn"
+        );
+        assert_eq!(
+            settings.level().map(LogLevel::console_level),
+            Some(ConsoleLevel::Error)
+        );
+    }
+
+    #[test]
+    fn test_verbosity_to_console_output() {
+        use ConsoleLevel::*;
+
+        let printed = |verbosity, cli_level| {
+            let settings = LogSettings {
+                cli_level,
+                ..Default::default()
+            };
+            let config = LoggerConfig {
+                log_file: None,
+                verbosity,
+                console_level: brief_console_level(verbosity, &settings),
+                console: true,
+                filter: String::new(),
+                syslog: None,
+            };
+            [Error, Warn, Info, Debug, Trace]
+                .into_iter()
+                .filter(|&level| config.prints_brief(level))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            printed(Verbosity::Normal, None),
+            [Error, Warn, Info, Debug, Trace]
+        );
+        assert_eq!(printed(Verbosity::Quiet, None), [Error, Warn]);
+        // -v and -vv trade the brief output for the tracing one
+        assert_eq!(printed(Verbosity::Verbose, None), []);
+        assert_eq!(printed(Verbosity::Debug, None), []);
+        // -q only ever removes levels
+        assert_eq!(
+            printed(Verbosity::Normal, Some(LogLevel::Info)),
+            [Error, Warn, Info]
+        );
+        assert_eq!(
+            printed(Verbosity::Quiet, Some(LogLevel::Debug)),
+            [Error, Warn]
+        );
+        assert_eq!(printed(Verbosity::Quiet, Some(LogLevel::Error)), [Error]);
+
+        // and no console at all prints nothing, whatever the verbosity
+        let silent = LoggerConfig {
+            log_file: None,
+            verbosity: Verbosity::Normal,
+            console_level: None,
+            console: false,
+            filter: String::new(),
+            syslog: None,
+        };
+        assert!(!silent.prints_brief(Error));
+    }
+
+    #[test]
+    fn test_debug_verbosity_raises_the_filter() {
+        assert_eq!(Verbosity::from_flags(false, 2), Verbosity::Debug);
+        assert_eq!(Verbosity::from_flags(true, 0), Verbosity::Quiet);
+        assert!(Verbosity::Verbose.detailed() && !Verbosity::Quiet.detailed());
+
+        // -vv beats the config's level but not --log-level
+        let filter = |verbosity, settings: LogSettings| {
+            settings.with_verbosity(verbosity).filter_directives(None)
+        };
+        let config_info = LogSettings {
+            config_level: Some(LogLevel::Info),
+            ..Default::default()
+        };
+        assert_eq!(filter(Verbosity::Verbose, config_info.clone()), "info");
+        assert_eq!(filter(Verbosity::Debug, config_info.clone()), "debug");
+        let cli_warn = LogSettings {
+            cli_level: Some(LogLevel::Warn),
+            ..config_info
+        };
+        assert_eq!(filter(Verbosity::Debug, cli_warn), "warn");
+    }
 }
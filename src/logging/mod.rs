@@ -1,9 +1,20 @@
+/// Brief console output
 pub mod console;
+/// Formatting of IDs, names and sizes for display
 pub mod formatter;
+/// Logger setup
 pub mod logger;
+/// Logging macros
 pub mod macros;
+/// Syslog and journald output
+pub mod syslog;
 
 // Re-export public items for easy access
-pub use formatter::{format_client_info, format_service_config, format_uuid};
-pub use logger::{init_logger, LoggerConfig};
+pub use formatter::{
+    format_bytes, format_client_info, format_service_config, format_uuid, redact, sanitize_name,
+    short_id, RedactedBytes,
+};
+pub use logger::{
+    init_file_logger, init_logger, LogLevel, LogSettings, LogTarget, LoggerConfig, Verbosity,
+};
 // pub use macros::*;
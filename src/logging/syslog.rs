@@ -0,0 +1,494 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{SecondsFormat, Utc};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::utils::net;
+
+/// Where `log_target = "syslog"` sends logs without `syslog_addr`, outside journald
+pub const DEFAULT_SYSLOG_ADDR: &str = "/dev/log";
+
+/// Socket of journald's native protocol
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Set by systemd when stderr goes to the journal
+const JOURNAL_STREAM: &str = "JOURNAL_STREAM";
+
+/// APP-NAME of the records, `SYSLOG_IDENTIFIER` in the journal
+const APP_NAME: &str = "sowback";
+
+/// SD-ID span fields are sent under, with the enterprise number reserved for
+/// documentation (RFC 5612)
+const SD_ID: &str = "sowback@32473";
+
+/// Facility of every record: system daemons
+const FACILITY_DAEMON: u8 = 3;
+
+/// How long connecting to or writing to a TCP syslog server may hold up the logging thread
+const TCP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Where syslog records are sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyslogAddr {
+    /// A Unix datagram socket like `/dev/log`
+    Unix(PathBuf),
+    /// A syslog server over UDP, `host:port`
+    Udp(String),
+    /// A syslog server over TCP, `host:port`, records framed by octet counting (RFC 6587)
+    Tcp(String),
+    /// journald's native protocol, span fields becoming journal fields
+    Journald,
+}
+
+impl SyslogAddr {
+    /// Parses `journald`, an absolute socket path, `udp://host:port` or `tcp://host:port`
+    pub fn parse(addr: &str) -> Result<Self, String> {
+        let has_port = |host_port: &str| {
+            host_port
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+        };
+        if addr == "journald" {
+            Ok(Self::Journald)
+        } else if let Some(host_port) = addr.strip_prefix("udp://") {
+            if !has_port(host_port) {
+                return Err(format!("'{}' is not udp://host:port", addr));
+            }
+            Ok(Self::Udp(host_port.to_string()))
+        } else if let Some(host_port) = addr.strip_prefix("tcp://") {
+            if !has_port(host_port) {
+                return Err(format!("'{}' is not tcp://host:port", addr));
+            }
+            Ok(Self::Tcp(host_port.to_string()))
+        } else if addr.starts_with('/') {
+            Ok(Self::Unix(PathBuf::from(addr)))
+        } else {
+            Err(format!(
+                "'{}' is neither journald, a socket path, udp://host:port nor tcp://host:port",
+                addr
+            ))
+        }
+    }
+
+    /// `syslog_addr` if given, else journald when running under it and `/dev/log` otherwise
+    pub fn resolve(configured: Option<&str>) -> Result<Self, String> {
+        match configured {
+            Some(addr) => Self::parse(addr),
+            None if std::env::var_os(JOURNAL_STREAM).is_some() => Ok(Self::Journald),
+            None => Ok(Self::Unix(PathBuf::from(DEFAULT_SYSLOG_ADDR))),
+        }
+    }
+}
+
+impl fmt::Display for SyslogAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "{}", path.display()),
+            Self::Udp(host_port) => write!(f, "udp://{}", host_port),
+            Self::Tcp(host_port) => write!(f, "tcp://{}", host_port),
+            Self::Journald => write!(f, "journald"),
+        }
+    }
+}
+
+/// The socket records are written to
+enum Sink {
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixDatagram),
+    Udp(UdpSocket),
+    Tcp {
+        addr: String,
+        stream: Option<TcpStream>,
+    },
+}
+
+impl Sink {
+    fn open(addr: &SyslogAddr) -> io::Result<Self> {
+        match addr {
+            #[cfg(unix)]
+            SyslogAddr::Unix(path) => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket.connect(path)?;
+                Ok(Self::Unix(socket))
+            }
+            #[cfg(unix)]
+            SyslogAddr::Journald => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket.connect(JOURNALD_SOCKET)?;
+                Ok(Self::Unix(socket))
+            }
+            #[cfg(not(unix))]
+            SyslogAddr::Unix(_) | SyslogAddr::Journald => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "syslog sockets are Unix sockets",
+            )),
+            SyslogAddr::Udp(host_port) => {
+                let target = resolve(host_port)?;
+                let local: SocketAddr = if target.is_ipv4() {
+                    ([0, 0, 0, 0], 0).into()
+                } else {
+                    ([0u16; 8], 0).into()
+                };
+                let socket = UdpSocket::bind(local)?;
+                socket.connect(target)?;
+                Ok(Self::Udp(socket))
+            }
+            SyslogAddr::Tcp(host_port) => {
+                let mut sink = Self::Tcp {
+                    addr: host_port.clone(),
+                    stream: None,
+                };
+                // a server that is down is reported at startup, and retried with each record
+                sink.tcp_stream()?;
+                Ok(sink)
+            }
+        }
+    }
+
+    /// The TCP connection, reconnected after it failed
+    fn tcp_stream(&mut self) -> io::Result<&mut TcpStream> {
+        let Self::Tcp { addr, stream } = self else {
+            unreachable!("not a TCP sink");
+        };
+        if stream.is_none() {
+            let connected = TcpStream::connect_timeout(&resolve(addr)?, TCP_TIMEOUT)?;
+            connected.set_write_timeout(Some(TCP_TIMEOUT))?;
+            *stream = Some(connected);
+        }
+        Ok(stream.as_mut().unwrap())
+    }
+
+    fn send(&mut self, record: &[u8]) -> io::Result<()> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(socket) => socket.send(record).map(drop),
+            Self::Udp(socket) => socket.send(record).map(drop),
+            Self::Tcp { .. } => {
+                let mut framed = format!("{} ", record.len()).into_bytes();
+                framed.extend_from_slice(record);
+                let written = self.tcp_stream()?.write_all(&framed);
+                if written.is_err() {
+                    if let Self::Tcp { stream, .. } = self {
+                        *stream = None;
+                    }
+                }
+                written
+            }
+        }
+    }
+}
+
+/// The first address `host_port` resolves to
+fn resolve(host_port: &str) -> io::Result<SocketAddr> {
+    host_port.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} resolves to no address", host_port),
+        )
+    })
+}
+
+/// Fields of a span, kept in its extensions for the events inside it
+struct SpanFields(Vec<(&'static str, String)>);
+
+/// The message and other fields of an event or span
+#[derive(Default)]
+struct Fields {
+    message: String,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, format!("{:?}", value));
+    }
+}
+
+impl Fields {
+    fn push(&mut self, field: &Field, value: String) {
+        if field.name() == "message" {
+            self.message = value;
+        } else {
+            self.fields.push((field.name(), value));
+        }
+    }
+}
+
+/// Syslog severity of a tracing level
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+/// Tracing layer sending every event to syslog, as RFC 5424 records with the fields of the
+/// spans around it as structured data, or to journald with them as journal fields
+pub struct SyslogLayer {
+    sink: Mutex<Sink>,
+    journald: bool,
+    hostname: String,
+    pid: u32,
+}
+
+impl SyslogLayer {
+    /// Opens the socket to `addr`, failing when nothing listens there
+    pub fn new(addr: &SyslogAddr) -> io::Result<Self> {
+        Ok(Self {
+            sink: Mutex::new(Sink::open(addr)?),
+            journald: *addr == SyslogAddr::Journald,
+            hostname: hostname(),
+            pid: std::process::id(),
+        })
+    }
+
+    /// `<PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID [SD] MSG`
+    fn rfc5424(&self, level: &Level, context: &[(&str, String)], message: &str) -> Vec<u8> {
+        let priority = FACILITY_DAEMON * 8 + severity(level);
+        let structured = if context.is_empty() {
+            "-".to_string()
+        } else {
+            let params: String = context
+                .iter()
+                .map(|(name, value)| format!(" {}=\"{}\"", sd_name(name), sd_value(value)))
+                .collect();
+            format!("[{}{}]", SD_ID, params)
+        };
+        format!(
+            "<{}>1 {} {} {} {} - {} {}",
+            priority,
+            Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.hostname,
+            APP_NAME,
+            self.pid,
+            structured,
+            message
+        )
+        .into_bytes()
+    }
+
+    /// `KEY=value` lines of the native protocol, values with line breaks length-prefixed
+    fn journald(
+        &self,
+        level: &Level,
+        target: &str,
+        context: &[(&str, String)],
+        message: &str,
+    ) -> Vec<u8> {
+        let mut record = Vec::new();
+        let mut field = |name: &str, value: &str| {
+            record.extend_from_slice(name.as_bytes());
+            if value.contains('\n') {
+                record.push(b'\n');
+                record.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            } else {
+                record.push(b'=');
+            }
+            record.extend_from_slice(value.as_bytes());
+            record.push(b'\n');
+        };
+        field("PRIORITY", &severity(level).to_string());
+        field("SYSLOG_IDENTIFIER", APP_NAME);
+        field("SYSLOG_PID", &self.pid.to_string());
+        field("TARGET", target);
+        field("MESSAGE", message);
+        for (name, value) in context {
+            field(&journal_name(name), value);
+        }
+        record
+    }
+}
+
+impl<S> Layer<S> for SyslogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(fields.fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        values.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            if let Some(recorded) = span.extensions_mut().get_mut::<SpanFields>() {
+                recorded.0.extend(fields.fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let mut context = Vec::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(recorded) = span.extensions().get::<SpanFields>() {
+                    context.extend(recorded.0.iter().cloned());
+                }
+            }
+        }
+        context.extend(fields.fields);
+
+        let metadata = event.metadata();
+        let record = if self.journald {
+            self.journald(
+                metadata.level(),
+                metadata.target(),
+                &context,
+                &fields.message,
+            )
+        } else {
+            self.rfc5424(metadata.level(), &context, &fields.message)
+        };
+        // a syslog daemon that went away loses records, nothing else is affected
+        let _ = self.sink.lock().unwrap().send(&record);
+    }
+}
+
+/// PARAM-NAME of structured data: printable ASCII but `=`, space, `]` and `"`, at most
+/// 32 characters
+fn sd_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '=' | ' ' | ']' | '"' => '_',
+            c if c.is_ascii_graphic() => c,
+            _ => '_',
+        })
+        .take(32)
+        .collect()
+}
+
+/// PARAM-VALUE of structured data, `"`, `\` and `]` escaped
+fn sd_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Journal field name: uppercase letters, digits and underscores, starting with a letter
+fn journal_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_uppercase()) {
+        name
+    } else {
+        format!("F{}", name)
+    }
+}
+
+/// HOSTNAME of the records, the nil value `-` when unknown
+fn hostname() -> String {
+    net::hostname()
+        .filter(|name| name.chars().all(|c| c.is_ascii_graphic()))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_syslog_addresses() {
+        assert_eq!(SyslogAddr::parse("journald"), Ok(SyslogAddr::Journald));
+        assert_eq!(
+            SyslogAddr::parse("/dev/log"),
+            Ok(SyslogAddr::Unix(PathBuf::from("/dev/log")))
+        );
+        assert_eq!(
+            SyslogAddr::parse("udp://logs.example.com:514"),
+            Ok(SyslogAddr::Udp("logs.example.com:514".to_string()))
+        );
+        assert_eq!(
+            SyslogAddr::parse("tcp://[::1]:601"),
+            Ok(SyslogAddr::Tcp("[::1]:601".to_string()))
+        );
+        for bad in ["udp://logs.example.com", "tcp://:601", "dev/log", "syslog"] {
+            assert!(SyslogAddr::parse(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(
+            SyslogAddr::resolve(Some("udp://127.0.0.1:514"))
+                .unwrap()
+                .to_string(),
+            "udp://127.0.0.1:514"
+        );
+    }
+
+    #[test]
+    fn test_names_and_values_are_escaped() {
+        assert_eq!(sd_value(r#"a "b" [c] \d"#), r#"a \"b\" [c\] \\d"#);
+        assert_eq!(sd_name("peer addr=x"), "peer_addr_x");
+        assert_eq!(journal_name("conn"), "CONN");
+        assert_eq!(journal_name("remote-port"), "REMOTE_PORT");
+        assert_eq!(journal_name("_private"), "F_PRIVATE");
+    }
+
+    #[test]
+    fn test_journald_records() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = SyslogAddr::Udp(socket.local_addr().unwrap().to_string());
+        let layer = SyslogLayer {
+            journald: true,
+            ..SyslogLayer::new(&addr).unwrap()
+        };
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("local_conn", conn = "ab12cd34");
+            let _entered = span.enter();
+            tracing::warn!("Local service refused\nthe connection");
+        });
+
+        let mut buf = [0u8; 1024];
+        let len = socket.recv(&mut buf).unwrap();
+        let record = &buf[..len];
+        let text = String::from_utf8_lossy(record);
+        assert!(
+            text.starts_with("PRIORITY=4\nSYSLOG_IDENTIFIER=sowback\n"),
+            "{}",
+            text
+        );
+        assert!(text.contains("\nCONN=ab12cd34\n"), "{}", text);
+        // the message spans lines, so it is sent length-prefixed
+        let message = b"Local service refused\nthe connection";
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&(message.len() as u64).to_le_bytes());
+        expected.extend_from_slice(message);
+        assert!(
+            record
+                .windows(expected.len())
+                .any(|window| window == expected),
+            "{}",
+            text
+        );
+    }
+}
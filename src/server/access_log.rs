@@ -0,0 +1,229 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+
+use super::stats::StatsSnapshot;
+use crate::utils::CloseCode;
+
+/// Why a proxy connection ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// The external peer finished first
+    PeerClosed,
+    /// The client finished first, or closed the connection
+    ClientClosed,
+    /// No traffic in either direction for `idle_timeout`
+    IdleTimeout,
+    /// A socket error, or the client could not reach its local service
+    Error,
+    /// The peer left more than `max_buffered_per_connection` of the client's data unread
+    SlowConsumer,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CloseReason::PeerClosed => "peer closed",
+            CloseReason::ClientClosed => "client closed",
+            CloseReason::IdleTimeout => "idle timeout",
+            CloseReason::Error => "error",
+            CloseReason::SlowConsumer => "slow consumer",
+        })
+    }
+}
+
+impl CloseReason {
+    /// The code a connection ending this way is closed with, when neither end sent one
+    pub fn code(self) -> CloseCode {
+        match self {
+            CloseReason::PeerClosed | CloseReason::ClientClosed => CloseCode::PeerClosed,
+            CloseReason::IdleTimeout => CloseCode::IdleTimeout,
+            CloseReason::Error => CloseCode::LocalError,
+            CloseReason::SlowConsumer => CloseCode::PolicyLimit,
+        }
+    }
+}
+
+/// One line of the access log
+#[derive(Debug, Serialize)]
+pub struct AccessEntry<'a> {
+    /// RFC 3339 time the connection ended
+    pub timestamp: String,
+    pub peer: &'a str,
+    pub remote_port: u16,
+    pub client_id: &'a str,
+    pub proxy_id: &'a str,
+    /// Bytes received from the peer
+    pub bytes_in: u64,
+    /// Bytes sent to the peer
+    pub bytes_out: u64,
+    pub duration_ms: u64,
+    pub reason: CloseReason,
+    /// Code of the `CloseConnection` that ended the connection, sent by either end, or the
+    /// code of `reason` when both ends finished sending
+    pub close_code: CloseCode,
+}
+
+impl<'a> AccessEntry<'a> {
+    pub fn new(
+        peer: &'a str,
+        remote_port: u16,
+        client_id: &'a str,
+        proxy_id: &'a str,
+        traffic: StatsSnapshot,
+        duration: std::time::Duration,
+        reason: CloseReason,
+    ) -> Self {
+        Self {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            peer,
+            remote_port,
+            client_id,
+            proxy_id,
+            bytes_in: traffic.bytes_in,
+            bytes_out: traffic.bytes_out,
+            duration_ms: duration.as_millis() as u64,
+            reason,
+            close_code: reason.code(),
+        }
+    }
+
+    /// The entry of a connection ended by a `CloseConnection` carrying `close_code`
+    pub fn with_close_code(self, close_code: CloseCode) -> Self {
+        Self { close_code, ..self }
+    }
+}
+
+/// JSON lines access log of proxy connections. Lines are written on a background
+/// thread, so a slow disk never stalls forwarding; they are dropped if it falls far behind
+#[derive(Clone)]
+pub struct AccessLog {
+    writer: NonBlocking,
+    reopen: Arc<AtomicBool>,
+    /// Flushes the remaining lines once the last clone is dropped
+    _guard: Arc<WorkerGuard>,
+}
+
+impl AccessLog {
+    pub fn open(path: &str) -> Result<Self> {
+        let file = ReopeningFile::open(path)
+            .map_err(|e| anyhow!("Failed to open access log {}: {}", path, e))?;
+        let reopen = file.reopen.clone();
+        let (writer, guard) = tracing_appender::non_blocking(file);
+        Ok(Self {
+            writer,
+            reopen,
+            _guard: Arc::new(guard),
+        })
+    }
+
+    pub fn record(&self, entry: &AccessEntry) {
+        let Ok(mut line) = serde_json::to_vec(entry) else {
+            return;
+        };
+        line.push(b'\n');
+        let _ = self.writer.clone().write_all(&line);
+    }
+
+    /// Reopens the file by its path before the next line, after logrotate moved it away
+    pub fn reopen(&self) {
+        self.reopen.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Appends to a file, opening it again by path when asked to
+struct ReopeningFile {
+    path: String,
+    file: File,
+    reopen: Arc<AtomicBool>,
+}
+
+impl ReopeningFile {
+    fn open(path: &str) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_string(),
+            file: Self::append(path)?,
+            reopen: Arc::default(),
+        })
+    }
+
+    fn append(path: &str) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+}
+
+impl Write for ReopeningFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.reopen.swap(false, Ordering::Relaxed) {
+            // keep writing to the old file if the new one cannot be opened
+            if let Ok(file) = Self::append(&self.path) {
+                self.file = file;
+            }
+        }
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn entry(reason: CloseReason) -> AccessEntry<'static> {
+        let traffic = StatsSnapshot {
+            connections: 1,
+            bytes_in: 120,
+            bytes_out: 4096,
+            ..Default::default()
+        };
+        AccessEntry::new(
+            "203.0.113.7:51234",
+            8080,
+            "client",
+            "proxy",
+            traffic,
+            Duration::from_millis(1500),
+            reason,
+        )
+    }
+
+    #[test]
+    fn test_lines_survive_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let rotated = dir.path().join("access.log.1");
+        let log = AccessLog::open(path.to_str().unwrap()).unwrap();
+
+        log.record(&entry(CloseReason::PeerClosed));
+        std::thread::sleep(Duration::from_millis(100));
+        std::fs::rename(&path, &rotated).unwrap();
+        log.reopen();
+        log.record(&entry(CloseReason::IdleTimeout).with_close_code(CloseCode::IdleTimeout));
+        drop(log);
+
+        let old = std::fs::read_to_string(&rotated).unwrap();
+        let line: serde_json::Value = serde_json::from_str(old.trim()).unwrap();
+        assert_eq!(line["peer"], "203.0.113.7:51234");
+        assert_eq!(line["remote_port"], 8080);
+        assert_eq!(line["bytes_out"], 4096);
+        assert_eq!(line["duration_ms"], 1500);
+        assert_eq!(line["reason"], "peer_closed");
+
+        assert_eq!(line["close_code"], "peer_closed");
+
+        let new = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(new.lines().count(), 1);
+        assert!(new.contains(r#""reason":"idle_timeout""#));
+        assert!(new.contains(r#""close_code":"idle_timeout""#));
+    }
+}
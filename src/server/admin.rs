@@ -0,0 +1,528 @@
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration};
+
+use super::http::MAX_HEAD_LEN;
+use super::ip_ban::IpRange;
+use super::Server;
+use crate::config::HumanDuration;
+use crate::logging::format_uuid;
+use crate::utils::crypto::secret_hashes_match;
+use crate::utils::{net, CloseCode, Message};
+use crate::{error, log_debug, log_warn, warn};
+
+/// Largest request body the admin API reads
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+/// How long a connection to the admin API may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why the session of a kicked client ended, as told to the client
+pub(super) const KICK_REASON: &str = "Kicked by an administrator";
+
+/// An admin API request, the parts the routes look at
+#[derive(Debug, PartialEq, Eq)]
+struct AdminRequest {
+    method: String,
+    path: String,
+    /// Token of an `Authorization: Bearer` header
+    bearer: Option<String>,
+    body: Vec<u8>,
+}
+
+/// A failed request: the status to answer with and what went wrong
+type Refusal = (u16, String);
+
+impl Server {
+    /// Serves the admin API on `listener`, one request per connection
+    pub(super) async fn serve_admin(&self, listener: Arc<TcpListener>) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    let server = self.clone();
+                    tokio::spawn(async move { server.handle_admin_connection(stream, addr).await });
+                }
+                Err(e) if net::is_fd_exhaustion(&e) => {
+                    log_warn!("Out of file descriptors accepting admin connections: {}", e);
+                    tokio::time::sleep(net::FD_EXHAUSTION_BACKOFF).await;
+                }
+                Err(e) => {
+                    error!("Failed to accept admin connection: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Reads one request, runs it if its token is accepted, and answers with JSON:
+    /// what was asked for or `{"message": ...}` on success, `{"error": ...}` otherwise
+    async fn handle_admin_connection(&self, mut stream: TcpStream, addr: SocketAddr) {
+        if self.auth_bans.is_banned(addr.ip()) {
+            log_debug!("Dropping admin connection from banned {}", addr);
+            return;
+        }
+        let outcome = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+            Ok(Ok(request)) => self.admin_request(request, addr).await,
+            Ok(Err(e)) => Err((400, e.to_string())),
+            Err(_) => Err((408, "Request timed out".to_string())),
+        };
+        let (status, body) = match outcome {
+            Ok(body) => (200, body),
+            Err((status, error)) => (status, json!({ "error": error })),
+        };
+        let _ = stream.write_all(&response(status, &body)).await;
+        let _ = stream.shutdown().await;
+    }
+
+    /// Authenticates `request` and runs the action it names, returning what was asked
+    /// for or what was done
+    async fn admin_request(
+        &self,
+        request: AdminRequest,
+        addr: SocketAddr,
+    ) -> std::result::Result<Value, Refusal> {
+        let Some(admin) = self.admin_identity(request.bearer.as_deref()) else {
+            self.record_auth_failure(addr.ip());
+            return Err((401, "Missing or invalid admin token".to_string()));
+        };
+        let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+        let method = match segments.as_slice() {
+            ["api", "clients"] | ["api", "ports"] => "GET",
+            ["api", "clients", _, "kick"]
+            | ["api", "connections", _, "close"]
+            | ["api", "bans"] => "POST",
+            _ => return Err((404, format!("No such endpoint {}", request.path))),
+        };
+        if request.method != method {
+            return Err((405, format!("{} only accepts {}", request.path, method)));
+        }
+        let message = match segments.as_slice() {
+            ["api", "clients"] => return Ok(self.list_clients().await),
+            ["api", "ports"] => return Ok(self.list_ports().await),
+            ["api", "clients", id, "kick"] => self.kick_client(&admin, id).await?,
+            ["api", "connections", id, "close"] => self.close_connection(&admin, id).await?,
+            _ => self.ban_peers(&admin, &request.body)?,
+        };
+        Ok(json!({ "message": message }))
+    }
+
+    /// The connected clients with what they run on and their proxies, with what their
+    /// clients last reported about the local services
+    async fn list_clients(&self) -> Value {
+        let clients_guard = self.clients.read().await;
+        let mut clients: Vec<_> = clients_guard.values().collect();
+        clients.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        let clients: Vec<Value> = clients
+            .into_iter()
+            .map(|client| {
+                let mut proxies: Vec<_> = client.proxies.iter().collect();
+                proxies.sort_by(|(_, a), (_, b)| a.name.cmp(&b.name));
+                let proxies: Vec<Value> = proxies
+                    .into_iter()
+                    .map(|(proxy_id, proxy)| {
+                        json!({
+                            "id": proxy_id,
+                            "name": proxy.name,
+                            "local": net::join_host_port(&proxy.local_ip, proxy.local_port),
+                            "remote_port": proxy.remote_port,
+                            "local_error": proxy.local_error,
+                        })
+                    })
+                    .collect();
+                json!({
+                    "id": client.client_id,
+                    "name": client.name,
+                    "addr": client.addr.to_string(),
+                    "meta": client.meta,
+                    "proxies": proxies,
+                })
+            })
+            .collect();
+        json!({ "clients": clients })
+    }
+
+    /// The bound service ports with the client holding each, the proxies serving it and
+    /// how long it is bound
+    async fn list_ports(&self) -> Value {
+        let listeners_guard = self.proxy_listeners.read().await;
+        let clients_guard = self.clients.read().await;
+        let mut listeners: Vec<_> = listeners_guard.iter().collect();
+        listeners.sort_by_key(|(port, _)| **port);
+        let ports: Vec<Value> = listeners
+            .into_iter()
+            .map(|(port, listener)| {
+                let bind_host = listener
+                    .listener
+                    .local_addr()
+                    .map(|addr| addr.ip().to_string())
+                    .unwrap_or_default();
+                let proxies: Vec<&str> = listener
+                    .members
+                    .iter()
+                    .map(|member| member.proxy_id.as_str())
+                    .collect();
+                json!({
+                    "port": port,
+                    "bind_host": bind_host,
+                    "client_id": listener.client_id,
+                    "client_name": clients_guard
+                        .get(&listener.client_id)
+                        .and_then(|client| client.name.as_deref()),
+                    "group": listener.group,
+                    "proxies": proxies,
+                    "orphaned": listener.orphaned.is_some(),
+                    "uptime_secs": listener.bound_at.elapsed().as_secs(),
+                })
+            })
+            .collect();
+        json!({ "ports": ports })
+    }
+
+    /// Name of the admin token `bearer` is, if it is one
+    fn admin_identity(&self, bearer: Option<&str>) -> Option<String> {
+        let bearer = bearer?;
+        self.config
+            .admin
+            .as_ref()?
+            .tokens
+            .iter()
+            .find(|entry| secret_hashes_match(entry.token.as_bytes(), bearer.as_bytes()))
+            .map(|entry| entry.name.clone())
+    }
+
+    /// Ends the session of a client like `cleanup_client` does for a departed one,
+    /// telling the client why first. `id` may be a unique prefix of the client ID
+    pub(super) async fn kick_client(
+        &self,
+        admin: &str,
+        id: &str,
+    ) -> std::result::Result<String, Refusal> {
+        let (client_id, label) = {
+            let mut clients_guard = self.clients.write().await;
+            let client_id = find_id(clients_guard.keys(), id, "client")?;
+            let client = clients_guard
+                .get_mut(&client_id)
+                .expect("found among the clients");
+            client.close(KICK_REASON);
+            (client_id, client.label())
+        };
+        // dropping the session's sender ends its writer, closing the control connection
+        // once the message above is written
+        self.cleanup_client(&client_id).await;
+        warn!("Admin '{}' kicked client {}", admin, label);
+        Ok(format!("Kicked client {}", client_id))
+    }
+
+    /// Closes one proxy connection and tells its client to close its end.
+    /// `id` may be a unique prefix of the connection ID
+    async fn close_connection(
+        &self,
+        admin: &str,
+        id: &str,
+    ) -> std::result::Result<String, Refusal> {
+        let (connection_id, connection) = {
+            let mut proxy_connections_guard = self.proxy_connections.write().await;
+            let connection_id = find_id(proxy_connections_guard.keys(), id, "connection")?;
+            let connection = proxy_connections_guard.remove(&connection_id);
+            (connection_id, connection)
+        };
+        // without its entry the connection's writer stops, which ends the connection
+        if let Some(connection) = connection {
+            let _ = connection.close_code.set(CloseCode::PolicyLimit);
+            if let Some(client) = self.clients.read().await.get(&connection.client_id) {
+                let _ = client.sender.send(Message::new_close_connection(
+                    &connection_id,
+                    CloseCode::PolicyLimit,
+                ));
+            }
+        }
+        warn!(
+            "Admin '{}' closed connection {}",
+            admin,
+            format_uuid(&connection_id, "conn")
+        );
+        Ok(format!("Closed connection {}", connection_id))
+    }
+
+    /// Bans the `target` address or CIDR block of a `{"target": ..., "duration": ...}`
+    /// body from the control port and every proxy port for `duration`
+    fn ban_peers(&self, admin: &str, body: &[u8]) -> std::result::Result<String, Refusal> {
+        let body: Value =
+            serde_json::from_slice(body).map_err(|e| (400, format!("Invalid JSON body: {}", e)))?;
+        let field = |name: &str| {
+            body.get(name)
+                .and_then(Value::as_str)
+                .ok_or_else(|| (400, format!("Missing string field '{}'", name)))
+        };
+        let range: IpRange = field("target")?
+            .parse()
+            .map_err(|e: anyhow::Error| (400, e.to_string()))?;
+        let duration: HumanDuration = field("duration")?
+            .parse()
+            .map_err(|e: anyhow::Error| (400, e.to_string()))?;
+        if duration.0.is_zero() {
+            return Err((400, "The duration must be longer than 0s".to_string()));
+        }
+        self.ip_bans.ban(range, duration.0);
+        warn!("Admin '{}' banned {} for {}", admin, range, duration);
+        Ok(format!("Banned {} for {}", range, duration))
+    }
+}
+
+/// The one ID of `ids` that is `id` or starts with it, so the short IDs in logs work too
+fn find_id<'a>(
+    ids: impl Iterator<Item = &'a String>,
+    id: &str,
+    kind: &str,
+) -> std::result::Result<String, Refusal> {
+    let matches: Vec<&String> = ids.filter(|candidate| candidate.starts_with(id)).collect();
+    match matches.as_slice() {
+        _ if id.is_empty() => Err((400, format!("No {} ID given", kind))),
+        [only] => Ok(only.to_string()),
+        _ if matches.iter().any(|candidate| candidate.as_str() == id) => Ok(id.to_string()),
+        [] => Err((404, format!("No {} {}", kind, id))),
+        _ => Err((400, format!("'{}' matches several {}s", id, kind))),
+    }
+}
+
+/// Reads a request head and its `Content-Length` body
+async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> Result<AdminRequest> {
+    let mut read = Vec::new();
+    let head_end = loop {
+        if let Some(end) = read.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if read.len() > MAX_HEAD_LEN {
+            return Err(anyhow!("Request head too large"));
+        }
+        let mut chunk = [0u8; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(anyhow!("Connection closed before the request was complete"));
+        }
+        read.extend_from_slice(&chunk[..n]);
+    };
+    let head = std::str::from_utf8(&read[..head_end])
+        .map_err(|_| anyhow!("Request head is not valid UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let (method, path) = match request_line.split(' ').collect::<Vec<_>>().as_slice() {
+        [method, path, _] => (method.to_string(), path.to_string()),
+        _ => return Err(anyhow!("Malformed request line '{}'", request_line)),
+    };
+
+    let mut bearer = None;
+    let mut content_length = 0;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(anyhow!("Malformed header line '{}'", line));
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "authorization" => {
+                bearer = value
+                    .split_once(' ')
+                    .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+                    .map(|(_, token)| token.trim().to_string());
+            }
+            "content-length" => {
+                content_length = value
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid Content-Length '{}'", value))?;
+            }
+            _ => {}
+        }
+    }
+    if content_length > MAX_BODY_LEN {
+        return Err(anyhow!("Request body larger than {} bytes", MAX_BODY_LEN));
+    }
+
+    let mut body = read.split_off(head_end + 4);
+    if body.len() < content_length {
+        let mut rest = vec![0u8; content_length - body.len()];
+        stream.read_exact(&mut rest).await?;
+        body.extend_from_slice(&rest);
+    }
+    body.truncate(content_length);
+    Ok(AdminRequest {
+        method,
+        path,
+        bearer,
+        body,
+    })
+}
+
+/// A complete JSON response, closing the connection
+fn response(status: u16, body: &Value) -> Vec<u8> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        _ => "Error",
+    };
+    let body = body.to_string();
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+/// Client of a server's admin API, see `[server.admin]`. Each call is one request
+/// authenticated with `token`, failing with the error the server answered
+pub struct AdminClient {
+    addr: String,
+    token: String,
+}
+
+impl AdminClient {
+    /// A client of the admin API served on `addr`, `host:port`
+    pub fn new(addr: impl Into<String>, token: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            token: token.into(),
+        }
+    }
+
+    /// The connected clients and their proxies, as `{"clients": [...]}`
+    pub async fn clients(&self) -> Result<Value> {
+        self.request("GET", "/api/clients", None).await
+    }
+
+    /// The bound service ports and the clients holding them, as `{"ports": [...]}`
+    pub async fn ports(&self) -> Result<Value> {
+        self.request("GET", "/api/ports", None).await
+    }
+
+    /// Disconnects a client, `client_id` may be a unique prefix of its ID
+    pub async fn kick(&self, client_id: &str) -> Result<String> {
+        self.post(&format!("/api/clients/{}/kick", client_id), None)
+            .await
+    }
+
+    /// Closes a proxy connection, `connection_id` may be a unique prefix of its ID
+    pub async fn close(&self, connection_id: &str) -> Result<String> {
+        self.post(&format!("/api/connections/{}/close", connection_id), None)
+            .await
+    }
+
+    /// Refuses connections from `target`, an IP address or CIDR block, for `duration`
+    pub async fn ban(&self, target: &str, duration: &str) -> Result<String> {
+        let body = json!({ "target": target, "duration": duration });
+        self.post("/api/bans", Some(body)).await
+    }
+
+    /// POSTs `body` to `path`, returning the message of a successful answer
+    async fn post(&self, path: &str, body: Option<Value>) -> Result<String> {
+        let answer = self.request("POST", path, body).await?;
+        Ok(answer
+            .get("message")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string())
+    }
+
+    /// Sends a request for `path`, returning the JSON of a successful answer
+    async fn request(&self, method: &str, path: &str, body: Option<Value>) -> Result<Value> {
+        let mut stream = TcpStream::connect(self.addr.as_str())
+            .await
+            .map_err(|e| anyhow!("Failed to connect to the admin API at {}: {}", self.addr, e))?;
+        let body = body.map(|body| body.to_string()).unwrap_or_default();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: sowback/{}\r\nAuthorization: Bearer {}\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            self.addr,
+            env!("CARGO_PKG_VERSION"),
+            self.token,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut answer = Vec::new();
+        stream
+            .take((MAX_HEAD_LEN + MAX_BODY_LEN) as u64)
+            .read_to_end(&mut answer)
+            .await?;
+        let answer = String::from_utf8_lossy(&answer);
+        let (head, body) = answer
+            .split_once("\r\n\r\n")
+            .ok_or_else(|| anyhow!("{} answered with an incomplete response", self.addr))?;
+        let status_line = head.lines().next().unwrap_or_default();
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| anyhow!("{} answered '{}'", self.addr, status_line))?;
+        let body: Value = serde_json::from_str(body).unwrap_or_default();
+        match status {
+            200..=299 => Ok(body),
+            _ => Err(anyhow!(body
+                .get("error")
+                .and_then(Value::as_str)
+                .unwrap_or(status_line)
+                .to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let raw = b"POST /api/bans HTTP/1.1\r\nHost: x\r\nauthorization: bearer s3cret\r\n\
+                    Content-Length: 13\r\n\r\n{\"target\":1}\n";
+        let request = read_request(&mut &raw[..]).await.unwrap();
+        assert_eq!(
+            request,
+            AdminRequest {
+                method: "POST".to_string(),
+                path: "/api/bans".to_string(),
+                bearer: Some("s3cret".to_string()),
+                body: b"{\"target\":1}\n".to_vec(),
+            }
+        );
+
+        let request =
+            read_request(&mut &b"GET / HTTP/1.1\r\nAuthorization: Basic eDp5\r\n\r\n"[..])
+                .await
+                .unwrap();
+        assert_eq!(request.bearer, None);
+        assert!(request.body.is_empty());
+
+        for invalid in [
+            &b"POST /api/bans\r\n\r\n"[..],
+            b"POST / HTTP/1.1\r\nContent-Length: 99999999\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort",
+            b"POST / HTTP/1.1\r\n",
+        ] {
+            assert!(read_request(&mut &invalid[..]).await.is_err());
+        }
+    }
+
+    #[test]
+    fn test_find_id() {
+        let ids = [
+            "0b6f1c2e-1111-4000-8000-000000000000".to_string(),
+            "0b6f9999-2222-4000-8000-000000000000".to_string(),
+        ];
+        assert_eq!(find_id(ids.iter(), "0b6f1", "client").unwrap(), ids[0]);
+        assert_eq!(find_id(ids.iter(), &ids[1], "client").unwrap(), ids[1]);
+        assert_eq!(find_id(ids.iter(), "0b6f", "client").unwrap_err().0, 400);
+        assert_eq!(find_id(ids.iter(), "ffff", "client").unwrap_err().0, 404);
+        assert_eq!(find_id(ids.iter(), "", "client").unwrap_err().0, 400);
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Recent authentication failures of one source IP
+#[derive(Debug, Default)]
+struct Failures {
+    /// Failures within the window, oldest first
+    recent: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+}
+
+/// Bans source IPs that keep failing authentication, so tokens cannot be brute-forced
+/// as fast as TCP allows. `limit` failures within `window` ban the IP for `ban`
+#[derive(Clone)]
+pub struct AuthBans {
+    limit: usize,
+    window: Duration,
+    ban: Duration,
+    failures: Arc<Mutex<HashMap<IpAddr, Failures>>>,
+}
+
+impl AuthBans {
+    pub fn new(limit: usize, window: Duration, ban: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            ban,
+            failures: Arc::default(),
+        }
+    }
+
+    /// Whether connections from `ip` are currently refused
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.is_banned_at(ip, Instant::now())
+    }
+
+    fn is_banned_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let failures = self.failures.lock().unwrap();
+        failures
+            .get(&ip)
+            .and_then(|failures| failures.banned_until)
+            .is_some_and(|until| now < until)
+    }
+
+    /// Records a failed authentication from `ip`, returns true if it got the IP banned
+    pub fn failed(&self, ip: IpAddr) -> bool {
+        self.failed_at(ip, Instant::now())
+    }
+
+    fn failed_at(&self, ip: IpAddr, now: Instant) -> bool {
+        if self.limit == 0 {
+            return false;
+        }
+        let mut failures = self.failures.lock().unwrap();
+        // forget IPs that have been quiet for a window, so the table stays bounded
+        failures.retain(|_, entry| {
+            Self::expire(entry, now, self.window);
+            !entry.recent.is_empty() || entry.banned_until.is_some()
+        });
+
+        let entry = failures.entry(ip).or_default();
+        entry.recent.push_back(now);
+        if entry.recent.len() < self.limit {
+            return false;
+        }
+        entry.recent.clear();
+        entry.banned_until = Some(now + self.ban);
+        true
+    }
+
+    /// Drops failures older than `window` and bans that are over
+    fn expire(entry: &mut Failures, now: Instant, window: Duration) {
+        while entry
+            .recent
+            .front()
+            .is_some_and(|at| now.saturating_duration_since(*at) >= window)
+        {
+            entry.recent.pop_front();
+        }
+        if entry.banned_until.is_some_and(|until| now >= until) {
+            entry.banned_until = None;
+        }
+    }
+
+    /// Forgets the failures of `ip` after it authenticated successfully
+    pub fn succeeded(&self, ip: IpAddr) {
+        self.failures.lock().unwrap().remove(&ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failures_within_window_ban_until_expiry() {
+        let bans = AuthBans::new(3, Duration::from_secs(60), Duration::from_secs(300));
+        let attacker: IpAddr = "203.0.113.7".parse().unwrap();
+        let start = Instant::now();
+
+        // failures spread beyond the window never add up to a ban
+        assert!(!bans.failed_at(attacker, start));
+        assert!(!bans.failed_at(attacker, start + Duration::from_secs(50)));
+        assert!(!bans.failed_at(attacker, start + Duration::from_secs(100)));
+        assert!(!bans.is_banned_at(attacker, start + Duration::from_secs(100)));
+
+        let now = start + Duration::from_secs(101);
+        assert!(bans.failed_at(attacker, now));
+        assert!(bans.is_banned_at(attacker, now));
+        assert!(bans.is_banned_at(attacker, now + Duration::from_secs(299)));
+        assert!(!bans.is_banned_at(attacker, now + Duration::from_secs(300)));
+
+        // a successful login clears the count
+        let other: IpAddr = "198.51.100.1".parse().unwrap();
+        assert!(!bans.failed_at(other, now));
+        assert!(!bans.failed_at(other, now));
+        bans.succeeded(other);
+        assert!(!bans.failed_at(other, now));
+
+        // quiet IPs are forgotten
+        bans.failed_at(other, now + Duration::from_secs(1000));
+        let failures = bans.failures.lock().unwrap();
+        assert_eq!(failures.keys().collect::<Vec<_>>(), vec![&other]);
+    }
+
+    #[test]
+    fn test_zero_limit_never_bans() {
+        let bans = AuthBans::new(0, Duration::from_secs(60), Duration::from_secs(300));
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        for _ in 0..10 {
+            assert!(!bans.failed(ip));
+        }
+        assert!(!bans.is_banned(ip));
+    }
+}
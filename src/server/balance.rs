@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How a listener shared by a group picks the member serving a new connection
+#[derive(Debug)]
+pub enum Balance {
+    /// Members take turns, in the order they joined
+    RoundRobin {
+        /// Turn of the next connection, taken modulo the members online
+        next: AtomicUsize,
+    },
+}
+
+impl Default for Balance {
+    fn default() -> Self {
+        Self::RoundRobin {
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Balance {
+    /// Picks the member of `candidates` to serve a new connection, None if there is none
+    pub fn pick<'a, T>(&self, candidates: &'a [T]) -> Option<&'a T> {
+        if candidates.is_empty() {
+            return None;
+        }
+        match self {
+            Self::RoundRobin { next } => {
+                let turn = next.fetch_add(1, Ordering::Relaxed);
+                candidates.get(turn % candidates.len())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_robin_takes_turns() {
+        let balance = Balance::default();
+        assert_eq!(balance.pick::<&str>(&[]), None);
+
+        let members = ["a", "b", "c"];
+        let picked: Vec<_> = (0..6).map(|_| *balance.pick(&members).unwrap()).collect();
+        assert_eq!(picked, ["a", "b", "c", "a", "b", "c"]);
+
+        // a member left, the others keep taking turns
+        let picked: Vec<_> = (0..3)
+            .map(|_| *balance.pick(&members[..2]).unwrap())
+            .collect();
+        assert_eq!(picked.len(), 3);
+        assert!(picked.contains(&"a") && picked.contains(&"b"));
+    }
+}
@@ -0,0 +1,105 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::Server;
+use crate::utils::net;
+use crate::{log_debug, log_info, warn};
+
+/// File descriptors set apart from `max_connections` for listeners, log files and outgoing
+/// connections when comparing it with the open files limit
+const FD_HEADROOM: u64 = 64;
+
+/// Bounds the connections the server holds open at once across its control, proxy and
+/// shared ports, so it sheds load by refusing connections before the OS runs out of file
+/// descriptors
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    permits: Option<Arc<Semaphore>>,
+}
+
+/// One accepted connection, counted against the limit until dropped
+pub struct ConnectionPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ConnectionLimit {
+    /// At most `limit` open connections, 0 is unlimited
+    pub fn new(limit: usize) -> Self {
+        Self {
+            permits: (limit > 0).then(|| Arc::new(Semaphore::new(limit))),
+        }
+    }
+
+    /// Counts an accepted connection, None when the limit is reached and it is to be closed
+    pub fn admit(&self) -> Option<ConnectionPermit> {
+        let permit = match &self.permits {
+            Some(permits) => Some(permits.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+        Some(ConnectionPermit { _permit: permit })
+    }
+}
+
+impl Server {
+    /// Counts a connection accepted from `addr` on a `kind` port, None when `max_connections`
+    /// are open and it is to be closed at once
+    pub(super) fn admit_connection(
+        &self,
+        addr: SocketAddr,
+        kind: &str,
+    ) -> Option<ConnectionPermit> {
+        let permit = self.connection_limit.admit();
+        if permit.is_none() {
+            log_debug!(
+                "{} connections open, refusing {} connection from {}",
+                self.config.max_connections,
+                kind,
+                addr
+            );
+        }
+        permit
+    }
+
+    /// Logs the open files limit of the process, warning when it is too low for
+    /// `max_connections`: each connection takes a file descriptor
+    pub(super) fn check_open_files_limit(&self) {
+        let Some(open_files) = net::open_files_limit() else {
+            return;
+        };
+        let max_connections = self.config.max_connections;
+        if max_connections == 0 {
+            log_info!("Open files limit: {}, connections unlimited", open_files);
+        } else if max_connections as u64 + FD_HEADROOM > open_files {
+            warn!(
+                "Open files limit {} leaves too few file descriptors for max_connections = {}, raise it (ulimit -n) or lower max_connections",
+                open_files, max_connections
+            );
+        } else {
+            log_info!(
+                "Open files limit: {}, at most {} connections",
+                open_files,
+                max_connections
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permits_are_limited_and_given_back() {
+        let limit = ConnectionLimit::new(2);
+        let first = limit.admit().unwrap();
+        let _second = limit.admit().unwrap();
+        assert!(limit.admit().is_none());
+        drop(first);
+        assert!(limit.admit().is_some());
+
+        let unlimited = ConnectionLimit::new(0);
+        let held: Vec<_> = (0..100).filter_map(|_| unlimited.admit()).collect();
+        assert_eq!(held.len(), 100);
+    }
+}
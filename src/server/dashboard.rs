@@ -0,0 +1,394 @@
+use anyhow::Result;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::mpsc;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+
+use super::Server;
+use crate::logging::console::{capture_console, release_console, ConsoleLevel, Scrollback};
+use crate::logging::{format_bytes, short_id};
+use crate::warn;
+
+/// Name the dashboard kicks clients under, as an admin token's name in the admin API
+const DASHBOARD_ADMIN: &str = "dashboard";
+
+/// Seconds of samples the throughput is averaged over
+const THROUGHPUT_WINDOW: usize = 5;
+
+/// How often the input thread checks whether the dashboard is still open
+const INPUT_POLL: Duration = Duration::from_millis(100);
+
+/// A client as the dashboard shows it
+struct ClientRow {
+    client_id: String,
+    name: String,
+    addr: String,
+    proxies: Vec<ProxyRow>,
+}
+
+/// A proxy as the dashboard shows it, its rates in bytes per second
+struct ProxyRow {
+    name: String,
+    remote_port: u16,
+    active: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    rate_in: u64,
+    rate_out: u64,
+}
+
+/// Byte rates of proxies, from the totals sampled once a second
+#[derive(Default)]
+struct Throughput {
+    samples: HashMap<String, VecDeque<(u64, u64)>>,
+}
+
+impl Throughput {
+    /// Records the byte totals of `proxy_id`, returning its bytes per second in and out
+    /// over the samples kept
+    fn sample(&mut self, proxy_id: &str, bytes_in: u64, bytes_out: u64) -> (u64, u64) {
+        let samples = self.samples.entry(proxy_id.to_string()).or_default();
+        if samples.len() > THROUGHPUT_WINDOW {
+            samples.pop_front();
+        }
+        samples.push_back((bytes_in, bytes_out));
+        let (first_in, first_out) = samples[0];
+        let seconds = (samples.len() as u64 - 1).max(1);
+        (
+            bytes_in.saturating_sub(first_in) / seconds,
+            bytes_out.saturating_sub(first_out) / seconds,
+        )
+    }
+}
+
+/// Restores the terminal when the dashboard closes, however it does
+struct TerminalGuard;
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        ratatui::restore();
+        release_console();
+    }
+}
+
+impl Server {
+    /// Runs the server like `run`, drawing a live view of its clients, their proxies and
+    /// traffic on the terminal meanwhile. Brief console output goes to a pane of the view.
+    /// `q` shuts the server down, `k` kicks the selected client
+    pub async fn run_with_dashboard(&self, shutdown: CancellationToken) -> Result<()> {
+        let scrollback = capture_console();
+        let guard = TerminalGuard;
+        let mut terminal = ratatui::try_init()?;
+
+        let running = self.run(shutdown.clone());
+        tokio::pin!(running);
+        let drawn = tokio::select! {
+            // e.g. the control port could not be bound
+            result = &mut running => {
+                drop(guard);
+                return result;
+            }
+            drawn = self.draw_dashboard(&mut terminal, &scrollback, &shutdown) => drawn,
+        };
+        drop(guard);
+        if let Err(e) = drawn {
+            warn!("Dashboard failed, shutting down: {}", e);
+            shutdown.cancel();
+        }
+        running.await
+    }
+
+    /// Redraws the dashboard once a second and on every key until it is quit
+    async fn draw_dashboard(
+        &self,
+        terminal: &mut DefaultTerminal,
+        scrollback: &Scrollback,
+        shutdown: &CancellationToken,
+    ) -> Result<()> {
+        let mut keys = spawn_input_thread();
+        let mut throughput = Throughput::default();
+        let mut selected = TableState::default().with_selected(0);
+        let mut refresh = interval(Duration::from_secs(1));
+        refresh.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut rows = Vec::new();
+
+        loop {
+            tokio::select! {
+                _ = refresh.tick() => {
+                    rows = self.dashboard_rows(&mut throughput).await;
+                }
+                key = keys.recv() => {
+                    let Some(key) = key else {
+                        return Err(anyhow::anyhow!("terminal input closed"));
+                    };
+                    let quit = key.code == KeyCode::Char('q')
+                        || (key.code == KeyCode::Char('c')
+                            && key.modifiers.contains(KeyModifiers::CONTROL));
+                    if quit {
+                        shutdown.cancel();
+                        return Ok(());
+                    }
+                    match key.code {
+                        KeyCode::Up => selected.select_previous(),
+                        KeyCode::Down => selected.select_next(),
+                        KeyCode::Char('k') => {
+                            let Some(index) = selected.selected().filter(|&index| index < rows.len())
+                            else {
+                                continue;
+                            };
+                            let client = rows.remove(index);
+                            // a client that left already needs no kick
+                            let _ = self.kick_client(DASHBOARD_ADMIN, &client.client_id).await;
+                        }
+                        _ => continue,
+                    }
+                }
+                _ = shutdown.cancelled() => return Ok(()),
+            }
+            // clamp the selection to the clients left
+            let last = rows.len().saturating_sub(1);
+            selected.select(Some(selected.selected().unwrap_or(0).min(last)));
+            terminal.draw(|frame| render(frame, &rows, &mut selected, scrollback))?;
+        }
+    }
+
+    /// The connected clients and their proxies, sorted, sampling the proxies' traffic
+    async fn dashboard_rows(&self, throughput: &mut Throughput) -> Vec<ClientRow> {
+        let clients_guard = self.clients.read().await;
+        let mut clients: Vec<_> = clients_guard.values().collect();
+        clients.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        let rows: Vec<ClientRow> = clients
+            .into_iter()
+            .map(|client| {
+                let mut proxies: Vec<_> = client.proxies.iter().collect();
+                proxies.sort_by_key(|(_, proxy)| proxy.remote_port);
+                let proxies = proxies
+                    .into_iter()
+                    .map(|(proxy_id, proxy)| {
+                        let stats = proxy.stats.snapshot();
+                        let (rate_in, rate_out) =
+                            throughput.sample(proxy_id, stats.bytes_in, stats.bytes_out);
+                        ProxyRow {
+                            name: proxy.name.clone(),
+                            remote_port: proxy.remote_port,
+                            active: stats.active,
+                            bytes_in: stats.bytes_in,
+                            bytes_out: stats.bytes_out,
+                            rate_in,
+                            rate_out,
+                        }
+                    })
+                    .collect();
+                ClientRow {
+                    client_id: client.client_id.clone(),
+                    name: client.name.clone().unwrap_or_default(),
+                    addr: client.addr.to_string(),
+                    proxies,
+                }
+            })
+            .collect();
+        let proxy_ids: Vec<&String> = clients_guard
+            .values()
+            .flat_map(|client| client.proxies.keys())
+            .collect();
+        throughput
+            .samples
+            .retain(|proxy_id, _| proxy_ids.contains(&proxy_id));
+        rows
+    }
+}
+
+/// Reads terminal keys on a thread of its own, the reads block. The thread ends once
+/// the returned receiver is dropped
+fn spawn_input_thread() -> mpsc::UnboundedReceiver<KeyEvent> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while !tx.is_closed() {
+            match event::poll(INPUT_POLL) {
+                Ok(false) => continue,
+                Ok(true) => {}
+                Err(_) => break,
+            }
+            match event::read() {
+                Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                    if tx.send(key).is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+    });
+    rx
+}
+
+/// `bytes` per second, e.g. "1.5 MiB/s"
+fn format_rate(bytes: u64) -> String {
+    format!("{}/s", format_bytes(bytes))
+}
+
+/// Draws the clients, the proxies of the selected one and the console output
+fn render(
+    frame: &mut Frame,
+    rows: &[ClientRow],
+    selected: &mut TableState,
+    scrollback: &Scrollback,
+) {
+    let [header, clients_area, proxies_area, log_area] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Percentage(35),
+        Constraint::Percentage(30),
+        Constraint::Fill(1),
+    ])
+    .areas(frame.area());
+
+    let active: u64 = rows
+        .iter()
+        .flat_map(|client| client.proxies.iter())
+        .map(|proxy| proxy.active)
+        .sum();
+    frame.render_widget(
+        Line::from(vec![
+            Span::from(" sowback ").bold().reversed(),
+            Span::from(format!(
+                "  {} clients, {} connections  ",
+                rows.len(),
+                active
+            )),
+            Span::from("↑↓ select  k kick  q quit").dim(),
+        ]),
+        header,
+    );
+
+    let client_rows = rows.iter().map(|client| {
+        let (rate_in, rate_out) = client
+            .proxies
+            .iter()
+            .fold((0, 0), |(rate_in, rate_out), proxy| {
+                (rate_in + proxy.rate_in, rate_out + proxy.rate_out)
+            });
+        Row::new(vec![
+            short_id(&client.client_id).to_string(),
+            client.name.clone(),
+            client.addr.clone(),
+            client.proxies.len().to_string(),
+            client
+                .proxies
+                .iter()
+                .map(|proxy| proxy.active)
+                .sum::<u64>()
+                .to_string(),
+            format_rate(rate_in),
+            format_rate(rate_out),
+        ])
+    });
+    let clients = Table::new(
+        client_rows,
+        [
+            Constraint::Length(8),
+            Constraint::Fill(1),
+            Constraint::Length(22),
+            Constraint::Length(7),
+            Constraint::Length(7),
+            Constraint::Length(12),
+            Constraint::Length(12),
+        ],
+    )
+    .header(
+        Row::new(["ID", "Name", "Address", "Proxies", "Active", "In", "Out"])
+            .style(Style::new().bold()),
+    )
+    .block(Block::bordered().title(" Clients "))
+    .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+    frame.render_stateful_widget(clients, clients_area, selected);
+
+    let proxies = selected
+        .selected()
+        .and_then(|index| rows.get(index))
+        .map_or(&[][..], |client| client.proxies.as_slice());
+    let proxy_rows = proxies.iter().map(|proxy| {
+        Row::new(vec![
+            proxy.name.clone(),
+            proxy.remote_port.to_string(),
+            proxy.active.to_string(),
+            format_rate(proxy.rate_in),
+            format_rate(proxy.rate_out),
+            format_bytes(proxy.bytes_in),
+            format_bytes(proxy.bytes_out),
+        ])
+    });
+    let proxies = Table::new(
+        proxy_rows,
+        [
+            Constraint::Fill(1),
+            Constraint::Length(6),
+            Constraint::Length(7),
+            Constraint::Length(12),
+            Constraint::Length(12),
+            Constraint::Length(11),
+            Constraint::Length(11),
+        ],
+    )
+    .header(
+        Row::new([
+            "Proxy",
+            "Port",
+            "Active",
+            "In",
+            "Out",
+            "Total in",
+            "Total out",
+        ])
+        .style(Style::new().bold()),
+    )
+    .block(Block::bordered().title(" Proxies "));
+    frame.render_widget(proxies, proxies_area);
+
+    let visible = log_area.height.saturating_sub(2) as usize;
+    let lines: Vec<Line> = scrollback
+        .last(visible)
+        .into_iter()
+        .map(|(level, line)| Line::styled(line, level_style(level)))
+        .collect();
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(" Log ")),
+        log_area,
+    );
+}
+
+/// How console lines of `level` are drawn
+fn level_style(level: ConsoleLevel) -> Style {
+    match level {
+        ConsoleLevel::Error => Style::new().fg(Color::Red),
+        ConsoleLevel::Warn => Style::new().fg(Color::Yellow),
+        ConsoleLevel::Info => Style::new(),
+        ConsoleLevel::Debug | ConsoleLevel::Trace => Style::new().add_modifier(Modifier::DIM),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throughput_averages_over_the_window() {
+        let mut throughput = Throughput::default();
+        // a first sample has nothing to compare with
+        assert_eq!(throughput.sample("proxy", 1000, 0), (0, 0));
+        assert_eq!(throughput.sample("proxy", 3000, 500), (2000, 500));
+        assert_eq!(throughput.sample("proxy", 4000, 500), (1500, 250));
+
+        // samples older than the window no longer count
+        for second in 0..THROUGHPUT_WINDOW as u64 {
+            throughput.sample("proxy", 10_000 + second * 100, 500);
+        }
+        assert_eq!(throughput.sample("proxy", 10_500, 500), (100, 0));
+    }
+}
@@ -0,0 +1,81 @@
+use anyhow::Result;
+use std::net::SocketAddr;
+
+use super::Server;
+use crate::utils::BoxedStream;
+use crate::{log_debug, warn};
+
+impl Server {
+    /// Serves a data channel opened with a `DataChannelHello` answering the challenge `nonce`:
+    /// once the proof checks out against the session named, the stream carries the proxy
+    /// connection waiting for it
+    pub(super) async fn accept_data_channel(
+        &self,
+        stream: BoxedStream,
+        addr: SocketAddr,
+        nonce: &[u8],
+        client_id: &str,
+        connection_id: &str,
+        proof: &[u8],
+    ) -> Result<()> {
+        self.auth_nonces
+            .consume(nonce)
+            .map_err(|reason| anyhow::anyhow!("Data channel from {} refused: {}", addr, reason))?;
+        let checked = match self.clients.read().await.get(client_id) {
+            None => Err("no such session"),
+            Some(client) if !client.data_channels => Err("the session has no data channels"),
+            Some(client)
+                if !client
+                    .crypto
+                    .verify_data_channel_proof(nonce, connection_id, proof) =>
+            {
+                self.record_auth_failure(addr.ip());
+                Err("invalid proof")
+            }
+            Some(_) => Ok(()),
+        };
+        if let Err(reason) = checked {
+            return Err(anyhow::anyhow!(
+                "Data channel from {} for connection {} refused: {}",
+                addr,
+                connection_id,
+                reason
+            ));
+        }
+
+        log_debug!(
+            "Data channel from {} carries connection {} of client {}",
+            addr,
+            connection_id,
+            client_id
+        );
+        self.deliver_data_stream(client_id, connection_id, stream)
+            .await;
+        Ok(())
+    }
+
+    /// Answers the proxy connection waiting for the client with the stream carrying it
+    pub(super) async fn deliver_data_stream(
+        &self,
+        client_id: &str,
+        connection_id: &str,
+        stream: BoxedStream,
+    ) {
+        let mut proxy_connections_guard = self.proxy_connections.write().await;
+        let waiting = proxy_connections_guard
+            .get_mut(connection_id)
+            .filter(|proxy_conn| proxy_conn.client_id == client_id)
+            .and_then(|proxy_conn| proxy_conn.response.take());
+        match waiting {
+            Some(response) => {
+                let _ = response.send(Ok(Some(stream)));
+            }
+            None => {
+                warn!(
+                    "Client {} opened a stream for connection {}, which is not waiting for one",
+                    client_id, connection_id
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::time::{interval, Duration};
+
+use super::{ProxyInfo, Server};
+use crate::logging::format_uuid;
+use crate::utils::{CloseCode, Message};
+use crate::{console_info, log_info};
+
+/// How often proxies are checked for a `ttl` that ran out
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+impl Server {
+    /// Removes the proxies whose `ttl` ran out, every `EXPIRY_CHECK_INTERVAL`
+    pub(super) async fn expire_proxies_periodically(&self) {
+        let mut interval = interval(EXPIRY_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.expire_proxies().await;
+            self.sweeps.beat();
+        }
+    }
+
+    /// Whether the expiry sweep ran lately enough for the watchdog pinged every `period`.
+    /// It takes the clients lock each time, so a stalled runtime or a lock never released
+    /// stops it
+    pub(super) fn sweeping(&self, period: Duration) -> bool {
+        self.sweeps.within(period.max(EXPIRY_CHECK_INTERVAL * 2))
+    }
+
+    /// Removes every proxy whose `ttl` ran out: its listener or route stops, its connections
+    /// are closed and its client is sent a `ProxyExpired`
+    pub(super) async fn expire_proxies(&self) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        for (client_id, client) in self.clients.write().await.iter_mut() {
+            client.proxies.retain(|proxy_id, proxy| {
+                if proxy.expires_at.is_none_or(|expires_at| now < expires_at) {
+                    return true;
+                }
+                expired.push((client_id.clone(), proxy_id.clone(), proxy.clone()));
+                false
+            });
+        }
+
+        for (client_id, proxy_id, proxy) in expired {
+            let closed = self.release_proxy(&proxy_id, &proxy).await;
+            if let Some(client) = self.clients.read().await.get(&client_id) {
+                for connection_id in &closed {
+                    let _ = client.sender.send(Message::new_close_connection(
+                        connection_id,
+                        CloseCode::PolicyLimit,
+                    ));
+                }
+                let _ = client.sender.send(Message::ProxyExpired {
+                    proxy_id: proxy_id.clone(),
+                });
+            }
+            log_info!(
+                "Proxy '{}' of client {} expired, closed {} connections",
+                proxy.name,
+                format_uuid(&client_id, "client"),
+                closed.len()
+            );
+            console_info!(
+                "Proxy '{}' expired: {}",
+                proxy.name,
+                format_uuid(&proxy_id, "proxy")
+            );
+        }
+    }
+
+    /// Stops whatever serves a proxy already removed from its client: its listener,
+    /// unless other group members remain, its host route or secret service, and its
+    /// connections. Returns the IDs of the connections closed
+    async fn release_proxy(&self, proxy_id: &str, proxy: &ProxyInfo) -> Vec<String> {
+        {
+            let mut listeners = self.proxy_listeners.write().await;
+            let port = listeners
+                .iter()
+                .find(|(_, listener)| {
+                    listener
+                        .members
+                        .iter()
+                        .any(|member| member.proxy_id == proxy_id)
+                })
+                .map(|(port, _)| *port);
+            if let Some(port) = port {
+                self.leave_listener(port, proxy_id, &mut listeners);
+            }
+        }
+        self.shared_routes
+            .write()
+            .await
+            .retain(|_, route| route.proxy_id != proxy_id);
+        self.release_secret_service(proxy_id).await;
+
+        // connections share the counters of their proxy
+        let mut closed = Vec::new();
+        self.proxy_connections
+            .write()
+            .await
+            .retain(|connection_id, connection| {
+                if !Arc::ptr_eq(&connection.stats, &proxy.stats) {
+                    return true;
+                }
+                let _ = connection.close_code.set(CloseCode::PolicyLimit);
+                closed.push(connection_id.clone());
+                false
+            });
+        closed
+    }
+}
@@ -0,0 +1,298 @@
+/// Request heads (and chunk size lines) larger than this are refused
+pub const MAX_HEAD_LEN: usize = 16 * 1024;
+
+/// A piece of the client's byte stream, split at request boundaries
+#[derive(Debug, PartialEq, Eq)]
+pub enum HttpEvent {
+    /// A complete request head, with the host it was addressed to
+    Head {
+        host: Option<String>,
+        bytes: Vec<u8>,
+    },
+    /// Body bytes of the current request, or raw bytes after an upgrade
+    Body(Vec<u8>),
+}
+
+#[derive(Debug, Default)]
+enum State {
+    #[default]
+    Head,
+    /// Bytes left in a `Content-Length` body
+    Body(u64),
+    ChunkSize,
+    /// Bytes left in the current chunk, including its trailing CRLF
+    ChunkData(u64),
+    Trailer,
+    /// The connection was upgraded, nothing is parsed anymore
+    Raw,
+}
+
+/// Splits the client's side of an HTTP/1.x connection into requests.
+/// Only request heads are parsed, bodies are skipped using `Content-Length` or chunked
+/// framing, so each request on a keep-alive connection can be routed by its own Host header.
+/// Upgraded connections (e.g. WebSocket) are passed through untouched.
+#[derive(Debug, Default)]
+pub struct RequestTracker {
+    state: State,
+    pending: Vec<u8>,
+}
+
+impl RequestTracker {
+    /// Feeds bytes read from the client, returning the events they complete.
+    /// Fails on malformed or oversized input, the error is meant for a 400 response.
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<HttpEvent>, String> {
+        self.pending.extend_from_slice(data);
+        let mut events = Vec::new();
+
+        loop {
+            match self.state {
+                State::Head => {
+                    let Some(end) = find(&self.pending, b"\r\n\r\n") else {
+                        if self.pending.len() > MAX_HEAD_LEN {
+                            return Err("Request head too large".to_string());
+                        }
+                        break;
+                    };
+                    let bytes: Vec<u8> = self.pending.drain(..end + 4).collect();
+                    let head = parse_head(&bytes)?;
+                    self.state = if head.upgrade {
+                        State::Raw
+                    } else if head.chunked {
+                        State::ChunkSize
+                    } else if head.content_length > 0 {
+                        State::Body(head.content_length)
+                    } else {
+                        State::Head
+                    };
+                    events.push(HttpEvent::Head {
+                        host: head.host,
+                        bytes,
+                    });
+                }
+                State::Body(remaining) | State::ChunkData(remaining) => {
+                    if self.pending.is_empty() {
+                        break;
+                    }
+                    let take = remaining.min(self.pending.len() as u64);
+                    push_body(&mut events, self.pending.drain(..take as usize).collect());
+                    let remaining = remaining - take;
+                    self.state = match (&self.state, remaining) {
+                        (State::Body(_), 0) => State::Head,
+                        (State::Body(_), _) => State::Body(remaining),
+                        (_, 0) => State::ChunkSize,
+                        _ => State::ChunkData(remaining),
+                    };
+                }
+                State::ChunkSize | State::Trailer => {
+                    let Some(end) = find(&self.pending, b"\r\n") else {
+                        if self.pending.len() > MAX_HEAD_LEN {
+                            return Err("Chunk header too large".to_string());
+                        }
+                        break;
+                    };
+                    let line: Vec<u8> = self.pending.drain(..end + 2).collect();
+                    self.state = match self.state {
+                        State::ChunkSize => match parse_chunk_size(&line[..end])? {
+                            0 => State::Trailer,
+                            size => State::ChunkData(size + 2),
+                        },
+                        // an empty line ends the trailer section and the request
+                        _ if end == 0 => State::Head,
+                        _ => State::Trailer,
+                    };
+                    push_body(&mut events, line);
+                }
+                State::Raw => {
+                    if !self.pending.is_empty() {
+                        push_body(&mut events, std::mem::take(&mut self.pending));
+                    }
+                    break;
+                }
+            }
+        }
+
+        Ok(events)
+    }
+}
+
+/// Builds a complete response with a plain text body, closing the connection
+pub fn error_response(status: u16, reason: &str, body: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+/// Header fields that decide routing and framing
+struct RequestHead {
+    host: Option<String>,
+    content_length: u64,
+    chunked: bool,
+    upgrade: bool,
+}
+
+fn parse_head(bytes: &[u8]) -> Result<RequestHead, String> {
+    let text = std::str::from_utf8(bytes).map_err(|_| "Request head is not valid UTF-8")?;
+    let mut lines = text.split("\r\n");
+
+    let request_line = lines.next().unwrap_or_default();
+    let method = request_line.split(' ').next().unwrap_or_default();
+    if method.is_empty() || request_line.split(' ').count() != 3 {
+        return Err(format!("Malformed request line '{}'", request_line));
+    }
+
+    let mut head = RequestHead {
+        host: None,
+        content_length: 0,
+        chunked: false,
+        upgrade: method.eq_ignore_ascii_case("CONNECT"),
+    };
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| format!("Malformed header line '{}'", line))?;
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "host" => head.host = Some(normalize_host(value)),
+            "content-length" => {
+                head.content_length = value
+                    .parse()
+                    .map_err(|_| format!("Invalid Content-Length '{}'", value))?;
+            }
+            "transfer-encoding" => {
+                head.chunked = value
+                    .rsplit(',')
+                    .next()
+                    .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+            }
+            "upgrade" => head.upgrade = true,
+            _ => {}
+        }
+    }
+
+    Ok(head)
+}
+
+/// Lowercases a Host header value and strips its port
+fn normalize_host(value: &str) -> String {
+    let host = match value.strip_prefix('[') {
+        // IPv6 literal, keep the brackets
+        Some(rest) => match rest.split_once(']') {
+            Some((address, _)) => format!("[{}]", address),
+            None => value.to_string(),
+        },
+        None => match value.rsplit_once(':') {
+            Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host.to_string(),
+            _ => value.to_string(),
+        },
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn parse_chunk_size(line: &[u8]) -> Result<u64, String> {
+    let line = String::from_utf8_lossy(line);
+    let size = line.split(';').next().unwrap_or_default().trim();
+    u64::from_str_radix(size, 16).map_err(|_| format!("Invalid chunk size '{}'", size))
+}
+
+/// Appends body bytes, merging with a preceding body event
+fn push_body(events: &mut Vec<HttpEvent>, bytes: Vec<u8>) {
+    match events.last_mut() {
+        Some(HttpEvent::Body(body)) => body.extend_from_slice(&bytes),
+        _ => events.push(HttpEvent::Body(bytes)),
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(events: &[HttpEvent]) -> Vec<Option<&str>> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                HttpEvent::Head { host, .. } => Some(host.as_deref()),
+                HttpEvent::Body(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_keep_alive_requests_are_split() {
+        let mut tracker = RequestTracker::default();
+        let input =
+            b"POST /a HTTP/1.1\r\nHost: App.Example.com:8080\r\nContent-Length: 5\r\n\r\nhello\
+GET /b HTTP/1.1\r\nHost: api.example.com\r\n\r\n";
+
+        // byte by byte, so every boundary falls across reads
+        let mut events = Vec::new();
+        for byte in input.iter() {
+            events.extend(tracker.feed(&[*byte]).unwrap());
+        }
+
+        assert_eq!(
+            hosts(&events),
+            vec![Some("app.example.com"), Some("api.example.com")]
+        );
+        let body: Vec<u8> = events
+            .iter()
+            .filter_map(|event| match event {
+                HttpEvent::Body(bytes) => Some(bytes.as_slice()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .concat();
+        assert_eq!(body, b"hello");
+    }
+
+    #[test]
+    fn test_chunked_body_is_skipped() {
+        let mut tracker = RequestTracker::default();
+        let events = tracker
+            .feed(
+                b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+4\r\nGET \r\n6;ext=1\r\n/ HTTP\r\n0\r\nX-Trailer: 1\r\n\r\n\
+GET / HTTP/1.1\r\nHost: b\r\n\r\n",
+            )
+            .unwrap();
+
+        // the chunk contents look like a request line but are not one
+        assert_eq!(hosts(&events), vec![Some("a"), Some("b")]);
+        assert_eq!(events.len(), 3);
+    }
+
+    #[test]
+    fn test_upgrade_passes_through() {
+        let mut tracker = RequestTracker::default();
+        let events = tracker
+            .feed(b"GET /ws HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\nGET / HTTP/1.1\r\n")
+            .unwrap();
+        assert_eq!(hosts(&events), vec![Some("a")]);
+        assert_eq!(events[1], HttpEvent::Body(b"GET / HTTP/1.1\r\n".to_vec()));
+    }
+
+    #[test]
+    fn test_host_normalization_and_errors() {
+        assert_eq!(normalize_host("[::1]:8080"), "[::1]");
+        assert_eq!(normalize_host("Example.COM."), "example.com");
+
+        let mut tracker = RequestTracker::default();
+        let events = tracker.feed(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(hosts(&events), vec![None]);
+
+        assert!(RequestTracker::default().feed(b"garbage\r\n\r\n").is_err());
+        assert!(RequestTracker::default()
+            .feed(&vec![b'a'; MAX_HEAD_LEN + 1])
+            .is_err());
+    }
+}
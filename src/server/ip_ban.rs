@@ -0,0 +1,168 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A single IP address or a CIDR block, e.g. `203.0.113.7` or `10.0.0.0/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    /// Whether `ip` is in the range, IPv4-mapped IPv6 addresses matching as IPv4
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                masked(network.to_bits().into(), 32, self.prefix)
+                    == masked(ip.to_bits().into(), 32, self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                masked(network.to_bits(), 128, self.prefix)
+                    == masked(ip.to_bits(), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The first `prefix` bits of a `bits` wide address
+fn masked(address: u128, bits: u8, prefix: u8) -> u128 {
+    match bits - prefix {
+        0 => address,
+        host if host >= 128 => 0,
+        host => address >> host,
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (address, prefix) = match s.trim().split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let network = address
+            .parse::<IpAddr>()
+            .map_err(|_| anyhow!("'{}' is not an IP address or CIDR block", s))?
+            .to_canonical();
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or_else(|| anyhow!("Invalid prefix length in '{}', expected 0-{}", s, bits))?,
+            None => bits,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.network, self.prefix) {
+            (IpAddr::V4(_), 32) | (IpAddr::V6(_), 128) => write!(f, "{}", self.network),
+            _ => write!(f, "{}/{}", self.network, self.prefix),
+        }
+    }
+}
+
+/// A range banned by an operator
+#[derive(Debug)]
+struct Ban {
+    range: IpRange,
+    until: Instant,
+}
+
+/// Peers banned through the admin API, refused on the control port and on proxy ports.
+/// Bans are dropped once they are over, when the table is next looked at
+#[derive(Clone, Default)]
+pub struct IpBans {
+    bans: Arc<Mutex<Vec<Ban>>>,
+}
+
+impl IpBans {
+    /// Bans `range` for `duration`, replacing an earlier ban of the same range
+    pub fn ban(&self, range: IpRange, duration: Duration) {
+        self.ban_at(range, duration, Instant::now());
+    }
+
+    fn ban_at(&self, range: IpRange, duration: Duration, now: Instant) {
+        let mut bans = self.bans.lock().unwrap();
+        bans.retain(|ban| ban.range != range && now < ban.until);
+        bans.push(Ban {
+            range,
+            until: now + duration,
+        });
+    }
+
+    /// Whether connections from `ip` are currently refused
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.is_banned_at(ip, Instant::now())
+    }
+
+    fn is_banned_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+        bans.retain(|ban| now < ban.until);
+        bans.iter().any(|ban| ban.range.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ranges() {
+        let range: IpRange = "10.1.0.0/16".parse().unwrap();
+        assert!(range.contains("10.1.255.7".parse().unwrap()));
+        assert!(!range.contains("10.2.0.1".parse().unwrap()));
+        assert!(range.contains("::ffff:10.1.0.9".parse().unwrap()));
+        assert!(!range.contains("2001:db8::1".parse().unwrap()));
+        assert_eq!(range.to_string(), "10.1.0.0/16");
+
+        let single: IpRange = "203.0.113.7".parse().unwrap();
+        assert!(single.contains("203.0.113.7".parse().unwrap()));
+        assert!(!single.contains("203.0.113.8".parse().unwrap()));
+        assert_eq!(single.to_string(), "203.0.113.7");
+
+        let everything: IpRange = "::/0".parse().unwrap();
+        assert!(everything.contains("2001:db8::1".parse().unwrap()));
+        let v6: IpRange = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains("2001:db8:ffff::1".parse().unwrap()));
+        assert!(!v6.contains("2001:db9::1".parse().unwrap()));
+
+        for invalid in ["", "example.com", "10.0.0.0/33", "10.0.0.0/x", "::/129"] {
+            assert!(invalid.parse::<IpRange>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_bans_expire() {
+        let bans = IpBans::default();
+        let start = Instant::now();
+        let peer: IpAddr = "198.51.100.20".parse().unwrap();
+        bans.ban_at(
+            "198.51.100.0/24".parse().unwrap(),
+            Duration::from_secs(60),
+            start,
+        );
+        assert!(bans.is_banned_at(peer, start + Duration::from_secs(59)));
+        assert!(!bans.is_banned_at("198.51.101.1".parse().unwrap(), start));
+        assert!(!bans.is_banned_at(peer, start + Duration::from_secs(60)));
+        assert!(bans.bans.lock().unwrap().is_empty());
+
+        // banning a range again replaces its ban
+        let range = "198.51.100.20".parse().unwrap();
+        bans.ban_at(range, Duration::from_secs(600), start);
+        bans.ban_at(range, Duration::from_secs(10), start);
+        assert_eq!(bans.bans.lock().unwrap().len(), 1);
+        assert!(!bans.is_banned_at(peer, start + Duration::from_secs(10)));
+    }
+}
@@ -1,19 +1,78 @@
 use anyhow::Result;
+use bytes::BytesMut;
 use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedReadHalf;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, RwLock, RwLockWriteGuard};
+use tokio::sync::{mpsc, oneshot, RwLock, RwLockWriteGuard};
+use tokio::task::JoinSet;
 use tokio::time::{timeout, Duration};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 use uuid::Uuid;
 
-use crate::config::ServerConfig;
-use crate::logging::format_uuid;
-use crate::utils::crypto::{sha256_with_salt, MAGIC_SALT};
-use crate::utils::protocol::ProxyConfigOpCode;
-use crate::utils::{CryptoContext, Frame, FrameReader, Message};
-use crate::{console_info, debug, error, log_debug, log_error, log_info, log_warn, warn};
+use crate::config::{AuthMode, DuplicateClientPolicy, PortSet, ServerConfig, TokenConfig, Version};
+use crate::logging::{format_bytes, format_client_info, format_uuid, sanitize_name, short_id};
+use crate::utils::compression::Compression;
+use crate::utils::crypto::{generate_nonce, verify_auth_proof};
+use crate::utils::protocol::{
+    ClientMeta, ClientStats, CloseCode, Framing, ProxyConfigOpCode, PROTOCOL_VERSION,
+};
+use crate::utils::proxy::{splice, HalfEnd, SpliceEnd, WriteBacklog, WriteCommand};
+use crate::utils::replay::REPLAY_CAPACITY;
+use crate::utils::sd_notify::{self, Progress};
+use crate::utils::{
+    net, tls, websocket, write_all_within, Acknowledger, Activity, BoxedStream, CryptoContext,
+    Frame, FrameReader, Message, ReplayBuffer, Rewound, Route, SendWindow, TransferStats,
+};
+use crate::{debug, error, info, log_debug, log_info, log_warn, warn};
+
+mod access_log;
+mod admin;
+mod auth_ban;
+mod balance;
+mod connection_limit;
+#[cfg(feature = "dashboard")]
+mod dashboard;
+mod data_channel;
+mod expiry;
+mod http;
+mod ip_ban;
+mod orphans;
+#[cfg(feature = "quic")]
+mod quic;
+mod rate_limit;
+mod relay;
+mod resume;
+mod session;
+mod sni;
+mod stats;
+mod upgrade;
+mod visitor;
+mod webhook;
+
+use access_log::{AccessEntry, AccessLog, CloseReason};
+pub use admin::AdminClient;
+use auth_ban::AuthBans;
+use balance::Balance;
+use connection_limit::{ConnectionLimit, ConnectionPermit};
+use http::{HttpEvent, RequestTracker};
+use ip_ban::IpBans;
+use orphans::OrphanQueue;
+use rate_limit::ConnectionRateLimiter;
+use relay::RelayService;
+use session::SessionState;
+use sni::ClientHello;
+use stats::{ConnectionSlot, ProxyStats};
+use upgrade::{Inherited, ListenerRole, UpgradeSignal};
+use visitor::{SecretService, VisitorLink};
+use webhook::Webhooks;
 
 /// Main server structure that handles client connections and proxy management
 pub struct Server {
@@ -21,6 +80,101 @@ pub struct Server {
     clients: Arc<RwLock<HashMap<String, ClientConnection>>>,
     proxy_listeners: Arc<RwLock<HashMap<u16, ProxyListenerInfo>>>,
     proxy_connections: Arc<RwLock<HashMap<String, ProxyConnectionInfo>>>,
+    tls_acceptor: Option<TlsAcceptor>,
+    auth_nonces: AuthNonces,
+    /// Source IPs banned for repeated authentication failures
+    auth_bans: AuthBans,
+    /// Source IPs and ranges banned through the admin API
+    ip_bans: IpBans,
+    /// Services on the shared `http_port` and `sni_port`, by host name
+    shared_routes: Arc<RwLock<HashMap<(SharedPort, String), HostRoute>>>,
+    /// Services registered with a secret, by name
+    secret_services: Arc<RwLock<HashMap<String, SecretService>>>,
+    /// Services reached by the clients in their `relay_peers`, by proxy ID
+    relay_services: Arc<RwLock<HashMap<String, RelayService>>>,
+    /// Connections from visitors to secret and relay services, by connection ID
+    visitor_links: Arc<RwLock<HashMap<String, VisitorLink>>>,
+    /// Where finished proxy connections are recorded, if configured
+    access_log: Option<AccessLog>,
+    /// Limits new proxy connections per peer IP, if configured
+    connection_limiter: Option<Arc<ConnectionRateLimiter>>,
+    /// Where events are reported, if configured
+    webhooks: Option<Webhooks>,
+    /// Bounds the connections open at once to `max_connections`
+    connection_limit: ConnectionLimit,
+    /// When the proxy expiry sweep last ran, what the service manager's watchdog is told about
+    sweeps: Arc<Progress>,
+    /// Where listeners are handed to a new process on SIGUSR2, if set
+    upgrade_socket: Option<PathBuf>,
+    /// Where a process taking over on an upgrade writes its pid
+    pid_file: Option<PathBuf>,
+    /// Cancelled once a new process took the listeners over, stopping every accept loop
+    handed_off: CancellationToken,
+}
+
+/// How often dropped connections are reported and idle peers forgotten by the rate limiter
+const RATE_LIMIT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a connection to a shared port may take to send what it is routed by
+const SHARED_PORT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bytes a proxy connection may receive before the client has reached its local service
+const MAX_EARLY_DATA: usize = 256 * 1024;
+
+/// A port shared by several services, routed by a host name found in the traffic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum SharedPort {
+    /// `http_port`, routed by the Host header
+    Http,
+    /// `sni_port`, routed by the server name in the TLS ClientHello
+    Sni,
+}
+
+impl SharedPort {
+    fn protocol(self) -> &'static str {
+        match self {
+            SharedPort::Http => "HTTP",
+            SharedPort::Sni => "SNI",
+        }
+    }
+
+    /// What the host name is called in messages
+    fn host_label(self) -> &'static str {
+        match self {
+            SharedPort::Http => "Host",
+            SharedPort::Sni => "SNI name",
+        }
+    }
+}
+
+/// How long a client has to answer an authentication challenge
+const AUTH_NONCE_TTL: Duration = Duration::from_secs(30);
+
+/// Authentication challenge nonces that have been issued but not yet answered.
+/// Each nonce is accepted once, and only within `AUTH_NONCE_TTL` of being issued.
+#[derive(Clone, Default)]
+struct AuthNonces {
+    issued: Arc<Mutex<HashMap<Vec<u8>, Instant>>>,
+}
+
+impl AuthNonces {
+    /// Issues a fresh nonce, dropping any that have expired
+    fn issue(&self) -> Vec<u8> {
+        let nonce = generate_nonce();
+        let mut issued = self.issued.lock().unwrap();
+        issued.retain(|_, at| at.elapsed() < AUTH_NONCE_TTL);
+        issued.insert(nonce.clone(), Instant::now());
+        nonce
+    }
+
+    /// Consumes a nonce, failing if it was never issued, already used, or expired
+    fn consume(&self, nonce: &[u8]) -> std::result::Result<(), String> {
+        match self.issued.lock().unwrap().remove(nonce) {
+            Some(at) if at.elapsed() < AUTH_NONCE_TTL => Ok(()),
+            Some(_) => Err("Authentication nonce expired".to_string()),
+            None => Err("Unknown or reused authentication nonce".to_string()),
+        }
+    }
 }
 
 /// Represents a connected client with its communication channel and proxy configurations
@@ -30,123 +184,826 @@ struct ClientConnection {
     sender: mpsc::UnboundedSender<Message>,
     crypto: Arc<CryptoContext>,
     proxies: HashMap<String, ProxyInfo>,
+    /// Negotiated compression codec for `Data` payloads
+    compression: Compression,
+    /// Token the client authenticated with
+    grant: TokenGrant,
+    /// Identifies this connection of the client, which may be replaced by a newer one
+    session_id: String,
+    /// Name from the client's certificate or `Auth` message, sanitized for logging
+    name: Option<String>,
+    /// Address the client connected from
+    addr: SocketAddr,
+    /// Whether the session may carry proxied connections on data channels
+    data_channels: bool,
+    /// What the client reported running on, sanitized
+    meta: Option<ClientMeta>,
+    /// Which messages the session takes, see `SessionState`
+    state: SessionState,
+    /// Protocol violations committed by the client in this session
+    violations: u32,
+}
+
+impl ClientConnection {
+    /// How the client appears in logs, its short ID followed by its name and address
+    fn label(&self) -> String {
+        format!(
+            "{} {}",
+            format_uuid(&self.client_id, "client"),
+            format_client_info(self.name.as_deref(), &self.addr.to_string())
+        )
+    }
+
+    /// Connections of the client's proxies, summed over their counters
+    fn stats(&self) -> ClientStats {
+        self.proxies
+            .values()
+            .fold(ClientStats::default(), |mut total, proxy| {
+                let stats = proxy.stats.snapshot();
+                total.active_connections += stats.active;
+                total.total_connections += stats.connections;
+                total.bytes_forwarded += stats.bytes_in + stats.bytes_out;
+                total
+            })
+    }
+}
+
+/// Restrictions attached to the token a client authenticated with
+#[derive(Clone, Default)]
+struct TokenGrant {
+    /// Token name, if configured
+    name: Option<String>,
+    /// Remote ports the client may claim, unrestricted when None
+    allowed_ports: Option<PortSet>,
+}
+
+impl From<TokenConfig> for TokenGrant {
+    fn from(token: TokenConfig) -> Self {
+        Self {
+            name: token.name,
+            allowed_ports: token.allowed_ports,
+        }
+    }
 }
 
 /// Configuration information for a proxy service
 #[derive(Clone)]
 struct ProxyInfo {
+    /// Service name, used in logs
+    name: String,
     local_ip: String,
     local_port: u16,
     remote_port: u16,
+    /// Connections let in at once, more are closed right after accepting
+    max_connections: Option<u32>,
+    /// Traffic counters, shared with the proxy's connections
+    stats: Arc<ProxyStats>,
+    /// When the proxy's `ttl` runs out and it is removed, never when None
+    expires_at: Option<Instant>,
+    /// Why the client last reported the local service unreachable, None while it answers
+    local_error: Option<String>,
 }
 
 /// Information about an active proxy connection for data forwarding
 struct ProxyConnectionInfo {
-    sender: mpsc::UnboundedSender<Vec<u8>>,
+    sender: mpsc::UnboundedSender<WriteCommand>,
+    client_id: String,
+    /// Counters of the proxy this connection belongs to
+    stats: Arc<ProxyStats>,
+    /// Told the client's `ConnectionResponse` while the connection waits for it, or the
+    /// stream a QUIC client opened for it. HTTP tunnels do not wait, a failure is answered
+    /// on their shared writer
+    response: Option<oneshot::Sender<ConnectionOutcome>>,
+    /// Credit for sending the connection's data to the client, held back while the client
+    /// paused the connection
+    window: Arc<SendWindow>,
+    /// HTTP tunnels share one writer, so data from the client is acknowledged
+    /// once queued rather than once written
+    acknowledge_on_receipt: bool,
+    /// What the client's end moved, when the client closed the connection
+    reported: Arc<OnceLock<TransferStats>>,
+    /// Code of the `CloseConnection` that ended the connection, whichever end sent it
+    close_code: Arc<OnceLock<CloseCode>>,
+    /// `seq` the client's next data message for the connection must carry
+    next_seq: AtomicU64,
+    /// Data from the client queued for the peer and not written yet
+    backlog: Arc<WriteBacklog>,
+    /// Data sent to the client and not acknowledged yet, for the client's next session to
+    /// resume the connection with. None for connections that cannot be resumed
+    replay: Option<Arc<ReplayBuffer>>,
+}
+
+/// Whether the client reached the local service of a proxy connection. Clients on QUIC
+/// answer with the stream carrying the connection instead of `Data` messages
+type ConnectionOutcome = std::result::Result<Option<BoxedStream>, String>;
+
+/// The client proxy serving a host name on a shared port
+#[derive(Clone)]
+struct HostRoute {
+    client_id: String,
+    proxy_id: String,
+}
+
+/// A routed request stream on the HTTP port, forwarded as one proxy connection
+struct HttpTunnel {
+    host: String,
     client_id: String,
+    proxy_id: String,
+    connection_id: String,
+    /// External peer of the HTTP connection, and the port it connected to
+    peer: String,
+    remote_port: u16,
+    stats: Arc<ProxyStats>,
+    /// What this tunnel moved, `stats` counts every connection of the proxy
+    traffic: ProxyStats,
+    window: Arc<SendWindow>,
+    /// `seq` of the next data message sent to the client
+    next_seq: u64,
+    /// Responses from the client, ending once the client forgot the tunnel
+    responses: mpsc::UnboundedReceiver<WriteCommand>,
+    /// What the client's end moved and why it closed, if the client closed the tunnel
+    reported: Arc<OnceLock<TransferStats>>,
+    close_code: Arc<OnceLock<CloseCode>>,
+    started: Instant,
+    /// Counts the tunnel in the proxy's active connections until it is dropped
+    _slot: ConnectionSlot,
+}
+
+/// The next response the client sends through `tunnel`, pending without a tunnel
+async fn next_response(tunnel: &mut Option<HttpTunnel>) -> Option<WriteCommand> {
+    match tunnel {
+        Some(tunnel) => tunnel.responses.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Rejects client IDs that are not UUIDs, clients generate theirs with `Uuid::new_v4`
+fn check_client_id(client_id: &str) -> std::result::Result<(), String> {
+    match Uuid::parse_str(client_id) {
+        Ok(_) => Ok(()),
+        Err(_) => Err("Malformed client ID, expected a UUID".to_string()),
+    }
 }
 
 /// Information about a proxy listener bound to a specific port
 struct ProxyListenerInfo {
     listener: Arc<TcpListener>,
+    /// Client that bound the listener, the only one that may join it unless `group` is set
     client_id: String,
-    proxy_id: String,
+    /// Group whose members all may register the port, sharing its connections
+    group: Option<String>,
+    /// Proxies sharing the listener, in the order they joined. Without a group these are
+    /// sessions of one client and new connections go to the current one, a group's members
+    /// online take turns as `balance` picks. The listener stops with the last proxy
+    members: Vec<ListenerMember>,
+    balance: Balance,
+    /// Set while no session serves the listener, it is kept for the client to reclaim
+    /// until `listener_grace_period` passed
+    orphaned: Option<Instant>,
+    /// Connections held while no session serves the listener
+    orphans: Arc<OrphanQueue>,
+    /// When the listener was bound, or taken over from an upgraded server
+    bound_at: Instant,
     cancel_tx: mpsc::UnboundedSender<()>,
 }
 
+/// A proxy serving a listener, and the client it belongs to
+struct ListenerMember {
+    proxy_id: String,
+    client_id: String,
+}
+
+/// The proxy picked for a connection accepted on its listener
+struct ServingProxy {
+    client_id: String,
+    proxy_id: String,
+    max_connections: Option<u32>,
+    stats: Arc<ProxyStats>,
+}
+
+/// Whether a client may put a proxy on a port, see `Server::claim_listener`
+enum PortClaim {
+    /// No listener is bound to the port
+    Free,
+    /// The client's listener can be reused, already serving this proxy ID if Some
+    Reuse(Option<String>),
+    /// The port is in use, for the reason given
+    Taken(String),
+}
+
 impl Server {
     /// Creates a new server instance with the given configuration
-    pub fn new(config: ServerConfig) -> Self {
-        Self {
+    pub fn new(config: ServerConfig) -> Result<Self> {
+        let tls_acceptor = match &config.tls {
+            Some(tls_config) => Some(tls::build_acceptor(tls_config)?),
+            None => None,
+        };
+        let access_log = match &config.access_log {
+            Some(path) => Some(AccessLog::open(path)?),
+            None => None,
+        };
+        let webhooks = match &config.webhooks {
+            Some(webhooks) => Some(Webhooks::new(webhooks, config.name.clone())?),
+            None => None,
+        };
+        let connection_limiter = match config.max_connections_per_ip_per_minute {
+            0 => None,
+            limit => Some(Arc::new(ConnectionRateLimiter::new(limit))),
+        };
+        let auth_bans = AuthBans::new(
+            config.auth_fail_limit,
+            config.auth_fail_window.0,
+            config.auth_ban_duration.0,
+        );
+
+        let connection_limit = ConnectionLimit::new(config.max_connections);
+
+        Ok(Self {
             config,
             clients: Arc::new(RwLock::new(HashMap::new())),
             proxy_listeners: Arc::new(RwLock::new(HashMap::new())),
             proxy_connections: Arc::new(RwLock::new(HashMap::new())),
-        }
+            tls_acceptor,
+            auth_nonces: AuthNonces::default(),
+            auth_bans,
+            ip_bans: IpBans::default(),
+            shared_routes: Arc::new(RwLock::new(HashMap::new())),
+            secret_services: Arc::new(RwLock::new(HashMap::new())),
+            relay_services: Arc::new(RwLock::new(HashMap::new())),
+            visitor_links: Arc::new(RwLock::new(HashMap::new())),
+            access_log,
+            connection_limiter,
+            webhooks,
+            connection_limit,
+            sweeps: Arc::default(),
+            upgrade_socket: None,
+            pid_file: None,
+            handed_off: CancellationToken::new(),
+        })
+    }
+
+    /// Hands the listeners over to a new process of the binary on SIGUSR2, through a Unix
+    /// socket at `path`, and serves the remaining connections for `drain_timeout`. Started
+    /// by such an upgrade, `run` takes the listeners over instead of binding them. Unix only
+    pub fn upgrade_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.upgrade_socket = Some(path.into());
+        self
+    }
+
+    /// Makes a process taking over on an upgrade write its pid to `path` once it accepts,
+    /// see `upgrade_socket`
+    pub fn pid_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.pid_file = Some(path.into());
+        self
+    }
+
+    /// Whether this process was started by a server upgrading, see `upgrade_socket`
+    pub fn is_upgrade() -> bool {
+        std::env::var_os(upgrade::UPGRADE_ENV).is_some()
+    }
+
+    /// Starts the server and accepts client connections until `shutdown` is cancelled,
+    /// then disconnects every client and closes their proxy listeners
+    pub async fn run(&self, shutdown: CancellationToken) -> Result<()> {
+        let mut inherited = self.inherit().await?;
+        let listener = match inherited.take(&ListenerRole::Control)? {
+            Some(listener) => listener,
+            None => net::bind_tcp(self.config.listen_addr.as_str(), self.config.reuseport).await?,
+        };
+        self.serve_inherited(listener, shutdown, inherited).await
+    }
+
+    /// Like `run`, accepting clients on a listener bound by the caller instead of
+    /// `listen_addr`, e.g. one on an ephemeral port
+    pub async fn serve(&self, listener: TcpListener, shutdown: CancellationToken) -> Result<()> {
+        self.serve_inherited(listener, shutdown, Inherited::default())
+            .await
     }
 
-    /// Starts the server and begins accepting client connections
-    pub async fn run(&self) -> Result<()> {
-        let listener = TcpListener::bind(&self.config.listen_addr).await?;
-        log_info!("Server ready, listening on {}", self.config.listen_addr);
+    /// Serves on `listener`, the other listeners taken from `inherited` where it has them
+    async fn serve_inherited(
+        &self,
+        listener: TcpListener,
+        shutdown: CancellationToken,
+        mut inherited: Inherited,
+    ) -> Result<()> {
+        log_info!("Server ready, listening on {}", listener.local_addr()?);
+        self.check_open_files_limit();
+        // clients without a CA for the certificate can pin it instead
+        if let Some(tls_config) = &self.config.tls {
+            log_info!(
+                "TLS certificate fingerprint: {}",
+                tls::certificate_fingerprint(&tls_config.cert)?
+            );
+        }
+
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.start();
+        }
+
+        // aborted when the server stops
+        let mut background = JoinSet::new();
+        // the shared ports and the admin API, stopped early when an upgrade took them over
+        let mut accepting = JoinSet::new();
+        let mut listening = Vec::new();
+
+        for kind in [SharedPort::Http, SharedPort::Sni] {
+            let Some(port) = self.shared_port(kind) else {
+                continue;
+            };
+            let addr = net::join_host_port(&self.config.bind_host, port);
+            let bind_host = net::unbracket(&self.config.bind_host);
+            let shared_listener = match inherited.take(&kind.into())? {
+                Some(listener) => listener,
+                None => net::bind_tcp((bind_host, port), self.config.reuseport)
+                    .await
+                    .map_err(|e| {
+                        anyhow::anyhow!("Failed to bind {} port {}: {}", kind.protocol(), addr, e)
+                    })?,
+            };
+            log_info!(
+                "{} services routed by host name on {}",
+                kind.protocol(),
+                addr
+            );
+            let shared_listener = Arc::new(shared_listener);
+            listening.push((kind.into(), shared_listener.clone()));
+            let server = self.clone();
+            accepting.spawn(async move { server.serve_shared_port(kind, shared_listener).await });
+        }
+
+        if let Some(admin) = &self.config.admin {
+            let admin_listener = match inherited.take(&ListenerRole::Admin)? {
+                Some(listener) => listener,
+                None => net::bind_tcp(admin.listen_addr.as_str(), self.config.reuseport)
+                    .await
+                    .map_err(|e| {
+                        anyhow::anyhow!("Failed to bind admin API {}: {}", admin.listen_addr, e)
+                    })?,
+            };
+            log_info!("Admin API listening on {}", admin_listener.local_addr()?);
+            let admin_listener = Arc::new(admin_listener);
+            listening.push((ListenerRole::Admin, admin_listener.clone()));
+            let server = self.clone();
+            accepting.spawn(async move { server.serve_admin(admin_listener).await });
+        }
+
+        if let Some(addr) = &self.config.websocket_listen_addr {
+            let websocket_listener = match inherited.take(&ListenerRole::WebSocket)? {
+                Some(listener) => listener,
+                None => net::bind_tcp(addr.as_str(), self.config.reuseport)
+                    .await
+                    .map_err(|e| {
+                        anyhow::anyhow!("Failed to bind WebSocket listener {}: {}", addr, e)
+                    })?,
+            };
+            log_info!(
+                "WebSocket clients accepted on {}",
+                websocket_listener.local_addr()?
+            );
+            let websocket_listener = Arc::new(websocket_listener);
+            listening.push((ListenerRole::WebSocket, websocket_listener.clone()));
+            let server = self.clone();
+            accepting.spawn(async move { server.serve_websocket(websocket_listener).await });
+        }
+
+        self.adopt_listeners(&mut inherited).await?;
+
+        #[cfg(feature = "quic")]
+        if let Some(addr) = &self.config.quic_listen_addr {
+            let tls_config = self.config.tls.as_ref().ok_or_else(|| {
+                anyhow::anyhow!("quic_listen_addr needs server.tls for its certificate")
+            })?;
+            let addr = addr
+                .parse()
+                .map_err(|e| anyhow::anyhow!("Invalid quic_listen_addr '{}': {}", addr, e))?;
+            let endpoint = crate::utils::quic::server_endpoint(addr, tls_config)?;
+            let server = self.clone();
+            background.spawn(async move { server.serve_quic(endpoint).await });
+        }
+
+        if self.config.stats_interval > 0 {
+            let server = self.clone();
+            background.spawn(async move { server.report_stats().await });
+        }
+
+        if let Some(limiter) = self.connection_limiter.clone() {
+            background.spawn(sweep_rate_limiter(limiter));
+        }
+
+        let server = self.clone();
+        background.spawn(async move { server.expire_proxies_periodically().await });
+
+        #[cfg(unix)]
+        if let Some(access_log) = self.access_log.clone() {
+            background.spawn(reopen_on_signal(access_log));
+        }
+
+        if let Some(period) = sd_notify::watchdog_period() {
+            let server = self.clone();
+            background.spawn(async move {
+                sd_notify::run_watchdog(period, || std::future::ready(server.sweeping(period)))
+                    .await
+            });
+        }
+        let mut upgrades = UpgradeSignal::new(self.upgrade_socket.is_some());
+        sd_notify::notify_ready();
+        inherited.ready(self.pid_file.as_deref());
 
         // listen for client to connect
         loop {
-            match listener.accept().await {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown.cancelled() => break,
+                _ = upgrades.recv() => {
+                    if self.hand_over(&listener, &listening).await {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            match accepted {
                 Ok((stream, addr)) => {
+                    let Some(permit) = self.admit_connection(addr, "control") else {
+                        continue;
+                    };
+                    self.config
+                        .control_socket_options()
+                        .apply(&stream, "control");
                     let server = self.clone();
                     tokio::spawn(async move {
-                        if let Err(e) = server.handle_client(stream, addr).await {
+                        if let Err(e) = server.handle_client(stream, addr, false).await {
                             error!("Error handling client {}: {}", addr, e);
                         }
+                        drop(permit);
                     });
                 }
+                Err(e) if net::is_fd_exhaustion(&e) => {
+                    log_warn!("Out of file descriptors accepting clients: {}", e);
+                    tokio::time::sleep(net::FD_EXHAUSTION_BACKOFF).await;
+                }
                 Err(e) => {
                     error!("Failed to accept connection: {}", e);
                 }
             }
         }
+
+        if self.handed_off.is_cancelled() {
+            drop(listener);
+            accepting.abort_all();
+            self.drain(&shutdown).await;
+        }
+
+        sd_notify::notify("STOPPING=1");
+        self.close_connections(CloseCode::ServerShutdown).await;
+        // removing a client ends its session and closes its listeners and connections
+        let client_ids: Vec<String> = self.clients.read().await.keys().cloned().collect();
+        for client_id in client_ids {
+            self.cleanup_client(&client_id).await;
+        }
+        // nobody can reclaim the listeners of clients that left before
+        self.proxy_listeners.write().await.retain(|_, listener| {
+            if listener.orphaned.is_some() {
+                let _ = listener.cancel_tx.send(());
+            }
+            listener.orphaned.is_none()
+        });
+        log_info!("Server stopped");
+        Ok(())
     }
 
-    /// Handles a single client connection through its entire lifecycle
-    async fn handle_client(&self, mut stream: TcpStream, addr: SocketAddr) -> Result<()> {
-        log_debug!("New client connection from {}", addr);
+    /// Accepts clients connecting over WebSocket on `websocket_listen_addr`
+    async fn serve_websocket(&self, listener: Arc<TcpListener>) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    let Some(permit) = self.admit_connection(addr, "control") else {
+                        continue;
+                    };
+                    self.config
+                        .control_socket_options()
+                        .apply(&stream, "control");
+                    let server = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = server.handle_client(stream, addr, true).await {
+                            error!("Error handling client {}: {}", addr, e);
+                        }
+                        drop(permit);
+                    });
+                }
+                Err(e) if net::is_fd_exhaustion(&e) => {
+                    log_warn!("Out of file descriptors accepting clients: {}", e);
+                    tokio::time::sleep(net::FD_EXHAUSTION_BACKOFF).await;
+                }
+                Err(e) => {
+                    error!("Failed to accept WebSocket connection: {}", e);
+                }
+            }
+        }
+    }
 
-        // Read authentication message
-        let mut frame_reader = FrameReader::new();
-        let mut buffer = [0u8; 4096];
+    /// Logs a traffic summary of every proxy each `stats_interval` minutes
+    async fn report_stats(&self) {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(self.config.stats_interval * 60));
+        // the first tick completes immediately, there is nothing to report yet
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let clients_guard = self.clients.read().await;
+            for (proxy_id, proxy) in clients_guard
+                .values()
+                .flat_map(|client| client.proxies.iter())
+            {
+                let stats = proxy.stats.snapshot();
+                info!(
+                    "proxy {} :{} ({}) — {} conns, {} active, {} dropped, {} rejected, {} out of sequence, {} in, {} out, closed: {}",
+                    format_uuid(proxy_id, "proxy"),
+                    proxy.remote_port,
+                    proxy.name,
+                    stats.connections,
+                    stats.active,
+                    stats.dropped,
+                    stats.rejected,
+                    stats.out_of_sequence,
+                    format_bytes(stats.bytes_in),
+                    format_bytes(stats.bytes_out),
+                    stats.closed
+                );
+            }
+        }
+    }
 
-        // take 30s to receive buffer data
-        let n = timeout(Duration::from_secs(30), stream.read(&mut buffer)).await??;
-        if n == 0 {
-            return Err(anyhow::anyhow!("Connection closed during auth"));
+    /// Handles a single client connection through its entire lifecycle. Connections to
+    /// `websocket_listen_addr` open with a WebSocket upgrade, after TLS if it is enabled
+    async fn handle_client(
+        &self,
+        stream: TcpStream,
+        addr: SocketAddr,
+        websocket: bool,
+    ) -> Result<()> {
+        if self.is_banned(addr.ip()) {
+            log_debug!("Dropping connection from banned {}", addr);
+            return Ok(());
         }
+        log_debug!("New client connection from {}", addr);
+
+        // identity of a verified client certificate, if one was presented
+        let (stream, peer_identity): (BoxedStream, Option<String>) = match &self.tls_acceptor {
+            Some(acceptor) => {
+                let tls_stream = timeout(Duration::from_secs(30), acceptor.accept(stream))
+                    .await
+                    .map_err(|_| anyhow::anyhow!("TLS handshake with {} timed out", addr))?
+                    .map_err(|e| anyhow::anyhow!("TLS handshake with {} failed: {}", addr, e))?;
+                let peer_identity = tls_stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(tls::peer_identity);
+                (Box::new(tls_stream), peer_identity)
+            }
+            None => (Box::new(stream), None),
+        };
+
+        let stream = if websocket {
+            websocket::accept_within(stream, websocket::HANDSHAKE_TIMEOUT)
+                .await
+                .map_err(|e| anyhow::anyhow!("WebSocket handshake with {} failed: {}", addr, e))?
+        } else {
+            stream
+        };
 
-        frame_reader.feed_data(&buffer[..n]);
+        self.serve_session(stream, Vec::new(), peer_identity, addr, None)
+            .await
+    }
+
+    /// Authenticates a client on its control stream and serves the session until it ends.
+    /// `early` holds bytes already read from the stream, `authenticated` is told the ID
+    /// the session is known by once it is registered
+    async fn serve_session(
+        &self,
+        mut stream: BoxedStream,
+        early: Vec<u8>,
+        peer_identity: Option<String>,
+        addr: SocketAddr,
+        authenticated: Option<oneshot::Sender<String>>,
+    ) -> Result<()> {
+        // --- Send authentication challenge ---
 
-        let frame = match frame_reader.try_read_frame()? {
-            Some(frame) => frame,
-            None => return Err(anyhow::anyhow!("Incomplete auth frame")),
+        let challenge_nonce = self.auth_nonces.issue();
+        let challenge = Frame::new(Message::AuthChallenge {
+            version: PROTOCOL_VERSION,
+            nonce: challenge_nonce.clone(),
+        });
+        stream.write_all(&challenge.serialize()?).await?;
+
+        // Read authentication message, clients have 30s to answer
+        let mut frame_reader = FrameReader::new();
+        frame_reader.feed_data(&early);
+        let frame = match timeout(AUTH_NONCE_TTL, frame_reader.read_frame(&mut stream)).await? {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Err(anyhow::anyhow!("Connection closed during auth")),
+            Err(e) => {
+                // Clients from before the challenge handshake send an auth message we cannot decode
+                let reason = format!(
+                    "Unsupported auth message, upgrade required (server speaks protocol version {})",
+                    PROTOCOL_VERSION
+                );
+                self.reject_auth(&mut stream, &reason).await?;
+                return Err(anyhow::anyhow!("Authentication failed for {}: {}", addr, e));
+            }
         };
 
         // --- Parse authentication ---
 
-        let (client_id, crypto) = match frame.message {
+        if !SessionState::AwaitingAuth.allows(&frame.message) {
+            let kind = frame.message.kind();
+            warn!(
+                "Protocol violation by {} in state {}: {}",
+                addr,
+                SessionState::AwaitingAuth,
+                kind
+            );
+            let error = Message::Error {
+                message: format!("Protocol violation: Unexpected {} before Auth", kind),
+            };
+            stream.write_all(&Frame::new(error).serialize()?).await?;
+            return Err(anyhow::anyhow!(
+                "Expected auth message from {}, got {}",
+                addr,
+                kind
+            ));
+        }
+
+        let (client_id, crypto, compression, grant, client_name, data_channels, meta) = match frame
+            .message
+        {
             Message::Auth {
+                version,
+                nonce,
                 enc_token,
                 client_id,
-                name: _client_name,
+                name: client_name,
+                compression: offered_compression,
+                data_channels: offered_data_channels,
+                meta,
             } => {
-                if enc_token != sha256_with_salt(self.config.token.as_bytes(), MAGIC_SALT) {
-                    let response = Message::AuthResponse {
-                        success: false,
-                        session_key: None,
-                        name: self.config.name.clone(),
-                        error: Some("Invalid token".to_string()),
-                    };
-                    let response_frame = Frame::new(response);
-                    stream.write_all(&response_frame.serialize()?).await?;
-                    return Err(anyhow::anyhow!("Authentication failed for {}", addr));
+                let checked = self
+                    .check_auth_version(version)
+                    .and_then(|_| {
+                        if nonce != challenge_nonce {
+                            return Err("Stale authentication nonce".to_string());
+                        }
+                        self.auth_nonces.consume(&nonce)
+                    })
+                    .and_then(|_| {
+                        self.check_credentials(
+                            &enc_token,
+                            &nonce,
+                            &client_id,
+                            peer_identity.as_deref(),
+                        )
+                        .inspect_err(|_| self.record_auth_failure(addr.ip()))
+                    })
+                    .and_then(|matched_token| {
+                        self.check_client_version(meta.as_ref())?;
+                        Ok(matched_token)
+                    });
+                let matched_token = match checked {
+                    Ok(matched_token) => {
+                        self.auth_bans.succeeded(addr.ip());
+                        matched_token
+                    }
+                    Err(reason) => {
+                        self.reject_auth(&mut stream, &reason).await?;
+                        return Err(anyhow::anyhow!(
+                            "Authentication failed for {}: {}",
+                            addr,
+                            reason
+                        ));
+                    }
+                };
+
+                // the ID keys the session's state and logs, and may be replaced by the
+                // server's own, but the proof above was made over the one sent
+                if let Err(reason) = check_client_id(&client_id) {
+                    self.reject_auth(&mut stream, &reason).await?;
+                    return Err(anyhow::anyhow!(
+                        "Authentication failed for {}: {}",
+                        addr,
+                        reason
+                    ));
+                }
+                let assigned = self.config.assign_client_ids;
+                let client_id = if assigned {
+                    Uuid::new_v4().to_string()
+                } else {
+                    client_id
+                };
+
+                if self.clients.read().await.contains_key(&client_id) {
+                    match self.config.duplicate_client_policy {
+                        DuplicateClientPolicy::Reject => {
+                            let reason = format!("Client ID {} is already connected", client_id);
+                            self.reject_auth(&mut stream, &reason).await?;
+                            return Err(anyhow::anyhow!(
+                                "Authentication failed for {}: {}",
+                                addr,
+                                reason
+                            ));
+                        }
+                        DuplicateClientPolicy::Replace => self.replace_session(&client_id).await,
+                    }
                 }
 
+                // A verified certificate identifies the client better than its self-reported name
+                let client_name = peer_identity
+                    .clone()
+                    .or(client_name)
+                    .map(|name| sanitize_name(&name))
+                    .filter(|name| !name.is_empty());
+
                 // Derive session key
-                let session_key =
-                    CryptoContext::derive_session_key(&self.config.token, &client_id)?;
+                let key_token = matched_token
+                    .as_ref()
+                    .map(|t| t.token.as_str())
+                    .unwrap_or(&self.config.token);
+                let session_key = CryptoContext::derive_session_key(key_token, &client_id)?;
                 let crypto = Arc::new(CryptoContext::new(&session_key)?);
 
+                // Pick the compression codec for this session
+                let compression =
+                    Compression::negotiate(self.config.compression, &offered_compression);
+                let data_channels = offered_data_channels && self.config.data_channels;
+
                 // Send success response
                 let response = Message::AuthResponse {
                     success: true,
                     session_key: Some(session_key.clone()),
                     name: self.config.name.clone(),
                     error: None,
+                    compression,
+                    client_id: assigned.then(|| client_id.clone()),
+                    data_channels,
                 };
                 let response_frame = Frame::new(response);
                 stream.write_all(&response_frame.serialize()?).await?;
+                frame_reader.set_framing(Framing::negotiate(version));
 
-                log_info!("Client {} authenticated successfully", client_id);
+                let via = if peer_identity.is_some() {
+                    " by certificate"
+                } else {
+                    ""
+                };
+                let meta = meta.map(ClientMeta::sanitized);
+                let running = match &meta {
+                    Some(meta) => meta.to_string(),
+                    None => "version unknown".to_string(),
+                };
+                log_info!(
+                    "Client {} {} authenticated successfully{} ({})",
+                    client_id,
+                    format_client_info(client_name.as_deref(), &addr.to_string()),
+                    via,
+                    running
+                );
+                log_debug!(
+                    "Negotiated compression {:?} for client {}",
+                    compression,
+                    client_id
+                );
                 // console_info!("Client {} authenticated", format_uuid(&client_id, "client")); TODO:
-                (client_id, crypto)
+                let grant = matched_token.map(TokenGrant::from).unwrap_or_default();
+                if let Some(token_name) = &grant.name {
+                    log_info!("Client {} used token '{}'", client_id, token_name);
+                }
+                (
+                    client_id,
+                    crypto,
+                    compression,
+                    grant,
+                    client_name,
+                    data_channels,
+                    meta,
+                )
+            }
+            Message::DataChannelHello {
+                client_id,
+                connection_id,
+                proof,
+            } => {
+                // the connection's first bytes may have come with the hello
+                let stream = Box::new(Rewound::new(frame_reader.into_buffered(), stream));
+                return self
+                    .accept_data_channel(
+                        stream,
+                        addr,
+                        &challenge_nonce,
+                        &client_id,
+                        &connection_id,
+                        &proof,
+                    )
+                    .await;
             }
             _ => return Err(anyhow::anyhow!("Expected auth message")),
         };
@@ -154,12 +1011,23 @@ impl Server {
         // --- Create client connection ---
 
         let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+        let session_id = Uuid::new_v4().to_string();
         let client_conn = ClientConnection {
             client_id: client_id.clone(),
             sender: tx,
             crypto: crypto.clone(),
             proxies: HashMap::new(),
+            compression,
+            grant,
+            session_id: session_id.clone(),
+            name: client_name,
+            addr,
+            data_channels,
+            meta,
+            state: SessionState::Authenticated,
+            violations: 0,
         };
+        let client_label = client_conn.label();
 
         {
             let mut clients_guard = self.clients.write().await;
@@ -167,45 +1035,72 @@ impl Server {
             if clients_guard.contains_key(&client_id) {
                 return Err(anyhow::anyhow!("Client ID {} already exists", client_id));
             }
+            self.notify_client_authenticated(&client_conn);
             clients_guard.insert(client_id.clone(), client_conn);
         }
+        if let Some(authenticated) = authenticated {
+            let _ = authenticated.send(client_id.clone());
+        }
 
         // Handle incoming messages from client
         let client_id_clone = client_id.clone();
         let bind_host = self.config.bind_host.clone();
         let server_for_cleanup = self.clone();
 
-        let (mut stream_read, mut stream_write) = stream.into_split();
+        let (mut stream_read, mut stream_write) = tokio::io::split(stream);
+        let framing = frame_reader.framing();
 
-        let read_task = {
+        let mut read_task = {
             let server_for_read = self.clone();
             let client_id = client_id.clone();
             let bind_host = bind_host.clone();
             let client_id_for_cleanup = client_id.clone();
+            let session_id = session_id.clone();
+            let client_label = client_label.clone();
 
             tokio::spawn(async move {
-                let mut frame_reader = FrameReader::new();
-                let mut buffer = [0u8; 4096];
-
-                loop {
-                    match stream_read.read(&mut buffer).await {
-                        Ok(0) => break,
-                        Ok(n) => {
-                            frame_reader.feed_data(&buffer[..n]);
-
-                            while let Some(frame) = frame_reader.try_read_frame().unwrap_or(None) {
-                                match server_for_read
-                                    .handle_client_message(frame.message, &client_id, &bind_host)
-                                    .await
-                                {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        error!("Error handling client message: {}", e);
-                                        break;
-                                    }
-                                }
+                'read: loop {
+                    // frames may already be buffered from the handshake
+                    loop {
+                        let frame = match frame_reader.try_read_frame() {
+                            Ok(Some(frame)) => frame,
+                            Ok(None) => break,
+                            Err(e) => {
+                                // the stream cannot be trusted past a bad frame
+                                error!("Closing connection to client {}: {}", client_id, e);
+                                server_for_read
+                                    .send_protocol_error(&client_id, "Undecodable frame")
+                                    .await;
+                                break 'read;
                             }
+                        };
+                        if let Err(e) = frame.message.check_payload_size() {
+                            error!("Closing connection to client {}: {}", client_id, e);
+                            server_for_read
+                                .send_protocol_error(&client_id, "Oversized data frame")
+                                .await;
+                            break 'read;
                         }
+                        if let Err(e) = server_for_read
+                            .handle_client_message(frame.message, &client_id, &bind_host)
+                            .await
+                        {
+                            error!("Error handling client message: {}", e);
+                            break 'read;
+                        }
+                    }
+
+                    let skipped = frame_reader.take_skipped();
+                    if skipped > 0 {
+                        warn!(
+                            "Skipped {} bytes of corrupt frames from client {}",
+                            skipped, client_id
+                        );
+                    }
+
+                    match frame_reader.fill_from(&mut stream_read).await {
+                        Ok(0) => break,
+                        Ok(_) => {}
                         Err(e) => {
                             error!("Error reading from client {}: {}", client_id, e);
                             break;
@@ -215,74 +1110,283 @@ impl Server {
 
                 // Immediately clean up when connection is lost
                 server_for_cleanup
-                    .cleanup_client(&client_id_for_cleanup)
+                    .cleanup_session(&client_id_for_cleanup, &session_id)
                     .await;
-                log_info!(
-                    "Client {} disconnected",
-                    format_uuid(&client_id_for_cleanup, "client")
-                );
+                log_info!("Client {} disconnected", client_label);
             })
         };
 
-        // Handle outgoing messages to client
-        let write_task = {
+        // Handle outgoing messages to client, a write stalled for too long takes it as gone
+        let mut write_task = {
+            let write_timeout = self.config.control_write_timeout;
+            let write_client_id = client_id.clone();
             tokio::spawn(async move {
+                let mut buffer = BytesMut::new();
                 while let Some(message) = rx.recv().await {
-                    let frame = Frame::new(message);
-                    match frame.serialize() {
-                        Ok(data) => {
-                            if let Err(e) = stream_write.write_all(&data).await {
-                                error!("Error writing to client: {}", e);
-                                break;
-                            }
-                        }
-                        Err(e) => {
-                            error!("Error serializing message: {}", e);
-                            break;
-                        }
+                    buffer.clear();
+                    if let Err(e) = Frame::new(message).encode_framed(&mut buffer, framing) {
+                        error!("Error serializing message: {}", e);
+                        break;
+                    }
+                    let written =
+                        write_all_within(&mut stream_write, &buffer, write_timeout.non_zero());
+                    if let Err(e) = written.await {
+                        error!("Error writing to client {}: {}", write_client_id, e);
+                        break;
                     }
                 }
             })
         };
 
-        // Wait for either task to complete
+        // Wait for either task to complete, the write task also ends when the session is replaced
         tokio::select! {
-            _ = read_task => {},
-            _ = write_task => {},
+            _ = &mut read_task => {},
+            _ = &mut write_task => {},
         }
+        read_task.abort();
 
         // Additional cleanup in case read_task didn't handle it
-        self.cleanup_client(&client_id_clone).await;
-        log_info!(
-            "Client {} connection closed",
-            format_uuid(&client_id_clone, "client")
-        );
+        self.cleanup_session(&client_id_clone, &session_id).await;
+        log_info!("Client {} connection closed", client_label);
 
         Ok(())
     }
 
-    /// Clean up all resources associated with a client
-    async fn cleanup_client(&self, client_id: &str) {
-        // Remove client first
-        let client_removed = {
-            let mut clients_guard = self.clients.write().await;
-            clients_guard.remove(client_id).is_some()
-        };
+    /// Whether `ip` is banned, for authentication failures or by an admin
+    fn is_banned(&self, ip: IpAddr) -> bool {
+        self.auth_bans.is_banned(ip) || self.ip_bans.is_banned(ip)
+    }
 
-        if !client_removed {
-            return; // Already cleaned up
+    /// Counts bad credentials from `ip`, banning it once there are too many.
+    /// The line logged for each failure is meant to be matched by tools like fail2ban
+    fn record_auth_failure(&self, ip: IpAddr) {
+        log_warn!("authentication failure from {}", ip);
+        if self.auth_bans.failed(ip) {
+            warn!(
+                "Banning {} for {} after {} authentication failures",
+                ip, self.config.auth_ban_duration, self.config.auth_fail_limit
+            );
+            self.notify_auth_failure_burst(ip);
         }
+    }
 
-        // Clean up proxy listeners for this client
-        let mut proxy_listeners_guard = self.proxy_listeners.write().await;
-        let mut listeners_to_remove = Vec::new();
-
-        for (port, listener_info) in proxy_listeners_guard.iter() {
-            if listener_info.client_id == client_id {
-                listeners_to_remove.push(*port);
-                // Send cancel signal to stop the listener
-                let _ = listener_info.cancel_tx.send(());
-            }
+    /// How a connected client appears in logs, see `ClientConnection::label`
+    async fn client_label(&self, client_id: &str) -> String {
+        match self.clients.read().await.get(client_id) {
+            Some(client) => client.label(),
+            None => format_uuid(client_id, "client"),
+        }
+    }
+
+    /// Sends a failed `AuthResponse` carrying `reason`
+    async fn reject_auth(&self, stream: &mut BoxedStream, reason: &str) -> Result<()> {
+        let response = Message::AuthResponse {
+            success: false,
+            session_key: None,
+            name: self.config.name.clone(),
+            error: Some(reason.to_string()),
+            compression: Compression::None,
+            client_id: None,
+            data_channels: false,
+        };
+        stream.write_all(&Frame::new(response).serialize()?).await?;
+        Ok(())
+    }
+
+    /// Rejects clients speaking another protocol version. Messages are encoded field by
+    /// field, so a peer of another version cannot decode them, e.g. a `NewConnection`
+    /// carrying the peer's `source_addr`
+    fn check_auth_version(&self, version: u32) -> std::result::Result<(), String> {
+        if version != PROTOCOL_VERSION {
+            return Err(format!(
+                "Client protocol version {} is not supported (server speaks {}), upgrade required",
+                version, PROTOCOL_VERSION
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the client runs at least `min_client_version`, when the server has one
+    fn check_client_version(&self, meta: Option<&ClientMeta>) -> std::result::Result<(), String> {
+        let Some(min) = self.config.min_client_version else {
+            return Ok(());
+        };
+        let Some(meta) = meta else {
+            return Err(format!(
+                "Client version unknown, this server accepts {} or newer, upgrade required",
+                min
+            ));
+        };
+        match meta.version.parse::<Version>() {
+            Ok(version) if version >= min => Ok(()),
+            _ => Err(format!(
+                "Client version {} is older than {}, the oldest this server accepts, upgrade required",
+                meta.version, min
+            )),
+        }
+    }
+
+    /// Checks the presented credentials against the configured authentication mode,
+    /// returning the token entry that matched when a token was required.
+    /// `enc_token` must be the HMAC proof for the challenge `nonce`.
+    /// The error tells whether the certificate or the token was the problem.
+    fn check_credentials(
+        &self,
+        enc_token: &[u8],
+        nonce: &[u8],
+        client_id: &str,
+        peer_identity: Option<&str>,
+    ) -> std::result::Result<Option<TokenConfig>, String> {
+        let mode = self.config.auth_mode();
+
+        if matches!(mode, AuthMode::Cert | AuthMode::Both) && peer_identity.is_none() {
+            return Err("Client certificate required".to_string());
+        }
+
+        if matches!(mode, AuthMode::Token | AuthMode::Both) {
+            if enc_token.is_empty() {
+                return Err("Token required".to_string());
+            }
+            return self
+                .config
+                .token_entries()
+                .into_iter()
+                .find(|entry| verify_auth_proof(&entry.token, nonce, client_id, enc_token))
+                .map(Some)
+                .ok_or_else(|| "Invalid token".to_string());
+        }
+
+        Ok(None)
+    }
+
+    /// Clean up all resources associated with a client
+    async fn cleanup_client(&self, client_id: &str) {
+        // Remove client first
+        let removed = {
+            let mut clients_guard = self.clients.write().await;
+            clients_guard.remove(client_id)
+        };
+
+        let Some(client) = removed else {
+            return; // Already cleaned up
+        };
+        self.release_client_resources(client, Duration::ZERO, Duration::ZERO)
+            .await;
+    }
+
+    /// Tells each client its proxy connections are closed for `code`, their handlers then
+    /// count and log them as closed for it
+    async fn close_connections(&self, code: CloseCode) {
+        let closed: Vec<(String, String)> = self
+            .proxy_connections
+            .read()
+            .await
+            .iter()
+            .map(|(connection_id, proxy_conn)| {
+                let _ = proxy_conn.close_code.set(code);
+                (connection_id.clone(), proxy_conn.client_id.clone())
+            })
+            .collect();
+        let clients_guard = self.clients.read().await;
+        for (connection_id, client_id) in closed {
+            if let Some(client) = clients_guard.get(&client_id) {
+                let _ = client
+                    .sender
+                    .send(Message::new_close_connection(&connection_id, code));
+            }
+        }
+    }
+
+    /// Cleans up `client_id` only while it still belongs to `session_id`,
+    /// so a replaced session cannot tear down the one that replaced it
+    async fn cleanup_session(&self, client_id: &str, session_id: &str) {
+        let removed = {
+            let mut clients_guard = self.clients.write().await;
+            match clients_guard.get(client_id) {
+                Some(client) if client.session_id == session_id => clients_guard.remove(client_id),
+                _ => None,
+            }
+        };
+
+        if let Some(client) = removed {
+            let grace = self.config.listener_grace_period.0;
+            self.release_client_resources(client, grace, grace).await;
+        }
+    }
+
+    /// Tells the current session of `client_id` it is being replaced, then cleans it up.
+    /// Its connections are kept for the new session to resume
+    async fn replace_session(&self, client_id: &str) {
+        let removed = {
+            let mut clients_guard = self.clients.write().await;
+            if let Some(client) = clients_guard.get_mut(client_id) {
+                client.close("Replaced by a new session with the same client ID");
+            }
+            clients_guard.remove(client_id)
+        };
+        if let Some(client) = removed {
+            let grace = self.config.listener_grace_period.0;
+            self.release_client_resources(client, Duration::ZERO, grace)
+                .await;
+        }
+        warn!(
+            "Client {} reconnected, replaced its previous session",
+            format_uuid(client_id, "client")
+        );
+    }
+
+    /// Stops the listeners, host routes, secret services and proxy connections of a removed client.
+    /// A listener a newer session of the client already joined keeps running, and with
+    /// a `grace` period the others stay bound that long for the client to reclaim them.
+    /// With a `resume` period, connections stay open that long for the client to resume
+    async fn release_client_resources(
+        &self,
+        client: ClientConnection,
+        grace: Duration,
+        resume: Duration,
+    ) {
+        let client_id = client.client_id.as_str();
+        self.notify_client_disconnected(&client);
+        // Clean up proxy listeners for this client
+        let mut proxy_listeners_guard = self.proxy_listeners.write().await;
+        let mut listeners_to_remove = Vec::new();
+
+        for (port, listener_info) in proxy_listeners_guard.iter_mut() {
+            let members = listener_info.members.len();
+            listener_info
+                .members
+                .retain(|member| !client.proxies.contains_key(&member.proxy_id));
+            let left = listener_info.members.len() < members;
+            if !left && listener_info.client_id != client_id {
+                continue;
+            }
+            if !listener_info.members.is_empty() {
+                if let Some(group) = listener_info.group.as_ref().filter(|_| left) {
+                    log_info!(
+                        "Client {} left group '{}' on port {}",
+                        format_uuid(client_id, "client"),
+                        group,
+                        port
+                    );
+                }
+                continue;
+            }
+            if grace.is_zero() {
+                listeners_to_remove.push(*port);
+                // Send cancel signal to stop the listener
+                let _ = listener_info.cancel_tx.send(());
+            } else if listener_info.orphaned.is_none() {
+                listener_info.orphaned = Some(Instant::now());
+                log_info!(
+                    "Keeping service listener on port {} for client {} to reconnect within {:?}",
+                    port,
+                    format_uuid(client_id, "client"),
+                    grace
+                );
+                let server = self.clone();
+                let port = *port;
+                tokio::spawn(async move { server.expire_orphaned_listener(port, grace).await });
+            }
         }
 
         for port in listeners_to_remove {
@@ -295,113 +1399,322 @@ impl Server {
         }
         drop(proxy_listeners_guard);
 
-        // Clean up any active proxy connections for this client
-        let mut proxy_connections_guard = self.proxy_connections.write().await;
-        let mut connections_to_remove = Vec::new();
-
-        // Find all connections belonging to this client
-        for (connection_id, connection_info) in proxy_connections_guard.iter() {
-            if connection_info.client_id == client_id {
-                connections_to_remove.push(connection_id.clone());
-            }
-        }
-
-        for connection_id in connections_to_remove {
-            if proxy_connections_guard.remove(&connection_id).is_some() {
+        // Release the host names this client served
+        self.shared_routes
+            .write()
+            .await
+            .retain(|(kind, host), route| {
+                if route.client_id != client_id {
+                    return true;
+                }
                 log_info!(
-                    "Cleaned up proxy connection {} for client {}",
-                    connection_id,
-                    client_id
-                );
-                console_info!(
-                    "Cleaned up connection {} for client {}",
-                    format_uuid(&connection_id, "conn"),
+                    "Released {} {} for client {}",
+                    kind.host_label(),
+                    host,
                     format_uuid(client_id, "client")
                 );
-            }
-        }
+                false
+            });
+
+        self.release_secret_services(&client).await;
+
+        self.detach_client_connections(client_id, resume).await;
     }
 
     /// Add a proxy listener for client and handle its connections
+    /// Binds a proxy listener and starts serving it, returning the proxy ID and the bound port.
+    /// A `port` of 0 binds any free port.
     async fn add_proxy(
         &self,
         bind_host: String,
         port: u16,
         client_id: &str,
+        group: Option<String>,
         proxy_listeners_write_guard: &mut RwLockWriteGuard<'_, HashMap<u16, ProxyListenerInfo>>,
-    ) -> Result<String> {
-        let listen_addr = format!("{}:{}", bind_host, port);
-        match TcpListener::bind(&listen_addr).await {
+    ) -> Result<(String, u16)> {
+        match net::bind_tcp((net::unbracket(&bind_host), port), self.config.reuseport).await {
             Ok(listener) => {
+                let port = listener.local_addr()?.port();
                 let listener = Arc::new(listener);
 
                 let (cancel_tx, cancel_rx) = mpsc::unbounded_channel();
+                let orphans = Arc::new(OrphanQueue::default());
 
                 let new_proxy_id = Uuid::new_v4().to_string();
                 let listener_info = ProxyListenerInfo {
                     listener: listener.clone(),
                     client_id: client_id.to_string(),
-                    proxy_id: new_proxy_id.clone(),
+                    group,
+                    members: vec![ListenerMember {
+                        proxy_id: new_proxy_id.clone(),
+                        client_id: client_id.to_string(),
+                    }],
+                    balance: Balance::default(),
+                    orphaned: None,
+                    orphans: orphans.clone(),
+                    bound_at: Instant::now(),
                     cancel_tx,
                 };
 
                 proxy_listeners_write_guard.insert(port, listener_info);
 
-                let client_id_clone = client_id.to_string();
-                let proxy_id_clone = new_proxy_id.clone();
-
                 let server_clone = self.clone();
                 tokio::spawn(async move {
-                    server_clone.handle_proxy_connections(
-                        listener,
-                        client_id_clone,
-                        proxy_id_clone,
-                        cancel_rx,
-                    )
-                    .await;
+                    server_clone
+                        .handle_proxy_connections(listener, port, orphans, cancel_rx)
+                        .await;
                 });
 
-                Ok(new_proxy_id)
+                Ok((new_proxy_id, port))
             }
             Err(e) => Err(e.into()),
         }
     }
 
-    async fn cancel_proxy(
+    /// Starts a proxy on a port picked by the server: `preferred_port` if it is still free or
+    /// the client's listener for `name` there can be reused, otherwise a free port from
+    /// `port_range`, the token's allowed ports, or the OS, in that order
+    async fn add_auto_proxy(
+        &self,
+        bind_host: &str,
+        preferred_port: Option<u16>,
+        client_id: &str,
+        name: &str,
+    ) -> Result<(String, u16)> {
+        let allowed_ports = {
+            let clients_guard = self.clients.read().await;
+            clients_guard
+                .get(client_id)
+                .and_then(|client| client.grant.allowed_ports.clone())
+        };
+        let permitted = |port: u16| {
+            self.server_port_violation(port).is_none()
+                && allowed_ports
+                    .as_ref()
+                    .is_none_or(|allowed| allowed.contains(port))
+        };
+
+        let mut listeners = self.proxy_listeners.write().await;
+
+        // after a reconnect the previous listener may not be released yet
+        if let Some(port) = preferred_port.filter(|port| *port != 0 && permitted(*port)) {
+            if let PortClaim::Reuse(proxy_id) = self
+                .claim_listener(port, client_id, name, None, &mut listeners)
+                .await
+            {
+                let proxy_id = self.join_listener(port, proxy_id, client_id, &mut listeners);
+                return Ok((proxy_id, port));
+            }
+        }
+
+        // the OS only picks the port when nothing narrows the choice
+        let restricted = allowed_ports
+            .as_ref()
+            .or(self.config.allowed_ports.as_ref());
+        let pool: Box<dyn Iterator<Item = u16> + Send + '_> =
+            match (&self.config.port_range, restricted) {
+                (Some(range), _) => Box::new(range.ports()),
+                (None, Some(allowed)) => Box::new(allowed.ports()),
+                (None, None) => Box::new(std::iter::once(0)),
+            };
+        let candidates = preferred_port
+            .filter(|port| *port != 0)
+            .into_iter()
+            .chain(pool);
+
+        for port in candidates {
+            if port != 0 && (listeners.contains_key(&port) || !permitted(port)) {
+                continue;
+            }
+            match self
+                .add_proxy(bind_host.to_string(), port, client_id, None, &mut listeners)
+                .await
+            {
+                Ok(assigned) => {
+                    if let Some(preferred) = preferred_port.filter(|p| *p != 0 && *p != assigned.1)
+                    {
+                        log_info!(
+                            "Port {} asked for by service '{}' is taken, assigned {}",
+                            preferred,
+                            name,
+                            assigned.1
+                        );
+                    }
+                    return Ok(assigned);
+                }
+                Err(e) => {
+                    log_debug!("Port {} unavailable for auto-assignment: {}", port, e);
+                }
+            }
+        }
+
+        Err(anyhow::anyhow!(
+            "No free port available for auto-assignment"
+        ))
+    }
+
+    /// Decides whether `client_id` may put its service `name` of `group` on `port`. Its own
+    /// listener there is reused when it serves `name`, or no proxy of the current session at
+    /// all, as after a reconnect that raced the cleanup of the previous session. A listener of
+    /// the same group is joined the same way, whichever member bound it
+    async fn claim_listener(
         &self,
         port: u16,
         client_id: &str,
-        clients_write_guard: &mut RwLockWriteGuard<'_, HashMap<String, ClientConnection>>,
+        name: &str,
+        group: Option<&str>,
         proxy_listeners_write_guard: &mut RwLockWriteGuard<'_, HashMap<u16, ProxyListenerInfo>>,
-    ) -> Result<()> {
-        if let Some(existing_listener) = proxy_listeners_write_guard.get_mut(&port) {
-            // check client_id match
-            if existing_listener.client_id != client_id {
-                return Err(anyhow::anyhow!(
-                    "Client {} does not own proxy listener on port {}",
-                    client_id,
-                    port
+    ) -> PortClaim {
+        let Some(existing_listener) = proxy_listeners_write_guard.get(&port) else {
+            return PortClaim::Free;
+        };
+        let clients_guard = self.clients.read().await;
+        let owner = self.port_owner(existing_listener, &clients_guard);
+        match (existing_listener.group.as_deref(), group) {
+            (Some(existing), Some(group)) if existing == group => {}
+            (Some(existing), _) => {
+                return PortClaim::Taken(format!("Port {port} is shared by group '{existing}'"));
+            }
+            (None, Some(group)) => {
+                return PortClaim::Taken(format!(
+                    "Port {port} already in use{owner} outside group '{group}'"
                 ));
             }
+            (None, None) if existing_listener.client_id != client_id => {
+                return PortClaim::Taken(format!("Port {port} already in use{owner}"));
+            }
+            (None, None) => {}
+        }
 
-            let proxy_id = existing_listener.proxy_id.clone();
+        let current = clients_guard.get(client_id).and_then(|client| {
+            client.proxies.iter().find(|(id, _)| {
+                existing_listener
+                    .members
+                    .iter()
+                    .any(|member| member.proxy_id == **id)
+            })
+        });
+        match current {
+            Some((proxy_id, proxy)) if proxy.name == name => {
+                PortClaim::Reuse(Some(proxy_id.clone()))
+            }
+            Some((_, proxy)) => PortClaim::Taken(format!(
+                "Port {port} already used by service '{}'",
+                proxy.name
+            )),
+            None => PortClaim::Reuse(None),
+        }
+    }
 
-            // Cancel the listener
-            let _ = existing_listener.cancel_tx.send(());
+    /// ` by client <short ID> (<name>)`, naming the client that bound `listener` for
+    /// rejections when `reveal_port_owner` is set, empty otherwise
+    fn port_owner(
+        &self,
+        listener: &ProxyListenerInfo,
+        clients: &HashMap<String, ClientConnection>,
+    ) -> String {
+        if !self.config.reveal_port_owner {
+            return String::new();
+        }
+        let owner = short_id(&listener.client_id);
+        match clients
+            .get(&listener.client_id)
+            .and_then(|client| client.name.as_deref())
+        {
+            Some(name) => format!(" by client {} ({})", owner, name),
+            None => format!(" by client {}", owner),
+        }
+    }
 
-            // Remove the listener
-            let _ = proxy_listeners_write_guard.remove(&port);
+    /// Adds a proxy of `client_id` to the listener on `port` unless `proxy_id` already
+    /// serves it, returning the proxy ID to answer with
+    fn join_listener(
+        &self,
+        port: u16,
+        proxy_id: Option<String>,
+        client_id: &str,
+        proxy_listeners_write_guard: &mut RwLockWriteGuard<'_, HashMap<u16, ProxyListenerInfo>>,
+    ) -> String {
+        proxy_id.unwrap_or_else(|| {
+            let proxy_id = Uuid::new_v4().to_string();
+            if let Some(listener) = proxy_listeners_write_guard.get_mut(&port) {
+                listener.members.push(ListenerMember {
+                    proxy_id: proxy_id.clone(),
+                    client_id: client_id.to_string(),
+                });
+                if listener.orphaned.take().is_some() {
+                    listener.orphans.settle();
+                    log_info!("Client reclaimed its listener on port {}", port);
+                }
+            }
+            proxy_id
+        })
+    }
 
-            // remove its proxy connections
-            let mut proxy_connections_write_guard = self.proxy_connections.write().await;
-            proxy_connections_write_guard.remove(&proxy_id);
+    /// Stops the listener on `port` once it stayed orphaned for `grace`
+    async fn expire_orphaned_listener(&self, port: u16, grace: Duration) {
+        tokio::time::sleep(grace).await;
+        let mut listeners_guard = self.proxy_listeners.write().await;
+        // reclaimed in time, or orphaned again since and left to a later expiry
+        let expired = listeners_guard.get(&port).is_some_and(|listener| {
+            listener
+                .orphaned
+                .is_some_and(|since| since.elapsed() >= grace)
+        });
+        if let Some(listener) = expired.then(|| listeners_guard.remove(&port)).flatten() {
+            let _ = listener.cancel_tx.send(());
+            log_info!(
+                "Cleaned up service listener on port {} for client {}, not reclaimed in time",
+                port,
+                format_uuid(&listener.client_id, "client")
+            );
+        }
+    }
 
-            // remove from clients
-            if let Some(client) = clients_write_guard.get_mut(client_id) {
-                client.proxies.remove(&proxy_id);
-            }
+    /// Removes one proxy from the listener on `port`, stopping the listener if it was the last
+    fn leave_listener(
+        &self,
+        port: u16,
+        proxy_id: &str,
+        proxy_listeners_write_guard: &mut RwLockWriteGuard<'_, HashMap<u16, ProxyListenerInfo>>,
+    ) {
+        let Some(listener) = proxy_listeners_write_guard.get_mut(&port) else {
+            return;
+        };
+        listener
+            .members
+            .retain(|member| member.proxy_id != proxy_id);
+        if listener.members.is_empty() {
+            let _ = listener.cancel_tx.send(());
+            proxy_listeners_write_guard.remove(&port);
         }
-        Ok(())
+    }
+
+    /// Picks the proxy serving a new connection on `port` among those of connected sessions
+    async fn serving_proxy(&self, port: u16) -> Option<ServingProxy> {
+        let listeners_guard = self.proxy_listeners.read().await;
+        let listener = listeners_guard.get(&port)?;
+        let clients_guard = self.clients.read().await;
+        let online: Vec<_> = listener
+            .members
+            .iter()
+            .filter_map(|member| {
+                let proxy = clients_guard
+                    .get(&member.client_id)?
+                    .proxies
+                    .get(&member.proxy_id)?;
+                Some((member, proxy))
+            })
+            .collect();
+        listener
+            .balance
+            .pick(&online)
+            .map(|(member, proxy)| ServingProxy {
+                client_id: member.client_id.clone(),
+                proxy_id: member.proxy_id.clone(),
+                max_connections: proxy.max_connections,
+                stats: proxy.stats.clone(),
+            })
     }
 
     /// Processes messages received from a client
@@ -411,10 +1724,21 @@ impl Server {
         client_id: &str,
         bind_host: &str,
     ) -> Result<()> {
+        if !self.admit_message(&message, client_id).await? {
+            return Ok(());
+        }
+        // visitor connections pass between two clients, the server only relays them
+        let Some(message) = self.relay_visitor_message(message, client_id).await? else {
+            return Ok(());
+        };
+        if !self.admit_connection_message(&message, client_id).await? {
+            return Ok(());
+        }
         match message {
             // receive response data
             Message::Data {
                 connection_id,
+                seq,
                 data,
             } => {
                 // Forward data to proxy connection
@@ -425,66 +1749,353 @@ impl Server {
                     connection_id
                 );
 
-                let proxy_connections_guard = self.proxy_connections.read().await;
-                if let Some(proxy_conn) = proxy_connections_guard.get(&connection_id) {
-                    if let Err(e) = proxy_conn.sender.send(data) {
-                        error!("Failed to forward data to proxy connection: {}", e);
+                self.forward_to_proxy_connection(&connection_id, seq, data)
+                    .await;
+            }
+            Message::CompressedData {
+                connection_id,
+                seq,
+                codec,
+                data,
+            } => {
+                let data = codec.decompress(&data)?;
+                log_debug!(
+                    client_id = client_id,
+                    "Received {} bytes ({:?} compressed) from client for connection {}",
+                    data.len(),
+                    codec,
+                    connection_id
+                );
+
+                self.forward_to_proxy_connection(&connection_id, seq, data)
+                    .await;
+            }
+            Message::ConnectionResponse {
+                connection_id,
+                success,
+                error,
+            } => {
+                let outcome = match (success, error) {
+                    (true, _) => Ok(()),
+                    (false, reason) => Err(reason.unwrap_or_else(|| "unknown error".to_string())),
+                };
+                if let Err(reason) = &outcome {
+                    log_info!(
+                        client_id = client_id,
+                        "Client could not open connection {}: {}",
+                        connection_id,
+                        reason
+                    );
+                }
+
+                let mut proxy_connections_guard = self.proxy_connections.write().await;
+                let Some(proxy_conn) = proxy_connections_guard.get_mut(&connection_id) else {
+                    log_debug!("Proxy connection {} not found", connection_id);
+                    return Ok(());
+                };
+                match (proxy_conn.response.take(), outcome) {
+                    (Some(response), Ok(())) => {
+                        let _ = response.send(Ok(None));
                     }
-                } else {
-                    log_warn!("Proxy connection {} not found", connection_id);
+                    (None, Ok(())) => {}
+                    // the client already forgot the connection
+                    (Some(response), Err(reason)) => {
+                        let _ = response.send(Err(reason));
+                        proxy_connections_guard.remove(&connection_id);
+                    }
+                    (None, Err(reason)) => {
+                        let body = format!("{}\n", reason);
+                        let response = http::error_response(502, "Bad Gateway", &body);
+                        let _ = proxy_conn.sender.send(WriteCommand::Data(response));
+                        let _ = proxy_conn.close_code.set(CloseCode::LocalError);
+                        proxy_connections_guard.remove(&connection_id);
+                    }
+                }
+            }
+            Message::WindowUpdate {
+                connection_id,
+                bytes,
+            } => {
+                if let Some(proxy_conn) = self.proxy_connections.read().await.get(&connection_id) {
+                    proxy_conn.window.grant(bytes);
+                    if let Some(replay) = &proxy_conn.replay {
+                        replay.acknowledge(bytes);
+                    }
+                }
+            }
+            Message::ResumeConnection {
+                connection_id,
+                received,
+                acknowledged,
+            } => {
+                self.resume_connection(client_id, &connection_id, received, acknowledged)
+                    .await;
+            }
+            Message::Pause { connection_id } => {
+                if let Some(proxy_conn) = self.proxy_connections.read().await.get(&connection_id) {
+                    debug!("Client {} paused connection {}", client_id, connection_id);
+                    proxy_conn.window.pause();
+                }
+            }
+            Message::Resume { connection_id } => {
+                if let Some(proxy_conn) = self.proxy_connections.read().await.get(&connection_id) {
+                    debug!("Client {} resumed connection {}", client_id, connection_id);
+                    proxy_conn.window.resume();
+                }
+            }
+            Message::CloseConnection {
+                connection_id,
+                stats,
+                reason,
+                code,
+            } => {
+                // the words of a reason tell more than its code, a local service that went
+                // away is not worth telling
+                match (reason, code) {
+                    (Some(reason), _) => {
+                        log_info!(
+                            client_id = client_id,
+                            "Client closed connection {}: {}",
+                            connection_id,
+                            reason
+                        );
+                    }
+                    (None, Some(code)) if code != CloseCode::PeerClosed => {
+                        log_info!(
+                            client_id = client_id,
+                            "Client closed connection {}: {}",
+                            connection_id,
+                            code
+                        );
+                    }
+                    (None, _) => {
+                        log_debug!(
+                            client_id = client_id,
+                            "Client closed connection {}",
+                            connection_id
+                        );
+                    }
+                }
+                // the connection's handler logs what the client reported
+                let removed = self.proxy_connections.write().await.remove(&connection_id);
+                if let Some(proxy_conn) = removed {
+                    if let Some(stats) = stats {
+                        let _ = proxy_conn.reported.set(stats);
+                    }
+                    if let Some(code) = code {
+                        let _ = proxy_conn.close_code.set(code);
+                    }
+                }
+            }
+            Message::ShutdownWrite { connection_id } => {
+                log_debug!(
+                    client_id = client_id,
+                    "Client finished sending on connection {}",
+                    connection_id
+                );
+                if let Some(proxy_conn) = self.proxy_connections.read().await.get(&connection_id) {
+                    let _ = proxy_conn.sender.send(WriteCommand::ShutdownWrite);
                 }
             }
             // update proxy (service) config
             Message::ProxyConfig {
+                request_id,
                 op,
+                name,
                 local_ip,
                 local_port,
                 remote_port,
+                preferred_port,
+                bind_host: requested_bind_host,
+                http_host,
+                sni,
+                group,
+                max_connections,
+                secret_hash,
+                relay_peers,
+                ttl_ms,
             } => {
-                log_info!(
-                    "Setting up proxy for client {}: {}:{} -> :{}",
-                    client_id,
-                    local_ip,
-                    local_port,
-                    remote_port
-                );
+                let expires_at = ttl_ms.map(|ttl| Instant::now() + Duration::from_millis(ttl));
+                if let Some(secret_hash) = secret_hash {
+                    let proxy_info = ProxyInfo {
+                        name,
+                        local_ip,
+                        local_port,
+                        remote_port: 0,
+                        max_connections,
+                        stats: Arc::default(),
+                        expires_at,
+                        local_error: None,
+                    };
+                    self.setup_secret_service(op, proxy_info, secret_hash, client_id, request_id)
+                        .await;
+                    return Ok(());
+                }
+                if let Some(peers) = relay_peers {
+                    let proxy_info = ProxyInfo {
+                        name,
+                        local_ip,
+                        local_port,
+                        remote_port: 0,
+                        max_connections,
+                        stats: Arc::default(),
+                        expires_at,
+                        local_error: None,
+                    };
+                    self.setup_relay_service(op, proxy_info, peers, client_id, request_id)
+                        .await;
+                    return Ok(());
+                }
+                // members share a listener, so a group needs a port of its own
+                if op == ProxyConfigOpCode::Update
+                    && group.is_some()
+                    && (remote_port == 0 || http_host.is_some() || sni.is_some())
+                {
+                    warn!(
+                        "Rejected proxy '{}' for client {}: group without a remote port",
+                        name,
+                        format_uuid(client_id, "client")
+                    );
+                    self.send_proxy_config_error(
+                        client_id,
+                        request_id,
+                        "A group needs a fixed remote_port".to_string(),
+                    )
+                    .await;
+                    return Ok(());
+                }
+                let shared = match (http_host, sni) {
+                    (Some(host), _) => Some((SharedPort::Http, host)),
+                    (None, Some(name)) => Some((SharedPort::Sni, name)),
+                    (None, None) => None,
+                };
+                if let Some((kind, host)) = shared {
+                    let proxy_info = ProxyInfo {
+                        name,
+                        local_ip,
+                        local_port,
+                        remote_port: self.shared_port(kind).unwrap_or_default(),
+                        // host routes share the port's connections, they are not limited
+                        max_connections: None,
+                        stats: Arc::default(),
+                        expires_at,
+                        local_error: None,
+                    };
+                    self.setup_shared_route(kind, op, &host, proxy_info, client_id, request_id)
+                        .await;
+                    return Ok(());
+                }
+
+                // services may ask for another bind host, subject to the server's whitelist
+                let bind_host = match requested_bind_host {
+                    Some(host) => {
+                        if let Some(reason) = self.bind_host_violation(&host) {
+                            warn!(
+                                "Rejected proxy '{}' on {} for client {}: {}",
+                                name,
+                                net::join_host_port(&host, remote_port),
+                                format_uuid(client_id, "client"),
+                                reason
+                            );
+                            self.send_proxy_config_error(client_id, request_id, reason)
+                                .await;
+                            return Ok(());
+                        }
+                        host
+                    }
+                    None => bind_host.to_string(),
+                };
+                let bind_host = bind_host.as_str();
 
                 let proxy_info = ProxyInfo {
+                    name: name.clone(),
                     local_ip: local_ip.clone(),
                     local_port,
                     remote_port,
+                    max_connections,
+                    stats: Arc::default(),
+                    expires_at,
+                    local_error: None,
                 };
 
+                if op == ProxyConfigOpCode::Update && remote_port == 0 {
+                    self.setup_auto_proxy(
+                        proxy_info,
+                        preferred_port,
+                        client_id,
+                        bind_host,
+                        request_id,
+                    )
+                    .await;
+                    return Ok(());
+                }
+
+                log_info!(
+                    "Setting up proxy '{}' for client {}: {} -> :{}",
+                    name,
+                    self.client_label(client_id).await,
+                    net::join_host_port(&local_ip, local_port),
+                    remote_port
+                );
+
+                if op == ProxyConfigOpCode::Update {
+                    if let Some(reason) = self.port_policy_violation(client_id, remote_port).await {
+                        warn!(
+                            "Rejected proxy '{}' on port {} for client {}: {}",
+                            name,
+                            remote_port,
+                            format_uuid(client_id, "client"),
+                            reason
+                        );
+                        self.send_proxy_config_error(client_id, request_id, reason)
+                            .await;
+                        return Ok(());
+                    }
+                }
+
                 let mut listeners = self.proxy_listeners.write().await;
-                // Start proxy listener if not already listening on this port
-                if !listeners.contains_key(&remote_port) {
-                    if op == ProxyConfigOpCode::Delete {
+                let claim = self
+                    .claim_listener(
+                        remote_port,
+                        client_id,
+                        &name,
+                        group.as_deref(),
+                        &mut listeners,
+                    )
+                    .await;
+                match (claim, op) {
+                    (PortClaim::Free, ProxyConfigOpCode::Delete) => {
                         // nothing to delete, ignore
                         log_debug!(
                             "Ignoring delete operation: {local_ip}:{local_port}:{remote_port}"
                         );
-                    } else {
-                        // if update, then listen and add proxy
+                    }
+                    (PortClaim::Free, ProxyConfigOpCode::Update) => {
+                        // Start proxy listener, nothing listens on this port yet
                         match self
                             .add_proxy(
                                 bind_host.to_string(),
                                 remote_port,
                                 client_id,
+                                group.clone(),
                                 &mut listeners,
                             )
                             .await
                         {
-                            Ok(new_proxy_id) => {
+                            Ok((new_proxy_id, _)) => {
                                 // send success response
                                 let mut clients_guard = self.clients.write().await;
                                 if let Some(client) = clients_guard.get_mut(client_id) {
+                                    self.notify_proxy_registered(client, &proxy_info, None);
                                     client.proxies.insert(new_proxy_id.clone(), proxy_info);
 
                                     // Send response
                                     let response = Message::ProxyConfigResponse {
+                                        request_id,
                                         success: true,
                                         proxy_id: Some(new_proxy_id.clone()),
                                         error: None,
+                                        assigned_port: Some(remote_port),
                                     };
                                     if let Err(e) = client.sender.send(response) {
                                         error!("Failed to send proxy config response: {}", e);
@@ -492,209 +2103,545 @@ impl Server {
                                 }
 
                                 log_info!(
-                                    "Proxy listener started on {}:{}",
-                                    bind_host,
-                                    remote_port
+                                    "Proxy '{}' listener started on {}",
+                                    name,
+                                    net::join_host_port(bind_host, remote_port)
                                 );
                             }
                             Err(e) => {
                                 error!("Failed to start proxy listener on {}: {}", bind_host, e);
-
-                                // Send error response
-                                let clients_guard = self.clients.read().await;
-                                if let Some(client) = clients_guard.get(client_id) {
-                                    let response = Message::ProxyConfigResponse {
-                                        success: false,
-                                        proxy_id: None,
-                                        error: Some(format!(
-                                            "Failed to bind port {remote_port}: {e}"
-                                        )),
-                                    };
-                                    let _ = client.sender.send(response);
-                                }
+                                self.notify_listener_bind_failed(
+                                    client_id,
+                                    &proxy_info,
+                                    e.to_string(),
+                                )
+                                .await;
+                                self.send_proxy_config_error(
+                                    client_id,
+                                    request_id,
+                                    format!("Failed to bind port {remote_port}: {e}"),
+                                )
+                                .await;
                             }
                         }
                     }
-                } else {
-                    // Port already in use, check if it's by the same client
-                    if let Some(existing_listener) = listeners.get(&remote_port) {
-                        if existing_listener.client_id == client_id {
-                            // same, delete former first
-
-                            let mut clients_guard = self.clients.write().await;
-                            match self
-                                .cancel_proxy(
-                                    remote_port,
-                                    client_id,
-                                    &mut clients_guard,
-                                    &mut listeners,
-                                )
-                                .await
-                            {
-                                Ok(()) => {}
-                                Err(e) => {
-                                    log_error!("Failed to cancel proxy: {}", e);
-                                    let response = Message::ProxyConfigResponse {
-                                        success: false,
-                                        proxy_id: None,
-                                        error: Some(format!("Failed to cancel proxy: {}", e)),
-                                    };
-                                    if let Some(client) = clients_guard.get(client_id) {
-                                        let _ = client.sender.send(response);
-                                        return Ok(());
-                                    }
-                                }
-                            }
-
-                            // has update, need recreate and add proxy
-                            if op == ProxyConfigOpCode::Update {
-                                match self
-                                    .add_proxy(
-                                        bind_host.to_string(),
-                                        remote_port,
-                                        client_id,
-                                        &mut listeners,
-                                    )
-                                    .await
-                                {
-                                    Ok(new_proxy_id) => {
-                                        // send success
-                                        if let Some(client) = clients_guard.get_mut(client_id) {
-                                            client.proxies.insert(new_proxy_id.clone(), proxy_info);
-
-                                            // Send response
-                                            let response = Message::ProxyConfigResponse {
-                                                success: true,
-                                                proxy_id: Some(new_proxy_id),
-                                                error: None,
-                                            };
-                                            let _ = client.sender.send(response);
-                                        }
-                                    }
-                                    Err(e) => {
-                                        // send error
-                                        if let Some(client) = clients_guard.get_mut(client_id) {
-                                            let response = Message::ProxyConfigResponse {
-                                                success: false,
-                                                proxy_id: None,
-                                                error: Some(format!("Failed to add proxy: {}", e)),
-                                            };
-                                            let _ = client.sender.send(response);
-                                        }
-                                    }
-                                }
-                            }
+                    (PortClaim::Reuse(proxy_id), ProxyConfigOpCode::Update) => {
+                        // the same service registered again, answer as the first time
+                        let proxy_id =
+                            self.join_listener(remote_port, proxy_id, client_id, &mut listeners);
+                        let mut clients_guard = self.clients.write().await;
+                        if let Some(client) = clients_guard.get_mut(client_id) {
+                            self.notify_proxy_registered(client, &proxy_info, None);
+                            client.proxies.insert(proxy_id.clone(), proxy_info);
+                            let response = Message::ProxyConfigResponse {
+                                request_id,
+                                success: true,
+                                proxy_id: Some(proxy_id),
+                                error: None,
+                                assigned_port: Some(remote_port),
+                            };
+                            let _ = client.sender.send(response);
+                        }
+                        // connections held while the client was away can be served now
+                        if let Some(listener) = listeners.get(&remote_port) {
+                            listener.orphans.wake();
+                        }
+                        if let Some(group) = &group {
+                            log_info!(
+                                "Proxy '{}' joined group '{}' on {}",
+                                name,
+                                group,
+                                net::join_host_port(bind_host, remote_port)
+                            );
                         } else {
-                            // not same, no permission
-                            if op == ProxyConfigOpCode::Delete {
-                                // no permission to delete, ignore
-                                log_warn!(
-                                    "Port {} already in use by another client: {}",
-                                    remote_port,
-                                    existing_listener.client_id
-                                );
-                            } else {
-                                // no permission to update
-                                let clients_guard = self.clients.read().await;
-                                if let Some(client) = clients_guard.get(client_id) {
-                                    let response = Message::ProxyConfigResponse {
-                                        success: false,
-                                        proxy_id: None,
-                                        error: Some(format!("Port {remote_port} already in use")),
-                                    };
-                                    let _ = client.sender.send(response);
-                                }
-                            }
+                            log_info!(
+                                "Proxy '{}' reuses its listener on {}",
+                                name,
+                                net::join_host_port(bind_host, remote_port)
+                            );
+                        }
+                    }
+                    (PortClaim::Reuse(Some(proxy_id)), ProxyConfigOpCode::Delete) => {
+                        self.leave_listener(remote_port, &proxy_id, &mut listeners);
+                        if let Some(client) = self.clients.write().await.get_mut(client_id) {
+                            client.proxies.remove(&proxy_id);
                         }
+                        log_info!("Proxy '{}' on port {} removed", name, remote_port);
+                    }
+                    (PortClaim::Reuse(None), ProxyConfigOpCode::Delete) => {
+                        log_debug!(
+                            "Ignoring delete operation, '{}' does not serve port {}",
+                            name,
+                            remote_port
+                        );
+                    }
+                    (PortClaim::Taken(reason), ProxyConfigOpCode::Delete) => {
+                        // no permission to delete, ignore
+                        log_warn!("Ignoring delete of '{}': {}", name, reason);
+                    }
+                    (PortClaim::Taken(reason), ProxyConfigOpCode::Update) => {
+                        // no permission to update
+                        warn!(
+                            "Rejected proxy '{}' for client {}: {}",
+                            name,
+                            format_uuid(client_id, "client"),
+                            reason
+                        );
+                        self.send_proxy_config_error(client_id, request_id, reason)
+                            .await;
+                    }
+                }
+            }
+            Message::VisitorConnect {
+                request_id,
+                service_name,
+                secret_hash,
+            } => {
+                self.connect_visitor(client_id, request_id, &service_name, &secret_hash)
+                    .await;
+            }
+            Message::RelayConnect {
+                request_id,
+                target_client,
+                target_service,
+            } => {
+                self.connect_relay(client_id, request_id, &target_client, &target_service)
+                    .await;
+            }
+            Message::ServiceStatus { proxy_id, error } => {
+                let mut clients_guard = self.clients.write().await;
+                let Some(client) = clients_guard.get_mut(client_id) else {
+                    return Ok(());
+                };
+                let label = client.label();
+                let Some(proxy) = client.proxies.get_mut(&proxy_id) else {
+                    log_debug!("Status of unknown proxy {} from client {}", proxy_id, label);
+                    return Ok(());
+                };
+                match &error {
+                    Some(error) => {
+                        warn!(
+                            "Client {} reports the local service of proxy '{}' unreachable: {}",
+                            label, proxy.name, error
+                        );
+                    }
+                    None => {
+                        log_info!(
+                            "Client {} reports the local service of proxy '{}' reachable",
+                            label,
+                            proxy.name
+                        );
                     }
                 }
+                proxy.local_error = error;
             }
             Message::Heartbeat { timestamp } => {
                 debug!("Heartbeat from client {}: {}", client_id, timestamp);
 
                 let clients_guard = self.clients.read().await;
                 if let Some(client) = clients_guard.get(client_id) {
-                    let response = Message::HeartbeatResponse { timestamp };
+                    let response = Message::HeartbeatResponse {
+                        timestamp,
+                        stats: Some(client.stats()),
+                    };
                     let _ = client.sender.send(response);
                 }
             }
-            _ => {
+            Message::Error { message } => {
+                warn!("Client {} reported an error: {}", client_id, message);
+            }
+            // refused by the session's state above
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Reports a protocol violation to a client with a `Message::Error`
+    async fn send_protocol_error(&self, client_id: &str, violation: &str) {
+        let clients_guard = self.clients.read().await;
+        if let Some(client) = clients_guard.get(client_id) {
+            let _ = client.sender.send(Message::Error {
+                message: format!("Protocol violation: {}", violation),
+            });
+        }
+    }
+
+    /// Handles a `ProxyConfig` update asking the server to pick the remote port
+    async fn setup_auto_proxy(
+        &self,
+        mut proxy_info: ProxyInfo,
+        preferred_port: Option<u16>,
+        client_id: &str,
+        bind_host: &str,
+        request_id: u64,
+    ) {
+        log_info!(
+            "Setting up proxy '{}' for client {}: {} -> auto (preferred: {:?})",
+            proxy_info.name,
+            self.client_label(client_id).await,
+            net::join_host_port(&proxy_info.local_ip, proxy_info.local_port),
+            preferred_port
+        );
+
+        let response = match self
+            .add_auto_proxy(bind_host, preferred_port, client_id, &proxy_info.name)
+            .await
+        {
+            Ok((proxy_id, port)) => {
+                log_info!(
+                    "Proxy '{}' listener started on {} (auto-assigned)",
+                    proxy_info.name,
+                    net::join_host_port(bind_host, port)
+                );
+                let mut clients_guard = self.clients.write().await;
+                if let Some(client) = clients_guard.get_mut(client_id) {
+                    proxy_info.remote_port = port;
+                    self.notify_proxy_registered(client, &proxy_info, None);
+                    client.proxies.insert(proxy_id.clone(), proxy_info);
+                }
+                drop(clients_guard);
+                if let Some(listener) = self.proxy_listeners.read().await.get(&port) {
+                    listener.orphans.wake();
+                }
+                Message::ProxyConfigResponse {
+                    request_id,
+                    success: true,
+                    proxy_id: Some(proxy_id),
+                    error: None,
+                    assigned_port: Some(port),
+                }
+            }
+            Err(e) => {
+                error!(
+                    "Failed to auto-assign a port for client {}: {}",
+                    client_id, e
+                );
+                self.notify_listener_bind_failed(client_id, &proxy_info, e.to_string())
+                    .await;
+                Message::ProxyConfigResponse {
+                    request_id,
+                    success: false,
+                    proxy_id: None,
+                    error: Some(e.to_string()),
+                    assigned_port: None,
+                }
+            }
+        };
+
+        let clients_guard = self.clients.read().await;
+        if let Some(client) = clients_guard.get(client_id) {
+            let _ = client.sender.send(response);
+        }
+    }
+
+    /// The configured port for a kind of shared port, None when that routing is disabled
+    fn shared_port(&self, kind: SharedPort) -> Option<u16> {
+        match kind {
+            SharedPort::Http => self.config.http_port,
+            SharedPort::Sni => self.config.sni_port,
+        }
+    }
+
+    /// Handles a `ProxyConfig` for a service routed by host name, claiming or releasing
+    /// `host` on the shared port
+    async fn setup_shared_route(
+        &self,
+        kind: SharedPort,
+        op: ProxyConfigOpCode,
+        host: &str,
+        proxy_info: ProxyInfo,
+        client_id: &str,
+        request_id: u64,
+    ) {
+        let host = host.to_ascii_lowercase();
+        let key = (kind, host.clone());
+        let mut routes = self.shared_routes.write().await;
+        let mut clients_guard = self.clients.write().await;
+        let Some(client) = clients_guard.get_mut(client_id) else {
+            return;
+        };
+        let owner = routes.get(&key).map(|route| route.client_id.clone());
+
+        if op == ProxyConfigOpCode::Delete {
+            if owner.as_deref() == Some(client_id) {
+                if let Some(route) = routes.remove(&key) {
+                    client.proxies.remove(&route.proxy_id);
+                }
+                log_info!(
+                    "{} {} released by client {}",
+                    kind.host_label(),
+                    host,
+                    client.label()
+                );
+            }
+            return;
+        }
+
+        let error = match (self.shared_port(kind), owner) {
+            (None, _) => Some(format!(
+                "{} routing is not enabled on this server",
+                kind.protocol()
+            )),
+            (_, Some(owner)) if owner != client_id => Some(format!(
+                "{} {} already registered by another client",
+                kind.host_label(),
+                host
+            )),
+            _ => None,
+        };
+        let response = match error {
+            Some(reason) => {
                 warn!(
-                    "Unexpected message from client {}: {:?}",
-                    client_id, message
+                    "Rejected {} service '{}' for {} from client {}: {}",
+                    kind.protocol(),
+                    proxy_info.name,
+                    host,
+                    format_uuid(client_id, "client"),
+                    reason
+                );
+                Message::ProxyConfigResponse {
+                    request_id,
+                    success: false,
+                    proxy_id: None,
+                    error: Some(reason),
+                    assigned_port: None,
+                }
+            }
+            None => {
+                let proxy_id = Uuid::new_v4().to_string();
+                let route = HostRoute {
+                    client_id: client_id.to_string(),
+                    proxy_id: proxy_id.clone(),
+                };
+                // re-registering replaces the client's previous proxy for this host
+                if let Some(previous) = routes.insert(key, route) {
+                    client.proxies.remove(&previous.proxy_id);
+                }
+                log_info!(
+                    "{} service '{}' for client {}: {} <- {}",
+                    kind.protocol(),
+                    proxy_info.name,
+                    client.label(),
+                    net::join_host_port(&proxy_info.local_ip, proxy_info.local_port),
+                    host
                 );
+                let shared_port = proxy_info.remote_port;
+                self.notify_proxy_registered(client, &proxy_info, Some(&host));
+                client.proxies.insert(proxy_id.clone(), proxy_info);
+                Message::ProxyConfigResponse {
+                    request_id,
+                    success: true,
+                    proxy_id: Some(proxy_id),
+                    error: None,
+                    assigned_port: Some(shared_port),
+                }
             }
+        };
+        let _ = client.sender.send(response);
+    }
+
+    /// Returns why services may not bind `host`, or None if it is the default bind host
+    /// or listed in `allowed_bind_hosts`
+    fn bind_host_violation(&self, host: &str) -> Option<String> {
+        let same = |allowed: &str| net::unbracket(allowed) == net::unbracket(host);
+        if same(&self.config.bind_host) || self.config.allowed_bind_hosts.iter().any(|h| same(h)) {
+            return None;
         }
+        Some(format!("Bind host {} not permitted by server policy", host))
+    }
 
-        Ok(())
+    /// Sends a failed `ProxyConfigResponse` to a client
+    async fn send_proxy_config_error(&self, client_id: &str, request_id: u64, reason: String) {
+        let clients_guard = self.clients.read().await;
+        if let Some(client) = clients_guard.get(client_id) {
+            let response = Message::ProxyConfigResponse {
+                request_id,
+                success: false,
+                proxy_id: None,
+                error: Some(reason),
+                assigned_port: None,
+            };
+            let _ = client.sender.send(response);
+        }
+    }
+
+    /// Returns why no client may claim `port` under the server's own policy
+    fn server_port_violation(&self, port: u16) -> Option<String> {
+        if self.config.listen_port() == Some(port) {
+            return Some(format!("Port {} is the server's control port", port));
+        }
+        for kind in [SharedPort::Http, SharedPort::Sni] {
+            if self.shared_port(kind) == Some(port) {
+                return Some(format!(
+                    "Port {} is the server's {} port",
+                    port,
+                    kind.protocol()
+                ));
+            }
+        }
+        match &self.config.allowed_ports {
+            Some(allowed_ports) if !allowed_ports.contains(port) => {
+                Some(format!("Port {} not permitted by server policy", port))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns why `client_id` may not claim `port`, or None if it may
+    async fn port_policy_violation(&self, client_id: &str, port: u16) -> Option<String> {
+        if let Some(reason) = self.server_port_violation(port) {
+            return Some(reason);
+        }
+
+        let clients_guard = self.clients.read().await;
+        let grant = &clients_guard.get(client_id)?.grant;
+
+        match &grant.allowed_ports {
+            Some(allowed_ports) if !allowed_ports.contains(port) => Some(format!(
+                "Port {} not permitted for token '{}' (allowed: {})",
+                port,
+                grant.name.as_deref().unwrap_or("unnamed"),
+                allowed_ports
+            )),
+            _ => None,
+        }
+    }
+
+    /// Forwards payload received from a client to the matching proxy connection
+    async fn forward_to_proxy_connection(&self, connection_id: &str, seq: u64, data: Vec<u8>) {
+        let len = data.len();
+        let (client_id, acknowledge, pause) = {
+            let proxy_connections_guard = self.proxy_connections.read().await;
+            let Some(proxy_conn) = proxy_connections_guard.get(connection_id) else {
+                log_warn!("Proxy connection {} not found", connection_id);
+                return;
+            };
+            let expected = proxy_conn.next_seq.fetch_add(1, Ordering::Relaxed);
+            if seq != expected {
+                drop(proxy_connections_guard);
+                self.close_out_of_sequence(connection_id, expected, seq)
+                    .await;
+                return;
+            }
+            if let Some(replay) = &proxy_conn.replay {
+                replay.record_received(len);
+            }
+            // the connection's handler closes it once the peer fell that far behind
+            let limit = self.config.max_buffered_per_connection.as_usize();
+            if !proxy_conn.backlog.queue(len, limit) {
+                return;
+            }
+            proxy_conn.stats.record_out(len);
+            if let Err(e) = proxy_conn.sender.send(WriteCommand::Data(data)) {
+                error!("Failed to forward data to proxy connection: {}", e);
+            }
+            // HTTP tunnels share one backlog and writer, which cannot resume them
+            let acknowledge = proxy_conn.acknowledge_on_receipt;
+            let pause = (!acknowledge && proxy_conn.backlog.wants_pause())
+                .then(|| proxy_conn.backlog.clone());
+            if !acknowledge && pause.is_none() {
+                return;
+            }
+            (proxy_conn.client_id.clone(), acknowledge, pause)
+        };
+
+        let clients_guard = self.clients.read().await;
+        if let Some(client) = clients_guard.get(&client_id) {
+            if acknowledge {
+                let _ = client
+                    .sender
+                    .send(Message::new_window_update(connection_id, len as u32));
+            }
+            let paused = pause.is_some_and(|backlog| {
+                backlog.pause_with(|| {
+                    let _ = client.sender.send(Message::new_pause(connection_id));
+                })
+            });
+            if paused {
+                log_debug!(
+                    "Paused connection {} of client {}, its peer is behind",
+                    connection_id,
+                    client_id
+                );
+            }
+        }
+    }
+
+    /// Closes a proxy connection whose data from the client skipped or repeated a `seq`,
+    /// a sign of messages delivered to the wrong connection
+    async fn close_out_of_sequence(&self, connection_id: &str, expected: u64, seq: u64) {
+        let Some(proxy_conn) = self.proxy_connections.write().await.remove(connection_id) else {
+            return;
+        };
+        proxy_conn.stats.record_out_of_sequence();
+        let _ = proxy_conn.close_code.set(CloseCode::LocalError);
+        error!(
+            "Data for connection {} of client {} out of sequence: expected seq {}, received {}; closing the connection",
+            connection_id,
+            format_uuid(&proxy_conn.client_id, "client"),
+            expected,
+            seq
+        );
+        let clients_guard = self.clients.read().await;
+        if let Some(client) = clients_guard.get(&proxy_conn.client_id) {
+            let _ = client.sender.send(Message::new_close_connection(
+                connection_id,
+                CloseCode::LocalError,
+            ));
+        }
     }
 
     /// Handles incoming connections to a proxy port and forwards them to the appropriate client
-    /// One proxy, one call this function
+    /// One listener, one call this function. Each connection goes to the proxy of the client's
+    /// current session, see `serving_proxy`
     async fn handle_proxy_connections(
         &self,
         listener: Arc<TcpListener>,
-        client_id: String,
-        proxy_id: String,
+        port: u16,
+        orphans: Arc<OrphanQueue>,
         mut cancel_rx: mpsc::UnboundedReceiver<()>,
     ) {
         loop {
             tokio::select! {
                 // Check for cancellation
                 _ = cancel_rx.recv() => {
-                    log_info!("Proxy listener for client {} cancelled", client_id);
+                    log_info!("Proxy listener on port {} cancelled", port);
                     break;
                 }
+                // the process an upgrade started accepts on it now
+                _ = self.handed_off.cancelled() => break,
                 // Accept new connections
                 result = listener.accept() => {
                     match result {
                         Ok((stream, addr)) => {
-                            debug!("New proxy connection from {} for client {}", addr, client_id);
-
-                            // Check if client still exists
-                            let client_exists = {
-                                let clients_guard = self.clients.read().await;
-                                clients_guard.contains_key(&client_id)
-                            };
-
-                            if !client_exists {
-                                log_info!("Client {} no longer exists, stopping proxy listener", format_uuid(&client_id, "client"));
-                                drop(stream);
-                                break; // Exit the loop instead of continuing
+                            if self.ip_bans.is_banned(addr.ip()) {
+                                log_debug!("Dropping proxy connection from banned {}", addr);
+                                continue;
                             }
-
-                            let connection_id = Uuid::new_v4().to_string();
-
-                            // Notify client about new connection
-                            {
-                                let clients_guard = self.clients.read().await;
-                                if let Some(client) = clients_guard.get(&client_id) {
-                                    let message = Message::NewConnection {
-                                        proxy_id: proxy_id.clone(),
-                                        connection_id: connection_id.clone(),
-                                    };
-                                    if let Err(e) = client.sender.send(message) {
-                                        error!("Failed to notify client about new connection: {}", e);
-                                        continue;
-                                    }
-                                } else {
-                                    warn!("Client {} not found for new connection", client_id);
-                                    continue;
+                            let Some(permit) = self.admit_connection(addr, "proxy") else {
+                                continue;
+                            };
+                            // the listener outlives a session that is replaced, it stops with
+                            // the last proxy it serves
+                            match self.serving_proxy(port).await {
+                                Some(serving) => {
+                                    self.dispatch_proxy_connection(stream, addr, serving, permit)
+                                        .await
                                 }
+                                None => self.handle_orphan_connection(
+                                    stream, addr, port, &orphans, permit,
+                                ),
                             }
-
-                            // Start forwarding data between the proxy connection and client
-                            let server_clone = self.clone();
-                            let client_id_clone = client_id.clone();
-                            let connection_id_clone = connection_id.clone();
-
-                            tokio::spawn(async move {
-                                server_clone.handle_proxy_stream(
-                                    stream,
-                                    client_id_clone,
-                                    connection_id_clone,
-                                ).await;
-                            });
+                        }
+                        // the listener lives on, closing connections frees descriptors
+                        Err(e) if net::is_fd_exhaustion(&e) => {
+                            log_warn!(
+                                "Out of file descriptors accepting proxy connections on port {}: {}",
+                                port,
+                                e
+                            );
+                            tokio::time::sleep(net::FD_EXHAUSTION_BACKOFF).await;
                         }
                         Err(e) => {
                             error!("Error accepting proxy connection: {}", e);
@@ -704,19 +2651,240 @@ impl Server {
                 }
             }
         }
+        orphans.close();
+    }
+
+    /// Tells the client of `serving` about a connection accepted on its listener and starts
+    /// forwarding it, unless a connection limit closes it first
+    async fn dispatch_proxy_connection(
+        &self,
+        stream: TcpStream,
+        addr: SocketAddr,
+        serving: ServingProxy,
+        permit: ConnectionPermit,
+    ) {
+        let ServingProxy {
+            client_id,
+            proxy_id,
+            max_connections,
+            stats,
+        } = serving;
+        // over the limit, close it before the client hears of it
+        if self
+            .connection_limiter
+            .as_ref()
+            .is_some_and(|limiter| !limiter.allow(addr.ip()))
+        {
+            stats.record_dropped();
+            return;
+        }
+        // the slot is given back however the connection ends
+        let Some(slot) = stats.open(max_connections) else {
+            log_debug!(
+                "Proxy {} at its {} connections, rejecting {}",
+                proxy_id,
+                max_connections.unwrap_or_default(),
+                addr
+            );
+            return;
+        };
+        debug!(
+            "New proxy connection from {} for client {}",
+            addr, client_id
+        );
+        self.config.socket_options().apply(&stream, "proxy");
+
+        let connection_id = Uuid::new_v4().to_string();
+
+        // Notify client about new connection
+        {
+            let clients_guard = self.clients.read().await;
+            if let Some(client) = clients_guard.get(&client_id) {
+                let dest_addr = stream
+                    .local_addr()
+                    .map(|local| local.to_string())
+                    .unwrap_or_default();
+                let message = Message::NewConnection {
+                    proxy_id: proxy_id.clone(),
+                    connection_id: connection_id.clone(),
+                    source_addr: addr.to_string(),
+                    dest_addr,
+                };
+                if let Err(e) = client.sender.send(message) {
+                    error!("Failed to notify client about new connection: {}", e);
+                    return;
+                }
+            } else {
+                warn!("Client {} not found for new connection", client_id);
+                return;
+            }
+        }
+
+        // Start forwarding data between the proxy connection and client
+        let server_clone = self.clone();
+        tokio::spawn(async move {
+            server_clone
+                .handle_proxy_stream(
+                    stream,
+                    client_id,
+                    proxy_id,
+                    connection_id,
+                    stats,
+                    Vec::new(),
+                )
+                .await;
+            drop(slot);
+            drop(permit);
+        });
     }
 
-    /// Handles bidirectional data forwarding for a single proxy connection
+    /// Handles bidirectional data forwarding for a single proxy connection, inside a span
+    /// carrying the connection's IDs so every event of the detailed logs can be correlated.
+    /// `preface` holds bytes already read from the stream, they are forwarded first.
+    /// The finished connection is recorded in the access log, if configured.
     async fn handle_proxy_stream(
         &self,
         stream: TcpStream,
         client_id: String,
+        proxy_id: String,
         connection_id: String,
+        stats: Arc<ProxyStats>,
+        preface: Vec<u8>,
+    ) {
+        let remote_port = stream
+            .local_addr()
+            .map(|addr| addr.port())
+            .unwrap_or_default();
+        let peer = stream
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        let span = tracing::info_span!(
+            "proxy_conn",
+            conn = short_id(&connection_id),
+            proxy = short_id(&proxy_id),
+            client = short_id(&client_id),
+            remote_port,
+            peer = %peer,
+        );
+        let started = Instant::now();
+        let traffic = Arc::new(ProxyStats::default());
+        let (reason, reported, code) = self
+            .forward_proxy_stream(
+                stream,
+                client_id.clone(),
+                connection_id.clone(),
+                stats.clone(),
+                traffic.clone(),
+                preface,
+            )
+            .instrument(span)
+            .await;
+
+        let entry = AccessEntry::new(
+            &peer,
+            remote_port,
+            &client_id,
+            &proxy_id,
+            traffic.snapshot(),
+            started.elapsed(),
+            reason,
+        )
+        .with_close_code(code);
+        self.record_closed_connection(&connection_id, &stats, &entry, reported);
+    }
+
+    /// Logs a finished proxy connection with what the peer's end moved, and the client's
+    /// if it reported it, counts its close code in the proxy's `stats` and records it in
+    /// the access log, if configured
+    fn record_closed_connection(
+        &self,
+        connection_id: &str,
+        stats: &ProxyStats,
+        entry: &AccessEntry,
+        reported: Option<TransferStats>,
     ) {
+        let transfer = TransferStats {
+            bytes_in: entry.bytes_in,
+            bytes_out: entry.bytes_out,
+            duration_ms: entry.duration_ms,
+        };
+        match reported {
+            Some(client) => {
+                log_info!(
+                    "Connection {} from {} closed, {}: peer {}, client {}",
+                    connection_id,
+                    entry.peer,
+                    entry.reason,
+                    transfer,
+                    client
+                );
+            }
+            None => {
+                log_info!(
+                    "Connection {} from {} closed, {}: peer {}",
+                    connection_id,
+                    entry.peer,
+                    entry.reason,
+                    transfer
+                );
+            }
+        }
+
+        stats.record_close(entry.close_code);
+
+        if let Some(access_log) = &self.access_log {
+            access_log.record(entry);
+        }
+    }
+
+    /// Forwards one proxy connection until it ends, counting its bytes in both the proxy's
+    /// `stats` and the connection's own `traffic`. Returns why it ended, what the client's
+    /// end moved if the client closed it, and the code of the `CloseConnection` that ended
+    /// it, whichever end sent it, or the code of the reason when neither did
+    async fn forward_proxy_stream(
+        &self,
+        stream: TcpStream,
+        client_id: String,
+        connection_id: String,
+        stats: Arc<ProxyStats>,
+        traffic: Arc<ProxyStats>,
+        preface: Vec<u8>,
+    ) -> (CloseReason, Option<TransferStats>, CloseCode) {
         let (mut stream_read, mut stream_write) = stream.into_split();
+        stats.record_connection();
+        let started = Instant::now();
+        let reported: Arc<OnceLock<TransferStats>> = Arc::default();
+        let close_code: Arc<OnceLock<CloseCode>> = Arc::default();
 
         // Channel for receiving data from client
-        let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let (tx, mut rx) = mpsc::unbounded_channel::<WriteCommand>();
+        let (response_tx, response_rx) = oneshot::channel();
+        let window = Arc::new(SendWindow::new());
+        let backlog = Arc::new(WriteBacklog::default());
+        let write_backlog = backlog.clone();
+        let compression_threshold = self.config.compression_threshold.as_usize();
+        // without a grace period the connection is closed with the session, nothing to resend
+        let capacity = match self.config.listener_grace_period.non_zero() {
+            Some(_) => REPLAY_CAPACITY,
+            None => 0,
+        };
+        let route = self
+            .clients
+            .read()
+            .await
+            .get(&client_id)
+            .map(|client| Route {
+                sender: client.sender.clone(),
+                compression: client.compression,
+            });
+        let replay = Arc::new(ReplayBuffer::new(
+            &connection_id,
+            capacity,
+            compression_threshold,
+            route,
+        ));
+        let write_replay = replay.clone();
 
         // Store proxy connection info
         {
@@ -726,78 +2894,241 @@ impl Server {
                 ProxyConnectionInfo {
                     sender: tx,
                     client_id: client_id.clone(),
+                    stats: stats.clone(),
+                    response: Some(response_tx),
+                    window: window.clone(),
+                    acknowledge_on_receipt: false,
+                    reported: reported.clone(),
+                    close_code: close_code.clone(),
+                    next_seq: AtomicU64::new(0),
+                    backlog: backlog.clone(),
+                    replay: Some(replay.clone()),
                 },
             );
         }
 
+        let (preface, peer_finished, channel) = match self
+            .await_connection_response(&mut stream_read, response_rx, preface)
+            .await
+        {
+            Ok(early) => early,
+            Err(reason) => {
+                debug!("Proxy connection {} dropped: {}", connection_id, reason);
+                let removed = self.proxy_connections.write().await.remove(&connection_id);
+                let code = *close_code.get_or_init(|| CloseReason::Error.code());
+                // unless it reported failure, the client may have connected or still connect
+                if removed.is_some() {
+                    let clients_guard = self.clients.read().await;
+                    if let Some(client) = clients_guard.get(&client_id) {
+                        let _ = client
+                            .sender
+                            .send(Message::new_close_connection(&connection_id, code));
+                    }
+                }
+                return (CloseReason::Error, None, code);
+            }
+        };
+        if let Some(channel) = channel {
+            self.proxy_connections.write().await.remove(&connection_id);
+            let Ok(stream) = stream_read.reunite(stream_write) else {
+                return (CloseReason::Error, None, CloseReason::Error.code());
+            };
+            let reason = self
+                .splice_proxy_stream(stream, channel, &connection_id, preface, stats, traffic)
+                .await;
+            return (reason, None, reason.code());
+        }
+
         let connection_id_clone = connection_id.clone();
-        let clients_clone = self.clients.clone();
+        let client_id_clone = client_id.clone();
+        let write_clients = self.clients.clone();
+        let write_client_id = client_id.clone();
+        let write_connection_id = connection_id.clone();
         let proxy_connections_clone = self.proxy_connections.clone();
+        let read_replay = replay.clone();
+        let activity = Activity::new();
+        let read_activity = activity.clone();
+        let write_activity = activity.clone();
+        let read_traffic = traffic.clone();
+        let traffic_clone = traffic.clone();
 
         // Task to read from proxy and send to client
-        let read_task = tokio::spawn(async move {
-            let mut buffer = [0u8; 4096];
-
-            loop {
-                match stream_read.read(&mut buffer).await {
-                    Ok(0) => {
-                        // Connection closed
-                        debug!("Proxy connection {} closed", connection_id);
-
-                        // Notify client about connection close
-                        let clients_guard = clients_clone.read().await;
-                        if let Some(client) = clients_guard.get(&client_id) {
-                            let message = Message::CloseConnection {
-                                connection_id: connection_id.clone(),
+        let mut read_task = tokio::spawn(
+            async move {
+                let mut buffer = [0u8; 4096];
+
+                if !preface.is_empty() {
+                    stats.record_in(preface.len());
+                    read_traffic.record_in(preface.len());
+                    window.spend(preface.len());
+                    read_replay.send(&preface);
+                }
+
+                loop {
+                    // pause reading until the client made room for more
+                    window.ready().await;
+                    let read = if peer_finished {
+                        Ok(0)
+                    } else {
+                        stream_read.read(&mut buffer).await
+                    };
+                    match read {
+                        Ok(0) => {
+                            // The peer finished sending, it may still read the response
+                            debug!("Proxy connection {} half-closed by peer", connection_id);
+
+                            return if read_replay.finish() {
+                                HalfEnd::Shutdown
+                            } else {
+                                HalfEnd::Failed
                             };
-                            let _ = client.sender.send(message);
                         }
-                        break;
-                    }
-                    Ok(n) => {
-                        // Forward data to client
-                        let data = buffer[..n].to_vec();
-                        debug!("Forwarding {} bytes from proxy to client {}", n, client_id);
+                        Ok(n) => {
+                            // Forward data to client
+                            stats.record_in(n);
+                            read_traffic.record_in(n);
+                            window.spend(n);
+                            read_activity.touch();
+                            debug!("Forwarding {} bytes from proxy to client {}", n, client_id);
 
-                        let clients_guard = clients_clone.read().await;
-                        if let Some(client) = clients_guard.get(&client_id) {
-                            let message = Message::Data {
-                                connection_id: connection_id.clone(),
-                                data,
-                            };
-                            if let Err(e) = client.sender.send(message) {
-                                error!("Failed to forward data to client: {}", e);
+                            // while the session is lost, the data waits for the next one
+                            if !read_replay.send(&buffer[..n]) {
+                                debug!(
+                                    "Proxy connection {} not resumed by its client",
+                                    connection_id
+                                );
                                 break;
                             }
-                        } else {
-                            warn!("Client {} not found for data forwarding", client_id);
+                        }
+                        Err(e) => {
+                            error!("Error reading from proxy stream: {}", e);
                             break;
                         }
                     }
-                    Err(e) => {
-                        error!("Error reading from proxy stream: {}", e);
-                        break;
-                    }
                 }
+                HalfEnd::Failed
             }
-        });
+            .in_current_span(),
+        );
 
         // Task to receive data from client and write to proxy
-        let write_task = tokio::spawn(async move {
-            while let Some(data) = rx.recv().await {
-                log_debug!("Writing {} bytes to proxy connection", data.len());
-                if let Err(e) = stream_write.write_all(&data).await {
-                    error!("Error writing to proxy stream: {}", e);
-                    break;
+        let mut write_task = tokio::spawn(
+            async move {
+                let mut acknowledger = Acknowledger::default();
+                while let Some(command) = rx.recv().await {
+                    match command {
+                        WriteCommand::Data(data) => {
+                            log_debug!("Writing {} bytes to proxy connection", data.len());
+                            if let Err(e) = stream_write.write_all(&data).await {
+                                error!("Error writing to proxy stream: {}", e);
+                                return HalfEnd::Failed;
+                            }
+                            write_backlog.written(data.len());
+                            traffic.record_out(data.len());
+                            write_activity.touch();
+                            let acknowledged = acknowledger.written(data.len());
+                            if let Some(bytes) = acknowledged {
+                                write_replay.record_acknowledged(bytes);
+                            }
+                            let resume = write_backlog.wants_resume();
+                            if acknowledged.is_some() || resume {
+                                let clients_guard = write_clients.read().await;
+                                if let Some(client) = clients_guard.get(&write_client_id) {
+                                    if let Some(bytes) = acknowledged {
+                                        let message =
+                                            Message::new_window_update(&write_connection_id, bytes);
+                                        let _ = client.sender.send(message);
+                                    }
+                                    if resume {
+                                        write_backlog.resume_with(|| {
+                                            let message = Message::new_resume(&write_connection_id);
+                                            let _ = client.sender.send(message);
+                                        });
+                                    }
+                                }
+                            }
+                        }
+                        WriteCommand::ShutdownWrite => {
+                            return match stream_write.shutdown().await {
+                                Ok(()) => HalfEnd::Shutdown,
+                                Err(_) => HalfEnd::Failed,
+                            };
+                        }
+                    }
                 }
+                HalfEnd::Closed
             }
-        });
+            .in_current_span(),
+        );
 
-        // Wait for either task to complete
-        tokio::select! {
-            _ = read_task => {},
-            _ = write_task => {},
-        }
+        // The connection lives until both directions are shut down, anything else ends it at once
+        let (mut reading, mut writing) = (true, true);
+        let mut end = HalfEnd::Shutdown;
+        // the side that finished first closed the connection
+        let mut reason = None;
+        while (reading || writing) && end == HalfEnd::Shutdown {
+            tokio::select! {
+                finished = &mut read_task, if reading => {
+                    reading = false;
+                    end = finished.unwrap_or(HalfEnd::Failed);
+                    reason.get_or_insert(CloseReason::PeerClosed);
+                }
+                finished = &mut write_task, if writing => {
+                    writing = false;
+                    end = finished.unwrap_or(HalfEnd::Failed);
+                    reason.get_or_insert(CloseReason::ClientClosed);
+                }
+                _ = activity.idle(self.config.idle_timeout.non_zero()) => {
+                    log_info!(
+                        "Proxy connection {} idle for {}, closing",
+                        connection_id_clone,
+                        self.config.idle_timeout
+                    );
+                    end = HalfEnd::Failed;
+                    reason = Some(CloseReason::IdleTimeout);
+                }
+                _ = backlog.overflowed() => {
+                    warn!(
+                        "Proxy connection {} closed: slow consumer, {} of the client's data unread by the peer",
+                        connection_id_clone,
+                        format_bytes(backlog.queued() as u64)
+                    );
+                    end = HalfEnd::Failed;
+                    reason = Some(CloseReason::SlowConsumer);
+                }
+                _ = replay.abandoned() => {
+                    end = HalfEnd::Failed;
+                    reason = Some(CloseReason::Error);
+                }
+            }
+        }
+        let reason = match (end, reason) {
+            (_, Some(CloseReason::IdleTimeout)) => CloseReason::IdleTimeout,
+            (_, Some(CloseReason::SlowConsumer)) => CloseReason::SlowConsumer,
+            (HalfEnd::Failed, _) => CloseReason::Error,
+            // the client closed the connection
+            (HalfEnd::Closed, _) => CloseReason::ClientClosed,
+            (HalfEnd::Shutdown, reason) => reason.unwrap_or(CloseReason::PeerClosed),
+        };
+        // dropping both halves closes the socket
+        read_task.abort();
+        write_task.abort();
+        if end == HalfEnd::Failed {
+            let snapshot = traffic_clone.snapshot();
+            let stats =
+                TransferStats::new(snapshot.bytes_in, snapshot.bytes_out, started.elapsed());
+            let code = *close_code.get_or_init(|| reason.code());
+            let clients_guard = self.clients.read().await;
+            if let Some(client) = clients_guard.get(&client_id_clone) {
+                let told = (reason == CloseReason::SlowConsumer).then(|| reason.to_string());
+                let _ = client.sender.send(Message::new_close_connection_with_stats(
+                    &connection_id_clone,
+                    stats,
+                    code,
+                    told,
+                ));
+            }
+        }
 
         // Clean up proxy connection
         {
@@ -806,6 +3137,641 @@ impl Server {
         }
 
         debug!("Proxy connection {} handler finished", connection_id_clone);
+        let code = close_code.get().copied().unwrap_or(reason.code());
+        (reason, reported.get().copied(), code)
+    }
+
+    /// Forwards a proxy connection a QUIC client carries on a stream of its own, `preface`
+    /// first, counting and ending like `forward_proxy_stream`
+    async fn splice_proxy_stream(
+        &self,
+        stream: TcpStream,
+        mut channel: BoxedStream,
+        connection_id: &str,
+        preface: Vec<u8>,
+        stats: Arc<ProxyStats>,
+        traffic: Arc<ProxyStats>,
+    ) -> CloseReason {
+        if !preface.is_empty() {
+            if let Err(e) = channel.write_all(&preface).await {
+                error!("Error writing to client stream: {}", e);
+                return CloseReason::Error;
+            }
+            stats.record_in(preface.len());
+            traffic.record_in(preface.len());
+        }
+
+        let idle_timeout = self.config.idle_timeout;
+        let end = splice(
+            stream,
+            channel,
+            idle_timeout.non_zero(),
+            |n| {
+                stats.record_in(n);
+                traffic.record_in(n);
+            },
+            |n| {
+                stats.record_out(n);
+                traffic.record_out(n);
+            },
+        )
+        .await;
+        debug!("Proxy connection {} handler finished", connection_id);
+        match end {
+            SpliceEnd::Finished { a_first: true } => CloseReason::PeerClosed,
+            SpliceEnd::Finished { a_first: false } => CloseReason::ClientClosed,
+            SpliceEnd::Failed => CloseReason::Error,
+            SpliceEnd::Idle => {
+                log_info!(
+                    "Proxy connection {} idle for {}, closing",
+                    connection_id,
+                    idle_timeout
+                );
+                CloseReason::IdleTimeout
+            }
+        }
+    }
+
+    /// Holds back what the peer sends until the client reports whether it reached the local
+    /// service. Returns the bytes held back, after `early`, whether the peer finished sending,
+    /// and the stream carrying the connection if the client opened one
+    async fn await_connection_response(
+        &self,
+        stream: &mut OwnedReadHalf,
+        mut response: oneshot::Receiver<ConnectionOutcome>,
+        mut early: Vec<u8>,
+    ) -> Result<(Vec<u8>, bool, Option<BoxedStream>), String> {
+        let connect_timeout = self.config.connect_timeout;
+        let deadline = async {
+            match connect_timeout.non_zero() {
+                Some(duration) => tokio::time::sleep(duration).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(deadline);
+
+        let mut buffer = [0u8; 4096];
+        let mut finished = false;
+        loop {
+            tokio::select! {
+                outcome = &mut response => {
+                    return match outcome {
+                        Ok(Ok(channel)) => Ok((early, finished, channel)),
+                        Ok(Err(reason)) => Err(reason),
+                        Err(_) => Err("client is gone".to_string()),
+                    };
+                }
+                _ = &mut deadline => {
+                    return Err(format!("no response from the client within {}", connect_timeout));
+                }
+                read = stream.read(&mut buffer), if !finished => match read {
+                    Ok(0) => finished = true,
+                    Ok(n) if early.len() + n > MAX_EARLY_DATA => {
+                        return Err(format!(
+                            "peer sent more than {} before the local service was reached",
+                            format_bytes(MAX_EARLY_DATA as u64)
+                        ));
+                    }
+                    Ok(n) => early.extend_from_slice(&buffer[..n]),
+                    Err(e) => return Err(e.to_string()),
+                }
+            }
+        }
+    }
+
+    /// Accepts connections on a shared port
+    async fn serve_shared_port(&self, kind: SharedPort, listener: Arc<TcpListener>) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    if self.ip_bans.is_banned(addr.ip()) {
+                        log_debug!(
+                            "Dropping {} connection from banned {}",
+                            kind.protocol(),
+                            addr
+                        );
+                        continue;
+                    }
+                    let Some(permit) = self.admit_connection(addr, kind.protocol()) else {
+                        continue;
+                    };
+                    self.config.socket_options().apply(&stream, "proxy");
+                    let server = self.clone();
+                    tokio::spawn(async move {
+                        match kind {
+                            SharedPort::Http => server.handle_http_connection(stream, addr).await,
+                            SharedPort::Sni => server.handle_sni_connection(stream, addr).await,
+                        }
+                        drop(permit);
+                    });
+                }
+                Err(e) if net::is_fd_exhaustion(&e) => {
+                    log_warn!(
+                        "Out of file descriptors accepting {} connections: {}",
+                        kind.protocol(),
+                        e
+                    );
+                    tokio::time::sleep(net::FD_EXHAUSTION_BACKOFF).await;
+                }
+                Err(e) => {
+                    error!("Failed to accept {} connection: {}", kind.protocol(), e);
+                }
+            }
+        }
+    }
+
+    /// Routes each request on an HTTP port connection to the client serving its Host.
+    /// Consecutive requests for the same host share one proxy connection, a request for
+    /// another host closes it and opens a new one. Unknown hosts get a 404 from the server.
+    async fn handle_http_connection(&self, stream: TcpStream, addr: SocketAddr) {
+        let local_addr = stream.local_addr().ok();
+        let dest_addr = local_addr
+            .map(|local| local.to_string())
+            .unwrap_or_default();
+        let remote_port = local_addr.map(|local| local.port()).unwrap_or_default();
+        let (mut stream_read, mut stream_write) = stream.into_split();
+
+        // responses from every tunnel, and the server's own errors, go through one writer
+        let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let backlog = Arc::new(WriteBacklog::default());
+        let write_backlog = backlog.clone();
+        let write_task = tokio::spawn(async move {
+            while let Some(data) = rx.recv().await {
+                if let Err(e) = stream_write.write_all(&data).await {
+                    debug!("Error writing to HTTP connection: {}", e);
+                    break;
+                }
+                write_backlog.written(data.len());
+            }
+            let _ = stream_write.shutdown().await;
+        });
+
+        let mut tracker = RequestTracker::default();
+        let mut tunnel: Option<HttpTunnel> = None;
+        let mut close_reason = None;
+        let mut close_code = CloseCode::PeerClosed;
+        let mut buffer = [0u8; 4096];
+
+        'connection: loop {
+            // connections without a tunnel must send a request head in time, those with one
+            // are closed after `idle_timeout` without requests or responses
+            let limit = match tunnel {
+                None => Some(SHARED_PORT_TIMEOUT),
+                Some(_) => self.config.idle_timeout.non_zero(),
+            };
+            let read = async {
+                let read = stream_read.read(&mut buffer);
+                match limit {
+                    Some(limit) => timeout(limit, read).await.ok(),
+                    None => Some(read.await),
+                }
+            };
+            let n = tokio::select! {
+                n = read => match n {
+                    Some(n) => n,
+                    None => {
+                        if tunnel.is_some() {
+                            log_info!(
+                                "HTTP connection {} idle for {}, closing",
+                                addr,
+                                self.config.idle_timeout
+                            );
+                            close_code = CloseCode::IdleTimeout;
+                        }
+                        break;
+                    }
+                },
+                command = next_response(&mut tunnel) => {
+                    match command {
+                        Some(WriteCommand::Data(data)) => {
+                            if let Some(current) = &tunnel {
+                                current.traffic.record_out(data.len());
+                            }
+                            let _ = tx.send(data);
+                        }
+                        // a backend finishing its response does not end the keep-alive connection
+                        Some(WriteCommand::ShutdownWrite) => {}
+                        // the client closed the tunnel, the next request opens another
+                        None => {
+                            if let Some(closed) = tunnel.take() {
+                                self.record_client_closed_http_tunnel(&closed);
+                            }
+                        }
+                    }
+                    continue;
+                }
+                _ = backlog.overflowed() => {
+                    warn!(
+                        "HTTP connection {} closed: slow consumer, {} of responses unread",
+                        addr,
+                        format_bytes(backlog.queued() as u64)
+                    );
+                    close_reason = Some(CloseReason::SlowConsumer.to_string());
+                    close_code = CloseCode::PolicyLimit;
+                    break;
+                }
+            };
+            let n = match n {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) => {
+                    debug!("Error reading from HTTP connection {}: {}", addr, e);
+                    break;
+                }
+            };
+
+            let events = match tracker.feed(&buffer[..n]) {
+                Ok(events) => events,
+                Err(reason) => {
+                    log_debug!("Bad HTTP request from {}: {}", addr, reason);
+                    let _ = tx.send(http::error_response(400, "Bad Request", &reason));
+                    break;
+                }
+            };
+
+            for event in events {
+                let data = match event {
+                    HttpEvent::Head { host, bytes } => {
+                        let Some(host) = host else {
+                            let _ = tx.send(http::error_response(
+                                400,
+                                "Bad Request",
+                                "Missing Host header",
+                            ));
+                            break 'connection;
+                        };
+                        // the client may have closed the tunnel after its last response
+                        let reusable = match &tunnel {
+                            Some(current) if current.host == host => self
+                                .proxy_connections
+                                .read()
+                                .await
+                                .contains_key(&current.connection_id),
+                            _ => false,
+                        };
+                        if !reusable {
+                            if let Some(previous) = tunnel.take() {
+                                self.close_http_tunnel(previous, CloseCode::PeerClosed, None)
+                                    .await;
+                            }
+                            tunnel = self
+                                .open_http_tunnel(
+                                    &host,
+                                    addr,
+                                    &dest_addr,
+                                    remote_port,
+                                    backlog.clone(),
+                                )
+                                .await;
+                            if tunnel.is_none() {
+                                log_debug!("No HTTP service for host {} from {}", host, addr);
+                                let body = format!("No service is registered for host {}\n", host);
+                                let _ = tx.send(http::error_response(404, "Not Found", &body));
+                                break 'connection;
+                            }
+                        }
+                        bytes
+                    }
+                    HttpEvent::Body(bytes) => bytes,
+                };
+
+                if let Some(current) = &mut tunnel {
+                    if !self.send_to_http_tunnel(current, data).await {
+                        break 'connection;
+                    }
+                }
+            }
+        }
+
+        if let Some(current) = tunnel.take() {
+            self.close_http_tunnel(current, close_code, close_reason)
+                .await;
+        }
+        // the writer stops once the last sender is gone, after flushing any error response
+        drop(tx);
+        let _ = write_task.await;
+    }
+
+    /// Registers a proxy connection for `host` and notifies the client serving it,
+    /// or returns None if no client serves the host. `remote_port` is the port of `dest_addr`
+    async fn open_http_tunnel(
+        &self,
+        host: &str,
+        source_addr: SocketAddr,
+        dest_addr: &str,
+        remote_port: u16,
+        backlog: Arc<WriteBacklog>,
+    ) -> Option<HttpTunnel> {
+        let route = self
+            .shared_routes
+            .read()
+            .await
+            .get(&(SharedPort::Http, host.to_string()))
+            .cloned()?;
+        let connection_id = Uuid::new_v4().to_string();
+        let stats = self.proxy_stats(&route).await;
+        let window = Arc::new(SendWindow::new());
+        // shared ports are not limited, the slot only counts the tunnel as active
+        let slot = stats.open(None)?;
+        let (sender, responses) = mpsc::unbounded_channel();
+        let reported = Arc::new(OnceLock::new());
+        let close_code = Arc::new(OnceLock::new());
+
+        self.proxy_connections.write().await.insert(
+            connection_id.clone(),
+            ProxyConnectionInfo {
+                sender,
+                client_id: route.client_id.clone(),
+                stats: stats.clone(),
+                response: None,
+                window: window.clone(),
+                acknowledge_on_receipt: true,
+                reported: reported.clone(),
+                close_code: close_code.clone(),
+                next_seq: AtomicU64::new(0),
+                backlog,
+                replay: None,
+            },
+        );
+
+        let notified = {
+            let clients_guard = self.clients.read().await;
+            clients_guard.get(&route.client_id).is_some_and(|client| {
+                let message = Message::NewConnection {
+                    proxy_id: route.proxy_id.clone(),
+                    connection_id: connection_id.clone(),
+                    source_addr: source_addr.to_string(),
+                    dest_addr: dest_addr.to_string(),
+                };
+                client.sender.send(message).is_ok()
+            })
+        };
+        if !notified {
+            self.proxy_connections.write().await.remove(&connection_id);
+            return None;
+        }
+
+        debug!(
+            "HTTP request for {} from {} routed to client {}",
+            host, source_addr, route.client_id
+        );
+        stats.record_connection();
+        Some(HttpTunnel {
+            host: host.to_string(),
+            client_id: route.client_id,
+            proxy_id: route.proxy_id,
+            connection_id,
+            peer: source_addr.to_string(),
+            remote_port,
+            stats,
+            traffic: ProxyStats::default(),
+            window,
+            next_seq: 0,
+            responses,
+            reported,
+            close_code,
+            started: Instant::now(),
+            _slot: slot,
+        })
+    }
+
+    /// Counters of the proxy behind a shared port route
+    async fn proxy_stats(&self, route: &HostRoute) -> Arc<ProxyStats> {
+        let clients_guard = self.clients.read().await;
+        clients_guard
+            .get(&route.client_id)
+            .and_then(|client| client.proxies.get(&route.proxy_id))
+            .map(|proxy| proxy.stats.clone())
+            .unwrap_or_default()
+    }
+
+    /// Forwards request bytes to the client behind `tunnel`, false if it is gone
+    /// or could not reach its local service
+    async fn send_to_http_tunnel(&self, tunnel: &mut HttpTunnel, data: Vec<u8>) -> bool {
+        let tunnel_open = || async {
+            self.proxy_connections
+                .read()
+                .await
+                .contains_key(&tunnel.connection_id)
+        };
+        if !tunnel_open().await {
+            return false;
+        }
+        // the window stays shut if the client goes away meanwhile
+        while timeout(Duration::from_secs(1), tunnel.window.ready())
+            .await
+            .is_err()
+        {
+            if !tunnel_open().await {
+                return false;
+            }
+        }
+        tunnel.window.spend(data.len());
+        tunnel.stats.record_in(data.len());
+        tunnel.traffic.record_in(data.len());
+        let seq = tunnel.next_seq;
+        tunnel.next_seq += 1;
+        let clients_guard = self.clients.read().await;
+        clients_guard.get(&tunnel.client_id).is_some_and(|client| {
+            let message = Message::new_payload(
+                &tunnel.connection_id,
+                seq,
+                data,
+                client.compression,
+                self.config.compression_threshold.as_usize(),
+            );
+            client.sender.send(message).is_ok()
+        })
+    }
+
+    /// Tells the client the proxy connection ended for `code`, and why in words if given,
+    /// and forgets it
+    async fn close_http_tunnel(&self, tunnel: HttpTunnel, code: CloseCode, reason: Option<String>) {
+        let removed = self
+            .proxy_connections
+            .write()
+            .await
+            .remove(&tunnel.connection_id);
+        // the client closed it first
+        if removed.is_none() {
+            self.record_client_closed_http_tunnel(&tunnel);
+            return;
+        }
+        {
+            let clients_guard = self.clients.read().await;
+            if let Some(client) = clients_guard.get(&tunnel.client_id) {
+                let snapshot = tunnel.traffic.snapshot();
+                let stats = TransferStats::new(
+                    snapshot.bytes_in,
+                    snapshot.bytes_out,
+                    tunnel.started.elapsed(),
+                );
+                let _ = client.sender.send(Message::new_close_connection_with_stats(
+                    &tunnel.connection_id,
+                    stats,
+                    code,
+                    reason,
+                ));
+            }
+        }
+        let reason = match code {
+            CloseCode::IdleTimeout => CloseReason::IdleTimeout,
+            CloseCode::PolicyLimit => CloseReason::SlowConsumer,
+            _ => CloseReason::PeerClosed,
+        };
+        self.record_closed_http_tunnel(&tunnel, reason, code);
+    }
+
+    /// Records `tunnel` closed by the client, with the code its `CloseConnection` carried
+    fn record_client_closed_http_tunnel(&self, tunnel: &HttpTunnel) {
+        let reason = CloseReason::ClientClosed;
+        let code = tunnel.close_code.get().copied().unwrap_or(reason.code());
+        self.record_closed_http_tunnel(tunnel, reason, code);
+    }
+
+    /// Records a finished `tunnel` as `record_closed_connection` does for other proxy
+    /// connections
+    fn record_closed_http_tunnel(&self, tunnel: &HttpTunnel, reason: CloseReason, code: CloseCode) {
+        let entry = AccessEntry::new(
+            &tunnel.peer,
+            tunnel.remote_port,
+            &tunnel.client_id,
+            &tunnel.proxy_id,
+            tunnel.traffic.snapshot(),
+            tunnel.started.elapsed(),
+            reason,
+        )
+        .with_close_code(code);
+        self.record_closed_connection(
+            &tunnel.connection_id,
+            &tunnel.stats,
+            &entry,
+            tunnel.reported.get().copied(),
+        );
+    }
+
+    /// Routes a TLS connection on the SNI port by the server name in its ClientHello,
+    /// without terminating TLS. Hellos without SNI or for unknown names are dropped.
+    async fn handle_sni_connection(&self, mut stream: TcpStream, addr: SocketAddr) {
+        let mut hello = Vec::new();
+        let mut buffer = [0u8; 4096];
+        let server_name = loop {
+            let n = match timeout(SHARED_PORT_TIMEOUT, stream.read(&mut buffer)).await {
+                Ok(Ok(n)) if n > 0 => n,
+                _ => {
+                    log_debug!("TLS connection from {} ended before its ClientHello", addr);
+                    return;
+                }
+            };
+            hello.extend_from_slice(&buffer[..n]);
+            match sni::parse_client_hello(&hello) {
+                Ok(ClientHello::Incomplete) if hello.len() <= sni::MAX_HELLO_LEN => {}
+                Ok(ClientHello::Incomplete) => {
+                    log_info!(
+                        "Dropped TLS connection from {}: ClientHello too large",
+                        addr
+                    );
+                    return;
+                }
+                Ok(ClientHello::Complete(server_name)) => break server_name,
+                Err(e) => {
+                    log_info!("Dropped TLS connection from {}: {}", addr, e);
+                    return;
+                }
+            }
+        };
+
+        let Some(server_name) = server_name else {
+            log_info!("Dropped TLS connection from {}: no SNI name", addr);
+            return;
+        };
+        let route = self
+            .shared_routes
+            .read()
+            .await
+            .get(&(SharedPort::Sni, server_name.clone()))
+            .cloned();
+        let Some(route) = route else {
+            log_info!(
+                "Dropped TLS connection from {}: no service for SNI name {}",
+                addr,
+                server_name
+            );
+            return;
+        };
+
+        let connection_id = Uuid::new_v4().to_string();
+        let notified = {
+            let clients_guard = self.clients.read().await;
+            clients_guard.get(&route.client_id).is_some_and(|client| {
+                let message = Message::NewConnection {
+                    proxy_id: route.proxy_id.clone(),
+                    connection_id: connection_id.clone(),
+                    source_addr: addr.to_string(),
+                    dest_addr: stream
+                        .local_addr()
+                        .map(|local| local.to_string())
+                        .unwrap_or_default(),
+                };
+                client.sender.send(message).is_ok()
+            })
+        };
+        if !notified {
+            warn!("Client {} not found for new connection", route.client_id);
+            return;
+        }
+
+        debug!(
+            "TLS connection for {} from {} routed to client {}",
+            server_name, addr, route.client_id
+        );
+        // the ClientHello was consumed while routing, the client still needs it
+        let stats = self.proxy_stats(&route).await;
+        self.handle_proxy_stream(
+            stream,
+            route.client_id,
+            route.proxy_id,
+            connection_id,
+            stats,
+            hello,
+        )
+        .await;
+    }
+}
+
+/// Warns once per interval about each IP that had connections dropped by the rate limiter
+async fn sweep_rate_limiter(limiter: Arc<ConnectionRateLimiter>) {
+    let mut interval = tokio::time::interval(RATE_LIMIT_SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        for (ip, dropped) in limiter.sweep() {
+            warn!(
+                "Dropped {} proxy connections from {} over the per-IP rate limit",
+                dropped, ip
+            );
+        }
+    }
+}
+
+/// Reopens the access log on SIGHUP or SIGUSR1, which logrotate sends after moving it away
+#[cfg(unix)]
+async fn reopen_on_signal(access_log: AccessLog) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let (Ok(mut hangup), Ok(mut user1)) = (
+        signal(SignalKind::hangup()),
+        signal(SignalKind::user_defined1()),
+    ) else {
+        log_warn!("Cannot listen for signals, the access log will not be reopened");
+        return;
+    };
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {}
+            _ = user1.recv() => {}
+        }
+        log_info!("Reopening access log");
+        access_log.reopen();
     }
 }
 
@@ -816,6 +3782,2966 @@ impl Clone for Server {
             clients: self.clients.clone(),
             proxy_listeners: self.proxy_listeners.clone(),
             proxy_connections: self.proxy_connections.clone(),
+            tls_acceptor: self.tls_acceptor.clone(),
+            auth_nonces: self.auth_nonces.clone(),
+            auth_bans: self.auth_bans.clone(),
+            ip_bans: self.ip_bans.clone(),
+            shared_routes: self.shared_routes.clone(),
+            secret_services: self.secret_services.clone(),
+            relay_services: self.relay_services.clone(),
+            visitor_links: self.visitor_links.clone(),
+            access_log: self.access_log.clone(),
+            connection_limiter: self.connection_limiter.clone(),
+            webhooks: self.webhooks.clone(),
+            connection_limit: self.connection_limit.clone(),
+            sweeps: self.sweeps.clone(),
+            upgrade_socket: self.upgrade_socket.clone(),
+            pid_file: self.pid_file.clone(),
+            handed_off: self.handed_off.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HumanBytes;
+    use crate::utils::crypto::auth_proof;
+    use crate::utils::protocol::MAX_DATA_PAYLOAD;
+
+    const CLIENT_ID: &str = "7046c8b3-b9ef-4fe9-abcf-68e5b1b79eb7";
+
+    fn test_server() -> Server {
+        let config: ServerConfig = toml::from_str::<crate::config::Config>(
+            r#"
+            [server]
+            listen_addr = "127.0.0.1:7000"
+            bind_host = "127.0.0.1"
+            token = "legacy"
+            max_clients = 10
+
+            [[server.tokens]]
+            token = "alice-secret"
+            name = "alice"
+            allowed_ports = "8000-8100"
+            "#,
+        )
+        .unwrap()
+        .server
+        .unwrap();
+        Server::new(config).unwrap()
+    }
+
+    #[test]
+    fn test_check_credentials_with_named_tokens() {
+        let server = test_server();
+        let nonce = server.auth_nonces.issue();
+        let check = |token: &str| {
+            server.check_credentials(
+                &auth_proof(token, &nonce, CLIENT_ID),
+                &nonce,
+                CLIENT_ID,
+                None,
+            )
+        };
+
+        let legacy = check("legacy").unwrap();
+        assert!(legacy.unwrap().allowed_ports.is_none());
+
+        let alice = check("alice-secret").unwrap().unwrap();
+        assert_eq!(alice.name.as_deref(), Some("alice"));
+        let allowed = alice.allowed_ports.unwrap();
+        assert!(allowed.contains(8050) && !allowed.contains(22));
+
+        assert_eq!(check("wrong").unwrap_err(), "Invalid token");
+        assert_eq!(
+            server
+                .check_credentials(&[], &nonce, CLIENT_ID, None)
+                .unwrap_err(),
+            "Token required"
+        );
+    }
+
+    #[test]
+    fn test_auth_proof_is_bound_to_challenge() {
+        let server = test_server();
+        let nonce = server.auth_nonces.issue();
+        let proof = auth_proof("legacy", &nonce, CLIENT_ID);
+
+        // a proof for one nonce or client does not verify against another
+        let other_nonce = server.auth_nonces.issue();
+        assert_eq!(
+            server
+                .check_credentials(&proof, &other_nonce, CLIENT_ID, None)
+                .unwrap_err(),
+            "Invalid token"
+        );
+        assert!(server
+            .check_credentials(&proof, &nonce, "client-2", None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_auth_nonce_replay_is_rejected() {
+        let server = test_server();
+        let nonce = server.auth_nonces.issue();
+
+        assert!(server.auth_nonces.consume(&nonce).is_ok());
+        assert_eq!(
+            server.auth_nonces.consume(&nonce).unwrap_err(),
+            "Unknown or reused authentication nonce"
+        );
+        assert!(server.auth_nonces.consume(b"never issued").is_err());
+    }
+
+    #[test]
+    fn test_other_protocol_versions_require_upgrade() {
+        let server = test_server();
+        assert!(server.check_auth_version(PROTOCOL_VERSION).is_ok());
+        for version in [PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+            let err = server.check_auth_version(version).unwrap_err();
+            assert!(err.contains("upgrade required"));
+        }
+    }
+
+    #[test]
+    fn test_old_client_versions_are_rejected() {
+        let mut server = test_server();
+        let meta = |version: &str| ClientMeta {
+            version: version.to_string(),
+            ..ClientMeta::current()
+        };
+        assert!(server.check_client_version(None).is_ok());
+        assert!(server.check_client_version(Some(&meta("0.0.1"))).is_ok());
+
+        server.config.min_client_version = Some("0.2.0".parse().unwrap());
+        for accepted in ["0.2.0", "0.10.3", "1.0.0-rc.1"] {
+            assert!(
+                server.check_client_version(Some(&meta(accepted))).is_ok(),
+                "{}",
+                accepted
+            );
+        }
+        let err = server
+            .check_client_version(Some(&meta("0.1.9")))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            "Client version 0.1.9 is older than 0.2.0, the oldest this server accepts, upgrade required"
+        );
+        // clients that do not tell cannot prove they are new enough
+        for unknown in [None, Some(meta("unknown"))] {
+            let err = server.check_client_version(unknown.as_ref()).unwrap_err();
+            assert!(err.contains("upgrade required"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_malformed_client_ids_are_rejected() {
+        assert!(check_client_id(CLIENT_ID).is_ok());
+        for malformed in [
+            "",
+            "client-1",
+            "7046c8b3",
+            "7046c8b3-b9ef-4fe9-abcf-68e5b1b79eb7x",
+        ] {
+            assert_eq!(
+                check_client_id(malformed).unwrap_err(),
+                "Malformed client ID, expected a UUID"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_ids_assigned_by_the_server() {
+        let mut config = test_server().config;
+        config.assign_client_ids = true;
+        let server = Server::new(config).unwrap();
+        let addr = spawn_control_listener(&server).await;
+
+        let (_first, response) = authenticate(addr, CLIENT_ID).await;
+        let Message::AuthResponse {
+            success: true,
+            client_id: Some(assigned),
+            ..
+        } = response
+        else {
+            panic!("expected an assigned client ID, got {:?}", response);
+        };
+        assert_ne!(assigned, CLIENT_ID);
+        assert!(check_client_id(&assigned).is_ok());
+
+        // the same ID sent again is a separate session, not a duplicate
+        let (_second, response) = authenticate(addr, CLIENT_ID).await;
+        assert!(matches!(
+            response,
+            Message::AuthResponse { success: true, client_id: Some(ref id), .. } if *id != assigned
+        ));
+        let clients_guard = server.clients.read().await;
+        assert!(clients_guard.contains_key(&assigned));
+        assert!(!clients_guard.contains_key(CLIENT_ID));
+        drop(clients_guard);
+
+        // a malformed ID is refused even though the server would replace it
+        let (_, response) = authenticate(addr, "").await;
+        assert!(matches!(
+            response,
+            Message::AuthResponse { success: false, error: Some(ref error), .. }
+                if error.starts_with("Malformed client ID")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_auto_assigned_port_prefers_previous_port() {
+        let server = test_server();
+        let _rx = connect_fake_client(&server, CLIENT_ID).await;
+
+        let (first_id, first) = server
+            .add_auto_proxy("127.0.0.1", None, CLIENT_ID, "web")
+            .await
+            .unwrap();
+        assert_ne!(first, 0);
+        assert!(server.proxy_listeners.read().await.contains_key(&first));
+        let proxy = ProxyInfo {
+            name: "web".to_string(),
+            local_ip: "127.0.0.1".to_string(),
+            local_port: 3000,
+            remote_port: first,
+            max_connections: None,
+            stats: Arc::default(),
+            expires_at: None,
+            local_error: None,
+        };
+        let mut clients = server.clients.write().await;
+        let client = clients.get_mut(CLIENT_ID).unwrap();
+        client.proxies.insert(first_id.clone(), proxy);
+        drop(clients);
+
+        // the previous port is taken by another service, so a fresh one is allocated
+        let (_, second) = server
+            .add_auto_proxy("127.0.0.1", Some(first), CLIENT_ID, "api")
+            .await
+            .unwrap();
+        assert_ne!(second, first);
+
+        // once released, the previous port is handed out again
+        let mut listeners = server.proxy_listeners.write().await;
+        server.leave_listener(first, &first_id, &mut listeners);
+        drop(listeners);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let (_, third) = server
+            .add_auto_proxy("127.0.0.1", Some(first), CLIENT_ID, "web")
+            .await
+            .unwrap();
+        assert_eq!(third, first);
+    }
+
+    /// Registers service `name` of `client_id` on `port`, returning the server's answer
+    async fn register_port(
+        server: &Server,
+        rx: &mut mpsc::UnboundedReceiver<Message>,
+        client_id: &str,
+        name: &str,
+        port: u16,
+    ) -> Result<String, String> {
+        register_in_group(server, rx, client_id, name, port, None).await
+    }
+
+    /// Registers service `name` of `client_id` on `port` as a member of `group`
+    async fn register_in_group(
+        server: &Server,
+        rx: &mut mpsc::UnboundedReceiver<Message>,
+        client_id: &str,
+        name: &str,
+        port: u16,
+        group: Option<&str>,
+    ) -> Result<String, String> {
+        let message = Message::ProxyConfig {
+            request_id: 3,
+            op: ProxyConfigOpCode::Update,
+            name: name.to_string(),
+            local_ip: "127.0.0.1".to_string(),
+            local_port: 3000,
+            remote_port: port,
+            preferred_port: None,
+            bind_host: None,
+            http_host: None,
+            sni: None,
+            group: group.map(str::to_string),
+            max_connections: None,
+            secret_hash: None,
+            relay_peers: None,
+            ttl_ms: None,
+        };
+        server
+            .handle_client_message(message, client_id, "127.0.0.1")
+            .await
+            .unwrap();
+        match rx.recv().await.unwrap() {
+            Message::ProxyConfigResponse {
+                success: true,
+                proxy_id: Some(proxy_id),
+                assigned_port,
+                ..
+            } => {
+                assert_eq!(assigned_port, Some(port));
+                Ok(proxy_id)
+            }
+            Message::ProxyConfigResponse {
+                success: false,
+                error: Some(error),
+                ..
+            } => Err(error),
+            other => panic!("expected ProxyConfigResponse, got {:?}", other),
+        }
+    }
+
+    /// A port nothing listens on
+    async fn free_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    /// Connects to `port` and returns the proxy ID of the `NewConnection` it causes
+    async fn announced_proxy(rx: &mut mpsc::UnboundedReceiver<Message>, port: u16) -> String {
+        let _peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        match timeout(Duration::from_secs(1), rx.recv()).await.unwrap() {
+            Some(Message::NewConnection { proxy_id, .. }) => proxy_id,
+            other => panic!("expected NewConnection, got {:?}", other),
         }
     }
+
+    #[tokio::test]
+    async fn test_reregistration_after_reconnect_reuses_the_listener() {
+        let server = test_server();
+        let port = free_port().await;
+        let mut old_rx = connect_fake_client(&server, CLIENT_ID).await;
+        let old_id = register_port(&server, &mut old_rx, CLIENT_ID, "web", port)
+            .await
+            .unwrap();
+
+        // the client reconnects before the old session's resources are released
+        let old_session = server.clients.write().await.remove(CLIENT_ID).unwrap();
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        let new_id = register_port(&server, &mut rx, CLIENT_ID, "web", port)
+            .await
+            .unwrap();
+        assert_ne!(new_id, old_id);
+
+        // releasing the old session leaves the listener to the new one
+        server
+            .release_client_resources(old_session, Duration::ZERO, Duration::ZERO)
+            .await;
+        assert_eq!(announced_proxy(&mut rx, port).await, new_id);
+
+        // registering again answers the same way, the listener goes with the last proxy
+        let again = register_port(&server, &mut rx, CLIENT_ID, "web", port).await;
+        assert_eq!(again.unwrap(), new_id);
+        server.cleanup_client(CLIENT_ID).await;
+        assert!(!server.proxy_listeners.read().await.contains_key(&port));
+    }
+
+    /// Ends the current session of `client_id` as if its control connection dropped
+    async fn disconnect(server: &Server, client_id: &str) {
+        let session_id = server.clients.read().await[client_id].session_id.clone();
+        server.cleanup_session(client_id, &session_id).await;
+    }
+
+    #[tokio::test]
+    async fn test_listener_is_reclaimed_within_the_grace_period() {
+        let mut config = test_server().config;
+        config.listener_grace_period = "5s".parse().unwrap();
+        let server = Server::new(config).unwrap();
+        let port = free_port().await;
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        register_port(&server, &mut rx, CLIENT_ID, "web", port)
+            .await
+            .unwrap();
+
+        // while the client is away the port stays bound, but refuses connections
+        disconnect(&server, CLIENT_ID).await;
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let read = timeout(Duration::from_secs(1), peer.read(&mut [0u8; 1])).await;
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+
+        // another client cannot take it meanwhile
+        let mut other_rx = connect_fake_client(&server, "client-2").await;
+        let other = register_port(&server, &mut other_rx, "client-2", "web", port).await;
+        assert!(other.is_err());
+
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        let proxy_id = register_port(&server, &mut rx, CLIENT_ID, "web", port)
+            .await
+            .unwrap();
+        assert_eq!(announced_proxy(&mut rx, port).await, proxy_id);
+        assert!(server.proxy_listeners.read().await[&port]
+            .orphaned
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_orphaned_listener_expires() {
+        let mut config = test_server().config;
+        config.listener_grace_period = "100ms".parse().unwrap();
+        let server = Server::new(config).unwrap();
+        let port = free_port().await;
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        register_port(&server, &mut rx, CLIENT_ID, "web", port)
+            .await
+            .unwrap();
+
+        disconnect(&server, CLIENT_ID).await;
+        assert!(server.proxy_listeners.read().await.contains_key(&port));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!server.proxy_listeners.read().await.contains_key(&port));
+
+        // the port is free for anyone once more
+        let mut other_rx = connect_fake_client(&server, "client-2").await;
+        let other = register_port(&server, &mut other_rx, "client-2", "web", port).await;
+        assert!(other.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_detached_connections_are_resumed_or_expire() {
+        let mut config = test_server().config;
+        config.listener_grace_period = "300ms".parse().unwrap();
+        let server = Server::new(config).unwrap();
+        let port = free_port().await;
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        register_port(&server, &mut rx, CLIENT_ID, "web", port)
+            .await
+            .unwrap();
+
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let connection_id = match timeout(Duration::from_secs(1), rx.recv()).await.unwrap() {
+            Some(Message::NewConnection { connection_id, .. }) => connection_id,
+            other => panic!("expected NewConnection, got {:?}", other),
+        };
+        let accepted = Message::ConnectionResponse {
+            connection_id: connection_id.clone(),
+            success: true,
+            error: None,
+        };
+        server
+            .handle_client_message(accepted, CLIENT_ID, "127.0.0.1")
+            .await
+            .unwrap();
+        peer.write_all(b"before").await.unwrap();
+        assert!(matches!(
+            rx.recv().await,
+            Some(Message::Data { seq: 0, data, .. }) if data == b"before"
+        ));
+
+        // the session is lost, what the peer sends meanwhile waits for the next one
+        disconnect(&server, CLIENT_ID).await;
+        peer.write_all(b"during").await.unwrap();
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        let resume = Message::ResumeConnection {
+            connection_id: connection_id.clone(),
+            received: 6,
+            acknowledged: 0,
+        };
+        server
+            .handle_client_message(resume, CLIENT_ID, "127.0.0.1")
+            .await
+            .unwrap();
+        assert!(matches!(
+            rx.recv().await,
+            Some(Message::ResumeConnection { received: 0, .. })
+        ));
+        assert!(matches!(
+            timeout(Duration::from_secs(1), rx.recv()).await.unwrap(),
+            Some(Message::Data { seq: 1, data, .. }) if data == b"during"
+        ));
+        let reply = Message::new_data(&connection_id, 0, b"reply".to_vec());
+        server
+            .handle_client_message(reply, CLIENT_ID, "127.0.0.1")
+            .await
+            .unwrap();
+        let mut buffer = [0u8; 5];
+        peer.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"reply");
+
+        // a connection the server does not know is closed
+        let unknown = Message::ResumeConnection {
+            connection_id: "unknown".to_string(),
+            received: 0,
+            acknowledged: 0,
+        };
+        server
+            .handle_client_message(unknown, CLIENT_ID, "127.0.0.1")
+            .await
+            .unwrap();
+        assert!(matches!(
+            rx.recv().await,
+            Some(Message::CloseConnection { connection_id, .. }) if connection_id == "unknown"
+        ));
+
+        // without the client resuming it, the connection is closed after the grace period
+        disconnect(&server, CLIENT_ID).await;
+        assert!(!refused(&mut peer, Duration::from_millis(100)).await);
+        assert!(refused(&mut peer, Duration::from_secs(1)).await);
+        assert!(server.proxy_connections.read().await.is_empty());
+    }
+
+    /// Whether the server closes `peer` within `within`
+    async fn refused(peer: &mut TcpStream, within: Duration) -> bool {
+        let read = timeout(within, peer.read(&mut [0u8; 1])).await;
+        matches!(read, Ok(Ok(0)) | Ok(Err(_)))
+    }
+
+    #[tokio::test]
+    async fn test_queued_connections_wait_for_the_client_to_return() {
+        let mut config = test_server().config;
+        config.listener_grace_period = "5s".parse().unwrap();
+        config.orphan_connection_policy = crate::config::OrphanConnectionPolicy::Queue;
+        config.orphan_queue_size = 2;
+        config.orphan_queue_timeout = "300ms".parse().unwrap();
+        let server = Server::new(config).unwrap();
+        let port = free_port().await;
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        register_port(&server, &mut rx, CLIENT_ID, "web", port)
+            .await
+            .unwrap();
+
+        // a burst while the client reconnects, over what the queue holds
+        disconnect(&server, CLIENT_ID).await;
+        let mut held = Vec::new();
+        for _ in 0..2 {
+            held.push(TcpStream::connect(("127.0.0.1", port)).await.unwrap());
+        }
+        let mut overflow = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        assert!(refused(&mut overflow, Duration::from_millis(200)).await);
+
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        let proxy_id = register_port(&server, &mut rx, CLIENT_ID, "web", port)
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            match timeout(Duration::from_secs(1), rx.recv()).await.unwrap() {
+                Some(Message::NewConnection { proxy_id: id, .. }) => assert_eq!(id, proxy_id),
+                other => panic!("expected NewConnection, got {:?}", other),
+            }
+        }
+
+        // without the client returning, held connections are refused after the timeout
+        disconnect(&server, CLIENT_ID).await;
+        let started = Instant::now();
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        assert!(refused(&mut peer, Duration::from_secs(2)).await);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // the queue has room again once its connections left it
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        register_port(&server, &mut rx, CLIENT_ID, "web", port)
+            .await
+            .unwrap();
+        assert!(matches!(
+            timeout(Duration::from_secs(1), rx.recv()).await.unwrap(),
+            Some(Message::NewConnection { .. })
+        ));
+        assert!(!refused(&mut peer, Duration::from_millis(50)).await);
+    }
+
+    #[tokio::test]
+    async fn test_services_requesting_the_same_port() {
+        let server = test_server();
+        let port = free_port().await;
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        let web = register_port(&server, &mut rx, CLIENT_ID, "web", port)
+            .await
+            .unwrap();
+
+        // a second service of the same client does not take the port over
+        let api = register_port(&server, &mut rx, CLIENT_ID, "api", port).await;
+        assert_eq!(
+            api.unwrap_err(),
+            format!("Port {port} already used by service 'web'")
+        );
+        assert_eq!(announced_proxy(&mut rx, port).await, web);
+
+        // nor does another client
+        let mut other_rx = connect_fake_client(&server, "client-2").await;
+        let other = register_port(&server, &mut other_rx, "client-2", "web", port).await;
+        assert_eq!(other.unwrap_err(), format!("Port {port} already in use"));
+    }
+
+    #[tokio::test]
+    async fn test_rejections_reveal_the_port_owner() {
+        let mut server = test_server();
+        server.config.reveal_port_owner = true;
+        let port = free_port().await;
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        register_port(&server, &mut rx, CLIENT_ID, "web", port)
+            .await
+            .unwrap();
+
+        let mut other_rx = connect_fake_client(&server, "client-2").await;
+        let other = register_port(&server, &mut other_rx, "client-2", "web", port).await;
+        assert_eq!(
+            other.unwrap_err(),
+            format!(
+                "Port {port} already in use by client {}",
+                short_id(CLIENT_ID)
+            )
+        );
+
+        // named when the owner gave a name
+        server
+            .clients
+            .write()
+            .await
+            .get_mut(CLIENT_ID)
+            .unwrap()
+            .name = Some("laptop".to_string());
+        let other =
+            register_in_group(&server, &mut other_rx, "client-2", "web", port, Some("web")).await;
+        assert_eq!(
+            other.unwrap_err(),
+            format!(
+                "Port {port} already in use by client {} (laptop) outside group 'web'",
+                short_id(CLIENT_ID)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_group_members_take_turns() {
+        let server = test_server();
+        let port = free_port().await;
+        let mut first_rx = connect_fake_client(&server, CLIENT_ID).await;
+        let first = register_in_group(&server, &mut first_rx, CLIENT_ID, "web", port, Some("web"))
+            .await
+            .unwrap();
+        let mut second_rx = connect_fake_client(&server, "client-2").await;
+        let second = register_in_group(
+            &server,
+            &mut second_rx,
+            "client-2",
+            "web",
+            port,
+            Some("web"),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(announced_proxy(&mut first_rx, port).await, first);
+        assert_eq!(announced_proxy(&mut second_rx, port).await, second);
+        assert_eq!(announced_proxy(&mut first_rx, port).await, first);
+
+        // outsiders and other groups are turned away
+        let mut other_rx = connect_fake_client(&server, "client-3").await;
+        let other = register_port(&server, &mut other_rx, "client-3", "web", port).await;
+        assert_eq!(
+            other.unwrap_err(),
+            format!("Port {port} is shared by group 'web'")
+        );
+        let other =
+            register_in_group(&server, &mut other_rx, "client-3", "web", port, Some("api")).await;
+        assert!(other.is_err());
+
+        // a member leaving the rotation keeps the listener for the others
+        disconnect(&server, "client-2").await;
+        assert_eq!(announced_proxy(&mut first_rx, port).await, first);
+        assert_eq!(announced_proxy(&mut first_rx, port).await, first);
+    }
+
+    #[tokio::test]
+    async fn test_group_cannot_join_a_private_listener() {
+        let server = test_server();
+        let port = free_port().await;
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        register_port(&server, &mut rx, CLIENT_ID, "web", port)
+            .await
+            .unwrap();
+
+        let mut other_rx = connect_fake_client(&server, "client-2").await;
+        let other =
+            register_in_group(&server, &mut other_rx, "client-2", "web", port, Some("web")).await;
+        assert_eq!(
+            other.unwrap_err(),
+            format!("Port {port} already in use outside group 'web'")
+        );
+        // a group needs a port to share
+        let auto =
+            register_in_group(&server, &mut other_rx, "client-2", "web", 0, Some("web")).await;
+        assert_eq!(auto.unwrap_err(), "A group needs a fixed remote_port");
+    }
+
+    #[tokio::test]
+    async fn test_server_port_policy() {
+        let mut config = test_server().config;
+        config.allowed_ports = Some("7000-7010,10443".parse().unwrap());
+        let server = Server::new(config).unwrap();
+
+        assert_eq!(
+            server.port_policy_violation(CLIENT_ID, 22).await.unwrap(),
+            "Port 22 not permitted by server policy"
+        );
+        // the control port is refused even though it is in the allowed set
+        assert!(server
+            .port_policy_violation(CLIENT_ID, 7000)
+            .await
+            .is_some());
+        assert!(server
+            .port_policy_violation(CLIENT_ID, 7001)
+            .await
+            .is_none());
+        assert!(server
+            .port_policy_violation(CLIENT_ID, 10443)
+            .await
+            .is_none());
+    }
+
+    /// Registers a client without a network connection, returning what the server sends it
+    async fn connect_fake_client(
+        server: &Server,
+        client_id: &str,
+    ) -> mpsc::UnboundedReceiver<Message> {
+        let (sender, rx) = mpsc::unbounded_channel();
+        let session_key = CryptoContext::derive_session_key("legacy", client_id).unwrap();
+        server.clients.write().await.insert(
+            client_id.to_string(),
+            ClientConnection {
+                client_id: client_id.to_string(),
+                sender,
+                crypto: Arc::new(CryptoContext::new(&session_key).unwrap()),
+                proxies: HashMap::new(),
+                compression: Compression::None,
+                grant: TokenGrant::default(),
+                session_id: Uuid::new_v4().to_string(),
+                name: None,
+                addr: "127.0.0.1:0".parse().unwrap(),
+                data_channels: true,
+                meta: None,
+                state: SessionState::Authenticated,
+                violations: 0,
+            },
+        );
+        rx
+    }
+
+    async fn register_shared_route(server: &Server, kind: SharedPort, client_id: &str, host: &str) {
+        let route = Some(host.to_string());
+        let (http_host, sni) = match kind {
+            SharedPort::Http => (route, None),
+            SharedPort::Sni => (None, route),
+        };
+        let message = Message::ProxyConfig {
+            request_id: 7,
+            op: ProxyConfigOpCode::Update,
+            name: host.to_string(),
+            local_ip: "127.0.0.1".to_string(),
+            local_port: 3000,
+            remote_port: 0,
+            preferred_port: None,
+            bind_host: None,
+            http_host,
+            sni,
+            group: None,
+            max_connections: None,
+            secret_hash: None,
+            relay_peers: None,
+            ttl_ms: None,
+        };
+        server
+            .handle_client_message(message, client_id, "127.0.0.1")
+            .await
+            .unwrap();
+    }
+
+    /// Serves `server`'s HTTP port on a port of its own, returning its address
+    async fn serve_http_port(server: &Server) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http_addr = listener.local_addr().unwrap();
+        let http_server = server.clone();
+        tokio::spawn(async move {
+            http_server
+                .serve_shared_port(SharedPort::Http, Arc::new(listener))
+                .await
+        });
+        http_addr
+    }
+
+    /// Counters of the one proxy `client_id` registered
+    async fn proxy_snapshot(server: &Server, client_id: &str) -> stats::StatsSnapshot {
+        let clients_guard = server.clients.read().await;
+        let proxy = clients_guard[client_id].proxies.values().next().unwrap();
+        proxy.stats.snapshot()
+    }
+
+    /// Receives a `NewConnection` and the request bytes sent after it
+    async fn expect_request(rx: &mut mpsc::UnboundedReceiver<Message>) -> (String, Vec<u8>) {
+        let connection_id = expect_new_connection(rx).await;
+        match rx.recv().await.unwrap() {
+            Message::Data { data, .. } => (connection_id, data),
+            other => panic!("expected Data, got {:?}", other),
+        }
+    }
+
+    async fn expect_new_connection(rx: &mut mpsc::UnboundedReceiver<Message>) -> String {
+        match rx.recv().await.unwrap() {
+            Message::NewConnection { connection_id, .. } => connection_id,
+            other => panic!("expected NewConnection, got {:?}", other),
+        }
+    }
+
+    /// Answers a connection's `NewConnection` as if the local service was reached
+    async fn accept_connection(server: &Server, client_id: &str, connection_id: &str) {
+        // the stream handler may not have registered the connection yet
+        while !server
+            .proxy_connections
+            .read()
+            .await
+            .contains_key(connection_id)
+        {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let message = Message::ConnectionResponse {
+            connection_id: connection_id.to_string(),
+            success: true,
+            error: None,
+        };
+        server
+            .handle_client_message(message, client_id, "127.0.0.1")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_http_routing_shares_one_port() {
+        let dir = tempfile::tempdir().unwrap();
+        let access_log = dir.path().join("access.log");
+        let mut config = test_server().config;
+        config.http_port = Some(8080);
+        config.access_log = Some(access_log.display().to_string());
+        let server = Server::new(config).unwrap();
+        // log formatting expects UUID client IDs
+        let (alice_id, bob_id) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+
+        let mut alice = connect_fake_client(&server, &alice_id).await;
+        let mut bob = connect_fake_client(&server, &bob_id).await;
+        register_shared_route(&server, SharedPort::Http, &alice_id, "app.example.com").await;
+        register_shared_route(&server, SharedPort::Http, &bob_id, "API.example.com").await;
+        for rx in [&mut alice, &mut bob] {
+            assert!(matches!(
+                rx.recv().await.unwrap(),
+                Message::ProxyConfigResponse {
+                    request_id: 7,
+                    success: true,
+                    assigned_port: Some(8080),
+                    ..
+                }
+            ));
+        }
+
+        // a host can only be claimed by one client
+        register_shared_route(&server, SharedPort::Http, &bob_id, "app.example.com").await;
+        match bob.recv().await.unwrap() {
+            Message::ProxyConfigResponse {
+                success: false,
+                error,
+                ..
+            } => {
+                assert_eq!(
+                    error.unwrap(),
+                    "Host app.example.com already registered by another client"
+                );
+            }
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http_addr = listener.local_addr().unwrap();
+        let http_server = server.clone();
+        tokio::spawn(async move {
+            http_server
+                .serve_shared_port(SharedPort::Http, Arc::new(listener))
+                .await
+        });
+
+        // keep-alive requests for different hosts reach their own client
+        let mut stream = TcpStream::connect(http_addr).await.unwrap();
+        stream
+            .write_all(b"GET /a HTTP/1.1\r\nHost: app.example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let (alice_conn, request) = expect_request(&mut alice).await;
+        assert!(request.starts_with(b"GET /a "));
+
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nalice".to_vec();
+        let reply = Message::Data {
+            connection_id: alice_conn.clone(),
+            seq: 0,
+            data: response.clone(),
+        };
+        server
+            .handle_client_message(reply, &alice_id, "127.0.0.1")
+            .await
+            .unwrap();
+        let mut received = vec![0u8; response.len()];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, response);
+        // the shared writer acknowledges responses as soon as they are queued
+        assert!(matches!(
+            alice.recv().await.unwrap(),
+            Message::WindowUpdate { connection_id, bytes }
+                if connection_id == alice_conn && bytes as usize == response.len()
+        ));
+
+        // traffic is counted against the proxy serving the host
+        let stats = server.clients.read().await[&alice_id]
+            .proxies
+            .values()
+            .next()
+            .unwrap()
+            .stats
+            .snapshot();
+        assert_eq!(stats.connections, 1);
+        assert_eq!(stats.bytes_in, request.len() as u64);
+        assert_eq!(stats.bytes_out, response.len() as u64);
+
+        stream
+            .write_all(b"GET /b HTTP/1.1\r\nHost: api.example.com:8080\r\n\r\n")
+            .await
+            .unwrap();
+        let (bob_conn, bob_request) = expect_request(&mut bob).await;
+        assert!(bob_request.starts_with(b"GET /b "));
+        // closing the tunnel tells the client what its HTTP connection moved
+        match alice.recv().await.unwrap() {
+            Message::CloseConnection {
+                connection_id,
+                stats: Some(stats),
+                ..
+            } if connection_id == alice_conn => {
+                assert_eq!(stats.bytes_in, request.len() as u64);
+                assert_eq!(stats.bytes_out, response.len() as u64);
+            }
+            other => panic!("expected CloseConnection with stats, got {:?}", other),
+        }
+
+        // the closed tunnel is in the access log like any other proxy connection
+        let line = loop {
+            let written = std::fs::read_to_string(&access_log).unwrap_or_default();
+            if let Some(line) = written.lines().next() {
+                break serde_json::from_str::<serde_json::Value>(line).unwrap();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(line["client_id"], alice_id.as_str());
+        assert_eq!(line["remote_port"], http_addr.port());
+        assert_eq!(line["bytes_in"], request.len() as u64);
+        assert_eq!(line["bytes_out"], response.len() as u64);
+        assert_eq!(line["reason"], "peer_closed");
+        let stats = proxy_snapshot(&server, &alice_id).await;
+        assert_eq!(
+            (stats.active, stats.closed.get(CloseCode::PeerClosed)),
+            (0, 1)
+        );
+        assert_eq!(proxy_snapshot(&server, &bob_id).await.active, 1);
+
+        // a tunnel the client closes is counted with the code it closed with
+        let close = Message::new_close_connection(&bob_conn, CloseCode::LocalError);
+        server
+            .handle_client_message(close, &bob_id, "127.0.0.1")
+            .await
+            .unwrap();
+        loop {
+            let stats = proxy_snapshot(&server, &bob_id).await;
+            if stats.closed.get(CloseCode::LocalError) == 1 {
+                assert_eq!(stats.active, 0);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // unknown hosts are answered by the server itself
+        let mut stream = TcpStream::connect(http_addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: unknown.example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found"),
+            "{}",
+            response
+        );
+
+        // disconnecting releases the client's hosts
+        server.cleanup_client(&alice_id).await;
+        assert!(!server
+            .shared_routes
+            .read()
+            .await
+            .contains_key(&(SharedPort::Http, "app.example.com".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_http_keep_alive_reopens_a_tunnel_the_client_closed() {
+        let mut config = test_server().config;
+        config.http_port = Some(8080);
+        let server = Server::new(config).unwrap();
+        let client_id = Uuid::new_v4().to_string();
+        let mut rx = connect_fake_client(&server, &client_id).await;
+        register_shared_route(&server, SharedPort::Http, &client_id, "app.example.com").await;
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            Message::ProxyConfigResponse { success: true, .. }
+        ));
+
+        let mut stream = TcpStream::connect(serve_http_port(&server).await)
+            .await
+            .unwrap();
+        for (path, body) in [("/first", "one"), ("/second", "two")] {
+            let request = format!("GET {} HTTP/1.1\r\nHost: app.example.com\r\n\r\n", path);
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let (connection_id, request) = expect_request(&mut rx).await;
+            assert!(request.starts_with(format!("GET {} ", path).as_bytes()));
+
+            // the backend answers, then closes its connection
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n{}", body);
+            let reply = Message::Data {
+                connection_id: connection_id.clone(),
+                seq: 0,
+                data: response.clone().into_bytes(),
+            };
+            server
+                .handle_client_message(reply, &client_id, "127.0.0.1")
+                .await
+                .unwrap();
+            let close = Message::new_close_connection(&connection_id, CloseCode::PeerClosed);
+            server
+                .handle_client_message(close, &client_id, "127.0.0.1")
+                .await
+                .unwrap();
+
+            let mut received = vec![0u8; response.len()];
+            stream.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, response.as_bytes());
+            assert!(matches!(
+                rx.recv().await.unwrap(),
+                Message::WindowUpdate { .. }
+            ));
+        }
+        assert_eq!(proxy_snapshot(&server, &client_id).await.connections, 2);
+    }
+
+    #[tokio::test]
+    async fn test_http_tunnel_closes_when_idle() {
+        let mut config = test_server().config;
+        config.http_port = Some(8080);
+        config.idle_timeout = "300ms".parse().unwrap();
+        let server = Server::new(config).unwrap();
+        let client_id = Uuid::new_v4().to_string();
+        let mut rx = connect_fake_client(&server, &client_id).await;
+        register_shared_route(&server, SharedPort::Http, &client_id, "app.example.com").await;
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            Message::ProxyConfigResponse { success: true, .. }
+        ));
+
+        let mut stream = TcpStream::connect(serve_http_port(&server).await)
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: app.example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let (connection_id, _) = expect_request(&mut rx).await;
+
+        // neither the peer nor the backend says anything more
+        let started = Instant::now();
+        match rx.recv().await.unwrap() {
+            Message::CloseConnection {
+                connection_id: closed,
+                code,
+                ..
+            } => {
+                assert_eq!(closed, connection_id);
+                assert_eq!(code, Some(CloseCode::IdleTimeout));
+            }
+            other => panic!("expected CloseConnection, got {:?}", other),
+        }
+        assert!(started.elapsed() >= Duration::from_millis(250));
+        assert_eq!(stream.read(&mut [0u8; 1]).await.unwrap(), 0);
+        let stats = proxy_snapshot(&server, &client_id).await;
+        assert_eq!(stats.closed.get(CloseCode::IdleTimeout), 1);
+    }
+
+    #[tokio::test]
+    async fn test_sni_routing_forwards_client_hello() {
+        let mut config = test_server().config;
+        config.sni_port = Some(8443);
+        let server = Server::new(config).unwrap();
+        let client_id = Uuid::new_v4().to_string();
+
+        let mut rx = connect_fake_client(&server, &client_id).await;
+        register_shared_route(&server, SharedPort::Sni, &client_id, "example.com").await;
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            Message::ProxyConfigResponse {
+                success: true,
+                assigned_port: Some(8443),
+                ..
+            }
+        ));
+        // HTTP routing is not enabled on this server
+        register_shared_route(&server, SharedPort::Http, &client_id, "example.com").await;
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            Message::ProxyConfigResponse { success: false, .. }
+        ));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sni_addr = listener.local_addr().unwrap();
+        let sni_server = server.clone();
+        tokio::spawn(async move {
+            sni_server
+                .serve_shared_port(SharedPort::Sni, Arc::new(listener))
+                .await
+        });
+
+        // the hello arrives in pieces, and is forwarded whole before anything else
+        let hello = sni::tests::hex(sni::tests::HELLO_WITH_SNI);
+        let mut stream = TcpStream::connect(sni_addr).await.unwrap();
+        for piece in hello.chunks(50) {
+            stream.write_all(piece).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let connection_id = expect_new_connection(&mut rx).await;
+        accept_connection(&server, &client_id, &connection_id).await;
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            Message::Data { data, .. } if data == hello
+        ));
+
+        // unknown names are dropped
+        let mut hello = hello;
+        let name_at = hello.windows(11).position(|w| w == b"example.com").unwrap();
+        hello[name_at..name_at + 7].copy_from_slice(b"unknown");
+        let mut stream = TcpStream::connect(sni_addr).await.unwrap();
+        stream.write_all(&hello).await.unwrap();
+        assert_eq!(stream.read(&mut [0u8; 16]).await.unwrap(), 0);
+    }
+
+    async fn next_message(reader: &mut FrameReader, stream: &mut TcpStream) -> Message {
+        reader.read_frame(stream).await.unwrap().unwrap().message
+    }
+
+    /// Accepts client connections for `server` on a local port, returning its address
+    async fn spawn_control_listener(server: &Server) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accept_server = server.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                let server = accept_server.clone();
+                tokio::spawn(async move { server.handle_client(stream, addr, false).await });
+            }
+        });
+        addr
+    }
+
+    /// Runs the handshake as `client_id`, returning the stream and the auth response
+    async fn authenticate(addr: SocketAddr, client_id: &str) -> (TcpStream, Message) {
+        authenticate_with(addr, client_id, "legacy").await
+    }
+
+    async fn authenticate_with(
+        addr: SocketAddr,
+        client_id: &str,
+        token: &str,
+    ) -> (TcpStream, Message) {
+        authenticate_on(TcpStream::connect(addr).await.unwrap(), client_id, token).await
+    }
+
+    /// Runs the handshake on a connection to the control port
+    async fn authenticate_on(
+        mut stream: TcpStream,
+        client_id: &str,
+        token: &str,
+    ) -> (TcpStream, Message) {
+        let mut reader = FrameReader::new();
+        let nonce = match reader
+            .read_frame(&mut stream)
+            .await
+            .unwrap()
+            .unwrap()
+            .message
+        {
+            Message::AuthChallenge { nonce, .. } => nonce,
+            other => panic!("expected AuthChallenge, got {:?}", other),
+        };
+        let auth = Message::new_auth(token, &nonce, client_id, None, vec![], true);
+        stream
+            .write_all(&Frame::new(auth).serialize().unwrap())
+            .await
+            .unwrap();
+        let response = reader.read_frame(&mut stream).await.unwrap().unwrap();
+        (stream, response.message)
+    }
+
+    /// Registers an auto-assigned service and returns its port
+    async fn register_service(stream: &mut TcpStream) -> u16 {
+        let message = Message::ProxyConfig {
+            request_id: 1,
+            op: ProxyConfigOpCode::Update,
+            name: "web".to_string(),
+            local_ip: "127.0.0.1".to_string(),
+            local_port: 3000,
+            remote_port: 0,
+            preferred_port: None,
+            bind_host: None,
+            http_host: None,
+            sni: None,
+            group: None,
+            max_connections: None,
+            secret_hash: None,
+            relay_peers: None,
+            ttl_ms: None,
+        };
+        stream
+            .write_all(&Frame::new(message).serialize().unwrap())
+            .await
+            .unwrap();
+        match FrameReader::new()
+            .read_frame(stream)
+            .await
+            .unwrap()
+            .unwrap()
+            .message
+        {
+            Message::ProxyConfigResponse {
+                success: true,
+                assigned_port: Some(port),
+                ..
+            } => port,
+            other => panic!("expected a successful ProxyConfigResponse, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_client_policy() {
+        for policy in [
+            DuplicateClientPolicy::Reject,
+            DuplicateClientPolicy::Replace,
+        ] {
+            let mut config = test_server().config;
+            config.duplicate_client_policy = policy;
+            let server = Server::new(config).unwrap();
+            let addr = spawn_control_listener(&server).await;
+            let client_id = Uuid::new_v4().to_string();
+
+            // the first session goes silent without closing, as after a network drop
+            let (mut stale, _) = authenticate(addr, &client_id).await;
+            register_service(&mut stale).await;
+
+            let (mut fresh, response) = authenticate(addr, &client_id).await;
+            match policy {
+                DuplicateClientPolicy::Reject => {
+                    assert!(matches!(
+                        response,
+                        Message::AuthResponse { success: false, error: Some(error), .. }
+                            if error.contains("already connected")
+                    ));
+                }
+                DuplicateClientPolicy::Replace => {
+                    assert!(matches!(
+                        response,
+                        Message::AuthResponse { success: true, .. }
+                    ));
+                    assert!(matches!(
+                        FrameReader::new()
+                            .read_frame(&mut stale)
+                            .await
+                            .unwrap()
+                            .unwrap()
+                            .message,
+                        Message::SessionClosed { .. }
+                    ));
+
+                    // service is restored on the new session
+                    let port = register_service(&mut fresh).await;
+                    let _peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+                    assert!(matches!(
+                        FrameReader::new()
+                            .read_frame(&mut fresh)
+                            .await
+                            .unwrap()
+                            .unwrap()
+                            .message,
+                        Message::NewConnection { .. }
+                    ));
+
+                    // the old session ending later leaves the new one alone
+                    drop(stale);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    assert!(server.clients.read().await.contains_key(&client_id));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clients_that_stop_reading_are_dropped_after_the_write_timeout() {
+        let mut config = test_server().config;
+        config.control_write_timeout = "300ms".parse().unwrap();
+        let server = Server::new(config).unwrap();
+        let addr = spawn_control_listener(&server).await;
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        let stream = socket.connect(addr).await.unwrap();
+        let (mut stream, _) = authenticate_on(stream, CLIENT_ID, "legacy").await;
+
+        // the answers to a flood of heartbeats fill the socket buffers, the client reads none
+        let mut flood = BytesMut::new();
+        for timestamp in 0..400_000 {
+            Frame::new(Message::Heartbeat { timestamp })
+                .encode_into(&mut flood)
+                .unwrap();
+        }
+        // the server may drop the session before the whole flood is written
+        let _ = stream.write_all(&flood).await;
+
+        timeout(Duration::from_secs(5), async {
+            while server.clients.read().await.contains_key(CLIENT_ID) {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("the stalled session was kept");
+    }
+
+    /// Opens a data channel to `addr` for `connection_id`, proving it with `crypto`
+    async fn open_data_channel(
+        addr: SocketAddr,
+        client_id: &str,
+        connection_id: &str,
+        crypto: &CryptoContext,
+    ) -> TcpStream {
+        let mut channel = TcpStream::connect(addr).await.unwrap();
+        let nonce = match next_message(&mut FrameReader::new(), &mut channel).await {
+            Message::AuthChallenge { nonce, .. } => nonce,
+            other => panic!("expected AuthChallenge, got {:?}", other),
+        };
+        let hello = Message::DataChannelHello {
+            client_id: client_id.to_string(),
+            connection_id: connection_id.to_string(),
+            proof: crypto.data_channel_proof(&nonce, connection_id),
+        };
+        channel
+            .write_all(&Frame::new(hello).serialize().unwrap())
+            .await
+            .unwrap();
+        channel
+    }
+
+    #[tokio::test]
+    async fn test_data_channels_carry_proxy_connections() {
+        let server = Server::new(test_server().config).unwrap();
+        let addr = spawn_control_listener(&server).await;
+        let (mut stream, response) = authenticate(addr, CLIENT_ID).await;
+        let Message::AuthResponse {
+            session_key: Some(session_key),
+            data_channels: true,
+            ..
+        } = response
+        else {
+            panic!(
+                "expected data channels in the AuthResponse, got {:?}",
+                response
+            );
+        };
+        let crypto = CryptoContext::new(&session_key).unwrap();
+        let port = register_service(&mut stream).await;
+        let mut reader = FrameReader::new();
+
+        // the peer's bytes go straight to the channel and back
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let Message::NewConnection { connection_id, .. } =
+            next_message(&mut reader, &mut stream).await
+        else {
+            panic!("expected NewConnection");
+        };
+        peer.write_all(b"hello").await.unwrap();
+        let mut channel = open_data_channel(addr, CLIENT_ID, &connection_id, &crypto).await;
+        channel.write_all(b"world").await.unwrap();
+        let mut received = [0u8; 5];
+        channel.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+        peer.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"world");
+
+        // a channel proven with another session's key is closed without carrying anything
+        let _other_peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let Message::NewConnection { connection_id, .. } =
+            next_message(&mut reader, &mut stream).await
+        else {
+            panic!("expected NewConnection");
+        };
+        let other_key = CryptoContext::derive_session_key("legacy", "other-client").unwrap();
+        let other = CryptoContext::new(&other_key).unwrap();
+        let mut forged = open_data_channel(addr, CLIENT_ID, &connection_id, &other).await;
+        let mut rest = Vec::new();
+        timeout(Duration::from_secs(2), forged.read_to_end(&mut rest))
+            .await
+            .expect("forged channel left open")
+            .unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connections_wait_for_client_response() {
+        let mut config = test_server().config;
+        config.connect_timeout = "300ms".parse().unwrap();
+        let server = Server::new(config).unwrap();
+        let addr = spawn_control_listener(&server).await;
+        let client_id = Uuid::new_v4().to_string();
+        let (mut control, _) = authenticate(addr, &client_id).await;
+        let port = register_service(&mut control).await;
+        let mut reader = FrameReader::new();
+
+        // early data is held back until the client reached its local service
+        let mut accepted = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        accepted.write_all(b"early").await.unwrap();
+        let Message::NewConnection { connection_id, .. } =
+            next_message(&mut reader, &mut control).await
+        else {
+            panic!("expected NewConnection");
+        };
+        assert!(
+            timeout(Duration::from_millis(100), reader.read_frame(&mut control))
+                .await
+                .is_err()
+        );
+        let response = Message::ConnectionResponse {
+            connection_id,
+            success: true,
+            error: None,
+        };
+        control
+            .write_all(&Frame::new(response).serialize().unwrap())
+            .await
+            .unwrap();
+        assert!(matches!(
+            next_message(&mut reader, &mut control).await,
+            Message::Data { data, .. } if data == b"early"
+        ));
+
+        // a local service that is down closes the external connection at once
+        let mut refused = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let Message::NewConnection { connection_id, .. } =
+            next_message(&mut reader, &mut control).await
+        else {
+            panic!("expected NewConnection");
+        };
+        let response = Message::ConnectionResponse {
+            connection_id,
+            success: false,
+            error: Some("Connection refused".to_string()),
+        };
+        control
+            .write_all(&Frame::new(response).serialize().unwrap())
+            .await
+            .unwrap();
+        let closed = timeout(Duration::from_millis(200), refused.read(&mut [0u8; 16])).await;
+        assert_eq!(closed.unwrap().unwrap(), 0);
+
+        // so does a client that never answers, once the connect timeout passes
+        let mut unanswered = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let Message::NewConnection { connection_id, .. } =
+            next_message(&mut reader, &mut control).await
+        else {
+            panic!("expected NewConnection");
+        };
+        let closed = timeout(Duration::from_secs(2), unanswered.read(&mut [0u8; 16])).await;
+        assert_eq!(closed.unwrap().unwrap(), 0);
+        assert!(matches!(
+            next_message(&mut reader, &mut control).await,
+            Message::CloseConnection { connection_id: closed, .. } if closed == connection_id
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_disconnects_clients() {
+        let mut config = test_server().config;
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        config.listen_addr = addr.to_string();
+        let server = Server::new(config).unwrap();
+        let shutdown = CancellationToken::new();
+        let running = tokio::spawn({
+            let server = server.clone();
+            let shutdown = shutdown.clone();
+            async move { server.run(shutdown).await }
+        });
+
+        let mut control = loop {
+            if TcpStream::connect(addr).await.is_ok() {
+                break authenticate(addr, CLIENT_ID).await.0;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let port = register_service(&mut control).await;
+
+        shutdown.cancel();
+        timeout(Duration::from_secs(1), running)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(control.read(&mut [0u8; 16]).await.unwrap(), 0);
+        assert!(server.clients.read().await.is_empty());
+        assert!(TcpStream::connect(addr).await.is_err());
+        // proxy listeners stop once they see the cancellation
+        timeout(Duration::from_secs(1), async {
+            while TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restarted_server_rebinds_its_ports_at_once() {
+        let mut config = test_server().config;
+        let addr: SocketAddr = format!("127.0.0.1:{}", free_port().await).parse().unwrap();
+        config.listen_addr = addr.to_string();
+        let port = free_port().await;
+
+        // the second server binds the ports the first one just closed connections on
+        for _ in 0..2 {
+            let server = Server::new(config.clone()).unwrap();
+            let shutdown = CancellationToken::new();
+            let running = tokio::spawn({
+                let server = server.clone();
+                let shutdown = shutdown.clone();
+                async move { server.run(shutdown).await }
+            });
+            let (mut control, response) = loop {
+                if let Ok(stream) = TcpStream::connect(addr).await {
+                    break authenticate_on(stream, CLIENT_ID, "legacy").await;
+                }
+                if running.is_finished() {
+                    panic!("server stopped: {:?}", running.await.unwrap());
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            };
+            assert!(matches!(
+                response,
+                Message::AuthResponse { success: true, .. }
+            ));
+
+            let mut rx = connect_fake_client(&server, "client-2").await;
+            register_port(&server, &mut rx, "client-2", "web", port)
+                .await
+                .unwrap();
+            let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            let connection_id = match timeout(Duration::from_secs(1), rx.recv()).await.unwrap() {
+                Some(Message::NewConnection { connection_id, .. }) => connection_id,
+                other => panic!("expected NewConnection, got {:?}", other),
+            };
+            // the server closing first leaves its side of both connections in TIME_WAIT
+            let failed = Message::ConnectionResponse {
+                connection_id,
+                success: false,
+                error: Some("connection refused".to_string()),
+            };
+            server
+                .handle_client_message(failed, "client-2", "127.0.0.1")
+                .await
+                .unwrap();
+            assert_eq!(peer.read(&mut [0u8; 16]).await.unwrap(), 0);
+
+            shutdown.cancel();
+            timeout(Duration::from_secs(1), running)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(control.read(&mut [0u8; 16]).await.unwrap(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_frames_are_skipped() {
+        let server = test_server();
+        let addr = spawn_control_listener(&server).await;
+        let (mut control, _) = authenticate(addr, CLIENT_ID).await;
+        assert!(server.clients.read().await.contains_key(CLIENT_ID));
+
+        let mut data = vec![0, 0, 0, 3, 0xff, 0xff, 0xff];
+        data.extend(
+            Frame::new(Message::Heartbeat { timestamp: 7 })
+                .serialize_framed(Framing::Marked)
+                .unwrap(),
+        );
+        control.write_all(&data).await.unwrap();
+        let mut reader = FrameReader::new();
+        let answered = timeout(Duration::from_secs(1), reader.read_frame(&mut control))
+            .await
+            .expect("heartbeat past the garbage was not answered");
+        match answered.unwrap().unwrap().message {
+            Message::HeartbeatResponse { timestamp, stats } => {
+                assert_eq!((timestamp, stats), (7, Some(ClientStats::default())))
+            }
+            other => panic!("expected HeartbeatResponse, got {:?}", other),
+        }
+        assert!(server.clients.read().await.contains_key(CLIENT_ID));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_data_closes_the_session() {
+        let server = test_server();
+        let addr = spawn_control_listener(&server).await;
+        let (mut control, _) = authenticate(addr, CLIENT_ID).await;
+
+        let oversized = Message::new_data("conn", 0, vec![0; MAX_DATA_PAYLOAD + 1]);
+        control
+            .write_all(&Frame::new(oversized).serialize().unwrap())
+            .await
+            .unwrap();
+        let mut reader = FrameReader::new();
+        let reported = timeout(Duration::from_secs(1), reader.read_frame(&mut control))
+            .await
+            .expect("violation was not reported");
+        match reported.unwrap().unwrap().message {
+            Message::Error { message } => {
+                assert_eq!(message, "Protocol violation: Oversized data frame")
+            }
+            other => panic!("expected Error, got {:?}", other),
+        }
+        let closed = timeout(Duration::from_secs(1), reader.read_frame(&mut control))
+            .await
+            .expect("connection was not closed");
+        assert!(closed.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_large_preface_is_chunked() {
+        let server = test_server();
+        let client_id = Uuid::new_v4().to_string();
+        let mut rx = connect_fake_client(&server, &client_id).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _peer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let preface: Vec<u8> = (0..10 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        tokio::spawn({
+            let server = server.clone();
+            let client_id = client_id.clone();
+            let preface = preface.clone();
+            async move {
+                server
+                    .handle_proxy_stream(
+                        stream,
+                        client_id,
+                        "proxy".to_string(),
+                        "big".to_string(),
+                        Arc::default(),
+                        preface,
+                    )
+                    .await
+            }
+        });
+        accept_connection(&server, &client_id, "big").await;
+
+        let mut received = Vec::new();
+        let mut largest = 0;
+        while received.len() < preface.len() {
+            match timeout(Duration::from_secs(5), rx.recv()).await.unwrap() {
+                Some(Message::Data { data, .. }) => {
+                    largest = largest.max(data.len());
+                    received.extend(data);
+                }
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+        assert!(received == preface);
+        assert_eq!(largest, MAX_DATA_PAYLOAD);
+    }
+
+    #[tokio::test]
+    async fn test_repeated_auth_failures_ban_the_ip() {
+        let mut config = test_server().config;
+        config.auth_fail_limit = 2;
+        config.auth_ban_duration = "300ms".parse().unwrap();
+        let server = Server::new(config).unwrap();
+        let addr = spawn_control_listener(&server).await;
+        let client_id = Uuid::new_v4().to_string();
+
+        for _ in 0..2 {
+            let (_, response) = authenticate_with(addr, &client_id, "guess").await;
+            assert!(matches!(
+                response,
+                Message::AuthResponse { success: false, .. }
+            ));
+        }
+
+        // banned, the connection is closed before any challenge is sent
+        let mut banned = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(banned.read(&mut [0u8; 16]).await.unwrap(), 0);
+
+        // the ban expires, and a successful login clears the failures
+        tokio::time::sleep(Duration::from_millis(350)).await;
+        let (_, response) = authenticate_with(addr, &client_id, "guess").await;
+        assert!(matches!(
+            response,
+            Message::AuthResponse { success: false, .. }
+        ));
+        let (_, response) = authenticate(addr, &client_id).await;
+        assert!(matches!(
+            response,
+            Message::AuthResponse { success: true, .. }
+        ));
+        let (_, response) = authenticate_with(addr, "other", "guess").await;
+        assert!(matches!(
+            response,
+            Message::AuthResponse { success: false, .. }
+        ));
+        assert!(!server.auth_bans.is_banned("127.0.0.1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_connections_over_the_ip_rate_limit_are_dropped() {
+        let mut config = test_server().config;
+        config.max_connections_per_ip_per_minute = 1;
+        let server = Server::new(config).unwrap();
+        let addr = spawn_control_listener(&server).await;
+        let client_id = Uuid::new_v4().to_string();
+        let (mut control, _) = authenticate(addr, &client_id).await;
+        let port = register_service(&mut control).await;
+        let mut reader = FrameReader::new();
+
+        let _first = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        assert!(matches!(
+            next_message(&mut reader, &mut control).await,
+            Message::NewConnection { .. }
+        ));
+
+        // the second one is closed without the client hearing of it
+        let mut second = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        assert_eq!(second.read(&mut [0u8; 16]).await.unwrap(), 0);
+        assert!(
+            timeout(Duration::from_millis(100), reader.read_frame(&mut control))
+                .await
+                .is_err()
+        );
+        let clients_guard = server.clients.read().await;
+        let proxy = clients_guard[&client_id].proxies.values().next().unwrap();
+        assert_eq!(proxy.stats.snapshot().dropped, 1);
+    }
+
+    #[tokio::test]
+    async fn test_admin_kick_close_and_ban() {
+        let mut config = test_server().config;
+        config.admin = Some(crate::config::AdminConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            tokens: vec![crate::config::AdminToken {
+                name: "ops".to_string(),
+                token: "admin-secret".to_string(),
+            }],
+        });
+        let server = Server::new(config).unwrap();
+        let addr = spawn_control_listener(&server).await;
+        let admin_listener = Arc::new(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let admin_addr = admin_listener.local_addr().unwrap().to_string();
+        let admin_server = server.clone();
+        tokio::spawn(async move { admin_server.serve_admin(admin_listener).await });
+        let admin = AdminClient::new(admin_addr.clone(), "admin-secret");
+
+        let client_id = Uuid::new_v4().to_string();
+        let (mut control, _) = authenticate(addr, &client_id).await;
+        let port = register_service(&mut control).await;
+        let mut reader = FrameReader::new();
+
+        let err = AdminClient::new(admin_addr, "guess")
+            .kick(&client_id)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Missing or invalid admin token");
+
+        // clients are listed with what they report about their local services
+        let proxy_id = server.clients.read().await[&client_id]
+            .proxies
+            .keys()
+            .next()
+            .unwrap()
+            .clone();
+        let status = Message::ServiceStatus {
+            proxy_id: proxy_id.clone(),
+            error: Some("connection refused".to_string()),
+        };
+        control
+            .write_all(&Frame::new(status).serialize().unwrap())
+            .await
+            .unwrap();
+        let proxy = loop {
+            let listing = admin.clients().await.unwrap();
+            let client = &listing["clients"][0];
+            assert_eq!(client["id"], client_id.as_str());
+            assert_eq!(client["meta"]["version"], env!("CARGO_PKG_VERSION"));
+            assert_eq!(client["meta"]["os"], std::env::consts::OS);
+            let proxy = client["proxies"][0].clone();
+            if !proxy["local_error"].is_null() {
+                break proxy;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        };
+        assert_eq!(proxy["id"], proxy_id.as_str());
+        assert_eq!(proxy["local"], "127.0.0.1:3000");
+        assert_eq!(proxy["remote_port"], port);
+        assert_eq!(proxy["local_error"], "connection refused");
+
+        // and each bound port with the client holding it
+        let ports = admin.ports().await.unwrap();
+        let listing = &ports["ports"][0];
+        assert_eq!(listing["port"], port);
+        assert_eq!(listing["bind_host"], "127.0.0.1");
+        assert_eq!(listing["client_id"], client_id.as_str());
+        assert_eq!(listing["proxies"][0], proxy_id.as_str());
+        assert_eq!(listing["orphaned"], false);
+        assert!(listing["uptime_secs"].is_u64());
+
+        // a connection is closed on both ends, found by its short ID
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let Message::NewConnection { connection_id, .. } =
+            next_message(&mut reader, &mut control).await
+        else {
+            panic!("expected NewConnection");
+        };
+        while !server
+            .proxy_connections
+            .read()
+            .await
+            .contains_key(&connection_id)
+        {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let closed = admin.close(short_id(&connection_id)).await.unwrap();
+        assert_eq!(closed, format!("Closed connection {}", connection_id));
+        assert_eq!(peer.read(&mut [0u8; 16]).await.unwrap(), 0);
+        assert!(matches!(
+            next_message(&mut reader, &mut control).await,
+            Message::CloseConnection { connection_id: closed, .. } if closed == connection_id
+        ));
+        let err = admin.close(&connection_id).await.unwrap_err();
+        assert!(err.to_string().starts_with("No connection"), "{}", err);
+
+        // banned peers are dropped before the client hears of them, as are new sessions
+        let err = admin.ban("127.0.0.0/33", "1m").await.unwrap_err();
+        assert!(err.to_string().contains("prefix length"), "{}", err);
+        let banned = admin.ban("127.0.0.0/8", "300ms").await.unwrap();
+        assert_eq!(banned, "Banned 127.0.0.0/8 for 300ms");
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        assert_eq!(peer.read(&mut [0u8; 16]).await.unwrap(), 0);
+        let mut session = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(session.read(&mut [0u8; 16]).await.unwrap(), 0);
+        assert!(
+            timeout(Duration::from_millis(100), reader.read_frame(&mut control))
+                .await
+                .is_err()
+        );
+        tokio::time::sleep(Duration::from_millis(350)).await;
+        let _peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        assert!(matches!(
+            next_message(&mut reader, &mut control).await,
+            Message::NewConnection { .. }
+        ));
+
+        // a kicked client is told why and its control connection closed
+        admin.kick(&client_id).await.unwrap();
+        assert!(!server.clients.read().await.contains_key(&client_id));
+        assert!(matches!(
+            next_message(&mut reader, &mut control).await,
+            Message::SessionClosed { reason } if reason == admin::KICK_REASON
+        ));
+        assert!(reader.read_frame(&mut control).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_connections_over_max_connections_are_rejected() {
+        let server = test_server();
+        let port = free_port().await;
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        let proxy_id = register_port(&server, &mut rx, CLIENT_ID, "web", port)
+            .await
+            .unwrap();
+        let stats = {
+            let mut clients_guard = server.clients.write().await;
+            let proxy = clients_guard
+                .get_mut(CLIENT_ID)
+                .unwrap()
+                .proxies
+                .get_mut(&proxy_id)
+                .unwrap();
+            proxy.max_connections = Some(2);
+            proxy.stats.clone()
+        };
+
+        let mut peers = Vec::new();
+        let mut connection_ids = Vec::new();
+        for _ in 0..2 {
+            peers.push(TcpStream::connect(("127.0.0.1", port)).await.unwrap());
+            match timeout(Duration::from_secs(1), rx.recv()).await.unwrap() {
+                Some(Message::NewConnection { connection_id, .. }) => {
+                    connection_ids.push(connection_id)
+                }
+                other => panic!("expected NewConnection, got {:?}", other),
+            }
+        }
+
+        // the third is closed without the client hearing of it
+        let mut third = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        assert_eq!(third.read(&mut [0u8; 16]).await.unwrap(), 0);
+        assert!(timeout(Duration::from_millis(100), rx.recv())
+            .await
+            .is_err());
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.active, snapshot.rejected), (2, 1));
+
+        // a connection failing frees its slot
+        let failed = Message::ConnectionResponse {
+            connection_id: connection_ids[0].clone(),
+            success: false,
+            error: Some("connection refused".to_string()),
+        };
+        server
+            .handle_client_message(failed, CLIENT_ID, "127.0.0.1")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(stats.snapshot().active, 1);
+        assert_eq!(announced_proxy(&mut rx, port).await, proxy_id);
+    }
+
+    #[tokio::test]
+    async fn test_connections_over_the_server_limit_are_refused() {
+        let mut config = test_server().config;
+        config.max_connections = 2;
+        let server = Server::new(config).unwrap();
+        let port = free_port().await;
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        let proxy_id = register_port(&server, &mut rx, CLIENT_ID, "web", port)
+            .await
+            .unwrap();
+
+        let mut peers = Vec::new();
+        let mut connection_ids = Vec::new();
+        for _ in 0..2 {
+            peers.push(TcpStream::connect(("127.0.0.1", port)).await.unwrap());
+            match timeout(Duration::from_secs(1), rx.recv()).await.unwrap() {
+                Some(Message::NewConnection { connection_id, .. }) => {
+                    connection_ids.push(connection_id)
+                }
+                other => panic!("expected NewConnection, got {:?}", other),
+            }
+        }
+
+        // more are closed without the client hearing of them
+        for _ in 0..3 {
+            let mut excess = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            assert_eq!(excess.read(&mut [0u8; 16]).await.unwrap(), 0);
+        }
+        assert!(timeout(Duration::from_millis(100), rx.recv())
+            .await
+            .is_err());
+
+        // the listener lives on, a connection ending makes room for the next
+        let failed = Message::ConnectionResponse {
+            connection_id: connection_ids[0].clone(),
+            success: false,
+            error: Some("connection refused".to_string()),
+        };
+        server
+            .handle_client_message(failed, CLIENT_ID, "127.0.0.1")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(announced_proxy(&mut rx, port).await, proxy_id);
+    }
+
+    #[tokio::test]
+    async fn test_expired_proxies_are_removed() {
+        let server = test_server();
+        let port = free_port().await;
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        let proxy_id = register_port(&server, &mut rx, CLIENT_ID, "web", port)
+            .await
+            .unwrap();
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let connection_id = match timeout(Duration::from_secs(1), rx.recv()).await.unwrap() {
+            Some(Message::NewConnection { connection_id, .. }) => connection_id,
+            other => panic!("expected NewConnection, got {:?}", other),
+        };
+        accept_connection(&server, CLIENT_ID, &connection_id).await;
+
+        // not due yet
+        let expires_at = Instant::now() + Duration::from_millis(200);
+        if let Some(client) = server.clients.write().await.get_mut(CLIENT_ID) {
+            client.proxies.get_mut(&proxy_id).unwrap().expires_at = Some(expires_at);
+        }
+        server.expire_proxies().await;
+        assert!(timeout(Duration::from_millis(50), rx.recv()).await.is_err());
+
+        tokio::time::sleep_until(expires_at.into()).await;
+        server.expire_proxies().await;
+        match rx.recv().await.unwrap() {
+            Message::CloseConnection {
+                connection_id: id, ..
+            } => assert_eq!(id, connection_id),
+            other => panic!("expected CloseConnection, got {:?}", other),
+        }
+        match rx.recv().await.unwrap() {
+            Message::ProxyExpired { proxy_id: id } => assert_eq!(id, proxy_id),
+            other => panic!("expected ProxyExpired, got {:?}", other),
+        }
+        assert_eq!(peer.read(&mut [0u8; 16]).await.unwrap(), 0);
+        assert!(server.clients.read().await[CLIENT_ID].proxies.is_empty());
+        assert!(!server.proxy_listeners.read().await.contains_key(&port));
+        assert!(server.proxy_connections.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_idle_proxy_connections_are_closed() {
+        let mut config = test_server().config;
+        config.idle_timeout = "300ms".parse().unwrap();
+        let server = Server::new(config).unwrap();
+        let client_id = Uuid::new_v4().to_string();
+        let mut rx = connect_fake_client(&server, &client_id).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut peers = Vec::new();
+        for connection_id in ["silent", "active"] {
+            let peer = TcpStream::connect(addr).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream_server = server.clone();
+            let stream_client_id = client_id.clone();
+            tokio::spawn(async move {
+                stream_server
+                    .handle_proxy_stream(
+                        stream,
+                        stream_client_id,
+                        "proxy".to_string(),
+                        connection_id.to_string(),
+                        Arc::default(),
+                        Vec::new(),
+                    )
+                    .await
+            });
+            accept_connection(&server, &client_id, connection_id).await;
+            peers.push(peer);
+        }
+
+        // only one side keeps talking, heartbeats or not
+        let mut active = peers.pop().unwrap();
+        for _ in 0..6 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            active.write_all(b"ping").await.unwrap();
+        }
+
+        let connections = server.proxy_connections.read().await;
+        assert!(!connections.contains_key("silent"));
+        assert!(connections.contains_key("active"));
+        drop(connections);
+
+        // the client is told, and the external peer sees the socket close
+        let mut closed = false;
+        while let Ok(message) = rx.try_recv() {
+            closed |= matches!(
+                message,
+                Message::CloseConnection { connection_id, .. } if connection_id == "silent"
+            );
+        }
+        assert!(closed);
+        let mut silent = peers.pop().unwrap();
+        assert_eq!(silent.read(&mut [0u8; 16]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_the_closing_side_reports_what_it_moved() {
+        let mut config = test_server().config;
+        config.idle_timeout = "300ms".parse().unwrap();
+        let server = Server::new(config).unwrap();
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut forwarded = Vec::new();
+        let mut peers = Vec::new();
+        for connection_id in ["idle", "closed"] {
+            let peer = TcpStream::connect(addr).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            let forward_server = server.clone();
+            forwarded.push(tokio::spawn(async move {
+                forward_server
+                    .forward_proxy_stream(
+                        stream,
+                        CLIENT_ID.to_string(),
+                        connection_id.to_string(),
+                        Arc::default(),
+                        Arc::default(),
+                        Vec::new(),
+                    )
+                    .await
+            }));
+            accept_connection(&server, CLIENT_ID, connection_id).await;
+            peers.push(peer);
+        }
+
+        // the client closing reports its end, which the server keeps
+        let client_end = TransferStats::new(7, 9, Duration::from_millis(120));
+        let close = Message::new_close_connection_with_stats(
+            "closed",
+            client_end,
+            CloseCode::PeerClosed,
+            None,
+        );
+        server
+            .handle_client_message(close, CLIENT_ID, "127.0.0.1")
+            .await
+            .unwrap();
+        let (reason, reported, code) = forwarded.pop().unwrap().await.unwrap();
+        assert_eq!(
+            (reason, reported, code),
+            (
+                CloseReason::ClientClosed,
+                Some(client_end),
+                CloseCode::PeerClosed
+            )
+        );
+        assert_eq!(peers.pop().unwrap().read(&mut [0u8; 1]).await.unwrap(), 0);
+
+        // the peer sends 5 bytes and receives 3 before falling silent
+        let mut peer = peers.pop().unwrap();
+        peer.write_all(b"hello").await.unwrap();
+        let data = Message::new_data("idle", 0, b"abc".to_vec());
+        server
+            .handle_client_message(data, CLIENT_ID, "127.0.0.1")
+            .await
+            .unwrap();
+        let mut received = [0u8; 3];
+        peer.read_exact(&mut received).await.unwrap();
+
+        // the server closes it and tells the client what its end moved, and why
+        let (stats, code) = loop {
+            match timeout(Duration::from_secs(2), rx.recv()).await.unwrap() {
+                Some(Message::CloseConnection {
+                    connection_id,
+                    stats,
+                    code,
+                    ..
+                }) if connection_id == "idle" => break (stats.unwrap(), code),
+                Some(_) => {}
+                None => panic!("client channel closed"),
+            }
+        };
+        assert_eq!((stats.bytes_in, stats.bytes_out), (5, 3));
+        assert!(stats.duration_ms >= 300);
+        assert_eq!(code, Some(CloseCode::IdleTimeout));
+        let (reason, reported, code) = forwarded.pop().unwrap().await.unwrap();
+        assert_eq!(
+            (reason, reported, code),
+            (CloseReason::IdleTimeout, None, CloseCode::IdleTimeout)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_a_stopping_server_closes_connections_with_its_code() {
+        let server = test_server();
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let stats = Arc::new(ProxyStats::default());
+        let forward_server = server.clone();
+        let forward_stats = stats.clone();
+        let forwarded = tokio::spawn(async move {
+            forward_server
+                .forward_proxy_stream(
+                    stream,
+                    CLIENT_ID.to_string(),
+                    "conn".to_string(),
+                    forward_stats,
+                    Arc::default(),
+                    Vec::new(),
+                )
+                .await
+        });
+        accept_connection(&server, CLIENT_ID, "conn").await;
+
+        server.close_connections(CloseCode::ServerShutdown).await;
+        server.cleanup_client(CLIENT_ID).await;
+        let code = loop {
+            match timeout(Duration::from_secs(2), rx.recv()).await.unwrap() {
+                Some(Message::CloseConnection {
+                    connection_id,
+                    code,
+                    ..
+                }) if connection_id == "conn" => break code,
+                Some(_) => {}
+                None => panic!("client channel closed"),
+            }
+        };
+        assert_eq!(code, Some(CloseCode::ServerShutdown));
+        let (_, _, code) = forwarded.await.unwrap();
+        assert_eq!(code, CloseCode::ServerShutdown);
+        assert_eq!(peer.read(&mut [0u8; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_peers_that_stop_reading_are_closed_as_slow_consumers() {
+        let limit = 256 * 1024;
+        let mut config = test_server().config;
+        config.max_buffered_per_connection = HumanBytes(limit as u64);
+        let server = Server::new(config).unwrap();
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        let _peer = socket
+            .connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let forward_server = server.clone();
+        let forwarded = tokio::spawn(async move {
+            forward_server
+                .forward_proxy_stream(
+                    stream,
+                    CLIENT_ID.to_string(),
+                    "slow".to_string(),
+                    Arc::default(),
+                    Arc::default(),
+                    Vec::new(),
+                )
+                .await
+        });
+        accept_connection(&server, CLIENT_ID, "slow").await;
+
+        // the client keeps sending, far more than the peer's socket takes
+        let backlog = server.proxy_connections.read().await["slow"]
+            .backlog
+            .clone();
+        let mut most_queued = 0;
+        for seq in 0..200 {
+            let data = Message::new_data("slow", seq, vec![0; MAX_DATA_PAYLOAD]);
+            server
+                .handle_client_message(data, CLIENT_ID, "127.0.0.1")
+                .await
+                .unwrap();
+            most_queued = most_queued.max(backlog.queued());
+            tokio::task::yield_now().await;
+        }
+        assert!(most_queued <= limit + MAX_DATA_PAYLOAD, "{}", most_queued);
+
+        // the client is told why
+        let (reason, code) = loop {
+            match timeout(Duration::from_secs(2), rx.recv()).await.unwrap() {
+                Some(Message::CloseConnection {
+                    connection_id,
+                    reason,
+                    code,
+                    ..
+                }) if connection_id == "slow" => break (reason, code),
+                Some(_) => {}
+                None => panic!("client channel closed"),
+            }
+        };
+        assert_eq!(reason.as_deref(), Some("slow consumer"));
+        assert_eq!(code, Some(CloseCode::PolicyLimit));
+        let (reason, _, _) = forwarded.await.unwrap();
+        assert_eq!(reason, CloseReason::SlowConsumer);
+        assert!(!server.proxy_connections.read().await.contains_key("slow"));
+    }
+
+    #[tokio::test]
+    async fn test_half_close_is_propagated() {
+        let server = test_server();
+        let client_id = Uuid::new_v4().to_string();
+        let mut rx = connect_fake_client(&server, &client_id).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let stream_server = server.clone();
+        let stream_client_id = client_id.clone();
+        let handle = tokio::spawn(async move {
+            stream_server
+                .handle_proxy_stream(
+                    stream,
+                    stream_client_id,
+                    "proxy".to_string(),
+                    "conn".to_string(),
+                    Arc::default(),
+                    Vec::new(),
+                )
+                .await
+        });
+
+        // the external peer sends a request and stops writing, before the client is ready
+        peer.write_all(b"request").await.unwrap();
+        peer.shutdown().await.unwrap();
+        accept_connection(&server, &client_id, "conn").await;
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            Message::Data { data, .. } if data == b"request"
+        ));
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            Message::ShutdownWrite { connection_id } if connection_id == "conn"
+        ));
+
+        // the response still reaches it, followed by EOF
+        assert!(server.proxy_connections.read().await.contains_key("conn"));
+        for message in [
+            Message::new_data("conn", 0, b"response".to_vec()),
+            Message::new_shutdown_write("conn"),
+        ] {
+            server
+                .handle_client_message(message, &client_id, "127.0.0.1")
+                .await
+                .unwrap();
+        }
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"response");
+
+        handle.await.unwrap();
+        assert!(!server.proxy_connections.read().await.contains_key("conn"));
+        // both sides shut down cleanly, nothing to close
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_data_out_of_sequence_closes_the_connection() {
+        let server = test_server();
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stats = Arc::new(ProxyStats::default());
+
+        for (connection_id, wrong_seq) in [("gap", 2), ("repeat", 0)] {
+            let mut peer = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream_server = server.clone();
+            let stream_stats = stats.clone();
+            let handle = tokio::spawn(async move {
+                stream_server
+                    .handle_proxy_stream(
+                        stream,
+                        CLIENT_ID.to_string(),
+                        "proxy".to_string(),
+                        connection_id.to_string(),
+                        stream_stats,
+                        Vec::new(),
+                    )
+                    .await
+            });
+            accept_connection(&server, CLIENT_ID, connection_id).await;
+
+            // the first message is in sequence and reaches the peer
+            let first = Message::new_data(connection_id, 0, b"first".to_vec());
+            server
+                .handle_client_message(first, CLIENT_ID, "127.0.0.1")
+                .await
+                .unwrap();
+            let mut received = [0u8; 5];
+            peer.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received, b"first");
+
+            // a skipped or repeated seq closes the connection on both ends
+            let stray = Message::new_data(connection_id, wrong_seq, b"stray".to_vec());
+            server
+                .handle_client_message(stray, CLIENT_ID, "127.0.0.1")
+                .await
+                .unwrap();
+            assert!(!server
+                .proxy_connections
+                .read()
+                .await
+                .contains_key(connection_id));
+            assert!(matches!(
+                rx.recv().await.unwrap(),
+                Message::CloseConnection { connection_id: closed, .. } if closed == connection_id
+            ));
+            let mut rest = Vec::new();
+            peer.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty(), "{:?}", rest);
+            handle.await.unwrap();
+        }
+        assert_eq!(stats.snapshot().out_of_sequence, 2);
+    }
+
+    #[tokio::test]
+    async fn test_messages_out_of_state_are_violations() {
+        let server = test_server();
+        let mut rx = connect_fake_client(&server, CLIENT_ID).await;
+        let state = |server: &Server| {
+            let server = server.clone();
+            async move {
+                let clients_guard = server.clients.read().await;
+                let client = &clients_guard[CLIENT_ID];
+                (client.state, client.violations)
+            }
+        };
+
+        // a second `Auth` and server-only messages are answered with an error each
+        let auth = Message::new_auth("legacy", &[0; 32], CLIENT_ID, None, vec![], false);
+        server
+            .handle_client_message(auth, CLIENT_ID, "127.0.0.1")
+            .await
+            .unwrap();
+        match rx.try_recv().unwrap() {
+            Message::Error { message } => assert_eq!(
+                message,
+                "Protocol violation: Unexpected Auth while authenticated"
+            ),
+            other => panic!("expected Error, got {:?}", other),
+        }
+        assert_eq!(state(&server).await, (SessionState::Authenticated, 1));
+        for _ in 1..session::MAX_PROTOCOL_VIOLATIONS {
+            let stray = Message::ProxyExpired {
+                proxy_id: "proxy".to_string(),
+            };
+            server
+                .handle_client_message(stray, CLIENT_ID, "127.0.0.1")
+                .await
+                .unwrap();
+            assert!(matches!(rx.try_recv().unwrap(), Message::Error { .. }));
+        }
+        assert_eq!(
+            state(&server).await,
+            (
+                SessionState::Authenticated,
+                session::MAX_PROTOCOL_VIOLATIONS
+            )
+        );
+
+        // one more closes the session
+        let stray = Message::HeartbeatResponse {
+            timestamp: 1,
+            stats: None,
+        };
+        assert!(server
+            .handle_client_message(stray, CLIENT_ID, "127.0.0.1")
+            .await
+            .is_err());
+        assert!(matches!(rx.try_recv().unwrap(), Message::Error { .. }));
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            Message::SessionClosed { .. }
+        ));
+        assert_eq!(state(&server).await.0, SessionState::Closing);
+
+        // a closing session only finishes its connections, anything else is dropped
+        server
+            .handle_client_message(Message::new_heartbeat(), CLIENT_ID, "127.0.0.1")
+            .await
+            .unwrap();
+        server
+            .handle_client_message(
+                Message::new_close_connection("conn", CloseCode::PeerClosed),
+                CLIENT_ID,
+                "127.0.0.1",
+            )
+            .await
+            .unwrap();
+        assert!(rx.try_recv().is_err());
+        assert_eq!(
+            state(&server).await,
+            (SessionState::Closing, session::MAX_PROTOCOL_VIOLATIONS + 1)
+        );
+
+        // nothing is taken from a client without a session
+        let data = Message::new_data("conn", 0, b"data".to_vec());
+        assert!(server
+            .handle_client_message(data, "client-2", "127.0.0.1")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_data_for_connections_not_of_the_client_is_refused() {
+        let server = test_server();
+        let _rx = connect_fake_client(&server, CLIENT_ID).await;
+        let mut other_rx = connect_fake_client(&server, "client-2").await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let stream_server = server.clone();
+        tokio::spawn(async move {
+            stream_server
+                .handle_proxy_stream(
+                    stream,
+                    CLIENT_ID.to_string(),
+                    "proxy".to_string(),
+                    "conn".to_string(),
+                    Arc::default(),
+                    Vec::new(),
+                )
+                .await
+        });
+        accept_connection(&server, CLIENT_ID, "conn").await;
+
+        // another client's data never reaches the peer
+        let injected = Message::new_data("conn", 0, b"injected".to_vec());
+        server
+            .handle_client_message(injected, "client-2", "127.0.0.1")
+            .await
+            .unwrap();
+        match other_rx.try_recv().unwrap() {
+            Message::Error { message } => assert_eq!(
+                message,
+                "Protocol violation: Data for a connection of another client"
+            ),
+            other => panic!("expected Error, got {:?}", other),
+        }
+        let owned = Message::new_data("conn", 0, b"owned".to_vec());
+        server
+            .handle_client_message(owned, CLIENT_ID, "127.0.0.1")
+            .await
+            .unwrap();
+        let mut received = [0u8; 5];
+        peer.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"owned");
+
+        // data for a connection the server does not know is answered with its close
+        let late = Message::new_data("gone", 3, b"late".to_vec());
+        server
+            .handle_client_message(late, "client-2", "127.0.0.1")
+            .await
+            .unwrap();
+        assert!(matches!(
+            other_rx.try_recv().unwrap(),
+            Message::CloseConnection { connection_id, .. } if connection_id == "gone"
+        ));
+        assert_eq!(server.clients.read().await["client-2"].violations, 1);
+    }
+
+    #[tokio::test]
+    async fn test_only_auth_is_taken_before_authenticating() {
+        let server = test_server();
+        let addr = spawn_control_listener(&server).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut reader = FrameReader::new();
+        let challenge = reader.read_frame(&mut stream).await.unwrap().unwrap();
+        assert!(matches!(challenge.message, Message::AuthChallenge { .. }));
+
+        let data = Message::new_data("conn", 0, b"early".to_vec());
+        stream
+            .write_all(&Frame::new(data).serialize().unwrap())
+            .await
+            .unwrap();
+        match reader
+            .read_frame(&mut stream)
+            .await
+            .unwrap()
+            .unwrap()
+            .message
+        {
+            Message::Error { message } => {
+                assert_eq!(message, "Protocol violation: Unexpected Data before Auth")
+            }
+            other => panic!("expected Error, got {:?}", other),
+        }
+        assert!(reader.read_frame(&mut stream).await.unwrap().is_none());
+        assert!(server.clients.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_bulk_transfer_is_bounded_by_window() {
+        use crate::utils::window::INITIAL_WINDOW;
+
+        let server = test_server();
+        let client_id = Uuid::new_v4().to_string();
+        let mut rx = connect_fake_client(&server, &client_id).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut peers = Vec::new();
+        for connection_id in ["bulk", "interactive"] {
+            let peer = TcpStream::connect(addr).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream_server = server.clone();
+            let stream_client_id = client_id.clone();
+            tokio::spawn(async move {
+                stream_server
+                    .handle_proxy_stream(
+                        stream,
+                        stream_client_id,
+                        "proxy".to_string(),
+                        connection_id.to_string(),
+                        Arc::default(),
+                        Vec::new(),
+                    )
+                    .await
+            });
+            accept_connection(&server, &client_id, connection_id).await;
+            peers.push(peer);
+        }
+
+        // the client does not acknowledge anything of a large upload yet
+        let mut interactive = peers.pop().unwrap();
+        let mut bulk = peers.pop().unwrap();
+        tokio::spawn(async move {
+            let _ = bulk.write_all(&vec![0u8; 4 * INITIAL_WINDOW]).await;
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // a small request is queued behind at most one window of bulk data
+        interactive.write_all(b"ping").await.unwrap();
+        let mut queued = 0;
+        loop {
+            match rx.recv().await.unwrap() {
+                Message::Data {
+                    connection_id,
+                    data,
+                    ..
+                } if connection_id == "bulk" => queued += data.len(),
+                Message::Data { data, .. } => {
+                    assert_eq!(data, b"ping");
+                    break;
+                }
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+        assert!(queued >= INITIAL_WINDOW);
+        assert!(queued <= INITIAL_WINDOW + 4096);
+
+        // the upload goes on once the client acknowledges it
+        let update = Message::new_window_update("bulk", INITIAL_WINDOW as u32);
+        server
+            .handle_client_message(update, &client_id, "127.0.0.1")
+            .await
+            .unwrap();
+        let resumed = timeout(Duration::from_secs(1), rx.recv()).await.unwrap();
+        assert!(matches!(
+            resumed.unwrap(),
+            Message::Data { connection_id, .. } if connection_id == "bulk"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_paused_connections_are_not_read() {
+        let server = test_server();
+        let client_id = Uuid::new_v4().to_string();
+        let mut rx = connect_fake_client(&server, &client_id).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let stream_server = server.clone();
+        let stream_client_id = client_id.clone();
+        tokio::spawn(async move {
+            stream_server
+                .handle_proxy_stream(
+                    stream,
+                    stream_client_id,
+                    "proxy".to_string(),
+                    "paused".to_string(),
+                    Arc::default(),
+                    Vec::new(),
+                )
+                .await
+        });
+        accept_connection(&server, &client_id, "paused").await;
+
+        let pause = Message::new_pause("paused");
+        server
+            .handle_client_message(pause, &client_id, "127.0.0.1")
+            .await
+            .unwrap();
+        peer.write_all(&[1; 64 * 1024]).await.unwrap();
+
+        // a read already waiting goes through, no more follow
+        let mut forwarded = 0;
+        while let Ok(Some(message)) = timeout(Duration::from_millis(200), rx.recv()).await {
+            if let Message::Data { data, .. } = message {
+                forwarded += data.len();
+            }
+        }
+        assert!(forwarded <= 4096, "{}", forwarded);
+
+        let resume = Message::new_resume("paused");
+        server
+            .handle_client_message(resume, &client_id, "127.0.0.1")
+            .await
+            .unwrap();
+        while forwarded < 64 * 1024 {
+            match timeout(Duration::from_secs(1), rx.recv()).await.unwrap() {
+                Some(Message::Data { data, .. }) => forwarded += data.len(),
+                Some(_) => {}
+                None => panic!("client channel closed"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_bind_host_whitelist() {
+        let mut config = test_server().config;
+        config.allowed_bind_hosts = vec!["10.0.0.5".to_string()];
+        let server = Server::new(config).unwrap();
+
+        assert!(server.bind_host_violation("127.0.0.1").is_none());
+        assert!(server.bind_host_violation("10.0.0.5").is_none());
+        assert_eq!(
+            server.bind_host_violation("0.0.0.0").unwrap(),
+            "Bind host 0.0.0.0 not permitted by server policy"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_visitor_connection_is_relayed_between_clients() {
+        use crate::utils::crypto::secret_hash;
+
+        let server = test_server();
+        let visitor_id = "5b0e3a94-2f6c-4d1a-9e8b-0c7d6f5a4b32";
+        let mut owner_rx = connect_fake_client(&server, CLIENT_ID).await;
+        let mut visitor_rx = connect_fake_client(&server, visitor_id).await;
+
+        let register = Message::ProxyConfig {
+            request_id: 1,
+            op: ProxyConfigOpCode::Update,
+            name: "ssh".to_string(),
+            local_ip: "127.0.0.1".to_string(),
+            local_port: 22,
+            remote_port: 0,
+            preferred_port: None,
+            bind_host: None,
+            http_host: None,
+            sni: None,
+            group: None,
+            max_connections: None,
+            secret_hash: Some(secret_hash("ssh", "between-us")),
+            relay_peers: None,
+            ttl_ms: None,
+        };
+        server
+            .handle_client_message(register, CLIENT_ID, "127.0.0.1")
+            .await
+            .unwrap();
+        let proxy_id = match owner_rx.recv().await.unwrap() {
+            Message::ProxyConfigResponse {
+                success: true,
+                proxy_id: Some(proxy_id),
+                assigned_port: None,
+                ..
+            } => proxy_id,
+            other => panic!("expected the secret service accepted, got {:?}", other),
+        };
+        assert!(server.proxy_listeners.read().await.is_empty());
+
+        let visit = |request_id, secret| Message::VisitorConnect {
+            request_id,
+            service_name: "ssh".to_string(),
+            secret_hash: secret_hash("ssh", secret),
+        };
+        server
+            .handle_client_message(visit(1, "guess"), visitor_id, "127.0.0.1")
+            .await
+            .unwrap();
+        match visitor_rx.recv().await.unwrap() {
+            Message::VisitorConnectResponse {
+                request_id: 1,
+                success: false,
+                ..
+            } => {}
+            other => panic!("expected the wrong secret refused, got {:?}", other),
+        }
+
+        server
+            .handle_client_message(visit(2, "between-us"), visitor_id, "127.0.0.1")
+            .await
+            .unwrap();
+        let connection_id = match owner_rx.recv().await.unwrap() {
+            Message::NewConnection {
+                proxy_id: asked,
+                connection_id,
+                ..
+            } if asked == proxy_id => connection_id,
+            other => panic!("expected a NewConnection, got {:?}", other),
+        };
+        let accepted = Message::ConnectionResponse {
+            connection_id: connection_id.clone(),
+            success: true,
+            error: None,
+        };
+        server
+            .handle_client_message(accepted, CLIENT_ID, "127.0.0.1")
+            .await
+            .unwrap();
+        match visitor_rx.recv().await.unwrap() {
+            Message::VisitorConnectResponse {
+                request_id: 2,
+                success: true,
+                connection_id: Some(opened),
+                ..
+            } if opened == connection_id => {}
+            other => panic!("expected the visitor connected, got {:?}", other),
+        }
+
+        // data passes both ways, the server keeps no proxy connection of its own
+        let banner = Message::new_data(&connection_id, 0, b"SSH-2.0".to_vec());
+        server
+            .handle_client_message(banner, CLIENT_ID, "127.0.0.1")
+            .await
+            .unwrap();
+        match visitor_rx.recv().await.unwrap() {
+            Message::Data { data, .. } => assert_eq!(data, b"SSH-2.0"),
+            other => panic!("expected the banner relayed, got {:?}", other),
+        }
+        let typed = Message::new_data(&connection_id, 0, b"ls\n".to_vec());
+        server
+            .handle_client_message(typed, visitor_id, "127.0.0.1")
+            .await
+            .unwrap();
+        match owner_rx.recv().await.unwrap() {
+            Message::Data { data, .. } => assert_eq!(data, b"ls\n"),
+            other => panic!("expected the input relayed, got {:?}", other),
+        }
+        assert!(server.proxy_connections.read().await.is_empty());
+
+        // the service's client leaving closes the visitor's end and frees the name
+        server.cleanup_client(CLIENT_ID).await;
+        match visitor_rx.recv().await.unwrap() {
+            Message::CloseConnection {
+                connection_id: closed,
+                ..
+            } if closed == connection_id => {}
+            other => panic!("expected the connection closed, got {:?}", other),
+        }
+        assert!(server.secret_services.read().await.is_empty());
+        assert!(server.visitor_links.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_relay_services_admit_their_peers_only() {
+        let peer_id = "5b0e3a94-2f6c-4d1a-9e8b-0c7d6f5a4b32";
+        let stranger_id = "9d4c2b1a-7e6f-4a3b-8c2d-1e0f9a8b7c6d";
+        let register = Message::ProxyConfig {
+            request_id: 1,
+            op: ProxyConfigOpCode::Update,
+            name: "ssh".to_string(),
+            local_ip: "127.0.0.1".to_string(),
+            local_port: 22,
+            remote_port: 0,
+            preferred_port: None,
+            bind_host: None,
+            http_host: None,
+            sni: None,
+            group: None,
+            max_connections: None,
+            secret_hash: None,
+            relay_peers: Some(vec!["laptop-a".to_string()]),
+            ttl_ms: None,
+        };
+
+        // refused unless the server allows relays
+        let server = test_server();
+        let mut owner_rx = connect_fake_client(&server, CLIENT_ID).await;
+        server
+            .handle_client_message(register.clone(), CLIENT_ID, "127.0.0.1")
+            .await
+            .unwrap();
+        match owner_rx.recv().await.unwrap() {
+            Message::ProxyConfigResponse {
+                success: false,
+                error: Some(error),
+                ..
+            } => assert!(error.contains("disabled"), "{}", error),
+            other => panic!("expected the relay service refused, got {:?}", other),
+        }
+
+        let mut config = test_server().config;
+        config.allow_relay = true;
+        let server = Server::new(config).unwrap();
+        let mut owner_rx = connect_fake_client(&server, CLIENT_ID).await;
+        let mut peer_rx = connect_fake_client(&server, peer_id).await;
+        let mut stranger_rx = connect_fake_client(&server, stranger_id).await;
+        for (client_id, name) in [(CLIENT_ID, "laptop-b"), (peer_id, "laptop-a")] {
+            server
+                .clients
+                .write()
+                .await
+                .get_mut(client_id)
+                .unwrap()
+                .name = Some(name.to_string());
+        }
+        server
+            .handle_client_message(register, CLIENT_ID, "127.0.0.1")
+            .await
+            .unwrap();
+        let proxy_id = match owner_rx.recv().await.unwrap() {
+            Message::ProxyConfigResponse {
+                success: true,
+                proxy_id: Some(proxy_id),
+                ..
+            } => proxy_id,
+            other => panic!("expected the relay service accepted, got {:?}", other),
+        };
+        assert!(server.proxy_listeners.read().await.is_empty());
+
+        let relay = |request_id, target: &str| Message::RelayConnect {
+            request_id,
+            target_client: target.to_string(),
+            target_service: "ssh".to_string(),
+        };
+        // a client not among the peers learns no more than of an unknown service
+        server
+            .handle_client_message(relay(1, "laptop-b"), stranger_id, "127.0.0.1")
+            .await
+            .unwrap();
+        match stranger_rx.recv().await.unwrap() {
+            Message::VisitorConnectResponse {
+                request_id: 1,
+                success: false,
+                error: Some(error),
+                ..
+            } => assert_eq!(error, "Unknown relay service or not one of its peers"),
+            other => panic!("expected the stranger refused, got {:?}", other),
+        }
+
+        // the owner is found by name or ID alike
+        for (request_id, target) in [(2, "laptop-b"), (3, CLIENT_ID)] {
+            server
+                .handle_client_message(relay(request_id, target), peer_id, "127.0.0.1")
+                .await
+                .unwrap();
+            match owner_rx.recv().await.unwrap() {
+                Message::NewConnection {
+                    proxy_id: asked, ..
+                } => assert_eq!(asked, proxy_id),
+                other => panic!("expected a NewConnection, got {:?}", other),
+            }
+        }
+        assert!(peer_rx.try_recv().is_err());
+        assert_eq!(server.visitor_links.read().await.len(), 2);
+
+        // the owner leaving takes the service and its pending links along
+        server.cleanup_client(CLIENT_ID).await;
+        assert!(server.relay_services.read().await.is_empty());
+        assert!(server.visitor_links.read().await.is_empty());
+    }
 }
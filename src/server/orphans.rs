@@ -0,0 +1,149 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::time::{timeout_at, Instant};
+
+use super::connection_limit::ConnectionPermit;
+use super::{Server, ServingProxy};
+use crate::config::OrphanConnectionPolicy;
+use crate::{log_debug, log_info};
+
+/// Connections accepted on a listener while no session of its client serves it, held
+/// for the client to reclaim the listener with `orphan_connection_policy = "queue"`
+#[derive(Debug, Default)]
+pub(super) struct OrphanQueue {
+    /// Connections held
+    waiting: AtomicUsize,
+    /// Set once the listener stopped, nothing held is served anymore
+    closed: AtomicBool,
+    /// Signalled when a proxy joins the listener or it stops
+    changed: Notify,
+    /// Set while connections are held whatever `orphan_connection_policy` says
+    hold: AtomicBool,
+}
+
+impl OrphanQueue {
+    /// A queue holding connections even with the `refuse` policy, for a listener inherited
+    /// from an upgraded server whose clients have not had the chance to reconnect yet
+    pub(super) fn holding() -> Self {
+        Self {
+            hold: AtomicBool::new(true),
+            ..Self::default()
+        }
+    }
+
+    /// Follows `orphan_connection_policy` from now on, a client reclaimed the listener
+    pub(super) fn settle(&self) {
+        self.hold.store(false, Ordering::Release);
+    }
+
+    /// Takes a place in the queue unless `limit` connections are held already
+    fn enter(self: &Arc<Self>, limit: usize) -> Option<QueuedConnection> {
+        self.waiting
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |waiting| {
+                (waiting < limit).then_some(waiting + 1)
+            })
+            .ok()?;
+        Some(QueuedConnection(self.clone()))
+    }
+
+    /// Wakes the connections held, a proxy may serve them now
+    pub(super) fn wake(&self) {
+        self.changed.notify_waiters();
+    }
+
+    /// Refuses the connections held, the listener stopped
+    pub(super) fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.changed.notify_waiters();
+    }
+}
+
+/// A place in an `OrphanQueue`, given back when dropped
+struct QueuedConnection(Arc<OrphanQueue>);
+
+impl Drop for QueuedConnection {
+    fn drop(&mut self) {
+        self.0.waiting.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Server {
+    /// Handles a connection accepted on `port` while no session serves it, as
+    /// `orphan_connection_policy` says: it is closed at once, or held in `orphans` until
+    /// the client returns within `orphan_queue_timeout`. A holding queue always holds it
+    pub(super) fn handle_orphan_connection(
+        &self,
+        stream: TcpStream,
+        addr: SocketAddr,
+        port: u16,
+        orphans: &Arc<OrphanQueue>,
+        permit: ConnectionPermit,
+    ) {
+        if self.config.orphan_connection_policy == OrphanConnectionPolicy::Refuse
+            && !orphans.hold.load(Ordering::Acquire)
+        {
+            log_info!(
+                "No connected client serves port {}, refusing connection from {}",
+                port,
+                addr
+            );
+            return;
+        }
+        let Some(queued) = orphans.enter(self.config.orphan_queue_size) else {
+            log_info!(
+                "Port {} already holds {} connections for its client, refusing connection from {}",
+                port,
+                self.config.orphan_queue_size,
+                addr
+            );
+            return;
+        };
+        log_debug!(
+            "No connected client serves port {}, holding connection from {}",
+            port,
+            addr
+        );
+        let server = self.clone();
+        let orphans = orphans.clone();
+        tokio::spawn(async move {
+            match server.await_serving_proxy(port, &orphans).await {
+                Some(serving) => {
+                    drop(queued);
+                    server
+                        .dispatch_proxy_connection(stream, addr, serving, permit)
+                        .await;
+                }
+                None => {
+                    log_info!(
+                        "No client served port {} within {}, refusing connection from {}",
+                        port,
+                        server.config.orphan_queue_timeout,
+                        addr
+                    );
+                }
+            }
+        });
+    }
+
+    /// Waits up to `orphan_queue_timeout` for a session to serve `port`, None if none did
+    /// in time or the listener stopped
+    async fn await_serving_proxy(&self, port: u16, orphans: &OrphanQueue) -> Option<ServingProxy> {
+        let deadline = Instant::now() + self.config.orphan_queue_timeout.0;
+        loop {
+            // registered before looking, a proxy joining in between still wakes it
+            let changed = orphans.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            if orphans.closed.load(Ordering::Acquire) {
+                return None;
+            }
+            if let Some(serving) = self.serving_proxy(port).await {
+                return Some(serving);
+            }
+            timeout_at(deadline, changed).await.ok()?;
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use anyhow::Result;
+use quinn::{Connection, Endpoint, Incoming};
+use tokio::sync::oneshot;
+use tokio::time::{timeout, Duration};
+
+use super::Server;
+use crate::utils::quic;
+use crate::{debug, error, log_debug, log_info};
+
+/// How long a QUIC client may take for its handshake and for opening the control stream
+const QUIC_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a data stream may take to send its header
+const STREAM_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl Server {
+    /// Accepts clients on the QUIC endpoint, each served like one on the control port
+    pub(super) async fn serve_quic(&self, endpoint: Endpoint) {
+        if let Ok(addr) = endpoint.local_addr() {
+            log_info!("Accepting QUIC clients on {}", addr);
+        }
+        while let Some(incoming) = endpoint.accept().await {
+            let server = self.clone();
+            tokio::spawn(async move {
+                let addr = incoming.remote_address();
+                if let Err(e) = server.handle_quic_client(incoming).await {
+                    error!("Error handling QUIC client {}: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    /// Completes the handshake of a QUIC client and serves its session on the first stream
+    /// it opens, the streams opened after authenticating each carry a proxy connection
+    async fn handle_quic_client(&self, incoming: Incoming) -> Result<()> {
+        let addr = incoming.remote_address();
+        if self.is_banned(addr.ip()) {
+            log_debug!("Dropping QUIC connection from banned {}", addr);
+            incoming.refuse();
+            return Ok(());
+        }
+        log_debug!("New QUIC client connection from {}", addr);
+
+        let connection = timeout(QUIC_HANDSHAKE_TIMEOUT, incoming)
+            .await
+            .map_err(|_| anyhow::anyhow!("QUIC handshake with {} timed out", addr))?
+            .map_err(|e| anyhow::anyhow!("QUIC handshake with {} failed: {}", addr, e))?;
+        let peer_identity = quic::peer_identity(&connection);
+
+        let (send, mut recv) = timeout(QUIC_HANDSHAKE_TIMEOUT, connection.accept_bi())
+            .await
+            .map_err(|_| anyhow::anyhow!("No control stream from {}", addr))??;
+        if !quic::read_stream_header(&mut recv).await?.is_empty() {
+            return Err(anyhow::anyhow!(
+                "QUIC client {} opened a data stream before authenticating",
+                addr
+            ));
+        }
+
+        let (authenticated_tx, authenticated_rx) = oneshot::channel();
+        let streams = {
+            let server = self.clone();
+            let connection = connection.clone();
+            tokio::spawn(async move {
+                if let Ok(client_id) = authenticated_rx.await {
+                    server.accept_data_streams(connection, client_id).await;
+                }
+            })
+        };
+        let served = self
+            .serve_session(
+                quic::join(send, recv),
+                Vec::new(),
+                peer_identity,
+                addr,
+                Some(authenticated_tx),
+            )
+            .await;
+        streams.abort();
+        connection.close(0u32.into(), b"session ended");
+        served
+    }
+
+    /// Hands each stream the client opens to the proxy connection named in its header
+    async fn accept_data_streams(&self, connection: Connection, client_id: String) {
+        while let Ok((send, mut recv)) = connection.accept_bi().await {
+            let server = self.clone();
+            let client_id = client_id.clone();
+            tokio::spawn(async move {
+                match timeout(STREAM_HEADER_TIMEOUT, quic::read_stream_header(&mut recv)).await {
+                    Ok(Ok(connection_id)) => {
+                        server
+                            .deliver_data_stream(&client_id, &connection_id, quic::join(send, recv))
+                            .await;
+                    }
+                    Ok(Err(e)) => {
+                        debug!("Unreadable stream header from client {}: {}", client_id, e);
+                    }
+                    Err(_) => {
+                        debug!("No stream header from client {} in time", client_id);
+                    }
+                }
+            });
+        }
+    }
+}
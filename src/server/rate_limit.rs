@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Token bucket of one peer IP
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Connections dropped since the last sweep
+    dropped: u64,
+}
+
+/// Limits how often each peer IP may open proxy connections, so a scanner hammering
+/// an exposed port does not flood clients with `NewConnection`s and local dials
+#[derive(Debug)]
+pub struct ConnectionRateLimiter {
+    /// Bucket size, also the number of tokens refilled per minute
+    per_minute: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl ConnectionRateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute: f64::from(per_minute),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for a new connection from `ip`, false if it is over the limit
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        self.allow_at(ip, Instant::now())
+    }
+
+    fn allow_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.per_minute,
+            updated: now,
+            dropped: 0,
+        });
+        self.refill(bucket, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            bucket.dropped += 1;
+            false
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_minute / 60.0).min(self.per_minute);
+        bucket.updated = now;
+    }
+
+    /// Returns the IPs that had connections dropped since the last sweep, with their counts,
+    /// and forgets IPs whose bucket refilled completely
+    pub fn sweep(&self) -> Vec<(IpAddr, u64)> {
+        self.sweep_at(Instant::now())
+    }
+
+    fn sweep_at(&self, now: Instant) -> Vec<(IpAddr, u64)> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut dropped = Vec::new();
+        buckets.retain(|ip, bucket| {
+            self.refill(bucket, now);
+            if bucket.dropped > 0 {
+                dropped.push((*ip, std::mem::take(&mut bucket.dropped)));
+            }
+            bucket.tokens < self.per_minute
+        });
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_limits_each_ip_and_forgets_idle_ones() {
+        let limiter = ConnectionRateLimiter::new(2);
+        let scanner: IpAddr = "203.0.113.7".parse().unwrap();
+        let other: IpAddr = "198.51.100.1".parse().unwrap();
+        let start = Instant::now();
+
+        assert!(limiter.allow_at(scanner, start));
+        assert!(limiter.allow_at(scanner, start));
+        assert!(!limiter.allow_at(scanner, start));
+        assert!(!limiter.allow_at(scanner, start));
+        assert!(limiter.allow_at(other, start));
+
+        // one token is back after half a minute
+        let later = start + Duration::from_secs(30);
+        assert!(limiter.allow_at(scanner, later));
+        assert!(!limiter.allow_at(scanner, later));
+
+        // the other IP's bucket refilled meanwhile and is evicted
+        assert_eq!(limiter.sweep_at(later), vec![(scanner, 3)]);
+        assert!(limiter.sweep_at(later).is_empty());
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+
+        limiter.sweep_at(start + Duration::from_secs(120));
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+}
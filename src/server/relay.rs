@@ -0,0 +1,167 @@
+use uuid::Uuid;
+
+use super::{ProxyInfo, Server};
+use crate::logging::format_uuid;
+use crate::utils::protocol::ProxyConfigOpCode;
+use crate::utils::{net, Message};
+use crate::{log_info, warn};
+
+/// A service reached by other clients through the server instead of a listener, by the
+/// clients named in its `relay_peers` only
+#[derive(Clone)]
+pub(super) struct RelayService {
+    client_id: String,
+    name: String,
+    /// IDs or names of the clients that may connect
+    peers: Vec<String>,
+}
+
+impl RelayService {
+    /// Whether the client of `client_id`, named `name` if it is, is one of the peers
+    fn admits(&self, client_id: &str, name: Option<&str>) -> bool {
+        self.peers
+            .iter()
+            .any(|peer| peer == client_id || Some(peer.as_str()) == name)
+    }
+}
+
+impl Server {
+    /// Handles a `ProxyConfig` for a relay service, registering it under the client's
+    /// proxies without binding a listener
+    pub(super) async fn setup_relay_service(
+        &self,
+        op: ProxyConfigOpCode,
+        proxy_info: ProxyInfo,
+        peers: Vec<String>,
+        client_id: &str,
+        request_id: u64,
+    ) {
+        let name = proxy_info.name.clone();
+        let mut services = self.relay_services.write().await;
+        let mut clients_guard = self.clients.write().await;
+        let Some(client) = clients_guard.get_mut(client_id) else {
+            return;
+        };
+        // re-registering replaces the client's previous proxy for this name
+        let previous: Vec<String> = services
+            .iter()
+            .filter(|(_, service)| service.client_id == client_id && service.name == name)
+            .map(|(proxy_id, _)| proxy_id.clone())
+            .collect();
+        for proxy_id in &previous {
+            services.remove(proxy_id);
+            client.proxies.remove(proxy_id);
+        }
+
+        if op == ProxyConfigOpCode::Delete {
+            if !previous.is_empty() {
+                log_info!(
+                    "Relay service '{}' removed by client {}",
+                    name,
+                    client.label()
+                );
+            }
+            return;
+        }
+
+        let response = if !self.config.allow_relay {
+            let reason = "Relay services are disabled on this server".to_string();
+            warn!(
+                "Rejected relay service '{}' for client {}: {}",
+                name,
+                format_uuid(client_id, "client"),
+                reason
+            );
+            Message::ProxyConfigResponse {
+                request_id,
+                success: false,
+                proxy_id: None,
+                error: Some(reason),
+                assigned_port: None,
+            }
+        } else {
+            let proxy_id = Uuid::new_v4().to_string();
+            log_info!(
+                "Relay service '{}' for client {}: {} (peers: {})",
+                name,
+                client.label(),
+                net::join_host_port(&proxy_info.local_ip, proxy_info.local_port),
+                peers.join(", ")
+            );
+            services.insert(
+                proxy_id.clone(),
+                RelayService {
+                    client_id: client_id.to_string(),
+                    name,
+                    peers,
+                },
+            );
+            self.notify_proxy_registered(client, &proxy_info, None);
+            client.proxies.insert(proxy_id.clone(), proxy_info);
+            Message::ProxyConfigResponse {
+                request_id,
+                success: true,
+                proxy_id: Some(proxy_id),
+                error: None,
+                assigned_port: None,
+            }
+        };
+        let _ = client.sender.send(response);
+    }
+
+    /// Handles a `RelayConnect`, asking the client of the relay service for a connection
+    /// like for a visitor of a secret service, see `connect_visitor`
+    pub(super) async fn connect_relay(
+        &self,
+        client_id: &str,
+        request_id: u64,
+        target_client: &str,
+        target_service: &str,
+    ) {
+        let found = {
+            let services = self.relay_services.read().await;
+            let clients_guard = self.clients.read().await;
+            let name_of = |id: &str| clients_guard.get(id).and_then(|client| client.name.clone());
+            let requester = name_of(client_id);
+            // an unknown service and one the client may not reach look the same to it
+            services
+                .iter()
+                .find(|(_, service)| {
+                    service.name == target_service
+                        && (service.client_id == target_client
+                            || name_of(&service.client_id).as_deref() == Some(target_client))
+                        && service.admits(client_id, requester.as_deref())
+                })
+                .map(|(proxy_id, service)| (proxy_id.clone(), service.client_id.clone()))
+        };
+        let found = match found {
+            _ if !self.config.allow_relay => {
+                Err("Relay services are disabled on this server".to_string())
+            }
+            Some((_, owner_id)) if owner_id == client_id => {
+                Err("A client cannot relay to its own service".to_string())
+            }
+            Some(found) => Ok(found),
+            None => Err("Unknown relay service or not one of its peers".to_string()),
+        };
+        let (proxy_id, owner_id) = match found {
+            Ok(found) => found,
+            Err(reason) => {
+                warn!(
+                    "Refused relay from client {} to '{}' of {}: {}",
+                    format_uuid(client_id, "client"),
+                    target_service,
+                    target_client,
+                    reason
+                );
+                self.answer_visitor(client_id, request_id, Err(reason))
+                    .await;
+                return;
+            }
+        };
+
+        let service_desc = format!("relay service '{}'", target_service);
+        self.link_visitor(client_id, request_id, &owner_id, &proxy_id, &service_desc)
+            .await;
+    }
+}
@@ -0,0 +1,186 @@
+use std::time::Duration;
+
+use super::Server;
+use crate::logging::format_uuid;
+use crate::utils::{CloseCode, Message, Route};
+use crate::{console_info, log_debug, log_info};
+
+impl Server {
+    /// Keeps the established connections of a lost session of `client_id` that are carried
+    /// in `Data` messages for `grace`, for its next session to resume them. The others are
+    /// closed, as are all of them without a `grace` period
+    pub(super) async fn detach_client_connections(&self, client_id: &str, grace: Duration) {
+        let mut proxy_connections_guard = self.proxy_connections.write().await;
+        let mut connections_to_remove = Vec::new();
+        let mut kept = 0;
+
+        for (connection_id, connection_info) in proxy_connections_guard.iter() {
+            if connection_info.client_id != client_id {
+                continue;
+            }
+            let resumable = connection_info
+                .replay
+                .as_ref()
+                .filter(|_| !grace.is_zero() && connection_info.response.is_none());
+            let Some(replay) = resumable else {
+                connections_to_remove.push(connection_id.clone());
+                continue;
+            };
+            let detached = replay.detach();
+            kept += 1;
+            let server = self.clone();
+            let connection_id = connection_id.clone();
+            tokio::spawn(async move {
+                server
+                    .expire_detached_connection(&connection_id, detached, grace)
+                    .await
+            });
+        }
+        if kept > 0 {
+            log_info!(
+                "Keeping {} connections of client {} for it to resume within {:?}",
+                kept,
+                format_uuid(client_id, "client"),
+                grace
+            );
+        }
+
+        for connection_id in connections_to_remove {
+            if let Some(proxy_conn) = proxy_connections_guard.remove(&connection_id) {
+                if let Some(replay) = proxy_conn.replay {
+                    replay.abandon();
+                }
+                log_info!(
+                    "Cleaned up proxy connection {} for client {}",
+                    connection_id,
+                    client_id
+                );
+                console_info!(
+                    "Cleaned up connection {} for client {}",
+                    format_uuid(&connection_id, "conn"),
+                    format_uuid(client_id, "client")
+                );
+            }
+        }
+    }
+
+    /// Closes a connection kept by `detach_client_connections` once `grace` passed, unless
+    /// a session of its client resumed it since its detachment `detached`
+    async fn expire_detached_connection(
+        &self,
+        connection_id: &str,
+        detached: u64,
+        grace: Duration,
+    ) {
+        tokio::time::sleep(grace).await;
+        let mut proxy_connections_guard = self.proxy_connections.write().await;
+        let expired = proxy_connections_guard
+            .get(connection_id)
+            .and_then(|proxy_conn| proxy_conn.replay.as_ref())
+            .is_some_and(|replay| replay.still_detached(detached));
+        if !expired {
+            return;
+        }
+        if let Some(proxy_conn) = proxy_connections_guard.remove(connection_id) {
+            if let Some(replay) = proxy_conn.replay {
+                replay.abandon();
+            }
+            log_info!(
+                "Closed connection {} of client {}, not resumed within {:?}",
+                connection_id,
+                format_uuid(&proxy_conn.client_id, "client"),
+                grace
+            );
+        }
+    }
+
+    /// Handles a `ResumeConnection` from the new session of `client_id`: answers with what
+    /// the server received and sends again what the client did not, or closes a connection
+    /// that cannot be resumed
+    pub(super) async fn resume_connection(
+        &self,
+        client_id: &str,
+        connection_id: &str,
+        received: u64,
+        acknowledged: u64,
+    ) {
+        let Some(route) = self
+            .clients
+            .read()
+            .await
+            .get(client_id)
+            .map(|client| Route {
+                sender: client.sender.clone(),
+                compression: client.compression,
+            })
+        else {
+            return;
+        };
+
+        let outcome = {
+            let proxy_connections_guard = self.proxy_connections.read().await;
+            let proxy_conn = proxy_connections_guard
+                .get(connection_id)
+                .filter(|proxy_conn| proxy_conn.client_id == client_id);
+            match proxy_conn.map(|proxy_conn| (proxy_conn, proxy_conn.replay.as_ref())) {
+                None => Err("Unknown connection".to_string()),
+                Some((_, None)) => Err("The connection cannot be resumed".to_string()),
+                Some((_, Some(replay))) if replay.is_attached() => {
+                    Err("The connection was not detached".to_string())
+                }
+                Some((proxy_conn, Some(replay))) => {
+                    let _ = route.sender.send(replay.resume_message());
+                    let sender = route.sender.clone();
+                    replay.reattach(route, received, acknowledged).map(|lost| {
+                        // updates lost with the session are granted, a pause asked again
+                        proxy_conn.window.grant(lost);
+                        proxy_conn.window.resume();
+                        if proxy_conn.backlog.is_paused() {
+                            let _ = sender.send(Message::new_pause(connection_id));
+                        }
+                    })
+                }
+            }
+        };
+
+        match outcome {
+            Ok(()) => {
+                log_debug!(
+                    "Client {} resumed connection {}",
+                    format_uuid(client_id, "client"),
+                    connection_id
+                );
+            }
+            Err(reason) => {
+                log_info!(
+                    "Could not resume connection {} of client {}: {}",
+                    connection_id,
+                    format_uuid(client_id, "client"),
+                    reason
+                );
+                let mut proxy_connections_guard = self.proxy_connections.write().await;
+                let owned = proxy_connections_guard
+                    .get(connection_id)
+                    .is_some_and(|proxy_conn| proxy_conn.client_id == client_id);
+                if let Some(proxy_conn) = owned
+                    .then(|| proxy_connections_guard.remove(connection_id))
+                    .flatten()
+                {
+                    let _ = proxy_conn.close_code.set(CloseCode::LocalError);
+                    if let Some(replay) = proxy_conn.replay {
+                        replay.abandon();
+                    }
+                }
+                drop(proxy_connections_guard);
+                if let Some(client) = self.clients.read().await.get(client_id) {
+                    let _ = client.sender.send(Message::CloseConnection {
+                        connection_id: connection_id.to_string(),
+                        stats: None,
+                        reason: Some(format!("Could not resume the connection: {}", reason)),
+                        code: Some(CloseCode::LocalError),
+                    });
+                }
+            }
+        }
+    }
+}
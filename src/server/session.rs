@@ -0,0 +1,221 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+use super::{ClientConnection, Server};
+use crate::logging::format_uuid;
+use crate::utils::{CloseCode, Message};
+use crate::{log_debug, warn};
+
+/// Protocol violations a session is forgiven, the next one closes it
+pub(super) const MAX_PROTOCOL_VIOLATIONS: u32 = 3;
+
+/// Where a client's control connection stands, deciding which messages it may send
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum SessionState {
+    /// Challenged, nothing but an `Auth` or a `DataChannelHello` is taken
+    AwaitingAuth,
+    /// The session is up and takes every message a client sends
+    Authenticated,
+    /// The server ends the session, only messages finishing its connections are taken
+    Closing,
+}
+
+impl SessionState {
+    /// Whether a client may send `message` in this state
+    pub(super) fn allows(self, message: &Message) -> bool {
+        match self {
+            SessionState::AwaitingAuth => {
+                matches!(
+                    message,
+                    Message::Auth { .. } | Message::DataChannelHello { .. }
+                )
+            }
+            SessionState::Authenticated => {
+                matches!(
+                    message,
+                    Message::ProxyConfig { .. }
+                        | Message::Heartbeat { .. }
+                        | Message::VisitorConnect { .. }
+                        | Message::RelayConnect { .. }
+                        | Message::ServiceStatus { .. }
+                        | Message::Error { .. }
+                ) || message.connection_id().is_some()
+            }
+            SessionState::Closing => message.connection_id().is_some(),
+        }
+    }
+}
+
+impl fmt::Display for SessionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SessionState::AwaitingAuth => "awaiting auth",
+            SessionState::Authenticated => "authenticated",
+            SessionState::Closing => "closing",
+        })
+    }
+}
+
+impl ClientConnection {
+    /// Tells the client its session ends for `reason`. Until it is cleaned up, the session
+    /// only takes the messages finishing its connections
+    pub(super) fn close(&mut self, reason: &str) {
+        self.state = SessionState::Closing;
+        let _ = self.sender.send(Message::SessionClosed {
+            reason: reason.to_string(),
+        });
+    }
+}
+
+impl Server {
+    /// Whether the session of `client_id` takes `message` in its state. A message the state
+    /// does not allow is a protocol violation while authenticated and dropped while closing.
+    /// Fails once the client committed too many violations, or has no session at all
+    pub(super) async fn admit_message(&self, message: &Message, client_id: &str) -> Result<bool> {
+        let state = match self.clients.read().await.get(client_id) {
+            Some(client) => client.state,
+            None => {
+                warn!(
+                    "Protocol violation by client {} without a session: {}",
+                    format_uuid(client_id, "client"),
+                    message.kind()
+                );
+                return Err(anyhow!("Client {} has no session", client_id));
+            }
+        };
+        if state.allows(message) {
+            return Ok(true);
+        }
+        if state == SessionState::Closing {
+            log_debug!(
+                "Dropping {} from client {}, its session is closing",
+                message.kind(),
+                client_id
+            );
+            return Ok(false);
+        }
+        let violation = format!("Unexpected {} while {}", message.kind(), state);
+        self.protocol_violation(client_id, &violation).await?;
+        Ok(false)
+    }
+
+    /// Whether a message about `connection_id` from `client_id` is about one of its own
+    /// connections. Data for a connection the server no longer knows, most likely closed
+    /// while the data was on its way, is answered with a `CloseConnection`; a message about
+    /// another client's connection is a protocol violation
+    pub(super) async fn admit_connection_message(
+        &self,
+        message: &Message,
+        client_id: &str,
+    ) -> Result<bool> {
+        let Some(connection_id) = message.connection_id() else {
+            return Ok(true);
+        };
+        let owner = self
+            .proxy_connections
+            .read()
+            .await
+            .get(connection_id)
+            .map(|proxy_conn| proxy_conn.client_id.clone());
+        match owner {
+            Some(owner) if owner == client_id => Ok(true),
+            // a client given a new ID by the server cannot know its connections are gone
+            Some(_) | None if matches!(message, Message::ResumeConnection { .. }) => {
+                if let Some(client) = self.clients.read().await.get(client_id) {
+                    let _ = client.sender.send(Message::new_close_connection(
+                        connection_id,
+                        CloseCode::LocalError,
+                    ));
+                }
+                Ok(false)
+            }
+            Some(_) => {
+                let violation = format!("{} for a connection of another client", message.kind());
+                self.protocol_violation(client_id, &violation).await?;
+                Ok(false)
+            }
+            None if matches!(
+                message,
+                Message::Data { .. } | Message::CompressedData { .. }
+            ) =>
+            {
+                log_debug!(
+                    "Refusing {} from client {} for unknown connection {}",
+                    message.kind(),
+                    client_id,
+                    connection_id
+                );
+                if let Some(client) = self.clients.read().await.get(client_id) {
+                    let _ = client.sender.send(Message::new_close_connection(
+                        connection_id,
+                        CloseCode::LocalError,
+                    ));
+                }
+                Ok(false)
+            }
+            // closing or answering a connection already gone changes nothing
+            None => Ok(true),
+        }
+    }
+
+    /// Reports a protocol violation to the client and counts it. The violation past
+    /// `MAX_PROTOCOL_VIOLATIONS` closes the session, failing with why
+    async fn protocol_violation(&self, client_id: &str, violation: &str) -> Result<()> {
+        let mut clients_guard = self.clients.write().await;
+        let Some(client) = clients_guard.get_mut(client_id) else {
+            return Ok(());
+        };
+        client.violations += 1;
+        warn!(
+            "Protocol violation by client {} in state {}: {}",
+            client.label(),
+            client.state,
+            violation
+        );
+        let _ = client.sender.send(Message::Error {
+            message: format!("Protocol violation: {}", violation),
+        });
+        if client.violations > MAX_PROTOCOL_VIOLATIONS {
+            client.close("Too many protocol violations");
+            return Err(anyhow!(
+                "Closing the session of client {} after {} protocol violations",
+                client_id,
+                client.violations
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_states_allow_their_messages() {
+        let auth = Message::new_auth("token", &[0; 32], "client", None, vec![], false);
+        let heartbeat = Message::new_heartbeat();
+        let data = Message::new_data("conn", 0, vec![1]);
+        let close = Message::new_close_connection("conn", CloseCode::PeerClosed);
+        let server_only = Message::SessionClosed {
+            reason: "bye".to_string(),
+        };
+
+        let allowed = |state: SessionState| {
+            [&auth, &heartbeat, &data, &close, &server_only].map(|message| state.allows(message))
+        };
+        assert_eq!(
+            allowed(SessionState::AwaitingAuth),
+            [true, false, false, false, false]
+        );
+        assert_eq!(
+            allowed(SessionState::Authenticated),
+            [false, true, true, true, false]
+        );
+        assert_eq!(
+            allowed(SessionState::Closing),
+            [false, false, true, true, false]
+        );
+        assert_eq!(SessionState::AwaitingAuth.to_string(), "awaiting auth");
+    }
+}
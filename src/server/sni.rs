@@ -0,0 +1,196 @@
+/// ClientHellos larger than this are refused
+pub const MAX_HELLO_LEN: usize = 64 * 1024;
+
+const RECORD_HEADER_LEN: usize = 5;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// Result of inspecting the first bytes of a TLS connection
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientHello {
+    /// More bytes are needed to see the whole ClientHello
+    Incomplete,
+    /// The ClientHello is complete, with its `server_name` if one was sent
+    Complete(Option<String>),
+}
+
+/// Reads the SNI `server_name` from the ClientHello at the start of `data`.
+/// The hello may be fragmented over several handshake records, which are reassembled.
+pub fn parse_client_hello(data: &[u8]) -> Result<ClientHello, String> {
+    let mut handshake = Vec::new();
+    let mut rest = data;
+
+    // reassemble handshake records until the whole ClientHello is there
+    loop {
+        if handshake.len() >= 4 {
+            let hello_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]);
+            if handshake.len() >= 4 + hello_len as usize {
+                handshake.truncate(4 + hello_len as usize);
+                break;
+            }
+        }
+        if rest.len() < RECORD_HEADER_LEN {
+            return Ok(ClientHello::Incomplete);
+        }
+        if rest[0] != CONTENT_TYPE_HANDSHAKE {
+            return Err(format!(
+                "Not a TLS handshake record (type {:#04x})",
+                rest[0]
+            ));
+        }
+        let record_len = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+        if rest.len() < RECORD_HEADER_LEN + record_len {
+            return Ok(ClientHello::Incomplete);
+        }
+        handshake.extend_from_slice(&rest[RECORD_HEADER_LEN..RECORD_HEADER_LEN + record_len]);
+        rest = &rest[RECORD_HEADER_LEN + record_len..];
+    }
+
+    if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+        return Err(format!(
+            "Expected a ClientHello, got handshake type {}",
+            handshake[0]
+        ));
+    }
+    let mut hello = Reader(&handshake[4..]);
+    hello.skip(2 + 32)?; // legacy_version, random
+    let session_id_len = hello.u8()? as usize;
+    hello.skip(session_id_len)?;
+    let cipher_suites_len = hello.u16()? as usize;
+    hello.skip(cipher_suites_len)?;
+    let compression_methods_len = hello.u8()? as usize;
+    hello.skip(compression_methods_len)?;
+    if hello.0.is_empty() {
+        // no extensions at all
+        return Ok(ClientHello::Complete(None));
+    }
+
+    let extensions_len = hello.u16()? as usize;
+    let mut extensions = Reader(hello.take(extensions_len)?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let extension_len = extensions.u16()? as usize;
+        let extension = extensions.take(extension_len)?;
+        if extension_type == EXTENSION_SERVER_NAME {
+            return server_name(extension).map(ClientHello::Complete);
+        }
+    }
+    Ok(ClientHello::Complete(None))
+}
+
+/// Picks the host name out of a `server_name` extension body
+fn server_name(extension: &[u8]) -> Result<Option<String>, String> {
+    let mut extension = Reader(extension);
+    let list_len = extension.u16()? as usize;
+    let mut list = Reader(extension.take(list_len)?);
+    while !list.0.is_empty() {
+        let name_type = list.u8()?;
+        let name_len = list.u16()? as usize;
+        let name = list.take(name_len)?;
+        if name_type == NAME_TYPE_HOST_NAME {
+            let name = std::str::from_utf8(name)
+                .map_err(|_| "SNI host name is not valid UTF-8".to_string())?;
+            return Ok(Some(name.trim_end_matches('.').to_ascii_lowercase()));
+        }
+    }
+    Ok(None)
+}
+
+/// Cursor over big-endian handshake fields
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.0.len() < len {
+            return Err("Truncated ClientHello".to_string());
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), String> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// ClientHello sent by OpenSSL (TLS 1.2) for `example.com`
+    pub(in crate::server) const HELLO_WITH_SNI: &str = "16030100b1010000ad030331a41fb247c37b4a4e13852d8c7bbcf114715743ccfc9d30dfa7814725a41a5900001ec02cc030c02bc02fcca9cca8c024c028c023c027009f009e006b006700ff0100006600000010000e00000b6578616d706c652e636f6d000b000403000102000a000c000a001d0017001e00190018002300000016000000170000000d002a0028040305030603080708080809080a080b080408050806040105010601030303010302040205020602";
+
+    /// The same client connecting by IP address, so without SNI
+    const HELLO_WITHOUT_SNI: &str = "160301009d0100009903039780c98f041c24dabdb0dc774edfd677ff1139712b79fd2866364eba8ccd33c500001ec02cc030c02bc02fcca9cca8c024c028c023c027009f009e006b006700ff01000052000b000403000102000a000c000a001d0017001e00190018002300000016000000170000000d002a0028040305030603080708080809080a080b080408050806040105010601030303010302040205020602";
+
+    pub(in crate::server) fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Re-frames the handshake of a single-record hello into records of at most `size` bytes
+    fn fragment(hello: &[u8], size: usize) -> Vec<u8> {
+        let (header, handshake) = hello.split_at(RECORD_HEADER_LEN);
+        handshake
+            .chunks(size)
+            .flat_map(|chunk| {
+                let mut record = header[..3].to_vec();
+                record.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+                record.extend_from_slice(chunk);
+                record
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_captured_hellos() {
+        assert_eq!(
+            parse_client_hello(&hex(HELLO_WITH_SNI)).unwrap(),
+            ClientHello::Complete(Some("example.com".to_string()))
+        );
+        assert_eq!(
+            parse_client_hello(&hex(HELLO_WITHOUT_SNI)).unwrap(),
+            ClientHello::Complete(None)
+        );
+    }
+
+    #[test]
+    fn test_fragmented_records() {
+        let hello = fragment(&hex(HELLO_WITH_SNI), 7);
+        // every prefix is incomplete, as when the hello arrives over several reads
+        for end in 0..hello.len() {
+            assert_eq!(
+                parse_client_hello(&hello[..end]).unwrap(),
+                ClientHello::Incomplete
+            );
+        }
+        assert_eq!(
+            parse_client_hello(&hello).unwrap(),
+            ClientHello::Complete(Some("example.com".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_rejects_non_tls() {
+        assert!(parse_client_hello(b"GET / HTTP/1.1\r\n\r\n").is_err());
+
+        // a handshake record that is not a ClientHello
+        let mut server_hello = hex(HELLO_WITH_SNI);
+        server_hello[RECORD_HEADER_LEN] = 0x02;
+        assert!(parse_client_hello(&server_hello).is_err());
+    }
+}
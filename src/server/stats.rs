@@ -0,0 +1,198 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::utils::CloseCode;
+
+/// Slots of `CloseCounts`, one for each known code and one for all others
+const CLOSE_SLOTS: usize = CloseCode::KNOWN.len() + 1;
+
+/// Traffic counters of one proxy, shared by all of its connections.
+/// They live as long as the proxy and reset only when it is removed.
+#[derive(Debug, Default)]
+pub struct ProxyStats {
+    connections: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    dropped: AtomicU64,
+    active: AtomicU64,
+    rejected: AtomicU64,
+    out_of_sequence: AtomicU64,
+    closed: [AtomicU64; CLOSE_SLOTS],
+}
+
+/// Point-in-time copy of `ProxyStats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub connections: u64,
+    /// Bytes received from external peers
+    pub bytes_in: u64,
+    /// Bytes sent to external peers
+    pub bytes_out: u64,
+    /// Connections closed at once for exceeding the per-IP rate limit
+    pub dropped: u64,
+    /// Connections open right now
+    pub active: u64,
+    /// Connections closed at once for exceeding the proxy's `max_connections`
+    pub rejected: u64,
+    /// Connections closed for data arriving out of sequence
+    pub out_of_sequence: u64,
+    /// Connections ended, by the code they closed with
+    pub closed: CloseCounts,
+}
+
+/// Connections closed with each `CloseCode`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CloseCounts([u64; CLOSE_SLOTS]);
+
+impl CloseCounts {
+    /// Connections closed with `code`, codes this version does not know are counted together
+    pub fn get(&self, code: CloseCode) -> u64 {
+        self.0[slot(code)]
+    }
+}
+
+impl fmt::Display for CloseCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut counted = CloseCode::KNOWN
+            .iter()
+            .map(|code| (code.to_string(), self.get(*code)))
+            .chain([("other".to_string(), self.0[CLOSE_SLOTS - 1])])
+            .filter(|(_, count)| *count > 0)
+            .peekable();
+        if counted.peek().is_none() {
+            return f.write_str("none");
+        }
+        for (i, (code, count)) in counted.enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{} {}", count, code)?;
+        }
+        Ok(())
+    }
+}
+
+/// Slot of `code` in `CloseCounts`
+fn slot(code: CloseCode) -> usize {
+    match code {
+        CloseCode::Other(_) => CLOSE_SLOTS - 1,
+        known => usize::from(known.to_u8()),
+    }
+}
+
+impl ProxyStats {
+    pub fn record_connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts bytes received from an external peer
+    pub fn record_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts bytes sent to an external peer
+    pub fn record_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a connection dropped by the per-IP rate limit
+    pub fn record_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a connection closed for data arriving out of sequence
+    pub fn record_out_of_sequence(&self) {
+        self.out_of_sequence.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a connection that ended with `code`
+    pub fn record_close(&self, code: CloseCode) {
+        self.closed[slot(code)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Takes one of `limit` connection slots, held until the returned slot is dropped.
+    /// None counts a rejected connection, every slot is taken
+    pub fn open(self: &Arc<Self>, limit: Option<u32>) -> Option<ConnectionSlot> {
+        let limit = limit.map_or(u64::MAX, u64::from);
+        let taken = self
+            .active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < limit).then_some(active + 1)
+            });
+        if taken.is_err() {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(ConnectionSlot {
+            stats: self.clone(),
+        })
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            connections: self.connections.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            active: self.active.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            out_of_sequence: self.out_of_sequence.load(Ordering::Relaxed),
+            closed: CloseCounts(std::array::from_fn(|slot| {
+                self.closed[slot].load(Ordering::Relaxed)
+            })),
+        }
+    }
+}
+
+/// An open connection of a proxy, counted in its `active` connections until dropped,
+/// however the connection ends
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    stats: Arc<ProxyStats>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.stats.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_are_limited_and_given_back() {
+        let stats = Arc::new(ProxyStats::default());
+        let first = stats.open(Some(2)).unwrap();
+        let _second = stats.open(Some(2)).unwrap();
+        assert!(stats.open(Some(2)).is_none());
+
+        drop(first);
+        let _third = stats.open(Some(2)).unwrap();
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.active, snapshot.rejected), (2, 1));
+
+        // without a limit every connection gets a slot
+        let unlimited: Vec<_> = (0..5).filter_map(|_| stats.open(None)).collect();
+        assert_eq!(unlimited.len(), 5);
+    }
+
+    #[test]
+    fn test_closes_are_counted_by_code() {
+        let stats = ProxyStats::default();
+        assert_eq!(stats.snapshot().closed.to_string(), "none");
+
+        stats.record_close(CloseCode::IdleTimeout);
+        stats.record_close(CloseCode::IdleTimeout);
+        stats.record_close(CloseCode::PeerClosed);
+        stats.record_close(CloseCode::Other(9));
+        stats.record_close(CloseCode::Other(10));
+        let closed = stats.snapshot().closed;
+        assert_eq!(closed.get(CloseCode::IdleTimeout), 2);
+        assert_eq!(closed.get(CloseCode::ServerShutdown), 0);
+        assert_eq!(closed.get(CloseCode::Other(11)), 2);
+        assert_eq!(closed.to_string(), "1 peer closed, 2 idle timeout, 2 other");
+    }
+}
@@ -0,0 +1,655 @@
+//! Upgrades without downtime: on SIGUSR2 a server with an upgrade socket starts a new copy
+//! of its binary and passes it the listening sockets over that Unix socket, with what each
+//! one is for. The new process accepts on them right away, the old one stops accepting and
+//! serves the connections it has until they end or `drain_timeout` passed
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+use super::orphans::OrphanQueue;
+use super::{Balance, ProxyListenerInfo, Server, SharedPort};
+use crate::logging::format_uuid;
+use crate::{error, log_info, log_warn};
+
+/// Set in the environment of the new process, to the pid of the one it takes over from
+pub(super) const UPGRADE_ENV: &str = "SOWBACK_UPGRADE_FROM";
+
+/// Why clients of a draining server are told their session ended
+pub(super) const UPGRADE_REASON: &str = "Server upgrading, reconnect";
+
+/// How often a draining server closes the sessions left without connections
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// What a listener handed over is for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "role", rename_all = "snake_case")]
+pub(super) enum ListenerRole {
+    /// `listen_addr`, where clients connect
+    Control,
+    /// `http_port`
+    Http,
+    /// `sni_port`
+    Sni,
+    /// The admin API
+    Admin,
+    /// `websocket_listen_addr`, where clients connect over WebSocket
+    WebSocket,
+    /// A service listener, reserved for the client that bound it
+    Proxy {
+        port: u16,
+        client_id: String,
+        group: Option<String>,
+    },
+}
+
+impl From<SharedPort> for ListenerRole {
+    fn from(kind: SharedPort) -> Self {
+        match kind {
+            SharedPort::Http => ListenerRole::Http,
+            SharedPort::Sni => ListenerRole::Sni,
+        }
+    }
+}
+
+/// A service listener taken over from the previous process
+struct InheritedProxy {
+    port: u16,
+    client_id: String,
+    group: Option<String>,
+    listener: TcpListener,
+}
+
+/// Listeners received from the process this one took over from, none when it was started
+/// otherwise
+#[derive(Default)]
+pub(super) struct Inherited {
+    listeners: Vec<(ListenerRole, std::net::TcpListener)>,
+    /// Told once this process accepts, see `ready`
+    #[cfg(unix)]
+    predecessor: Option<std::os::unix::net::UnixStream>,
+}
+
+impl Inherited {
+    /// Takes the listener received for `role`, None if there was none
+    pub(super) fn take(&mut self, role: &ListenerRole) -> Result<Option<TcpListener>> {
+        let Some(index) = self.listeners.iter().position(|(r, _)| r == role) else {
+            return Ok(None);
+        };
+        let (_, listener) = self.listeners.remove(index);
+        listener.set_nonblocking(true)?;
+        Ok(Some(TcpListener::from_std(listener)?))
+    }
+
+    /// Takes the service listeners received
+    fn take_proxies(&mut self) -> Result<Vec<InheritedProxy>> {
+        let mut proxies = Vec::new();
+        for (role, listener) in std::mem::take(&mut self.listeners) {
+            let ListenerRole::Proxy {
+                port,
+                client_id,
+                group,
+            } = role
+            else {
+                self.listeners.push((role, listener));
+                continue;
+            };
+            listener.set_nonblocking(true)?;
+            proxies.push(InheritedProxy {
+                port,
+                client_id,
+                group,
+                listener: TcpListener::from_std(listener)?,
+            });
+        }
+        Ok(proxies)
+    }
+
+    /// Tells the previous process this one accepts on the listeners, so it stops, and
+    /// names this process in `pid_file` instead
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    pub(super) fn ready(&mut self, pid_file: Option<&Path>) {
+        #[cfg(unix)]
+        if let Some(mut predecessor) = self.predecessor.take() {
+            use std::io::Write;
+
+            if let Some(pid_file) = pid_file {
+                if let Err(e) = crate::utils::daemon::write_pid(pid_file, std::process::id()) {
+                    log_warn!("Cannot write pid file {}: {}", pid_file.display(), e);
+                }
+            }
+            if let Err(e) = predecessor.write_all(&[1]) {
+                log_warn!(
+                    "Cannot tell the previous process the upgrade is done: {}",
+                    e
+                );
+            }
+            // the service manager follows this process from now on
+            crate::utils::sd_notify::notify(&format!("MAINPID={}", std::process::id()));
+        }
+    }
+}
+
+/// SIGUSR2, asking a server with an upgrade socket to hand its listeners over
+pub(super) struct UpgradeSignal {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl UpgradeSignal {
+    /// Listens for SIGUSR2 if `enabled`, leaving the signal alone otherwise
+    pub(super) fn new(enabled: bool) -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let signal = match enabled.then(|| signal(SignalKind::user_defined2())) {
+                Some(Ok(signal)) => Some(signal),
+                Some(Err(e)) => {
+                    log_warn!("Cannot listen for SIGUSR2, upgrades are off: {}", e);
+                    None
+                }
+                None => None,
+            };
+            Self { signal }
+        }
+        #[cfg(not(unix))]
+        {
+            if enabled {
+                log_warn!("Upgrades are only supported on Unix");
+            }
+            Self {}
+        }
+    }
+
+    /// Waits for the next upgrade request, forever when not listening
+    pub(super) async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            signal.recv().await;
+            return;
+        }
+        std::future::pending().await
+    }
+}
+
+impl Server {
+    /// The listeners of the previous process when this one was started by its upgrade,
+    /// otherwise none
+    pub(super) async fn inherit(&self) -> Result<Inherited> {
+        #[cfg(unix)]
+        if let Some(socket) = self.upgrade_socket.clone() {
+            if let Some(from) = std::env::var_os(UPGRADE_ENV) {
+                let inherited =
+                    tokio::task::spawn_blocking(move || handoff::receive(&socket)).await??;
+                log_info!(
+                    "Took over {} listeners from pid {}",
+                    inherited.listeners.len(),
+                    from.to_string_lossy()
+                );
+                return Ok(inherited);
+            }
+        }
+        Ok(Inherited::default())
+    }
+
+    /// Serves the service listeners of `inherited` as orphaned, each held for its client
+    /// to reclaim within `listener_grace_period` after the previous process let it go
+    pub(super) async fn adopt_listeners(&self, inherited: &mut Inherited) -> Result<()> {
+        let grace = self.config.listener_grace_period.0 + self.config.drain_timeout.0;
+        let mut listeners_guard = self.proxy_listeners.write().await;
+        for proxy in inherited.take_proxies()? {
+            let port = proxy.port;
+            let listener = Arc::new(proxy.listener);
+            let (cancel_tx, cancel_rx) = mpsc::unbounded_channel();
+            // the clients have not had the chance to reconnect yet, nothing is refused
+            let orphans = Arc::new(OrphanQueue::holding());
+            log_info!(
+                "Took over service listener on port {} for client {}",
+                port,
+                format_uuid(&proxy.client_id, "client")
+            );
+            listeners_guard.insert(
+                port,
+                ProxyListenerInfo {
+                    listener: listener.clone(),
+                    client_id: proxy.client_id,
+                    group: proxy.group,
+                    members: Vec::new(),
+                    balance: Balance::default(),
+                    orphaned: Some(Instant::now()),
+                    orphans: orphans.clone(),
+                    bound_at: Instant::now(),
+                    cancel_tx,
+                },
+            );
+            let server = self.clone();
+            tokio::spawn(async move {
+                server
+                    .handle_proxy_connections(listener, port, orphans, cancel_rx)
+                    .await;
+            });
+            let server = self.clone();
+            tokio::spawn(async move { server.expire_orphaned_listener(port, grace).await });
+        }
+        Ok(())
+    }
+
+    /// Starts a new process of the binary and hands it `control`, `listening` and the
+    /// service listeners, returning whether it took them over. This process stops
+    /// accepting on all of them when it did, it keeps serving when anything failed
+    pub(super) async fn hand_over(
+        &self,
+        control: &TcpListener,
+        listening: &[(ListenerRole, Arc<TcpListener>)],
+    ) -> bool {
+        #[cfg(unix)]
+        if let Some(socket) = &self.upgrade_socket {
+            log_info!("Upgrade requested, starting a new process");
+            let handed = match self.listener_handles(control, listening).await {
+                Ok(listeners) => handoff::send(socket, listeners).await,
+                Err(e) => Err(e.into()),
+            };
+            return match handed {
+                Ok(pid) => {
+                    self.handed_off.cancel();
+                    log_info!(
+                        "Handed the listeners over to pid {}, draining {} clients for up to {}",
+                        pid,
+                        self.clients.read().await.len(),
+                        self.config.drain_timeout
+                    );
+                    true
+                }
+                Err(e) => {
+                    error!("Upgrade failed, still serving: {}", e);
+                    false
+                }
+            };
+        }
+        let _ = (control, listening);
+        false
+    }
+
+    /// Duplicates of every listener to hand over, with what each is for
+    #[cfg(unix)]
+    async fn listener_handles(
+        &self,
+        control: &TcpListener,
+        listening: &[(ListenerRole, Arc<TcpListener>)],
+    ) -> std::io::Result<Vec<(ListenerRole, std::os::fd::OwnedFd)>> {
+        use std::os::fd::AsFd;
+
+        let mut handles = vec![(ListenerRole::Control, control.as_fd().try_clone_to_owned()?)];
+        for (role, listener) in listening {
+            handles.push((role.clone(), listener.as_fd().try_clone_to_owned()?));
+        }
+        for (port, info) in self.proxy_listeners.read().await.iter() {
+            let role = ListenerRole::Proxy {
+                port: *port,
+                client_id: info.client_id.clone(),
+                group: info.group.clone(),
+            };
+            handles.push((role, info.listener.as_fd().try_clone_to_owned()?));
+        }
+        Ok(handles)
+    }
+
+    /// Serves the sessions left after a hand over until their connections ended, closing
+    /// each once it has none so its client reconnects to the new process, or until
+    /// `drain_timeout` passed or `shutdown` is cancelled
+    pub(super) async fn drain(&self, shutdown: &CancellationToken) {
+        let deadline = tokio::time::Instant::now() + self.config.drain_timeout.0;
+        let mut interval = tokio::time::interval(DRAIN_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = tokio::time::sleep_until(deadline) => {
+                    log_info!(
+                        "Drain timeout passed, closing {} clients",
+                        self.clients.read().await.len()
+                    );
+                    return;
+                }
+                _ = shutdown.cancelled() => return,
+            }
+            let busy: HashSet<String> = self
+                .proxy_connections
+                .read()
+                .await
+                .values()
+                .map(|connection| connection.client_id.clone())
+                .collect();
+            let idle: Vec<String> = {
+                let mut clients_guard = self.clients.write().await;
+                clients_guard
+                    .iter_mut()
+                    .filter(|(client_id, _)| !busy.contains(*client_id))
+                    .map(|(client_id, client)| {
+                        client.close(UPGRADE_REASON);
+                        client_id.clone()
+                    })
+                    .collect()
+            };
+            // dropping the session's sender ends its writer once the message is written
+            for client_id in idle {
+                self.cleanup_client(&client_id).await;
+            }
+            if self.clients.read().await.is_empty() {
+                log_info!("Every client left, the upgrade is done");
+                return;
+            }
+        }
+    }
+}
+
+/// The Unix socket exchange: a length prefixed JSON list of roles, then the descriptors in
+/// the same order, `FDS_PER_MESSAGE` to a message, then one byte back once the new
+/// process accepts
+#[cfg(unix)]
+mod handoff {
+    use super::{Inherited, ListenerRole, UPGRADE_ENV};
+    use anyhow::{anyhow, Result};
+    use std::io::{self, Read, Write};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+    use std::process::{Child, Command};
+    use std::time::{Duration, Instant};
+
+    /// Most descriptors passed in one message
+    const FDS_PER_MESSAGE: usize = 64;
+
+    /// How long each step of the hand over may take
+    const HANDOFF_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Largest list of roles accepted
+    const MAX_ROLES_LEN: usize = 16 * 1024 * 1024;
+
+    /// Starts the new process, hands it `listeners` over `socket` and waits until it
+    /// accepts on them, returning its pid
+    pub(super) async fn send(
+        socket: &Path,
+        listeners: Vec<(ListenerRole, OwnedFd)>,
+    ) -> Result<u32> {
+        // left behind by a process that did not finish an upgrade
+        let _ = std::fs::remove_file(socket);
+        let rendezvous = tokio::net::UnixListener::bind(socket)
+            .map_err(|e| anyhow!("Cannot bind upgrade socket {}: {}", socket.display(), e))?;
+        let mut child = spawn_successor()?;
+        let sent = send_to(&rendezvous, &mut child, listeners).await;
+        drop(rendezvous);
+        let _ = std::fs::remove_file(socket);
+        match sent {
+            Ok(()) => Ok(child.id()),
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                Err(e)
+            }
+        }
+    }
+
+    /// Runs the binary again with the arguments of this process
+    fn spawn_successor() -> Result<Child> {
+        // argv[0] finds the binary installed over this one, `current_exe` would name the
+        // replaced file
+        let program = match std::env::args_os().next() {
+            Some(program) => program,
+            None => std::env::current_exe()?.into_os_string(),
+        };
+        Command::new(&program)
+            .args(std::env::args_os().skip(1))
+            .env(UPGRADE_ENV, std::process::id().to_string())
+            .spawn()
+            .map_err(|e| anyhow!("Cannot start {}: {}", program.to_string_lossy(), e))
+    }
+
+    async fn send_to(
+        rendezvous: &tokio::net::UnixListener,
+        child: &mut Child,
+        listeners: Vec<(ListenerRole, OwnedFd)>,
+    ) -> Result<()> {
+        let deadline = Instant::now() + HANDOFF_TIMEOUT;
+        let stream = loop {
+            tokio::select! {
+                accepted = rendezvous.accept() => break accepted?.0,
+                _ = tokio::time::sleep(Duration::from_millis(100)) => {
+                    if let Some(status) = child.try_wait()? {
+                        return Err(anyhow!("The new process exited with {} before taking over", status));
+                    }
+                    if Instant::now() >= deadline {
+                        return Err(anyhow!("The new process did not connect within {:?}", HANDOFF_TIMEOUT));
+                    }
+                }
+            }
+        };
+        let mut stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        tokio::task::spawn_blocking(move || {
+            send_listeners(&mut stream, &listeners)?;
+            stream.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+            let mut ack = [0];
+            match stream.read(&mut ack)? {
+                1 => Ok(()),
+                _ => Err(anyhow!("The new process exited before it was ready")),
+            }
+        })
+        .await?
+    }
+
+    /// Writes the roles of `listeners`, then their descriptors
+    fn send_listeners(
+        stream: &mut UnixStream,
+        listeners: &[(ListenerRole, OwnedFd)],
+    ) -> Result<()> {
+        let roles: Vec<&ListenerRole> = listeners.iter().map(|(role, _)| role).collect();
+        let roles = serde_json::to_vec(&roles)?;
+        stream.write_all(&(roles.len() as u32).to_be_bytes())?;
+        stream.write_all(&roles)?;
+        for batch in listeners.chunks(FDS_PER_MESSAGE) {
+            let fds: Vec<RawFd> = batch.iter().map(|(_, fd)| fd.as_raw_fd()).collect();
+            send_fds(stream, &fds)?;
+        }
+        Ok(())
+    }
+
+    /// Connects to the process upgrading at `socket` and receives its listeners
+    pub(super) fn receive(socket: &Path) -> Result<Inherited> {
+        let stream = UnixStream::connect(socket).map_err(|e| {
+            anyhow!(
+                "Cannot connect to upgrade socket {}: {}",
+                socket.display(),
+                e
+            )
+        })?;
+        receive_from(stream)
+    }
+
+    /// Reads what `send_listeners` wrote to the other end of `stream`
+    fn receive_from(mut stream: UnixStream) -> Result<Inherited> {
+        stream.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+        let mut len = [0; 4];
+        stream.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_ROLES_LEN {
+            return Err(anyhow!(
+                "Upgrade listener list of {} bytes is too long",
+                len
+            ));
+        }
+        let mut roles = vec![0; len];
+        stream.read_exact(&mut roles)?;
+        let roles: Vec<ListenerRole> = serde_json::from_slice(&roles)?;
+        let mut fds = Vec::with_capacity(roles.len());
+        while fds.len() < roles.len() {
+            fds.extend(recv_fds(&stream)?);
+        }
+        if fds.len() != roles.len() {
+            return Err(anyhow!(
+                "Received {} listeners for {} roles",
+                fds.len(),
+                roles.len()
+            ));
+        }
+        let listeners = roles
+            .into_iter()
+            .zip(fds)
+            .map(|(role, fd)| (role, std::net::TcpListener::from(fd)))
+            .collect();
+        Ok(Inherited {
+            listeners,
+            predecessor: Some(stream),
+        })
+    }
+
+    /// Sends `fds` with a byte holding their count
+    fn send_fds(stream: &UnixStream, fds: &[RawFd]) -> io::Result<()> {
+        let count = [fds.len() as u8];
+        let mut iov = libc::iovec {
+            iov_base: count.as_ptr() as *mut libc::c_void,
+            iov_len: count.len(),
+        };
+        let data_len = std::mem::size_of_val(fds);
+        // SAFETY: CMSG_SPACE only computes a size
+        let space = unsafe { libc::CMSG_SPACE(data_len as u32) } as usize;
+        // u64 keeps the buffer aligned for the cmsghdr written into it
+        let mut control = vec![0u64; space.div_ceil(8)];
+        // SAFETY: an all zero msghdr is valid, the pointers are set below
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+        // SAFETY: the control buffer holds one header with room for `fds`, and every
+        // pointer of `msg` outlives the call
+        let sent = unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(data_len as u32) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr() as *const u8,
+                libc::CMSG_DATA(cmsg),
+                data_len,
+            );
+            libc::sendmsg(stream.as_raw_fd(), &msg, 0)
+        };
+        match sent {
+            1 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// Receives the descriptors of one `send_fds` message
+    fn recv_fds(stream: &UnixStream) -> io::Result<Vec<OwnedFd>> {
+        let mut count = [0u8];
+        let mut iov = libc::iovec {
+            iov_base: count.as_mut_ptr() as *mut libc::c_void,
+            iov_len: count.len(),
+        };
+        // SAFETY: CMSG_SPACE only computes a size
+        let space =
+            unsafe { libc::CMSG_SPACE((FDS_PER_MESSAGE * std::mem::size_of::<RawFd>()) as u32) }
+                as usize;
+        let mut control = vec![0u64; space.div_ceil(8)];
+        // SAFETY: an all zero msghdr is valid, the pointers are set below
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = space as _;
+        // SAFETY: every pointer of `msg` outlives the call
+        let received = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, 0) };
+        match received {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n if n < 0 => return Err(io::Error::last_os_error()),
+            _ => {}
+        }
+
+        let mut fds = Vec::new();
+        // SAFETY: the headers walked are the ones the kernel wrote into the control
+        // buffer, each descriptor in them is open in this process and owned by no one else
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                    let data = libc::CMSG_DATA(cmsg);
+                    let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                    for index in 0..len / std::mem::size_of::<RawFd>() {
+                        let fd = std::ptr::read_unaligned((data as *const RawFd).add(index));
+                        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                        fds.push(OwnedFd::from_raw_fd(fd));
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 || fds.len() != usize::from(count[0]) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "expected {} listeners in a message, got {}",
+                    count[0],
+                    fds.len()
+                ),
+            ));
+        }
+        Ok(fds)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::net::{TcpListener, TcpStream};
+
+        #[test]
+        fn test_listeners_pass_with_their_roles() {
+            let (mut sender, receiver) = UnixStream::pair().unwrap();
+            // more than fit in one message
+            let bound: Vec<TcpListener> = (0..FDS_PER_MESSAGE + 3)
+                .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+                .collect();
+            let listeners: Vec<(ListenerRole, OwnedFd)> = bound
+                .iter()
+                .map(|listener| {
+                    let role = ListenerRole::Proxy {
+                        port: listener.local_addr().unwrap().port(),
+                        client_id: "client".to_string(),
+                        group: Some("group".to_string()),
+                    };
+                    (role, OwnedFd::from(listener.try_clone().unwrap()))
+                })
+                .collect();
+            let roles: Vec<ListenerRole> = listeners.iter().map(|(role, _)| role.clone()).collect();
+            let old = std::thread::spawn(move || {
+                send_listeners(&mut sender, &listeners).unwrap();
+                let mut ack = [0];
+                sender.read_exact(&mut ack).unwrap();
+                ack[0]
+            });
+
+            let mut inherited = receive_from(receiver).unwrap();
+            let received: Vec<ListenerRole> = inherited
+                .listeners
+                .iter()
+                .map(|(role, _)| role.clone())
+                .collect();
+            assert_eq!(received, roles);
+            // the very sockets, a connection to the old one is accepted on the new one
+            let (_, last) = inherited.listeners.last().unwrap();
+            let _stream = TcpStream::connect(bound.last().unwrap().local_addr().unwrap()).unwrap();
+            last.accept().unwrap();
+
+            inherited.ready(None);
+            assert_eq!(old.join().unwrap(), 1);
+        }
+    }
+}
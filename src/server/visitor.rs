@@ -0,0 +1,445 @@
+use anyhow::Result;
+use uuid::Uuid;
+
+use super::{ClientConnection, ProxyInfo, Server};
+use crate::logging::format_uuid;
+use crate::utils::crypto::secret_hashes_match;
+use crate::utils::protocol::ProxyConfigOpCode;
+use crate::utils::{net, CloseCode, Message};
+use crate::{log_debug, log_info, warn};
+
+/// A service registered with a secret, reached by visitors instead of a listener
+#[derive(Clone)]
+pub(super) struct SecretService {
+    client_id: String,
+    proxy_id: String,
+    secret_hash: Vec<u8>,
+}
+
+/// A connection from a visitor to a secret service. The server relays its messages
+/// between the two clients, which forward it like any proxied connection
+pub(super) struct VisitorLink {
+    visitor_id: String,
+    service_id: String,
+    /// Proxy of the secret service visited
+    proxy_id: String,
+    /// `request_id` of the visitor's `VisitorConnect`, until the service's client answers
+    pending: Option<u64>,
+}
+
+impl VisitorLink {
+    /// The other end of the link from `client_id`, None if it is not part of it
+    fn peer_of(&self, client_id: &str) -> Option<&str> {
+        if client_id == self.visitor_id {
+            Some(&self.service_id)
+        } else if client_id == self.service_id {
+            Some(&self.visitor_id)
+        } else {
+            None
+        }
+    }
+}
+
+impl Server {
+    /// Handles a `ProxyConfig` for a secret service, registering it under its name
+    pub(super) async fn setup_secret_service(
+        &self,
+        op: ProxyConfigOpCode,
+        proxy_info: ProxyInfo,
+        secret_hash: Vec<u8>,
+        client_id: &str,
+        request_id: u64,
+    ) {
+        let name = proxy_info.name.clone();
+        let mut services = self.secret_services.write().await;
+        let mut clients_guard = self.clients.write().await;
+        let Some(client) = clients_guard.get_mut(client_id) else {
+            return;
+        };
+        let owner = services.get(&name).map(|service| service.client_id.clone());
+
+        if op == ProxyConfigOpCode::Delete {
+            if owner.as_deref() == Some(client_id) {
+                if let Some(service) = services.remove(&name) {
+                    client.proxies.remove(&service.proxy_id);
+                }
+                log_info!(
+                    "Secret service '{}' removed by client {}",
+                    name,
+                    client.label()
+                );
+            }
+            return;
+        }
+
+        let response = match owner {
+            Some(owner) if owner != client_id => {
+                let reason = format!(
+                    "Secret service '{}' already registered by another client",
+                    name
+                );
+                warn!(
+                    "Rejected secret service '{}' for client {}: {}",
+                    name,
+                    format_uuid(client_id, "client"),
+                    reason
+                );
+                Message::ProxyConfigResponse {
+                    request_id,
+                    success: false,
+                    proxy_id: None,
+                    error: Some(reason),
+                    assigned_port: None,
+                }
+            }
+            _ => {
+                let proxy_id = Uuid::new_v4().to_string();
+                let service = SecretService {
+                    client_id: client_id.to_string(),
+                    proxy_id: proxy_id.clone(),
+                    secret_hash,
+                };
+                // re-registering replaces the client's previous proxy for this name
+                if let Some(previous) = services.insert(name.clone(), service) {
+                    client.proxies.remove(&previous.proxy_id);
+                }
+                log_info!(
+                    "Secret service '{}' for client {}: {}",
+                    name,
+                    client.label(),
+                    net::join_host_port(&proxy_info.local_ip, proxy_info.local_port)
+                );
+                self.notify_proxy_registered(client, &proxy_info, None);
+                client.proxies.insert(proxy_id.clone(), proxy_info);
+                Message::ProxyConfigResponse {
+                    request_id,
+                    success: true,
+                    proxy_id: Some(proxy_id),
+                    error: None,
+                    assigned_port: None,
+                }
+            }
+        };
+        let _ = client.sender.send(response);
+    }
+
+    /// Handles a `VisitorConnect`, asking the client of the secret service for a connection.
+    /// Its `ConnectionResponse` answers the visitor, see `relay_visitor_message`
+    pub(super) async fn connect_visitor(
+        &self,
+        client_id: &str,
+        request_id: u64,
+        service_name: &str,
+        secret_hash: &[u8],
+    ) {
+        // an unknown name and a wrong secret look the same to the visitor
+        let service = self
+            .secret_services
+            .read()
+            .await
+            .get(service_name)
+            .filter(|service| secret_hashes_match(&service.secret_hash, secret_hash))
+            .cloned();
+        let service = match service {
+            Some(service) if service.client_id == client_id => {
+                Err("A client cannot visit its own secret service".to_string())
+            }
+            Some(service) => Ok(service),
+            None => Err("Unknown secret service or wrong secret".to_string()),
+        };
+        let service = match service {
+            Ok(service) => service,
+            Err(reason) => {
+                warn!(
+                    "Refused visitor {} for secret service '{}': {}",
+                    format_uuid(client_id, "client"),
+                    service_name,
+                    reason
+                );
+                self.answer_visitor(client_id, request_id, Err(reason))
+                    .await;
+                return;
+            }
+        };
+
+        let service_desc = format!("secret service '{}'", service_name);
+        self.link_visitor(
+            client_id,
+            request_id,
+            &service.client_id,
+            &service.proxy_id,
+            &service_desc,
+        )
+        .await;
+    }
+
+    /// Links a visitor's connection to the service of `proxy_id` of client `owner_id`,
+    /// asking the owner for it with a `NewConnection`. A visitor whose service's client
+    /// cannot be asked is told so right away
+    pub(super) async fn link_visitor(
+        &self,
+        client_id: &str,
+        request_id: u64,
+        owner_id: &str,
+        proxy_id: &str,
+        service_desc: &str,
+    ) {
+        let connection_id = Uuid::new_v4().to_string();
+        self.visitor_links.write().await.insert(
+            connection_id.clone(),
+            VisitorLink {
+                visitor_id: client_id.to_string(),
+                service_id: owner_id.to_string(),
+                proxy_id: proxy_id.to_string(),
+                pending: Some(request_id),
+            },
+        );
+
+        let sent = {
+            let clients_guard = self.clients.read().await;
+            let source_addr = clients_guard
+                .get(client_id)
+                .map(|visitor| visitor.addr.to_string())
+                .unwrap_or_default();
+            clients_guard.get(owner_id).is_some_and(|owner| {
+                let request = Message::NewConnection {
+                    proxy_id: proxy_id.to_string(),
+                    connection_id: connection_id.clone(),
+                    source_addr,
+                    // visitors reached the service through the control port
+                    dest_addr: self.config.listen_addr.clone(),
+                };
+                owner.sender.send(request).is_ok()
+            })
+        };
+        if !sent {
+            self.visitor_links.write().await.remove(&connection_id);
+            let reason = format!("The {} is not reachable", service_desc);
+            self.answer_visitor(client_id, request_id, Err(reason))
+                .await;
+            return;
+        }
+
+        log_info!(
+            "Visitor {} connecting to {} of client {}: conn={}",
+            format_uuid(client_id, "client"),
+            service_desc,
+            format_uuid(owner_id, "client"),
+            connection_id
+        );
+    }
+
+    /// Sends the `VisitorConnectResponse` to a visitor's request
+    pub(super) async fn answer_visitor(
+        &self,
+        client_id: &str,
+        request_id: u64,
+        outcome: std::result::Result<String, String>,
+    ) {
+        if let Some(visitor) = self.clients.read().await.get(client_id) {
+            let _ = visitor.sender.send(visitor_response(request_id, outcome));
+        }
+    }
+
+    /// Relays a message about a visitor connection to the other client of the link,
+    /// handing back any other message. Payloads are compressed again for the receiving
+    /// client, the two sessions may have negotiated different codecs
+    pub(super) async fn relay_visitor_message(
+        &self,
+        message: Message,
+        client_id: &str,
+    ) -> Result<Option<Message>> {
+        let Some(connection_id) = message.connection_id().map(str::to_string) else {
+            return Ok(Some(message));
+        };
+        // a visitor connection ends with either session, it is never resumed
+        if matches!(message, Message::ResumeConnection { .. }) {
+            return Ok(Some(message));
+        }
+
+        let (peer_id, pending) = {
+            let mut links = self.visitor_links.write().await;
+            let Some(link) = links.get_mut(&connection_id) else {
+                return Ok(Some(message));
+            };
+            let Some(peer_id) = link.peer_of(client_id).map(str::to_string) else {
+                warn!(
+                    "Client {} sent a message for visitor connection {} it is not part of",
+                    format_uuid(client_id, "client"),
+                    connection_id
+                );
+                return Ok(None);
+            };
+            // only the service's client answers, and only once
+            let pending = match &message {
+                Message::ConnectionResponse { .. } if client_id == link.service_id => {
+                    link.pending.take()
+                }
+                _ => None,
+            };
+            let ends = match &message {
+                Message::CloseConnection { .. } => true,
+                Message::ConnectionResponse { success, .. } => pending.is_some() && !success,
+                _ => false,
+            };
+            if ends {
+                links.remove(&connection_id);
+            }
+            (peer_id, pending)
+        };
+
+        let clients_guard = self.clients.read().await;
+        let Some(peer) = clients_guard.get(&peer_id) else {
+            log_debug!(
+                "Dropping message for visitor connection {}, its peer is gone",
+                connection_id
+            );
+            return Ok(None);
+        };
+        let relayed = match message {
+            Message::ConnectionResponse { success, error, .. } => {
+                let Some(request_id) = pending else {
+                    return Ok(None);
+                };
+                let outcome = match (success, error) {
+                    (true, _) => Ok(connection_id),
+                    (false, reason) => Err(reason.unwrap_or_else(|| "unknown error".to_string())),
+                };
+                visitor_response(request_id, outcome)
+            }
+            Message::Data { seq, data, .. } => self.repack(peer, &connection_id, seq, data),
+            Message::CompressedData {
+                seq, codec, data, ..
+            } => {
+                let data = codec.decompress(&data)?;
+                self.repack(peer, &connection_id, seq, data)
+            }
+            message => message,
+        };
+        let _ = peer.sender.send(relayed);
+        Ok(None)
+    }
+
+    /// The data message carrying `data` to `peer`, compressed with its codec. It keeps the
+    /// `seq` given by the sending client, which the receiving one checks
+    fn repack(
+        &self,
+        peer: &ClientConnection,
+        connection_id: &str,
+        seq: u64,
+        data: Vec<u8>,
+    ) -> Message {
+        Message::new_payload(
+            connection_id,
+            seq,
+            data,
+            peer.compression,
+            self.config.compression_threshold.as_usize(),
+        )
+    }
+
+    /// Drops the secret and relay services of a removed client session and the visitor
+    /// connections it was part of, telling the client at the other end of each
+    pub(super) async fn release_secret_services(&self, client: &ClientConnection) {
+        let client_id = client.client_id.as_str();
+        self.relay_services
+            .write()
+            .await
+            .retain(|proxy_id, _| !client.proxies.contains_key(proxy_id));
+        self.secret_services.write().await.retain(|name, service| {
+            if !client.proxies.contains_key(&service.proxy_id) {
+                return true;
+            }
+            log_info!(
+                "Released secret service '{}' for client {}",
+                name,
+                format_uuid(client_id, "client")
+            );
+            false
+        });
+
+        let mut broken = Vec::new();
+        self.visitor_links
+            .write()
+            .await
+            .retain(|connection_id, link| {
+                let Some(peer_id) = link.peer_of(client_id) else {
+                    return true;
+                };
+                // a visitor still waiting learns why, a connection in use is just closed
+                let message = match link.pending {
+                    Some(request_id) if peer_id == link.visitor_id => visitor_response(
+                        request_id,
+                        Err("The service's client disconnected".to_string()),
+                    ),
+                    _ => Message::new_close_connection(connection_id, CloseCode::PeerClosed),
+                };
+                broken.push((peer_id.to_string(), message));
+                false
+            });
+
+        let clients_guard = self.clients.read().await;
+        for (peer_id, message) in broken {
+            if let Some(peer) = clients_guard.get(&peer_id) {
+                let _ = peer.sender.send(message);
+            }
+        }
+    }
+}
+
+impl Server {
+    /// Removes the secret or relay service of `proxy_id`, if it is one, closing the
+    /// connections of its visitors
+    pub(super) async fn release_secret_service(&self, proxy_id: &str) {
+        self.secret_services
+            .write()
+            .await
+            .retain(|_, service| service.proxy_id != proxy_id);
+        self.relay_services.write().await.remove(proxy_id);
+
+        let mut closed = Vec::new();
+        self.visitor_links
+            .write()
+            .await
+            .retain(|connection_id, link| {
+                if link.proxy_id != proxy_id {
+                    return true;
+                }
+                let close = Message::new_close_connection(connection_id, CloseCode::PeerClosed);
+                // a visitor still waiting learns why, a connection in use is just closed
+                let message = match link.pending {
+                    Some(request_id) => {
+                        visitor_response(request_id, Err("The service expired".to_string()))
+                    }
+                    None => close.clone(),
+                };
+                closed.push((link.visitor_id.clone(), message));
+                closed.push((link.service_id.clone(), close));
+                false
+            });
+
+        let clients_guard = self.clients.read().await;
+        for (client_id, message) in closed {
+            if let Some(client) = clients_guard.get(&client_id) {
+                let _ = client.sender.send(message);
+            }
+        }
+    }
+}
+
+fn visitor_response(request_id: u64, outcome: std::result::Result<String, String>) -> Message {
+    match outcome {
+        Ok(connection_id) => Message::VisitorConnectResponse {
+            request_id,
+            success: true,
+            connection_id: Some(connection_id),
+            error: None,
+        },
+        Err(reason) => Message::VisitorConnectResponse {
+            request_id,
+            success: false,
+            connection_id: None,
+            error: Some(reason),
+        },
+    }
+}
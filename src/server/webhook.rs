@@ -0,0 +1,407 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::{sleep, timeout, Duration};
+use tokio_rustls::TlsConnector;
+
+use super::{ClientConnection, ProxyInfo, Server};
+use crate::config::{ClientTlsConfig, WebhookConfig, WebhookEvent};
+use crate::logging::short_id;
+use crate::utils::net;
+use crate::utils::tls;
+use crate::utils::webhook::{self, WebhookUrl, EVENT_HEADER, SIGNATURE_HEADER};
+use crate::{log_debug, log_warn};
+
+/// Events waiting for delivery, more are dropped until the queue drains
+const QUEUE_SIZE: usize = 256;
+
+/// Deliveries of an event tried before giving it up
+const ATTEMPTS: u32 = 3;
+
+/// Wait before the second attempt, doubled before each one after it
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How long one attempt may take, from connecting to the response's status
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// JSON body POSTed for an event
+#[derive(Debug, Serialize)]
+pub struct WebhookPayload {
+    pub event: WebhookEvent,
+    /// RFC 3339 time of the event
+    pub timestamp: String,
+    /// One line summary, shown as the message by chat services
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<WebhookClient>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<WebhookProxy>,
+    /// Source IP banned by `auth_failure_burst`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    /// Why a `listener_bind_failed` listener could not be bound
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The client session an event is about
+#[derive(Debug, Serialize)]
+pub struct WebhookClient {
+    pub id: String,
+    pub name: Option<String>,
+    pub addr: String,
+}
+
+/// The proxy an event is about
+#[derive(Debug, Serialize)]
+pub struct WebhookProxy {
+    pub name: String,
+    /// Address of the service, as seen by the client
+    pub local: String,
+    /// Port the proxy is reached on, None for secret services
+    pub remote_port: Option<u16>,
+    /// Host name of services routed on a shared port
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+}
+
+impl WebhookPayload {
+    pub fn new(event: WebhookEvent, text: String) -> Self {
+        Self {
+            event,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            text,
+            server: None,
+            client: None,
+            proxy: None,
+            ip: None,
+            error: None,
+        }
+    }
+
+    pub fn client(mut self, client: &ClientConnection) -> Self {
+        self.client = Some(WebhookClient {
+            id: client.client_id.clone(),
+            name: client.name.clone(),
+            addr: client.addr.to_string(),
+        });
+        self
+    }
+
+    pub fn proxy(mut self, proxy: &ProxyInfo, host: Option<&str>) -> Self {
+        self.proxy = Some(WebhookProxy {
+            name: proxy.name.clone(),
+            local: net::join_host_port(&proxy.local_ip, proxy.local_port),
+            remote_port: (proxy.remote_port != 0).then_some(proxy.remote_port),
+            host: host.map(str::to_string),
+        });
+        self
+    }
+}
+
+/// How a client is named in the `text` of payloads, without the colors of log lines
+fn client_text(client: &ClientConnection) -> String {
+    match &client.name {
+        Some(name) if !name.is_empty() => format!("{} ({})", name, short_id(&client.client_id)),
+        _ => short_id(&client.client_id).to_string(),
+    }
+}
+
+/// Sends server events to the configured webhook on a task of its own, so neither
+/// client sessions nor their cleanup ever wait for the receiver
+#[derive(Clone)]
+pub struct Webhooks {
+    config: Arc<WebhookConfig>,
+    server_name: Option<String>,
+    queue: mpsc::Sender<WebhookPayload>,
+    /// Taken by `start`
+    delivery: Arc<Mutex<Option<Delivery>>>,
+}
+
+impl Webhooks {
+    pub fn new(config: &WebhookConfig, server_name: Option<String>) -> Result<Self> {
+        let url = WebhookUrl::parse(&config.url)?;
+        let connector = if url.tls {
+            let tls_config = tls::build_client_config(&ClientTlsConfig::default())
+                .context("Cannot verify the certificate of the webhook URL")?;
+            Some(TlsConnector::from(Arc::new(tls_config)))
+        } else {
+            None
+        };
+        let (queue, queued) = mpsc::channel(QUEUE_SIZE);
+        let delivery = Delivery {
+            url,
+            connector,
+            secret: config.secret.clone(),
+            queued,
+            backoff: RETRY_BACKOFF,
+        };
+        Ok(Self {
+            config: Arc::new(config.clone()),
+            server_name,
+            queue,
+            delivery: Arc::new(Mutex::new(Some(delivery))),
+        })
+    }
+
+    /// Starts delivering queued events, once. The task outlives the server's other
+    /// background tasks, so the disconnects of its shutdown are still sent
+    pub fn start(&self) {
+        if let Some(delivery) = self.delivery.lock().unwrap().take() {
+            tokio::spawn(delivery.run());
+        }
+    }
+
+    /// Queues an event unless it is filtered out, dropping it if the queue is full
+    pub fn notify(&self, mut payload: WebhookPayload) {
+        if !self.config.sends(payload.event) {
+            return;
+        }
+        payload.server = self.server_name.clone();
+        if let Err(TrySendError::Full(payload)) = self.queue.try_send(payload) {
+            log_warn!(
+                "Webhook queue is full, dropped a {} event",
+                payload.event.as_str()
+            );
+        }
+    }
+}
+
+/// The receiving end of the queue, with what delivering needs
+struct Delivery {
+    url: WebhookUrl,
+    connector: Option<TlsConnector>,
+    secret: Option<String>,
+    queued: mpsc::Receiver<WebhookPayload>,
+    backoff: Duration,
+}
+
+impl Delivery {
+    async fn run(mut self) {
+        while let Some(payload) = self.queued.recv().await {
+            let event = payload.event.as_str();
+            if let Err(e) = self.deliver(&payload).await {
+                log_warn!(
+                    "Webhook {} event not delivered to {} after {} attempts: {}",
+                    event,
+                    self.url.host,
+                    ATTEMPTS,
+                    e
+                );
+            }
+        }
+    }
+
+    /// POSTs one payload, retrying with exponential backoff
+    async fn deliver(&self, payload: &WebhookPayload) -> Result<()> {
+        let body = serde_json::to_vec(payload)?;
+        let mut headers = vec![(EVENT_HEADER, payload.event.as_str().to_string())];
+        if let Some(secret) = &self.secret {
+            headers.push((SIGNATURE_HEADER, webhook::signature(secret, &body)));
+        }
+
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            let sent = timeout(
+                ATTEMPT_TIMEOUT,
+                webhook::post(&self.url, self.connector.as_ref(), &headers, &body),
+            )
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out after {:?}", ATTEMPT_TIMEOUT)));
+            match sent {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt == ATTEMPTS => return Err(e),
+                Err(e) => {
+                    log_debug!(
+                        "Webhook attempt {} for a {} event failed, retrying in {:?}: {}",
+                        attempt,
+                        payload.event.as_str(),
+                        backoff,
+                        e
+                    );
+                }
+            }
+            sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+impl Server {
+    /// Reports an event to the webhook, if one is configured
+    pub(super) fn notify(&self, payload: WebhookPayload) {
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.notify(payload);
+        }
+    }
+
+    pub(super) fn notify_client_authenticated(&self, client: &ClientConnection) {
+        let text = format!(
+            "Client {} connected from {}",
+            client_text(client),
+            client.addr
+        );
+        self.notify(WebhookPayload::new(WebhookEvent::ClientAuthenticated, text).client(client));
+    }
+
+    pub(super) fn notify_client_disconnected(&self, client: &ClientConnection) {
+        let text = format!("Client {} disconnected", client_text(client));
+        self.notify(WebhookPayload::new(WebhookEvent::ClientDisconnected, text).client(client));
+    }
+
+    /// Reports a registered proxy, `host` naming the route of one on a shared port
+    pub(super) fn notify_proxy_registered(
+        &self,
+        client: &ClientConnection,
+        proxy: &ProxyInfo,
+        host: Option<&str>,
+    ) {
+        let reached = match (host, proxy.remote_port) {
+            (Some(host), port) => format!("on {} (port {})", host, port),
+            (None, 0) => "for visitors".to_string(),
+            (None, port) => format!("on port {}", port),
+        };
+        let text = format!(
+            "Client {} registered proxy '{}' {}",
+            client_text(client),
+            proxy.name,
+            reached
+        );
+        self.notify(
+            WebhookPayload::new(WebhookEvent::ProxyRegistered, text)
+                .client(client)
+                .proxy(proxy, host),
+        );
+    }
+
+    /// Reports a proxy whose listener could not be bound
+    pub(super) async fn notify_listener_bind_failed(
+        &self,
+        client_id: &str,
+        proxy: &ProxyInfo,
+        error: String,
+    ) {
+        let clients_guard = self.clients.read().await;
+        let Some(client) = clients_guard.get(client_id) else {
+            return;
+        };
+        let text = format!(
+            "Proxy '{}' of client {} could not listen: {}",
+            proxy.name,
+            client_text(client),
+            error
+        );
+        let mut payload = WebhookPayload::new(WebhookEvent::ListenerBindFailed, text)
+            .client(client)
+            .proxy(proxy, None);
+        payload.error = Some(error);
+        self.notify(payload);
+    }
+
+    pub(super) fn notify_auth_failure_burst(&self, ip: IpAddr) {
+        let text = format!(
+            "Banned {} for {} after {} authentication failures",
+            ip, self.config.auth_ban_duration, self.config.auth_fail_limit
+        );
+        let mut payload = WebhookPayload::new(WebhookEvent::AuthFailureBurst, text);
+        payload.ip = Some(ip);
+        self.notify(payload);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::stats::ProxyStats;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn proxy(remote_port: u16) -> ProxyInfo {
+        ProxyInfo {
+            name: "web".to_string(),
+            local_ip: "127.0.0.1".to_string(),
+            local_port: 3000,
+            remote_port,
+            max_connections: None,
+            stats: Arc::new(ProxyStats::default()),
+            expires_at: None,
+            local_error: None,
+        }
+    }
+
+    #[test]
+    fn test_payload_leaves_out_what_does_not_apply() {
+        let mut payload =
+            WebhookPayload::new(WebhookEvent::ProxyRegistered, "registered".to_string())
+                .proxy(&proxy(8080), None);
+        payload.server = Some("edge".to_string());
+        let json = serde_json::to_value(&payload).unwrap();
+        assert_eq!(json["event"], "proxy_registered");
+        assert_eq!(json["server"], "edge");
+        assert_eq!(json["proxy"]["local"], "127.0.0.1:3000");
+        assert_eq!(json["proxy"]["remote_port"], 8080);
+        assert!(chrono::DateTime::parse_from_rfc3339(json["timestamp"].as_str().unwrap()).is_ok());
+        for absent in ["client", "ip", "error"] {
+            assert!(json.get(absent).is_none(), "{}", absent);
+        }
+        assert!(json["proxy"].get("host").is_none());
+
+        let secret = WebhookPayload::new(WebhookEvent::ProxyRegistered, String::new())
+            .proxy(&proxy(0), None);
+        let json = serde_json::to_value(&secret).unwrap();
+        assert!(json["proxy"]["remote_port"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_filtered_events_are_dropped_and_others_signed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = WebhookConfig {
+            url: format!("http://{}/hook", listener.local_addr().unwrap()),
+            events: Some(vec![WebhookEvent::AuthFailureBurst]),
+            secret: Some("shared".to_string()),
+        };
+        let webhooks = Webhooks::new(&config, Some("edge".to_string())).unwrap();
+        webhooks.delivery.lock().unwrap().as_mut().unwrap().backoff = Duration::from_millis(10);
+        webhooks.start();
+
+        webhooks.notify(WebhookPayload::new(
+            WebhookEvent::ClientDisconnected,
+            "filtered".to_string(),
+        ));
+        let mut burst = WebhookPayload::new(WebhookEvent::AuthFailureBurst, "sent".to_string());
+        burst.ip = Some("203.0.113.7".parse().unwrap());
+        webhooks.notify(burst);
+
+        // the first attempt is refused, the retry accepted
+        let mut requests = Vec::new();
+        for status in ["503 Service Unavailable", "200 OK"] {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut chunk = [0u8; 4096];
+            while !request.ends_with(b"}") {
+                let n = stream.read(&mut chunk).await.unwrap();
+                request.extend_from_slice(&chunk[..n]);
+            }
+            let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+            stream.write_all(response.as_bytes()).await.unwrap();
+            requests.push(String::from_utf8(request).unwrap());
+        }
+        assert_eq!(requests[0], requests[1]);
+
+        let (head, body) = requests[1].split_once("\r\n\r\n").unwrap();
+        let json: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(json["event"], "auth_failure_burst");
+        assert_eq!(json["ip"], "203.0.113.7");
+        assert_eq!(json["server"], "edge");
+        let headers: Vec<&str> = head.lines().collect();
+        assert!(headers.contains(&"X-Sowback-Event: auth_failure_burst"));
+        let signature = webhook::signature("shared", body.as_bytes());
+        assert!(headers.contains(&format!("X-Sowback-Signature: {}", signature).as_str()));
+    }
+}
@@ -0,0 +1,37 @@
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep_until, Duration, Instant};
+
+/// Last time bytes flowed through a connection, shared by its reading and writing halves
+#[derive(Debug, Clone)]
+pub struct Activity(Arc<Mutex<Instant>>);
+
+impl Activity {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    /// Records traffic now
+    pub fn touch(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    /// Resolves once no traffic was recorded for `timeout`, never when it is None
+    pub async fn idle(&self, timeout: Option<Duration>) {
+        let Some(timeout) = timeout else {
+            return std::future::pending().await;
+        };
+        loop {
+            let deadline = *self.0.lock().unwrap() + timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            sleep_until(deadline).await;
+        }
+    }
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
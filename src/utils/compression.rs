@@ -0,0 +1,125 @@
+use anyhow::{anyhow, Result};
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::protocol::MAX_DATA_PAYLOAD;
+
+/// Default minimum payload size (in bytes) before compression is attempted
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 512;
+
+/// zstd level used for tunneled payloads, favouring speed over ratio
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compression codec for `Data` payloads
+#[derive(
+    Debug, Clone, Copy, Default, Serialize, Deserialize, Encode, Decode, PartialEq, Eq, Hash,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Payloads are sent as-is
+    #[default]
+    None,
+    /// Payloads are compressed with zstd
+    Zstd,
+}
+
+impl Compression {
+    /// Codecs to advertise to the server, in order of preference
+    pub fn advertised(preferred: Compression) -> Vec<Compression> {
+        match preferred {
+            Compression::None => vec![Compression::None],
+            Compression::Zstd => vec![Compression::Zstd, Compression::None],
+        }
+    }
+
+    /// Picks the codec for a session: the server's preference if the client supports it,
+    /// otherwise no compression
+    pub fn negotiate(server_preferred: Compression, client_offered: &[Compression]) -> Compression {
+        if client_offered.contains(&server_preferred) {
+            server_preferred
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Compresses `data` if it is at least `threshold` bytes long.
+    /// Returns None when compression is disabled, skipped, or would not make the payload smaller.
+    pub fn compress(&self, data: &[u8], threshold: usize) -> Option<Vec<u8>> {
+        if data.len() < threshold {
+            return None;
+        }
+
+        match self {
+            Compression::None => None,
+            Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL)
+                .ok()
+                .filter(|compressed| compressed.len() < data.len()),
+        }
+    }
+
+    /// Decompresses a payload produced by [`Compression::compress`], failing if it
+    /// expands beyond `MAX_DATA_PAYLOAD`
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Zstd => zstd::bulk::decompress(data, MAX_DATA_PAYLOAD)
+                .map_err(|e| anyhow!("zstd decompression failed: {}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    #[test]
+    fn test_zstd_roundtrip_compressible() {
+        let data = b"GET /api/v1/items HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(64);
+
+        let compressed = Compression::Zstd
+            .compress(&data, DEFAULT_COMPRESSION_THRESHOLD)
+            .expect("compressible payload should be compressed");
+        assert!(compressed.len() < data.len());
+
+        let decompressed = Compression::Zstd.decompress(&compressed).unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn test_zstd_skips_incompressible() {
+        let mut data = vec![0u8; 4096];
+        rand::rng().fill_bytes(&mut data);
+
+        assert!(Compression::Zstd
+            .compress(&data, DEFAULT_COMPRESSION_THRESHOLD)
+            .is_none());
+    }
+
+    #[test]
+    fn test_below_threshold_is_skipped() {
+        let data = vec![b'a'; DEFAULT_COMPRESSION_THRESHOLD - 1];
+        assert!(Compression::Zstd
+            .compress(&data, DEFAULT_COMPRESSION_THRESHOLD)
+            .is_none());
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let offered = Compression::advertised(Compression::Zstd);
+        assert_eq!(
+            Compression::negotiate(Compression::Zstd, &offered),
+            Compression::Zstd
+        );
+        assert_eq!(
+            Compression::negotiate(Compression::None, &offered),
+            Compression::None
+        );
+
+        let offered = Compression::advertised(Compression::None);
+        assert_eq!(
+            Compression::negotiate(Compression::Zstd, &offered),
+            Compression::None
+        );
+    }
+}
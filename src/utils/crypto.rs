@@ -1,22 +1,62 @@
 use aes_gcm::{aead::Aead, Aes256Gcm, Key, KeyInit, Nonce};
 use anyhow::{anyhow, Result};
 use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
 use rand::RngCore;
-use sha2::{Digest, Sha256};
+use sha2::Sha256;
 
-pub const MAGIC_SALT: &[u8] = b".Kita_Ikuyo.^_^.";
+/// Length of the server's authentication challenge nonce
+pub const AUTH_NONCE_LEN: usize = 32;
 
-/// SHA-256 with salt
-pub fn sha256_with_salt(data: &[u8], salt: &[u8]) -> Vec<u8> {
-    let mut hasher = Sha256::default();
-    hasher.update(data);
-    hasher.update(salt);
-    hasher.finalize().to_vec()
+/// Generates a random authentication challenge nonce
+pub fn generate_nonce() -> Vec<u8> {
+    let mut nonce = vec![0u8; AUTH_NONCE_LEN];
+    rand::rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+fn auth_mac(token: &str, nonce: &[u8], client_id: &str) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(token.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(nonce);
+    mac.update(client_id.as_bytes());
+    mac
+}
+
+/// Proves knowledge of `token` for a challenge: HMAC-SHA256(token, nonce || client_id)
+pub fn auth_proof(token: &str, nonce: &[u8], client_id: &str) -> Vec<u8> {
+    auth_mac(token, nonce, client_id)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+/// Verifies an [`auth_proof`] in constant time
+pub fn verify_auth_proof(token: &str, nonce: &[u8], client_id: &str, proof: &[u8]) -> bool {
+    auth_mac(token, nonce, client_id)
+        .verify_slice(proof)
+        .is_ok()
+}
+
+/// Identifies a secret service to the server without revealing its secret:
+/// HMAC-SHA256(secret, service name). Owner and visitors send the same hash
+pub fn secret_hash(service_name: &str, secret: &str) -> Vec<u8> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(service_name.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Compares two secret hashes in constant time
+pub fn secret_hashes_match(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
 }
 
 /// Cryptographic context for secure communication between client and server
 pub struct CryptoContext {
     cipher: Aes256Gcm,
+    /// Keys the data channel proofs of the session
+    session_key: Vec<u8>,
 }
 
 impl CryptoContext {
@@ -38,7 +78,39 @@ impl CryptoContext {
         let key = Key::<Aes256Gcm>::from_slice(session_key);
         let cipher = Aes256Gcm::new(key);
 
-        Ok(CryptoContext { cipher })
+        Ok(CryptoContext {
+            cipher,
+            session_key: session_key.to_vec(),
+        })
+    }
+
+    fn data_channel_mac(&self, nonce: &[u8], connection_id: &str) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.session_key)
+            .expect("HMAC accepts keys of any size");
+        mac.update(nonce);
+        mac.update(connection_id.as_bytes());
+        mac
+    }
+
+    /// Proves a data channel belongs to the session for a challenge:
+    /// HMAC-SHA256(session key, nonce || connection_id)
+    pub fn data_channel_proof(&self, nonce: &[u8], connection_id: &str) -> Vec<u8> {
+        self.data_channel_mac(nonce, connection_id)
+            .finalize()
+            .into_bytes()
+            .to_vec()
+    }
+
+    /// Verifies a [`CryptoContext::data_channel_proof`] in constant time
+    pub fn verify_data_channel_proof(
+        &self,
+        nonce: &[u8],
+        connection_id: &str,
+        proof: &[u8],
+    ) -> bool {
+        self.data_channel_mac(nonce, connection_id)
+            .verify_slice(proof)
+            .is_ok()
     }
 
     /// Encrypts data using AES-256-GCM with a random nonce
@@ -94,4 +166,46 @@ mod tests {
 
         assert_eq!(original_data, decrypted.as_slice());
     }
+
+    #[test]
+    fn test_auth_proof() {
+        let client_id = "0058454c-ba2f-40de-8390-c1bcfc65754f";
+        let nonce = generate_nonce();
+        let proof = auth_proof("ciallo", &nonce, client_id);
+
+        assert!(verify_auth_proof("ciallo", &nonce, client_id, &proof));
+        assert!(!verify_auth_proof("wrong", &nonce, client_id, &proof));
+        assert!(!verify_auth_proof(
+            "ciallo",
+            &generate_nonce(),
+            client_id,
+            &proof
+        ));
+        assert!(!verify_auth_proof("ciallo", &nonce, "other-client", &proof));
+    }
+
+    #[test]
+    fn test_data_channel_proof() {
+        let key = CryptoContext::derive_session_key("ciallo", "client").unwrap();
+        let crypto = CryptoContext::new(&key).unwrap();
+        let nonce = generate_nonce();
+        let proof = crypto.data_channel_proof(&nonce, "conn-1");
+
+        assert!(crypto.verify_data_channel_proof(&nonce, "conn-1", &proof));
+        assert!(!crypto.verify_data_channel_proof(&nonce, "conn-2", &proof));
+        assert!(!crypto.verify_data_channel_proof(&generate_nonce(), "conn-1", &proof));
+        let other_key = CryptoContext::derive_session_key("ciallo", "other-client").unwrap();
+        let other = CryptoContext::new(&other_key).unwrap();
+        assert!(!other.verify_data_channel_proof(&nonce, "conn-1", &proof));
+    }
+
+    #[test]
+    fn test_secret_hash() {
+        let hash = secret_hash("ssh", "ciallo");
+
+        assert!(secret_hashes_match(&hash, &secret_hash("ssh", "ciallo")));
+        assert!(!secret_hashes_match(&hash, &secret_hash("ssh", "wrong")));
+        assert!(!secret_hashes_match(&hash, &secret_hash("web", "ciallo")));
+        assert!(!secret_hashes_match(&hash, &hash[..16]));
+    }
 }
@@ -0,0 +1,273 @@
+use anyhow::{anyhow, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How long `stop_daemon` waits for the process to exit after signalling it
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The pid file a daemon wrote, removed again when dropped unless another process
+/// took it over since
+#[derive(Debug)]
+pub struct PidFile {
+    path: Option<PathBuf>,
+}
+
+impl PidFile {
+    /// A handle on `pid_file` for a process writing its pid there later on, e.g. a server
+    /// that takes over from another on an upgrade
+    pub fn adopt(pid_file: &Path) -> Self {
+        PidFile {
+            path: Some(pid_file.to_path_buf()),
+        }
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if read_pid(path).ok().flatten() == Some(std::process::id() as i32) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// The pid `pid_file` holds, None when it does not exist
+fn read_pid(pid_file: &Path) -> Result<Option<i32>> {
+    let content = match fs::read_to_string(pid_file) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(anyhow!(
+                "Cannot read pid file {}: {}",
+                pid_file.display(),
+                e
+            ))
+        }
+    };
+    // 0 and negative pids would signal whole process groups
+    match content.trim().parse() {
+        Ok(pid) if pid > 0 => Ok(Some(pid)),
+        _ => Err(anyhow!("Pid file {} holds no pid", pid_file.display())),
+    }
+}
+
+/// Writes `pid` to `pid_file` through a temporary file renamed over it, so readers never
+/// see it half written
+pub(crate) fn write_pid(pid_file: &Path, pid: u32) -> std::io::Result<()> {
+    let mut temporary = pid_file.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, format!("{}\n", pid))?;
+    fs::rename(&temporary, pid_file)
+}
+
+/// Whether a process with `pid` exists
+#[cfg(unix)]
+fn is_alive(pid: i32) -> bool {
+    // SAFETY: signal 0 only checks that the process exists and may be signalled
+    let signalled = unsafe { libc::kill(pid, 0) } == 0;
+    signalled || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Forks into the background with the classic double fork: the calling process exits once
+/// the daemon wrote `pid_file`, the daemon returns in a new session without a controlling
+/// terminal, stdin on /dev/null and stdout and stderr appended to `output` or /dev/null.
+/// Refuses to start when `pid_file` names a live process. Must run before any thread is
+/// started, the tokio runtime and the logger included
+#[cfg(unix)]
+pub fn daemonize(pid_file: Option<&Path>, output: Option<&Path>) -> Result<PidFile> {
+    use std::fs::OpenOptions;
+    use std::io::{Read, Write};
+    use std::os::fd::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    if let Some(pid_file) = pid_file {
+        if let Some(pid) = read_pid(pid_file)?.filter(|&pid| is_alive(pid)) {
+            return Err(anyhow!(
+                "Already running as pid {}, see {}",
+                pid,
+                pid_file.display()
+            ));
+        }
+    }
+    // opened here so a bad path is reported on the terminal
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    let output = match output {
+        Some(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow!("Cannot open {}: {}", path.display(), e))?,
+        None => null.try_clone()?,
+    };
+    // the daemon reports whether it started through this pair
+    let (mut started, mut report) = UnixStream::pair()?;
+
+    // SAFETY: no other thread runs yet, the children may use anything the parent set up
+    match unsafe { libc::fork() } {
+        -1 => return Err(std::io::Error::last_os_error().into()),
+        0 => {}
+        _ => {
+            drop(report);
+            let mut outcome = String::new();
+            started.read_to_string(&mut outcome)?;
+            if outcome.is_empty() {
+                return Err(anyhow!("The daemon exited while starting"));
+            }
+            if let Some(error) = outcome.strip_prefix("error: ") {
+                return Err(anyhow!("{}", error));
+            }
+            std::process::exit(0);
+        }
+    }
+    drop(started);
+    // SAFETY: setsid and fork take no pointers, the intermediate child leaves with _exit
+    // so nothing of the parent's state is torn down twice
+    unsafe {
+        if libc::setsid() == -1 {
+            let _ = write!(
+                report,
+                "error: setsid failed: {}",
+                std::io::Error::last_os_error()
+            );
+            libc::_exit(1);
+        }
+        // a child of the session leader can never acquire a controlling terminal again
+        match libc::fork() {
+            -1 => {
+                let _ = write!(
+                    report,
+                    "error: fork failed: {}",
+                    std::io::Error::last_os_error()
+                );
+                libc::_exit(1);
+            }
+            0 => {}
+            _ => libc::_exit(0),
+        }
+    }
+
+    let pid = std::process::id();
+    if let Some(pid_file) = pid_file {
+        if let Err(e) = write_pid(pid_file, pid) {
+            let _ = write!(
+                report,
+                "error: cannot write pid file {}: {}",
+                pid_file.display(),
+                e
+            );
+            std::process::exit(1);
+        }
+    }
+    // SAFETY: dup2 only replaces the standard descriptors with ones open in this process
+    unsafe {
+        libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO);
+        libc::dup2(output.as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(output.as_raw_fd(), libc::STDERR_FILENO);
+    }
+    let _ = write!(report, "started {}", pid);
+    Ok(PidFile {
+        path: pid_file.map(Path::to_path_buf),
+    })
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_pid_file: Option<&Path>, _output: Option<&Path>) -> Result<PidFile> {
+    Err(anyhow!(
+        "--daemon is only supported on Unix, run sowback as a service instead"
+    ))
+}
+
+/// Sends SIGTERM to the daemon `pid_file` names and waits for it to exit, returning its pid
+#[cfg(unix)]
+pub fn stop_daemon(pid_file: &Path) -> Result<i32> {
+    let pid = read_pid(pid_file)?
+        .ok_or_else(|| anyhow!("No pid file at {}, is it running?", pid_file.display()))?;
+    if !is_alive(pid) {
+        return Err(anyhow!(
+            "Pid {} of {} is not running",
+            pid,
+            pid_file.display()
+        ));
+    }
+    // SAFETY: kill takes no pointers
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        return Err(anyhow!(
+            "Cannot signal pid {}: {}",
+            pid,
+            std::io::Error::last_os_error()
+        ));
+    }
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    while is_alive(pid) {
+        if Instant::now() >= deadline {
+            return Err(anyhow!(
+                "Pid {} is still running {}s after SIGTERM",
+                pid,
+                STOP_TIMEOUT.as_secs()
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    Ok(pid)
+}
+
+#[cfg(not(unix))]
+pub fn stop_daemon(_pid_file: &Path) -> Result<i32> {
+    Err(anyhow!("--stop is only supported on Unix"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_files_are_written_whole_and_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("sowback.pid");
+        assert_eq!(read_pid(&pid_file).unwrap(), None);
+
+        write_pid(&pid_file, 4242).unwrap();
+        assert_eq!(fs::read_to_string(&pid_file).unwrap(), "4242\n");
+        assert_eq!(read_pid(&pid_file).unwrap(), Some(4242));
+        assert!(!dir.path().join("sowback.pid.tmp").exists());
+
+        for garbage in ["garbage", "0", "-1"] {
+            fs::write(&pid_file, garbage).unwrap();
+            assert!(read_pid(&pid_file).is_err());
+        }
+
+        // dropping the daemon's handle removes its pid file
+        write_pid(&pid_file, std::process::id()).unwrap();
+        drop(PidFile::adopt(&pid_file));
+        assert!(!pid_file.exists());
+
+        // unless another process took it over since
+        write_pid(&pid_file, 4242).unwrap();
+        drop(PidFile::adopt(&pid_file));
+        assert_eq!(read_pid(&pid_file).unwrap(), Some(4242));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stopping_needs_a_live_process() {
+        assert!(is_alive(std::process::id() as i32));
+
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("sowback.pid");
+        let err = stop_daemon(&pid_file).unwrap_err().to_string();
+        assert!(err.contains("No pid file"), "{}", err);
+
+        // a process that has exited and been reaped
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        write_pid(&pid_file, child.id()).unwrap();
+        let err = stop_daemon(&pid_file).unwrap_err().to_string();
+        assert!(err.contains("is not running"), "{}", err);
+    }
+}
@@ -1,15 +1,53 @@
-use crate::utils::protocol::Frame;
+use crate::utils::protocol::{Frame, Framing, FRAME_MAGIC};
 use anyhow::Result;
+use bytes::{Buf, BytesMut};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Free space `fill_from` makes sure of before each read
+const READ_CHUNK: usize = 4096;
 
 /// Utility for reading framed messages from a stream buffer
+#[derive(Default)]
 pub struct FrameReader {
-    buffer: Vec<u8>,
+    /// Frames are split off the front, so consuming one never moves the rest
+    buffer: BytesMut,
+    /// Framing of the session, deciding what happens to a corrupt frame
+    framing: Framing,
+    /// Bytes of corrupt frames skipped since `take_skipped` was last called
+    skipped: usize,
 }
 
 impl FrameReader {
     /// Creates a new frame reader with an empty buffer
     pub fn new() -> Self {
-        Self { buffer: Vec::new() }
+        Self {
+            buffer: BytesMut::new(),
+            framing: Framing::Plain,
+            skipped: 0,
+        }
+    }
+
+    /// Sets the framing negotiated for the session once the handshake is done. Frames
+    /// are read marked or not either way, with `Framing::Marked` a corrupt frame is
+    /// skipped up to the next `FRAME_MAGIC` instead of failing the read
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    /// The framing set for the session, which its own frames are sent with too
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Bytes skipped to resynchronize since the last call, 0 if none
+    pub fn take_skipped(&mut self) -> usize {
+        std::mem::take(&mut self.skipped)
+    }
+
+    /// Bytes received and not read as frames yet
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
     }
 
     /// Adds new data to the internal buffer
@@ -17,35 +55,107 @@ impl FrameReader {
         self.buffer.extend_from_slice(data);
     }
 
+    /// Reads once from `reader` straight into the internal buffer, returning the number
+    /// of bytes read, 0 at the end of the stream
+    pub async fn fill_from<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> io::Result<usize> {
+        // reclaims the space of consumed frames when nothing is left behind them
+        self.buffer.reserve(READ_CHUNK);
+        reader.read_buf(&mut self.buffer).await
+    }
+
     /// Attempts to read a complete frame from the buffer
-    /// Returns None if there's insufficient data for a complete frame
+    /// Returns None if there's insufficient data for a complete frame.
+    /// A frame that cannot be decoded leaves a plain stream unusable, the buffer is
+    /// discarded; a marked one is resynchronized, see `set_framing`
     pub fn try_read_frame(&mut self) -> Result<Option<Frame>> {
-        if self.buffer.len() < 4 {
+        loop {
+            match self.next_frame() {
+                Ok(frame) => return Ok(frame),
+                Err(_) if self.framing == Framing::Marked => {
+                    if !self.resynchronize() {
+                        return Ok(None);
+                    }
+                }
+                Err(e) => {
+                    self.buffer.clear();
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Decodes the frame at the front of the buffer, consuming it once it is complete
+    fn next_frame(&mut self) -> Result<Option<Frame>> {
+        let header = match self.buffer.starts_with(&FRAME_MAGIC) {
+            true => FRAME_MAGIC.len() + 4,
+            false => 4,
+        };
+        if self.buffer.len() < header {
             return Ok(None);
         }
 
-        // Read frame length
-        let length = u32::from_be_bytes([
-            self.buffer[0],
-            self.buffer[1],
-            self.buffer[2],
-            self.buffer[3],
-        ]) as usize;
+        // a length past the limit is refused before anything is buffered for it
+        let length = Frame::length_prefix(&self.buffer[header - 4..header])?;
 
         // Check if we have the complete frame
-        if self.buffer.len() < 4 + length {
+        if self.buffer.len() < header + length {
             return Ok(None);
         }
 
-        // Extract frame data
-        let frame_data = &self.buffer[..4 + length];
-        let (frame, _) = Frame::deserialize(frame_data)
-            .map_err(|e| anyhow::anyhow!("Frame deserialization error: {}", e))?;
+        let message =
+            Frame::decode_message(&self.buffer[header..header + length]).map_err(|e| {
+                anyhow::anyhow!(
+                    "Frame deserialization error (length prefix {}): {}",
+                    length,
+                    e
+                )
+            })?;
+        self.buffer.advance(header + length);
+        Ok(Some(Frame {
+            length: length as u32,
+            message,
+        }))
+    }
+
+    /// Drops the corrupt frame at the front of the buffer up to the next `FRAME_MAGIC`,
+    /// returning whether one was found. Without one, only what could be the start of
+    /// a magic is kept
+    fn resynchronize(&mut self) -> bool {
+        let next = self
+            .buffer
+            .windows(FRAME_MAGIC.len())
+            .skip(1)
+            .position(|window| window == FRAME_MAGIC)
+            .map(|position| position + 1);
+        let skip = match next {
+            Some(position) => position,
+            None => self.buffer.len() - partial_magic(&self.buffer),
+        };
+        self.buffer.advance(skip);
+        self.skipped += skip;
+        next.is_some()
+    }
 
-        // Remove processed data from buffer
-        self.buffer.drain(..4 + length);
+    /// Reads from `reader` until a complete frame is available.
+    /// Returns None if the stream ends first.
+    pub async fn read_frame<R: AsyncRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<Option<Frame>> {
+        loop {
+            if let Some(frame) = self.try_read_frame()? {
+                return Ok(Some(frame));
+            }
+            if self.fill_from(reader).await? == 0 {
+                return Ok(None);
+            }
+        }
+    }
 
-        Ok(Some(frame))
+    /// Gives up the bytes received past the last frame read, for a stream that stops
+    /// carrying frames
+    pub fn into_buffered(self) -> bytes::Bytes {
+        self.buffer.freeze()
     }
 
     /// Clears the internal buffer
@@ -53,3 +163,137 @@ impl FrameReader {
         self.buffer.clear();
     }
 }
+
+/// Length of the longest end of `buffer` a `FRAME_MAGIC` could start with, up to 3 bytes
+fn partial_magic(buffer: &[u8]) -> usize {
+    (1..FRAME_MAGIC.len())
+        .rev()
+        .find(|&len| buffer.ends_with(&FRAME_MAGIC[..len]))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{CloseCode, Message};
+
+    #[test]
+    fn test_garbage_after_valid_frame_is_an_error() {
+        let mut reader = FrameReader::new();
+        let mut data = Frame::new(Message::new_heartbeat()).serialize().unwrap();
+        data.extend_from_slice(&[0, 0, 0, 3, 0xff, 0xff, 0xff]);
+        reader.feed_data(&data);
+
+        assert!(matches!(
+            reader.try_read_frame().unwrap().unwrap().message,
+            Message::Heartbeat { .. }
+        ));
+        let error = reader.try_read_frame().unwrap_err();
+        assert!(error.to_string().contains("length prefix 3"));
+        // the bad bytes are gone rather than tripping every later read
+        assert!(reader.try_read_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_marked_frames_resynchronize() {
+        let marked = |message| {
+            Frame::new(message)
+                .serialize_framed(Framing::Marked)
+                .unwrap()
+        };
+        let mut reader = FrameReader::new();
+        reader.set_framing(Framing::Marked);
+
+        // a frame that does not decode is skipped up to the next magic
+        let mut data = FRAME_MAGIC.to_vec();
+        data.extend_from_slice(&[0, 0, 0, 3, 0xff, 0xff, 0xff]);
+        let next = marked(Message::new_close_connection("conn", CloseCode::PeerClosed));
+        data.extend(&next);
+        reader.feed_data(&data);
+        assert!(matches!(
+            reader.try_read_frame().unwrap().unwrap().message,
+            Message::CloseConnection { .. }
+        ));
+        assert_eq!(reader.take_skipped(), data.len() - next.len());
+        assert_eq!(reader.take_skipped(), 0);
+
+        // without a magic in sight, only what could start one is kept
+        reader.feed_data(&[0, 0, 0, 3, 0xff, 0xff, 0xff, FRAME_MAGIC[0], FRAME_MAGIC[1]]);
+        assert!(reader.try_read_frame().unwrap().is_none());
+        assert_eq!(reader.buffered(), 2);
+        reader.feed_data(&marked(Message::new_heartbeat())[2..]);
+        assert!(matches!(
+            reader.try_read_frame().unwrap().unwrap().message,
+            Message::Heartbeat { .. }
+        ));
+        assert_eq!(reader.take_skipped(), 7);
+        assert_eq!(reader.buffered(), 0);
+    }
+
+    #[test]
+    fn test_unmarked_frames_read_in_a_marked_session() {
+        let mut reader = FrameReader::new();
+        reader.set_framing(Framing::Marked);
+        let mut data = Frame::new(Message::new_heartbeat()).serialize().unwrap();
+        data.extend(
+            Frame::new(Message::new_heartbeat())
+                .serialize_framed(Framing::Marked)
+                .unwrap(),
+        );
+        reader.feed_data(&data);
+        for _ in 0..2 {
+            assert!(reader.try_read_frame().unwrap().is_some());
+        }
+        assert_eq!(reader.take_skipped(), 0);
+    }
+
+    #[test]
+    fn test_frames_spanning_feeds() {
+        let mut data = Vec::new();
+        for i in 0..3 {
+            let message = Message::new_data("conn", u64::from(i), vec![i; 10_000]);
+            data.extend(Frame::new(message).serialize().unwrap());
+        }
+
+        // odd sized feeds split length prefixes and payloads alike
+        let mut reader = FrameReader::new();
+        let mut payloads = Vec::new();
+        for chunk in data.chunks(7) {
+            reader.feed_data(chunk);
+            while let Some(frame) = reader.try_read_frame().unwrap() {
+                let Message::Data { data, .. } = frame.message else {
+                    panic!("expected data");
+                };
+                payloads.push(data);
+            }
+        }
+        assert_eq!(
+            payloads,
+            (0..3).map(|i| vec![i; 10_000]).collect::<Vec<_>>()
+        );
+        assert!(reader.buffer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fill_from_reads_into_the_buffer() {
+        let mut data = Frame::new(Message::new_heartbeat()).serialize().unwrap();
+        data.extend(
+            Frame::new(Message::new_close_connection("conn", CloseCode::PeerClosed))
+                .serialize()
+                .unwrap(),
+        );
+        let (mut tx, mut rx) = tokio::io::duplex(3);
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            tx.write_all(&data).await.unwrap();
+        });
+
+        let mut reader = FrameReader::new();
+        let first = reader.read_frame(&mut rx).await.unwrap().unwrap();
+        assert!(matches!(first.message, Message::Heartbeat { .. }));
+        let second = reader.read_frame(&mut rx).await.unwrap().unwrap();
+        assert!(matches!(second.message, Message::CloseConnection { .. }));
+        assert!(reader.read_frame(&mut rx).await.unwrap().is_none());
+        assert_eq!(reader.fill_from(&mut rx).await.unwrap(), 0);
+    }
+}
@@ -1,9 +1,29 @@
+pub mod activity;
+pub mod compression;
 pub mod crypto;
+pub mod daemon;
 pub mod frame_reader;
+pub mod net;
 pub mod protocol;
 pub mod proxy;
+pub mod proxy_protocol;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod replay;
+pub mod runtime;
+pub mod sd_notify;
+pub mod socket;
+pub mod tls;
+pub mod transport;
+pub mod webhook;
+pub mod websocket;
+pub mod window;
 
+pub use activity::Activity;
 pub use crypto::CryptoContext;
 pub use frame_reader::FrameReader;
-pub use protocol::{Frame, Message};
-pub use proxy::forward_data;
+pub use protocol::{CloseCode, Frame, Message, TransferStats};
+pub use replay::{ReplayBuffer, Route};
+pub use socket::SocketOptions;
+pub use transport::{write_all_within, BoxedStream, Rewound};
+pub use window::{Acknowledger, SendWindow};
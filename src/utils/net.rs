@@ -0,0 +1,191 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpListener, ToSocketAddrs};
+
+/// Backlog of the listeners `bind_tcp` creates
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// How long an accept loop pauses once file descriptors ran out. Every accept fails at once
+/// until closing connections free some, retrying right away would only spin
+pub const FD_EXHAUSTION_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Strips the brackets of an IPv6 literal written as `[::1]`
+pub fn unbracket(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+/// `host:port` for display, with IPv6 literals in brackets
+pub fn join_host_port(host: &str, port: u16) -> String {
+    let host = unbracket(host);
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Name of this machine, None when the OS does not tell
+pub fn hostname() -> Option<String> {
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        // SAFETY: gethostname writes at most buf.len() bytes into buf
+        if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+            return None;
+        }
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        let name = String::from_utf8_lossy(&buf[..len]).into_owned();
+        Some(name).filter(|name| !name.is_empty())
+    }
+    #[cfg(not(unix))]
+    {
+        std::env::var("COMPUTERNAME")
+            .ok()
+            .filter(|name| !name.is_empty())
+    }
+}
+
+/// Whether `bind_tcp` can set SO_REUSEPORT on this platform
+pub const REUSE_PORT_SUPPORTED: bool = cfg!(all(
+    unix,
+    not(any(
+        target_os = "solaris",
+        target_os = "illumos",
+        target_os = "cygwin"
+    ))
+));
+
+/// Binds a TCP listener, e.g. `("::", 8080)` or `"0.0.0.0:7000"`, with SO_REUSEADDR set so a
+/// restarted server rebinds ports its old connections still hold in TIME_WAIT, and with
+/// `reuse_port` SO_REUSEPORT too, letting another process bind the same port alongside.
+/// The IPv6 unspecified address accepts IPv4 connections too, whatever the OS default.
+pub async fn bind_tcp(addr: impl ToSocketAddrs, reuse_port: bool) -> io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let addr = lookup_host(addr).await?.next().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+    })?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if let SocketAddr::V6(v6) = addr {
+        if v6.ip().is_unspecified() {
+            socket.set_only_v6(false)?;
+        }
+    }
+    // on Windows it would let other sockets take over the port, rebinding works without it
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+))]
+fn set_reuse_port(socket: &socket2::Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+)))]
+fn set_reuse_port(_socket: &socket2::Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT (reuseport = true) is only available on Linux and the BSDs",
+    ))
+}
+
+/// Whether an accept failed because the process or the system ran out of file descriptors
+pub fn is_fd_exhaustion(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    const EXHAUSTED: [i32; 2] = [libc::EMFILE, libc::ENFILE];
+    // WSAEMFILE
+    #[cfg(windows)]
+    const EXHAUSTED: [i32; 1] = [10024];
+    e.raw_os_error()
+        .is_some_and(|errno| EXHAUSTED.contains(&errno))
+}
+
+/// Soft limit of the files this process may open, None where it is unknown or unlimited
+pub fn open_files_limit() -> Option<u64> {
+    #[cfg(unix)]
+    {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: getrlimit only writes the struct it is given
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0
+            || limit.rlim_cur == libc::RLIM_INFINITY
+        {
+            return None;
+        }
+        // rlim_t is signed on some systems
+        #[allow(clippy::unnecessary_cast)]
+        Some(limit.rlim_cur as u64)
+    }
+    #[cfg(not(unix))]
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn test_host_port_formatting() {
+        assert_eq!(join_host_port("127.0.0.1", 80), "127.0.0.1:80");
+        assert_eq!(join_host_port("::1", 80), "[::1]:80");
+        assert_eq!(join_host_port("[::1]", 80), "[::1]:80");
+        assert_eq!(join_host_port("example.com", 443), "example.com:443");
+        assert_eq!(unbracket("[::]"), "::");
+    }
+
+    #[test]
+    fn test_fd_exhaustion_is_told_apart() {
+        #[cfg(unix)]
+        for errno in [libc::EMFILE, libc::ENFILE] {
+            assert!(is_fd_exhaustion(&io::Error::from_raw_os_error(errno)));
+        }
+        assert!(!is_fd_exhaustion(&io::Error::from(
+            io::ErrorKind::ConnectionAborted
+        )));
+        #[cfg(unix)]
+        assert!(open_files_limit().is_some_and(|limit| limit > 0));
+    }
+
+    #[tokio::test]
+    async fn test_unspecified_ipv6_is_dual_stack() {
+        let listener = bind_tcp(("::", 0), false).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        for target in ["127.0.0.1", "::1"] {
+            let connect = TcpStream::connect((target, port));
+            let (connected, accepted) = tokio::join!(connect, listener.accept());
+            connected.unwrap();
+            accepted.unwrap();
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_reuse_port_shares_the_port() {
+        let first = bind_tcp("127.0.0.1:0", true).await.unwrap();
+        let addr = first.local_addr().unwrap();
+        assert!(bind_tcp(addr, false).await.is_err());
+
+        let second = bind_tcp(addr, true).await.unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+    }
+}
@@ -1,7 +1,76 @@
 use bincode::{Decode, Encode};
+use bytes::{BufMut, BytesMut};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
 
-use crate::utils::crypto::{sha256_with_salt, MAGIC_SALT};
+use crate::config::HumanDuration;
+use crate::logging::{format_bytes, RedactedBytes};
+use crate::utils::compression::Compression;
+use crate::utils::crypto::auth_proof;
+use crate::utils::net;
+
+/// Version of the wire protocol, exchanged during the authentication handshake.
+/// - v2: challenge-response authentication (`AuthChallenge`)
+/// - v3: half-closed connections (`ShutdownWrite`)
+/// - v4: per-connection flow control (`WindowUpdate`)
+/// - v5: `request_id` matching each `ProxyConfigResponse` to its `ProxyConfig`
+/// - v6: `group` letting several clients serve one remote port
+/// - v7: `max_connections` limiting a proxy's concurrent connections
+/// - v8: `client_id` assigned by the server in `AuthResponse`
+/// - v9: secret services, reached by other clients through `VisitorConnect`
+/// - v10: `ttl_ms` removing a proxy once it runs out, announced by `ProxyExpired`
+/// - v11: `ServiceStatus` reporting whether a proxy's local service is reachable
+/// - v12: `stats` of the closing side in `CloseConnection`
+/// - v13: data channels, connections of their own opened with `DataChannelHello`
+/// - v14: `seq` numbering the `Data` and `CompressedData` messages of each connection
+/// - v15: `reason` of a `CloseConnection` the sender's end did not finish itself
+/// - v16: `meta` describing the client's version, platform and host in `Auth`
+/// - v17: frames after the handshake start with `FRAME_MAGIC`, see `Framing`
+/// - v18: `Pause` and `Resume` of a connection whose receiver falls behind
+/// - v19: `stats` of the client's proxies in `HeartbeatResponse`
+/// - v20: relay services, reached by the clients in their `relay_peers` through `RelayConnect`
+/// - v21: `ResumeConnection` carrying connections over to the next session of the client
+/// - v22: `code` telling why the sender of a `CloseConnection` closed the connection
+pub const PROTOCOL_VERSION: u32 = 22;
+
+/// Marks the start of each frame of sessions with `Framing::Marked`. Its first byte is
+/// never the first byte of a length prefix within `MAX_FRAME_LEN`, so marked and unmarked
+/// frames are told apart by their first 4 bytes
+pub const FRAME_MAGIC: [u8; 4] = [0xf5, b'S', b'W', b'B'];
+
+/// Largest encoded message a frame may carry. A longer length prefix can only come from
+/// a corrupt stream, so it is never waited for
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// Whether the frames of a session start with `FRAME_MAGIC`, picked by the protocol
+/// version both ends speak once the handshake is done. Handshake frames are never marked
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Each frame is its length prefix followed by the message
+    #[default]
+    Plain,
+    /// Each frame is `FRAME_MAGIC` followed by a plain frame, letting a reader that finds
+    /// a corrupt frame skip to the next magic instead of giving up on the stream
+    Marked,
+}
+
+impl Framing {
+    /// First protocol version whose sessions mark their frames
+    const MARKED_SINCE: u32 = 17;
+
+    /// The framing of a session with a peer speaking protocol `peer_version`
+    pub fn negotiate(peer_version: u32) -> Self {
+        match peer_version.min(PROTOCOL_VERSION) >= Self::MARKED_SINCE {
+            true => Self::Marked,
+            false => Self::Plain,
+        }
+    }
+}
+
+/// Largest payload a `Data` or `CompressedData` message carries, uncompressed. Larger reads
+/// are split by `Message::new_payloads`, larger inbound payloads are a protocol violation
+pub const MAX_DATA_PAYLOAD: usize = 64 * 1024;
 
 /// ProxyConfig Operation
 #[derive(Debug, Clone, Serialize, Deserialize, Encode, Decode, PartialEq, Eq)]
@@ -12,71 +81,748 @@ pub enum ProxyConfigOpCode {
     Update,
 }
 
-/// Messages exchanged between client and server
-#[derive(Debug, Clone, Serialize, Deserialize, Encode, Decode)]
+/// Messages exchanged between client and server. Their `Debug` output shows how many bytes
+/// tokens, keys and payloads hold but never the bytes, so messages are safe to log
+#[derive(Clone, Serialize, Deserialize, Encode, Decode)]
 pub enum Message {
-    /// Client authentication request
+    /// Client authentication request, answering an `AuthChallenge`
     Auth {
+        /// protocol version spoken by the client
+        version: u32,
+        /// the challenge nonce this request answers
+        nonce: Vec<u8>,
+        /// HMAC-SHA256(token, nonce || client_id), empty when authenticating by certificate
         enc_token: Vec<u8>,
+        /// client ID, the session key is derived from it
         client_id: String,
         /// client name
         name: Option<String>,
+        /// supported compression codecs, in order of preference
+        compression: Vec<Compression>,
+        /// whether the client would carry each proxied connection on a data channel
+        data_channels: bool,
+        /// what the client runs on, to tell clients apart
+        meta: Option<ClientMeta>,
     },
     /// Server authentication response
     AuthResponse {
+        /// whether the client was accepted
         success: bool,
+        /// key for encrypted payloads, on success
         session_key: Option<Vec<u8>>,
         /// server name
         name: Option<String>,
+        /// why the client was rejected
         error: Option<String>,
+        /// negotiated compression codec for this session
+        compression: Compression,
+        /// client ID the server assigned for this session, replacing the one sent
+        client_id: Option<String>,
+        /// whether the client may open data channels for this session
+        data_channels: bool,
     },
     /// Client proxy configuration
     ProxyConfig {
-        // enum: Delete, Update
+        /// picked by the client, echoed in the `ProxyConfigResponse`
+        request_id: u64,
+        /// whether the service is added or removed
         op: ProxyConfigOpCode,
+        /// service name, used in logs
+        name: String,
+        /// host of the local service, as the client sees it
         local_ip: String,
+        /// port of the local service
         local_port: u16,
+        /// 0 lets the server pick the port
         remote_port: u16,
+        /// port to try first when `remote_port` is 0, e.g. the one assigned before a reconnect
+        preferred_port: Option<u16>,
+        /// host to bind on instead of the server's default `bind_host`
+        bind_host: Option<String>,
+        /// host name to route to this service on the server's `http_port`,
+        /// replacing the dedicated remote port
+        http_host: Option<String>,
+        /// TLS server name to route to this service on the server's `sni_port`,
+        /// replacing the dedicated remote port
+        sni: Option<String>,
+        /// group whose members share `remote_port`, each new connection going to one of them
+        group: Option<String>,
+        /// connections the server lets in at once, more are closed right after accepting
+        max_connections: Option<u32>,
+        /// makes this a secret service: no listener is bound, only visitors presenting
+        /// this hash reach it, see `crypto::secret_hash`
+        secret_hash: Option<Vec<u8>>,
+        /// makes this a relay service: no listener is bound, only the clients named here,
+        /// by ID or name, reach it with a `RelayConnect`
+        relay_peers: Option<Vec<String>>,
+        /// milliseconds until the server removes the proxy, never when None
+        ttl_ms: Option<u64>,
     },
     /// Server proxy configuration response
     ProxyConfigResponse {
+        /// `request_id` of the `ProxyConfig` answered
+        request_id: u64,
+        /// whether the service was set up
         success: bool,
+        /// ID of the proxy, carried by its `NewConnection`s
         proxy_id: Option<String>,
+        /// why the service was refused
         error: Option<String>,
+        /// port the proxy listener was actually bound to
+        assigned_port: Option<u16>,
     },
     /// Heartbeat message
-    Heartbeat { timestamp: u64 },
+    Heartbeat {
+        /// seconds since the Unix epoch when it was sent, identifies the heartbeat
+        timestamp: u64,
+    },
     /// Heartbeat response
-    HeartbeatResponse { timestamp: u64 },
+    HeartbeatResponse {
+        /// timestamp of the heartbeat answered
+        timestamp: u64,
+        /// connections of the client's proxies, None from servers that do not count them
+        stats: Option<ClientStats>,
+    },
     /// New connection request from server to client
     NewConnection {
+        /// proxy the peer connected to
         proxy_id: String,
+        /// ID every later message about this connection carries
         connection_id: String,
+        /// address of the external peer that connected to the proxy listener
+        source_addr: String,
+        /// address of the proxy listener the peer connected to
+        dest_addr: String,
     },
     /// Connection response from client
     ConnectionResponse {
+        /// connection answered
         connection_id: String,
+        /// whether the client reached its local service
         success: bool,
+        /// why the local service could not be reached
         error: Option<String>,
     },
     /// Data transfer
     Data {
+        /// connection the data belongs to
         connection_id: String,
+        /// position among the connection's data messages from this sender, counting from 0
+        seq: u64,
+        /// the bytes, as read from the socket
+        data: Vec<u8>,
+    },
+    /// Compressed data transfer
+    CompressedData {
+        /// connection the data belongs to
+        connection_id: String,
+        /// position among the connection's data messages from this sender, counting from 0
+        seq: u64,
+        /// codec the data was compressed with
+        codec: Compression,
+        /// the compressed bytes
         data: Vec<u8>,
     },
     /// Close connection
-    CloseConnection { connection_id: String },
+    CloseConnection {
+        /// connection to close
+        connection_id: String,
+        /// what the sender's end of the connection moved. None when the sender closes it from
+        /// outside its forwarding, e.g. for an admin's close or the proxy's expiry, and has no
+        /// counts to tell
+        stats: Option<TransferStats>,
+        /// why the sender closed the connection, e.g. "slow consumer", when it is worth telling
+        reason: Option<String>,
+        /// why the sender closed the connection, None when it does not tell
+        code: Option<CloseCode>,
+    },
     /// Error message
-    Error { message: String },
+    Error {
+        /// what went wrong
+        message: String,
+    },
+    /// Server authentication challenge, sent right after accepting a connection
+    AuthChallenge {
+        /// protocol version spoken by the server
+        version: u32,
+        /// fresh nonce the client proves knowledge of its token over
+        nonce: Vec<u8>,
+    },
+    /// The server is closing this session, sent before the connection is torn down
+    SessionClosed {
+        /// why the session ends
+        reason: String,
+    },
+    /// The sender's end stopped sending (EOF) but still reads, the receiver shuts down
+    /// the write half of its end. The connection is gone once both sides sent it
+    ShutdownWrite {
+        /// connection that stopped sending
+        connection_id: String,
+    },
+    /// The receiver wrote `bytes` of the connection's data to its socket,
+    /// the sender may send that many more
+    WindowUpdate {
+        /// connection the window belongs to
+        connection_id: String,
+        /// bytes acknowledged
+        bytes: u32,
+    },
+    /// Visitor request for a connection to another client's secret service
+    VisitorConnect {
+        /// picked by the visitor, echoed in the `VisitorConnectResponse`
+        request_id: u64,
+        /// name the secret service was registered with
+        service_name: String,
+        /// `crypto::secret_hash` of the service name and its secret
+        secret_hash: Vec<u8>,
+    },
+    /// Server response to a `VisitorConnect`, once the service's client answered
+    VisitorConnectResponse {
+        /// `request_id` of the `VisitorConnect` answered
+        request_id: u64,
+        /// whether the service's client reached its local service
+        success: bool,
+        /// ID the connection's messages carry, on success. The server relays them
+        /// to the service's client
+        connection_id: Option<String>,
+        /// why no connection was made
+        error: Option<String>,
+    },
+    /// The proxy's `ttl_ms` ran out: the server closed its listener and connections
+    ProxyExpired {
+        /// proxy removed
+        proxy_id: String,
+    },
+    /// Whether the local service of a proxy answers the client's health checks,
+    /// sent when that changes
+    ServiceStatus {
+        /// proxy of the service
+        proxy_id: String,
+        /// why the local service could not be reached, None once it answers
+        error: Option<String>,
+    },
+    /// First message of a data channel: a connection of its own to the server carrying one
+    /// proxied connection, opened by a client whose session negotiated `data_channels`.
+    /// Sent instead of `Auth`, the connection's bytes follow it unframed
+    DataChannelHello {
+        /// ID the session is known by on the server
+        client_id: String,
+        /// connection carried
+        connection_id: String,
+        /// `CryptoContext::data_channel_proof` over the challenge nonce and `connection_id`
+        proof: Vec<u8>,
+    },
+    /// The receiver's end of the connection queued more of its data than it writes, the
+    /// sender stops reading its socket for the connection until a `Resume`
+    Pause {
+        /// connection to pause
+        connection_id: String,
+    },
+    /// The receiver's end of a paused connection caught up, the sender reads again
+    Resume {
+        /// connection to resume
+        connection_id: String,
+    },
+    /// Client request for a connection to a relay service of another client, answered
+    /// with a `VisitorConnectResponse` like a `VisitorConnect`
+    RelayConnect {
+        /// picked by the client, echoed in the `VisitorConnectResponse`
+        request_id: u64,
+        /// ID or name of the client the service belongs to
+        target_client: String,
+        /// name the relay service was registered with
+        target_service: String,
+    },
+    /// Carries a connection of a lost session over to the client's next one: sent by the
+    /// client for each connection it kept, answered by the server with its own or with a
+    /// `CloseConnection`. Each end then sends again what the other did not receive
+    ResumeConnection {
+        /// connection to resume
+        connection_id: String,
+        /// bytes of the connection's data the sender received in sequence
+        received: u64,
+        /// bytes the sender acknowledged with `WindowUpdate`s, sent or lost
+        acknowledged: u64,
+    },
+}
+
+impl fmt::Debug for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Message::Auth {
+                version,
+                nonce,
+                enc_token,
+                client_id,
+                name,
+                compression,
+                data_channels,
+                meta,
+            } => f
+                .debug_struct("Auth")
+                .field("version", version)
+                .field("nonce", &RedactedBytes(nonce))
+                .field("enc_token", &RedactedBytes(enc_token))
+                .field("client_id", client_id)
+                .field("name", name)
+                .field("compression", compression)
+                .field("data_channels", data_channels)
+                .field("meta", meta)
+                .finish(),
+            Message::AuthResponse {
+                success,
+                session_key,
+                name,
+                error,
+                compression,
+                client_id,
+                data_channels,
+            } => f
+                .debug_struct("AuthResponse")
+                .field("success", success)
+                .field("session_key", &session_key.as_deref().map(RedactedBytes))
+                .field("name", name)
+                .field("error", error)
+                .field("compression", compression)
+                .field("client_id", client_id)
+                .field("data_channels", data_channels)
+                .finish(),
+            Message::ProxyConfig {
+                request_id,
+                op,
+                name,
+                local_ip,
+                local_port,
+                remote_port,
+                preferred_port,
+                bind_host,
+                http_host,
+                sni,
+                group,
+                max_connections,
+                secret_hash,
+                relay_peers,
+                ttl_ms,
+            } => f
+                .debug_struct("ProxyConfig")
+                .field("request_id", request_id)
+                .field("op", op)
+                .field("name", name)
+                .field("local_ip", local_ip)
+                .field("local_port", local_port)
+                .field("remote_port", remote_port)
+                .field("preferred_port", preferred_port)
+                .field("bind_host", bind_host)
+                .field("http_host", http_host)
+                .field("sni", sni)
+                .field("group", group)
+                .field("max_connections", max_connections)
+                .field("secret_hash", &secret_hash.as_deref().map(RedactedBytes))
+                .field("relay_peers", relay_peers)
+                .field("ttl_ms", ttl_ms)
+                .finish(),
+            Message::ProxyConfigResponse {
+                request_id,
+                success,
+                proxy_id,
+                error,
+                assigned_port,
+            } => f
+                .debug_struct("ProxyConfigResponse")
+                .field("request_id", request_id)
+                .field("success", success)
+                .field("proxy_id", proxy_id)
+                .field("error", error)
+                .field("assigned_port", assigned_port)
+                .finish(),
+            Message::Heartbeat { timestamp } => f
+                .debug_struct("Heartbeat")
+                .field("timestamp", timestamp)
+                .finish(),
+            Message::HeartbeatResponse { timestamp, stats } => f
+                .debug_struct("HeartbeatResponse")
+                .field("timestamp", timestamp)
+                .field("stats", stats)
+                .finish(),
+            Message::NewConnection {
+                proxy_id,
+                connection_id,
+                source_addr,
+                dest_addr,
+            } => f
+                .debug_struct("NewConnection")
+                .field("proxy_id", proxy_id)
+                .field("connection_id", connection_id)
+                .field("source_addr", source_addr)
+                .field("dest_addr", dest_addr)
+                .finish(),
+            Message::ConnectionResponse {
+                connection_id,
+                success,
+                error,
+            } => f
+                .debug_struct("ConnectionResponse")
+                .field("connection_id", connection_id)
+                .field("success", success)
+                .field("error", error)
+                .finish(),
+            Message::Data {
+                connection_id,
+                seq,
+                data,
+            } => f
+                .debug_struct("Data")
+                .field("connection_id", connection_id)
+                .field("seq", seq)
+                .field("data", &RedactedBytes(data))
+                .finish(),
+            Message::CompressedData {
+                connection_id,
+                seq,
+                codec,
+                data,
+            } => f
+                .debug_struct("CompressedData")
+                .field("connection_id", connection_id)
+                .field("seq", seq)
+                .field("codec", codec)
+                .field("data", &RedactedBytes(data))
+                .finish(),
+            Message::CloseConnection {
+                connection_id,
+                stats,
+                reason,
+                code,
+            } => f
+                .debug_struct("CloseConnection")
+                .field("connection_id", connection_id)
+                .field("stats", stats)
+                .field("reason", reason)
+                .field("code", code)
+                .finish(),
+            Message::Error { message } => {
+                f.debug_struct("Error").field("message", message).finish()
+            }
+            Message::AuthChallenge { version, nonce } => f
+                .debug_struct("AuthChallenge")
+                .field("version", version)
+                .field("nonce", &RedactedBytes(nonce))
+                .finish(),
+            Message::SessionClosed { reason } => f
+                .debug_struct("SessionClosed")
+                .field("reason", reason)
+                .finish(),
+            Message::ShutdownWrite { connection_id } => f
+                .debug_struct("ShutdownWrite")
+                .field("connection_id", connection_id)
+                .finish(),
+            Message::WindowUpdate {
+                connection_id,
+                bytes,
+            } => f
+                .debug_struct("WindowUpdate")
+                .field("connection_id", connection_id)
+                .field("bytes", bytes)
+                .finish(),
+            Message::VisitorConnect {
+                request_id,
+                service_name,
+                secret_hash,
+            } => f
+                .debug_struct("VisitorConnect")
+                .field("request_id", request_id)
+                .field("service_name", service_name)
+                .field("secret_hash", &RedactedBytes(secret_hash))
+                .finish(),
+            Message::VisitorConnectResponse {
+                request_id,
+                success,
+                connection_id,
+                error,
+            } => f
+                .debug_struct("VisitorConnectResponse")
+                .field("request_id", request_id)
+                .field("success", success)
+                .field("connection_id", connection_id)
+                .field("error", error)
+                .finish(),
+            Message::ProxyExpired { proxy_id } => f
+                .debug_struct("ProxyExpired")
+                .field("proxy_id", proxy_id)
+                .finish(),
+            Message::ServiceStatus { proxy_id, error } => f
+                .debug_struct("ServiceStatus")
+                .field("proxy_id", proxy_id)
+                .field("error", error)
+                .finish(),
+            Message::DataChannelHello {
+                client_id,
+                connection_id,
+                proof,
+            } => f
+                .debug_struct("DataChannelHello")
+                .field("client_id", client_id)
+                .field("connection_id", connection_id)
+                .field("proof", &RedactedBytes(proof))
+                .finish(),
+            Message::Pause { connection_id } => f
+                .debug_struct("Pause")
+                .field("connection_id", connection_id)
+                .finish(),
+            Message::Resume { connection_id } => f
+                .debug_struct("Resume")
+                .field("connection_id", connection_id)
+                .finish(),
+            Message::RelayConnect {
+                request_id,
+                target_client,
+                target_service,
+            } => f
+                .debug_struct("RelayConnect")
+                .field("request_id", request_id)
+                .field("target_client", target_client)
+                .field("target_service", target_service)
+                .finish(),
+            Message::ResumeConnection {
+                connection_id,
+                received,
+                acknowledged,
+            } => f
+                .debug_struct("ResumeConnection")
+                .field("connection_id", connection_id)
+                .field("received", received)
+                .field("acknowledged", acknowledged)
+                .finish(),
+        }
+    }
+}
+
+/// What a client runs on, sent in `Auth` so operators can tell clients apart.
+/// Reported by the client itself, so only for display and `min_client_version`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+pub struct ClientMeta {
+    /// sowback version of the client, e.g. `0.2.1`
+    pub version: String,
+    /// operating system, e.g. `linux`
+    pub os: String,
+    /// CPU architecture, e.g. `aarch64`
+    pub arch: String,
+    /// host name of the client's machine, empty when unknown
+    pub hostname: String,
+}
+
+impl ClientMeta {
+    /// Longest host name kept, longer ones are truncated
+    pub const MAX_HOSTNAME_LEN: usize = 64;
+
+    /// Longest version, OS or architecture kept
+    const MAX_FIELD_LEN: usize = 32;
+
+    /// This process
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            hostname: net::hostname().unwrap_or_default(),
+        }
+        .sanitized()
+    }
+
+    /// The fields truncated and stripped of control characters, as the server keeps
+    /// what clients send
+    pub fn sanitized(self) -> Self {
+        let cap = |field: String, max: usize| -> String {
+            field
+                .trim()
+                .chars()
+                .filter(|c| !c.is_control())
+                .take(max)
+                .collect()
+        };
+        Self {
+            version: cap(self.version, Self::MAX_FIELD_LEN),
+            os: cap(self.os, Self::MAX_FIELD_LEN),
+            arch: cap(self.arch, Self::MAX_FIELD_LEN),
+            hostname: cap(self.hostname, Self::MAX_HOSTNAME_LEN),
+        }
+    }
+}
+
+impl fmt::Display for ClientMeta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}, {}/{}", self.version, self.os, self.arch)?;
+        if !self.hostname.is_empty() {
+            write!(f, " on {}", self.hostname)?;
+        }
+        Ok(())
+    }
+}
+
+/// Connections of the proxies a client has on a server, counted by the server since
+/// each proxy was registered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+pub struct ClientStats {
+    /// connections open right now
+    pub active_connections: u64,
+    /// connections accepted in all
+    pub total_connections: u64,
+    /// bytes forwarded both ways
+    pub bytes_forwarded: u64,
+}
+
+/// What one end of a tunneled connection moved through its socket: the external peer's
+/// on the server, the local service's on the client
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+pub struct TransferStats {
+    /// bytes read from the socket
+    pub bytes_in: u64,
+    /// bytes written to the socket
+    pub bytes_out: u64,
+    /// how long the connection was open, in milliseconds
+    pub duration_ms: u64,
+}
+
+impl TransferStats {
+    /// Stats of a connection open for `duration`
+    pub fn new(bytes_in: u64, bytes_out: u64, duration: Duration) -> Self {
+        Self {
+            bytes_in,
+            bytes_out,
+            duration_ms: duration.as_millis() as u64,
+        }
+    }
+}
+
+impl fmt::Display for TransferStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} in, {} out in {}",
+            format_bytes(self.bytes_in),
+            format_bytes(self.bytes_out),
+            HumanDuration(Duration::from_millis(self.duration_ms))
+        )
+    }
 }
 
+/// Why the sender of a `CloseConnection` closed the connection. It travels as a single
+/// byte, and a byte this version does not know decodes as `Other` rather than failing the
+/// frame, so newer peers can add codes without breaking older ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseCode {
+    /// The sender's end of the connection went away: the external peer on the server, the
+    /// local service on the client
+    PeerClosed,
+    /// A socket error, or data the sender could not make sense of
+    LocalError,
+    /// No traffic in either direction for the sender's `idle_timeout`
+    IdleTimeout,
+    /// A limit of the sender ended the connection, e.g. its buffer for a slow consumer
+    /// or the `ttl` of its proxy
+    PolicyLimit,
+    /// The client is shutting down
+    ClientShutdown,
+    /// The server is shutting down
+    ServerShutdown,
+    /// A code of a newer protocol version
+    Other(u8),
+}
+
+impl CloseCode {
+    /// Codes this version knows, in the order of their bytes
+    pub const KNOWN: [CloseCode; 6] = [
+        CloseCode::PeerClosed,
+        CloseCode::LocalError,
+        CloseCode::IdleTimeout,
+        CloseCode::PolicyLimit,
+        CloseCode::ClientShutdown,
+        CloseCode::ServerShutdown,
+    ];
+
+    /// The byte the code travels as
+    pub fn to_u8(self) -> u8 {
+        match self {
+            CloseCode::PeerClosed => 0,
+            CloseCode::LocalError => 1,
+            CloseCode::IdleTimeout => 2,
+            CloseCode::PolicyLimit => 3,
+            CloseCode::ClientShutdown => 4,
+            CloseCode::ServerShutdown => 5,
+            CloseCode::Other(code) => code,
+        }
+    }
+
+    /// The code travelling as `code`, `Other` for one this version does not know
+    pub fn from_u8(code: u8) -> Self {
+        Self::KNOWN
+            .get(usize::from(code))
+            .copied()
+            .unwrap_or(CloseCode::Other(code))
+    }
+}
+
+impl fmt::Display for CloseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseCode::PeerClosed => f.write_str("peer closed"),
+            CloseCode::LocalError => f.write_str("local error"),
+            CloseCode::IdleTimeout => f.write_str("idle timeout"),
+            CloseCode::PolicyLimit => f.write_str("policy limit"),
+            CloseCode::ClientShutdown => f.write_str("client shutdown"),
+            CloseCode::ServerShutdown => f.write_str("server shutdown"),
+            CloseCode::Other(code) => write!(f, "close code {}", code),
+        }
+    }
+}
+
+impl Encode for CloseCode {
+    fn encode<E: bincode::enc::Encoder>(
+        &self,
+        encoder: &mut E,
+    ) -> Result<(), bincode::error::EncodeError> {
+        self.to_u8().encode(encoder)
+    }
+}
+
+impl<Context> Decode<Context> for CloseCode {
+    fn decode<D: bincode::de::Decoder<Context = Context>>(
+        decoder: &mut D,
+    ) -> Result<Self, bincode::error::DecodeError> {
+        u8::decode(decoder).map(Self::from_u8)
+    }
+}
+
+bincode::impl_borrow_decode!(CloseCode);
+
 impl Message {
-    /// Creates a new authentication message
-    pub fn new_auth(token: &str, client_id: &str, name: Option<String>) -> Self {
+    /// Creates a new authentication message answering the challenge `nonce`, describing
+    /// this client in its `meta`
+    pub fn new_auth(
+        token: &str,
+        nonce: &[u8],
+        client_id: &str,
+        name: Option<String>,
+        compression: Vec<Compression>,
+        data_channels: bool,
+    ) -> Self {
+        // An empty token is sent as-is, for servers authenticating by client certificate
+        let enc_token = if token.is_empty() {
+            Vec::new()
+        } else {
+            auth_proof(token, nonce, client_id)
+        };
+
         Message::Auth {
-            enc_token: sha256_with_salt(token.as_bytes(), MAGIC_SALT),
+            version: PROTOCOL_VERSION,
+            nonce: nonce.to_vec(),
+            enc_token,
             client_id: client_id.to_string(),
             name,
+            compression,
+            data_channels,
+            meta: Some(ClientMeta::current()),
         }
     }
 
@@ -90,18 +836,169 @@ impl Message {
         }
     }
 
-    /// Creates a new data message for forwarding payload
-    pub fn new_data(connection_id: &str, data: Vec<u8>) -> Self {
+    /// Creates a new data message for forwarding payload, the `seq`-th of its connection
+    pub fn new_data(connection_id: &str, seq: u64, data: Vec<u8>) -> Self {
         Message::Data {
             connection_id: connection_id.to_string(),
+            seq,
             data,
         }
     }
 
-    /// Creates a new close connection message
-    pub fn new_close_connection(connection_id: &str) -> Self {
+    /// Creates a data message, compressing the payload with `codec` when it is
+    /// at least `threshold` bytes and compression actually makes it smaller
+    pub fn new_payload(
+        connection_id: &str,
+        seq: u64,
+        data: Vec<u8>,
+        codec: Compression,
+        threshold: usize,
+    ) -> Self {
+        match codec.compress(&data, threshold) {
+            Some(compressed) => Message::CompressedData {
+                connection_id: connection_id.to_string(),
+                seq,
+                codec,
+                data: compressed,
+            },
+            None => Self::new_data(connection_id, seq, data),
+        }
+    }
+
+    /// Creates the data messages carrying `data`, split into payloads of at most
+    /// `MAX_DATA_PAYLOAD` bytes and each compressed as by `new_payload`. Each message takes
+    /// `next_seq` in turn
+    pub fn new_payloads(
+        connection_id: &str,
+        next_seq: &mut u64,
+        data: &[u8],
+        codec: Compression,
+        threshold: usize,
+    ) -> Vec<Self> {
+        data.chunks(MAX_DATA_PAYLOAD)
+            .map(|chunk| {
+                let seq = *next_seq;
+                *next_seq += 1;
+                Self::new_payload(connection_id, seq, chunk.to_vec(), codec, threshold)
+            })
+            .collect()
+    }
+
+    /// Fails for a `Data` or `CompressedData` message larger than `MAX_DATA_PAYLOAD`,
+    /// which a conforming peer never sends
+    pub fn check_payload_size(&self) -> Result<(), anyhow::Error> {
+        let (Message::Data { data, .. } | Message::CompressedData { data, .. }) = self else {
+            return Ok(());
+        };
+        if data.len() > MAX_DATA_PAYLOAD {
+            return Err(anyhow::anyhow!(
+                "Data payload of {} bytes exceeds the {} byte limit",
+                data.len(),
+                MAX_DATA_PAYLOAD
+            ));
+        }
+        Ok(())
+    }
+
+    /// Name of the message's variant, for logs that should not show its fields
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::Auth { .. } => "Auth",
+            Message::AuthResponse { .. } => "AuthResponse",
+            Message::ProxyConfig { .. } => "ProxyConfig",
+            Message::ProxyConfigResponse { .. } => "ProxyConfigResponse",
+            Message::Heartbeat { .. } => "Heartbeat",
+            Message::HeartbeatResponse { .. } => "HeartbeatResponse",
+            Message::NewConnection { .. } => "NewConnection",
+            Message::ConnectionResponse { .. } => "ConnectionResponse",
+            Message::Data { .. } => "Data",
+            Message::CompressedData { .. } => "CompressedData",
+            Message::CloseConnection { .. } => "CloseConnection",
+            Message::Error { .. } => "Error",
+            Message::AuthChallenge { .. } => "AuthChallenge",
+            Message::SessionClosed { .. } => "SessionClosed",
+            Message::ShutdownWrite { .. } => "ShutdownWrite",
+            Message::WindowUpdate { .. } => "WindowUpdate",
+            Message::VisitorConnect { .. } => "VisitorConnect",
+            Message::VisitorConnectResponse { .. } => "VisitorConnectResponse",
+            Message::ProxyExpired { .. } => "ProxyExpired",
+            Message::ServiceStatus { .. } => "ServiceStatus",
+            Message::DataChannelHello { .. } => "DataChannelHello",
+            Message::Pause { .. } => "Pause",
+            Message::Resume { .. } => "Resume",
+            Message::RelayConnect { .. } => "RelayConnect",
+            Message::ResumeConnection { .. } => "ResumeConnection",
+        }
+    }
+
+    /// The tunneled connection the message is about, None for session messages
+    pub fn connection_id(&self) -> Option<&str> {
+        match self {
+            Message::Data { connection_id, .. }
+            | Message::CompressedData { connection_id, .. }
+            | Message::ConnectionResponse { connection_id, .. }
+            | Message::WindowUpdate { connection_id, .. }
+            | Message::Pause { connection_id }
+            | Message::Resume { connection_id }
+            | Message::ResumeConnection { connection_id, .. }
+            | Message::ShutdownWrite { connection_id }
+            | Message::CloseConnection { connection_id, .. } => Some(connection_id),
+            _ => None,
+        }
+    }
+
+    /// Creates a new half-close message
+    pub fn new_shutdown_write(connection_id: &str) -> Self {
+        Message::ShutdownWrite {
+            connection_id: connection_id.to_string(),
+        }
+    }
+
+    /// Creates a new flow control acknowledgement
+    pub fn new_window_update(connection_id: &str, bytes: u32) -> Self {
+        Message::WindowUpdate {
+            connection_id: connection_id.to_string(),
+            bytes,
+        }
+    }
+
+    /// Creates a message pausing the sender's reads of a connection
+    pub fn new_pause(connection_id: &str) -> Self {
+        Message::Pause {
+            connection_id: connection_id.to_string(),
+        }
+    }
+
+    /// Creates a message resuming the sender's reads of a paused connection
+    pub fn new_resume(connection_id: &str) -> Self {
+        Message::Resume {
+            connection_id: connection_id.to_string(),
+        }
+    }
+
+    /// Creates a new close connection message, closed for `code`
+    pub fn new_close_connection(connection_id: &str, code: CloseCode) -> Self {
         Message::CloseConnection {
             connection_id: connection_id.to_string(),
+            stats: None,
+            reason: None,
+            code: Some(code),
+        }
+    }
+
+    /// Creates a close connection message carrying what the sender's end moved, the `code`
+    /// it closed the connection for and why in words if given
+    pub fn new_close_connection_with_stats(
+        connection_id: &str,
+        stats: TransferStats,
+        code: CloseCode,
+        reason: Option<String>,
+    ) -> Self {
+        Message::CloseConnection {
+            connection_id: connection_id.to_string(),
+            stats: Some(stats),
+            reason,
+            code: Some(code),
         }
     }
 }
@@ -109,7 +1006,9 @@ impl Message {
 /// Frame format for message serialization
 #[derive(Debug)]
 pub struct Frame {
+    /// Length of the encoded message, set when a frame is deserialized
     pub length: u32,
+    /// The message carried
     pub message: Message,
 }
 
@@ -122,42 +1021,438 @@ impl Frame {
         }
     }
 
-    /// Serializes the frame into bytes for network transmission
+    /// Serializes the frame into bytes for network transmission, see `encode_into`
     pub fn serialize(&self) -> Result<Vec<u8>, anyhow::Error> {
-        let config = bincode::config::standard();
-        let message_data = bincode::encode_to_vec(&self.message, config)
-            .map_err(|e| anyhow::anyhow!("Serialization error: {:?}", e))?;
-        let length = message_data.len() as u32;
+        let mut buffer = BytesMut::new();
+        self.encode_into(&mut buffer)?;
+        Ok(buffer.into())
+    }
 
-        let mut result = Vec::new();
-        result.extend_from_slice(&length.to_be_bytes());
-        result.extend_from_slice(&message_data);
+    /// Serializes the frame as the session's `framing` has it
+    pub fn serialize_framed(&self, framing: Framing) -> Result<Vec<u8>, anyhow::Error> {
+        let mut buffer = BytesMut::new();
+        self.encode_framed(&mut buffer, framing)?;
+        Ok(buffer.into())
+    }
 
-        Ok(result)
+    /// Appends the frame to `buffer` like `encode_into`, after `FRAME_MAGIC` if `framing`
+    /// marks frames
+    pub fn encode_framed(
+        &self,
+        buffer: &mut BytesMut,
+        framing: Framing,
+    ) -> Result<(), anyhow::Error> {
+        let start = buffer.len();
+        if framing == Framing::Marked {
+            buffer.put_slice(&FRAME_MAGIC);
+        }
+        self.encode_into(buffer)
+            .inspect_err(|_| buffer.truncate(start))
     }
 
+    /// Appends the length-prefixed frame to `buffer`, encoding the message in place.
+    /// Write loops reuse one buffer so frames are not allocated one by one
+    pub fn encode_into(&self, buffer: &mut BytesMut) -> Result<(), anyhow::Error> {
+        let start = buffer.len();
+        // the length is only known once the message is encoded
+        buffer.put_u32(0);
+        let config = bincode::config::standard();
+        if let Err(e) = bincode::encode_into_std_write(&self.message, &mut buffer.writer(), config)
+        {
+            buffer.truncate(start);
+            return Err(anyhow::anyhow!("Serialization error: {:?}", e));
+        }
+        let length = (buffer.len() - start - 4) as u32;
+        buffer[start..start + 4].copy_from_slice(&length.to_be_bytes());
+        Ok(())
+    }
+
+    /// Decodes one frame from the start of `data`, marked or not, returning it and the
+    /// bytes it took up
     pub fn deserialize(data: &[u8]) -> Result<(Self, usize), anyhow::Error> {
-        if data.len() < 4 {
+        let header = match data.starts_with(&FRAME_MAGIC) {
+            true => FRAME_MAGIC.len() + 4,
+            false => 4,
+        };
+        if data.len() < header {
             return Err(anyhow::anyhow!("Insufficient data for length field"));
         }
 
-        let length = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        let length = Self::length_prefix(&data[header - 4..header])?;
 
-        if data.len() < 4 + length {
+        if data.len() < header + length {
             return Err(anyhow::anyhow!("Insufficient data for message"));
         }
 
-        let message_data = &data[4..4 + length];
-        let config = bincode::config::standard();
-        let (message, _): (Message, usize) = bincode::decode_from_slice(message_data, config)
-            .map_err(|e| anyhow::anyhow!("Deserialization error: {:?}", e))?;
-
+        let message = Self::decode_message(&data[header..header + length])?;
         Ok((
             Frame {
                 length: length as u32,
                 message,
             },
-            4 + length,
+            header + length,
         ))
     }
+
+    /// The message length a 4 byte prefix announces, refused past `MAX_FRAME_LEN`
+    pub(crate) fn length_prefix(prefix: &[u8]) -> Result<usize, anyhow::Error> {
+        let bytes: [u8; 4] = prefix
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Length prefix is {} bytes", prefix.len()))?;
+        let length = u32::from_be_bytes(bytes) as usize;
+        if length > MAX_FRAME_LEN {
+            return Err(anyhow::anyhow!(
+                "Length prefix {} exceeds the {} byte limit",
+                length,
+                MAX_FRAME_LEN
+            ));
+        }
+        Ok(length)
+    }
+
+    /// Decodes a message taking up all of `data`. The limit keeps the lengths encoded
+    /// inside a corrupt message from allocating more than the frame could hold
+    pub(crate) fn decode_message(data: &[u8]) -> Result<Message, anyhow::Error> {
+        let config = bincode::config::standard().with_limit::<MAX_FRAME_LEN>();
+        let (message, used): (Message, usize) = bincode::decode_from_slice(data, config)
+            .map_err(|e| anyhow::anyhow!("Deserialization error: {:?}", e))?;
+        if used != data.len() {
+            return Err(anyhow::anyhow!(
+                "Deserialization error: {} bytes left after the message",
+                data.len() - used
+            ));
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The layout `serialize` used to build, two allocations per frame
+    fn legacy_serialize(message: &Message) -> Vec<u8> {
+        let data = bincode::encode_to_vec(message, bincode::config::standard()).unwrap();
+        let mut result = (data.len() as u32).to_be_bytes().to_vec();
+        result.extend_from_slice(&data);
+        result
+    }
+
+    #[test]
+    fn test_encoding_matches_the_wire_format() {
+        let messages = [
+            Message::new_heartbeat(),
+            Message::new_data("conn", 0, vec![7; 70_000]),
+            Message::new_close_connection("conn", CloseCode::PeerClosed),
+            Message::Error {
+                message: String::new(),
+            },
+        ];
+
+        // one reused buffer, as the write loops do
+        let mut buffer = BytesMut::new();
+        let mut expected = Vec::new();
+        for message in &messages {
+            let frame = Frame::new(message.clone());
+            assert_eq!(frame.serialize().unwrap(), legacy_serialize(message));
+            frame.encode_into(&mut buffer).unwrap();
+            expected.extend(legacy_serialize(message));
+        }
+        assert_eq!(&buffer[..], &expected[..]);
+
+        let mut offset = 0;
+        for message in &messages {
+            let (frame, used) = Frame::deserialize(&buffer[offset..]).unwrap();
+            assert_eq!(legacy_serialize(&frame.message), legacy_serialize(message));
+            offset += used;
+        }
+        assert_eq!(offset, buffer.len());
+    }
+
+    #[test]
+    fn test_framing_follows_the_peer_version() {
+        assert_eq!(Framing::negotiate(PROTOCOL_VERSION), Framing::Marked);
+        assert_eq!(Framing::negotiate(17), Framing::Marked);
+        // peers from before the magic prefix keep plain frames
+        assert_eq!(Framing::negotiate(16), Framing::Plain);
+        assert_eq!(Framing::negotiate(0), Framing::Plain);
+
+        let frame = Frame::new(Message::new_heartbeat());
+        let plain = frame.serialize_framed(Framing::negotiate(16)).unwrap();
+        assert_eq!(plain, frame.serialize().unwrap());
+        let marked = frame.serialize_framed(Framing::negotiate(17)).unwrap();
+        assert_eq!(&marked[..4], &FRAME_MAGIC);
+        assert_eq!(&marked[4..], &plain[..]);
+    }
+
+    #[test]
+    fn test_new_connection_keeps_the_peer_address() {
+        for source_addr in ["203.0.113.7:51234", "[2001:db8::7]:51234"] {
+            let message = Message::NewConnection {
+                proxy_id: "proxy".to_string(),
+                connection_id: "conn".to_string(),
+                source_addr: source_addr.to_string(),
+                dest_addr: "0.0.0.0:8080".to_string(),
+            };
+            let data = Frame::new(message).serialize().unwrap();
+            match Frame::deserialize(&data).unwrap().0.message {
+                Message::NewConnection {
+                    source_addr: decoded,
+                    ..
+                } => assert_eq!(decoded, source_addr),
+                other => panic!("expected NewConnection, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_close_connection_carries_optional_stats() {
+        let stats = TransferStats::new(2048, 5, Duration::from_millis(1500));
+        assert_eq!(stats.to_string(), "2.0 KiB in, 5 B out in 1500ms");
+        for message in [
+            Message::new_close_connection("conn", CloseCode::PeerClosed),
+            Message::new_close_connection_with_stats("conn", stats, CloseCode::LocalError, None),
+            Message::new_close_connection_with_stats(
+                "conn",
+                stats,
+                CloseCode::PolicyLimit,
+                Some("slow consumer".to_string()),
+            ),
+            Message::CloseConnection {
+                connection_id: "conn".to_string(),
+                stats: None,
+                reason: None,
+                code: None,
+            },
+        ] {
+            let data = Frame::new(message.clone()).serialize().unwrap();
+            match (Frame::deserialize(&data).unwrap().0.message, message) {
+                (
+                    Message::CloseConnection {
+                        stats: decoded,
+                        reason: decoded_reason,
+                        code: decoded_code,
+                        ..
+                    },
+                    Message::CloseConnection {
+                        stats: sent,
+                        reason: sent_reason,
+                        code: sent_code,
+                        ..
+                    },
+                ) => assert_eq!(
+                    (decoded, decoded_reason, decoded_code),
+                    (sent, sent_reason, sent_code)
+                ),
+                (other, _) => panic!("expected CloseConnection, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_close_codes_decode() {
+        for code in CloseCode::KNOWN {
+            assert_eq!(CloseCode::from_u8(code.to_u8()), code);
+        }
+
+        // a code of a newer peer is kept as it is, the rest of the frame still decodes
+        let message = Message::CloseConnection {
+            connection_id: "conn".to_string(),
+            stats: None,
+            reason: Some("draining".to_string()),
+            code: Some(CloseCode::Other(42)),
+        };
+        let data = Frame::new(message).serialize().unwrap();
+        match Frame::deserialize(&data).unwrap().0.message {
+            Message::CloseConnection { reason, code, .. } => {
+                assert_eq!(reason.as_deref(), Some("draining"));
+                assert_eq!(code, Some(CloseCode::Other(42)));
+            }
+            other => panic!("expected CloseConnection, got {:?}", other),
+        }
+        assert_eq!(CloseCode::Other(42).to_string(), "close code 42");
+        assert_eq!(CloseCode::from_u8(6), CloseCode::Other(6));
+    }
+
+    #[test]
+    fn test_heartbeat_responses_carry_stats_or_not() {
+        let stats = ClientStats {
+            active_connections: 2,
+            total_connections: 40,
+            bytes_forwarded: 1 << 33,
+        };
+        for sent in [None, Some(stats)] {
+            let message = Message::HeartbeatResponse {
+                timestamp: 7,
+                stats: sent,
+            };
+            let data = Frame::new(message).serialize().unwrap();
+            match Frame::deserialize(&data).unwrap().0.message {
+                Message::HeartbeatResponse { timestamp, stats } => {
+                    assert_eq!((timestamp, stats), (7, sent))
+                }
+                other => panic!("expected HeartbeatResponse, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_auth_describes_the_client() {
+        let message = Message::new_auth("token", &[0; 32], "client", None, vec![], false);
+        let data = Frame::new(message).serialize().unwrap();
+        match Frame::deserialize(&data).unwrap().0.message {
+            Message::Auth {
+                meta: Some(meta), ..
+            } => {
+                assert_eq!(meta.version, env!("CARGO_PKG_VERSION"));
+                assert_eq!(meta.os, std::env::consts::OS);
+                assert_eq!(meta.arch, std::env::consts::ARCH);
+            }
+            other => panic!("expected Auth with meta, got {:?}", other),
+        }
+
+        let meta = ClientMeta {
+            version: "0.2.1".to_string(),
+            os: "linux".to_string(),
+            arch: "aarch64".to_string(),
+            hostname: format!(" kitchen-pi\u{1b}[2J{} ", "x".repeat(100)),
+        }
+        .sanitized();
+        assert_eq!(meta.hostname.chars().count(), ClientMeta::MAX_HOSTNAME_LEN);
+        assert!(meta.hostname.starts_with("kitchen-pi[2Jxxx"));
+        let unnamed = ClientMeta {
+            hostname: String::new(),
+            ..meta.clone()
+        };
+        assert_eq!(unnamed.to_string(), "v0.2.1, linux/aarch64");
+        let long = ClientMeta {
+            version: "9".repeat(1000),
+            ..unnamed
+        };
+        assert_eq!(long.sanitized().version.len(), 32);
+    }
+
+    #[test]
+    fn test_large_payloads_are_chunked() {
+        let data: Vec<u8> = (0..10 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        for codec in [Compression::None, Compression::Zstd] {
+            let mut received = Vec::new();
+            let mut next_seq = 3;
+            for (i, message) in Message::new_payloads("conn", &mut next_seq, &data, codec, 512)
+                .into_iter()
+                .enumerate()
+            {
+                message.check_payload_size().unwrap();
+                match message {
+                    Message::Data { seq, data, .. } => {
+                        assert_eq!(seq, 3 + i as u64);
+                        received.extend(data)
+                    }
+                    Message::CompressedData {
+                        seq, codec, data, ..
+                    } => {
+                        assert_eq!(seq, 3 + i as u64);
+                        received.extend(codec.decompress(&data).unwrap())
+                    }
+                    other => panic!("unexpected message {:?}", other),
+                }
+            }
+            assert!(received == data, "{:?} payload corrupted", codec);
+            assert_eq!(next_seq, 3 + data.len().div_ceil(MAX_DATA_PAYLOAD) as u64);
+        }
+
+        let oversized = Message::new_data("conn", 0, vec![0; MAX_DATA_PAYLOAD + 1]);
+        assert!(oversized.check_payload_size().is_err());
+    }
+
+    #[test]
+    fn test_debug_output_hides_secrets_and_payloads() {
+        // 171 never shows up as a number in these messages but would in every byte list
+        let secret = || vec![0xab; 32];
+        let messages = [
+            Message::Auth {
+                version: PROTOCOL_VERSION,
+                nonce: secret(),
+                enc_token: secret(),
+                client_id: "client".to_string(),
+                name: Some("laptop".to_string()),
+                compression: vec![Compression::Zstd],
+                data_channels: true,
+                meta: Some(ClientMeta::current()),
+            },
+            Message::AuthResponse {
+                success: true,
+                session_key: Some(secret()),
+                name: None,
+                error: None,
+                compression: Compression::None,
+                client_id: None,
+                data_channels: false,
+            },
+            Message::ProxyConfig {
+                request_id: 1,
+                op: ProxyConfigOpCode::Update,
+                name: "web".to_string(),
+                local_ip: "127.0.0.1".to_string(),
+                local_port: 8080,
+                remote_port: 0,
+                preferred_port: None,
+                bind_host: None,
+                http_host: None,
+                sni: None,
+                group: None,
+                max_connections: None,
+                secret_hash: Some(secret()),
+                relay_peers: None,
+                ttl_ms: None,
+            },
+            Message::new_data("conn", 0, secret()),
+            Message::CompressedData {
+                connection_id: "conn".to_string(),
+                seq: 1,
+                codec: Compression::Zstd,
+                data: secret(),
+            },
+            Message::AuthChallenge {
+                version: PROTOCOL_VERSION,
+                nonce: secret(),
+            },
+            Message::VisitorConnect {
+                request_id: 2,
+                service_name: "db".to_string(),
+                secret_hash: secret(),
+            },
+            Message::DataChannelHello {
+                client_id: "client".to_string(),
+                connection_id: "conn".to_string(),
+                proof: secret(),
+            },
+        ];
+        for message in &messages {
+            let formatted = format!("{:?}", message);
+            assert!(!formatted.contains("171"), "{}", formatted);
+            assert!(formatted.contains("<32 bytes>"), "{}", formatted);
+            // the pretty form goes through the same fields
+            assert!(!format!("{:#?}", message).contains("171"));
+        }
+
+        // everything else is printed as it is
+        assert_eq!(
+            format!("{:?}", messages[1]),
+            "AuthResponse { success: true, session_key: Some(<32 bytes>), name: None, \
+             error: None, compression: None, client_id: None, data_channels: false }"
+        );
+        assert_eq!(
+            format!(
+                "{:?}",
+                Message::new_close_connection("conn", CloseCode::IdleTimeout)
+            ),
+            "CloseConnection { connection_id: \"conn\", stats: None, reason: None, \
+             code: Some(IdleTimeout) }"
+        );
+        assert_eq!(
+            format!("{:?}", Message::new_heartbeat()).split(':').next(),
+            Some("Heartbeat { timestamp")
+        );
+    }
 }
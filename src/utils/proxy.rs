@@ -1,57 +1,419 @@
 use anyhow::Result;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tracing::{debug, error};
-
-/// Bidirectional data forwarding between two TCP streams
-pub async fn forward_data(mut stream1: TcpStream, mut stream2: TcpStream) -> Result<()> {
-    let (mut r1, mut w1) = stream1.split();
-    let (mut r2, mut w2) = stream2.split();
-
-    let forward1 = async {
-        let mut buffer = [0u8; 4096];
-        loop {
-            match r1.read(&mut buffer).await {
-                Ok(0) => break,
-                Ok(n) => {
-                    if let Err(e) = w2.write_all(&buffer[..n]).await {
-                        error!("Error writing to stream2: {}", e);
-                        break;
-                    }
-                    debug!("Forwarded {} bytes from stream1 to stream2", n);
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+use super::{Activity, TransferStats};
+use crate::logging::RedactedBytes;
+
+/// Work for the task writing to one end of a tunneled connection
+#[derive(PartialEq, Eq)]
+pub enum WriteCommand {
+    /// Bytes to write
+    Data(Vec<u8>),
+    /// The other end finished sending, shut down the write half but keep reading
+    ShutdownWrite,
+}
+
+impl std::fmt::Debug for WriteCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriteCommand::Data(data) => f.debug_tuple("Data").field(&RedactedBytes(data)).finish(),
+            WriteCommand::ShutdownWrite => f.write_str("ShutdownWrite"),
+        }
+    }
+}
+
+/// Backlog at which the receiver of a connection asks its sender to `Pause`
+pub const PAUSE_HIGH_WATERMARK: usize = 256 * 1024;
+
+/// Backlog a paused connection drains to before its receiver lets the sender `Resume`
+pub const PAUSE_LOW_WATERMARK: usize = 64 * 1024;
+
+/// Bytes queued for the task writing to one end of a tunneled connection and not written
+/// yet. An end that stops reading while the other keeps sending would have them pile up
+/// without bound, past a limit the connection overflows instead
+#[derive(Debug, Default)]
+pub struct WriteBacklog {
+    bytes: AtomicUsize,
+    overflow: CancellationToken,
+    /// Whether the sender was asked to `Pause` and not to `Resume` yet
+    paused: Mutex<bool>,
+}
+
+impl WriteBacklog {
+    /// Counts `bytes` queued, false once the backlog went beyond `limit` (0 is unlimited):
+    /// the connection overflowed and the bytes should be dropped
+    pub fn queue(&self, bytes: usize, limit: usize) -> bool {
+        let queued = self.bytes.fetch_add(bytes, Ordering::AcqRel) + bytes;
+        if limit != 0 && queued > limit {
+            self.overflow.cancel();
+        }
+        !self.overflow.is_cancelled()
+    }
+
+    /// Counts `bytes` written. Writes of bytes that were never queued are not held against it
+    pub fn written(&self, bytes: usize) {
+        let _ = self
+            .bytes
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| {
+                Some(queued.saturating_sub(bytes))
+            });
+    }
+
+    /// Bytes queued and not written yet
+    pub fn queued(&self) -> usize {
+        self.bytes.load(Ordering::Acquire)
+    }
+
+    /// Completes once the backlog went beyond its limit
+    pub async fn overflowed(&self) {
+        self.overflow.cancelled().await
+    }
+
+    /// Whether the backlog reached `PAUSE_HIGH_WATERMARK` and the sender was not paused yet,
+    /// checked after queueing before looking up where to send a `Pause`
+    pub fn wants_pause(&self) -> bool {
+        !*self.paused.lock().unwrap() && self.queued() >= PAUSE_HIGH_WATERMARK
+    }
+
+    /// Whether the sender was asked to `Pause` and not to `Resume` yet, for a resumed
+    /// connection to ask again
+    pub fn is_paused(&self) -> bool {
+        *self.paused.lock().unwrap()
+    }
+
+    /// Whether the sender was paused and the backlog drained to `PAUSE_LOW_WATERMARK`,
+    /// checked after writing before looking up where to send a `Resume`
+    pub fn wants_resume(&self) -> bool {
+        *self.paused.lock().unwrap() && self.queued() <= PAUSE_LOW_WATERMARK
+    }
+
+    /// Calls `send_pause` if the backlog still wants a pause, returning whether it did.
+    /// Pauses and resumes are decided and sent under one lock, so they reach the sender in
+    /// order, and a writer draining the backlog meanwhile is not missed
+    pub fn pause_with(&self, send_pause: impl FnOnce()) -> bool {
+        let mut paused = self.paused.lock().unwrap();
+        if *paused || self.queued() < PAUSE_HIGH_WATERMARK {
+            return false;
+        }
+        *paused = true;
+        send_pause();
+        true
+    }
+
+    /// Calls `send_resume` if the backlog still wants a resume, returning whether it did
+    pub fn resume_with(&self, send_resume: impl FnOnce()) -> bool {
+        let mut paused = self.paused.lock().unwrap();
+        if !*paused || self.queued() > PAUSE_LOW_WATERMARK {
+            return false;
+        }
+        *paused = false;
+        send_resume();
+        true
+    }
+}
+
+/// How one direction of a tunneled connection ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HalfEnd {
+    /// Finished cleanly, the other direction goes on
+    Shutdown,
+    /// Failed, the whole connection is closed and the peer told
+    Failed,
+    /// The peer closed the whole connection
+    Closed,
+}
+
+/// Bytes one tunneled connection moved through its socket, counted as they pass
+#[derive(Debug)]
+pub struct TransferCounter {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    started: Instant,
+}
+
+impl Default for TransferCounter {
+    fn default() -> Self {
+        Self {
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl TransferCounter {
+    /// Counts bytes read from the socket
+    pub fn record_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts bytes written to the socket
+    pub fn record_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// What the connection moved since it was counted
+    pub fn stats(&self) -> TransferStats {
+        TransferStats::new(
+            self.bytes_in.load(Ordering::Relaxed),
+            self.bytes_out.load(Ordering::Relaxed),
+            self.started.elapsed(),
+        )
+    }
+}
+
+/// Bytes moved by `forward_data` in each direction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ForwardStats {
+    /// Bytes read from the first stream and written to the second
+    pub a_to_b: u64,
+    /// Bytes read from the second stream and written to the first
+    pub b_to_a: u64,
+    /// Time from the start of forwarding until both directions finished
+    pub duration: Duration,
+}
+
+/// Bidirectional data forwarding between two streams, until both directions finished.
+/// Each direction shuts down its write half once its reader ends, so half-closed
+/// connections keep receiving the other way
+pub async fn forward_data<A, B>(mut a: A, mut b: B) -> Result<ForwardStats>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let start = Instant::now();
+    let (a_to_b, b_to_a) = tokio::io::copy_bidirectional(&mut a, &mut b).await?;
+    let stats = ForwardStats {
+        a_to_b,
+        b_to_a,
+        duration: start.elapsed(),
+    };
+    debug!(
+        "Forwarded {} bytes one way and {} bytes back in {:?}",
+        stats.a_to_b, stats.b_to_a, stats.duration
+    );
+    Ok(stats)
+}
+
+/// How a connection forwarded by `splice` ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpliceEnd {
+    /// Both directions finished, `a` first if `a_first`
+    Finished { a_first: bool },
+    /// A direction failed, the other was cut short
+    Failed,
+    /// Nothing was sent either way for the idle timeout
+    Idle,
+}
+
+/// Forwards a connection between two streams carrying nothing else, until both directions
+/// finished, one failed, or `idle_timeout` passed without traffic. `a_to_b` and `b_to_a`
+/// are told the size of every chunk forwarded that way
+pub async fn splice<A, B>(
+    a: A,
+    b: B,
+    idle_timeout: Option<Duration>,
+    mut a_to_b: impl FnMut(usize),
+    mut b_to_a: impl FnMut(usize),
+) -> SpliceEnd
+where
+    A: AsyncRead + AsyncWrite,
+    B: AsyncRead + AsyncWrite,
+{
+    let activity = Activity::new();
+    let (a_read, a_write) = tokio::io::split(a);
+    let (b_read, b_write) = tokio::io::split(b);
+    let forward = copy_half(a_read, b_write, |n| {
+        activity.touch();
+        a_to_b(n)
+    });
+    let backward = copy_half(b_read, a_write, |n| {
+        activity.touch();
+        b_to_a(n)
+    });
+    tokio::pin!(forward, backward);
+
+    let (mut forwarding, mut backwarding) = (true, true);
+    let mut a_first = None;
+    while forwarding || backwarding {
+        tokio::select! {
+            end = &mut forward, if forwarding => {
+                forwarding = false;
+                if end == HalfEnd::Failed {
+                    return SpliceEnd::Failed;
                 }
-                Err(e) => {
-                    error!("Error reading from stream1: {}", e);
-                    break;
+                a_first.get_or_insert(true);
+            }
+            end = &mut backward, if backwarding => {
+                backwarding = false;
+                if end == HalfEnd::Failed {
+                    return SpliceEnd::Failed;
                 }
+                a_first.get_or_insert(false);
             }
+            _ = activity.idle(idle_timeout) => return SpliceEnd::Idle,
         }
-    };
+    }
+    SpliceEnd::Finished {
+        a_first: a_first.unwrap_or(true),
+    }
+}
 
-    let forward2 = async {
-        let mut buffer = [0u8; 4096];
-        loop {
-            match r2.read(&mut buffer).await {
-                Ok(0) => break,
-                Ok(n) => {
-                    if let Err(e) = w1.write_all(&buffer[..n]).await {
-                        error!("Error writing to stream1: {}", e);
-                        break;
-                    }
-                    debug!("Forwarded {} bytes from stream2 to stream1", n);
-                }
-                Err(e) => {
-                    error!("Error reading from stream2: {}", e);
-                    break;
+/// Copies `reader` into `writer` until the reader ends, then shuts the writer down
+/// so the other end sees the half-close
+async fn copy_half<R, W>(mut reader: R, mut writer: W, mut copied: impl FnMut(usize)) -> HalfEnd
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0u8; 16 * 1024];
+    loop {
+        match reader.read(&mut buffer).await {
+            Ok(0) => {
+                return match writer.shutdown().await {
+                    Ok(()) => HalfEnd::Shutdown,
+                    Err(_) => HalfEnd::Failed,
+                };
+            }
+            Ok(n) => {
+                if writer.write_all(&buffer[..n]).await.is_err() {
+                    return HalfEnd::Failed;
                 }
+                copied(n);
             }
+            Err(_) => return HalfEnd::Failed,
         }
-    };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::time::timeout;
+
+    #[test]
+    fn test_backlog_pauses_between_watermarks() {
+        let backlog = WriteBacklog::default();
+        assert!(backlog.queue(PAUSE_HIGH_WATERMARK - 1, 0));
+        assert!(!backlog.wants_pause());
+        assert!(backlog.queue(1, 0));
+        assert!(backlog.wants_pause());
+        let mut sent = Vec::new();
+        assert!(backlog.pause_with(|| sent.push("pause")));
+        // told once, however much more is queued
+        assert!(backlog.queue(4096, 0));
+        assert!(!backlog.wants_pause());
+        assert!(!backlog.pause_with(|| sent.push("pause")));
+
+        // resumed only once drained to the low watermark
+        backlog.written(PAUSE_HIGH_WATERMARK - PAUSE_LOW_WATERMARK);
+        assert!(!backlog.wants_resume());
+        backlog.written(4096);
+        assert!(backlog.wants_resume());
+        assert!(backlog.resume_with(|| sent.push("resume")));
+        assert!(!backlog.resume_with(|| sent.push("resume")));
+        assert_eq!(sent, ["pause", "resume"]);
+    }
+
+    #[tokio::test]
+    async fn test_backlog_overflows_past_its_limit() {
+        let backlog = WriteBacklog::default();
+        assert!(backlog.queue(3000, 4096));
+        backlog.written(2000);
+        assert!(backlog.queue(3000, 4096));
+        assert_eq!(backlog.queued(), 4000);
+
+        // going beyond the limit drops the bytes from then on, even once some are written
+        assert!(!backlog.queue(100, 4096));
+        backlog.written(4100);
+        assert!(!backlog.queue(1, 4096));
+        timeout(Duration::from_secs(1), backlog.overflowed())
+            .await
+            .unwrap();
 
-    tokio::select! {
-        _ = forward1 => {},
-        _ = forward2 => {},
+        // without a limit it never overflows
+        let unlimited = WriteBacklog::default();
+        assert!(unlimited.queue(usize::MAX / 2, 0));
+        unlimited.written(usize::MAX);
+        assert_eq!(unlimited.queued(), 0);
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn test_half_close_and_large_transfer() {
+        let (a, mut a_peer) = duplex(8192);
+        let (b, mut b_peer) = duplex(8192);
+        let forward = tokio::spawn(forward_data(a, b));
+
+        // a uploads a lot and finishes sending, b still answers afterwards
+        let upload: Vec<u8> = (0..4 * 1024 * 1024u32).map(|i| i as u8).collect();
+        let sent = upload.clone();
+        let uploader = tokio::spawn(async move {
+            a_peer.write_all(&sent).await.unwrap();
+            a_peer.shutdown().await.unwrap();
+            a_peer
+        });
+        let mut received = Vec::new();
+        b_peer.read_to_end(&mut received).await.unwrap();
+        assert!(received == upload);
+
+        b_peer.write_all(b"done").await.unwrap();
+        b_peer.shutdown().await.unwrap();
+        let mut a_peer = uploader.await.unwrap();
+        let mut answer = Vec::new();
+        a_peer.read_to_end(&mut answer).await.unwrap();
+        assert_eq!(answer, b"done");
+
+        let stats = forward.await.unwrap().unwrap();
+        assert_eq!(stats.a_to_b, upload.len() as u64);
+        assert_eq!(stats.b_to_a, 4);
+    }
+
+    #[tokio::test]
+    async fn test_splice_counts_and_reports_who_finished() {
+        let (a, mut a_peer) = duplex(1024);
+        let (b, mut b_peer) = duplex(1024);
+        let (mut sent, mut received) = (0, 0);
+        let forwarding = async { splice(a, b, None, |n| sent += n, |n| received += n).await };
+        let peers = async {
+            b_peer.write_all(b"hello").await.unwrap();
+            b_peer.shutdown().await.unwrap();
+            let mut greeting = Vec::new();
+            a_peer.read_to_end(&mut greeting).await.unwrap();
+            assert_eq!(greeting, b"hello");
+            a_peer.write_all(b"bye").await.unwrap();
+            a_peer.shutdown().await.unwrap();
+            let mut rest = Vec::new();
+            b_peer.read_to_end(&mut rest).await.unwrap();
+            assert_eq!(rest, b"bye");
+        };
+        let (end, ()) = tokio::join!(forwarding, peers);
+        assert_eq!(end, SpliceEnd::Finished { a_first: false });
+        assert_eq!((sent, received), (3, 5));
+
+        // nothing sent either way
+        let (a, _a_peer) = duplex(1024);
+        let (b, _b_peer) = duplex(1024);
+        let idle = Some(Duration::from_millis(50));
+        assert_eq!(splice(a, b, idle, |_| {}, |_| {}).await, SpliceEnd::Idle);
+    }
+
+    #[tokio::test]
+    async fn test_error_on_one_side() {
+        let (a, mut a_peer) = duplex(1024);
+        let (b, b_peer) = duplex(1024);
+        drop(b_peer);
+
+        let forward = tokio::spawn(forward_data(a, b));
+        let _ = a_peer.write_all(b"nobody reads this").await;
+        let result = tokio::time::timeout(Duration::from_secs(1), forward)
+            .await
+            .expect("forwarding did not end");
+        assert!(result.unwrap().is_err());
+    }
 }
@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+
+/// Signature opening every PROXY protocol v2 header
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+/// PROXY protocol version written to local services ahead of the payload,
+/// so backends see the address of the original peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    /// Human-readable text header
+    V1,
+    /// Binary header
+    V2,
+}
+
+impl ProxyProtocol {
+    /// Builds the header for a TCP connection from `source` to `dest`.
+    /// Mixed address families are sent as IPv6, mapping the IPv4 side.
+    pub fn header(&self, source: SocketAddr, dest: SocketAddr) -> Vec<u8> {
+        let (source, dest) = unify_families(source, dest);
+        match self {
+            ProxyProtocol::V1 => v1_header(source, dest),
+            ProxyProtocol::V2 => v2_header(source, dest),
+        }
+    }
+}
+
+fn unify_families(source: SocketAddr, dest: SocketAddr) -> (SocketAddr, SocketAddr) {
+    if source.is_ipv4() == dest.is_ipv4() {
+        return (source, dest);
+    }
+    let to_v6 = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+    (to_v6(source), to_v6(dest))
+}
+
+fn v1_header(source: SocketAddr, dest: SocketAddr) -> Vec<u8> {
+    let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        family,
+        source.ip(),
+        dest.ip(),
+        source.port(),
+        dest.port()
+    )
+    .into_bytes()
+}
+
+fn v2_header(source: SocketAddr, dest: SocketAddr) -> Vec<u8> {
+    let mut addresses = Vec::with_capacity(36);
+    let family = match (source.ip(), dest.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            addresses.extend_from_slice(&src.octets());
+            addresses.extend_from_slice(&dst.octets());
+            0x11 // AF_INET, STREAM
+        }
+        (IpAddr::V6(src), IpAddr::V6(dst)) => {
+            addresses.extend_from_slice(&src.octets());
+            addresses.extend_from_slice(&dst.octets());
+            0x21 // AF_INET6, STREAM
+        }
+        _ => unreachable!("address families are unified before encoding"),
+    };
+    addresses.extend_from_slice(&source.port().to_be_bytes());
+    addresses.extend_from_slice(&dest.port().to_be_bytes());
+
+    let mut header = Vec::with_capacity(16 + addresses.len());
+    header.extend_from_slice(&V2_SIGNATURE);
+    header.push(0x21); // version 2, PROXY command
+    header.push(family);
+    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+    header.extend_from_slice(&addresses);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_v1_ipv4() {
+        let header = ProxyProtocol::V1.header(addr("192.0.2.10:51234"), addr("198.51.100.1:443"));
+        assert_eq!(header, b"PROXY TCP4 192.0.2.10 198.51.100.1 51234 443\r\n");
+    }
+
+    #[test]
+    fn test_v1_ipv6() {
+        let header =
+            ProxyProtocol::V1.header(addr("[2001:db8::1]:51234"), addr("[2001:db8::2]:443"));
+        assert_eq!(header, b"PROXY TCP6 2001:db8::1 2001:db8::2 51234 443\r\n");
+    }
+
+    #[test]
+    fn test_v1_mixed_families_map_to_ipv6() {
+        let header = ProxyProtocol::V1.header(addr("192.0.2.10:51234"), addr("[2001:db8::2]:443"));
+        assert_eq!(
+            header,
+            b"PROXY TCP6 ::ffff:192.0.2.10 2001:db8::2 51234 443\r\n"
+        );
+    }
+
+    #[test]
+    fn test_v2_ipv4() {
+        let header = ProxyProtocol::V2.header(addr("192.0.2.10:51234"), addr("198.51.100.1:443"));
+        let expected: Vec<u8> = [
+            &V2_SIGNATURE[..],
+            &[0x21, 0x11, 0x00, 0x0C],
+            &[192, 0, 2, 10],
+            &[198, 51, 100, 1],
+            &[0xC8, 0x22], // 51234
+            &[0x01, 0xBB], // 443
+        ]
+        .concat();
+        assert_eq!(header, expected);
+    }
+
+    #[test]
+    fn test_v2_ipv6() {
+        let header =
+            ProxyProtocol::V2.header(addr("[2001:db8::1]:51234"), addr("[2001:db8::2]:443"));
+        let mut src = [0u8; 16];
+        src[..4].copy_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        src[15] = 1;
+        let mut dst = src;
+        dst[15] = 2;
+        let expected: Vec<u8> = [
+            &V2_SIGNATURE[..],
+            &[0x21, 0x21, 0x00, 0x24],
+            &src[..],
+            &dst[..],
+            &[0xC8, 0x22, 0x01, 0xBB],
+        ]
+        .concat();
+        assert_eq!(header, expected);
+    }
+}
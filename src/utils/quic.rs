@@ -0,0 +1,126 @@
+use anyhow::{anyhow, Context, Result};
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Connection, Endpoint, RecvStream, SendStream, TransportConfig, VarInt};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Duration;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+
+use crate::config::{ClientTlsConfig, ServerTlsConfig};
+use crate::utils::{tls, BoxedStream};
+
+/// Application protocol both ends announce, a QUIC handshake requires one
+const ALPN: &[u8] = b"sowback";
+
+/// Keeps quiet connections from reaching QUIC's idle timeout between heartbeats
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Streams a client may have open at once, one per proxied connection plus the control stream
+const MAX_STREAMS: u32 = 4096;
+
+fn transport_config() -> Arc<TransportConfig> {
+    let mut transport = TransportConfig::default();
+    transport
+        .keep_alive_interval(Some(KEEP_ALIVE_INTERVAL))
+        .max_concurrent_bidi_streams(VarInt::from_u32(MAX_STREAMS));
+    Arc::new(transport)
+}
+
+/// Binds the server endpoint on `addr`, presenting the certificate of the TLS settings
+pub fn server_endpoint(addr: SocketAddr, config: &ServerTlsConfig) -> Result<Endpoint> {
+    let mut crypto = tls::build_server_config(config)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicServerConfig::try_from(crypto).context("TLS settings unusable for QUIC")?;
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    server_config.transport_config(transport_config());
+    Endpoint::server(server_config, addr)
+        .with_context(|| format!("Failed to bind QUIC endpoint on {}", addr))
+}
+
+/// Binds a client endpoint able to reach `remote`, verifying servers with the TLS settings
+pub fn client_endpoint(remote: SocketAddr, config: &ClientTlsConfig) -> Result<Endpoint> {
+    let mut crypto = tls::build_client_config(config)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicClientConfig::try_from(crypto).context("TLS settings unusable for QUIC")?;
+    let mut client_config = quinn::ClientConfig::new(Arc::new(crypto));
+    client_config.transport_config(transport_config());
+
+    let local: SocketAddr = if remote.is_ipv6() {
+        "[::]:0".parse()?
+    } else {
+        "0.0.0.0:0".parse()?
+    };
+    let mut endpoint = Endpoint::client(local)?;
+    endpoint.set_default_client_config(client_config);
+    Ok(endpoint)
+}
+
+/// Identity of the verified client certificate of a connection, see `tls::peer_identity`
+pub fn peer_identity(connection: &Connection) -> Option<String> {
+    let certs = connection
+        .peer_identity()?
+        .downcast::<Vec<CertificateDer<'static>>>()
+        .ok()?;
+    tls::peer_identity(&certs)
+}
+
+/// Joins the halves of a bidirectional stream into one byte stream
+pub fn join(send: SendStream, recv: RecvStream) -> BoxedStream {
+    Box::new(tokio::io::join(recv, send))
+}
+
+/// Opens a stream with its header, the ID of the proxied connection it carries or an
+/// empty one for the control stream. The peer only learns of a stream once it has data
+pub async fn open_stream(connection: &Connection, connection_id: &str) -> Result<BoxedStream> {
+    let (mut send, recv) = connection.open_bi().await?;
+    write_stream_header(&mut send, connection_id).await?;
+    Ok(join(send, recv))
+}
+
+/// Writes the header opening a stream: the connection ID's length as one byte, then the ID
+pub async fn write_stream_header<W>(stream: &mut W, connection_id: &str) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let len = u8::try_from(connection_id.len())
+        .map_err(|_| anyhow!("Connection ID too long for a stream header"))?;
+    let mut header = Vec::with_capacity(1 + connection_id.len());
+    header.push(len);
+    header.extend_from_slice(connection_id.as_bytes());
+    stream.write_all(&header).await?;
+    Ok(())
+}
+
+/// Reads the header written by `write_stream_header`, returning the connection ID
+pub async fn read_stream_header<R>(stream: &mut R) -> Result<String>
+where
+    R: AsyncRead + Unpin,
+{
+    let len = stream.read_u8().await?;
+    let mut connection_id = vec![0u8; len as usize];
+    stream.read_exact(&mut connection_id).await?;
+    String::from_utf8(connection_id).map_err(|_| anyhow!("Stream header is not UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stream_header_round_trip() {
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        let connection_id = "0b6c2c38-5b84-4e5f-9a3c-1f4f5a0e7d21";
+        write_stream_header(&mut a, connection_id).await.unwrap();
+        write_stream_header(&mut a, "").await.unwrap();
+        a.write_all(b"payload").await.unwrap();
+
+        assert_eq!(read_stream_header(&mut b).await.unwrap(), connection_id);
+        assert_eq!(read_stream_header(&mut b).await.unwrap(), "");
+        let mut payload = [0u8; 7];
+        b.read_exact(&mut payload).await.unwrap();
+        assert_eq!(&payload, b"payload");
+
+        assert!(write_stream_header(&mut a, &"x".repeat(256)).await.is_err());
+    }
+}
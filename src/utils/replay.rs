@@ -0,0 +1,349 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::sync::CancellationToken;
+
+use crate::utils::compression::Compression;
+use crate::utils::protocol::MAX_DATA_PAYLOAD;
+use crate::utils::window::INITIAL_WINDOW;
+use crate::utils::Message;
+
+/// Bytes of a connection's data kept for sending again. The window holds what the peer did
+/// not acknowledge to about `INITIAL_WINDOW`, so only data of a peer that stopped
+/// acknowledging is dropped from the buffer
+pub const REPLAY_CAPACITY: usize = 2 * INITIAL_WINDOW;
+
+/// The session a connection's data is sent on
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub sender: UnboundedSender<Message>,
+    pub compression: Compression,
+}
+
+/// The sending side of a connection carried in `Data` messages, keeping what the peer has
+/// not acknowledged yet. When the session carrying the connection is lost, the connection
+/// is detached and its data buffered, until a `ResumeConnection` exchange on the next
+/// session attaches it again and sends what the peer did not receive
+#[derive(Debug)]
+pub struct ReplayBuffer {
+    connection_id: String,
+    /// Bytes kept at most, 0 keeps nothing and lets only connections that lost no data resume
+    capacity: usize,
+    /// Smallest payload compressed, see `Message::new_payload`
+    threshold: usize,
+    state: Mutex<ReplayState>,
+    /// Cancelled once the connection cannot be resumed anymore
+    abandoned: CancellationToken,
+}
+
+#[derive(Debug, Default)]
+struct ReplayState {
+    /// Payloads sent and not acknowledged yet with their `seq`, oldest first
+    unacked: VecDeque<(u64, Vec<u8>)>,
+    /// Bytes sent before the first payload of `unacked`
+    base: u64,
+    /// Bytes of the payloads in `unacked`
+    buffered: usize,
+    /// Bytes the peer acknowledged with `WindowUpdate`s
+    peer_acknowledged: u64,
+    /// `seq` of the next payload
+    next_seq: u64,
+    /// Whether the `ShutdownWrite` was sent
+    finished: bool,
+    /// None while the connection is detached
+    route: Option<Route>,
+    /// Times the connection was detached, telling one detachment from the next
+    detachments: u64,
+    /// Bytes of the peer's data received in sequence
+    received: u64,
+    /// Bytes of the peer's data acknowledged, whether the `WindowUpdate`s arrived or not
+    acknowledged: u64,
+}
+
+impl ReplayState {
+    /// Drops the payloads the peer has all of, once it is known to have received `bytes`
+    fn trim(&mut self, bytes: u64) {
+        while let Some((_, data)) = self
+            .unacked
+            .front()
+            .filter(|(_, data)| self.base + data.len() as u64 <= bytes)
+        {
+            let len = data.len();
+            self.unacked.pop_front();
+            self.base += len as u64;
+            self.buffered -= len;
+        }
+    }
+
+    /// Bytes sent on the connection so far
+    fn sent(&self) -> u64 {
+        self.base + self.buffered as u64
+    }
+}
+
+impl ReplayBuffer {
+    /// Creates the buffer of a connection sending on `route`
+    pub fn new(
+        connection_id: &str,
+        capacity: usize,
+        threshold: usize,
+        route: Option<Route>,
+    ) -> Self {
+        Self {
+            connection_id: connection_id.to_string(),
+            capacity,
+            threshold,
+            state: Mutex::new(ReplayState {
+                route,
+                ..ReplayState::default()
+            }),
+            abandoned: CancellationToken::new(),
+        }
+    }
+
+    /// Sends `data` in payloads of at most `MAX_DATA_PAYLOAD` bytes, only buffering it while
+    /// detached. False once the connection was abandoned
+    pub fn send(&self, data: &[u8]) -> bool {
+        let mut state = self.state.lock().unwrap();
+        for chunk in data.chunks(MAX_DATA_PAYLOAD) {
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            if let Some(route) = &state.route {
+                let message = Message::new_payload(
+                    &self.connection_id,
+                    seq,
+                    chunk.to_vec(),
+                    route.compression,
+                    self.threshold,
+                );
+                // a session that ended meanwhile detaches the connection
+                if route.sender.send(message).is_err() {
+                    state.route = None;
+                }
+            }
+            state.unacked.push_back((seq, chunk.to_vec()));
+            state.buffered += chunk.len();
+            while state.buffered > self.capacity {
+                let Some((_, dropped)) = state.unacked.pop_front() else {
+                    break;
+                };
+                state.base += dropped.len() as u64;
+                state.buffered -= dropped.len();
+            }
+        }
+        !self.abandoned.is_cancelled()
+    }
+
+    /// Sends the `ShutdownWrite` ending the connection's data. False once the connection
+    /// was abandoned
+    pub fn finish(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.finished = true;
+        if let Some(route) = &state.route {
+            let message = Message::new_shutdown_write(&self.connection_id);
+            if route.sender.send(message).is_err() {
+                state.route = None;
+            }
+        }
+        !self.abandoned.is_cancelled()
+    }
+
+    /// Drops what the peer acknowledged with a `WindowUpdate` of `bytes`
+    pub fn acknowledge(&self, bytes: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.peer_acknowledged += u64::from(bytes);
+        let acknowledged = state.peer_acknowledged;
+        state.trim(acknowledged);
+    }
+
+    /// Counts `bytes` of the peer's data received in sequence
+    pub fn record_received(&self, bytes: usize) {
+        self.state.lock().unwrap().received += bytes as u64;
+    }
+
+    /// Counts `bytes` acknowledged to the peer, counted even when the `WindowUpdate` could
+    /// not be sent: the peer is told on resuming how many it missed
+    pub fn record_acknowledged(&self, bytes: u32) {
+        self.state.lock().unwrap().acknowledged += u64::from(bytes);
+    }
+
+    /// Whether the connection is sending on a session
+    pub fn is_attached(&self) -> bool {
+        self.state.lock().unwrap().route.is_some()
+    }
+
+    /// Stops sending on the lost session, returning which detachment this is for
+    /// `still_detached`
+    pub fn detach(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.route = None;
+        state.detachments += 1;
+        state.detachments
+    }
+
+    /// Whether the connection was not attached again since the detachment `detached`
+    pub fn still_detached(&self, detached: u64) -> bool {
+        let state = self.state.lock().unwrap();
+        state.route.is_none() && state.detachments == detached
+    }
+
+    /// The `ResumeConnection` telling the peer what this end received
+    pub fn resume_message(&self) -> Message {
+        let state = self.state.lock().unwrap();
+        Message::ResumeConnection {
+            connection_id: self.connection_id.clone(),
+            received: state.received,
+            acknowledged: state.acknowledged,
+        }
+    }
+
+    /// Attaches the connection to the session of `route` once the peer's `ResumeConnection`
+    /// told what it `received` and `acknowledged`, sending again what it did not receive.
+    /// Returns the bytes of the peer's acknowledgments that were lost, for the window to be
+    /// granted; fails when the data the peer is missing is no longer buffered
+    pub fn reattach(&self, route: Route, received: u64, acknowledged: u64) -> Result<u32, String> {
+        let mut state = self.state.lock().unwrap();
+        if received > state.sent() {
+            return Err(format!(
+                "the peer received {} bytes, only {} were sent",
+                received,
+                state.sent()
+            ));
+        }
+        if received < state.base {
+            return Err(format!(
+                "{} bytes the peer did not receive are no longer buffered",
+                state.base - received
+            ));
+        }
+        state.trim(received);
+        if state.base != received {
+            return Err(format!(
+                "the peer received {} bytes, not a whole number of messages",
+                received
+            ));
+        }
+        let lost = acknowledged.saturating_sub(state.peer_acknowledged);
+        state.peer_acknowledged += lost;
+
+        for (seq, data) in &state.unacked {
+            let message = Message::new_payload(
+                &self.connection_id,
+                *seq,
+                data.clone(),
+                route.compression,
+                self.threshold,
+            );
+            let _ = route.sender.send(message);
+        }
+        if state.finished {
+            let _ = route
+                .sender
+                .send(Message::new_shutdown_write(&self.connection_id));
+        }
+        state.route = Some(route);
+        Ok(lost.min(u64::from(u32::MAX)) as u32)
+    }
+
+    /// Gives up on resuming the connection, its handler closes it
+    pub fn abandon(&self) {
+        self.state.lock().unwrap().route = None;
+        self.abandoned.cancel();
+    }
+
+    /// Completes once the connection was abandoned
+    pub async fn abandoned(&self) {
+        self.abandoned.cancelled().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    fn route() -> (Route, mpsc::UnboundedReceiver<Message>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let route = Route {
+            sender,
+            compression: Compression::None,
+        };
+        (route, receiver)
+    }
+
+    fn drain(receiver: &mut mpsc::UnboundedReceiver<Message>) -> Vec<(u64, Vec<u8>)> {
+        let mut payloads = Vec::new();
+        while let Ok(message) = receiver.try_recv() {
+            match message {
+                Message::Data { seq, data, .. } => payloads.push((seq, data)),
+                Message::ShutdownWrite { .. } => payloads.push((u64::MAX, Vec::new())),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        payloads
+    }
+
+    #[test]
+    fn test_resuming_sends_what_the_peer_missed() {
+        let (first, mut first_rx) = route();
+        let replay = ReplayBuffer::new("conn", REPLAY_CAPACITY, usize::MAX, Some(first));
+        assert!(replay.send(&[1; 100]));
+        assert!(replay.send(&[2; 100]));
+        assert_eq!(drain(&mut first_rx).len(), 2);
+        replay.acknowledge(50);
+
+        // the session is lost, what is read meanwhile is only buffered
+        let detached = replay.detach();
+        assert!(replay.send(&[3; 100]));
+        assert!(replay.finish());
+        assert!(replay.still_detached(detached));
+        assert!(drain(&mut first_rx).is_empty());
+
+        // the peer got the first payload, and acknowledged it all in updates partly lost
+        let (second, mut second_rx) = route();
+        assert_eq!(replay.reattach(second, 100, 100), Ok(50));
+        assert!(replay.is_attached());
+        assert!(!replay.still_detached(detached));
+        assert_eq!(
+            drain(&mut second_rx),
+            vec![(1, vec![2; 100]), (2, vec![3; 100]), (u64::MAX, Vec::new())]
+        );
+
+        replay.record_received(10);
+        replay.record_acknowledged(4);
+        assert!(matches!(
+            replay.resume_message(),
+            Message::ResumeConnection {
+                received: 10,
+                acknowledged: 4,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_resuming_fails_past_the_buffer() {
+        let replay = ReplayBuffer::new("conn", 150, usize::MAX, None);
+        for _ in 0..3 {
+            replay.send(&[0; 100]);
+        }
+
+        // the first two payloads made room for the third
+        let (route, _receiver) = route();
+        let error = replay.reattach(route.clone(), 100, 0).unwrap_err();
+        assert!(error.contains("100 bytes"), "{}", error);
+        assert!(replay.reattach(route.clone(), 400, 0).is_err());
+        assert!(replay.reattach(route.clone(), 250, 0).is_err());
+        assert_eq!(replay.reattach(route, 200, 0), Ok(0));
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_connections_stop_sending() {
+        let replay = ReplayBuffer::new("conn", 0, usize::MAX, None);
+        assert!(replay.send(b"kept"));
+        replay.abandon();
+        replay.abandoned().await;
+        assert!(!replay.send(b"lost"));
+        assert!(!replay.finish());
+    }
+}
@@ -0,0 +1,49 @@
+use std::io;
+use std::thread::available_parallelism;
+use tokio::runtime::{Builder, Runtime};
+
+/// Builds the tokio runtime a server or client runs on, with `workers` worker threads:
+/// 1 is a current-thread runtime running everything on the calling thread, 0 starts
+/// one worker per core
+pub fn build_runtime(workers: usize) -> io::Result<Runtime> {
+    match worker_threads(workers) {
+        1 => Builder::new_current_thread().enable_all().build(),
+        workers => Builder::new_multi_thread()
+            .worker_threads(workers)
+            .enable_all()
+            .build(),
+    }
+}
+
+/// The worker threads `workers` stands for, 0 being the number of cores
+pub fn worker_threads(workers: usize) -> usize {
+    match workers {
+        0 => available_parallelism().map_or(1, |cores| cores.get()),
+        workers => workers,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::RuntimeFlavor;
+
+    #[test]
+    fn test_one_worker_runs_on_the_current_thread() {
+        let runtime = build_runtime(1).unwrap();
+        assert_eq!(
+            runtime.handle().runtime_flavor(),
+            RuntimeFlavor::CurrentThread
+        );
+
+        let runtime = build_runtime(3).unwrap();
+        assert_eq!(
+            runtime.handle().runtime_flavor(),
+            RuntimeFlavor::MultiThread
+        );
+        assert_eq!(runtime.metrics().num_workers(), 3);
+
+        assert!(worker_threads(0) >= 1);
+        assert_eq!(worker_threads(2), 2);
+    }
+}
@@ -0,0 +1,216 @@
+use std::env;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::{log_debug, log_warn};
+
+/// Variable the service manager names its notification socket in, set for `Type=notify`
+const NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+
+/// Whether `notify_ready` told the service manager already
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Sends `state`, e.g. `"READY=1"`, to the service manager named by `NOTIFY_SOCKET`,
+/// returning whether it was sent. Without the variable nothing happens
+pub fn notify(state: &str) -> bool {
+    let Some(socket) = env::var_os(NOTIFY_SOCKET) else {
+        return false;
+    };
+    match send(&socket, state) {
+        Ok(()) => true,
+        Err(e) => {
+            log_debug!("Cannot notify the service manager of {}: {}", state, e);
+            false
+        }
+    }
+}
+
+/// Tells the service manager the process is up, once however often it is called
+pub fn notify_ready() {
+    if !READY.swap(true, Ordering::AcqRel) {
+        notify("READY=1");
+    }
+}
+
+/// Sends `state` in one datagram to `socket`, a path or, starting with `@`, an abstract
+/// socket name
+#[cfg(unix)]
+fn send(socket: &std::ffi::OsStr, state: &str) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let datagram = UnixDatagram::unbound()?;
+    match socket.as_bytes() {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        [b'@', name @ ..] => {
+            #[cfg(target_os = "android")]
+            use std::os::android::net::SocketAddrExt;
+            #[cfg(target_os = "linux")]
+            use std::os::linux::net::SocketAddrExt;
+
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            datagram.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        [b'/', ..] => {
+            datagram.send_to(state.as_bytes(), socket)?;
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported {} {:?}", NOTIFY_SOCKET, socket),
+            ))
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_socket: &std::ffi::OsStr, _state: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "notification sockets are Unix sockets",
+    ))
+}
+
+/// How often to ping the watchdog the service manager set with `WATCHDOG_USEC`: half its
+/// timeout. None without a watchdog, or when `WATCHDOG_PID` names another process
+pub fn watchdog_period() -> Option<Duration> {
+    watchdog_period_in(|name| env::var(name).ok())
+}
+
+/// `watchdog_period` with the variables looked up with `var`
+fn watchdog_period_in(var: impl Fn(&str) -> Option<String>) -> Option<Duration> {
+    let usec: u64 = var("WATCHDOG_USEC")?.trim().parse().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    if let Some(pid) = var("WATCHDOG_PID") {
+        if pid.trim().parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Pings the watchdog every `period` as long as `healthy` answers true, so the service
+/// manager restarts a process whose loops hang as well as one that stopped running
+pub async fn run_watchdog<F, Fut>(period: Duration, mut healthy: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let mut ticks = tokio::time::interval(period);
+    loop {
+        ticks.tick().await;
+        if healthy().await {
+            notify("WATCHDOG=1");
+        } else {
+            log_warn!("Skipping the watchdog ping, the main loops stopped making progress");
+        }
+    }
+}
+
+/// When a loop last went round, for the watchdog to tell a stalled one
+#[derive(Debug)]
+pub struct Progress {
+    since: Instant,
+    /// Milliseconds after `since` of the last beat
+    last: AtomicU64,
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self {
+            since: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Progress {
+    /// Records that the loop went round
+    pub fn beat(&self) {
+        let elapsed = self.since.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Whether the loop went round within `period`, or was created that recently
+    pub fn within(&self, period: Duration) -> bool {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.since.elapsed().saturating_sub(last) <= period
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_period_is_half_the_timeout() {
+        let vars = |usec: &'static str, pid: Option<String>| {
+            move |name: &str| match name {
+                "WATCHDOG_USEC" => Some(usec.to_string()),
+                "WATCHDOG_PID" => pid.clone(),
+                _ => None,
+            }
+        };
+        assert_eq!(
+            watchdog_period_in(vars("30000000", None)),
+            Some(Duration::from_secs(15))
+        );
+        let own = Some(std::process::id().to_string());
+        assert_eq!(
+            watchdog_period_in(vars("2000000", own)),
+            Some(Duration::from_secs(1))
+        );
+        // meant for another process, or no watchdog at all
+        let other = Some((std::process::id() + 1).to_string());
+        assert_eq!(watchdog_period_in(vars("2000000", other)), None);
+        assert_eq!(watchdog_period_in(vars("0", None)), None);
+        assert_eq!(watchdog_period_in(vars("soon", None)), None);
+        assert_eq!(watchdog_period_in(|_| None), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_states_reach_the_notification_socket() {
+        use std::os::unix::net::UnixDatagram;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let manager = UnixDatagram::bind(&path).unwrap();
+        send(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0u8; 64];
+        let len = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        assert!(send("relative.sock".as_ref(), "READY=1").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_abstract_notification_sockets() {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+        let name = format!("sowback-notify-test-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let manager = UnixDatagram::bind_addr(&addr).unwrap();
+        send(format!("@{}", name).as_ref(), "WATCHDOG=1").unwrap();
+        let mut buf = [0u8; 64];
+        let len = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"WATCHDOG=1");
+    }
+
+    #[test]
+    fn test_progress_goes_stale() {
+        let progress = Progress::default();
+        assert!(progress.within(Duration::from_millis(50)));
+        std::thread::sleep(Duration::from_millis(80));
+        assert!(!progress.within(Duration::from_millis(50)));
+        progress.beat();
+        assert!(progress.within(Duration::from_millis(50)));
+    }
+}
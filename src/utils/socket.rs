@@ -0,0 +1,118 @@
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+
+use crate::{log_debug, log_warn};
+
+/// Keepalive probes sent before a silent peer is considered gone
+const KEEPALIVE_RETRIES: u32 = 4;
+
+/// TCP options applied to control and tunnel sockets
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    /// Disable Nagle's algorithm, so small writes of interactive protocols go out at once
+    pub nodelay: bool,
+    /// Idle time before keepalive probes start, keepalive is off when None
+    pub keepalive: Option<Duration>,
+    /// How long written data may go unacknowledged before the connection is dropped
+    /// (`TCP_USER_TIMEOUT`, Linux only), left to the OS when None
+    pub user_timeout: Option<Duration>,
+}
+
+impl SocketOptions {
+    /// Applies the options to `stream`, failures are logged and otherwise ignored
+    pub fn apply(&self, stream: &TcpStream, purpose: &str) {
+        if let Err(e) = stream.set_nodelay(self.nodelay) {
+            log_warn!("Failed to set TCP_NODELAY on {} socket: {}", purpose, e);
+        }
+
+        let socket = SockRef::from(stream);
+        let result = match self.keepalive {
+            Some(time) => socket.set_tcp_keepalive(&keepalive_params(time)),
+            None => socket.set_keepalive(false),
+        };
+        if let Err(e) = result {
+            log_warn!("Failed to set TCP keepalive on {} socket: {}", purpose, e);
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(user_timeout) = self.user_timeout {
+            if let Err(e) = socket.set_tcp_user_timeout(Some(user_timeout)) {
+                log_warn!(
+                    "Failed to set TCP user timeout on {} socket: {}",
+                    purpose,
+                    e
+                );
+            }
+        }
+
+        log_debug!(
+            "Socket options for {} socket: nodelay={}, keepalive={:?}, user_timeout={:?}",
+            purpose,
+            self.nodelay,
+            self.keepalive,
+            self.user_timeout
+        );
+    }
+}
+
+/// Probes start after `time` idle, then repeat so a dead peer is noticed within about twice that
+fn keepalive_params(time: Duration) -> TcpKeepalive {
+    let keepalive = TcpKeepalive::new().with_time(time);
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "windows",
+    ))]
+    let keepalive = keepalive
+        .with_interval((time / KEEPALIVE_RETRIES).max(Duration::from_secs(1)))
+        .with_retries(KEEPALIVE_RETRIES);
+    keepalive
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_options_are_applied() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let options = SocketOptions {
+            nodelay: true,
+            keepalive: Some(Duration::from_secs(60)),
+            user_timeout: Some(Duration::from_secs(45)),
+        };
+        options.apply(&stream, "test");
+        let socket = SockRef::from(&stream);
+        assert!(stream.nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            socket.tcp_keepalive_time().unwrap(),
+            Duration::from_secs(60)
+        );
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            socket.tcp_user_timeout().unwrap(),
+            Some(Duration::from_secs(45))
+        );
+
+        SocketOptions {
+            nodelay: false,
+            keepalive: None,
+            user_timeout: None,
+        }
+        .apply(&stream, "test");
+        assert!(!stream.nodelay().unwrap());
+        assert!(!socket.keepalive().unwrap());
+    }
+}
@@ -0,0 +1,616 @@
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::sync::Arc;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{
+    self, AlertDescription, CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore,
+    ServerConfig, SignatureScheme,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::config::{AuthMode, ClientTlsConfig, ServerTlsConfig};
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Loads every certificate from a PEM file
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .with_context(|| format!("Failed to open certificate file {}", path))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse certificate file {}", path))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {}", path));
+    }
+    Ok(certs)
+}
+
+/// Loads every certificate from a PEM file into a root store
+fn load_roots(path: &str) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .with_context(|| format!("Invalid CA certificate in {}", path))?;
+    }
+    Ok(roots)
+}
+
+/// Loads the first private key from a PEM file
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .with_context(|| format!("Failed to load private key from {}", path))
+}
+
+/// Builds the TLS acceptor for the server control port
+pub fn build_acceptor(config: &ServerTlsConfig) -> Result<TlsAcceptor> {
+    Ok(TlsAcceptor::from(Arc::new(build_server_config(config)?)))
+}
+
+/// Builds the server side TLS settings, shared by the control port and QUIC
+pub fn build_server_config(config: &ServerTlsConfig) -> Result<ServerConfig> {
+    let certs = load_certs(&config.cert)?;
+    let key = load_key(&config.key)?;
+
+    let builder =
+        ServerConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+    let builder = match &config.client_ca {
+        Some(client_ca) => {
+            // Certificates are optional at the TLS layer so that the auth mode decides
+            // whether a missing one is fatal, but a presented one must chain to `client_ca`
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(load_roots(client_ca)?),
+                provider(),
+            )
+            .allow_unauthenticated()
+            .build()
+            .context("Invalid client CA")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None if config.auth_mode() != AuthMode::Token => {
+            return Err(anyhow!(
+                "tls.auth_mode = {:?} requires tls.client_ca to verify client certificates",
+                config.auth_mode()
+            ));
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    builder
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate or key")
+}
+
+/// Builds the TLS connector used to dial servers
+pub fn build_connector(config: &ClientTlsConfig) -> Result<TlsConnector> {
+    Ok(TlsConnector::from(Arc::new(build_client_config(config)?)))
+}
+
+/// Builds the client side TLS settings, shared by TLS over TCP and QUIC
+pub fn build_client_config(config: &ClientTlsConfig) -> Result<ClientConfig> {
+    let builder =
+        ClientConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+
+    let builder = if config.insecure_skip_verify {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoCertificateVerification(provider())))
+    } else if let Some(fingerprint) = &config.fingerprint {
+        let fingerprint = parse_fingerprint(fingerprint)
+            .ok_or_else(|| anyhow!("Invalid tls.fingerprint '{}'", fingerprint))?;
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertificate {
+                fingerprint,
+                provider: provider(),
+            }))
+    } else {
+        let roots = match &config.ca {
+            Some(ca_path) => load_roots(ca_path)?,
+            None => {
+                let mut roots = RootCertStore::empty();
+                let native = rustls_native_certs::load_native_certs();
+                let (added, _) = roots.add_parsable_certificates(native.certs);
+                if added == 0 {
+                    return Err(anyhow!(
+                        "No system root certificates found; set tls.ca to a CA bundle"
+                    ));
+                }
+                roots
+            }
+        };
+        builder.with_root_certificates(roots)
+    };
+
+    match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .context("Invalid TLS client certificate or key"),
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => Err(anyhow!(
+            "tls.cert and tls.key must be set together for client certificates"
+        )),
+    }
+}
+
+/// SHA-256 fingerprint of a certificate, what `tls.fingerprint` pins
+pub fn fingerprint(cert: &CertificateDer<'_>) -> [u8; 32] {
+    Sha256::digest(cert.as_ref()).into()
+}
+
+/// Fingerprint of the first certificate in a PEM file, written as `tls.fingerprint` takes it
+pub fn certificate_fingerprint(path: &str) -> Result<String> {
+    let certs = load_certs(path)?;
+    let hex: Vec<String> = fingerprint(&certs[0])
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect();
+    Ok(hex.join(":"))
+}
+
+/// Parses a SHA-256 fingerprint written as hex, colons between the bytes allowed
+pub fn parse_fingerprint(text: &str) -> Option<[u8; 32]> {
+    let digits: Vec<u8> = text.bytes().filter(|&byte| byte != b':').collect();
+    if digits.len() != 64 {
+        return None;
+    }
+    let mut fingerprint = [0u8; 32];
+    for (byte, pair) in fingerprint.iter_mut().zip(digits.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(fingerprint)
+}
+
+/// Human readable identity of a verified peer certificate: its subject CN, or first DNS SAN
+pub fn peer_identity(certs: &[CertificateDer<'_>]) -> Option<String> {
+    let cert = certs.first()?;
+    let identity = x509_parser::parse_x509_certificate(cert.as_ref())
+        .ok()
+        .and_then(|(_, cert)| {
+            let common_name = cert
+                .subject()
+                .iter_common_name()
+                .next()
+                .and_then(|cn| cn.as_str().ok())
+                .map(str::to_string);
+            common_name.or_else(|| {
+                cert.subject_alternative_name()
+                    .ok()
+                    .flatten()
+                    .and_then(|san| {
+                        san.value.general_names.iter().find_map(|name| match name {
+                            x509_parser::extensions::GeneralName::DNSName(dns) => {
+                                Some(dns.to_string())
+                            }
+                            _ => None,
+                        })
+                    })
+            })
+        });
+    Some(identity.unwrap_or_else(|| "(unnamed certificate)".to_string()))
+}
+
+/// Name the server certificate is verified against: `tls.server_name`, or the host part of the address
+pub fn server_name(server_addr: &str, config: &ClientTlsConfig) -> Result<ServerName<'static>> {
+    let host = match &config.server_name {
+        Some(name) => name.as_str(),
+        None => server_addr
+            .rsplit_once(':')
+            .map(|(host, _)| host)
+            .unwrap_or(server_addr)
+            .trim_start_matches('[')
+            .trim_end_matches(']'),
+    };
+    ServerName::try_from(host.to_string())
+        .map_err(|_| anyhow!("Invalid TLS server name '{}'", host))
+}
+
+/// Turns a failed client-side handshake into an error telling the user what to fix
+pub fn describe_handshake_error(
+    err: io::Error,
+    server_addr: &str,
+    name: &ServerName,
+) -> anyhow::Error {
+    let reason = match err.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) {
+        Some(rustls::Error::InvalidCertificate(cert_err)) => match cert_err {
+            CertificateError::Expired | CertificateError::ExpiredContext { .. } => {
+                "the server certificate has expired".to_string()
+            }
+            CertificateError::NotValidYet | CertificateError::NotValidYetContext { .. } => {
+                "the server certificate is not valid yet (check the system clock)".to_string()
+            }
+            CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. } => {
+                format!(
+                    "the server certificate is not valid for '{}' (set tls.server_name to the name in the certificate)",
+                    name.to_str()
+                )
+            }
+            CertificateError::ApplicationVerificationFailure => {
+                "the server certificate does not match tls.fingerprint".to_string()
+            }
+            CertificateError::UnknownIssuer => {
+                "the server certificate is signed by an unknown CA (set tls.ca to the issuing CA bundle)"
+                    .to_string()
+            }
+            other => format!("invalid server certificate: {:?}", other),
+        },
+        Some(rustls::Error::InvalidMessage(_)) => {
+            "the server did not answer with TLS (is tls configured on the server?)".to_string()
+        }
+        Some(rustls::Error::AlertReceived(
+            alert @ (AlertDescription::BadCertificate
+            | AlertDescription::UnknownCA
+            | AlertDescription::CertificateExpired
+            | AlertDescription::CertificateRevoked
+            | AlertDescription::CertificateUnknown
+            | AlertDescription::CertificateRequired),
+        )) => {
+            format!(
+                "the server rejected the client certificate ({:?}); check tls.cert against the server's client_ca",
+                alert
+            )
+        }
+        Some(rustls::Error::AlertReceived(alert)) => {
+            format!("the server rejected the handshake ({:?})", alert)
+        }
+        Some(other) => other.to_string(),
+        None if err.kind() == io::ErrorKind::UnexpectedEof => {
+            "the server closed the connection (is tls configured on the server?)".to_string()
+        }
+        None => err.to_string(),
+    };
+    anyhow!("TLS handshake with {} failed: {}", server_addr, reason)
+}
+
+/// Verifier for `insecure_skip_verify`, accepting any server certificate
+#[derive(Debug)]
+struct NoCertificateVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Verifier for `tls.fingerprint`, accepting only the server certificate with that
+/// fingerprint whatever signed it and whichever name it is for
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    struct TestCert {
+        cert: NamedTempFile,
+        key: NamedTempFile,
+    }
+
+    fn write_temp(content: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file
+    }
+
+    fn self_signed(name: &str, expired: bool) -> TestCert {
+        let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, name);
+        if expired {
+            params.not_before = rcgen::date_time_ymd(2000, 1, 1);
+            params.not_after = rcgen::date_time_ymd(2001, 1, 1);
+        }
+        let key_pair = KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key_pair).unwrap();
+        TestCert {
+            cert: write_temp(&cert.pem()),
+            key: write_temp(&key_pair.serialize_pem()),
+        }
+    }
+
+    fn path(file: &NamedTempFile) -> String {
+        file.path().to_string_lossy().to_string()
+    }
+
+    fn server_config(cert: &TestCert) -> ServerTlsConfig {
+        ServerTlsConfig {
+            cert: path(&cert.cert),
+            key: path(&cert.key),
+            client_ca: None,
+            auth_mode: None,
+        }
+    }
+
+    /// Runs one handshake against a TLS echo server and returns the client-side result
+    async fn handshake(server_cert: &TestCert, client_config: ClientTlsConfig) -> Result<()> {
+        handshake_with(server_config(server_cert), client_config)
+            .await
+            .map(|_| ())
+    }
+
+    /// Like [`handshake`] with a custom server config, also returning the server-side peer identity
+    async fn handshake_with(
+        server_config: ServerTlsConfig,
+        client_config: ClientTlsConfig,
+    ) -> Result<Option<String>> {
+        let acceptor = build_acceptor(&server_config).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let server_task = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut tls = acceptor.accept(stream).await.ok()?;
+            let identity = tls.get_ref().1.peer_certificates().and_then(peer_identity);
+            let mut buf = [0u8; 4];
+            if tls.read_exact(&mut buf).await.is_ok() {
+                let _ = tls.write_all(&buf).await;
+            }
+            identity
+        });
+
+        let connector = build_connector(&client_config)?;
+        let name = server_name(&addr, &client_config)?;
+        let stream = TcpStream::connect(&addr).await?;
+        let mut tls = connector
+            .connect(name.clone(), stream)
+            .await
+            .map_err(|e| describe_handshake_error(e, &addr, &name))?;
+
+        // With TLS 1.3 a rejected client certificate only surfaces on the first read
+        tls.write_all(b"ping")
+            .await
+            .map_err(|e| describe_handshake_error(e, &addr, &name))?;
+        let mut buf = [0u8; 4];
+        tls.read_exact(&mut buf)
+            .await
+            .map_err(|e| describe_handshake_error(e, &addr, &name))?;
+        assert_eq!(&buf, b"ping");
+        Ok(server_task.await.unwrap())
+    }
+
+    fn client_config(ca: &TestCert, server_name: &str) -> ClientTlsConfig {
+        ClientTlsConfig {
+            enable: true,
+            ca: Some(ca.cert.path().to_string_lossy().to_string()),
+            server_name: Some(server_name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tls_handshake_with_ca() {
+        let cert = self_signed("tunnel.example.com", false);
+        handshake(&cert, client_config(&cert, "tunnel.example.com"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tls_name_mismatch_is_reported() {
+        let cert = self_signed("tunnel.example.com", false);
+        let err = handshake(&cert, client_config(&cert, "other.example.com"))
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("not valid for 'other.example.com'"));
+    }
+
+    #[tokio::test]
+    async fn test_tls_expired_cert_is_reported() {
+        let cert = self_signed("tunnel.example.com", true);
+        let err = handshake(&cert, client_config(&cert, "tunnel.example.com"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("has expired"));
+    }
+
+    #[tokio::test]
+    async fn test_tls_insecure_skip_verify() {
+        let cert = self_signed("tunnel.example.com", true);
+        let config = ClientTlsConfig {
+            enable: true,
+            insecure_skip_verify: true,
+            server_name: Some("anything.invalid".to_string()),
+            ..Default::default()
+        };
+        handshake(&cert, config).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tls_pinned_fingerprint() {
+        let cert = self_signed("tunnel.example.com", false);
+        let pinned = fingerprint(&load_certs(&path(&cert.cert)).unwrap()[0]);
+        let hex: Vec<String> = pinned.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let config = ClientTlsConfig {
+            enable: true,
+            fingerprint: Some(hex.join(":")),
+            server_name: Some("anything.invalid".to_string()),
+            ..Default::default()
+        };
+        handshake(&cert, config).await.unwrap();
+
+        let other = self_signed("tunnel.example.com", false);
+        let config = ClientTlsConfig {
+            enable: true,
+            fingerprint: Some(hex.concat()),
+            ..client_config(&other, "tunnel.example.com")
+        };
+        let err = handshake(&other, config).await.unwrap_err();
+        assert!(err.to_string().contains("does not match tls.fingerprint"));
+    }
+
+    #[test]
+    fn test_parse_fingerprint() {
+        let hex = "00".repeat(31) + "fF";
+        let mut expected = [0u8; 32];
+        expected[31] = 0xff;
+        assert_eq!(parse_fingerprint(&hex), Some(expected));
+        let colons: Vec<&str> = (0..32).map(|i| &hex[i * 2..i * 2 + 2]).collect();
+        assert_eq!(parse_fingerprint(&colons.join(":")), Some(expected));
+        assert_eq!(parse_fingerprint(&hex[2..]), None);
+        assert_eq!(parse_fingerprint(&("zz".to_string() + &hex[2..])), None);
+    }
+
+    #[tokio::test]
+    async fn test_mtls_client_identity() {
+        let server_cert = self_signed("tunnel.example.com", false);
+        let client_cert = self_signed("kitchen-pi", false);
+
+        let mut server = server_config(&server_cert);
+        server.client_ca = Some(path(&client_cert.cert));
+        assert_eq!(server.auth_mode(), AuthMode::Cert);
+
+        let mut client = client_config(&server_cert, "tunnel.example.com");
+        client.cert = Some(path(&client_cert.cert));
+        client.key = Some(path(&client_cert.key));
+
+        let identity = handshake_with(server, client).await.unwrap();
+        assert_eq!(identity.as_deref(), Some("kitchen-pi"));
+    }
+
+    #[tokio::test]
+    async fn test_mtls_untrusted_client_cert_is_reported() {
+        let server_cert = self_signed("tunnel.example.com", false);
+        let trusted = self_signed("trusted", false);
+        let untrusted = self_signed("untrusted", false);
+
+        let mut server = server_config(&server_cert);
+        server.client_ca = Some(path(&trusted.cert));
+
+        let mut client = client_config(&server_cert, "tunnel.example.com");
+        client.cert = Some(path(&untrusted.cert));
+        client.key = Some(path(&untrusted.key));
+
+        let err = handshake_with(server, client).await.unwrap_err();
+        assert!(err.to_string().contains("client certificate"), "{}", err);
+    }
+
+    #[test]
+    fn test_cert_mode_requires_client_ca() {
+        let server_cert = self_signed("tunnel.example.com", false);
+        let mut server = server_config(&server_cert);
+        server.auth_mode = Some(AuthMode::Both);
+        assert!(build_acceptor(&server).is_err());
+    }
+
+    #[test]
+    fn test_server_name_from_address() {
+        let config = ClientTlsConfig::default();
+        assert_eq!(
+            server_name("tunnel.example.com:7000", &config)
+                .unwrap()
+                .to_str(),
+            "tunnel.example.com"
+        );
+        assert_eq!(server_name("[::1]:7000", &config).unwrap().to_str(), "::1");
+    }
+}
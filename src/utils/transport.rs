@@ -0,0 +1,127 @@
+use bytes::{Buf, Bytes};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::{timeout, Duration};
+
+use crate::config::HumanDuration;
+
+/// Byte stream carrying the framed control protocol (plain TCP, TLS, WebSocket or QUIC)
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+/// Type-erased control connection stream
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+/// Writes all of `buf`, failing with `TimedOut` if that takes longer than `limit`, e.g. because
+/// the peer stopped reading and the socket buffers are full. None waits as long as it takes
+pub async fn write_all_within<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    buf: &[u8],
+    limit: Option<Duration>,
+) -> io::Result<()> {
+    let Some(limit) = limit else {
+        return writer.write_all(buf).await;
+    };
+    timeout(limit, writer.write_all(buf)).await.map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("write stalled for {}", HumanDuration(limit)),
+        )
+    })?
+}
+
+/// Stream reading `early` before anything else, e.g. the bytes a `FrameReader` buffered
+/// past the frame that opened the stream
+pub struct Rewound<S> {
+    early: Bytes,
+    inner: S,
+}
+
+impl<S> Rewound<S> {
+    /// Wraps `inner`, reads return `early` first
+    pub fn new(early: impl Into<Bytes>, inner: S) -> Self {
+        Self {
+            early: early.into(),
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewound<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.early.has_remaining() {
+            let n = self.early.len().min(buf.remaining());
+            buf.put_slice(&self.early[..n]);
+            self.early.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewound<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpSocket};
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    async fn test_writes_to_a_peer_that_stopped_reading_give_up_in_time() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.set_send_buffer_size(4096).unwrap();
+        let mut writer = socket
+            .connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        // accepted, and never read from
+        let (_peer, _) = listener.accept().await.unwrap();
+
+        let started = Instant::now();
+        let payload = vec![0u8; 32 * 1024 * 1024];
+        let limit = Duration::from_millis(300);
+        let err = write_all_within(&mut writer, &payload, Some(limit))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < limit * 3);
+    }
+
+    #[tokio::test]
+    async fn test_rewound_reads_early_bytes_first() {
+        let (mut near, far) = tokio::io::duplex(64);
+        let mut stream = Rewound::new(b"early ".to_vec(), far);
+        near.write_all(b"late").await.unwrap();
+        drop(near);
+
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"early late");
+    }
+}
@@ -0,0 +1,404 @@
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::{Buf, BufMut, BytesMut};
+use sha1::{Digest, Sha1};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::{timeout, Duration};
+
+use super::BoxedStream;
+
+/// Appended to the client's key before hashing it into `Sec-WebSocket-Accept` (RFC 6455)
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longest HTTP head of a handshake, in bytes
+const MAX_HEAD: usize = 8 * 1024;
+
+/// Largest WebSocket frame payload accepted, well above a control protocol frame
+const MAX_FRAME_PAYLOAD: usize = 1024 * 1024;
+
+/// Largest payload of a frame written, longer writes are split
+const MAX_WRITE: usize = 64 * 1024;
+
+/// How long a connection to a WebSocket listener is given to send its upgrade request
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Which end of the connection a `WebSocketStream` is, clients mask what they send
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Client,
+    Server,
+}
+
+/// Byte stream carried in binary WebSocket messages over `S`. Every write becomes one
+/// message and the payloads read are handed out as one stream, so the framed control
+/// protocol runs over it unchanged
+pub struct WebSocketStream<S> {
+    inner: S,
+    role: Role,
+    /// Bytes read from `inner` that do not make up a whole frame yet
+    read_buf: BytesMut,
+    /// Payload of data frames not handed out yet
+    payload: BytesMut,
+    /// Encoded frames not written to `inner` yet
+    write_buf: BytesMut,
+    /// A close frame was received, reads see the end of the stream
+    closed: bool,
+    /// A close frame was queued by `poll_shutdown`
+    close_sent: bool,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketStream<S> {
+    fn new(inner: S, role: Role, read: &[u8]) -> Self {
+        Self {
+            inner,
+            role,
+            read_buf: BytesMut::from(read),
+            payload: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            closed: false,
+            close_sent: false,
+        }
+    }
+
+    /// Queues a frame, masked when sent by the client
+    fn queue_frame(&mut self, opcode: u8, payload: &[u8]) {
+        let mask = (self.role == Role::Client).then(rand::random::<[u8; 4]>);
+        encode_frame(&mut self.write_buf, opcode, payload, mask);
+    }
+
+    /// Writes out the queued frames
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.payload.is_empty() && !this.closed {
+            match decode_frame(&mut this.read_buf)? {
+                Some((OPCODE_BINARY | OPCODE_CONTINUATION, data)) => this.payload = data,
+                Some((OPCODE_CLOSE, _)) => this.closed = true,
+                // answered along with the next write
+                Some((OPCODE_PING, data)) => this.queue_frame(OPCODE_PONG, &data),
+                Some((OPCODE_PONG, _)) => {}
+                Some((opcode, _)) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unexpected WebSocket opcode {:#x}", opcode),
+                    )));
+                }
+                None => {
+                    let mut chunk = [0u8; 4096];
+                    let mut chunk = ReadBuf::new(&mut chunk);
+                    ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+                    if chunk.filled().is_empty() {
+                        // the peer went away without a close frame
+                        this.closed = true;
+                    }
+                    this.read_buf.extend_from_slice(chunk.filled());
+                }
+            }
+        }
+        let n = this.payload.len().min(buf.remaining());
+        buf.put_slice(&this.payload.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        let n = buf.len().min(MAX_WRITE);
+        this.queue_frame(OPCODE_BINARY, &buf[..n]);
+        // the frame is queued, it goes out now or with the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.close_sent {
+            this.close_sent = true;
+            this.queue_frame(OPCODE_CLOSE, &[]);
+        }
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Appends a final frame carrying `payload`, masked with `mask` if given
+fn encode_frame(out: &mut BytesMut, opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) {
+    out.put_u8(0x80 | opcode);
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len if len < 126 => out.put_u8(mask_bit | len as u8),
+        len if len <= u16::MAX as usize => {
+            out.put_u8(mask_bit | 126);
+            out.put_u16(len as u16);
+        }
+        len => {
+            out.put_u8(mask_bit | 127);
+            out.put_u64(len as u64);
+        }
+    }
+    match mask {
+        Some(mask) => {
+            out.put_slice(&mask);
+            out.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        }
+        None => out.put_slice(payload),
+    }
+}
+
+/// Splits a whole frame off `buf`, returning its opcode and unmasked payload.
+/// None if the frame is not complete yet
+fn decode_frame(buf: &mut BytesMut) -> io::Result<Option<(u8, BytesMut)>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let opcode = buf[0] & 0x0F;
+    let masked = buf[1] & 0x80 != 0;
+    let (len, mut header) = match buf[1] & 0x7F {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as usize, 4),
+        127 if buf.len() >= 10 => {
+            let len = u64::from_be_bytes(buf[2..10].try_into().unwrap());
+            (usize::try_from(len).unwrap_or(usize::MAX), 10)
+        }
+        126 | 127 => return Ok(None),
+        len => (len as usize, 2),
+    };
+    if len > MAX_FRAME_PAYLOAD {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("WebSocket frame of {} bytes is too large", len),
+        ));
+    }
+    let mask = if masked { Some(header) } else { None };
+    if masked {
+        header += 4;
+    }
+    if buf.len() < header + len {
+        return Ok(None);
+    }
+
+    let mut frame = buf.split_to(header + len);
+    let mask: Option<[u8; 4]> = mask.map(|at| frame[at..at + 4].try_into().unwrap());
+    let mut payload = frame.split_off(header);
+    if let Some(mask) = mask {
+        for (byte, m) in payload.iter_mut().zip(mask.iter().cycle()) {
+            *byte ^= m;
+        }
+    }
+    Ok(Some((opcode, payload)))
+}
+
+/// `Sec-WebSocket-Accept` answering the client's `Sec-WebSocket-Key`
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+    BASE64.encode(hasher.finalize())
+}
+
+/// Reads an HTTP head up to its blank line, after the bytes already in `read`.
+/// Returns the head and the bytes that followed it
+async fn read_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    mut read: Vec<u8>,
+) -> Result<(String, Vec<u8>)> {
+    loop {
+        if let Some(end) = read.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = read.split_off(end + 4);
+            let head = String::from_utf8(read)
+                .map_err(|_| anyhow!("WebSocket handshake is not valid UTF-8"))?;
+            return Ok((head, rest));
+        }
+        if read.len() > MAX_HEAD {
+            return Err(anyhow!("WebSocket handshake head is too large"));
+        }
+        let mut chunk = [0u8; 1024];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(anyhow!("Connection closed during the WebSocket handshake"));
+        }
+        read.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Value of the header `name` in an HTTP head, matched case-insensitively
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+/// Opens a WebSocket over `stream` to a server reached as `host`
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    host: &str,
+) -> Result<WebSocketStream<S>> {
+    let key = BASE64.encode(rand::random::<[u8; 16]>());
+    let request = format!(
+        "GET / HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        host, key
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let (head, rest) = read_head(&mut stream, Vec::new()).await?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("101") {
+        return Err(anyhow!("WebSocket upgrade refused by {}: {}", host, status));
+    }
+    if header(&head, "Sec-WebSocket-Accept") != Some(accept_key(&key).as_str()) {
+        return Err(anyhow!(
+            "WebSocket upgrade to {} answered with a wrong Sec-WebSocket-Accept",
+            host
+        ));
+    }
+    Ok(WebSocketStream::new(stream, Role::Client, &rest))
+}
+
+/// Answers the upgrade request opening `stream`
+async fn accept<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) -> Result<WebSocketStream<S>> {
+    let (head, rest) = read_head(&mut stream, Vec::new()).await?;
+    let upgrade = header(&head, "Upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    let Some(key) = header(&head, "Sec-WebSocket-Key").filter(|_| upgrade) else {
+        stream
+            .write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n")
+            .await?;
+        return Err(anyhow!("HTTP request is not a WebSocket upgrade"));
+    };
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(WebSocketStream::new(stream, Role::Server, &rest))
+}
+
+/// Upgrades a new control connection on a WebSocket listener, which must open with its
+/// upgrade request within `limit`
+pub async fn accept_within(stream: BoxedStream, limit: Duration) -> Result<BoxedStream> {
+    let stream = timeout(limit, accept(stream))
+        .await
+        .map_err(|_| anyhow!("WebSocket handshake timed out"))??;
+    Ok(Box::new(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // the example of RFC 6455, section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_frames_round_trip() {
+        for len in [0, 125, 126, 65535, 65536] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            for mask in [None, Some([1, 2, 3, 4])] {
+                let mut buf = BytesMut::new();
+                encode_frame(&mut buf, OPCODE_BINARY, &payload, mask);
+                // nothing is decoded before the frame is complete
+                let mut partial = BytesMut::from(&buf[..buf.len() - 1]);
+                assert!(decode_frame(&mut partial).unwrap().is_none());
+
+                let (opcode, decoded) = decode_frame(&mut buf).unwrap().unwrap();
+                assert_eq!(opcode, OPCODE_BINARY);
+                assert_eq!(decoded, payload);
+                assert!(buf.is_empty());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_and_stream() {
+        let (client_side, server_side) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut stream = accept_within(Box::new(server_side), HANDSHAKE_TIMEOUT)
+                .await
+                .unwrap();
+            let mut received = vec![0u8; 200_000];
+            stream.read_exact(&mut received).await.unwrap();
+            stream.write_all(&received).await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let mut client = connect(client_side, "example.com:7000").await.unwrap();
+        let payload: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        client.write_all(&payload).await.unwrap();
+        client.flush().await.unwrap();
+        let mut echoed = Vec::new();
+        client.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, payload);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_other_connections_are_refused() {
+        // a raw client waits for the server to speak first
+        let (_client_side, server_side) = tokio::io::duplex(64);
+        let err = accept_within(Box::new(server_side), Duration::from_millis(50))
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+
+        // other HTTP requests are refused
+        let (mut client_side, server_side) = tokio::io::duplex(256);
+        client_side
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(accept_within(Box::new(server_side), HANDSHAKE_TIMEOUT)
+            .await
+            .is_err());
+        let mut response = Vec::new();
+        client_side.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 400"));
+    }
+}
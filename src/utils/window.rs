@@ -0,0 +1,140 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+/// Bytes either side may send on one connection before the receiver acknowledges them
+pub const INITIAL_WINDOW: usize = 1024 * 1024;
+
+/// Written bytes are acknowledged in batches of at least this many
+const ACK_BATCH: usize = INITIAL_WINDOW / 4;
+
+/// Credit for sending `Data` on one connection, spent by the reading half of its socket
+/// and replenished by the peer's `WindowUpdate`s. Keeps one bulk connection from
+/// queueing more than a window ahead of the others on the shared control channel.
+/// The peer may also hold all of it back with a `Pause` until its `Resume`
+#[derive(Debug)]
+pub struct SendWindow {
+    /// May drop below zero by at most one read
+    available: Mutex<i64>,
+    /// Set by the peer's `Pause`, cleared by its `Resume`
+    paused: AtomicBool,
+    replenished: Notify,
+}
+
+impl SendWindow {
+    pub fn new() -> Self {
+        Self {
+            available: Mutex::new(INITIAL_WINDOW as i64),
+            paused: AtomicBool::new(false),
+            replenished: Notify::new(),
+        }
+    }
+
+    /// Waits until some of the window is left and the peer has not paused the connection,
+    /// readers call it before each read
+    pub async fn ready(&self) {
+        while self.is_paused() || *self.available.lock().unwrap() <= 0 {
+            self.replenished.notified().await;
+        }
+    }
+
+    /// Holds reads back until `resume`, on the peer's `Pause`
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Release);
+    }
+
+    /// Lets reads go on, on the peer's `Resume`
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Release);
+        self.replenished.notify_one();
+    }
+
+    /// Whether the peer paused the connection
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    /// Counts `bytes` as sent
+    pub fn spend(&self, bytes: usize) {
+        *self.available.lock().unwrap() -= bytes as i64;
+    }
+
+    /// Adds the bytes the peer acknowledged
+    pub fn grant(&self, bytes: u32) {
+        *self.available.lock().unwrap() += i64::from(bytes);
+        // a permit is stored if the reader is not waiting yet
+        self.replenished.notify_one();
+    }
+}
+
+impl Default for SendWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counts bytes written to a socket and tells when to send a `WindowUpdate` for them
+#[derive(Debug, Default)]
+pub struct Acknowledger(usize);
+
+impl Acknowledger {
+    /// Records `bytes` as written, returns the bytes to acknowledge once a batch is full
+    pub fn written(&mut self, bytes: usize) -> Option<u32> {
+        self.0 += bytes;
+        if self.0 < ACK_BATCH {
+            return None;
+        }
+        let acknowledged = std::mem::take(&mut self.0);
+        Some(acknowledged as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::time::{timeout, Duration};
+
+    #[tokio::test]
+    async fn test_window_pauses_until_acknowledged() {
+        let window = Arc::new(SendWindow::new());
+        let mut acknowledger = Acknowledger::default();
+
+        window.ready().await;
+        window.spend(INITIAL_WINDOW + 100);
+        let waiting = window.clone();
+        let reader = tokio::spawn(async move { waiting.ready().await });
+        assert!(timeout(Duration::from_millis(50), window.ready())
+            .await
+            .is_err());
+
+        // small writes are batched, the window opens again once the batch is acknowledged
+        assert_eq!(acknowledger.written(100), None);
+        let acknowledged = acknowledger.written(ACK_BATCH).unwrap();
+        assert_eq!(acknowledged as usize, ACK_BATCH + 100);
+        window.grant(acknowledged);
+        timeout(Duration::from_millis(50), reader)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_paused_window_waits_for_resume() {
+        let window = Arc::new(SendWindow::new());
+        window.pause();
+        let waiting = window.clone();
+        let reader = tokio::spawn(async move { waiting.ready().await });
+        // credit alone does not let a paused reader go on
+        window.grant(1024);
+        assert!(timeout(Duration::from_millis(50), window.ready())
+            .await
+            .is_err());
+
+        window.resume();
+        timeout(Duration::from_millis(50), reader)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
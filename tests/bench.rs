@@ -0,0 +1,64 @@
+mod common;
+
+use common::{TestServer, TOKEN, WAIT};
+use sowback::{BenchOptions, CancellationToken, Client, ClientConfig};
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+fn options() -> BenchOptions {
+    BenchOptions {
+        duration: Duration::from_millis(300),
+        streams: 2,
+        payload_size: 16 * 1024,
+    }
+}
+
+fn config(token: &str) -> ClientConfig {
+    ClientConfig {
+        token: token.to_string(),
+        ..ClientConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn test_bench_measures_through_the_tunnel() {
+    let server = TestServer::start().await;
+    let report = tokio::time::timeout(
+        WAIT * 2,
+        Client::bench(
+            config(TOKEN),
+            &server.addr.to_string(),
+            &options(),
+            CancellationToken::new(),
+        ),
+    )
+    .await
+    .expect("bench did not finish")
+    .unwrap();
+
+    assert_eq!(report.setup.count, 10);
+    assert!(report.latency.count > 0);
+    assert!(report.latency.min <= report.latency.p50);
+    assert!(report.latency.p99 <= report.latency.max);
+    assert!(report.upload.bytes > 0);
+    assert!(report.download.bytes > 0);
+    // the temporary service is gone from the server
+    assert!(TcpStream::connect(&report.public_addr).await.is_err());
+}
+
+#[tokio::test]
+async fn test_bench_fails_on_a_rejected_token() {
+    let server = TestServer::start().await;
+    let result = tokio::time::timeout(
+        WAIT,
+        Client::bench(
+            config("wrong-token"),
+            &server.addr.to_string(),
+            &options(),
+            CancellationToken::new(),
+        ),
+    )
+    .await
+    .expect("bench did not give up");
+    assert!(result.is_err());
+}
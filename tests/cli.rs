@@ -0,0 +1,279 @@
+mod common;
+
+use common::{TOKEN, WAIT};
+use serde_json::Value;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// The `sowback` binary running with `args`, killed when dropped
+struct Sowback(Child);
+
+impl Sowback {
+    fn start(dir: &Path, args: &[&str]) -> Self {
+        let child = Command::new(env!("CARGO_BIN_EXE_sowback"))
+            .args(args)
+            .current_dir(dir)
+            .env_remove("RUST_LOG")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        Self(child)
+    }
+}
+
+impl Drop for Sowback {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// The first JSON record of `log_file` whose message contains `expected`, waiting for it
+/// to be written
+fn wait_for_record(log_file: &Path, expected: &str) -> Value {
+    let deadline = Instant::now() + WAIT;
+    loop {
+        let content = std::fs::read_to_string(log_file).unwrap_or_default();
+        let found = content
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).expect("log line is not JSON"))
+            .find(|record| {
+                record["fields"]["message"]
+                    .as_str()
+                    .is_some_and(|message| message.contains(expected))
+            });
+        if let Some(record) = found {
+            return record;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "no record with '{}' in {}:\n{}",
+            expected,
+            log_file.display(),
+            content
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn write_config(dir: &Path, content: &str) -> String {
+    let path = dir.join("sowback.toml");
+    std::fs::write(&path, content).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn test_server_logs_to_the_configured_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let log_file = dir.path().join("server.log");
+    let config = write_config(
+        dir.path(),
+        &format!(
+            r#"
+            [server]
+            listen_addr = "127.0.0.1:0"
+            bind_host = "127.0.0.1"
+            token = "{TOKEN}"
+            max_clients = 10
+            log_file = "{}"
+            "#,
+            log_file.display()
+        ),
+    );
+    let _server = Sowback::start(dir.path(), &["listen", "--config", &config]);
+
+    let record = wait_for_record(&log_file, "listening on 127.0.0.1:0");
+    assert_eq!(record["level"], "INFO");
+    // where the configuration came from is logged too, nothing before the file is lost
+    wait_for_record(&log_file, &format!("Using configuration file {}", config));
+}
+
+#[test]
+fn test_client_logs_to_the_configured_file_unless_given_another() {
+    let dir = tempfile::tempdir().unwrap();
+    let configured = dir.path().join("client.log");
+    // a server that is not running, the client keeps trying
+    let server = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = write_config(
+        dir.path(),
+        &format!(
+            r#"
+            [client]
+            servers = ["{server}"]
+            token = "{TOKEN}"
+            reconnect_interval = 1
+            heartbeat_interval = 30
+            log_file = "{}"
+            "#,
+            configured.display()
+        ),
+    );
+    {
+        let _client = Sowback::start(dir.path(), &["connect", "--config", &config]);
+        wait_for_record(&configured, "connecting to servers");
+    }
+
+    // --log wins over log_file
+    std::fs::remove_file(&configured).unwrap();
+    let given = dir.path().join("given.log");
+    let _client = Sowback::start(
+        dir.path(),
+        &[
+            "connect",
+            "--config",
+            &config,
+            "--log",
+            given.to_str().unwrap(),
+        ],
+    );
+    wait_for_record(&given, "connecting to servers");
+    assert!(!configured.exists());
+}
+
+#[test]
+fn test_connect_once_exits_when_the_server_is_unreachable() {
+    let dir = tempfile::tempdir().unwrap();
+    let server = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let mut client = Sowback::start(
+        dir.path(),
+        &["connect", &server, "--token", TOKEN, "--once"],
+    );
+
+    let deadline = Instant::now() + WAIT;
+    let status = loop {
+        if let Some(status) = client.0.try_wait().unwrap() {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "client kept reconnecting");
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    assert_eq!(status.code(), Some(3));
+}
+
+/// Sends `signal` to the process `pid`
+#[cfg(unix)]
+fn send_signal(pid: u32, signal: i32) {
+    // SAFETY: kill takes no pointers
+    assert_eq!(unsafe { libc::kill(pid as i32, signal) }, 0);
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_upgrade_hands_listeners_over_without_dropping_connections() {
+    use common::{echo_service, round_trip, TestClient};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    let dir = tempfile::tempdir().unwrap();
+    let log_file = dir.path().join("server.log");
+    let socket = dir.path().join("upgrade.sock");
+    let pid_file = dir.path().join("sowback.pid");
+    let control = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = write_config(
+        dir.path(),
+        &format!(
+            r#"
+            [server]
+            listen_addr = "{control}"
+            bind_host = "127.0.0.1"
+            token = "{TOKEN}"
+            max_clients = 10
+            log_file = "{}"
+            drain_timeout = "10s"
+            "#,
+            log_file.display()
+        ),
+    );
+    let mut old = Sowback::start(
+        dir.path(),
+        &[
+            "listen",
+            "--config",
+            &config,
+            "--upgrade-socket",
+            socket.to_str().unwrap(),
+            "--pid-file",
+            pid_file.to_str().unwrap(),
+        ],
+    );
+    wait_for_record(&log_file, "Server ready");
+
+    let echo = echo_service().await;
+    let client = TestClient::start(control, TOKEN, echo);
+    let port = client.remote_port().await;
+
+    // a transfer in flight across the upgrade
+    let mut transfer = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let first = vec![1u8; 64 * 1024];
+    transfer.write_all(&first).await.unwrap();
+    let mut echoed = vec![0; first.len()];
+    transfer.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(echoed, first);
+
+    send_signal(old.0.id(), libc::SIGUSR2);
+    let record = wait_for_record(&log_file, "Handed the listeners over to pid");
+    let message = record["fields"]["message"].as_str().unwrap().to_string();
+    let new_pid: u32 = message
+        .split_whitespace()
+        .find_map(|word| word.trim_end_matches(',').parse().ok())
+        .unwrap();
+    // killed however the test ends
+    struct Successor(u32);
+    impl Drop for Successor {
+        fn drop(&mut self) {
+            send_signal(self.0, libc::SIGTERM);
+        }
+    }
+    let _successor = Successor(new_pid);
+    assert_eq!(
+        std::fs::read_to_string(&pid_file).unwrap().trim(),
+        new_pid.to_string()
+    );
+
+    // the old process still serves the transfer it has
+    let second = vec![2u8; 64 * 1024];
+    transfer.write_all(&second).await.unwrap();
+    transfer.shutdown().await.unwrap();
+    let mut rest = Vec::new();
+    transfer.read_to_end(&mut rest).await.unwrap();
+    assert_eq!(rest, second);
+
+    // new connections go to the new process, held until the client is back
+    for i in 0..20u8 {
+        let payload = vec![i; 1024];
+        let received = tokio::time::timeout(WAIT, round_trip(port, &payload))
+            .await
+            .expect("connection after the upgrade hung")
+            .expect("connection after the upgrade failed");
+        assert_eq!(received, payload);
+    }
+
+    // and the old one leaves once its client did
+    let deadline = Instant::now() + WAIT;
+    let status = loop {
+        if let Some(status) = old.0.try_wait().unwrap() {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "old process kept running");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+    assert!(status.success());
+    assert_eq!(
+        std::fs::read_to_string(&pid_file).unwrap().trim(),
+        new_pid.to_string()
+    );
+    wait_for_record(&log_file, "Client reclaimed its listener");
+}
@@ -0,0 +1,199 @@
+//! Harness running a server, a client and a local echo service in one runtime
+
+#![allow(dead_code)]
+
+use sowback::{CancellationToken, Client, Config, Server};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+pub const TOKEN: &str = "integration-secret";
+
+/// Name of the service every test client registers
+pub const SERVICE: &str = "echo";
+
+/// How long helpers wait for something to happen before failing the test
+pub const WAIT: Duration = Duration::from_secs(5);
+
+/// A running server, stopped when dropped
+pub struct TestServer {
+    pub addr: SocketAddr,
+    shutdown: CancellationToken,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl TestServer {
+    /// Starts a server on an ephemeral port
+    pub async fn start() -> Self {
+        Self::start_on("127.0.0.1:0").await
+    }
+
+    /// Starts a server on `addr`, e.g. the address of a server that was stopped
+    pub async fn start_on(addr: &str) -> Self {
+        Self::launch(TcpListener::bind(addr).await.unwrap(), String::new())
+    }
+
+    /// Starts a server on an ephemeral port, with the TOML lines `extra` returns for the
+    /// port's address added to the `[server]` table
+    pub async fn start_with(extra: impl FnOnce(SocketAddr) -> String) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let extra = extra(listener.local_addr().unwrap());
+        Self::launch(listener, extra)
+    }
+
+    fn launch(listener: TcpListener, extra: String) -> Self {
+        let config = toml::from_str::<Config>(&format!(
+            r#"
+            [server]
+            listen_addr = "127.0.0.1:0"
+            bind_host = "127.0.0.1"
+            token = "{TOKEN}"
+            max_clients = 10
+            {extra}
+            "#
+        ))
+        .unwrap()
+        .server
+        .unwrap();
+        let server = Server::new(config).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        let task = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { server.serve(listener, shutdown).await }
+        });
+        Self {
+            addr,
+            shutdown,
+            task,
+        }
+    }
+
+    /// Stops the server and waits until its clients are disconnected
+    pub async fn stop(mut self) {
+        self.shutdown.cancel();
+        tokio::time::timeout(WAIT, &mut self.task)
+            .await
+            .expect("server did not stop")
+            .unwrap()
+            .unwrap();
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+/// A running client, stopped when dropped
+pub struct TestClient {
+    pub client: Client,
+    server_addr: String,
+    shutdown: CancellationToken,
+}
+
+impl TestClient {
+    /// Starts a client exposing `local` as the auto-assigned `SERVICE` on `server`
+    pub fn start(server: SocketAddr, token: &str, local: SocketAddr) -> Self {
+        Self::start_with(server, token, local, "")
+    }
+
+    /// Like `start`, with `extra` TOML lines added to the `[client]` table
+    pub fn start_with(server: SocketAddr, token: &str, local: SocketAddr, extra: &str) -> Self {
+        Self::start_config(
+            server,
+            token,
+            &format!(
+                r#"
+                {extra}
+
+                [[client.services]]
+                name = "{SERVICE}"
+                local_ip = "{ip}"
+                local_port = {port}
+                remote_port = 0
+                "#,
+                ip = local.ip(),
+                port = local.port(),
+            ),
+        )
+    }
+
+    /// Starts a client of `server` with `rest` completing its `[client]` table, e.g. with
+    /// services or visitors of its own instead of `SERVICE`
+    pub fn start_config(server: SocketAddr, token: &str, rest: &str) -> Self {
+        let config = toml::from_str::<Config>(&format!(
+            r#"
+            [client]
+            servers = ["{server}"]
+            token = "{token}"
+            reconnect_interval = 1
+            heartbeat_interval = 30
+            {rest}
+            "#
+        ))
+        .unwrap()
+        .client
+        .unwrap();
+        let client = Client::new(config).unwrap();
+        let shutdown = CancellationToken::new();
+        tokio::spawn({
+            let client = client.clone();
+            let shutdown = shutdown.clone();
+            async move { client.run(shutdown).await }
+        });
+        Self {
+            client,
+            server_addr: server.to_string(),
+            shutdown,
+        }
+    }
+
+    /// Waits until the server assigned a remote port to `SERVICE`
+    pub async fn remote_port(&self) -> u16 {
+        tokio::time::timeout(WAIT, async {
+            loop {
+                if let Some(port) = self.client.assigned_port(&self.server_addr, SERVICE).await {
+                    return port;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("service was not registered")
+    }
+}
+
+impl Drop for TestClient {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+/// Starts a local service echoing everything back, returning its address
+pub async fn echo_service() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    });
+    addr
+}
+
+/// Sends `payload` through the tunnel on `port` and returns what came back
+pub async fn round_trip(port: u16, payload: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    stream.write_all(payload).await?;
+    stream.shutdown().await?;
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).await?;
+    Ok(received)
+}
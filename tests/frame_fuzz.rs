@@ -0,0 +1,161 @@
+//! Feeds random and mutated byte streams to the frame parsers. The streams come from
+//! seeded generators, so a failing case replays with the seed it reports
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use sowback::{CloseCode, Frame, FrameReader, Framing, Message, FRAME_MAGIC, MAX_FRAME_LEN};
+
+const SEEDS: u64 = 300;
+
+/// Largest chunk a stream is fed to a `FrameReader` in
+const MAX_CHUNK: usize = 4096;
+
+/// A few frames of the messages a session carries most
+fn frames(rng: &mut StdRng, framing: Framing) -> Vec<u8> {
+    let mut data = Vec::new();
+    for seq in 0..rng.random_range(1..8) {
+        let message = match rng.random_range(0..4) {
+            0 => Message::new_heartbeat(),
+            1 => Message::new_close_connection("conn", CloseCode::from_u8(rng.random())),
+            2 => Message::new_window_update("conn", rng.random()),
+            _ => {
+                let mut payload = vec![0; rng.random_range(0..2048)];
+                rng.fill(&mut payload[..]);
+                Message::new_data("conn", seq, payload)
+            }
+        };
+        data.extend(Frame::new(message).serialize_framed(framing).unwrap());
+    }
+    data
+}
+
+/// `data` with bytes flipped, dropped, inserted or cut off
+fn mutate(rng: &mut StdRng, mut data: Vec<u8>) -> Vec<u8> {
+    for _ in 0..rng.random_range(1..6) {
+        if data.is_empty() {
+            break;
+        }
+        let at = rng.random_range(0..data.len());
+        match rng.random_range(0..5) {
+            0 => data[at] ^= 1 << rng.random_range(0..8),
+            1 => data[at] = rng.random(),
+            2 => {
+                data.remove(at);
+            }
+            3 => data.insert(at, rng.random()),
+            _ => data.truncate(at),
+        }
+    }
+    data
+}
+
+/// A stream for `seed`: noise, or frames mutated or not
+fn stream(seed: u64, framing: Framing) -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    match seed % 3 {
+        0 => {
+            let mut noise = vec![0; rng.random_range(0..16 * 1024)];
+            rng.fill(&mut noise[..]);
+            noise
+        }
+        1 => {
+            let data = frames(&mut rng, framing);
+            mutate(&mut rng, data)
+        }
+        _ => frames(&mut rng, framing),
+    }
+}
+
+/// Reads `data` fed in random chunks, returning the messages read and whether a read
+/// failed. The buffer never holds more than the largest frame and a chunk
+fn read_chunked(seed: u64, data: &[u8], framing: Framing) -> (Vec<Message>, bool) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut reader = FrameReader::new();
+    reader.set_framing(framing);
+    let mut messages = Vec::new();
+    let mut failed = false;
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (chunk, next) = rest.split_at(rng.random_range(1..=MAX_CHUNK).min(rest.len()));
+        rest = next;
+        reader.feed_data(chunk);
+        loop {
+            match reader.try_read_frame() {
+                Ok(Some(frame)) => messages.push(frame.message),
+                Ok(None) => break,
+                Err(_) => failed = true,
+            }
+        }
+        assert!(
+            reader.buffered() <= FRAME_MAGIC.len() + 4 + MAX_FRAME_LEN + MAX_CHUNK,
+            "seed {}: {} bytes buffered",
+            seed,
+            reader.buffered()
+        );
+    }
+    (messages, failed)
+}
+
+#[test]
+fn test_deserialize_survives_any_input() {
+    for seed in 0..SEEDS {
+        let data = stream(seed, Framing::Plain);
+        // every offset is a frame boundary to a parser that lost track
+        for start in 0..data.len().min(512) {
+            let _ = Frame::deserialize(&data[start..]);
+        }
+        let mut rest = &data[..];
+        while let Ok((_, used)) = Frame::deserialize(rest) {
+            assert!(used > 0 && used <= rest.len(), "seed {}", seed);
+            rest = &rest[used..];
+        }
+    }
+}
+
+#[test]
+fn test_frame_reader_survives_any_input() {
+    for seed in 0..SEEDS {
+        for framing in [Framing::Plain, Framing::Marked] {
+            let data = stream(seed, framing);
+            let (messages, failed) = read_chunked(seed, &data, framing);
+            if seed % 3 == 2 {
+                // untouched frames read back whole, however they arrive
+                let mut expected = Vec::new();
+                let mut rest = &data[..];
+                while !rest.is_empty() {
+                    let (frame, used) = Frame::deserialize(rest).unwrap();
+                    expected.push(format!("{:?}", frame.message));
+                    rest = &rest[used..];
+                }
+                let read: Vec<_> = messages.iter().map(|m| format!("{:?}", m)).collect();
+                assert_eq!(read, expected, "seed {}", seed);
+                assert!(!failed, "seed {}", seed);
+            }
+            // a marked stream is resynchronized rather than failed
+            if framing == Framing::Marked {
+                assert!(!failed, "seed {}", seed);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_oversized_length_prefix_is_not_waited_for() {
+    for framing in [Framing::Plain, Framing::Marked] {
+        let mut data = Vec::new();
+        if framing == Framing::Marked {
+            data.extend_from_slice(&FRAME_MAGIC);
+        }
+        data.extend_from_slice(&u32::MAX.to_be_bytes());
+        assert!(Frame::deserialize(&data).is_err());
+
+        let mut reader = FrameReader::new();
+        reader.set_framing(framing);
+        reader.feed_data(&data);
+        match framing {
+            Framing::Plain => assert!(reader.try_read_frame().is_err()),
+            Framing::Marked => assert!(reader.try_read_frame().unwrap().is_none()),
+        }
+        assert!(reader.buffered() < data.len());
+    }
+}
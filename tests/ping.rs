@@ -0,0 +1,32 @@
+mod common;
+
+use common::{TestServer, TOKEN, WAIT};
+use sowback::{Client, ClientConfig, PingError};
+use tokio::net::TcpListener;
+
+#[tokio::test]
+async fn test_ping_measures_heartbeats() {
+    let server = TestServer::start().await;
+    let client = Client::new(ClientConfig::default()).unwrap();
+
+    let mut session = client.ping(&server.addr.to_string(), TOKEN).await.unwrap();
+    let rtt = session.probe(WAIT).await.unwrap().expect("heartbeat lost");
+    assert!(rtt < WAIT);
+    assert!(session.auth_time < WAIT);
+}
+
+#[tokio::test]
+async fn test_ping_tells_failures_apart() {
+    let server = TestServer::start().await;
+    let client = Client::new(ClientConfig::default()).unwrap();
+
+    let rejected = client.ping(&server.addr.to_string(), "wrong-token").await;
+    assert!(matches!(rejected, Err(PingError::AuthRejected(_))));
+
+    // a port nothing listens on any more
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = closed.local_addr().unwrap().to_string();
+    drop(closed);
+    let unreachable = client.ping(&addr, TOKEN).await;
+    assert!(matches!(unreachable, Err(PingError::Unreachable(_))));
+}
@@ -0,0 +1,54 @@
+#![cfg(unix)]
+
+use sowback::logging::syslog::SyslogAddr;
+use sowback::logging::{init_logger, LogLevel, LogSettings, Verbosity};
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+#[test]
+fn test_logs_reach_the_syslog_socket() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("log.sock");
+    let daemon = UnixDatagram::bind(&path).unwrap();
+    daemon
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    init_logger(
+        None,
+        Verbosity::Normal,
+        &LogSettings {
+            cli_level: Some(LogLevel::Info),
+            syslog: Some(SyslogAddr::Unix(path)),
+            ..Default::default()
+        },
+    );
+
+    let span = tracing::info_span!("proxy_conn", conn = "ab12cd34", client = "ef56ab78");
+    span.in_scope(|| {
+        sowback::log_debug!("Below the level, never sent");
+        sowback::log_warn!("Proxy connection refused");
+    });
+    let mut buf = [0u8; 1024];
+    let len = daemon.recv(&mut buf).unwrap();
+    let record = String::from_utf8_lossy(&buf[..len]);
+
+    // facility daemon, severity warning
+    assert!(record.starts_with("<28>1 "), "{}", record);
+    let fields: Vec<&str> = record.splitn(8, ' ').collect();
+    assert_eq!(fields[3], "sowback", "{}", record);
+    assert_eq!(fields[4], std::process::id().to_string(), "{}", record);
+    assert!(
+        record.ends_with(
+            r#" - [sowback@32473 conn="ab12cd34" client="ef56ab78"] Proxy connection refused"#
+        ),
+        "{}",
+        record
+    );
+
+    // an event outside any span carries no structured data
+    sowback::log_info!("Server stopped");
+    let len = daemon.recv(&mut buf).unwrap();
+    let record = String::from_utf8_lossy(&buf[..len]);
+    assert!(record.starts_with("<30>1 "), "{}", record);
+    assert!(record.ends_with(" - - Server stopped"), "{}", record);
+}
@@ -0,0 +1,623 @@
+mod common;
+
+use common::{echo_service, round_trip, TestClient, TestServer, SERVICE, TOKEN, WAIT};
+use sowback::{Frame, FrameReader, Framing, Message, FRAME_MAGIC};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+
+#[tokio::test]
+async fn test_echo_round_trip() {
+    let server = TestServer::start().await;
+    let client = TestClient::start(server.addr, TOKEN, echo_service().await);
+    let port = client.remote_port().await;
+
+    let payload: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+    assert_eq!(round_trip(port, &payload).await.unwrap(), payload);
+}
+
+#[tokio::test]
+async fn test_websocket_transport_round_trip() {
+    // a free port for the WebSocket listener
+    let websocket_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let _server =
+        TestServer::start_with(|_| format!(r#"websocket_listen_addr = "{websocket_addr}""#)).await;
+    let client = TestClient::start_with(
+        websocket_addr,
+        TOKEN,
+        echo_service().await,
+        r#"transport = "websocket""#,
+    );
+    let port = client.remote_port().await;
+
+    let payload: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+    assert_eq!(round_trip(port, &payload).await.unwrap(), payload);
+}
+
+#[cfg(feature = "quic")]
+#[tokio::test]
+async fn test_quic_transport_round_trip() {
+    use sha2::{Digest, Sha256};
+
+    // a self-signed certificate, pinned by the client as in a token-only deployment
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+    std::fs::write(&cert, certified.cert.pem()).unwrap();
+    std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+    let fingerprint: String = Sha256::digest(certified.cert.der())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    let server = TestServer::start_with(|addr| {
+        format!(
+            r#"
+            quic_listen_addr = "{addr}"
+            [server.tls]
+            cert = "{}"
+            key = "{}"
+            "#,
+            cert.display(),
+            key.display()
+        )
+    })
+    .await;
+    let client = TestClient::start_with(
+        server.addr,
+        TOKEN,
+        echo_service().await,
+        &format!(
+            r#"transport = "quic"
+            tls = {{ fingerprint = "{fingerprint}" }}"#
+        ),
+    );
+    let port = client.remote_port().await;
+
+    // every connection is carried on a stream of its own
+    let connections: Vec<_> = (0..10)
+        .map(|i| {
+            tokio::spawn(async move {
+                let payload: Vec<u8> = (0..100_000u32).map(|n| (n + i) as u8).collect();
+                assert_eq!(round_trip(port, &payload).await.unwrap(), payload);
+            })
+        })
+        .collect();
+    for connection in connections {
+        tokio::time::timeout(WAIT, connection)
+            .await
+            .expect("connection stalled")
+            .unwrap();
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("echo.sock");
+    let listener = tokio::net::UnixListener::bind(&path).unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    });
+
+    let server = TestServer::start().await;
+    let client = TestClient::start_config(
+        server.addr,
+        TOKEN,
+        &format!(
+            r#"
+            [[client.services]]
+            name = "{SERVICE}"
+            local_path = "{}"
+            remote_port = 0
+            "#,
+            path.display()
+        ),
+    );
+    let port = client.remote_port().await;
+
+    let payload: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+    assert_eq!(round_trip(port, &payload).await.unwrap(), payload);
+}
+
+#[tokio::test]
+async fn test_large_transfer_round_trip() {
+    let server = TestServer::start().await;
+    let client = TestClient::start(server.addr, TOKEN, echo_service().await);
+    let port = client.remote_port().await;
+
+    // the server drops peers sending too much before the local service is reached,
+    // so one echo comes back before the bulk of the payload is written while it is read
+    let payload: Vec<u8> = (0..10 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let (mut read, mut write) = stream.into_split();
+    write.write_all(&payload[..1024]).await.unwrap();
+    let mut echoed = vec![0; 1024];
+    read.read_exact(&mut echoed).await.unwrap();
+
+    let rest = payload[1024..].to_vec();
+    let writer = tokio::spawn(async move {
+        write.write_all(&rest).await.unwrap();
+        write.shutdown().await.unwrap();
+    });
+    tokio::time::timeout(Duration::from_secs(30), read.read_to_end(&mut echoed))
+        .await
+        .expect("transfer stalled")
+        .unwrap();
+    writer.await.unwrap();
+    assert!(echoed == payload);
+}
+
+#[tokio::test]
+async fn test_concurrent_connections() {
+    let server = TestServer::start().await;
+    let client = TestClient::start(server.addr, TOKEN, echo_service().await);
+    let port = client.remote_port().await;
+
+    let connections: Vec<_> = (0..50)
+        .map(|i| {
+            tokio::spawn(async move {
+                let payload = format!("connection {i} ").repeat(100).into_bytes();
+                assert_eq!(round_trip(port, &payload).await.unwrap(), payload);
+            })
+        })
+        .collect();
+    for connection in connections {
+        tokio::time::timeout(WAIT, connection)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
+
+#[test]
+fn test_single_worker_runtime_round_trip() {
+    // the runtime `--workers 1` builds, server, client and service sharing its one thread
+    sowback::build_runtime(1).unwrap().block_on(async {
+        let server = TestServer::start().await;
+        let client = TestClient::start(server.addr, TOKEN, echo_service().await);
+        let port = client.remote_port().await;
+
+        let connections: Vec<_> = (0..10)
+            .map(|i| {
+                tokio::spawn(async move {
+                    let payload = format!("connection {i} ").repeat(10_000).into_bytes();
+                    assert_eq!(round_trip(port, &payload).await.unwrap(), payload);
+                })
+            })
+            .collect();
+        for connection in connections {
+            tokio::time::timeout(WAIT, connection)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+    });
+}
+
+#[tokio::test]
+async fn test_client_reconnects_after_server_restart() {
+    let server = TestServer::start().await;
+    let addr = server.addr;
+    let client = TestClient::start(addr, TOKEN, echo_service().await);
+    let port = client.remote_port().await;
+    assert_eq!(round_trip(port, b"before").await.unwrap(), b"before");
+
+    server.stop().await;
+    let _server = TestServer::start_on(&addr.to_string()).await;
+
+    // the client asks for the same port again once it is back
+    tokio::time::timeout(WAIT, async {
+        loop {
+            if let Ok(echoed) = round_trip(port, b"after").await {
+                if echoed == b"after" {
+                    break;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("client did not reconnect");
+}
+
+/// A relay between clients and `server` whose connections are cut by aborting its tasks,
+/// like a network blip that leaves both ends running
+async fn relay(server: std::net::SocketAddr) -> (std::net::SocketAddr, Arc<Mutex<JoinSet<()>>>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = Arc::new(Mutex::new(JoinSet::new()));
+    tokio::spawn({
+        let connections = connections.clone();
+        async move {
+            while let Ok((mut inbound, _)) = listener.accept().await {
+                connections.lock().unwrap().spawn(async move {
+                    let mut outbound = TcpStream::connect(server).await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                });
+            }
+        }
+    });
+    (addr, connections)
+}
+
+#[tokio::test]
+async fn test_assigned_port_survives_a_control_connection_blip() {
+    let server = TestServer::start().await;
+    let (relay_addr, connections) = relay(server.addr).await;
+    let client = TestClient::start(relay_addr, TOKEN, echo_service().await);
+    let port = client.remote_port().await;
+    assert_eq!(round_trip(port, b"before").await.unwrap(), b"before");
+
+    // the server keeps running, only the control connection drops
+    connections.lock().unwrap().abort_all();
+
+    // the client registers again on the port it had; a connection caught by the blip may
+    // never be answered, so each attempt is given up after a while
+    tokio::time::timeout(WAIT, async {
+        loop {
+            let attempt = round_trip(port, b"after");
+            if let Ok(Ok(echoed)) = tokio::time::timeout(Duration::from_millis(500), attempt).await
+            {
+                if echoed == b"after" {
+                    break;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("client did not get its port back");
+    assert_eq!(client.remote_port().await, port);
+}
+
+#[tokio::test]
+async fn test_connections_survive_a_control_connection_blip() {
+    let server = TestServer::start().await;
+    let (relay_addr, connections) = relay(server.addr).await;
+    let client = TestClient::start(relay_addr, TOKEN, echo_service().await);
+    let port = client.remote_port().await;
+
+    // as in test_large_transfer_round_trip, one echo comes back before the bulk is written
+    let payload: Vec<u8> = (0..4 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let (mut read, mut write) = stream.into_split();
+    write.write_all(&payload[..1024]).await.unwrap();
+    let mut echoed = vec![0; 1024];
+    read.read_exact(&mut echoed).await.unwrap();
+    let rest = payload[1024..].to_vec();
+    let writer = tokio::spawn(async move {
+        write.write_all(&rest).await.unwrap();
+        write.shutdown().await.unwrap();
+    });
+
+    // the control connection drops mid-transfer, both ends keep running
+    echoed.resize(payload.len() / 2, 0);
+    read.read_exact(&mut echoed[1024..]).await.unwrap();
+    connections.lock().unwrap().abort_all();
+
+    // the client resumes the connection on its next session, nothing is lost meanwhile
+    tokio::time::timeout(Duration::from_secs(30), read.read_to_end(&mut echoed))
+        .await
+        .expect("transfer stalled")
+        .unwrap();
+    writer.await.unwrap();
+    assert!(echoed == payload);
+}
+
+#[tokio::test]
+async fn test_bad_token_is_rejected() {
+    let server = TestServer::start().await;
+    let mut stream = TcpStream::connect(server.addr).await.unwrap();
+    let mut reader = FrameReader::new();
+
+    let nonce = match reader
+        .read_frame(&mut stream)
+        .await
+        .unwrap()
+        .unwrap()
+        .message
+    {
+        Message::AuthChallenge { nonce, .. } => nonce,
+        other => panic!("expected AuthChallenge, got {:?}", other),
+    };
+    let auth = Message::new_auth("wrong-token", &nonce, "intruder", None, vec![], false);
+    stream
+        .write_all(&Frame::new(auth).serialize().unwrap())
+        .await
+        .unwrap();
+    match reader
+        .read_frame(&mut stream)
+        .await
+        .unwrap()
+        .unwrap()
+        .message
+    {
+        Message::AuthResponse {
+            success: false,
+            error,
+            ..
+        } => assert_eq!(error.as_deref(), Some("Invalid token")),
+        other => panic!("expected a failed AuthResponse, got {:?}", other),
+    }
+
+    // a client with the wrong token never gets its service registered
+    let client = TestClient::start(server.addr, "wrong-token", echo_service().await);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(client
+        .client
+        .assigned_port(&server.addr.to_string(), common::SERVICE)
+        .await
+        .is_none());
+}
+
+/// Reads one frame off `stream`, returning whether it started with `FRAME_MAGIC`
+async fn read_raw_frame(stream: &mut TcpStream) -> (bool, Message) {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await.unwrap();
+    let marked = header == FRAME_MAGIC;
+    if marked {
+        stream.read_exact(&mut header).await.unwrap();
+    }
+    let mut frame = header.to_vec();
+    frame.resize(4 + u32::from_be_bytes(header) as usize, 0);
+    stream.read_exact(&mut frame[4..]).await.unwrap();
+    (marked, Frame::deserialize(&frame).unwrap().0.message)
+}
+
+#[tokio::test]
+async fn test_session_frames_are_marked_after_the_handshake() {
+    let server = TestServer::start().await;
+    let mut stream = TcpStream::connect(server.addr).await.unwrap();
+
+    let nonce = match read_raw_frame(&mut stream).await {
+        (false, Message::AuthChallenge { nonce, .. }) => nonce,
+        other => panic!("expected a plain AuthChallenge, got {:?}", other),
+    };
+    let client_id = "7046c8b3-1f0e-4c52-9a4e-3a5b0d7e2f11";
+    let auth = Message::new_auth(TOKEN, &nonce, client_id, None, vec![], false);
+    stream
+        .write_all(&Frame::new(auth).serialize().unwrap())
+        .await
+        .unwrap();
+    match read_raw_frame(&mut stream).await {
+        (false, Message::AuthResponse { success: true, .. }) => {}
+        other => panic!("expected a plain successful AuthResponse, got {:?}", other),
+    }
+
+    let heartbeat = Frame::new(Message::new_heartbeat())
+        .serialize_framed(Framing::Marked)
+        .unwrap();
+    stream.write_all(&heartbeat).await.unwrap();
+    match read_raw_frame(&mut stream).await {
+        (true, Message::HeartbeatResponse { .. }) => {}
+        other => panic!("expected a marked HeartbeatResponse, got {:?}", other),
+    }
+}
+
+/// A local service that speaks first like an SSH server, then echoes
+async fn banner_service() -> std::net::SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                stream.write_all(b"SSH-2.0-sowback\r\n").await.unwrap();
+                let (mut read, mut write) = stream.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_visitor_reaches_secret_service() {
+    let server = TestServer::start().await;
+    let ssh = banner_service().await;
+    let _owner = TestClient::start_config(
+        server.addr,
+        TOKEN,
+        &format!(
+            r#"
+            [[client.services]]
+            name = "ssh"
+            local_ip = "{}"
+            local_port = {}
+            secret = "between-us"
+            "#,
+            ssh.ip(),
+            ssh.port()
+        ),
+    );
+    let bind = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let _visitor = TestClient::start_config(
+        server.addr,
+        TOKEN,
+        &format!(
+            r#"
+            [[client.visitors]]
+            service = "ssh"
+            secret = "between-us"
+            bind_addr = "{bind}"
+            "#
+        ),
+    );
+
+    // both clients need to be registered, until then the visitor drops connections
+    let banner = b"SSH-2.0-sowback\r\n";
+    let mut stream = tokio::time::timeout(WAIT, async {
+        loop {
+            if let Ok(mut stream) = TcpStream::connect(bind).await {
+                let mut received = vec![0; banner.len()];
+                if stream.read_exact(&mut received).await.is_ok() {
+                    assert_eq!(received, banner);
+                    return stream;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("visitor did not reach the secret service");
+
+    // more than a window's worth, so the relayed window updates have to arrive
+    let payload: Vec<u8> = (0..3 * 1024 * 1024u32).map(|i| (i % 253) as u8).collect();
+    let (mut read, mut write) = stream.split();
+    let ((), echoed) = tokio::join!(
+        async {
+            write.write_all(&payload).await.unwrap();
+            write.shutdown().await.unwrap();
+        },
+        async {
+            let mut echoed = Vec::new();
+            tokio::time::timeout(WAIT, read.read_to_end(&mut echoed))
+                .await
+                .expect("transfer stalled")
+                .unwrap();
+            echoed
+        }
+    );
+    assert!(echoed == payload);
+}
+
+#[tokio::test]
+async fn test_relay_reaches_service_of_peer() {
+    let server = TestServer::start_with(|_| "allow_relay = true".to_string()).await;
+    let ssh = banner_service().await;
+    let _owner = TestClient::start_config(
+        server.addr,
+        TOKEN,
+        &format!(
+            r#"
+            name = "laptop-b"
+
+            [[client.services]]
+            name = "ssh"
+            local_ip = "{}"
+            local_port = {}
+            relay_peers = ["laptop-a"]
+            "#,
+            ssh.ip(),
+            ssh.port()
+        ),
+    );
+    let bind = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let _peer = TestClient::start_config(
+        server.addr,
+        TOKEN,
+        &format!(
+            r#"
+            name = "laptop-a"
+
+            [[client.relays]]
+            client = "laptop-b"
+            service = "ssh"
+            bind_addr = "{bind}"
+            "#
+        ),
+    );
+
+    // refused until both clients are registered, then the banner comes through
+    let banner = b"SSH-2.0-sowback\r\n";
+    let mut stream = tokio::time::timeout(WAIT, async {
+        loop {
+            if let Ok(mut stream) = TcpStream::connect(bind).await {
+                let mut received = vec![0; banner.len()];
+                if stream.read_exact(&mut received).await.is_ok() {
+                    assert_eq!(received, banner);
+                    return stream;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("relay did not reach the service of its peer");
+
+    stream.write_all(b"ping").await.unwrap();
+    let mut echoed = [0; 4];
+    tokio::time::timeout(WAIT, stream.read_exact(&mut echoed))
+        .await
+        .expect("relayed connection stalled")
+        .unwrap();
+    assert_eq!(&echoed, b"ping");
+}
+
+/// Median round trip of small messages through the tunnel of a client started with
+/// `extra`, while other connections of the tunnel move as much data as they can
+async fn round_trip_under_load(extra: &str) -> Duration {
+    let server = TestServer::start().await;
+    let client = TestClient::start_with(server.addr, TOKEN, echo_service().await, extra);
+    let port = client.remote_port().await;
+
+    // every connection is set up before the load starts, and is echoed once first: the
+    // server drops peers sending too much before the local service is reached
+    let mut connections = Vec::new();
+    for _ in 0..5 {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        stream.read_exact(&mut [0u8; 4]).await.unwrap();
+        connections.push(stream);
+    }
+    let mut probe = connections.pop().unwrap();
+    let mut load = Vec::new();
+    for bulk in connections {
+        let (mut bulk_read, mut bulk_write) = bulk.into_split();
+        load.push(tokio::spawn(async move {
+            let chunk = vec![0x5a; 64 * 1024];
+            while bulk_write.write_all(&chunk).await.is_ok() {}
+        }));
+        load.push(tokio::spawn(async move {
+            let mut buffer = vec![0; 64 * 1024];
+            while let Ok(1..) = bulk_read.read(&mut buffer).await {}
+        }));
+    }
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut rtts = Vec::new();
+    for _ in 0..21 {
+        let start = tokio::time::Instant::now();
+        probe.write_all(b"ping").await.unwrap();
+        tokio::time::timeout(WAIT, probe.read_exact(&mut [0u8; 4]))
+            .await
+            .expect("round trip stalled")
+            .unwrap();
+        rtts.push(start.elapsed());
+    }
+    for task in load {
+        task.abort();
+    }
+    rtts.sort();
+    rtts[rtts.len() / 2]
+}
+
+#[tokio::test]
+async fn test_data_channels_keep_round_trips_quick_under_load() {
+    // in messages, the probe's data queues on the control connection behind the bulk's
+    let in_messages = round_trip_under_load("").await;
+    let on_channels = round_trip_under_load("data_channels = true").await;
+    assert!(
+        on_channels < in_messages,
+        "round trips took {:?} on data channels, {:?} in messages",
+        on_channels,
+        in_messages
+    );
+}